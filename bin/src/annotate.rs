@@ -0,0 +1,153 @@
+//! Reports warnings and errors as annotations understood by CI systems,
+//! so that they're surfaced on the run summary rather than buried in the log.
+//!
+//! For GitHub Actions, each warning or error is written as a workflow command
+//! (`::warning ...::message` or `::error ...::message`) on its own line.
+//! These are written to stderr so they don't interleave with output on stdout (like JSON listings);
+//! the runner reads workflow commands from both.
+//!
+//! Reference:
+//! - https://docs.github.com/en/actions/reference/workflow-commands-for-github-actions
+
+use std::{fmt, io::Write};
+
+use clap::ValueEnum;
+use color_eyre::eyre::Report;
+use tracing::{
+    field::{Field, Visit},
+    Event, Level, Subscriber,
+};
+use tracing_subscriber::{layer::Context, Layer};
+
+/// The CI system for which annotations are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// GitHub Actions workflow commands (`::warning` and `::error`).
+    Github,
+}
+
+/// The title given to every annotation.
+const TITLE: &str = "circe";
+
+impl Format {
+    /// A tracing layer that writes an annotation for each warning or error event.
+    pub fn layer(self) -> Annotations {
+        Annotations { format: self }
+    }
+
+    /// Write an annotation for the error that caused circe to fail.
+    pub fn error(self, err: &Report) {
+        // The alternate format reports the chain of causes on one line, without colors.
+        let annotation = self.annotation(Level::ERROR, &format!("{err:#}"), None);
+        let _ = writeln!(std::io::stderr().lock(), "{annotation}");
+    }
+
+    fn annotation(self, level: Level, message: &str, file: Option<&str>) -> String {
+        match self {
+            Format::Github => {
+                let command = match level {
+                    Level::ERROR => "error",
+                    _ => "warning",
+                };
+                let mut properties = format!("title={}", escape_property(TITLE));
+                if let Some(file) = file {
+                    properties.push_str(&format!(",file={}", escape_property(file)));
+                }
+                format!("::{command} {properties}::{}", escape_data(message))
+            }
+        }
+    }
+}
+
+/// Writes an annotation for each warning or error event; see [`Format::layer`].
+pub struct Annotations {
+    format: Format,
+}
+
+impl<S: Subscriber> Layer<S> for Annotations {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let level = *event.metadata().level();
+        if level > Level::WARN {
+            return;
+        }
+
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let annotation = self
+            .format
+            .annotation(level, &fields.message(), fields.path.as_deref());
+        let _ = writeln!(std::io::stderr().lock(), "{annotation}");
+    }
+}
+
+/// The fields of an event, rendered for an annotation.
+#[derive(Debug, Default)]
+struct Fields {
+    message: String,
+
+    /// The path the event is about, if any, which is reported as the file of the annotation.
+    path: Option<String>,
+
+    /// Other fields, rendered as `name=value`.
+    /// Fields whose values span several lines are omitted.
+    rest: Vec<String>,
+}
+
+impl Fields {
+    fn message(&self) -> String {
+        match self.rest.is_empty() {
+            true => self.message.clone(),
+            false => format!("{} ({})", self.message, self.rest.join(", ")),
+        }
+    }
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message = value.to_string(),
+            "path" => self.path = Some(value.to_string()),
+            name => self.rest.push(format!("{name}={value}")),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let value = format!("{value:?}");
+        match field.name() {
+            // Values spanning several lines (like error reports) are too long for an annotation;
+            // they're still written to the log.
+            name if name != "message" && value.contains('\n') => {}
+            "message" => self.message = value,
+            // Paths recorded with `?path` are quoted by their debug representation.
+            "path" => self.path = Some(value.trim_matches('"').to_string()),
+            name => self.rest.push(format!("{name}={value}")),
+        }
+    }
+}
+
+/// Escape the message of a workflow command.
+fn escape_data(value: &str) -> String {
+    value
+        .replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+/// Escape the value of a workflow command property.
+fn escape_property(value: &str) -> String {
+    escape_data(value).replace(':', "%3A").replace(',', "%2C")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    #[test_case(Level::WARN, "skipped layer", None, "::warning title=circe::skipped layer"; "warning")]
+    #[test_case(Level::ERROR, "failed\nbadly", None, "::error title=circe::failed%0Abadly"; "error_multiline")]
+    #[test_case(Level::WARN, "100% done", Some("a:b,c"), "::warning title=circe,file=a%3Ab%2Cc::100%25 done"; "escaped")]
+    #[test]
+    fn annotation(level: Level, message: &str, file: Option<&str>, expected: &str) {
+        pretty_assertions::assert_eq!(expected, Format::Github.annotation(level, message, file));
+    }
+}
@@ -0,0 +1,292 @@
+//! Processes many images in one invocation, for `extract`, `list`, and `reexport` with `--batch`.
+//!
+//! The batch file lists one image per line, as it would be passed on the command line;
+//! blank lines and lines starting with `#` are ignored:
+//!
+//! ```text
+//! # Base images
+//! docker.io/library/alpine:latest
+//! docker.io/library/ubuntu:24.04
+//!
+//! ./images/app.tar
+//! ```
+//!
+//! Images are processed in parallel with every other option shared between them, up to `--jobs` at once,
+//! and each is written to its own file or directory inside the output, named after the image (see [`output_name`]).
+//! A combined report of every image is printed once they're done, in the same format as `circe run`.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use circe_lib::Identity;
+use clap::Args;
+use color_eyre::{
+    eyre::{bail, Context, Result},
+    Section, SectionExt,
+};
+use serde::Serialize;
+use tokio::{io::AsyncReadExt, sync::Semaphore, task::JoinSet};
+use tracing::{info, warn};
+
+/// The number of images processed at once if `--jobs` isn't provided.
+const DEFAULT_JOBS: usize = 4;
+
+/// Options for processing many images with one invocation.
+#[derive(Debug, Clone, Default, Args)]
+pub struct Batch {
+    /// Process every image listed in this file instead of a single image
+    ///
+    /// The file lists one image per line; blank lines and lines starting with `#` are ignored.
+    /// Use `-` to read the list from stdin.
+    /// Each image is written inside the output, named after the image, and a combined report is printed at the end.
+    /// Since no image is provided, the output is then the first positional argument.
+    #[arg(long, value_name = "FILE")]
+    pub batch: Option<PathBuf>,
+
+    /// The number of images processed in parallel with `--batch` [default: 4]
+    ///
+    /// Each image is processed on its own thread, so decompressing, hashing, and writing images
+    /// are spread across cores as well as waiting on the network.
+    #[arg(long, requires = "batch")]
+    pub jobs: Option<NonZeroUsize>,
+
+    /// Also write the combined report for `--batch` to this file
+    #[arg(long, requires = "batch")]
+    pub batch_report: Option<PathBuf>,
+}
+
+/// The outcome of every image in a batch, or every target in a job.
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    pub succeeded: usize,
+    pub failed: usize,
+    pub targets: Vec<Outcome>,
+}
+
+/// The outcome of an image.
+#[derive(Debug, Serialize)]
+pub struct Outcome {
+    pub image: String,
+    pub output: PathBuf,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identity: Option<Identity>,
+
+    /// Why the image failed, if it did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Outcome {
+    /// The outcome of an image that failed.
+    pub fn failed(image: String, output: PathBuf, err: &color_eyre::Report) -> Self {
+        warn!(%image, ?err, "image failed");
+        Self {
+            image,
+            output,
+            digest: None,
+            identity: None,
+            error: Some(format!("{err:#}")),
+        }
+    }
+}
+
+impl Summary {
+    pub fn new(targets: Vec<Outcome>) -> Self {
+        let failed = targets
+            .iter()
+            .filter(|outcome| outcome.error.is_some())
+            .count();
+        Self {
+            succeeded: targets.len() - failed,
+            failed,
+            targets,
+        }
+    }
+
+    /// Print the summary, also writing it to the path if there is one,
+    /// then fail if any image failed.
+    pub async fn report(&self, path: Option<&Path>) -> Result<()> {
+        let rendered = serde_json::to_string_pretty(self).context("render report")?;
+        if let Some(path) = path {
+            tokio::fs::write(path, &rendered)
+                .await
+                .context("write report")
+                .with_section(|| path.display().to_string().header("Path:"))?;
+        }
+        println!("{rendered}");
+
+        if self.failed > 0 {
+            bail!(
+                "{} of {} targets failed",
+                self.failed,
+                self.succeeded + self.failed
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Batch {
+    /// Read the images listed in the batch file.
+    pub async fn images(&self) -> Result<Vec<String>> {
+        let Some(path) = &self.batch else {
+            bail!("no batch file provided");
+        };
+
+        let content = if path == Path::new("-") {
+            let mut content = String::new();
+            tokio::io::stdin()
+                .read_to_string(&mut content)
+                .await
+                .context("read batch from stdin")?;
+            content
+        } else {
+            tokio::fs::read_to_string(path)
+                .await
+                .context("read batch file")
+                .with_section(|| path.display().to_string().header("Path:"))?
+        };
+
+        let images = parse(&content);
+        if images.is_empty() {
+            bail!("batch file lists no images");
+        }
+        Ok(images)
+    }
+
+    /// Process every image in the batch, up to `--jobs` at once, then report the outcome of each.
+    ///
+    /// `process` is given each image along with the name of its output (see [`output_name`]),
+    /// and reports its outcome rather than failing so that the rest of the batch continues.
+    pub async fn run<F, Fut>(&self, process: F) -> Result<()>
+    where
+        F: Fn(String, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Outcome> + 'static,
+    {
+        let images = self.images().await?;
+        let mut names = HashMap::new();
+        for image in &images {
+            if let Some(other) = names.insert(output_name(image), image) {
+                bail!("images '{other}' and '{image}' would be written to the same output");
+            }
+        }
+        let jobs = self.jobs.map_or(DEFAULT_JOBS, NonZeroUsize::get);
+        info!(images = images.len(), jobs, "processing batch");
+
+        // Layers are read as streams that can't be sent between threads,
+        // so each image is processed on a blocking thread with its own runtime, like `sandbox::run`.
+        // Waiting for them here instead of blocking keeps this task responsive to interruption.
+        let process = Arc::new(process);
+        let permits = Arc::new(Semaphore::new(jobs));
+        let mut set = JoinSet::new();
+        for (index, image) in images.into_iter().enumerate() {
+            let (process, permits) = (process.clone(), permits.clone());
+            set.spawn(async move {
+                let _permit = permits.acquire_owned().await;
+                let outcome = tokio::task::spawn_blocking(move || {
+                    let name = output_name(&image);
+                    tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()
+                        .context("build runtime")
+                        .map(|runtime| runtime.block_on(process(image, name)))
+                })
+                .await
+                .context("join batch task")??;
+                Result::<_>::Ok((index, outcome))
+            });
+        }
+
+        let mut outcomes = Vec::new();
+        while let Some(result) = set.join_next().await {
+            outcomes.push(result.context("join batch task")??);
+        }
+        outcomes.sort_by_key(|(index, _)| *index);
+        let outcomes = outcomes.into_iter().map(|(_, outcome)| outcome).collect();
+        Summary::new(outcomes)
+            .report(self.batch_report.as_deref())
+            .await
+    }
+}
+
+/// The output provided on the command line along with `--batch`, or the working directory if there isn't one.
+///
+/// No image is provided with `--batch`, so an output provided positionally is parsed as the image.
+pub fn output(image: &str, output: Option<&Path>) -> Result<PathBuf> {
+    match (image, output) {
+        ("", Some(output)) => Ok(output.to_path_buf()),
+        ("", None) => Ok(PathBuf::from(".")),
+        (image, None) => Ok(PathBuf::from(image)),
+        (image, Some(output)) => bail!(
+            "only the output can be provided with --batch, not both '{image}' and '{}'",
+            output.display()
+        ),
+    }
+}
+
+/// The images listed in the content of a batch file.
+fn parse(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+/// The name of the file or directory to which the image is written in a batch:
+/// the image with characters that aren't safe in file names replaced.
+fn output_name(image: &str) -> String {
+    image
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '_' | '-' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    #[test]
+    fn parse_batch() {
+        let content =
+            "# base images\ndocker.io/library/alpine:latest\n\n  ubuntu:24.04  \n#ubuntu:22.04\n";
+        pretty_assertions::assert_eq!(
+            vec!["docker.io/library/alpine:latest", "ubuntu:24.04"],
+            parse(content)
+        );
+    }
+
+    #[test_case("", Some("out"), Some("out"); "flag")]
+    #[test_case("out", None, Some("out"); "positional")]
+    #[test_case("", None, Some("."); "default")]
+    #[test_case("alpine", Some("out"), None; "image_and_output")]
+    #[test]
+    fn batch_output(image: &str, output: Option<&str>, expected: Option<&str>) {
+        pretty_assertions::assert_eq!(
+            expected.map(PathBuf::from),
+            super::output(image, output.map(Path::new)).ok()
+        );
+    }
+
+    #[test_case("docker.io/library/alpine:latest", "docker.io_library_alpine_latest"; "reference")]
+    #[test_case("alpine@sha256:abc", "alpine_sha256_abc"; "digest")]
+    #[test_case("./images/app.tar", "._images_app.tar"; "path")]
+    #[test]
+    fn batch_output_name(image: &str, expected: &str) {
+        pretty_assertions::assert_eq!(expected, output_name(image));
+    }
+}
@@ -0,0 +1,41 @@
+use circe_lib::capabilities::Capabilities;
+use clap::{CommandFactory, Parser};
+use color_eyre::eyre::{Context, Result};
+use serde::Serialize;
+
+use crate::Cli;
+
+/// The version of the output document; incremented when existing fields change meaning or are removed.
+const SCHEMA: u32 = 1;
+
+#[derive(Debug, Parser)]
+pub struct Options {}
+
+/// The capabilities of this build of circe reported by `capabilities`.
+#[derive(Debug, Serialize)]
+struct Report {
+    schema: u32,
+    version: &'static str,
+    commands: Vec<String>,
+
+    #[serde(flatten)]
+    library: Capabilities,
+}
+
+#[tracing::instrument]
+pub async fn main(_opts: Options) -> Result<()> {
+    let report = Report {
+        schema: SCHEMA,
+        version: env!("CARGO_PKG_VERSION"),
+        commands: Cli::command()
+            .get_subcommands()
+            .map(|command| command.get_name().to_string())
+            .collect(),
+        library: Capabilities::current(),
+    };
+
+    let rendered = serde_json::to_string_pretty(&report).context("render capabilities")?;
+    println!("{rendered}");
+
+    Ok(())
+}
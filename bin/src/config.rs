@@ -0,0 +1,560 @@
+//! Defaults read from a configuration file, so they don't need to be provided on every invocation.
+//!
+//! The file is read from `$CIRCE_CONFIG` if it's set, otherwise from `circe/config.toml`
+//! in `$XDG_CONFIG_HOME` (or `~/.config` if that isn't set); a missing file is the same as an empty one.
+//! For example:
+//!
+//! ```toml
+//! platform = "linux/amd64"
+//! oci_base = "ghcr.io"
+//! oci_namespace = "fossas"
+//! allowed_hosts = ["ghcr.io", "docker.io"]
+//!
+//! [registry."ghcr.io"]
+//! username = "me"
+//! password = "token"
+//!
+//! [extract]
+//! layers = "separate"
+//! file_glob = ["**/*.jar"]
+//! ```
+//!
+//! Values are taken from the first of these that provides them:
+//! 1. Command line flags
+//! 2. Environment variables (e.g. `CIRCE_PLATFORM`)
+//! 3. The configuration file
+//! 4. The built-in defaults
+//!
+//! Unknown keys are rejected, so that a typo doesn't silently leave a setting unapplied.
+//!
+//! Registry credentials can also be provided through environment variables (see [`env_auth`]),
+//! so that CI systems don't need to write them to a file or pass them on the command line.
+
+use std::{collections::HashMap, path::PathBuf, str::FromStr};
+
+use circe_lib::{
+    AllowedHosts, Authentication, OciDefaults, Platform, Reference, OCI_BASE_VAR, OCI_DEFAULT_BASE,
+    OCI_DEFAULT_NAMESPACE, OCI_NAMESPACE_VAR,
+};
+use clap::{Args, ValueEnum};
+use color_eyre::{
+    eyre::{bail, eyre, Context, OptionExt, Result},
+    Section, SectionExt,
+};
+use derive_more::Debug;
+use serde::Deserialize;
+use toml_edit::{DocumentMut, Item, TableLike};
+
+use crate::extract::Mode;
+
+/// Set to the path of the configuration file to read it instead of the default location.
+pub const CONFIG_VAR: &str = "CIRCE_CONFIG";
+
+/// The prefix of the variables that provide credentials for a single registry host;
+/// see [`env_auth`] for how the host is named.
+pub const AUTH_VAR_PREFIX: &str = "CIRCE_AUTH_";
+
+/// Set to a JSON object mapping registry hosts to credentials; see [`env_auth`].
+pub const REGISTRY_AUTH_VAR: &str = "CIRCE_REGISTRY_AUTH";
+
+/// Defaults read from the configuration file.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Config {
+    /// The platform to select if none is provided.
+    pub platform: Option<Platform>,
+
+    /// The registry host used to complete partial image references; see [`ReferenceDefaults`].
+    pub oci_base: Option<String>,
+
+    /// The namespace used to complete partial image references; see [`ReferenceDefaults`].
+    pub oci_namespace: Option<String>,
+
+    /// The only registry hosts that may be contacted; see [`HostAllowlist`].
+    pub allowed_hosts: Option<Vec<String>>,
+
+    /// Credentials for each registry host, used if none are provided.
+    pub registries: HashMap<String, Credentials>,
+
+    /// Defaults for `extract`.
+    pub extract: Extract,
+}
+
+/// Credentials for a registry host.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Credentials {
+    pub username: String,
+
+    #[debug(skip)]
+    pub password: String,
+}
+
+/// Defaults for `extract`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Extract {
+    pub layers: Option<Mode>,
+    pub layer_glob: Option<Vec<String>>,
+    pub layer_regex: Option<Vec<String>>,
+    pub file_glob: Option<Vec<String>>,
+    pub file_regex: Option<Vec<String>>,
+}
+
+impl Config {
+    /// The location of the configuration file, if one can be determined.
+    pub fn path() -> Option<PathBuf> {
+        if let Some(path) = std::env::var_os(CONFIG_VAR) {
+            return Some(PathBuf::from(path));
+        }
+
+        let dir = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_absolute())
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        Some(dir.join("circe").join("config.toml"))
+    }
+
+    /// Read the configuration file.
+    ///
+    /// If the file doesn't exist the defaults are used, unless it was named with [`CONFIG_VAR`].
+    pub async fn load() -> Result<Self> {
+        let Some(path) = Self::path() else {
+            return Ok(Self::default());
+        };
+
+        let content = match tokio::fs::read_to_string(&path).await {
+            Ok(content) => content,
+            Err(err)
+                if err.kind() == std::io::ErrorKind::NotFound
+                    && std::env::var_os(CONFIG_VAR).is_none() =>
+            {
+                return Ok(Self::default());
+            }
+            Err(err) => {
+                return Err(err)
+                    .context("read config file")
+                    .with_section(|| path.display().to_string().header("Path:"))
+            }
+        };
+
+        Self::parse(&content).with_section(|| path.display().to_string().header("Path:"))
+    }
+
+    /// Parse the content of a configuration file.
+    pub fn parse(content: &str) -> Result<Self> {
+        let document = DocumentMut::from_str(content).context("parse config file")?;
+        let mut config = Self::default();
+        for (key, item) in document.iter() {
+            match key {
+                "platform" => config.platform = Some(parse_str(key, item)?),
+                "oci_base" => config.oci_base = Some(string(key, item)?),
+                "oci_namespace" => config.oci_namespace = Some(string(key, item)?),
+                "allowed_hosts" => config.allowed_hosts = Some(strings(key, item)?),
+                "registry" => {
+                    for (host, item) in table(key, item)?.iter() {
+                        let credentials = Credentials::parse(table(host, item)?)
+                            .with_context(|| format!("parse credentials for {host:?}"))?;
+                        config.registries.insert(host.to_string(), credentials);
+                    }
+                }
+                "extract" => config.extract = Extract::parse(table(key, item)?)?,
+                key => bail!("unknown key: {key:?}"),
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Credentials for the registry hosting the image, if configured.
+    pub fn auth(&self, image: &str) -> Option<Authentication> {
+        let reference = Reference::from_str(image).ok()?;
+        self.registries
+            .get(&reference.host)
+            .map(|credentials| Authentication::basic(&credentials.username, &credentials.password))
+    }
+}
+
+impl Credentials {
+    fn parse(table: &dyn TableLike) -> Result<Self> {
+        let mut username = None;
+        let mut password = None;
+        for (key, item) in table.iter() {
+            match key {
+                "username" => username = Some(string(key, item)?),
+                "password" => password = Some(string(key, item)?),
+                key => bail!("unknown key: {key:?}"),
+            }
+        }
+
+        Ok(Self {
+            username: username.ok_or_eyre("username is required")?,
+            password: password.ok_or_eyre("password is required")?,
+        })
+    }
+}
+
+impl Extract {
+    fn parse(table: &dyn TableLike) -> Result<Self> {
+        let mut extract = Self::default();
+        for (key, item) in table.iter() {
+            match key {
+                "layers" => {
+                    let mode = string(key, item)?;
+                    let mode = Mode::from_str(&mode, true)
+                        .map_err(|err| eyre!("invalid value for {key:?}: {err}"))?;
+                    extract.layers = Some(mode);
+                }
+                "layer_glob" => extract.layer_glob = Some(strings(key, item)?),
+                "layer_regex" => extract.layer_regex = Some(strings(key, item)?),
+                "file_glob" => extract.file_glob = Some(strings(key, item)?),
+                "file_regex" => extract.file_regex = Some(strings(key, item)?),
+                key => bail!("unknown key: extract.{key}"),
+            }
+        }
+
+        Ok(extract)
+    }
+}
+
+/// Flags for the defaults used to complete partial image references:
+/// for example `ubuntu` is expanded to `{base}/{namespace}/ubuntu`.
+#[derive(Debug, Default, Args)]
+pub struct ReferenceDefaults {
+    /// The registry host used for image references that don't name one [default: docker.io]
+    ///
+    /// Provide a host, optionally with a port (e.g. `ghcr.io` or `localhost:5000`), without a scheme or path.
+    #[arg(long, global = true, env = OCI_BASE_VAR)]
+    pub oci_base: Option<String>,
+
+    /// The namespace used for image references that only name an image [default: library]
+    ///
+    /// Provide one or more path components (e.g. `fossas` or `somecorp/someproject`), without a leading or trailing `/`.
+    #[arg(long, global = true, env = OCI_NAMESPACE_VAR)]
+    pub oci_namespace: Option<String>,
+}
+
+impl ReferenceDefaults {
+    /// Resolve the defaults from the flags (or environment variables) and the configuration file,
+    /// validating them so that mistakes are reported before any reference is parsed.
+    pub fn resolve(&self, config: &Config) -> Result<OciDefaults> {
+        let base = self
+            .oci_base
+            .as_deref()
+            .or(config.oci_base.as_deref())
+            .unwrap_or(OCI_DEFAULT_BASE);
+        let namespace = self
+            .oci_namespace
+            .as_deref()
+            .or(config.oci_namespace.as_deref())
+            .unwrap_or(OCI_DEFAULT_NAMESPACE);
+
+        OciDefaults::new(base, namespace)
+            .context("invalid default registry for image references")
+            .with_suggestion(|| {
+                format!("check --oci-base and --oci-namespace, {OCI_BASE_VAR} and {OCI_NAMESPACE_VAR}, or 'oci_base' and 'oci_namespace' in the configuration file")
+            })
+    }
+}
+
+/// Flags restricting which registry hosts circe contacts.
+#[derive(Debug, Default, Args)]
+pub struct HostAllowlist {
+    /// Only contact these registry hosts, failing before any network access for references to other hosts
+    ///
+    /// Provide hosts as they appear in image references, optionally with a port (e.g. `ghcr.io` or `localhost:5000`).
+    /// References are checked after they're completed with `--oci-base`, so `ubuntu` is checked as `docker.io` by default.
+    /// Use this when processing untrusted lists of references, so they can't make circe contact arbitrary hosts.
+    #[arg(
+        long,
+        global = true,
+        env = "CIRCE_ALLOWED_HOSTS",
+        value_delimiter = ','
+    )]
+    pub allowed_hosts: Option<Vec<String>>,
+}
+
+impl HostAllowlist {
+    /// Resolve the allowed hosts from the flag (or environment variable) and the configuration file;
+    /// if neither provides them, every host is allowed.
+    pub fn resolve(&self, config: &Config) -> Result<Option<AllowedHosts>> {
+        let Some(hosts) = self
+            .allowed_hosts
+            .as_ref()
+            .or(config.allowed_hosts.as_ref())
+        else {
+            return Ok(None);
+        };
+
+        AllowedHosts::new(hosts)
+            .map(Some)
+            .context("invalid allowed registry hosts")
+            .with_suggestion(|| {
+                "check --allowed-hosts, CIRCE_ALLOWED_HOSTS, or 'allowed_hosts' in the configuration file"
+            })
+    }
+}
+
+/// Read credentials for the registry host from the environment, if provided.
+///
+/// Credentials are read from the first of these that has them:
+/// 1. `CIRCE_AUTH_<HOST>`, in the form `username:password`. The host is uppercased,
+///    and each character other than a letter or digit is replaced with `_`:
+///    for example, `CIRCE_AUTH_GHCR_IO` for `ghcr.io` or `CIRCE_AUTH_LOCALHOST_5000` for `localhost:5000`.
+/// 2. `CIRCE_REGISTRY_AUTH`, a JSON object mapping hosts to credentials:
+///    `{"ghcr.io": {"username": "me", "password": "token"}}`.
+pub fn env_auth(host: &str) -> Result<Option<Authentication>> {
+    env_auth_from(host, |name| std::env::var(name).ok())
+}
+
+fn env_auth_from(
+    host: &str,
+    var: impl Fn(&str) -> Option<String>,
+) -> Result<Option<Authentication>> {
+    let name = auth_var(host);
+    if let Some(value) = var(&name) {
+        // The value is never included in errors, since it's a secret.
+        let (username, password) = value
+            .split_once(':')
+            .ok_or_else(|| eyre!("{name} must be in the form 'username:password'"))?;
+        return Ok(Some(Authentication::basic(username, password)));
+    }
+
+    let Some(value) = var(REGISTRY_AUTH_VAR) else {
+        return Ok(None);
+    };
+    let mut hosts = serde_json::from_str::<HashMap<String, Credentials>>(&value)
+        .map_err(|err| {
+            eyre!(
+                "invalid value at line {}, column {}",
+                err.line(),
+                err.column()
+            )
+        })
+        .with_context(|| format!("parse {REGISTRY_AUTH_VAR} as a JSON object of credentials"))?;
+    Ok(hosts
+        .remove(host)
+        .map(|credentials| Authentication::basic(credentials.username, credentials.password)))
+}
+
+/// The name of the variable that provides credentials for the host.
+fn auth_var(host: &str) -> String {
+    let host = host
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_uppercase(),
+            false => '_',
+        })
+        .collect::<String>();
+    format!("{AUTH_VAR_PREFIX}{host}")
+}
+
+fn table<'a>(key: &str, item: &'a Item) -> Result<&'a dyn TableLike> {
+    item.as_table_like()
+        .ok_or_else(|| eyre!("{key:?} must be a table"))
+}
+
+fn string(key: &str, item: &Item) -> Result<String> {
+    item.as_str()
+        .map(String::from)
+        .ok_or_else(|| eyre!("{key:?} must be a string"))
+}
+
+fn strings(key: &str, item: &Item) -> Result<Vec<String>> {
+    let array = item
+        .as_array()
+        .ok_or_else(|| eyre!("{key:?} must be an array of strings"))?;
+    array
+        .iter()
+        .map(|value| value.as_str().map(String::from))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| eyre!("{key:?} must be an array of strings"))
+}
+
+fn parse_str<T>(key: &str, item: &Item) -> Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    string(key, item)?
+        .parse()
+        .with_context(|| format!("invalid value for {key:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    #[test]
+    fn parses() {
+        let content = r#"
+            platform = "linux/arm64"
+            oci_base = "ghcr.io"
+            oci_namespace = "fossas"
+            allowed_hosts = ["ghcr.io"]
+
+            [registry."ghcr.io"]
+            username = "me"
+            password = "token"
+
+            [extract]
+            layers = "base-and-squash-other"
+            file_glob = ["**/*.jar"]
+        "#;
+
+        let expected = Config {
+            platform: Some(Platform::linux_arm64()),
+            oci_base: Some(String::from("ghcr.io")),
+            oci_namespace: Some(String::from("fossas")),
+            allowed_hosts: Some(vec![String::from("ghcr.io")]),
+            registries: HashMap::from([(
+                String::from("ghcr.io"),
+                Credentials {
+                    username: String::from("me"),
+                    password: String::from("token"),
+                },
+            )]),
+            extract: Extract {
+                layers: Some(Mode::BaseAndSquashOther),
+                file_glob: Some(vec![String::from("**/*.jar")]),
+                ..Default::default()
+            },
+        };
+        pretty_assertions::assert_eq!(expected, Config::parse(content).expect("parse config"));
+    }
+
+    #[test_case("platfrom = \"linux/amd64\""; "unknown_key")]
+    #[test_case("platform = \"linux\""; "invalid_platform")]
+    #[test_case("[extract]\nlayers = \"flatten\""; "invalid_mode")]
+    #[test_case("[extract]\nfile_glob = \"*.jar\""; "glob_not_array")]
+    #[test_case("[registry.\"ghcr.io\"]\nusername = \"me\""; "missing_password")]
+    #[test_case("platform = "; "invalid_toml")]
+    #[test]
+    fn rejects(content: &str) {
+        assert!(Config::parse(content).is_err(), "config is rejected");
+    }
+
+    #[test_case(Some("ghcr.io"), None, "ghcr.io", "fossas"; "flag_base")]
+    #[test_case(None, Some("somecorp/app"), "host.dev", "somecorp/app"; "flag_namespace")]
+    #[test_case(None, None, "host.dev", "fossas"; "configured")]
+    #[test]
+    fn reference_defaults(
+        base: Option<&str>,
+        namespace: Option<&str>,
+        expected_base: &str,
+        expected_namespace: &str,
+    ) {
+        let config = Config {
+            oci_base: Some(String::from("host.dev")),
+            oci_namespace: Some(String::from("fossas")),
+            ..Default::default()
+        };
+        let flags = ReferenceDefaults {
+            oci_base: base.map(String::from),
+            oci_namespace: namespace.map(String::from),
+        };
+
+        let defaults = flags.resolve(&config).expect("resolve defaults");
+        pretty_assertions::assert_eq!(expected_base, defaults.base());
+        pretty_assertions::assert_eq!(expected_namespace, defaults.namespace());
+    }
+
+    #[test]
+    fn rejects_reference_defaults() {
+        let flags = ReferenceDefaults {
+            oci_base: Some(String::from("https://ghcr.io/")),
+            ..Default::default()
+        };
+        assert!(
+            flags.resolve(&Config::default()).is_err(),
+            "defaults are rejected"
+        );
+    }
+
+    #[test_case(None, Some(&["ghcr.io"]), Some(&["ghcr.io"]); "configured")]
+    #[test_case(Some(&["GHCR.io", "localhost:5000"]), Some(&["docker.io"]), Some(&["ghcr.io", "localhost:5000"]); "flag")]
+    #[test_case(None, None, None; "unrestricted")]
+    #[test]
+    fn allowed_hosts(
+        flag: Option<&[&str]>,
+        configured: Option<&[&str]>,
+        expected: Option<&[&str]>,
+    ) {
+        let config = Config {
+            allowed_hosts: configured.map(|hosts| hosts.iter().map(|h| h.to_string()).collect()),
+            ..Default::default()
+        };
+        let flags = HostAllowlist {
+            allowed_hosts: flag.map(|hosts| hosts.iter().map(|h| h.to_string()).collect()),
+        };
+
+        let allowed = flags.resolve(&config).expect("resolve allowed hosts");
+        let hosts = allowed.as_ref().map(|allowed| allowed.hosts());
+        let expected =
+            expected.map(|hosts| hosts.iter().map(|h| h.to_string()).collect::<Vec<_>>());
+        pretty_assertions::assert_eq!(expected.as_deref(), hosts);
+    }
+
+    #[test]
+    fn rejects_allowed_hosts() {
+        let flags = HostAllowlist {
+            allowed_hosts: Some(vec![String::from("https://ghcr.io")]),
+        };
+        assert!(
+            flags.resolve(&Config::default()).is_err(),
+            "allowed hosts are rejected"
+        );
+    }
+
+    #[test_case("ghcr.io/fossas/circe:latest", Some("me"); "configured_host")]
+    #[test_case("docker.io/library/ubuntu:latest", None; "other_host")]
+    #[test]
+    fn auth(image: &str, expected: Option<&str>) {
+        let config =
+            Config::parse("[registry.\"ghcr.io\"]\nusername = \"me\"\npassword = \"token\"")
+                .expect("parse config");
+        let username = config.auth(image).map(|auth| match auth {
+            Authentication::Basic { username, .. } => username,
+            auth => panic!("unexpected authentication: {auth:?}"),
+        });
+        pretty_assertions::assert_eq!(expected.map(String::from), username);
+    }
+
+    #[test_case("ghcr.io", "CIRCE_AUTH_GHCR_IO"; "domain")]
+    #[test_case("localhost:5000", "CIRCE_AUTH_LOCALHOST_5000"; "port")]
+    #[test_case("my-registry.example.com", "CIRCE_AUTH_MY_REGISTRY_EXAMPLE_COM"; "dash")]
+    #[test]
+    fn auth_var(host: &str, expected: &str) {
+        pretty_assertions::assert_eq!(expected, super::auth_var(host));
+    }
+
+    #[test_case(&[("CIRCE_AUTH_GHCR_IO", "me:to:ken")], Some(("me", "to:ken")); "host_var")]
+    #[test_case(&[(REGISTRY_AUTH_VAR, r#"{"ghcr.io":{"username":"me","password":"token"}}"#)], Some(("me", "token")); "json")]
+    #[test_case(&[("CIRCE_AUTH_GHCR_IO", "me:token"), (REGISTRY_AUTH_VAR, r#"{"ghcr.io":{"username":"other","password":"other"}}"#)], Some(("me", "token")); "host_var_first")]
+    #[test_case(&[(REGISTRY_AUTH_VAR, r#"{"docker.io":{"username":"me","password":"token"}}"#)], None; "json_other_host")]
+    #[test_case(&[("CIRCE_AUTH_DOCKER_IO", "me:token")], None; "other_host")]
+    #[test]
+    fn env_auth(vars: &[(&str, &str)], expected: Option<(&str, &str)>) {
+        let auth = env_auth_from("ghcr.io", |name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string())
+        })
+        .expect("read auth");
+        let credentials = auth.map(|auth| match auth {
+            Authentication::Basic { username, password } => (username, password),
+            auth => panic!("unexpected authentication: {auth:?}"),
+        });
+        let expected =
+            expected.map(|(username, password)| (username.to_string(), password.to_string()));
+        pretty_assertions::assert_eq!(expected, credentials);
+    }
+
+    #[test_case("CIRCE_AUTH_GHCR_IO", "token"; "host_var_without_username")]
+    #[test_case(REGISTRY_AUTH_VAR, r#"{"ghcr.io":{"username":"me"}}"#; "json_missing_password")]
+    #[test_case(REGISTRY_AUTH_VAR, "me:token"; "json_invalid")]
+    #[test]
+    fn rejects_env_auth(name: &str, value: &str) {
+        let auth = env_auth_from("ghcr.io", |var| (var == name).then(|| value.to_string()));
+        assert!(auth.is_err(), "credentials are rejected");
+    }
+}
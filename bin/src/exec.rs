@@ -0,0 +1,131 @@
+//! Runs user commands after a command completes, for `--exec`.
+
+use color_eyre::eyre::{Context, Result};
+use std::{
+    ffi::OsStr,
+    path::Path,
+    process::{ExitStatus, Stdio},
+};
+use tokio::process::Command;
+use tracing::{info, warn};
+
+/// The placeholder replaced with the output path in hook commands, like `find -exec`.
+const PLACEHOLDER: &str = "{}";
+
+/// Environment variables passed through to hook commands.
+///
+/// Everything else (for example registry credentials or tokens in CI)
+/// is removed so that hooks don't have access to it by accident.
+const ALLOWED_ENV: &[&str] = &[
+    "HOME", "LANG", "LOGNAME", "PATH", "SHELL", "TERM", "TMPDIR", "TZ", "USER",
+];
+
+/// Run the hook command with `sh -c`, substituting the output path for each `{}` in the command.
+///
+/// The hook inherits stdout and stderr, but not stdin.
+/// If the hook fails this returns [`HookFailed`], with which circe exits with the hook's status
+/// so that pipelines observe it.
+pub async fn run(command: &str, output: &Path) -> Result<()> {
+    let status = status(command, output).await?;
+    if !status.success() {
+        return Err(HookFailed::new(command, status).into());
+    }
+
+    Ok(())
+}
+
+/// Run the hook command, reporting its exit status.
+async fn status(command: &str, output: &Path) -> Result<ExitStatus> {
+    let command = substitute(command, output);
+    info!(%command, "running hook");
+
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(&command)
+        .env_clear()
+        .envs(std::env::vars_os().filter(|(name, _)| allowed(name)))
+        .stdin(Stdio::null())
+        .status()
+        .await
+        .with_context(|| format!("run hook `{command}`"))?;
+
+    if !status.success() {
+        warn!(%command, %status, "hook failed");
+    }
+
+    Ok(status)
+}
+
+/// A hook exited unsuccessfully.
+#[derive(Debug)]
+pub struct HookFailed {
+    command: String,
+    status: ExitStatus,
+}
+
+impl HookFailed {
+    pub fn new(command: &str, status: ExitStatus) -> Self {
+        Self {
+            command: command.to_string(),
+            status,
+        }
+    }
+
+    /// The code with which circe exits for the failure: the hook's own exit code,
+    /// or 1 if it was terminated by a signal.
+    pub fn code(&self) -> i32 {
+        self.status.code().unwrap_or(1)
+    }
+}
+
+impl std::fmt::Display for HookFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "hook `{}` failed: {}", self.command, self.status)
+    }
+}
+
+impl std::error::Error for HookFailed {}
+
+/// Replace each placeholder in the command with the path, quoted for the shell.
+fn substitute(command: &str, path: &Path) -> String {
+    let path = path.to_string_lossy().replace('\'', r"'\''");
+    command.replace(PLACEHOLDER, &format!("'{path}'"))
+}
+
+/// Whether the variable is passed to hooks; names that aren't valid UTF-8 never are.
+fn allowed(name: &OsStr) -> bool {
+    name.to_str()
+        .is_some_and(|name| ALLOWED_ENV.contains(&name) || name.starts_with("LC_"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    #[test_case("ls {}", "/tmp/out", "ls '/tmp/out'"; "single")]
+    #[test_case("tar -C {} -cf {}.tar .", "/tmp/out", "tar -C '/tmp/out' -cf '/tmp/out'.tar ."; "multiple")]
+    #[test_case("ls {}", "/tmp/my out", "ls '/tmp/my out'"; "space")]
+    #[test_case("ls {}", "/tmp/it's", r"ls '/tmp/it'\''s'"; "quote")]
+    #[test_case("echo done", "/tmp/out", "echo done"; "no_placeholder")]
+    #[test]
+    fn substitutes(command: &str, path: &str, expected: &str) {
+        pretty_assertions::assert_eq!(expected, substitute(command, Path::new(path)));
+    }
+
+    #[test_case("PATH", true; "path")]
+    #[test_case("LC_ALL", true; "locale")]
+    #[test_case("AWS_SECRET_ACCESS_KEY", false; "secret")]
+    #[test_case("DOCKER_AUTH_CONFIG", false; "docker")]
+    #[test]
+    fn allows_env(name: &str, expected: bool) {
+        pretty_assertions::assert_eq!(expected, allowed(OsStr::new(name)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn skips_non_utf8_env() {
+        use std::os::unix::ffi::OsStrExt;
+        assert!(!allowed(OsStr::from_bytes(b"LC_\xff")));
+    }
+}
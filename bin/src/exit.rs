@@ -0,0 +1,214 @@
+//! Reports why circe failed, so that scripts which run it can react to the cause.
+//!
+//! Each kind of failure exits with its own code, and with `--error-format json`
+//! the error is written to stderr as a single line of JSON instead of a human-readable report.
+//! Failures are classified by the [`circe_lib::Error`] in the error's chain of causes, if any,
+//! or by the [`HookFailed`] error of an `--exec` hook.
+
+use clap::ValueEnum;
+use color_eyre::eyre::Report;
+use serde::{Serialize, Serializer};
+
+use crate::exec::HookFailed;
+
+/// How the error that caused circe to fail is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ErrorFormat {
+    /// A human-friendly report, with context and suggestions.
+    Human,
+
+    /// One line of JSON, with the kind of failure, its exit code, and the chain of causes.
+    Json,
+}
+
+/// The kind of failure that caused circe to exit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// Any failure not covered by the other kinds.
+    Other,
+
+    /// The registry (or Docker daemon) rejected the credentials, or requires credentials.
+    Auth,
+
+    /// The image, tag, layer, or file doesn't exist.
+    NotFound,
+
+    /// Communication with the registry or Docker daemon failed.
+    Network,
+
+    /// The image uses a format circe doesn't support, like an unknown layer media type.
+    Unsupported,
+
+    /// A layer exceeded a limit like `--max-layer-size`.
+    Limit,
+
+    /// circe was interrupted, e.g. with Ctrl-C; see [`crate::interrupt`].
+    Interrupted,
+
+    /// An `--exec` hook failed, exiting with this code.
+    Hook(i32),
+}
+
+impl Failure {
+    /// Classify the error by the first library error in its chain of causes.
+    pub fn of(err: &Report) -> Self {
+        if let Some(hook) = err
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<HookFailed>())
+        {
+            return Failure::Hook(hook.code());
+        }
+
+        err.chain()
+            .find_map(|cause| cause.downcast_ref::<circe_lib::Error>())
+            .map(|err| match err {
+                circe_lib::Error::Auth(_) => Failure::Auth,
+                circe_lib::Error::NotFound(_) => Failure::NotFound,
+                circe_lib::Error::Network(_) => Failure::Network,
+                circe_lib::Error::Unsupported(_) => Failure::Unsupported,
+                circe_lib::Error::Limit(_) => Failure::Limit,
+                _ => Failure::Other,
+            })
+            .unwrap_or(Failure::Other)
+    }
+
+    /// The code with which circe exits for the failure.
+    ///
+    /// Code 2 isn't used here: it's reserved for invalid arguments, which are reported before circe runs.
+    pub fn code(self) -> i32 {
+        match self {
+            Failure::Other => 1,
+            Failure::Auth => 3,
+            Failure::NotFound => 4,
+            Failure::Network => 5,
+            Failure::Unsupported => 6,
+            Failure::Limit => 7,
+            // Shells report a process killed by SIGINT as 128 + 2.
+            Failure::Interrupted => 130,
+            Failure::Hook(code) => code,
+        }
+    }
+
+    /// The name of the kind of failure, as written with `--error-format json`.
+    fn name(self) -> &'static str {
+        match self {
+            Failure::Other => "other",
+            Failure::Auth => "auth",
+            Failure::NotFound => "not_found",
+            Failure::Network => "network",
+            Failure::Unsupported => "unsupported",
+            Failure::Limit => "limit",
+            Failure::Interrupted => "interrupted",
+            Failure::Hook(_) => "hook",
+        }
+    }
+}
+
+// The hook's exit code is already reported as `exit_code`, so only the name is written.
+impl Serialize for Failure {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+/// The error written with `--error-format json`.
+#[derive(Debug, Serialize)]
+struct Payload {
+    kind: Failure,
+    exit_code: i32,
+
+    /// The outermost description of the error.
+    message: String,
+
+    /// The description of each error in the chain, from the outermost to the root cause.
+    causes: Vec<String>,
+}
+
+impl ErrorFormat {
+    /// Write the error to stderr in this format.
+    pub fn write(self, err: &Report, failure: Failure) {
+        match self {
+            // Like the report written when `main` returns an error.
+            ErrorFormat::Human => eprintln!("Error: {err:?}"),
+            ErrorFormat::Json => eprintln!("{}", payload(err, failure)),
+        }
+    }
+}
+
+fn payload(err: &Report, failure: Failure) -> String {
+    let payload = Payload {
+        kind: failure,
+        exit_code: failure.code(),
+        message: err.to_string(),
+        causes: err.chain().map(ToString::to_string).collect(),
+    };
+    serde_json::to_string(&payload).unwrap_or_else(|_| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use color_eyre::eyre::{eyre, Context};
+    use simple_test_case::test_case;
+
+    fn lib_error(err: circe_lib::Error) -> Report {
+        Err::<(), _>(err)
+            .context("pull image")
+            .expect_err("must error")
+    }
+
+    #[test_case(lib_error(circe_lib::Error::Auth("denied".into())), Failure::Auth, 3; "auth")]
+    #[test_case(lib_error(circe_lib::Error::NotFound("no such tag".into())), Failure::NotFound, 4; "not_found")]
+    #[test_case(lib_error(circe_lib::Error::Network("connection refused".into())), Failure::Network, 5; "network")]
+    #[test_case(lib_error(circe_lib::Error::Limit("layer is too large".into())), Failure::Limit, 7; "limit")]
+    #[test_case(lib_error(circe_lib::Error::Io("disk full".into())), Failure::Other, 1; "io")]
+    #[test_case(eyre!("no layers to extract found in image"), Failure::Other, 1; "other")]
+    #[test]
+    fn classify(err: Report, expected: Failure, code: i32) {
+        pretty_assertions::assert_eq!(expected, Failure::of(&err));
+        pretty_assertions::assert_eq!(code, expected.code());
+    }
+
+    #[test]
+    fn classify_unsupported_media_type() {
+        let err = "application/vnd.example.layer"
+            .parse::<circe_lib::LayerMediaType>()
+            .context("parse media type")
+            .expect_err("must error");
+        pretty_assertions::assert_eq!(Failure::Unsupported, Failure::of(&err));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn classify_hook() {
+        use std::os::unix::process::ExitStatusExt;
+
+        // A wait status holds the exit code in its second byte.
+        let status = std::process::ExitStatus::from_raw(3 << 8);
+        let err = Err::<(), _>(HookFailed::new("exit 3", status))
+            .context("run hook")
+            .expect_err("must error");
+        pretty_assertions::assert_eq!(Failure::Hook(3), Failure::of(&err));
+        pretty_assertions::assert_eq!(3, Failure::of(&err).code());
+        pretty_assertions::assert_eq!(
+            "\"hook\"",
+            serde_json::to_string(&Failure::of(&err)).expect("serialize")
+        );
+    }
+
+    #[test]
+    fn json_payload() {
+        let err = lib_error(circe_lib::Error::NotFound("no such tag".into()));
+        let payload = serde_json::from_str::<serde_json::Value>(&payload(&err, Failure::of(&err)))
+            .expect("parse payload");
+        pretty_assertions::assert_eq!(
+            serde_json::json!({
+                "kind": "not_found",
+                "exit_code": 4,
+                "message": "pull image",
+                "causes": ["pull image", "no such tag"],
+            }),
+            payload
+        );
+    }
+}
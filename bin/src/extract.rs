@@ -1,37 +1,77 @@
 use circe_lib::{
-    docker::{Daemon, Tarball},
-    extract::{extract, Report, Strategy},
-    registry::Registry,
-    Authentication, Filters, Platform, Reference, Source,
+    cache::Cache,
+    docker,
+    extract::{
+        extract, hash_files, prune_empty_dirs, verity_digests, Report, ReportFormat, Spill,
+        Strategy, Vfs,
+    },
+    filter_file::FilterFile,
+    registry,
+    source::{self, AnySource},
+    transform::Buffering,
+    Annotation, Authentication, CaseCollisions, Digest, FileType, FilterCommands, Filters,
+    Identity, Layer, LayerRange, Limits, Platform, Predicate, PullPolicy, Reference, Source,
+    SourceKind, Unpack,
+};
+use clap::{ArgAction, Args, Parser, ValueEnum};
+use color_eyre::{
+    eyre::{self, bail, eyre, Context, Result},
+    Section,
 };
-use clap::{Args, Parser, ValueEnum};
-use color_eyre::eyre::{bail, Context, Result};
 use derive_more::Debug;
-use std::{path::PathBuf, str::FromStr};
-use tracing::{debug, info};
+use serde::Deserialize;
+use sha2::{Digest as _, Sha256};
+use std::{
+    num::{NonZeroU64, NonZeroUsize},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+use tracing::{debug, info, warn};
 
-use crate::{try_strategies, Outcome};
+use crate::{
+    batch::{self, Batch, Outcome},
+    config::{self, Config},
+    exec,
+    interrupt::Partial,
+    output::{Format, Output},
+    progress, sandbox,
+    space::Estimate,
+    verity,
+};
 
-#[derive(Debug, Parser)]
+#[derive(Debug, Default, Clone, Parser)]
+// The image isn't provided with `--batch`; see `batch::output`.
+// Changing the image reorders the positional arguments, so both are placed explicitly.
+#[command(
+    mut_arg("image", |arg| arg.index(1).required(false).required_unless_present("batch").default_value("")),
+    mut_arg("output_dir", |arg| arg.index(2)),
+)]
 pub struct Options {
     /// Target to extract
     #[clap(flatten)]
-    target: Target,
+    pub target: Target,
 
     /// Directory to which the extracted contents will be written
     ///
     /// Layers are extracted into subdirectories based on the `layers` option.
-    /// An `image.json` file is written to this directory with details about the extracted content.
+    /// An `image.json` file is written to this directory with details about the extracted content,
+    /// including an `identity` section recording the reference, resolved digest, and display name of the image.
     #[arg(default_value = ".")]
-    output_dir: String,
+    pub output_dir: String,
 
     /// Overwrite the existing output directory if it exists
+    ///
+    /// Without this, extracting into a directory that already contains the same image (as recorded by the digest
+    /// in its `image.json`), extracted with the same options that shape the output (like filters, `--layers`,
+    /// or `--whiteouts`), succeeds without extracting it again; any other existing directory is an error.
     #[arg(long, short)]
-    overwrite: bool,
+    pub overwrite: bool,
 
-    /// How to handle layers during extraction
-    #[arg(long, default_value = "squash")]
-    layers: Mode,
+    /// How to handle layers during extraction [default: squash]
+    #[arg(long)]
+    pub layers: Option<Mode>,
 
     /// Glob filters for layers to extract
     ///
@@ -41,7 +81,7 @@ pub struct Options {
     /// You can provide this multiple times to provide multiple filters.
     /// If filters are provided, only layers whose digest matches any filter are extracted.
     #[arg(long, alias = "lg")]
-    layer_glob: Option<Vec<String>>,
+    pub layer_glob: Option<Vec<String>>,
 
     /// Glob filters for files to extract
     ///
@@ -57,7 +97,7 @@ pub struct Options {
     /// You can provide this multiple times to provide multiple filters.
     /// If filters are provided, only files whose path matches any filter are extracted.
     #[arg(long, alias = "fg")]
-    file_glob: Option<Vec<String>>,
+    pub file_glob: Option<Vec<String>>,
 
     /// Regex filters for layers to extract
     ///
@@ -67,7 +107,7 @@ pub struct Options {
     /// You can provide this multiple times to provide multiple filters.
     /// If filters are provided, only layers whose digest matches any filter are extracted.
     #[arg(long, alias = "lr")]
-    layer_regex: Option<Vec<String>>,
+    pub layer_regex: Option<Vec<String>>,
 
     /// Regex filters for files to extract
     ///
@@ -80,35 +120,423 @@ pub struct Options {
     /// You can provide this multiple times to provide multiple filters.
     /// If filters are provided, only files whose path matches any filter are extracted.
     #[arg(long, alias = "fr")]
-    file_regex: Option<Vec<String>>,
+    pub file_regex: Option<Vec<String>>,
+
+    /// Positions of layers to extract, counting from zero at the base layer
+    ///
+    /// Negative positions count back from the last layer, so `-1` is the last layer.
+    /// You can provide this multiple times, or separate positions with commas (e.g. `0,-1`).
+    /// Layers selected by position are extracted along with layers matching the other layer filters.
+    #[arg(long, value_delimiter = ',', allow_hyphen_values = true)]
+    pub layer_index: Option<Vec<i64>>,
+
+    /// Ranges of layer positions to extract, written like Rust ranges
+    ///
+    /// For example `2..=5` selects the third through sixth layers, `1..` skips the base layer,
+    /// and `-2..` selects only the last two layers.
+    /// Positions are counted like `--layer-index`.
+    ///
+    /// You can provide this multiple times to provide multiple ranges.
+    #[arg(long, allow_hyphen_values = true)]
+    pub layer_range: Option<Vec<LayerRange>>,
+
+    /// Glob filters for layers to skip, written like `--layer-glob`
+    ///
+    /// Layers whose digest matches any of these filters are skipped, even if they match other layer filters.
+    #[arg(long)]
+    pub layer_glob_exclude: Option<Vec<String>>,
+
+    /// Glob filters for files to skip, written like `--file-glob`
+    ///
+    /// Files whose path matches any of these filters are skipped, even if they match other file filters;
+    /// for example `--file-glob '**/*.jar' --file-glob-exclude '**/test/**'`
+    /// extracts every `.jar` file outside of `test` directories.
+    #[arg(long)]
+    pub file_glob_exclude: Option<Vec<String>>,
+
+    /// Regex filters for layers to skip, written like `--layer-regex`
+    ///
+    /// Layers whose digest matches any of these filters are skipped, even if they match other layer filters.
+    #[arg(long)]
+    pub layer_regex_exclude: Option<Vec<String>>,
+
+    /// Regex filters for files to skip, written like `--file-regex`
+    ///
+    /// Files whose path matches any of these filters are skipped, even if they match other file filters.
+    #[arg(long)]
+    pub file_regex_exclude: Option<Vec<String>>,
+
+    /// Read layer and file filters from a file, one per line
+    ///
+    /// The syntax is similar to `.gitignore`: each line is a glob for files to extract,
+    /// which matches at any depth (so `*.jar` matches every `.jar` file, and `test/` everything in `test` directories).
+    /// Lines starting with `!` exclude matching files instead, `regex:` marks a regex instead of a glob,
+    /// and `layer:` filters layers by digest instead of files, for example `!layer:sha256:1234*`.
+    /// Blank lines and lines starting with `#` are ignored.
+    ///
+    /// Filters from the file are combined with the filters provided as arguments.
+    #[arg(long, value_parser = read_filter_file)]
+    pub filter_file: Option<FilterFile>,
+
+    /// Filters for files to extract by type, size, and content
+    #[clap(flatten)]
+    pub predicates: Predicates,
+
+    /// Compute the SHA256 digest of every extracted regular file
+    ///
+    /// By default (or with `report`) the digests are recorded in a `files` section of `image.json`.
+    /// For large images, `sidecar` instead writes them to `files.ndjson` in the output directory,
+    /// one JSON object per line.
+    #[arg(long, num_args = 0..=1, default_missing_value = "report")]
+    pub hash_files: Option<HashFiles>,
+
+    /// Compute the fs-verity digest of every extracted regular file
+    ///
+    /// By default (or with `record`) the digests are recorded alongside the SHA256 digests from `--hash-files`
+    /// (which defaults to `report` if not otherwise set), so that the files can be sealed later.
+    /// With `enable`, fs-verity is also enabled on each file using the `fsverity` tool,
+    /// sealing the files against modification; this requires filesystem support.
+    #[arg(long, num_args = 0..=1, default_missing_value = "record")]
+    pub fs_verity: Option<FsVerity>,
+
+    /// Whether to write directories that are empty once extraction completes
+    ///
+    /// Directories are written with the mode recorded in the layer,
+    /// so special modes like the sticky bit on `/tmp` are preserved.
+    /// Set to `false` if you're only interested in files.
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    pub emit_empty_dirs: bool,
+
+    /// Fail if paths in the image collide on a case-insensitive filesystem
+    ///
+    /// Images can contain paths that differ only by case (e.g. `README` and `readme`),
+    /// which collide when extracted to a filesystem that isn't case-sensitive,
+    /// like the defaults on macOS and Windows.
+    /// By default the later entry is written with `~N` appended to its name instead,
+    /// and the renamed entries are recorded in the `collisions` section of `image.json`.
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Record each whiteout applied during extraction in `whiteouts.json`
+    ///
+    /// Later layers delete files from earlier layers with whiteouts,
+    /// but the deleted files still exist in the earlier layers of the image
+    /// (for example, credentials removed by a later layer).
+    /// Each entry records the layer containing the whiteout, the path it deleted,
+    /// and whether anything existed at that path.
+    #[arg(long)]
+    pub whiteouts: bool,
+
+    /// Recover paths deleted by whiteouts into a `deleted` directory instead of removing them
+    ///
+    /// Content deleted by a later layer still ships in the earlier layers of the image,
+    /// so it's worth scanning for secrets or licensed code that was only "removed" from the final filesystem.
+    /// Each layer's deletions are written to `deleted/<layer digest hex>/`,
+    /// at the same path they had in the image.
+    /// With `--whiteouts`, each entry in `whiteouts.json` also records the path to which its content was recovered.
+    #[arg(long)]
+    pub include_deleted: bool,
+
+    /// Record the layer that last wrote or deleted each extracted path in `provenance.ndjson`
+    ///
+    /// When layers are squashed, the report doesn't say which layer each file came from;
+    /// this writes one JSON object per line with the path (relative to the output directory),
+    /// the digest of the layer that last changed it, and whether that layer deleted it with a whiteout.
+    /// Layers reused from a layer store aren't written again, so this can't be combined with `--layer-store`.
+    #[arg(long, conflicts_with = "layer_store")]
+    pub provenance: bool,
+
+    /// Pipe each decompressed layer through a command before it is extracted
+    ///
+    /// This is an escape hatch for layer encodings circe doesn't support natively,
+    /// such as a custom decryption or sanitization tool:
+    /// the command reads the layer tarball on stdin and writes the tarball to extract on stdout.
+    /// Commands are run with `sh -c`.
+    ///
+    /// Prefix the command with a media type and `=` to only apply it to layers with that media type,
+    /// for example `application/vnd.oci.image.layer.v1.tar+gzip=decrypt --key key.pem`;
+    /// commands for a specific media type take precedence over commands without one.
+    ///
+    /// You can provide this multiple times to provide commands for multiple media types.
+    #[arg(long)]
+    pub filter_cmd: Option<Vec<String>>,
+
+    /// Fail if a layer decompresses to more than this many bytes
+    ///
+    /// Images are often untrusted, and a malicious layer can decompress to far more than its size,
+    /// filling the disk; this stops extraction as soon as a layer exceeds the limit.
+    /// Accepts a suffix of `K`, `M`, or `G` for kibibytes, mebibytes, or gibibytes, for example `10G`.
+    /// By default layers aren't limited.
+    #[arg(long, value_parser = parse_size)]
+    pub max_layer_size: Option<u64>,
+
+    /// Fail if a layer contains more than this many entries (files, directories, links, and whiteouts)
+    ///
+    /// By default layers aren't limited.
+    #[arg(long)]
+    pub max_files: Option<u64>,
+
+    /// Fail if a single file in a layer is larger than this many bytes
+    ///
+    /// Accepts the same suffixes as `--max-layer-size`. By default files aren't limited.
+    #[arg(long, value_parser = parse_size)]
+    pub max_file_size: Option<u64>,
+
+    /// Share layers extracted separately between images through a store directory
+    ///
+    /// Each layer is extracted into the store the first time it's seen, named after the hex of its digest;
+    /// its files are then hard linked into the output directory (or copied if that isn't possible),
+    /// so images built on common base layers share those files on disk instead of each having a copy.
+    /// This applies to layers extracted to their own directory with `--layers separate` or `base-and-squash-other`.
+    ///
+    /// Linked files share their content with the store, so they must not be modified in place.
+    /// The store can't be used with file filters or filter commands,
+    /// since the layers in it would differ depending on how they were filtered.
+    #[arg(long, conflicts_with_all = [
+        "file_glob", "file_regex", "file_glob_exclude", "file_regex_exclude", "filter_file",
+        "file_max_size", "file_type", "exclude_binary", "filter_cmd",
+    ])]
+    pub layer_store: Option<PathBuf>,
+
+    /// Run a command after extraction succeeds
+    ///
+    /// Each `{}` in the command is replaced with the path to the output directory,
+    /// for example `--exec 'tar -C {} -czf image.tar.gz .'`.
+    /// Commands are run with `sh -c` once the report is written,
+    /// with only basic environment variables (like `PATH`, `HOME`, and locale settings) passed through.
+    ///
+    /// If the command fails, circe exits with the same status.
+    /// A hook's status can coincide with circe's own exit codes (like 4 for an image that wasn't found);
+    /// use `--error-format json` to tell them apart, which reports the failure's kind as `hook`.
+    #[arg(long)]
+    pub exec: Option<String>,
+
+    /// The format in which the report is printed once the image is extracted: `json`, `yaml`, `toml`, or `none`
+    ///
+    /// This only affects the printed report; `image.json` in the output directory is always written as JSON.
+    /// Use `none` to print nothing, for example when stdout is read by something else.
+    #[arg(long, default_value_t = ReportFormat::Json, value_parser = ReportFormat::from_str, conflicts_with_all = ["dry_run", "batch"])]
+    pub report_format: ReportFormat,
+
+    /// Write the printed report to this file instead of stdout, replacing it if it exists
+    ///
+    /// The report is written in the format selected with `--report-format`; nothing is written with `none`.
+    #[arg(long, conflicts_with_all = ["dry_run", "batch"])]
+    pub report_path: Option<PathBuf>,
+
+    /// Unpack layers on a thread restricted to writing within the output and temporary directories
+    ///
+    /// This limits the damage a hostile image can do if it exploits a bug in extraction:
+    /// Landlock prevents writes outside the output directory, the temporary directory, and the layer store,
+    /// and seccomp denies system calls extraction never needs (like `mount`, `mknod`, and `ptrace`).
+    /// Filter commands run during extraction inherit the restrictions, and can't gain privileges (e.g. with `sudo`).
+    ///
+    /// This requires Linux 5.13 or later, and circe built with the `sandbox` feature;
+    /// on kernels before 5.19 deleted paths can't be recovered and layers are copied from the layer store.
+    #[arg(long)]
+    pub sandbox: bool,
+
+    /// Only write `image.json`, listing every entry in the squashed image instead of extracting it
+    ///
+    /// Layers are squashed in memory, so no files are written; this is a lightweight alternative to extraction
+    /// for consumers that only need the inventory of the image as a container running it would see it.
+    /// The listing is recorded in the `listing` section of `image.json`: the path, kind, mode,
+    /// and size of each entry, along with the SHA256 digest of files and the target of symlinks.
+    ///
+    /// Since file contents are held in memory while layers are squashed, this is best suited to images that fit in memory.
+    #[arg(long, conflicts_with_all = [
+        "layers", "file_glob", "file_regex", "file_glob_exclude", "file_regex_exclude", "filter_file",
+        "file_max_size", "file_type", "exclude_binary", "hash_files", "fs_verity", "emit_empty_dirs", "strict", "whiteouts", "include_deleted", "provenance", "filter_cmd", "layer_store", "sandbox",
+    ])]
+    pub report_only_squashed_listing: bool,
+
+    /// Report the disk space extraction is estimated to need instead of extracting
+    ///
+    /// The estimate is printed as JSON: the size of each layer that would be extracted as distributed,
+    /// and the size of its uncompressed content if its descriptor records it (as eStargz layers do).
+    /// Compressed layers that don't record it are counted at their distributed size,
+    /// in which case the estimate is marked as a lower bound.
+    /// The space available in the output directory is included if it can be determined,
+    /// along with whether layers would be buffered there because the temporary directory is too small for them.
+    #[arg(long, conflicts_with_all = ["report_only_squashed_listing", "exec", "batch"])]
+    pub dry_run: bool,
+
+    /// Don't check that there's enough space in the output directory before extracting
+    ///
+    /// By default extraction fails before any layer is written if the estimated size of the layers
+    /// (as reported by `--dry-run`) exceeds the available space.
+    /// The estimate counts each layer in full, so it can be too high for images whose later layers
+    /// delete much of the earlier content, or whose layers are already in the layer store.
+    #[arg(long)]
+    pub skip_space_check: bool,
+
+    /// Fail if the image has no layers to extract
+    ///
+    /// Scratch-based images and config-only artifacts legitimately have no layers, as do images
+    /// whose layers are all excluded by layer filters; by default these succeed with an empty report.
+    #[arg(long)]
+    pub fail_on_empty: bool,
+
+    /// Don't display progress bars
+    ///
+    /// Progress bars are also not displayed if stdout is not a terminal, or with `--batch`.
+    #[arg(long, short)]
+    pub quiet: bool,
+
+    /// Extract many images, each into a directory inside the output directory named after the image
+    #[clap(flatten)]
+    pub batch: Batch,
 }
 
 impl Options {
+    /// Fill in the options that weren't provided from the configuration file.
+    ///
+    /// Filters from the file are only used if no filters of the same kind (layer or file) are provided.
+    /// File filters aren't used with `--layer-store`, since stored layers must be extracted without them.
+    pub fn configure(&mut self, config: &Config) {
+        self.target.configure(config);
+
+        let defaults = &config.extract;
+        if self.layers.is_none() {
+            self.layers = defaults.layers;
+        }
+        let layer_filters = [
+            &self.layer_glob,
+            &self.layer_regex,
+            &self.layer_glob_exclude,
+            &self.layer_regex_exclude,
+        ];
+        let layer_positions = self.layer_index.is_none() && self.layer_range.is_none();
+        let filter_file = self.filter_file.is_none();
+        if layer_filters.iter().all(|filters| filters.is_none()) && layer_positions && filter_file {
+            self.layer_glob = defaults.layer_glob.clone();
+            self.layer_regex = defaults.layer_regex.clone();
+        }
+        let file_filters = [
+            &self.file_glob,
+            &self.file_regex,
+            &self.file_glob_exclude,
+            &self.file_regex_exclude,
+        ];
+        if file_filters.iter().all(|filters| filters.is_none())
+            && filter_file
+            && self.layer_store.is_none()
+        {
+            self.file_glob = defaults.file_glob.clone();
+            self.file_regex = defaults.file_regex.clone();
+        }
+    }
+
+    /// Identifies the options that shape what is written to the output directory, as the digest of their values.
+    ///
+    /// This is recorded in the report, so that an existing extraction is only reused
+    /// by an extraction that would write the same output; see [`extracted`].
+    fn output_options(&self) -> String {
+        // The fields are only read through the debug representation that is hashed.
+        #[allow(dead_code)]
+        #[derive(Debug)]
+        struct Shaping<'a> {
+            layers: Option<Mode>,
+            layer_glob: &'a Option<Vec<String>>,
+            file_glob: &'a Option<Vec<String>>,
+            layer_regex: &'a Option<Vec<String>>,
+            file_regex: &'a Option<Vec<String>>,
+            layer_index: &'a Option<Vec<i64>>,
+            layer_range: &'a Option<Vec<LayerRange>>,
+            layer_glob_exclude: &'a Option<Vec<String>>,
+            file_glob_exclude: &'a Option<Vec<String>>,
+            layer_regex_exclude: &'a Option<Vec<String>>,
+            file_regex_exclude: &'a Option<Vec<String>>,
+            filter_file: &'a Option<FilterFile>,
+            predicates: &'a Predicates,
+            hash_files: Option<HashFiles>,
+            fs_verity: Option<FsVerity>,
+            emit_empty_dirs: bool,
+            strict: bool,
+            whiteouts: bool,
+            include_deleted: bool,
+            provenance: bool,
+            filter_cmd: &'a Option<Vec<String>>,
+            layer_store: &'a Option<PathBuf>,
+            report_only_squashed_listing: bool,
+        }
+
+        let shaping = Shaping {
+            layers: self.layers,
+            layer_glob: &self.layer_glob,
+            file_glob: &self.file_glob,
+            layer_regex: &self.layer_regex,
+            file_regex: &self.file_regex,
+            layer_index: &self.layer_index,
+            layer_range: &self.layer_range,
+            layer_glob_exclude: &self.layer_glob_exclude,
+            file_glob_exclude: &self.file_glob_exclude,
+            layer_regex_exclude: &self.layer_regex_exclude,
+            file_regex_exclude: &self.file_regex_exclude,
+            filter_file: &self.filter_file,
+            predicates: &self.predicates,
+            hash_files: self.hash_files,
+            fs_verity: self.fs_verity,
+            emit_empty_dirs: self.emit_empty_dirs,
+            strict: self.strict,
+            whiteouts: self.whiteouts,
+            include_deleted: self.include_deleted,
+            provenance: self.provenance,
+            filter_cmd: &self.filter_cmd,
+            layer_store: &self.layer_store,
+            report_only_squashed_listing: self.report_only_squashed_listing,
+        };
+        let hash = Sha256::digest(format!("{shaping:?}"));
+        Digest::from_hash(hash.to_vec()).to_string()
+    }
+
     /// Combined filters for layers.
     pub fn layer_filters(&self) -> Result<Filters> {
         let layer_globs = Filters::parse_glob(self.layer_glob.iter().flatten())?;
         let layer_regexes = Filters::parse_regex(self.layer_regex.iter().flatten())?;
-        Ok(layer_globs + layer_regexes)
+        let excluded_globs = Filters::parse_glob(self.layer_glob_exclude.iter().flatten())?;
+        let excluded_regexes = Filters::parse_regex(self.layer_regex_exclude.iter().flatten())?;
+        let excluded = (excluded_globs + excluded_regexes).exclude();
+        let indices = self
+            .layer_index
+            .iter()
+            .flatten()
+            .copied()
+            .map(LayerRange::index);
+        let ranges = Filters::ranges(indices.chain(self.layer_range.iter().flatten().copied()));
+        let from_file = self.filter_file.as_ref().map(|file| file.layers.clone());
+        Ok(layer_globs + layer_regexes + excluded + ranges + from_file.unwrap_or_default())
     }
 
     /// Combined filters for files.
     pub fn file_filters(&self) -> Result<Filters> {
         let file_globs = Filters::parse_glob(self.file_glob.iter().flatten())?;
         let file_regexes = Filters::parse_regex(self.file_regex.iter().flatten())?;
-        Ok(file_globs + file_regexes)
+        let excluded_globs = Filters::parse_glob(self.file_glob_exclude.iter().flatten())?;
+        let excluded_regexes = Filters::parse_regex(self.file_regex_exclude.iter().flatten())?;
+        let excluded = (excluded_globs + excluded_regexes).exclude();
+        let predicates = Filters::predicates(self.predicates.predicates());
+        let from_file = self.filter_file.as_ref().map(|file| file.files.clone());
+        Ok(file_globs + file_regexes + excluded + predicates + from_file.unwrap_or_default())
     }
 
-    /// Registry authentication.
-    pub async fn auth(&self, reference: &Reference) -> Result<Authentication> {
-        Ok(match (&self.target.username, &self.target.password) {
-            (Some(username), Some(password)) => Authentication::basic(username, password),
-            _ => Authentication::docker(reference).await?,
-        })
+    /// Safety limits enforced as layers are applied.
+    pub fn limits(&self) -> Limits {
+        Limits::builder()
+            .maybe_max_layer_size(self.max_layer_size)
+            .maybe_max_files(self.max_files)
+            .maybe_max_file_size(self.max_file_size)
+            .build()
+    }
+
+    /// Commands through which layers are piped.
+    pub fn filter_commands(&self) -> Result<FilterCommands> {
+        FilterCommands::parse(self.filter_cmd.iter().flatten()).map_err(Into::into)
     }
 }
 
 /// Shared options for any command that needs to work with the OCI registry for a given image.
-#[derive(Debug, Args)]
+#[derive(Debug, Default, Clone, Args)]
 pub struct Target {
     /// Image reference being extracted (e.g. docker.io/library/ubuntu:latest)
     ///
@@ -137,6 +565,10 @@ pub struct Target {
     /// If the image is not multi-platform, this is ignored.
     /// If the image is multi-platform, this is used to select the platform to extract.
     ///
+    /// Windows images can differ only by OS version; select one by appending it after a colon,
+    /// e.g. `windows/amd64:10.0.17763.1879`. The image must be the same build (`10.0.17763`)
+    /// with the same or an older revision; use `x` as the revision to accept any (e.g. `10.0.17763.x`).
+    ///
     /// If the image is multi-platform and this argument is not provided,
     /// the platform is chosen according to the following priority list:
     /// 1. The first platform-independent image
@@ -144,36 +576,336 @@ pub struct Target {
     /// 3. The `linux` platform for the current architecture
     /// 4. The `linux` platform for the `amd64` architecture
     /// 5. The first platform in the image manifest
-    #[arg(long, value_parser = Platform::from_str, verbatim_doc_comment)]
+    ///
+    /// This applies to images in a registry, the Docker daemon, or a tarball.
+    /// A default can also be set with `platform` in the configuration file.
+    #[arg(long, env = "CIRCE_PLATFORM", value_parser = Platform::from_str, verbatim_doc_comment)]
     pub platform: Option<Platform>,
 
+    /// Only select images from an image index that have this annotation (e.g. org.opencontainers.image.ref.name=foo)
+    ///
+    /// Some indexes contain several images for the same platform (for example attestations or provenance),
+    /// which are distinguished by their annotations.
+    /// Images are then selected by platform from those that have the annotations.
+    ///
+    /// You can provide this multiple times; images must have every annotation to be selected.
+    /// This only applies to images in a registry, since the Docker daemon and tarballs don't record annotations.
+    #[arg(long = "select-annotation", value_parser = Annotation::from_str)]
+    pub annotations: Option<Vec<Annotation>>,
+
+    /// Select the manifest with this digest from an image index, instead of selecting one by platform
+    ///
+    /// This is useful when you already know the digest of the platform-specific manifest,
+    /// and also allows deliberately selecting manifests that are otherwise skipped, like attestations.
+    /// When provided, `--platform` and `--select-annotation` are ignored.
+    /// This applies to images in a registry or a tarball; the Docker daemon is skipped.
+    #[arg(long, value_parser = Digest::from_str)]
+    pub manifest_digest: Option<Digest>,
+
     /// The username to use for authenticating to the registry
+    ///
+    /// Credentials passed on the command line are visible in process listings;
+    /// to avoid this, set `CIRCE_AUTH_<HOST>` (e.g. `CIRCE_AUTH_GHCR_IO=username:password`)
+    /// or `CIRCE_REGISTRY_AUTH` (e.g. `{"ghcr.io":{"username":"me","password":"token"}}`) instead.
     #[arg(long, requires = "password")]
     pub username: Option<String>,
 
+    /// Read credentials for the registry from a Kubernetes image pull secret
+    ///
+    /// The secret is a file in the form written by `kubectl get secret <name> -o json` (or `-o yaml`),
+    /// of type `kubernetes.io/dockerconfigjson` (or the legacy `kubernetes.io/dockercfg`).
+    /// If the secret doesn't have credentials for the registry hosting the image,
+    /// credentials are read from the environment, the configuration file, or the local Docker configuration as usual.
+    #[arg(long = "auth-from-k8s-secret", conflicts_with = "username")]
+    pub k8s_secret: Option<PathBuf>,
+
     /// The password to use for authenticating to the registry
     #[arg(long, requires = "username")]
     #[debug(skip)]
     pub password: Option<String>,
+
+    /// The size in bytes of the chunks in which layers are read as they're decompressed and unpacked
+    ///
+    /// Larger chunks mean fewer, larger reads, which improves throughput at the cost of memory.
+    /// Defaults to 4096.
+    #[arg(long, env = "CIRCE_CHUNK_SIZE")]
+    pub chunk_size: Option<NonZeroUsize>,
+
+    /// The number of bytes of each layer to download ahead of the content being unpacked
+    ///
+    /// This overlaps downloading a layer with decompressing and unpacking it, which helps most on high-latency links.
+    /// It only applies to images pulled from a registry.
+    /// Defaults to 0, which disables read-ahead.
+    #[arg(long, env = "CIRCE_READ_AHEAD")]
+    pub read_ahead: Option<usize>,
+
+    /// The maximum rate at which layers are downloaded, in bytes per second
+    ///
+    /// Accepts a suffix of `K`, `M`, or `G` for kibibytes, mebibytes, or gibibytes, for example `500K` or `2M`.
+    /// It only applies to images pulled from a registry. By default downloads aren't limited.
+    #[arg(long, env = "CIRCE_LIMIT_RATE", value_parser = parse_rate)]
+    pub limit_rate: Option<NonZeroU64>,
+
+    /// The source to read the image from: `auto`, `registry`, `daemon`, `tarball`, or `oci-layout`
+    ///
+    /// By default (`auto`) the image is read from an OCI image layout if the target is a directory,
+    /// or a tarball if it's another path that exists, or the Docker daemon if it has the image,
+    /// or otherwise its registry; if none of them can read the image, the error from each source that was tried
+    /// is reported. Selecting a source reports only its error instead.
+    ///
+    /// `oci-layout` reads an OCI image layout, either a tarball or a directory
+    /// like one written by `skopeo copy docker://<image> oci:<dir>`.
+    #[arg(long, env = "CIRCE_SOURCE", value_parser = SourceKind::from_str)]
+    pub source: Option<SourceKind>,
+
+    /// When the Docker daemon pulls the image before it's read: `never`, `missing`, or `always`
+    ///
+    /// By default (`never`) the daemon is only used for images it already has,
+    /// and other images are pulled from their registry by circe directly.
+    /// With `missing` the daemon pulls images it doesn't have, and with `always` it pulls the image
+    /// even if it has it, so that the daemon has the latest content for the tag.
+    /// This only applies to the Docker daemon.
+    #[arg(long, env = "CIRCE_PULL_POLICY", value_parser = PullPolicy::from_str)]
+    pub pull_policy: Option<PullPolicy>,
+
+    /// The Docker daemon to read images from, like `tcp://host:2376` or `ssh://user@host`
+    ///
+    /// By default the local daemon is used.
+    /// With `tcp://`, TLS is used if `DOCKER_TLS_VERIFY` or `--docker-cert-path` is set.
+    #[arg(long, env = "DOCKER_HOST")]
+    pub docker_host: Option<String>,
+
+    /// The directory containing `ca.pem`, `cert.pem`, and `key.pem` for connecting to the Docker daemon over TLS
+    ///
+    /// Defaults to `~/.docker` when `DOCKER_TLS_VERIFY` is set.
+    #[arg(long, env = "DOCKER_CERT_PATH")]
+    pub docker_cert_path: Option<PathBuf>,
+
+    /// The socket of the local Docker daemon, overriding `--docker-host`
+    ///
+    /// By default the socket is discovered from the standard location and the locations used by
+    /// Colima, Rancher Desktop, Podman machine, and Docker Desktop on macOS.
+    #[arg(long, env = "CIRCE_DOCKER_SOCKET")]
+    pub docker_socket: Option<PathBuf>,
+
+    /// Read images stored by `circe prefetch` in this directory instead of pulling them
+    ///
+    /// Images are only read from the cache when the source is selected automatically,
+    /// and only if they were stored for the same reference and platform.
+    #[arg(long, env = "CIRCE_CACHE_DIR")]
+    pub cache_dir: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub timeouts: Timeouts,
+
+    /// Credentials for the registry from the configuration file, filled in by [`Target::configure`].
+    #[arg(skip)]
+    pub configured_auth: Option<Authentication>,
+}
+
+/// Parse a rate in bytes per second, with an optional binary suffix like `curl --limit-rate`.
+pub fn parse_rate(rate: &str) -> Result<NonZeroU64> {
+    let rate = parse_size(rate)?;
+    NonZeroU64::new(rate).ok_or_else(|| eyre!("rate must be at least 1 byte per second"))
+}
+
+/// Read and parse a filter file.
+///
+/// Argument errors only display the outermost error, so the causes are folded into it.
+fn read_filter_file(path: &str) -> Result<FilterFile> {
+    std::fs::read_to_string(path)
+        .context("read filter file")
+        .and_then(|content| content.parse::<FilterFile>().context("parse filter file"))
+        .map_err(|err| eyre!("{err:#}"))
+}
+
+/// Parse a size in bytes, with an optional binary suffix of `K`, `M`, or `G`.
+pub fn parse_size(size: &str) -> Result<u64> {
+    let (number, multiplier) = match size.char_indices().last() {
+        Some((index, 'k' | 'K')) => (&size[..index], 1 << 10),
+        Some((index, 'm' | 'M')) => (&size[..index], 1 << 20),
+        Some((index, 'g' | 'G')) => (&size[..index], 1 << 30),
+        _ => (size, 1),
+    };
+    number
+        .parse::<u64>()
+        .with_context(|| format!("parse size {number:?}"))?
+        .checked_mul(multiplier)
+        .ok_or_else(|| eyre!("size is too large"))
+}
+
+/// Filters on the type, size, and content of files, beyond their paths.
+///
+/// Unlike path filters, files must satisfy every one of these filters to be included.
+#[derive(Debug, Default, Clone, Args)]
+pub struct Predicates {
+    /// Skip regular files larger than this many bytes
+    ///
+    /// Accepts the same suffixes as `--max-layer-size`.
+    /// Unlike `--max-file-size`, larger files are skipped instead of failing extraction.
+    #[arg(long, value_parser = parse_size)]
+    pub file_max_size: Option<u64>,
+
+    /// Only include entries of these types: `regular`, `symlink`, or `dir`
+    ///
+    /// You can provide this multiple times, or separate types with commas.
+    /// Hard links are considered regular files.
+    #[arg(long, value_delimiter = ',')]
+    pub file_type: Option<Vec<FileType>>,
+
+    /// Skip regular files whose content looks binary
+    ///
+    /// Like git, a file is considered binary if its first 8000 bytes contain a NUL byte.
+    #[arg(long, visible_alias = "only-text")]
+    pub exclude_binary: bool,
+}
+
+impl Predicates {
+    /// The predicates files must satisfy.
+    pub fn predicates(&self) -> Vec<Predicate> {
+        let max_size = self.file_max_size.map(Predicate::MaxSize);
+        let types = self.file_type.clone().map(Predicate::Types);
+        let binary = self.exclude_binary.then_some(Predicate::ExcludeBinary);
+        [max_size, types, binary].into_iter().flatten().collect()
+    }
+}
+
+/// Deadlines for requests to the registry, so that hung connections fail instead of blocking indefinitely.
+///
+/// Durations are written like `30s`, `2m`, or `1m 30s`.
+#[derive(Debug, Default, Clone, Args)]
+pub struct Timeouts {
+    /// The default for each of the more specific timeouts that isn't provided
+    ///
+    /// If neither this nor a specific timeout is provided, requests can take indefinitely long.
+    #[arg(long, env = "CIRCE_TIMEOUT", value_parser = humantime::parse_duration)]
+    pub timeout: Option<Duration>,
+
+    /// The time allowed to connect to the registry, or to the storage it redirects downloads to
+    #[arg(long, env = "CIRCE_CONNECT_TIMEOUT", value_parser = humantime::parse_duration)]
+    pub connect_timeout: Option<Duration>,
+
+    /// The time allowed for each request for a manifest or its digest, including authentication
+    #[arg(long, env = "CIRCE_MANIFEST_TIMEOUT", value_parser = humantime::parse_duration)]
+    pub manifest_timeout: Option<Duration>,
+
+    /// The time a layer download may go without receiving any content before it fails
+    ///
+    /// Large layers can take a long time to download, so this bounds stalls rather than the whole download.
+    #[arg(long, env = "CIRCE_STALL_TIMEOUT", value_parser = humantime::parse_duration)]
+    pub stall_timeout: Option<Duration>,
+}
+
+impl Timeouts {
+    /// The deadlines for requests to the registry, with `--timeout` filling in any that weren't provided.
+    pub fn registry(&self) -> registry::Timeouts {
+        registry::Timeouts::builder()
+            .maybe_connect(self.connect_timeout.or(self.timeout))
+            .maybe_manifest(self.manifest_timeout.or(self.timeout))
+            .maybe_stall(self.stall_timeout.or(self.timeout))
+            .build()
+    }
 }
 
 impl Target {
+    /// Fill in the options that weren't provided from the configuration file.
+    pub fn configure(&mut self, config: &Config) {
+        if self.platform.is_none() {
+            self.platform = config.platform.clone();
+        }
+        self.configured_auth = config.auth(&self.image);
+    }
+
+    /// Registry authentication, if provided.
+    ///
+    /// Credentials are taken from the first of these that provides them:
+    /// 1. `--username` and `--password`
+    /// 2. The Kubernetes secret passed with `--auth-from-k8s-secret`
+    /// 3. The environment (see [`config::env_auth`])
+    /// 4. The configuration file
+    ///
+    /// If not provided, [`source::detect`] reads credentials from the local Docker configuration.
+    pub async fn auth(&self) -> Result<Option<Authentication>> {
+        if let (Some(username), Some(password)) = (&self.username, &self.password) {
+            return Ok(Some(Authentication::basic(username, password)));
+        }
+        if self.is_path().await {
+            return Ok(None);
+        }
+
+        let reference = Reference::from_str(&self.image).context("parse image reference")?;
+        if let Some(secret) = &self.k8s_secret {
+            match Authentication::k8s_secret(secret, &reference)
+                .await
+                .context("read kubernetes secret")?
+            {
+                Authentication::None => {
+                    warn!(?secret, host = %reference.host, "no credentials for host in secret");
+                }
+                auth => return Ok(Some(auth)),
+            }
+        }
+
+        if let Some(auth) =
+            config::env_auth(&reference.host).context("read credentials from environment")?
+        {
+            return Ok(Some(auth));
+        }
+        Ok(self.configured_auth.clone())
+    }
+
+    /// How to connect to the Docker daemon.
+    pub fn daemon(&self) -> docker::Connection {
+        docker::Connection::builder()
+            .maybe_host(self.docker_host.clone())
+            .maybe_cert_path(self.docker_cert_path.clone())
+            .maybe_socket(self.docker_socket.clone())
+            .build()
+    }
+
+    /// The cache of images stored by `circe prefetch`, if one is configured.
+    pub fn cache(&self) -> Option<Cache> {
+        self.cache_dir.clone().map(Cache::new)
+    }
+
+    /// How layer content is buffered as it's read and unpacked.
+    pub fn buffering(&self) -> Buffering {
+        Buffering::builder()
+            .maybe_chunk_size(self.chunk_size)
+            .maybe_read_ahead(self.read_ahead)
+            .build()
+    }
+
+    /// Detect the source of the image.
+    ///
+    /// When the source is detected automatically, the error suggests `--source`,
+    /// since selecting a source reports why that source in particular can't read the image.
+    pub async fn detect(&self, options: source::Options) -> Result<AnySource> {
+        let detected = source::detect(&self.image, options)
+            .await
+            .context("detect source");
+        match self.source.unwrap_or_default() {
+            SourceKind::Auto => detected.with_suggestion(|| {
+                "Use `--source` to read the image from a specific source and see its error."
+            }),
+            _ => detected,
+        }
+    }
+
     /// Check if the image appears to be a path.
     /// The validation is performed simply by attempting to canonicalize the path, then checking if a file exists.
     /// If either operation fails or the file does not exist, the image is not considered a path.
     pub async fn is_path(&self) -> bool {
         // We could make this nicer with `futures::future::AndThen`, but we don't currently import `futures`.
         match tokio::fs::canonicalize(&self.image).await {
-            Ok(path) => match tokio::fs::try_exists(path).await {
-                Ok(exists) => exists,
-                Err(_) => false,
-            },
+            Ok(path) => tokio::fs::try_exists(path).await.unwrap_or_default(),
             Err(_) => false,
         }
     }
 }
 
-#[derive(Copy, Clone, Debug, Default, ValueEnum)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum Mode {
     /// Squash all layers into a single output directory, resulting in a file system equivalent to a running container.
     #[default]
@@ -192,106 +924,311 @@ pub enum Mode {
     Separate,
 }
 
-#[tracing::instrument]
-pub async fn main(opts: Options) -> Result<()> {
-    info!("extracting image");
-    try_strategies!(&opts; strategy_tarball, strategy_daemon, strategy_registry)
+/// What to do with fs-verity digests of extracted files.
+#[derive(Copy, Clone, Debug, Default, ValueEnum)]
+pub enum FsVerity {
+    /// Record the digests with the other file digests.
+    #[default]
+    Record,
+
+    /// Record the digests, and enable fs-verity on each file.
+    Enable,
 }
 
-async fn strategy_registry(opts: &Options) -> Result<Outcome> {
-    if opts.target.is_path().await {
-        debug!("input appears to be a file path, skipping strategy");
-        return Ok(Outcome::Skipped);
+/// Where to record the digests of extracted files.
+#[derive(Copy, Clone, Debug, Default, ValueEnum)]
+pub enum HashFiles {
+    /// Record the digests in the `files` section of `image.json`.
+    #[default]
+    Report,
+
+    /// Record the digests in `files.ndjson`, one JSON object per line.
+    Sidecar,
+}
+
+#[tracing::instrument]
+pub async fn main(opts: Options) -> Result<()> {
+    if opts.batch.batch.is_some() {
+        return extract_batch(opts).await;
     }
 
-    let reference = Reference::from_str(&opts.target.image)?;
-    let layer_filters = opts.layer_filters()?;
-    let file_filters = opts.file_filters()?;
-    let auth = opts.auth(&reference).await?;
+    if opts.dry_run {
+        let estimate = estimate_image(&opts).await?;
+        let mut stdout = Output::create(Format::Json, None)?;
+        stdout.json(&estimate).context("render estimate")?;
+        return stdout.finish();
+    }
 
-    let registry = Registry::builder()
-        .maybe_platform(opts.target.platform.as_ref())
-        .reference(reference)
-        .auth(auth)
-        .layer_filters(layer_filters)
-        .file_filters(file_filters)
-        .build()
+    let (output, report) = extract_image(&opts).await?;
+    report
+        .emit(opts.report_format, opts.report_path.as_deref())
         .await
-        .context("configure remote registry")?;
+        .context("print report")?;
 
-    extract_layers(opts, registry)
+    if let Some(command) = &opts.exec {
+        exec::run(command, &output).await.context("run hook")?;
+    }
+
+    Ok(())
+}
+
+/// Extract every image in the batch into its own directory inside the output directory,
+/// running the `--exec` hook for each.
+async fn extract_batch(opts: Options) -> Result<()> {
+    let provided = Some(Path::new(&opts.output_dir)).filter(|dir| *dir != Path::new("."));
+    let output_dir = batch::output(&opts.target.image, provided)?;
+    opts.batch
+        .clone()
+        .run(move |image, name| {
+            let mut opts = opts.clone();
+            opts.target.image = image.clone();
+            opts.output_dir = output_dir.join(name).to_string_lossy().to_string();
+            opts.quiet = true;
+            async move {
+                let output = PathBuf::from(&opts.output_dir);
+                match extract_batch_image(&opts).await {
+                    Ok((output, report)) => Outcome {
+                        image,
+                        output,
+                        digest: Some(report.digest),
+                        identity: report.identity,
+                        error: None,
+                    },
+                    Err(err) => Outcome::failed(image, output, &err),
+                }
+            }
+        })
         .await
-        .context("extract layers")
-        .map(|_| Outcome::Success)
 }
 
-async fn strategy_daemon(opts: &Options) -> Result<Outcome> {
-    if opts.target.is_path().await {
-        debug!("input appears to be a file path, skipping strategy");
-        return Ok(Outcome::Skipped);
+/// Extract an image in a batch, then run its hook.
+///
+/// A failing hook fails the image rather than exiting, so that the rest of the batch continues.
+async fn extract_batch_image(opts: &Options) -> Result<(PathBuf, Report)> {
+    let (output, report) = extract_image(opts).await?;
+    if let Some(command) = &opts.exec {
+        exec::run(command, &output).await?;
     }
+    Ok((output, report))
+}
 
-    let layer_filters = opts.layer_filters()?;
-    let file_filters = opts.file_filters()?;
-    let daemon = Daemon::builder()
-        .reference(&opts.target.image)
-        .layer_filters(layer_filters)
-        .file_filters(file_filters)
-        .build()
-        .await
-        .context("build daemon reference")?;
+/// Extract the image as configured by the options,
+/// reporting the output directory and the report written to it.
+///
+/// The report isn't printed and the `--exec` hook isn't run; that's left to the caller.
+pub async fn extract_image(opts: &Options) -> Result<(PathBuf, Report)> {
+    if opts.sandbox && !sandbox::SUPPORTED {
+        bail!("--sandbox requires circe to be built for Linux with the `sandbox` feature");
+    }
 
-    tracing::info!("pulled image from daemon");
-    extract_layers(opts, daemon)
+    info!("extracting image");
+    let progress = progress::Recorder::new(progress::reporter(opts.quiet));
+    let progress = match opts.provenance {
+        true => Arc::new(progress.with_provenance()),
+        false => Arc::new(progress),
+    };
+    let source = detect(opts, progress.clone()).await?;
+    extract_layers(opts, source, progress)
         .await
         .context("extract layers")
-        .map(|_| Outcome::Success)
 }
 
-async fn strategy_tarball(opts: &Options) -> Result<Outcome> {
-    let path = PathBuf::from(&opts.target.image);
-    if matches!(tokio::fs::try_exists(&path).await, Err(_) | Ok(false)) {
-        bail!("path does not exist: {path:?}");
-    }
-
-    let layer_filters = opts.layer_filters()?;
-    let file_filters = opts.file_filters()?;
-    let name = path
-        .file_name()
-        .map(|name| name.to_string_lossy())
-        .unwrap_or_else(|| opts.target.image.clone().into())
-        .to_string();
-
-    let tarball = Tarball::builder()
-        .path(path)
-        .name(name)
-        .file_filters(file_filters)
-        .layer_filters(layer_filters)
-        .build()
-        .await
-        .context("build tarball reference")?;
+/// Estimate the disk space needed to extract the image as configured by the options, without extracting it.
+pub async fn estimate_image(opts: &Options) -> Result<Estimate> {
+    info!("estimating image size");
+    let progress = Arc::new(progress::Recorder::new(progress::reporter(opts.quiet)));
+    let source = detect(opts, progress).await?;
+    let layers = source.layers().await.context("list layers")?;
+    check_empty(opts, &layers)?;
 
-    tracing::info!("extracting layers from tarball");
-    extract_layers(opts, tarball)
-        .await
-        .context("extract layers")
-        .map(|_| Outcome::Success)
+    let strategies = strategies(opts, layers);
+    Ok(Estimate::new(&strategies, Path::new(&opts.output_dir)))
 }
 
-#[tracing::instrument]
-async fn extract_layers(opts: &Options, registry: impl Source) -> Result<()> {
+/// Detect the source of the image as configured by the options.
+async fn detect(opts: &Options, progress: Arc<progress::Recorder>) -> Result<AnySource> {
+    let collisions = match opts.strict {
+        true => CaseCollisions::Error,
+        false => CaseCollisions::Rename,
+    };
+
+    let options = source::Options::builder()
+        .maybe_platform(opts.target.platform.clone())
+        .maybe_annotations(opts.target.annotations.clone())
+        .maybe_manifest_digest(opts.target.manifest_digest.clone())
+        .maybe_auth(opts.target.auth().await?)
+        .buffering(opts.target.buffering())
+        .timeouts(opts.target.timeouts.registry())
+        .maybe_limit_rate(opts.target.limit_rate)
+        .maybe_pull_policy(opts.target.pull_policy)
+        .maybe_kind(opts.target.source)
+        .daemon(opts.target.daemon())
+        .maybe_cache(opts.target.cache())
+        .layer_filters(opts.layer_filters()?)
+        .file_filters(opts.file_filters()?)
+        .case_collisions(collisions)
+        .maybe_deleted_dir(
+            opts.include_deleted
+                .then(|| PathBuf::from(&opts.output_dir).join(Report::DELETED_DIR)),
+        )
+        .filter_commands(opts.filter_commands()?)
+        .limits(opts.limits())
+        .progress(progress)
+        .build();
+
+    opts.target.detect(options).await
+}
+
+#[tracing::instrument(skip(progress))]
+async fn extract_layers(
+    opts: &Options,
+    registry: impl Unpack + Sync,
+    progress: Arc<progress::Recorder>,
+) -> Result<(PathBuf, Report)> {
+    let identity = registry.identity().await.context("fetch identity")?;
+    if let Some(extracted) = extracted(opts, &identity).await {
+        return Ok(extracted);
+    }
+
     let layers = registry.layers().await.context("list layers")?;
-    if layers.is_empty() {
+    check_empty(opts, &layers)?;
+
+    let diff_ids = diff_ids(&layers);
+    if opts.report_only_squashed_listing {
+        return report_listing(opts, registry, identity, &layers, progress).await;
+    }
+
+    let strategies = strategies(opts, layers);
+    let output = canonicalize_output_dir(&opts.output_dir, opts.overwrite)?;
+    let partial = Partial::register(&output);
+    let estimate = Estimate::new(&strategies, &output);
+    if !opts.skip_space_check {
+        estimate.check()?;
+    }
+
+    // Layers are buffered in the temporary directory while they're applied;
+    // if it's too small they're buffered in the output directory instead, which is cleaned up once extraction ends.
+    let spill = Spill::install(&output);
+    if estimate.spill {
+        info!(dir = ?spill.dir(), "temporary directory is too small for the largest layer: spilling to output directory");
+        spill.activate().context("spill to output directory")?;
+    }
+
+    let layers = match opts.sandbox {
+        false => extract(&registry, &output, strategies, progress.as_ref())
+            .await
+            .map_err(eyre::Report::from),
+        true => {
+            let temp = std::env::temp_dir();
+            let mut writable = vec![output.as_path(), temp.as_path()];
+            if let Some(store) = &opts.layer_store {
+                std::fs::create_dir_all(store).context("create layer store")?;
+                writable.push(store);
+            }
+
+            sandbox::run(&writable, || {
+                extract(&registry, &output, strategies, progress.as_ref())
+            })
+        }
+    };
+    drop(spill);
+    let layers = layers.context("extract image")?;
+
+    if !opts.emit_empty_dirs {
+        let removed = prune_empty_dirs(&layers)
+            .await
+            .context("prune empty directories")?;
+        debug!(removed, "pruned empty directories");
+    }
+
+    let hash_mode = opts
+        .hash_files
+        .or_else(|| opts.fs_verity.map(|_| HashFiles::default()));
+    let files = match hash_mode {
+        None => None,
+        Some(mode) => {
+            let mut files = hash_files(&output, &layers).await.context("hash files")?;
+            if let Some(verity) = opts.fs_verity {
+                verity_digests(&output, &mut files)
+                    .await
+                    .context("compute fs-verity digests")?;
+                if let FsVerity::Enable = verity {
+                    verity::enable(&output, &files)
+                        .await
+                        .context("enable fs-verity")?;
+                }
+            }
+
+            match mode {
+                HashFiles::Report => Some(files),
+                HashFiles::Sidecar => {
+                    Report::write_files(&output, &files)
+                        .await
+                        .context("write file digests to disk")?;
+                    None
+                }
+            }
+        }
+    };
+
+    let report = Report::builder()
+        .digest(identity.resolved_digest.to_string())
+        .identity(identity)
+        .layers(layers)
+        .diff_ids(diff_ids)
+        .maybe_files(files)
+        .collisions(progress.collisions(&output))
+        .compression(progress.compression())
+        .layer_details(progress.layer_details())
+        .options(opts.output_options())
+        .build();
+
+    report
+        .write(&output)
+        .await
+        .context("write report to disk")?;
+
+    if opts.whiteouts {
+        Report::write_whiteouts(&output, &progress.whiteouts(&output))
+            .await
+            .context("write whiteouts to disk")?;
+    }
+
+    if opts.provenance {
+        Report::write_provenance(&output, &progress.provenance(&output))
+            .await
+            .context("write provenance to disk")?;
+    }
+
+    partial.complete();
+    Ok((output, report))
+}
+
+/// Fail if there are no layers to extract and the options require some,
+/// otherwise note that the report will be empty.
+fn check_empty(opts: &Options, layers: &[Layer]) -> Result<()> {
+    if !layers.is_empty() {
+        return Ok(());
+    }
+    if opts.fail_on_empty {
         bail!("no layers to extract found in image");
     }
+    warn!("no layers to extract found in image; the output will be empty");
+    Ok(())
+}
 
-    let strategies = match opts.layers {
+/// How the layers are extracted, as configured by the options.
+///
+/// Modes that squash no layers (like `squash-other` for an image with a single layer) have nothing to extract,
+/// so there's no strategy for them.
+fn strategies(opts: &Options, layers: Vec<Layer>) -> Vec<Strategy> {
+    let strategies = match opts.layers.unwrap_or_default() {
         Mode::Squash => vec![Strategy::Squash(layers)],
         Mode::SquashOther => vec![Strategy::Squash(layers.into_iter().skip(1).collect())],
         Mode::Base => vec![Strategy::Squash(layers.into_iter().take(1).collect())],
         Mode::Separate => layers.into_iter().map(Strategy::Separate).collect(),
         Mode::BaseAndSquashOther => match layers.as_slice() {
-            [] => unreachable!(),
+            [] => vec![],
             [base] => vec![Strategy::Separate(base.clone())],
             [base, rest @ ..] => vec![
                 Strategy::Separate(base.clone()),
@@ -299,16 +1236,50 @@ async fn extract_layers(opts: &Options, registry: impl Source) -> Result<()> {
             ],
         },
     };
+    let strategies = strategies
+        .into_iter()
+        .filter(|strategy| !matches!(strategy, Strategy::Squash(layers) if layers.is_empty()));
 
+    match &opts.layer_store {
+        None => strategies.collect(),
+        Some(store) => strategies
+            .map(|strategy| match strategy {
+                Strategy::Separate(layer) => Strategy::Stored(layer, store.clone()),
+                strategy => strategy,
+            })
+            .collect(),
+    }
+}
+
+/// Pair the digest of each layer with its diff ID, for the layers whose diff ID is known.
+fn diff_ids(layers: &[Layer]) -> Vec<(Digest, Digest)> {
+    layers
+        .iter()
+        .filter_map(|layer| Some((layer.digest.clone(), layer.diff_id.clone()?)))
+        .collect()
+}
+
+#[tracing::instrument(skip(progress))]
+async fn report_listing(
+    opts: &Options,
+    registry: impl Unpack,
+    identity: Identity,
+    layers: &[Layer],
+    progress: Arc<progress::Recorder>,
+) -> Result<(PathBuf, Report)> {
     let output = canonicalize_output_dir(&opts.output_dir, opts.overwrite)?;
-    let digest = registry.digest().await.context("fetch digest")?;
-    let layers = extract(&registry, &output, strategies)
+    let partial = Partial::register(&output);
+    let vfs = Vfs::squash(&registry, layers, progress.as_ref())
         .await
-        .context("extract image")?;
+        .context("squash image")?;
 
     let report = Report::builder()
-        .digest(digest.to_string())
-        .layers(layers)
+        .digest(identity.resolved_digest.to_string())
+        .identity(identity)
+        .layers(vec![])
+        .diff_ids(diff_ids(layers))
+        .listing(vfs.listing())
+        .options(opts.output_options())
         .build();
 
     report
@@ -316,9 +1287,45 @@ async fn extract_layers(opts: &Options, registry: impl Source) -> Result<()> {
         .await
         .context("write report to disk")?;
 
-    println!("{}", report.render()?);
+    partial.complete();
+    Ok((output, report))
+}
 
-    Ok(())
+/// The output directory and its report, if the image was already extracted to it and `--overwrite` isn't set.
+///
+/// The report is written once extraction succeeds, so a directory whose report records the same digest
+/// (and the same options that shape the output, like filters or `--layers`) already holds the image;
+/// anything else, like a partial extraction, an extraction with other options, or a report that can't be read,
+/// is left for [`canonicalize_output_dir`] to reject.
+async fn extracted(opts: &Options, identity: &Identity) -> Option<(PathBuf, Report)> {
+    if opts.overwrite {
+        return None;
+    }
+
+    let output = std::fs::canonicalize(&opts.output_dir).ok()?;
+    let content = tokio::fs::read_to_string(output.join(Report::FILENAME))
+        .await
+        .ok()?;
+    let report = match Report::parse(&content) {
+        Ok(report) => report,
+        Err(err) => {
+            debug!(?err, ?output, "unable to read existing report");
+            return None;
+        }
+    };
+
+    let digest = identity.resolved_digest.to_string();
+    if report.digest != digest {
+        info!(?output, existing = %report.digest, %digest, "output directory contains another extraction");
+        return None;
+    }
+    if report.options.as_deref() != Some(opts.output_options().as_str()) {
+        info!(?output, %digest, "output directory contains an extraction with other options");
+        return None;
+    }
+
+    info!(?output, %digest, "image already extracted to output directory; skipping extraction");
+    Some((output, report))
 }
 
 /// Given a (probably relative) path to a directory, canonicalize it to an absolute path.
@@ -347,3 +1354,55 @@ fn canonicalize_output_dir(path: &str, overwrite: bool) -> Result<PathBuf> {
     std::fs::create_dir_all(&path).context("create parent dir")?;
     std::fs::canonicalize(&path).context("canonicalize path")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    #[test_case("1", Some(1); "bytes")]
+    #[test_case("500K", Some(500 * 1024); "kibibytes")]
+    #[test_case("2m", Some(2 * 1024 * 1024); "mebibytes")]
+    #[test_case("1G", Some(1024 * 1024 * 1024); "gibibytes")]
+    #[test_case("0", None; "zero")]
+    #[test_case("K", None; "suffix_only")]
+    #[test_case("1.5M", None; "fraction")]
+    #[test_case("20000000000G", None; "overflow")]
+    #[test]
+    fn rate(input: &str, expected: Option<u64>) {
+        let rate = parse_rate(input).ok().map(NonZeroU64::get);
+        pretty_assertions::assert_eq!(expected, rate);
+    }
+
+    #[test_case(Mode::Squash, 0, &[]; "squash_empty")]
+    #[test_case(Mode::Separate, 0, &[]; "separate_empty")]
+    #[test_case(Mode::BaseAndSquashOther, 0, &[]; "base_and_squash_other_empty")]
+    #[test_case(Mode::SquashOther, 1, &[]; "squash_other_single")]
+    #[test_case(Mode::BaseAndSquashOther, 1, &[1]; "base_and_squash_other_single")]
+    #[test_case(Mode::BaseAndSquashOther, 3, &[1, 2]; "base_and_squash_other")]
+    #[test]
+    fn strategies(mode: Mode, count: u8, expected: &[usize]) {
+        let layers = (0..count)
+            .map(|byte| {
+                Layer::builder()
+                    .digest(Digest::from_hash(vec![byte; 32]))
+                    .size(0)
+                    .media_type(circe_lib::LayerMediaType::Oci(vec![]))
+                    .build()
+            })
+            .collect();
+        let opts = Options {
+            layers: Some(mode),
+            ..Default::default()
+        };
+
+        let sizes = super::strategies(&opts, layers)
+            .into_iter()
+            .map(|strategy| match strategy {
+                Strategy::Squash(layers) => layers.len(),
+                Strategy::Separate(_) | Strategy::Stored(..) => 1,
+            })
+            .collect::<Vec<_>>();
+        pretty_assertions::assert_eq!(expected, sizes);
+    }
+}
@@ -0,0 +1,135 @@
+use circe_lib::{source, Digest, Identity, Source};
+use clap::Parser;
+use color_eyre::eyre::{Context, Result};
+use derive_more::Debug;
+use serde::Serialize;
+use tracing::debug;
+
+use crate::{config::Config, extract::Target, template};
+
+#[derive(Debug, Parser)]
+pub struct Options {
+    /// Target container image to inspect
+    #[clap(flatten)]
+    target: Target,
+
+    /// Render the output with a template instead of as JSON
+    ///
+    /// Templates are a small subset of Go templates, like the docker CLI:
+    /// `{{.Field}}` renders a field (e.g. `{{.Digest}}` or `{{.ShortDigest}}`),
+    /// `{{.}}` renders the whole output, and `{{json .Field}}` renders a field as JSON.
+    /// Fields that aren't strings or numbers, like `{{.Layers}}`, are always rendered as JSON.
+    #[arg(long, short)]
+    format: Option<String>,
+}
+
+impl Options {
+    /// Fill in the options that weren't provided from the configuration file.
+    pub fn configure(&mut self, config: &Config) {
+        self.target.configure(config);
+    }
+}
+
+/// The details about an image reported by `inspect`.
+#[derive(Debug, Serialize)]
+struct Inspection {
+    name: String,
+    identity: Identity,
+    digest: Digest,
+    short_digest: String,
+    layers: Vec<LayerInspection>,
+
+    /// The manifest this image refers to through its `subject` field;
+    /// for artifacts like signatures and SBOMs, this is the image they describe.
+    subject: Option<SubjectInspection>,
+}
+
+/// The details about a layer reported by `inspect`.
+#[derive(Debug, Serialize)]
+struct LayerInspection {
+    digest: Digest,
+    short_digest: String,
+
+    /// The digest of the layer's uncompressed content, if the image config lists it.
+    diff_id: Option<Digest>,
+
+    size: i64,
+    media_type: String,
+}
+
+/// The details about the manifest an image refers to, reported by `inspect`.
+#[derive(Debug, Serialize)]
+struct SubjectInspection {
+    digest: Digest,
+    short_digest: String,
+    size: i64,
+    media_type: String,
+}
+
+#[tracing::instrument]
+pub async fn main(opts: Options) -> Result<()> {
+    let options = source::Options::builder()
+        .maybe_platform(opts.target.platform.clone())
+        .maybe_annotations(opts.target.annotations.clone())
+        .maybe_manifest_digest(opts.target.manifest_digest.clone())
+        .maybe_auth(opts.target.auth().await?)
+        .buffering(opts.target.buffering())
+        .timeouts(opts.target.timeouts.registry())
+        .maybe_limit_rate(opts.target.limit_rate)
+        .maybe_pull_policy(opts.target.pull_policy)
+        .maybe_kind(opts.target.source)
+        .daemon(opts.target.daemon())
+        .maybe_cache(opts.target.cache())
+        .build();
+
+    let source = opts.target.detect(options).await?;
+
+    let inspection = inspect(&source).await.context("inspect image")?;
+    debug!(?inspection, "inspected image");
+
+    let rendered = match &opts.format {
+        Some(format) => template::render(format, &inspection).context("render template")?,
+        None => serde_json::to_string_pretty(&inspection).context("render inspection")?,
+    };
+    println!("{rendered}");
+
+    Ok(())
+}
+
+async fn inspect(source: &impl Source) -> Result<Inspection> {
+    let name = source.name().await.context("get image name")?;
+    let identity = source.identity().await.context("get image identity")?;
+    let digest = identity.resolved_digest.clone();
+    let layers = source
+        .layers()
+        .await
+        .context("list layers")?
+        .into_iter()
+        .map(|layer| LayerInspection {
+            short_digest: layer.digest.short(),
+            digest: layer.digest,
+            diff_id: layer.diff_id,
+            size: layer.size,
+            media_type: layer.media_type.to_string(),
+        })
+        .collect();
+    let subject = source
+        .subject()
+        .await
+        .context("get image subject")?
+        .map(|subject| SubjectInspection {
+            short_digest: subject.digest.short(),
+            digest: subject.digest,
+            size: subject.size,
+            media_type: subject.media_type,
+        });
+
+    Ok(Inspection {
+        name,
+        identity,
+        short_digest: digest.short(),
+        digest,
+        layers,
+        subject,
+    })
+}
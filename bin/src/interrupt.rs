@@ -0,0 +1,99 @@
+//! Stops circe cleanly when it's interrupted with Ctrl-C (SIGINT).
+//!
+//! The running command is dropped rather than left to finish, which cancels in-flight downloads
+//! and removes the temporary files and spill directories it was using.
+//! Output that was being written when circe was interrupted, registered with [`Partial::register`],
+//! is then removed so that it isn't mistaken for complete output (or rejected as existing output by the next run),
+//! and circe exits with [`Failure::Interrupted`](crate::exit::Failure::Interrupted).
+//!
+//! Extraction with `--sandbox` runs on its own thread, which isn't interrupted;
+//! circe stops once the layer it's extracting is done.
+
+use std::{
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use tracing::{debug, info, warn};
+
+/// Output that is being written and is incomplete until [`Partial::complete`] is called.
+static PARTIAL: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// A file or directory that is being written, removed if circe is interrupted before it's complete.
+#[derive(Debug)]
+#[must_use = "output is removed when circe is interrupted unless it's completed"]
+pub struct Partial {
+    path: PathBuf,
+}
+
+impl Partial {
+    /// Register the output at the path as being written.
+    pub fn register(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        if let Ok(mut partial) = PARTIAL.lock() {
+            partial.push(path.clone());
+        }
+        Self { path }
+    }
+
+    /// Mark the output as complete, so that it's kept if circe is interrupted.
+    pub fn complete(self) {
+        if let Ok(mut partial) = PARTIAL.lock() {
+            partial.retain(|path| path != &self.path);
+        }
+    }
+}
+
+/// Run the future until it completes or circe is interrupted.
+///
+/// If circe is interrupted the future is dropped, partial output is removed, and `None` is returned.
+pub async fn or_interrupted<F: Future>(future: F) -> Option<F::Output> {
+    let mut future = Box::pin(future);
+    tokio::select! {
+        // Checked first, so that an interrupt received while the future blocked the thread isn't missed.
+        biased;
+        () = interrupted() => {}
+        output = &mut future => return Some(output),
+    }
+
+    warn!("interrupted: stopping");
+    drop(future);
+    remove_partial();
+    None
+}
+
+/// Resolve once circe is interrupted; if interrupts can't be observed, never resolve.
+async fn interrupted() {
+    if let Err(err) = tokio::signal::ctrl_c().await {
+        warn!(?err, "unable to listen for interrupts");
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Remove all output registered as partial.
+fn remove_partial() {
+    let paths = match PARTIAL.lock() {
+        Ok(mut partial) => std::mem::take(&mut *partial),
+        Err(_) => return,
+    };
+    for path in paths {
+        match remove(&path) {
+            Ok(()) => info!(?path, "removed partial output"),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                debug!(?path, "partial output already removed")
+            }
+            Err(err) => warn!(error = ?err, "remove partial output {path:?}"),
+        }
+    }
+}
+
+/// Remove the file or directory at the path.
+fn remove(path: &Path) -> io::Result<()> {
+    if std::fs::symlink_metadata(path)?.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    }
+}
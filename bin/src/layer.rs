@@ -0,0 +1,122 @@
+use circe_lib::{source, Layer, Source, Unpack};
+use clap::{Parser, Subcommand};
+use color_eyre::{
+    eyre::{bail, eyre, Context, Result},
+    Section, SectionExt,
+};
+use derive_more::Debug;
+use tracing::{debug, info};
+
+use crate::{config::Config, extract::Target};
+
+#[derive(Debug, Parser)]
+pub struct Options {
+    #[command(subcommand)]
+    command: Command,
+}
+
+impl Options {
+    /// Fill in the options that weren't provided from the configuration file.
+    pub fn configure(&mut self, config: &Config) {
+        match &mut self.command {
+            Command::Headers(opts) => opts.target.configure(config),
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Print the tar header of every entry in a layer, without extracting it
+    ///
+    /// Each entry is printed as it is recorded in the layer tarball,
+    /// including its type flag, mode, ownership, and link target.
+    /// PAX extension and GNU long name entries are listed in their own right
+    /// (along with their records) rather than applied to the entries that follow them,
+    /// which makes this useful for debugging malformed images.
+    #[clap(verbatim_doc_comment)]
+    Headers(Headers),
+}
+
+#[derive(Debug, Parser)]
+struct Headers {
+    /// Target container image containing the layer
+    #[clap(flatten)]
+    target: Target,
+
+    /// The layer to inspect, by digest
+    ///
+    /// The algorithm may be omitted, and any unique prefix of the hex digest is accepted;
+    /// run `circe list` to see the layers in the image.
+    #[arg(long)]
+    layer: String,
+}
+
+#[tracing::instrument]
+pub async fn main(opts: Options) -> Result<()> {
+    match opts.command {
+        Command::Headers(opts) => headers(opts).await,
+    }
+}
+
+#[tracing::instrument]
+async fn headers(opts: Headers) -> Result<()> {
+    let options = source::Options::builder()
+        .maybe_platform(opts.target.platform.clone())
+        .maybe_annotations(opts.target.annotations.clone())
+        .maybe_manifest_digest(opts.target.manifest_digest.clone())
+        .maybe_auth(opts.target.auth().await?)
+        .buffering(opts.target.buffering())
+        .timeouts(opts.target.timeouts.registry())
+        .maybe_limit_rate(opts.target.limit_rate)
+        .maybe_pull_policy(opts.target.pull_policy)
+        .maybe_kind(opts.target.source)
+        .daemon(opts.target.daemon())
+        .maybe_cache(opts.target.cache())
+        .build();
+
+    let source = opts.target.detect(options).await?;
+
+    let layer = select_layer(&source, &opts.layer)
+        .await
+        .context("select layer")?;
+
+    info!(layer = %layer, "reading layer headers");
+    let Some(headers) = source.headers(&layer).await.context("read headers")? else {
+        bail!("layer {layer} is a foreign layer, which is not distributed with the image");
+    };
+
+    debug!(layer = %layer, headers = %headers.len(), "read headers");
+    let rendered = serde_json::to_string_pretty(&headers).context("render headers")?;
+    println!("{rendered}");
+
+    Ok(())
+}
+
+/// Find the layer in the image whose digest matches the selector.
+async fn select_layer(source: &impl Source, selector: &str) -> Result<Layer> {
+    let layers = source.layers().await.context("list layers")?;
+    let selector = selector.to_lowercase();
+    let hex = selector
+        .split_once(':')
+        .map_or(selector.as_str(), |(_, hex)| hex);
+
+    let mut matches = layers.iter().filter(|layer| {
+        layer.digest.to_string() == selector || layer.digest.as_hex().starts_with(hex)
+    });
+
+    match (matches.next(), matches.next()) {
+        (Some(layer), None) => Ok(layer.clone()),
+        (Some(_), Some(_)) => Err(eyre!("layer '{selector}' is ambiguous"))
+            .with_section(|| available(&layers).header("Layers:")),
+        (None, _) => Err(eyre!("layer '{selector}' not found in image"))
+            .with_section(|| available(&layers).header("Layers:")),
+    }
+}
+
+fn available(layers: &[Layer]) -> String {
+    layers
+        .iter()
+        .map(|layer| layer.digest.to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
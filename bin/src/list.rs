@@ -1,102 +1,194 @@
 use circe_lib::{
-    docker::{Daemon, Tarball},
-    registry::Registry,
-    Authentication, Reference, Source,
+    entries::EntryKind, extract::LayerCompression, progress::Silent, source, Digest, Filters,
+    Unpack,
 };
 use clap::Parser;
-use color_eyre::eyre::{bail, Context, Result};
+use color_eyre::eyre::{Context, Result};
 use derive_more::Debug;
+use futures_lite::StreamExt;
+use indicatif::HumanBytes;
 use pluralizer::pluralize;
-use std::{collections::HashMap, path::PathBuf, str::FromStr};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
 use tracing::{debug, info};
 
-use crate::{extract::Target, try_strategies, Outcome};
+use crate::{
+    batch::{self, Batch, Outcome},
+    config::Config,
+    extract::{Predicates, Target},
+    output::{Format, Output, Record},
+    progress,
+};
 
-#[derive(Debug, Parser)]
+#[derive(Debug, Clone, Parser)]
+#[command(mut_arg("image", |arg| arg.required(false).required_unless_present("batch").default_value("")))]
 pub struct Options {
     /// Target container image to list layers and files from
     #[clap(flatten)]
     target: Target,
-}
 
-#[tracing::instrument]
-pub async fn main(opts: Options) -> Result<()> {
-    info!("extracting image");
-    try_strategies!(&opts; strategy_tarball, strategy_daemon, strategy_registry)
-}
+    /// Only list files that satisfy these filters
+    ///
+    /// Sizes (with `--sizes`) and summaries still count every file.
+    #[clap(flatten)]
+    predicates: Predicates,
 
-async fn strategy_registry(opts: &Options) -> Result<Outcome> {
-    if opts.target.is_path().await {
-        debug!("input appears to be a file path, skipping strategy");
-        return Ok(Outcome::Skipped);
-    }
+    /// Print the compressed and uncompressed size of each layer instead of its files
+    ///
+    /// Sizes are observed while the layers are decompressed, along with the ratio between them;
+    /// layers with a low ratio are good candidates for recompression (e.g. with zstd).
+    #[arg(long)]
+    summary: bool,
 
-    let reference = Reference::from_str(&opts.target.image)?;
-    let auth = match (&opts.target.username, &opts.target.password) {
-        (Some(username), Some(password)) => Authentication::basic(username, password),
-        _ => Authentication::docker(&reference).await?,
-    };
+    /// Print the cumulative size of the files in each top-level directory instead of the files themselves
+    ///
+    /// Sizes are reported for each layer, and for the squashed image (as `extract --layers squash` would write it),
+    /// where content deleted or replaced by later layers isn't counted.
+    /// Sizes are read from the tar headers of the layers, so nothing is extracted.
+    #[arg(long, conflicts_with = "summary")]
+    sizes: bool,
 
-    let registry = Registry::builder()
-        .maybe_platform(opts.target.platform.as_ref())
-        .reference(reference)
-        .auth(auth)
-        .build()
-        .await
-        .context("configure remote registry")?;
+    /// Print the digest, size, media type, and creating command of each layer instead of its files
+    ///
+    /// Only the manifest and image config are read, so no layers are downloaded;
+    /// this is much faster than listing files for large images.
+    #[arg(long, conflicts_with_all = ["summary", "sizes"])]
+    layers_only: bool,
 
-    list_files(registry)
-        .await
-        .context("list files")
-        .map(|_| Outcome::Success)
+    /// The format in which the output is written
+    ///
+    /// `json` writes a single document; the other formats write a record for each file
+    /// (or for each layer with `--summary` or `--layers-only`, or each directory with `--sizes`).
+    /// Files are written as they're enumerated with the other formats, so the listing isn't held in memory;
+    /// prefer them for images with many files.
+    /// With `--sizes`, `text` (or its alias `table`) writes a table with human-readable sizes.
+    #[arg(long, value_enum, default_value_t = Format::Json)]
+    format: Format,
+
+    /// Write the output to this file instead of stdout, replacing it if it exists
+    ///
+    /// With `--batch`, this is the directory to which the listing of each image is written instead.
+    #[arg(long)]
+    output: Option<PathBuf>,
+
+    /// List many images, writing each listing to a file named after the image
+    #[clap(flatten)]
+    batch: Batch,
 }
 
-async fn strategy_daemon(opts: &Options) -> Result<Outcome> {
-    if opts.target.is_path().await {
-        debug!("input appears to be a file path, skipping strategy");
-        return Ok(Outcome::Skipped);
+impl Options {
+    /// Fill in the options that weren't provided from the configuration file.
+    pub fn configure(&mut self, config: &Config) {
+        self.target.configure(config);
     }
+}
 
-    let daemon = Daemon::builder()
-        .reference(&opts.target.image)
-        .build()
-        .await
-        .context("build daemon reference")?;
+#[tracing::instrument]
+pub async fn main(opts: Options) -> Result<()> {
+    if opts.batch.batch.is_some() {
+        return list_batch(opts).await;
+    }
+    list(&opts).await
+}
 
-    tracing::info!("pulled image from daemon");
-    list_files(daemon)
+/// List every image in the batch, writing each listing to its own file inside the output directory.
+async fn list_batch(opts: Options) -> Result<()> {
+    let dir = batch::output(&opts.target.image, opts.output.as_deref())?;
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .with_context(|| format!("create output directory {dir:?}"))?;
+    opts.batch
+        .clone()
+        .run(move |image, name| {
+            let mut opts = opts.clone();
+            let output = dir.join(format!("{name}.{}", opts.format.extension()));
+            opts.target.image = image.clone();
+            opts.output = Some(output.clone());
+            async move {
+                match list(&opts).await {
+                    Ok(()) => Outcome {
+                        image,
+                        output,
+                        digest: None,
+                        identity: None,
+                        error: None,
+                    },
+                    Err(err) => Outcome::failed(image, output, &err),
+                }
+            }
+        })
         .await
-        .context("list files")
-        .map(|_| Outcome::Success)
 }
 
-async fn strategy_tarball(opts: &Options) -> Result<Outcome> {
-    let path = PathBuf::from(&opts.target.image);
-    if matches!(tokio::fs::try_exists(&path).await, Err(_) | Ok(false)) {
-        bail!("path does not exist: {path:?}");
+async fn list(opts: &Options) -> Result<()> {
+    info!("extracting image");
+    let progress = Arc::new(progress::Recorder::new(Arc::new(Silent)));
+    let options = source::Options::builder()
+        .maybe_platform(opts.target.platform.clone())
+        .maybe_annotations(opts.target.annotations.clone())
+        .maybe_manifest_digest(opts.target.manifest_digest.clone())
+        .maybe_auth(opts.target.auth().await?)
+        .buffering(opts.target.buffering())
+        .timeouts(opts.target.timeouts.registry())
+        .maybe_limit_rate(opts.target.limit_rate)
+        .maybe_pull_policy(opts.target.pull_policy)
+        .maybe_kind(opts.target.source)
+        .daemon(opts.target.daemon())
+        .maybe_cache(opts.target.cache())
+        .file_filters(Filters::predicates(opts.predicates.predicates()))
+        .progress(progress.clone())
+        .build();
+
+    let source = opts.target.detect(options).await?;
+
+    if opts.layers_only {
+        let inventory = inventory(source).await.context("list layers")?;
+        let mut output = Output::create(opts.format, opts.output.as_deref())?;
+        output.document(&inventory, &inventory)?;
+        return output.finish();
     }
 
-    let name = path
-        .file_name()
-        .map(|name| name.to_string_lossy())
-        .unwrap_or_else(|| opts.target.image.clone().into())
-        .to_string();
-    let tarball = Tarball::builder()
-        .path(path)
-        .name(name)
-        .build()
-        .await
-        .context("build tarball reference")?;
+    if opts.sizes {
+        let sizes = sizes(source).await.context("compute sizes")?;
+        let mut output = Output::create(opts.format, opts.output.as_deref())?;
+        match opts.format {
+            Format::Text => output.text(&sizes.table())?,
+            _ => output.document(&sizes, sizes.records())?,
+        }
+        return output.finish();
+    }
+
+    if opts.summary {
+        list_files(source).await.context("list files")?;
+        let compression = progress.compression();
+        let mut output = Output::create(opts.format, opts.output.as_deref())?;
+        output.document(
+            &compression,
+            compression.layers.iter().map(LayerRecord::from),
+        )?;
+        return output.finish();
+    }
 
-    tracing::info!("listing files in tarball");
-    list_files(tarball)
+    if opts.format == Format::Json {
+        let listing = list_files(source).await.context("list files")?;
+        let mut output = Output::create(opts.format, opts.output.as_deref())?;
+        output.json(&listing)?;
+        return output.finish();
+    }
+
+    let mut output = Output::create(opts.format, opts.output.as_deref())?;
+    stream_files(source, &mut output)
         .await
-        .context("list files")
-        .map(|_| Outcome::Success)
+        .context("list files")?;
+    output.finish()
 }
 
 #[tracing::instrument]
-async fn list_files(registry: impl Source) -> Result<()> {
+async fn list_files(registry: impl Unpack) -> Result<HashMap<String, Vec<String>>> {
     let layers = registry.layers().await.context("list layers")?;
     let count = layers.len();
     debug!(?count, ?layers, "listed layers");
@@ -114,8 +206,451 @@ async fn list_files(registry: impl Source) -> Result<()> {
         listing.insert(descriptor.digest.to_string(), files);
     }
 
-    let rendered = serde_json::to_string_pretty(&listing).context("render listing")?;
-    println!("{rendered}");
+    Ok(listing)
+}
+
+/// Write a record for each file to the output as it is enumerated.
+#[tracing::instrument(skip(output))]
+async fn stream_files(registry: impl Unpack, output: &mut Output) -> Result<()> {
+    let layers = registry.layers().await.context("list layers")?;
+    let count = layers.len();
+    debug!(?count, ?layers, "listed layers");
+    info!("enumerated {}", pluralize("layer", count as isize, true));
+
+    for (descriptor, layer) in layers.into_iter().zip(1usize..) {
+        info!(layer = %descriptor, %layer, "reading layer");
+        registry
+            .visit_files(&descriptor, |path| {
+                output.record(&FileRecord {
+                    layer: &descriptor.digest,
+                    path: &path,
+                })
+            })
+            .await
+            .context("list files")?;
+    }
 
     Ok(())
 }
+
+/// A file in a layer, as written by `list` in the record-oriented formats.
+#[derive(Debug, Serialize)]
+struct FileRecord<'a> {
+    layer: &'a Digest,
+    path: &'a str,
+}
+
+impl Record for FileRecord<'_> {
+    fn text(&self) -> String {
+        format!("{}  {}", self.layer, self.path)
+    }
+}
+
+/// The sizes of a layer, as written by `list --summary` in the record-oriented formats.
+///
+/// The `diff_id` of the layer is omitted so that every record has the same fields.
+#[derive(Debug, Serialize)]
+struct LayerRecord<'a> {
+    layer: &'a Digest,
+    compressed: u64,
+    uncompressed: u64,
+    ratio: f64,
+}
+
+impl<'a> From<&'a LayerCompression> for LayerRecord<'a> {
+    fn from(layer: &'a LayerCompression) -> Self {
+        Self {
+            layer: &layer.layer,
+            compressed: layer.compressed,
+            uncompressed: layer.uncompressed,
+            ratio: layer.ratio,
+        }
+    }
+}
+
+impl Record for LayerRecord<'_> {
+    fn text(&self) -> String {
+        format!(
+            "{}  {} -> {} ({:.2}x)",
+            self.layer,
+            HumanBytes(self.compressed),
+            HumanBytes(self.uncompressed),
+            self.ratio
+        )
+    }
+}
+
+/// Describe each layer from the manifest and image config, without reading the layers themselves.
+#[tracing::instrument]
+async fn inventory(source: impl Unpack) -> Result<Vec<LayerInventory>> {
+    let layers = source.layers().await.context("list layers")?;
+    let config = source.config().await.context("read image config")?;
+
+    // History can only be matched to layers by position if there's an entry for every layer.
+    let history = config.layer_history();
+    let history = if history.len() == layers.len() {
+        history
+    } else {
+        Vec::new()
+    };
+    debug!(
+        layers = layers.len(),
+        history = history.len(),
+        "read layers"
+    );
+
+    Ok(layers
+        .into_iter()
+        .enumerate()
+        .map(|(index, layer)| LayerInventory {
+            created_by: history
+                .get(index)
+                .and_then(|history| history.created_by.clone()),
+            media_type: layer.media_type.to_string(),
+            size: layer.size,
+            digest: layer.digest,
+        })
+        .collect())
+}
+
+/// A layer as described by its descriptor and the image config, reported by `list --layers-only`.
+#[derive(Debug, Serialize)]
+struct LayerInventory {
+    digest: Digest,
+
+    /// The size of the layer as distributed, in bytes.
+    size: i64,
+
+    media_type: String,
+
+    /// The command that created the layer, if recorded in the image config's history.
+    created_by: Option<String>,
+}
+
+impl Record for &LayerInventory {
+    fn text(&self) -> String {
+        let size = HumanBytes(u64::try_from(self.size).unwrap_or_default());
+        let created_by = self.created_by.as_deref().unwrap_or_default();
+        format!("{}  {size}  {}  {created_by}", self.digest, self.media_type)
+            .trim_end()
+            .to_string()
+    }
+}
+
+/// Compute the sizes of the files in each layer, and in the squashed image, from the layers' tar headers.
+#[tracing::instrument]
+async fn sizes(source: impl Unpack) -> Result<Sizes> {
+    let layers = source.layers().await.context("list layers")?;
+    info!(
+        "enumerated {}",
+        pluralize("layer", layers.len() as isize, true)
+    );
+
+    let mut sizes = Sizes::default();
+    let mut squashed = Squashed::default();
+    for layer in layers {
+        info!(layer = %layer, "reading layer");
+        let Some(mut entries) = source.entries(&layer).await.context("read entries")? else {
+            debug!(layer = %layer, "skipping foreign layer");
+            continue;
+        };
+
+        let mut content = Content::default();
+        while let Some(entry) = entries.next().await {
+            let entry = entry.context("read entry")?;
+            let path = normalize(entry.path());
+            match entry.whiteout().map(|path| normalize(&path)) {
+                Some(deleted) if deleted.file_name().is_some_and(|name| name == OPAQUE) => {
+                    content
+                        .cleared
+                        .push(deleted.parent().map(Path::to_path_buf).unwrap_or_default());
+                }
+                Some(deleted) => content.deleted.push(deleted),
+                None if entry.kind() == EntryKind::File => {
+                    content.entries.push((path, entry.size()))
+                }
+                None => content.entries.push((path, 0)),
+            }
+        }
+
+        sizes.layers.push(LayerSizes {
+            digest: layer.digest.clone(),
+            sizes: content
+                .entries
+                .iter()
+                .map(|(path, size)| (path, *size))
+                .collect(),
+        });
+        squashed.apply(content);
+    }
+
+    sizes.squashed = squashed.sizes();
+    Ok(sizes)
+}
+
+/// The name of the whiteout file that hides the content of its directory in earlier layers,
+/// as reported by [`circe_lib::entries::Entry::whiteout`].
+const OPAQUE: &str = ".wh..opq";
+
+/// The cumulative sizes of files in an image, reported by `list --sizes`.
+#[derive(Debug, Default, Serialize)]
+struct Sizes {
+    /// The sizes of the files in each layer, in order from the base image to the application.
+    layers: Vec<LayerSizes>,
+
+    /// The sizes of the files in the squashed image;
+    /// files deleted or replaced by later layers aren't counted.
+    squashed: DirectorySizes,
+}
+
+/// The sizes of the files in a layer.
+#[derive(Debug, Serialize)]
+struct LayerSizes {
+    digest: Digest,
+
+    #[serde(flatten)]
+    sizes: DirectorySizes,
+}
+
+/// The total size of a set of files, and the size within each top-level directory.
+///
+/// Files at the root are counted under `/`.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+struct DirectorySizes {
+    total: u64,
+    directories: BTreeMap<String, u64>,
+}
+
+impl<P: AsRef<Path>> FromIterator<(P, u64)> for DirectorySizes {
+    fn from_iter<I: IntoIterator<Item = (P, u64)>>(files: I) -> Self {
+        let mut sizes = Self::default();
+        for (path, size) in files.into_iter().filter(|(_, size)| *size > 0) {
+            let directory = match path.as_ref().components().collect::<Vec<_>>().as_slice() {
+                [directory, _, ..] => format!("/{}", directory.as_os_str().to_string_lossy()),
+                _ => String::from("/"),
+            };
+            *sizes.directories.entry(directory).or_default() += size;
+            sizes.total += size;
+        }
+        sizes
+    }
+}
+
+impl Sizes {
+    /// The size of each directory and the total of each layer, then of the squashed image.
+    fn records(&self) -> Vec<SizeRecord> {
+        self.rows(Digest::to_string)
+    }
+
+    /// The size of each directory and the total of each layer, then of the squashed image,
+    /// with layers named by the function.
+    fn rows(&self, name: impl Fn(&Digest) -> String) -> Vec<SizeRecord> {
+        let layers = self
+            .layers
+            .iter()
+            .map(|layer| (name(&layer.digest), &layer.sizes));
+        layers
+            .chain(std::iter::once((String::from("squashed"), &self.squashed)))
+            .flat_map(|(name, sizes)| {
+                sizes
+                    .directories
+                    .iter()
+                    .map(|(directory, size)| (directory.as_str(), *size))
+                    .chain(std::iter::once(("total", sizes.total)))
+                    .map(move |(directory, size)| SizeRecord {
+                        layer: name.clone(),
+                        directory: directory.to_string(),
+                        size,
+                    })
+            })
+            .collect()
+    }
+
+    /// Render the sizes as a table, with a row for each directory and the total of each layer.
+    fn table(&self) -> String {
+        let rows = self
+            .rows(Digest::short)
+            .into_iter()
+            .map(|record| {
+                [
+                    record.layer,
+                    record.directory,
+                    HumanBytes(record.size).to_string(),
+                ]
+            })
+            .collect::<Vec<_>>();
+
+        let header = [
+            String::from("LAYER"),
+            String::from("DIRECTORY"),
+            String::from("SIZE"),
+        ];
+        let widths = std::iter::once(&header)
+            .chain(&rows)
+            .fold([0; 3], |widths, row| {
+                [0, 1, 2].map(|column| widths[column].max(row[column].len()))
+            });
+        std::iter::once(&header)
+            .chain(&rows)
+            .map(|[layer, directory, size]| {
+                format!(
+                    "{layer:<0$}  {directory:<1$}  {size:>2$}",
+                    widths[0], widths[1], widths[2]
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// The size of a directory (or the total, as `total`) in a layer or the squashed image,
+/// as written by `list --sizes` in the record-oriented formats.
+#[derive(Debug, Serialize)]
+struct SizeRecord {
+    /// The digest of the layer, or `squashed` for the squashed image.
+    layer: String,
+    directory: String,
+    size: u64,
+}
+
+impl Record for SizeRecord {
+    fn text(&self) -> String {
+        format!(
+            "{}  {}  {}",
+            self.layer,
+            self.directory,
+            HumanBytes(self.size)
+        )
+    }
+}
+
+/// The entries of a layer that affect the squashed image.
+#[derive(Debug, Default)]
+struct Content {
+    /// Each entry with the size of its content; only regular files have content.
+    entries: Vec<(PathBuf, u64)>,
+
+    /// Paths deleted from earlier layers by whiteouts.
+    deleted: Vec<PathBuf>,
+
+    /// Directories whose content in earlier layers is hidden by opaque whiteouts.
+    cleared: Vec<PathBuf>,
+}
+
+/// The entries of the image as layers are applied in order, with the size of their content.
+#[derive(Debug, Default)]
+struct Squashed(BTreeMap<PathBuf, u64>);
+
+impl Squashed {
+    /// Apply a layer: whiteouts delete content from earlier layers, then the entries of the layer replace any at the same paths.
+    fn apply(&mut self, content: Content) {
+        for deleted in &content.deleted {
+            self.0.retain(|path, _| !path.starts_with(deleted));
+        }
+        for cleared in &content.cleared {
+            self.0
+                .retain(|path, _| path == cleared || !path.starts_with(cleared));
+        }
+        self.0.extend(content.entries);
+    }
+
+    fn sizes(&self) -> DirectorySizes {
+        self.0.iter().map(|(path, size)| (path, *size)).collect()
+    }
+}
+
+/// Normalize a path in a layer to be relative to the root, without `.` components;
+/// layers variously record paths like `etc/hosts`, `./etc/hosts`, and `/etc/hosts`.
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    fn content(entries: &[(&str, u64)], deleted: &[&str], cleared: &[&str]) -> Content {
+        Content {
+            entries: entries
+                .iter()
+                .map(|(path, size)| (PathBuf::from(path), *size))
+                .collect(),
+            deleted: deleted.iter().map(PathBuf::from).collect(),
+            cleared: cleared.iter().map(PathBuf::from).collect(),
+        }
+    }
+
+    fn directories(sizes: &[(&str, u64)]) -> DirectorySizes {
+        DirectorySizes {
+            total: sizes.iter().map(|(_, size)| size).sum(),
+            directories: sizes
+                .iter()
+                .map(|(directory, size)| (directory.to_string(), *size))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn layer_sizes() {
+        let layer = content(
+            &[
+                ("etc", 0),
+                ("etc/hosts", 10),
+                ("etc/ssl/cert.pem", 5),
+                ("usr/bin/sh", 100),
+                ("README", 1),
+            ],
+            &[],
+            &[],
+        );
+        let sizes = layer
+            .entries
+            .iter()
+            .map(|(path, size)| (path, *size))
+            .collect::<DirectorySizes>();
+        pretty_assertions::assert_eq!(directories(&[("/", 1), ("/etc", 15), ("/usr", 100)]), sizes);
+    }
+
+    #[test_case(content(&[("etc/hosts", 20)], &[], &[]), &[("/etc", 20), ("/usr", 100)]; "replaced")]
+    #[test_case(content(&[], &["etc/hosts"], &[]), &[("/usr", 100)]; "deleted_file")]
+    #[test_case(content(&[], &["usr"], &[]), &[("/etc", 10)]; "deleted_directory")]
+    #[test_case(content(&[("usr/bin/bash", 50)], &[], &["usr/bin"]), &[("/etc", 10), ("/usr", 50)]; "opaque")]
+    #[test_case(content(&[], &["usr/b"], &[]), &[("/etc", 10), ("/usr", 100)]; "deleted_prefix")]
+    #[test]
+    fn squashed_sizes(layer: Content, expected: &[(&str, u64)]) {
+        let mut squashed = Squashed::default();
+        squashed.apply(content(&[("etc/hosts", 10), ("usr/bin/sh", 100)], &[], &[]));
+        squashed.apply(layer);
+        pretty_assertions::assert_eq!(directories(expected), squashed.sizes());
+    }
+
+    #[test_case("./etc/hosts", "etc/hosts"; "dot")]
+    #[test_case("/etc/hosts", "etc/hosts"; "absolute")]
+    #[test_case("etc/hosts", "etc/hosts"; "relative")]
+    #[test]
+    fn normalizes(path: &str, expected: &str) {
+        pretty_assertions::assert_eq!(PathBuf::from(expected), normalize(Path::new(path)));
+    }
+
+    #[test]
+    fn table() {
+        let sizes = Sizes {
+            layers: vec![LayerSizes {
+                digest: Digest::from_hash(vec![0xab; 32]),
+                sizes: directories(&[("/etc", 2048)]),
+            }],
+            squashed: directories(&[("/etc", 2048)]),
+        };
+        let expected = [
+            "LAYER         DIRECTORY      SIZE",
+            "abababababab  /etc       2.00 KiB",
+            "abababababab  total      2.00 KiB",
+            "squashed      /etc       2.00 KiB",
+            "squashed      total      2.00 KiB",
+        ];
+        pretty_assertions::assert_eq!(expected.join("\n"), sizes.table());
+    }
+}
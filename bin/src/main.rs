@@ -5,31 +5,133 @@
 
 use clap::{
     builder::{styling::AnsiColor, Styles},
-    Parser,
+    Parser, ValueEnum,
 };
-use color_eyre::{eyre::Result, Section};
+use color_eyre::eyre::{eyre, Context, Result};
+use std::{path::PathBuf, sync::Mutex};
 use tracing::level_filters::LevelFilter;
-use tracing_subscriber::{self, prelude::*};
+use tracing_subscriber::{self, filter::Targets, fmt::format::FmtSpan, prelude::*};
 
+mod annotate;
+mod batch;
+mod capabilities;
+mod config;
+mod exec;
+mod exit;
 mod extract;
+mod inspect;
+mod interrupt;
+mod layer;
 mod list;
+#[cfg(all(target_os = "linux", feature = "fuse"))]
+mod mount;
+mod output;
+mod prefetch;
+mod progress;
 mod reexport;
+mod repos;
+mod run;
+mod sandbox;
+mod serve;
+mod space;
+mod template;
+mod verity;
+mod watch;
 
 #[derive(Debug, Parser)]
 #[command(version, about, styles = style())]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Report warnings and errors as annotations for a CI system
+    ///
+    /// Warnings, and the error that caused circe to fail, are then shown on the run summary.
+    #[arg(long, global = true, env = "CIRCE_ANNOTATE")]
+    annotate: Option<annotate::Format>,
+
+    /// The format of log output
+    ///
+    /// tree: Human-friendly output, with events nested under the operations that emitted them.
+    /// json: One JSON object per line, for ingestion by log processors.
+    ///
+    /// JSON objects have the fields `timestamp`, `level`, `target`, and `message`,
+    /// along with the event's own fields; `span` and `spans` describe the operations it occurred within.
+    #[arg(
+        long,
+        global = true,
+        env = "CIRCE_LOG_FORMAT",
+        default_value = "tree",
+        verbatim_doc_comment
+    )]
+    log_format: LogFormat,
+
+    /// The format of the error written if circe fails
+    ///
+    /// human: A human-friendly report, with context and suggestions.
+    /// json: One line of JSON with the fields `kind`, `exit_code`, `message`, and `causes`.
+    ///
+    /// Regardless of format, circe exits with a code for the kind of failure:
+    /// 1 for other failures, 2 for invalid arguments, 3 for authentication failures,
+    /// 4 if the image (or other content) wasn't found, 5 for network failures,
+    /// and 6 if the image uses an unsupported format.
+    #[arg(
+        long,
+        global = true,
+        env = "CIRCE_ERROR_FORMAT",
+        default_value = "human",
+        verbatim_doc_comment
+    )]
+    error_format: exit::ErrorFormat,
+
+    /// Record every decision circe makes to a file, for debugging
+    ///
+    /// The file is written as a timeline of JSON objects, one per line, in the same shape as `--log-format json`,
+    /// with debug events from circe included regardless of `RUST_LOG`: media type translation,
+    /// how each layer is decompressed, which filters matched each layer and file, whiteouts, and fallbacks.
+    /// Each operation is also recorded when it starts and when it finishes, along with the time it took.
+    #[arg(long, global = true, env = "CIRCE_TRACE_FILE")]
+    trace_file: Option<PathBuf>,
+
+    #[command(flatten)]
+    reference_defaults: config::ReferenceDefaults,
+
+    #[command(flatten)]
+    host_allowlist: config::HostAllowlist,
+
+    /// Forbid network access, reading images only from local tarballs (including OCI layouts)
+    /// and the local Docker daemon without pulling
+    ///
+    /// Tarballs are checked for every layer of the image before anything is read,
+    /// and the digests of any missing layers are reported. Can also be set with `CIRCE_OFFLINE`.
+    #[arg(long, global = true)]
+    offline: bool,
+}
+
+/// The format of log output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    /// Human-friendly output, with events nested under the operations that emitted them.
+    Tree,
+
+    /// One JSON object per line, for ingestion by log processors.
+    Json,
 }
 
 #[derive(Debug, Parser)]
 enum Commands {
     /// Extract OCI image to a directory
-    Extract(extract::Options),
+    Extract(Box<extract::Options>),
 
     /// Enumerate the layers and files in an OCI image
     List(list::Options),
 
+    /// Print the digest and layers of an OCI image without downloading its layers
+    Inspect(inspect::Options),
+
+    /// Inspect a single layer of an OCI image
+    Layer(layer::Options),
+
     /// Re-export an OCI image for FOSSA CLI
     ///
     /// Unless you work at FOSSA, this is almost definitely not what you want.
@@ -49,45 +151,239 @@ enum Commands {
     ///   with extracted container data
     #[clap(verbatim_doc_comment)]
     Reexport(reexport::Options),
+
+    /// Serve an image on a local address over the OCI distribution API
+    ///
+    /// Tools that can only pull images from a registry (like containerd, or FOSSA CLI's registry path)
+    /// can then pull an image circe reads from a tarball, for example `localhost:5000/image:latest`.
+    /// The image is served under any repository name and tag until circe is stopped.
+    /// The registry is read-only and doesn't require authentication.
+    #[clap(verbatim_doc_comment)]
+    Serve(serve::Options),
+
+    /// Mount the squashed filesystem of an image read-only over FUSE
+    ///
+    /// The image is squashed into memory, then mounted on an existing directory
+    /// until circe is stopped or the filesystem is unmounted (e.g. with `umount`).
+    /// Mounting requires root (or `CAP_SYS_ADMIN`), since circe mounts the filesystem itself rather than with `fusermount`.
+    #[cfg(all(target_os = "linux", feature = "fuse"))]
+    #[clap(verbatim_doc_comment)]
+    Mount(mount::Options),
+
+    /// Detect whether images have changed since they were last checked
+    ///
+    /// Each image's digest is resolved with a `HEAD` request, which registries
+    /// generally don't count against pull rate limits; no layers are downloaded.
+    /// Images whose digest differs from the one recorded in the state file
+    /// are printed as one JSON object per line, so scheduled pipelines
+    /// can limit expensive work such as extraction to images that changed.
+    #[clap(verbatim_doc_comment)]
+    Watch(watch::Options),
+
+    /// Download images into a cache so that later commands can read them without network access
+    ///
+    /// Each image is stored with all of its layers for a single platform in the directory given
+    /// with `--cache-dir`; pass the same directory to other commands to read the images from it.
+    /// Images stored by tag aren't refreshed when the tag changes, so prefetch them again to update them.
+    #[clap(verbatim_doc_comment)]
+    Prefetch(prefetch::Options),
+
+    /// List the repositories in a registry
+    ///
+    /// Repositories are listed with the registry's `/v2/_catalog` endpoint,
+    /// which self-hosted registries like Harbor and the reference `registry` implement.
+    /// Each repository is printed as a reference on its own line by default,
+    /// so the output can be passed to `--batch` of `extract`, `list`, and `reexport`.
+    #[clap(verbatim_doc_comment)]
+    Repos(repos::Options),
+
+    /// Extract the images described by a job file
+    ///
+    /// The job file (YAML or JSON) lists targets, each with its own image, output directory,
+    /// platform, filters, and commands to run once it's extracted.
+    /// Targets share a layer store if one is configured, and a report of every target
+    /// is printed as JSON once they're done.
+    #[clap(verbatim_doc_comment)]
+    Run(run::Options),
+
+    /// Describe what this build of circe supports as JSON
+    ///
+    /// Reports the sources, layer media types, compression codecs,
+    /// authentication methods, and features compiled into this build,
+    /// so that tools which run circe can adapt to the version they have.
+    /// The `schema` field is incremented if existing fields change meaning.
+    #[clap(verbatim_doc_comment)]
+    Capabilities(capabilities::Options),
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    color_eyre::install()?;
+    let Cli {
+        command,
+        annotate,
+        log_format,
+        error_format,
+        trace_file,
+        reference_defaults,
+        host_allowlist,
+        offline,
+    } = Cli::parse();
+
+    // Colors would be written as escape codes in JSON logs.
+    match log_format {
+        LogFormat::Tree => color_eyre::install()?,
+        LogFormat::Json => color_eyre::config::HookBuilder::new()
+            .theme(color_eyre::config::Theme::new())
+            .install()?,
+    }
+
+    let trace = match trace_file {
+        None => None,
+        Some(path) => std::fs::File::create(&path)
+            .with_context(|| format!("create trace file: {path:?}"))
+            .map(Some)?,
+    };
+
+    // Logs are filtered as configured by `RUST_LOG`, unlike the trace file.
+    // `Option` has its own `and_then`, so the layers are combined through the `Layer` trait.
+    let annotations = annotate.map(annotate::Format::layer);
+    let log = tracing_subscriber::Layer::and_then(
+        annotations,
+        (log_format == LogFormat::Json).then(|| {
+            tracing_subscriber::fmt::layer()
+                .json()
+                .flatten_event(true)
+                .with_current_span(true)
+                .with_span_list(true)
+                .with_writer(std::io::stderr)
+        }),
+    )
+    .and_then((log_format == LogFormat::Tree).then(|| {
+        tracing_tree::HierarchicalLayer::default()
+            .with_indent_lines(true)
+            .with_indent_amount(2)
+            .with_thread_ids(false)
+            .with_thread_names(false)
+            .with_verbose_exit(false)
+            .with_verbose_entry(false)
+            .with_deferred_spans(true)
+            .with_bracketed_fields(true)
+            .with_span_retrace(true)
+            .with_targets(false)
+    }))
+    .with_filter(
+        tracing_subscriber::EnvFilter::builder()
+            .with_default_directive(LevelFilter::INFO.into())
+            .from_env_lossy(),
+    );
+
+    // The trace file records debug events from circe no matter what is logged,
+    // so that it captures enough to diagnose a report without reproducing it.
+    let trace = trace.map(|file| {
+        tracing_subscriber::fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(true)
+            .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
+            .with_writer(Mutex::new(file))
+            .with_filter(
+                Targets::new()
+                    .with_default(LevelFilter::INFO)
+                    .with_target("circe", LevelFilter::DEBUG)
+                    .with_target("circe_lib", LevelFilter::DEBUG),
+            )
+    });
+
     tracing_subscriber::registry()
         .with(tracing_error::ErrorLayer::default())
-        .with(
-            tracing_tree::HierarchicalLayer::default()
-                .with_indent_lines(true)
-                .with_indent_amount(2)
-                .with_thread_ids(false)
-                .with_thread_names(false)
-                .with_verbose_exit(false)
-                .with_verbose_entry(false)
-                .with_deferred_spans(true)
-                .with_bracketed_fields(true)
-                .with_span_retrace(true)
-                .with_targets(false),
-        )
-        .with(
-            tracing_subscriber::EnvFilter::builder()
-                .with_default_directive(LevelFilter::INFO.into())
-                .from_env_lossy(),
-        )
+        .with(log)
+        .with(trace)
         .init();
 
-    match Cli::parse().command {
-        Commands::Extract(opts) => extract::main(opts).await,
+    if offline {
+        circe_lib::install_offline();
+    }
+
+    // `serve` and `mount` stop gracefully on Ctrl-C by themselves.
+    let result = match command {
+        Commands::Serve(_) => run(command, reference_defaults, host_allowlist).await,
+        #[cfg(all(target_os = "linux", feature = "fuse"))]
+        Commands::Mount(_) => run(command, reference_defaults, host_allowlist).await,
+        _ => {
+            match interrupt::or_interrupted(run(command, reference_defaults, host_allowlist)).await
+            {
+                Some(result) => result,
+                None => {
+                    let err = eyre!("interrupted");
+                    error_format.write(&err, exit::Failure::Interrupted);
+                    std::process::exit(exit::Failure::Interrupted.code());
+                }
+            }
+        }
+    };
+    let Err(err) = result else {
+        return Ok(());
+    };
+    if let Some(annotate) = annotate {
+        annotate.error(&err);
+    }
+
+    let failure = exit::Failure::of(&err);
+    error_format.write(&err, failure);
+    std::process::exit(failure.code());
+}
+
+async fn run(
+    mut command: Commands,
+    reference_defaults: config::ReferenceDefaults,
+    host_allowlist: config::HostAllowlist,
+) -> Result<()> {
+    let config = config::Config::load()
+        .await
+        .context("load configuration file")?;
+    reference_defaults.resolve(&config)?.install();
+    if let Some(allowed) = host_allowlist.resolve(&config)? {
+        allowed.install();
+    }
+    command.configure(&config);
+
+    match command {
+        Commands::Extract(opts) => extract::main(*opts).await,
         Commands::List(opts) => list::main(opts).await,
+        Commands::Inspect(opts) => inspect::main(opts).await,
+        Commands::Layer(opts) => layer::main(opts).await,
         Commands::Reexport(opts) => reexport::main(opts).await,
+        Commands::Serve(opts) => serve::main(opts).await,
+        #[cfg(all(target_os = "linux", feature = "fuse"))]
+        Commands::Mount(opts) => mount::main(opts).await,
+        Commands::Watch(opts) => watch::main(opts).await,
+        Commands::Prefetch(opts) => prefetch::main(opts).await,
+        Commands::Repos(opts) => repos::main(opts).await,
+        Commands::Run(opts) => run::main(opts).await,
+        Commands::Capabilities(opts) => capabilities::main(opts).await,
+    }
+}
+
+impl Commands {
+    /// Fill in the options that weren't provided from the configuration file.
+    fn configure(&mut self, config: &config::Config) {
+        match self {
+            Commands::Extract(opts) => opts.configure(config),
+            Commands::List(opts) => opts.configure(config),
+            Commands::Inspect(opts) => opts.configure(config),
+            Commands::Layer(opts) => opts.configure(config),
+            Commands::Reexport(opts) => opts.configure(config),
+            Commands::Serve(opts) => opts.configure(config),
+            #[cfg(all(target_os = "linux", feature = "fuse"))]
+            Commands::Mount(opts) => opts.configure(config),
+            Commands::Run(opts) => opts.configure(config),
+            Commands::Watch(_)
+            | Commands::Prefetch(_)
+            | Commands::Repos(_)
+            | Commands::Capabilities(_) => {}
+        }
     }
-    .with_warning(|| {
-        concat!(
-            "Authentication errors are sometimes reported when the actual issue ",
-            "is that the specified image or tag does not exist. ",
-            "This depends on the behavior of the remote container registry.",
-        )
-    })
 }
 
 fn style() -> Styles {
@@ -100,30 +396,3 @@ fn style() -> Styles {
         .invalid(AnsiColor::Red.on_default())
         .valid(AnsiColor::Blue.on_default())
 }
-
-/// Try a list of asynchronous strategies in sequence.
-///
-/// The first strategy to succeed with [`Outcome::Success`] stops executing the rest.
-/// If all strategies fail, an error is returned
-///
-/// Note: this macro returns from the calling context, not from the current expression.
-#[macro_export]
-#[doc(hidden)]
-macro_rules! try_strategies {
-    ($opts:expr; $($strategy:expr),*) => {{
-        $(match $strategy(&$opts).await {
-            Ok($crate::Outcome::Success) => return Ok(()),
-            Ok($crate::Outcome::Skipped) => {},
-            Err(err) => tracing::warn!(?err, "strategy failed"),
-        })*
-
-        color_eyre::eyre::bail!("all strategies failed")
-    }}
-}
-
-/// The result of executing a strategy.
-/// When executing multiple strategies, the first successful one stops the sequence.
-pub enum Outcome {
-    Success,
-    Skipped,
-}
@@ -0,0 +1,100 @@
+//! Mounts the squashed filesystem of an image read-only over FUSE,
+//! so that it can be browsed with any tool without extracting it.
+//!
+//! circe speaks the FUSE protocol over `/dev/fuse` itself rather than through `libfuse` (see [`fuse`]),
+//! so mounting needs the privilege to call `mount` (root, or `CAP_SYS_ADMIN`) instead of `fusermount`.
+//! The image is squashed into memory with [`Vfs::squash`] before it's mounted,
+//! and requests are answered from it until the filesystem is unmounted,
+//! either with Ctrl-C or by anything else (e.g. `umount`).
+//! Stopping circe detaches the mount even if the filesystem is still in use (like `umount --lazy`).
+//!
+//! Reference:
+//! - https://www.kernel.org/doc/html/latest/filesystems/fuse.html
+
+use circe_lib::{extract::Vfs, progress::Silent, source, Source};
+use clap::Parser;
+use color_eyre::{
+    eyre::{Context, Result},
+    Section,
+};
+use derive_more::Debug;
+use pluralizer::pluralize;
+use std::path::PathBuf;
+use tracing::info;
+
+use crate::{config::Config, extract::Target};
+use fuse::{Filesystem, Session};
+
+mod fuse;
+
+#[derive(Debug, Parser)]
+pub struct Options {
+    /// Target container image to mount
+    #[clap(flatten)]
+    target: Target,
+
+    /// The existing directory on which the squashed filesystem of the image is mounted
+    mountpoint: PathBuf,
+}
+
+impl Options {
+    /// Fill in the options that weren't provided from the configuration file.
+    pub fn configure(&mut self, config: &Config) {
+        self.target.configure(config);
+    }
+}
+
+#[tracing::instrument]
+pub async fn main(opts: Options) -> Result<()> {
+    let options = source::Options::builder()
+        .maybe_platform(opts.target.platform.clone())
+        .maybe_annotations(opts.target.annotations.clone())
+        .maybe_manifest_digest(opts.target.manifest_digest.clone())
+        .maybe_auth(opts.target.auth().await?)
+        .buffering(opts.target.buffering())
+        .timeouts(opts.target.timeouts.registry())
+        .maybe_limit_rate(opts.target.limit_rate)
+        .maybe_pull_policy(opts.target.pull_policy)
+        .maybe_kind(opts.target.source)
+        .daemon(opts.target.daemon())
+        .maybe_cache(opts.target.cache())
+        .build();
+    let source = opts.target.detect(options).await?;
+
+    let layers = source.layers().await.context("list layers")?;
+    info!(
+        "squashing {}",
+        pluralize("layer", layers.len() as isize, true)
+    );
+    let vfs = Vfs::squash(&source, &layers, &Silent)
+        .await
+        .context("squash image")?;
+    let filesystem = Filesystem::new(vfs);
+
+    let mountpoint = opts.mountpoint;
+    let session = Session::mount(&mountpoint)
+        .with_context(|| format!("mount image on {mountpoint:?}"))
+        .with_suggestion(|| {
+            "Mounting requires root (or `CAP_SYS_ADMIN`), the `fuse` kernel module, and an existing directory."
+        })?;
+    info!(?mountpoint, "mounted image; stop with ctrl-c");
+
+    // Requests are answered on a thread of their own rather than the blocking pool,
+    // which circe would wait on to exit while anything still uses the filesystem.
+    let (done, served) = tokio::sync::oneshot::channel();
+    std::thread::spawn(move || {
+        let _ = done.send(session.serve(&filesystem));
+    });
+    tokio::select! {
+        result = served => {
+            info!("image was unmounted");
+            return result.context("serve filesystem")?;
+        }
+        _ = tokio::signal::ctrl_c() => {}
+    }
+
+    // Anything still using the filesystem is disconnected from it once circe exits.
+    fuse::unmount(&mountpoint).with_context(|| format!("unmount {mountpoint:?}"))?;
+    info!("unmounted image");
+    Ok(())
+}
@@ -0,0 +1,532 @@
+//! The FUSE protocol, spoken directly over `/dev/fuse`.
+//!
+//! Each request the kernel writes to the device is answered with a single reply written back to it.
+//! Only the requests a read-only filesystem needs are handled; the rest are answered with `ENOSYS`,
+//! which the kernel remembers for requests like `getxattr` so that they aren't sent again.
+//! Messages are laid out as in `include/uapi/linux/fuse.h`, in the byte order of the host.
+
+use std::{
+    collections::HashMap,
+    ffi::{CString, OsStr},
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    os::{fd::AsRawFd, unix::ffi::OsStrExt},
+    path::{Path, PathBuf},
+};
+
+use circe_lib::extract::{Node, NodeKind, Vfs};
+use color_eyre::eyre::{Context, Result};
+use rustix::{
+    fs::{FileType, OFlags},
+    io::{self, Errno},
+    mount::{MountFlags, UnmountFlags},
+    process::{getgid, getuid},
+};
+use tap::Pipe;
+use tracing::{debug, warn};
+
+/// The version of the protocol circe speaks.
+///
+/// The kernel speaks the older of its own version and this one;
+/// 7.23 is the oldest whose `init` reply has the layout written here.
+const MAJOR: u32 = 7;
+const MINOR: u32 = 31;
+const MIN_MINOR: u32 = 23;
+
+const LOOKUP: u32 = 1;
+const FORGET: u32 = 2;
+const GETATTR: u32 = 3;
+const READLINK: u32 = 5;
+const OPEN: u32 = 14;
+const READ: u32 = 15;
+const STATFS: u32 = 17;
+const RELEASE: u32 = 18;
+const INIT: u32 = 26;
+const OPENDIR: u32 = 27;
+const READDIR: u32 = 28;
+const RELEASEDIR: u32 = 29;
+const INTERRUPT: u32 = 36;
+const DESTROY: u32 = 38;
+const BATCH_FORGET: u32 = 42;
+
+/// The size of the header of each request, and of each reply.
+const IN_HEADER: usize = 40;
+const OUT_HEADER: usize = 16;
+
+/// The largest write the kernel may send; nothing is written to a read-only filesystem,
+/// so this is the minimum the kernel accepts.
+const MAX_WRITE: u32 = 4096;
+
+/// The size of the buffer requests are read into;
+/// the kernel refuses to write a request into a buffer smaller than a write request could be.
+const BUFFER: usize = 64 * 1024;
+
+/// How long, in seconds, the kernel may cache names and attributes; the image doesn't change while it's mounted.
+const TTL: u64 = 60 * 60;
+
+/// Keep the content of files in the page cache when they're opened again.
+const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+
+/// The inode of the root directory, which the kernel knows without looking it up.
+const ROOT: u64 = 1;
+
+const BLOCK_SIZE: u32 = 4096;
+const NAME_MAX: u32 = 255;
+
+/// A connection to the kernel for a mounted filesystem.
+pub struct Session {
+    device: File,
+}
+
+impl Session {
+    /// Mount a filesystem on the mountpoint, served by [`Session::serve`].
+    ///
+    /// Only the user mounting the filesystem can access it, and it's mounted read-only.
+    pub fn mount(mountpoint: &Path) -> Result<Self> {
+        let device = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/fuse")
+            .context("open /dev/fuse")?;
+
+        // The kernel checks access against the modes of the entries, like it does for other filesystems.
+        let data = format!(
+            "fd={},rootmode=40000,user_id={},group_id={},default_permissions",
+            device.as_raw_fd(),
+            getuid().as_raw(),
+            getgid().as_raw(),
+        );
+        let data = CString::new(data).context("encode mount options")?;
+        let flags = MountFlags::RDONLY | MountFlags::NOSUID | MountFlags::NODEV;
+        rustix::mount::mount("circe", mountpoint, "fuse.circe", flags, data.as_c_str())
+            .context("mount filesystem")?;
+
+        Ok(Self { device })
+    }
+
+    /// Answer requests from the kernel until the filesystem is unmounted.
+    pub fn serve(mut self, filesystem: &Filesystem) -> Result<()> {
+        let mut buffer = vec![0; BUFFER];
+        loop {
+            let len = match self.device.read(&mut buffer) {
+                Ok(len) => len,
+                // The request was interrupted before it could be read.
+                Err(err)
+                    if matches!(Errno::from_io_error(&err), Some(Errno::INTR | Errno::NOENT)) =>
+                {
+                    continue
+                }
+                Err(err) if Errno::from_io_error(&err) == Some(Errno::NODEV) => {
+                    debug!("filesystem was unmounted");
+                    return Ok(());
+                }
+                Err(err) => return Err(err).context("read request"),
+            };
+
+            let Some(request) = Request::parse(&buffer[..len]) else {
+                warn!(len, "skip: malformed request");
+                continue;
+            };
+            if request.opcode == DESTROY {
+                debug!("filesystem was destroyed");
+                return Ok(());
+            }
+
+            let Some(reply) = filesystem.respond(&request) else {
+                continue;
+            };
+            let (error, body) = match reply {
+                Ok(body) => (0, body),
+                Err(errno) => (-errno.raw_os_error(), Vec::new()),
+            };
+            let message = Message::default()
+                .u32((OUT_HEADER + body.len()) as u32)
+                .i32(error)
+                .u64(request.unique)
+                .bytes(&body);
+            match self.device.write(&message.0) {
+                Ok(_) => {}
+                // The request was interrupted while it was answered, so the kernel no longer wants the reply.
+                Err(err) if Errno::from_io_error(&err) == Some(Errno::NOENT) => {
+                    debug!(unique = request.unique, "request was interrupted");
+                }
+                Err(err) if Errno::from_io_error(&err) == Some(Errno::NODEV) => return Ok(()),
+                Err(err) => return Err(err).context("write reply"),
+            }
+        }
+    }
+}
+
+/// Unmount the filesystem mounted on the mountpoint.
+///
+/// The mount is detached immediately, but the filesystem is only released once nothing uses it.
+pub fn unmount(mountpoint: &Path) -> Result<()> {
+    rustix::mount::unmount(mountpoint, UnmountFlags::DETACH).context("unmount filesystem")
+}
+
+/// A request from the kernel.
+#[derive(Debug)]
+struct Request<'a> {
+    opcode: u32,
+    unique: u64,
+    inode: u64,
+    body: &'a [u8],
+}
+
+impl<'a> Request<'a> {
+    /// Parse a request as it was read from the device.
+    fn parse(message: &'a [u8]) -> Option<Self> {
+        let len = u32_at(message, 0)? as usize;
+        Some(Self {
+            opcode: u32_at(message, 4)?,
+            unique: u64_at(message, 8)?,
+            inode: u64_at(message, 16)?,
+            body: message.get(IN_HEADER..len)?,
+        })
+    }
+}
+
+/// The reply to a request: its body, or the error with which it failed.
+///
+/// Requests that aren't answered (like `forget`) have no reply.
+type Reply = Option<io::Result<Vec<u8>>>;
+
+/// An entry in the filesystem, identified by its inode.
+struct Entry {
+    path: PathBuf,
+    node: Node,
+    parent: u64,
+    children: Vec<u64>,
+}
+
+/// The squashed filesystem of an image, with an inode assigned to each entry.
+pub struct Filesystem {
+    /// The entries of the filesystem; the entry with inode `n` is at index `n - 1`, so the root is first.
+    entries: Vec<Entry>,
+    inodes: HashMap<PathBuf, u64>,
+    uid: u32,
+    gid: u32,
+}
+
+impl Filesystem {
+    /// Assign an inode to each entry in the filesystem.
+    pub fn new(vfs: Vfs) -> Self {
+        let root = Entry {
+            path: PathBuf::new(),
+            node: Node {
+                mode: 0o755,
+                kind: NodeKind::Directory,
+            },
+            parent: ROOT,
+            children: Vec::new(),
+        };
+        let mut entries = vec![root];
+        let mut inodes = HashMap::from([(PathBuf::new(), ROOT)]);
+
+        // Entries are ordered by path, and every parent directory is an entry,
+        // so each parent is assigned an inode before its children.
+        for (path, node) in vfs.iter() {
+            let inode = entries.len() as u64 + 1;
+            let parent = path
+                .parent()
+                .and_then(|parent| inodes.get(parent))
+                .copied()
+                .unwrap_or(ROOT);
+            entries[(parent - 1) as usize].children.push(inode);
+            inodes.insert(path.to_path_buf(), inode);
+            entries.push(Entry {
+                path: path.to_path_buf(),
+                node: node.clone(),
+                parent,
+                children: Vec::new(),
+            });
+        }
+
+        Self {
+            entries,
+            inodes,
+            uid: getuid().as_raw(),
+            gid: getgid().as_raw(),
+        }
+    }
+
+    /// The entry with the inode.
+    fn entry(&self, inode: u64) -> io::Result<&Entry> {
+        inode
+            .checked_sub(1)
+            .and_then(|index| self.entries.get(index as usize))
+            .ok_or(Errno::NOENT)
+    }
+
+    /// Answer the request.
+    fn respond(&self, request: &Request<'_>) -> Reply {
+        debug!(opcode = request.opcode, inode = request.inode, "request");
+        let reply = match request.opcode {
+            // Inodes live as long as the filesystem, so the kernel doesn't need to be told about forgotten ones.
+            FORGET | BATCH_FORGET | INTERRUPT => return None,
+            INIT => init(request.body),
+            LOOKUP => self.lookup(request.inode, request.body),
+            GETATTR => self.entry(request.inode).map(|entry| {
+                Message::default()
+                    .u64(TTL)
+                    .u32(0)
+                    .u32(0)
+                    .bytes(&self.attr(request.inode, entry).0)
+                    .0
+            }),
+            READLINK => self.readlink(request.inode),
+            OPEN => self.open(request.inode, request.body),
+            READ => self.read(request.inode, request.body),
+            OPENDIR => self.opendir(request.inode),
+            READDIR => self.readdir(request.inode, request.body),
+            RELEASE | RELEASEDIR => Ok(Vec::new()),
+            STATFS => Ok(self.statfs()),
+            _ => Err(Errno::NOSYS),
+        };
+        Some(reply)
+    }
+
+    /// Look up the entry with the name in the directory.
+    fn lookup(&self, parent: u64, body: &[u8]) -> io::Result<Vec<u8>> {
+        let name = body.split(|&byte| byte == 0).next().unwrap_or_default();
+        let path = self.entry(parent)?.path.join(OsStr::from_bytes(name));
+        let inode = *self.inodes.get(&path).ok_or(Errno::NOENT)?;
+        let entry = self.entry(inode)?;
+        Message::default()
+            .u64(inode)
+            .u64(0)
+            .u64(TTL)
+            .u64(TTL)
+            .u32(0)
+            .u32(0)
+            .bytes(&self.attr(inode, entry).0)
+            .0
+            .pipe(Ok)
+    }
+
+    /// Read the target of the symlink.
+    fn readlink(&self, inode: u64) -> io::Result<Vec<u8>> {
+        match &self.entry(inode)?.node.kind {
+            NodeKind::Symlink(target) => Ok(target.as_os_str().as_bytes().to_vec()),
+            _ => Err(Errno::INVAL),
+        }
+    }
+
+    /// Open the file for reading.
+    fn open(&self, inode: u64, body: &[u8]) -> io::Result<Vec<u8>> {
+        let flags = u32_at(body, 0).ok_or(Errno::INVAL)?;
+        if flags & OFlags::ACCMODE.bits() != OFlags::RDONLY.bits() {
+            return Err(Errno::ROFS);
+        }
+        match &self.entry(inode)?.node.kind {
+            NodeKind::Directory => Err(Errno::ISDIR),
+            _ => Ok(Message::default().u64(0).u32(FOPEN_KEEP_CACHE).u32(0).0),
+        }
+    }
+
+    /// Read a range of the file's content.
+    fn read(&self, inode: u64, body: &[u8]) -> io::Result<Vec<u8>> {
+        let offset = u64_at(body, 8).ok_or(Errno::INVAL)?;
+        let size = u32_at(body, 16).ok_or(Errno::INVAL)?;
+        match &self.entry(inode)?.node.kind {
+            NodeKind::File(content) => {
+                let start = usize::try_from(offset)
+                    .unwrap_or(usize::MAX)
+                    .min(content.len());
+                let end = start.saturating_add(size as usize).min(content.len());
+                Ok(content[start..end].to_vec())
+            }
+            NodeKind::Directory => Err(Errno::ISDIR),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /// Open the directory for listing.
+    fn opendir(&self, inode: u64) -> io::Result<Vec<u8>> {
+        match &self.entry(inode)?.node.kind {
+            NodeKind::Directory => Ok(Message::default().u64(0).u32(0).u32(0).0),
+            _ => Err(Errno::NOTDIR),
+        }
+    }
+
+    /// List the directory's entries, starting from the offset, in as many as fit in the requested size.
+    ///
+    /// The offset of each entry is its index in the listing, which starts with `.` and `..`.
+    fn readdir(&self, inode: u64, body: &[u8]) -> io::Result<Vec<u8>> {
+        let offset = u64_at(body, 8).ok_or(Errno::INVAL)?;
+        let size = u32_at(body, 16).ok_or(Errno::INVAL)? as usize;
+        let entry = self.entry(inode)?;
+        if entry.node.kind != NodeKind::Directory {
+            return Err(Errno::NOTDIR);
+        }
+
+        let dots = [(inode, OsStr::new(".")), (entry.parent, OsStr::new(".."))];
+        let children = entry.children.iter().filter_map(|&child| {
+            let name = self.entry(child).ok()?.path.file_name()?;
+            Some((child, name))
+        });
+
+        let mut listing = Message::default();
+        for ((child, name), index) in dots
+            .into_iter()
+            .chain(children)
+            .zip(1u64..)
+            .skip(offset as usize)
+        {
+            let name = name.as_bytes();
+            let len = (24 + name.len()).next_multiple_of(8);
+            if listing.0.len() + len > size {
+                break;
+            }
+
+            let kind = self
+                .entry(child)
+                .map(|child| file_type(&child.node) >> 12)
+                .unwrap_or(0);
+            listing = listing
+                .u64(child)
+                .u64(index)
+                .u32(name.len() as u32)
+                .u32(kind)
+                .bytes(name)
+                .pad(8);
+        }
+        Ok(listing.0)
+    }
+
+    /// Describe the filesystem as a whole.
+    fn statfs(&self) -> Vec<u8> {
+        let size = self
+            .entries
+            .iter()
+            .map(|entry| match &entry.node.kind {
+                NodeKind::File(content) => content.len() as u64,
+                _ => 0,
+            })
+            .sum::<u64>();
+        Message::default()
+            .u64(size.div_ceil(BLOCK_SIZE as u64))
+            .u64(0)
+            .u64(0)
+            .u64(self.entries.len() as u64)
+            .u64(0)
+            .u32(BLOCK_SIZE)
+            .u32(NAME_MAX)
+            .u32(BLOCK_SIZE)
+            .u32(0)
+            .bytes(&[0; 24])
+            .0
+    }
+
+    /// The attributes of the entry.
+    ///
+    /// Layers record owners, but the squashed filesystem doesn't,
+    /// so every entry is owned by the user who mounted it; entries are dated at the epoch.
+    fn attr(&self, inode: u64, entry: &Entry) -> Message {
+        let size = match &entry.node.kind {
+            NodeKind::File(content) => content.len() as u64,
+            NodeKind::Symlink(target) => target.as_os_str().len() as u64,
+            _ => 0,
+        };
+        let nlink = match entry.node.kind {
+            NodeKind::Directory => 2,
+            _ => 1,
+        };
+        Message::default()
+            .u64(inode)
+            .u64(size)
+            .u64(size.div_ceil(512))
+            .bytes(&[0; 36])
+            .u32(file_type(&entry.node) | (entry.node.mode & 0o7777))
+            .u32(nlink)
+            .u32(self.uid)
+            .u32(self.gid)
+            .u32(0)
+            .u32(BLOCK_SIZE)
+            .u32(0)
+    }
+}
+
+/// Answer the `init` request that starts the session.
+fn init(body: &[u8]) -> io::Result<Vec<u8>> {
+    let major = u32_at(body, 0).ok_or(Errno::INVAL)?;
+    let minor = u32_at(body, 4).ok_or(Errno::INVAL)?;
+    let max_readahead = u32_at(body, 8).ok_or(Errno::INVAL)?;
+    if major != MAJOR || minor < MIN_MINOR {
+        warn!(major, minor, "unsupported FUSE protocol version");
+        return Err(Errno::PROTO);
+    }
+
+    debug!(major, minor, "initialized session");
+    Message::default()
+        .u32(MAJOR)
+        .u32(MINOR)
+        .u32(max_readahead)
+        .u32(0)
+        .u16(0)
+        .u16(0)
+        .u32(MAX_WRITE)
+        .u32(1)
+        .u16(0)
+        .u16(0)
+        .u32(0)
+        .u32(0)
+        .bytes(&[0; 24])
+        .0
+        .pipe(Ok)
+}
+
+/// The `S_IF*` bits of the entry's mode.
+///
+/// Devices and FIFOs aren't recorded in the squashed filesystem, so they're presented as empty files.
+fn file_type(node: &Node) -> u32 {
+    let kind = match node.kind {
+        NodeKind::Directory => FileType::Directory,
+        NodeKind::Symlink(_) => FileType::Symlink,
+        _ => FileType::RegularFile,
+    };
+    kind.as_raw_mode()
+}
+
+/// A message being encoded.
+#[derive(Default)]
+struct Message(Vec<u8>);
+
+impl Message {
+    fn u16(self, value: u16) -> Self {
+        self.bytes(&value.to_ne_bytes())
+    }
+
+    fn u32(self, value: u32) -> Self {
+        self.bytes(&value.to_ne_bytes())
+    }
+
+    fn i32(self, value: i32) -> Self {
+        self.bytes(&value.to_ne_bytes())
+    }
+
+    fn u64(self, value: u64) -> Self {
+        self.bytes(&value.to_ne_bytes())
+    }
+
+    fn bytes(mut self, bytes: &[u8]) -> Self {
+        self.0.extend_from_slice(bytes);
+        self
+    }
+
+    /// Pad the message with zeroes to a multiple of the alignment.
+    fn pad(mut self, alignment: usize) -> Self {
+        self.0.resize(self.0.len().next_multiple_of(alignment), 0);
+        self
+    }
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset + 4)?;
+    bytes.try_into().ok().map(u32::from_ne_bytes)
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> Option<u64> {
+    let bytes = bytes.get(offset..offset + 8)?;
+    bytes.try_into().ok().map(u64::from_ne_bytes)
+}
@@ -0,0 +1,137 @@
+//! Renders the output of commands in the format selected with `--format`,
+//! writing it to stdout or to the file named with `--output`.
+//!
+//! Output is either a single document, rendered as pretty JSON with `json`,
+//! or a sequence of [`Record`]s, which are written as they're produced with the other formats
+//! so that large listings don't need to be held in memory.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use clap::ValueEnum;
+use color_eyre::eyre::{bail, Context, Result};
+use serde::Serialize;
+
+/// The format in which the output of a command is written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// A single JSON document.
+    #[default]
+    Json,
+
+    /// Newline-delimited JSON, with one record per line.
+    Ndjson,
+
+    /// Comma-separated values, with a header row naming the fields of the records.
+    Csv,
+
+    /// Human-readable text, such as one record per line or a table.
+    #[value(alias = "table")]
+    Text,
+}
+
+impl Format {
+    /// The extension of files written in the format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Ndjson => "ndjson",
+            Format::Csv => "csv",
+            Format::Text => "txt",
+        }
+    }
+}
+
+/// A unit of output that is written on its own line with the record-oriented formats.
+///
+/// The fields of a record must serialize to scalar values so that it can be written as a CSV row.
+pub trait Record: Serialize {
+    /// Render the record as a line of text.
+    fn text(&self) -> String;
+}
+
+/// Where the output of a command is written, and the format in which it's written.
+pub struct Output {
+    format: Format,
+    sink: Sink,
+}
+
+enum Sink {
+    Lines(BufWriter<Box<dyn Write + Send>>),
+    Csv(Box<csv::Writer<Box<dyn Write + Send>>>),
+}
+
+impl Output {
+    /// Write output in the format to the file at the path, replacing it if it exists, or to stdout if there's no path.
+    pub fn create(format: Format, path: Option<&Path>) -> Result<Self> {
+        let writer: Box<dyn Write + Send> = match path {
+            Some(path) => Box::new(
+                File::create(path).with_context(|| format!("create output file {path:?}"))?,
+            ),
+            None => Box::new(std::io::stdout()),
+        };
+        let sink = match format {
+            Format::Csv => Sink::Csv(Box::new(csv::Writer::from_writer(writer))),
+            _ => Sink::Lines(BufWriter::new(writer)),
+        };
+        Ok(Self { format, sink })
+    }
+
+    /// Write a document: as pretty JSON with `json`, and as its records otherwise.
+    pub fn document<R: Record>(
+        &mut self,
+        document: &impl Serialize,
+        records: impl IntoIterator<Item = R>,
+    ) -> Result<()> {
+        match self.format {
+            Format::Json => self.json(document),
+            _ => records
+                .into_iter()
+                .try_for_each(|record| self.record(&record))
+                .context("write record"),
+        }
+    }
+
+    /// Write a value as pretty JSON, regardless of the format.
+    pub fn json(&mut self, value: &impl Serialize) -> Result<()> {
+        let rendered = serde_json::to_string_pretty(value).context("render output")?;
+        self.text(&rendered)
+    }
+
+    /// Write a line of text, regardless of the format.
+    pub fn text(&mut self, text: &str) -> Result<()> {
+        let Sink::Lines(writer) = &mut self.sink else {
+            bail!("text can't be written as CSV");
+        };
+        writeln!(writer, "{text}").context("write output")
+    }
+
+    /// Write a record in one of the record-oriented formats.
+    ///
+    /// This reports an I/O error so that it can be used while enumerating files with [`circe_lib::Unpack::visit_files`].
+    pub fn record(&mut self, record: &impl Record) -> std::io::Result<()> {
+        match (&mut self.sink, self.format) {
+            (Sink::Csv(writer), _) => writer.serialize(record).map_err(std::io::Error::from),
+            (Sink::Lines(writer), Format::Text) => writeln!(writer, "{}", record.text()),
+            (Sink::Lines(writer), Format::Ndjson) => {
+                serde_json::to_writer(&mut *writer, record)?;
+                writeln!(writer)
+            }
+            (Sink::Lines(_), format) => Err(std::io::Error::other(format!(
+                "records can't be written individually as {format:?}"
+            ))),
+        }
+    }
+
+    /// Flush any buffered output.
+    pub fn finish(self) -> Result<()> {
+        match self.sink {
+            Sink::Lines(mut writer) => writer.flush(),
+            Sink::Csv(mut writer) => writer.flush(),
+        }
+        .context("flush output")
+    }
+}
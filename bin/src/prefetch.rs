@@ -0,0 +1,119 @@
+use circe_lib::{cache::Cache, registry::Registry, Authentication, Platform, Reference};
+use clap::Parser;
+use color_eyre::eyre::{bail, Context, Result};
+use derive_more::Debug;
+use std::{num::NonZeroUsize, path::PathBuf, str::FromStr};
+use tracing::{info, warn};
+
+use crate::{config, extract::Timeouts};
+
+#[derive(Debug, Parser)]
+pub struct Options {
+    /// Image references to download (e.g. docker.io/library/ubuntu:latest)
+    ///
+    /// Only remote registries are supported, since images in the Docker daemon
+    /// or in tarballs can already be read without network access.
+    #[arg(required = true)]
+    images: Vec<String>,
+
+    /// The directory in which images are stored
+    ///
+    /// Pass the same directory to `extract`, `list`, `reexport`, and the other commands
+    /// with `--cache-dir` to read the images from it instead of the registry.
+    #[arg(long, env = "CIRCE_CACHE_DIR")]
+    cache_dir: PathBuf,
+
+    /// Target platform for the images (e.g. linux/amd64)
+    ///
+    /// Images are stored per platform, so later commands must request the same platform
+    /// (or also leave it unset) to read them from the cache.
+    #[arg(long, env = "CIRCE_PLATFORM", value_parser = Platform::from_str)]
+    platform: Option<Platform>,
+
+    /// The number of layers to download at once for each image
+    #[arg(long, default_value = "4")]
+    concurrency: NonZeroUsize,
+
+    /// The username to use for authenticating to the registry
+    #[arg(long, requires = "password")]
+    username: Option<String>,
+
+    /// The password to use for authenticating to the registry
+    #[arg(long, requires = "username")]
+    #[debug(skip)]
+    password: Option<String>,
+
+    #[clap(flatten)]
+    timeouts: Timeouts,
+}
+
+#[tracing::instrument]
+pub async fn main(opts: Options) -> Result<()> {
+    let references = opts
+        .images
+        .iter()
+        .map(|image| Reference::from_str(image).with_context(|| format!("parse {image:?}")))
+        .collect::<Result<Vec<_>>>()?;
+
+    let cache = Cache::new(&opts.cache_dir);
+    info!(dir = ?cache.dir(), "prefetching {} images", references.len());
+
+    let mut failures = 0;
+    for reference in &references {
+        match prefetch(&opts, &cache, reference).await {
+            Ok(stored) => {
+                info!(%reference, path = ?stored.path, "prefetched image");
+                println!(
+                    "{}",
+                    serde_json::to_string(&stored).context("render stored image")?
+                );
+            }
+            Err(err) => {
+                warn!(?err, %reference, "unable to prefetch image");
+                failures += 1;
+            }
+        }
+    }
+
+    if failures > 0 {
+        bail!(
+            "unable to prefetch {failures} of {} images",
+            references.len()
+        );
+    }
+    Ok(())
+}
+
+async fn prefetch(
+    opts: &Options,
+    cache: &Cache,
+    reference: &Reference,
+) -> Result<circe_lib::cache::Stored> {
+    let auth = match (&opts.username, &opts.password) {
+        (Some(username), Some(password)) => Authentication::basic(username, password),
+        _ => {
+            match config::env_auth(&reference.host).context("read credentials from environment")? {
+                Some(auth) => auth,
+                None => Authentication::docker(reference).await?,
+            }
+        }
+    };
+
+    let registry = Registry::builder()
+        .reference(reference.clone())
+        .auth(auth)
+        .maybe_platform(opts.platform.clone())
+        .timeouts(opts.timeouts.registry())
+        .build()
+        .await
+        .context("configure remote registry")?;
+    registry
+        .require_exists()
+        .await
+        .context("check that image exists")?;
+
+    cache
+        .store(&registry, opts.platform.as_ref(), opts.concurrency)
+        .await
+        .context("store image")
+}
@@ -0,0 +1,432 @@
+use circe_lib::{
+    extract::{Collision, Compression, LayerCompression, LayerDetails, Provenance, Whiteout},
+    progress::{Progress, SharedProgress, Silent},
+    Digest, Layer,
+};
+use derive_more::Debug;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use tap::Pipe;
+
+/// Select how to report progress.
+///
+/// Progress bars are only shown when stdout is a terminal:
+/// if it isn't, the output is most likely being consumed by another program
+/// that is only interested in the final report.
+pub fn reporter(quiet: bool) -> SharedProgress {
+    if quiet || !std::io::stdout().is_terminal() {
+        Arc::new(Silent)
+    } else {
+        Arc::new(Bars::default())
+    }
+}
+
+/// Renders a progress bar for each layer as it is extracted.
+#[derive(Debug, Default)]
+pub struct Bars {
+    #[debug(skip)]
+    multi: MultiProgress,
+
+    /// Progress bars and the count of files written, keyed by layer digest.
+    #[debug(skip)]
+    layers: Mutex<HashMap<String, (ProgressBar, u64)>>,
+}
+
+impl Bars {
+    fn style() -> ProgressStyle {
+        ProgressStyle::with_template(
+            "{prefix} [{bar:40}] {bytes}/{total_bytes} ({bytes_per_sec}) {msg}",
+        )
+        .unwrap_or_else(|_| ProgressStyle::default_bar())
+        .progress_chars("=> ")
+    }
+
+    fn with_layer(&self, layer: &Layer, f: impl FnOnce(&mut (ProgressBar, u64))) {
+        if let Ok(mut layers) = self.layers.lock() {
+            if let Some(entry) = layers.get_mut(&layer.digest.to_string()) {
+                f(entry);
+            }
+        }
+    }
+}
+
+impl Progress for Bars {
+    fn layer_started(&self, layer: &Layer) {
+        let size = u64::try_from(layer.size).unwrap_or_default();
+        let bar = self.multi.add(ProgressBar::new(size));
+        bar.set_style(Self::style());
+
+        // The full digest is too long to be useful in a progress bar;
+        // the same short form as `docker pull` is enough to tell layers apart.
+        let hex = layer.digest.as_hex();
+        bar.set_prefix(hex.chars().take(12).collect::<String>());
+
+        if let Ok(mut layers) = self.layers.lock() {
+            layers.insert(layer.digest.to_string(), (bar, 0));
+        }
+    }
+
+    fn bytes_read(&self, layer: &Layer, bytes: u64) {
+        self.with_layer(layer, |(bar, _)| bar.inc(bytes));
+    }
+
+    fn file_written(&self, layer: &Layer, _path: &Path) {
+        self.with_layer(layer, |(bar, files)| {
+            *files += 1;
+            bar.set_message(format!("{files} files"));
+        });
+    }
+
+    fn layer_completed(&self, layer: &Layer) {
+        self.with_layer(layer, |(bar, _)| bar.finish());
+    }
+}
+
+/// Records the files renamed to avoid case collisions, the whiteouts applied,
+/// the compressed and uncompressed size of each layer, how long each layer took to apply,
+/// and optionally the layer that last changed each path during extraction,
+/// forwarding every update to another reporter.
+#[derive(Debug)]
+pub struct Recorder {
+    #[debug(skip)]
+    inner: SharedProgress,
+
+    renamed: Mutex<Vec<Collision>>,
+
+    whiteouts: Mutex<Vec<Whiteout>>,
+
+    /// The bytes read and decompressed for each layer, in the order the layers were first read.
+    sizes: Mutex<Vec<(Layer, u64, u64)>>,
+
+    /// When each layer was started, last read, and completed, in the order the layers were started.
+    timings: Mutex<Vec<(Layer, Timing)>>,
+
+    /// The layer that last changed each path, and whether it deleted the path; only recorded if enabled,
+    /// since it holds every path written.
+    provenance: Option<Mutex<BTreeMap<PathBuf, (Digest, bool)>>>,
+}
+
+impl Recorder {
+    pub fn new(inner: SharedProgress) -> Self {
+        Self {
+            inner,
+            renamed: Mutex::default(),
+            whiteouts: Mutex::default(),
+            sizes: Mutex::default(),
+            timings: Mutex::default(),
+            provenance: None,
+        }
+    }
+
+    /// Also record the layer that last changed each path; see [`Recorder::provenance`].
+    pub fn with_provenance(self) -> Self {
+        Self {
+            provenance: Some(Mutex::default()),
+            ..self
+        }
+    }
+
+    /// The layer that last wrote or deleted each path so far, sorted by path,
+    /// with paths made relative to the output directory.
+    pub fn provenance(&self, output: &Path) -> Vec<Provenance> {
+        let Some(provenance) = &self.provenance else {
+            return Vec::new();
+        };
+        provenance
+            .lock()
+            .map(|provenance| {
+                provenance
+                    .iter()
+                    .map(|(path, (layer, deleted))| Provenance {
+                        path: path.strip_prefix(output).unwrap_or(path).to_path_buf(),
+                        layer: layer.clone(),
+                        deleted: *deleted,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn with_provenance_map(&self, f: impl FnOnce(&mut BTreeMap<PathBuf, (Digest, bool)>)) {
+        if let Some(Ok(mut provenance)) = self.provenance.as_ref().map(Mutex::lock) {
+            f(&mut provenance);
+        }
+    }
+
+    /// The collisions recorded so far, with paths made relative to the output directory.
+    pub fn collisions(&self, output: &Path) -> Vec<Collision> {
+        let relative = |path: &Path| path.strip_prefix(output).unwrap_or(path).to_path_buf();
+        self.renamed
+            .lock()
+            .map(|renamed| {
+                renamed
+                    .iter()
+                    .map(|collision| Collision {
+                        layer: collision.layer.clone(),
+                        path: relative(&collision.path),
+                        renamed: relative(&collision.renamed),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// The whiteouts applied so far, with paths made relative to the output directory.
+    pub fn whiteouts(&self, output: &Path) -> Vec<Whiteout> {
+        let relative = |path: &Path| path.strip_prefix(output).unwrap_or(path).to_path_buf();
+        self.whiteouts
+            .lock()
+            .map(|whiteouts| {
+                whiteouts
+                    .iter()
+                    .map(|whiteout| Whiteout {
+                        layer: whiteout.layer.clone(),
+                        path: relative(&whiteout.path),
+                        existed: whiteout.existed,
+                        recovered: whiteout.recovered.as_deref().map(relative),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// The size of the layers read so far.
+    pub fn compression(&self) -> Compression {
+        self.sizes
+            .lock()
+            .map(|sizes| {
+                sizes
+                    .iter()
+                    .map(|(layer, compressed, uncompressed)| LayerCompression {
+                        diff_id: layer.diff_id.clone(),
+                        ..LayerCompression::new(layer.digest.clone(), *compressed, *uncompressed)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default()
+            .pipe(Compression::new)
+    }
+
+    /// The descriptor of each layer started so far, with how long it took to read and apply.
+    pub fn layer_details(&self) -> Vec<LayerDetails> {
+        let millis = |started: Instant, at: Option<Instant>| {
+            at.map(|at| u64::try_from(at.duration_since(started).as_millis()).unwrap_or(u64::MAX))
+        };
+        self.timings
+            .lock()
+            .map(|timings| {
+                timings
+                    .iter()
+                    .map(|(layer, timing)| LayerDetails {
+                        download_ms: millis(timing.started, timing.read),
+                        extraction_ms: millis(timing.started, timing.completed),
+                        ..LayerDetails::new(layer)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn with_timing(&self, layer: &Layer, f: impl FnOnce(&mut Timing)) {
+        if let Ok(mut timings) = self.timings.lock() {
+            let timing = timings
+                .iter_mut()
+                .rev()
+                .find(|(recorded, _)| recorded.digest == layer.digest);
+            if let Some((_, timing)) = timing {
+                f(timing);
+            }
+        }
+    }
+
+    fn with_sizes(&self, layer: &Layer, f: impl FnOnce(&mut u64, &mut u64)) {
+        if let Ok(mut sizes) = self.sizes.lock() {
+            let index = match sizes
+                .iter()
+                .position(|(recorded, ..)| recorded.digest == layer.digest)
+            {
+                Some(index) => index,
+                None => {
+                    sizes.push((layer.clone(), 0, 0));
+                    sizes.len() - 1
+                }
+            };
+            let (_, compressed, uncompressed) = &mut sizes[index];
+            f(compressed, uncompressed);
+        }
+    }
+}
+
+/// When a layer was started, last read from the source, and completed.
+#[derive(Debug, Clone, Copy)]
+struct Timing {
+    started: Instant,
+    read: Option<Instant>,
+    completed: Option<Instant>,
+}
+
+impl Progress for Recorder {
+    fn layer_started(&self, layer: &Layer) {
+        if let Ok(mut timings) = self.timings.lock() {
+            let timing = Timing {
+                started: Instant::now(),
+                read: None,
+                completed: None,
+            };
+            timings.push((layer.clone(), timing));
+        }
+        self.inner.layer_started(layer);
+    }
+
+    fn bytes_read(&self, layer: &Layer, bytes: u64) {
+        self.with_sizes(layer, |compressed, _| *compressed += bytes);
+        self.with_timing(layer, |timing| timing.read = Some(Instant::now()));
+        self.inner.bytes_read(layer, bytes);
+    }
+
+    fn bytes_decompressed(&self, layer: &Layer, bytes: u64) {
+        self.with_sizes(layer, |_, uncompressed| *uncompressed += bytes);
+        self.inner.bytes_decompressed(layer, bytes);
+    }
+
+    fn file_written(&self, layer: &Layer, path: &Path) {
+        self.with_provenance_map(|provenance| {
+            provenance.insert(path.to_path_buf(), (layer.digest.clone(), false));
+        });
+        self.inner.file_written(layer, path);
+    }
+
+    fn file_renamed(&self, layer: &Layer, path: &Path, renamed: &Path) {
+        if let Ok(mut collisions) = self.renamed.lock() {
+            collisions.push(Collision {
+                layer: layer.digest.clone(),
+                path: path.to_path_buf(),
+                renamed: renamed.to_path_buf(),
+            });
+        }
+        self.inner.file_renamed(layer, path, renamed);
+    }
+
+    fn whiteout_applied(&self, layer: &Layer, path: &Path, existed: bool) {
+        if let Ok(mut whiteouts) = self.whiteouts.lock() {
+            whiteouts.push(Whiteout {
+                layer: layer.digest.clone(),
+                path: path.to_path_buf(),
+                existed,
+                recovered: None,
+            });
+        }
+        // Everything under a deleted directory is deleted with it.
+        self.with_provenance_map(|provenance| {
+            provenance.retain(|written, _| !written.starts_with(path));
+            provenance.insert(path.to_path_buf(), (layer.digest.clone(), true));
+        });
+        self.inner.whiteout_applied(layer, path, existed);
+    }
+
+    fn file_recovered(&self, layer: &Layer, path: &Path, recovered: &Path) {
+        if let Ok(mut whiteouts) = self.whiteouts.lock() {
+            // The whiteout is always reported before the path it deleted is recovered.
+            let whiteout = whiteouts
+                .iter_mut()
+                .rev()
+                .find(|whiteout| whiteout.layer == layer.digest && whiteout.path == path);
+            if let Some(whiteout) = whiteout {
+                whiteout.recovered = Some(recovered.to_path_buf());
+            }
+        }
+        self.inner.file_recovered(layer, path, recovered);
+    }
+
+    fn layer_completed(&self, layer: &Layer) {
+        self.with_timing(layer, |timing| timing.completed = Some(Instant::now()));
+        self.inner.layer_completed(layer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use circe_lib::LayerMediaType;
+
+    fn layer(byte: u8) -> Layer {
+        Layer::builder()
+            .digest(Digest::from_hash(vec![byte; 32]))
+            .size(0)
+            .media_type(LayerMediaType::Oci(vec![]))
+            .build()
+    }
+
+    #[test]
+    fn provenance() {
+        let (base, app) = (layer(1), layer(2));
+        let output = Path::new("/out");
+        let recorder = Recorder::new(Arc::new(Silent)).with_provenance();
+        recorder.file_written(&base, &output.join("etc/os-release"));
+        recorder.file_written(&base, &output.join("opt/tool/bin"));
+        recorder.file_written(&base, &output.join("opt/tool/lib"));
+        recorder.file_written(&base, &output.join("usr/bin/sh"));
+        recorder.whiteout_applied(&app, &output.join("opt/tool"), true);
+        recorder.file_written(&app, &output.join("usr/bin/sh"));
+
+        let expected = [
+            ("etc/os-release", &base, false),
+            ("opt/tool", &app, true),
+            ("usr/bin/sh", &app, false),
+        ]
+        .map(|(path, layer, deleted)| Provenance {
+            path: PathBuf::from(path),
+            layer: layer.digest.clone(),
+            deleted,
+        });
+        pretty_assertions::assert_eq!(expected.to_vec(), recorder.provenance(output));
+    }
+
+    #[test]
+    fn provenance_disabled() {
+        let recorder = Recorder::new(Arc::new(Silent));
+        recorder.file_written(&layer(1), Path::new("/out/etc/os-release"));
+        pretty_assertions::assert_eq!(
+            Vec::<Provenance>::new(),
+            recorder.provenance(Path::new("/out"))
+        );
+    }
+
+    #[test]
+    fn layer_details() {
+        let (base, app) = (layer(1), layer(2));
+        let recorder = Recorder::new(Arc::new(Silent));
+        recorder.layer_started(&base);
+        recorder.bytes_read(&base, 10);
+        recorder.layer_completed(&base);
+        recorder.layer_started(&app);
+
+        let details = recorder.layer_details();
+        let described = details
+            .iter()
+            .map(|details| {
+                (
+                    details.layer.clone(),
+                    details.media_type.clone(),
+                    details.download_ms.is_some(),
+                    details.extraction_ms.is_some(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let media_type = LayerMediaType::Oci(vec![]).to_string();
+        pretty_assertions::assert_eq!(
+            vec![
+                (base.digest.clone(), media_type.clone(), true, true),
+                (app.digest.clone(), media_type, false, false),
+            ],
+            described
+        );
+        assert!(details[0].download_ms <= details[0].extraction_ms);
+    }
+}
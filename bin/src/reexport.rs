@@ -1,118 +1,428 @@
-use async_tempfile::TempFile;
 use circe_lib::{
-    docker::{Daemon, Tarball},
-    fossacli::{Image, Manifest, ManifestEntry, RootFs},
-    registry::Registry,
-    Authentication, Digest, Reference, Source,
+    fossacli::{Hints, Image, Manifest, ManifestEntry, RootFs, Writer},
+    source::{self, AnySource},
+    Digest, Filters, Reference, Source, Unpack,
 };
 use clap::Parser;
 use color_eyre::eyre::{bail, Context, Result};
 use derive_more::Debug;
 use pluralizer::pluralize;
-use std::{path::PathBuf, str::FromStr};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 use tap::Pipe;
-use tokio_tar::Builder;
 use tracing::{debug, info, warn};
 
-use crate::{extract::Target, try_strategies, Outcome};
+use crate::{
+    batch::{self, Batch},
+    config::Config,
+    extract::Target,
+    interrupt::Partial,
+};
 
-#[derive(Debug, Parser)]
+#[derive(Debug, Clone, Parser)]
+// The image isn't provided with `--batch`; see `batch::output`.
+// Changing the image reorders the positional arguments, so both are placed explicitly.
+#[command(
+    mut_arg("image", |arg| arg.index(1).required(false).required_unless_present("batch").default_value("")),
+    mut_arg("output", |arg| arg.index(2)),
+)]
 pub struct Options {
     /// Target container image to re-export
     #[clap(flatten)]
     target: Target,
 
     /// File path where the re-exported tarball will be written
-    #[arg(default_value = "image.tar")]
+    ///
+    /// Use `-` to write the tarball to stdout instead, for example to pipe it to another program.
+    /// Since stdout can't be rewound, each layer is decompressed to a temporary file before it is written.
+    ///
+    /// With `--batch`, this is the directory to which the tarball of each image is written instead.
+    #[arg(default_value = DEFAULT_OUTPUT)]
     output: String,
+
+    /// Glob filters for layers to exclude from the tarball
+    ///
+    /// Filters are unix-style glob patterns, for example `sha256:1234*`
+    /// matches any layer with a sha256 digest starting with `1234`.
+    ///
+    /// You can provide this multiple times to provide multiple filters.
+    /// If filters are provided, layers whose digest matches any filter are left out of the tarball;
+    /// this is useful to skip large base layers that don't need to be scanned.
+    #[arg(long, alias = "lg")]
+    layer_glob: Option<Vec<String>>,
+
+    /// Regex filters for layers to exclude from the tarball
+    ///
+    /// Filters are regex patterns, for example `sha256:1234.*`
+    /// matches any layer with a sha256 digest starting with `1234`.
+    ///
+    /// You can provide this multiple times to provide multiple filters.
+    /// If filters are provided, layers whose digest matches any filter are left out of the tarball;
+    /// this is useful to skip large base layers that don't need to be scanned.
+    #[arg(long, alias = "lr")]
+    layer_regex: Option<Vec<String>>,
+
+    /// File recording the tarball to which each image was re-exported, by digest
+    ///
+    /// If the image was already re-exported (for example under another tag that resolves to the same digest)
+    /// and that tarball still exists, it is reused instead of pulling the image again:
+    /// it is hard linked (or copied, if hard links aren't supported) to the output if the tag is the same,
+    /// otherwise its entries are copied with the tag in its manifest replaced.
+    ///
+    /// The file is created if it does not exist, and is updated after every re-export.
+    /// The index is ignored when layer filters are provided, since the tarball depends on the filters.
+    #[arg(long)]
+    index: Option<PathBuf>,
+
+    /// Also write hints about the image to this file, for FOSSA CLI to improve attribution
+    ///
+    /// Hints are JSON recording what the tarball doesn't: the resolved digest and platform of the image,
+    /// the diff ID of each layer, and the foreign layers that were skipped.
+    /// The format is versioned with its `version` field.
+    #[arg(long, conflicts_with = "batch")]
+    hints: Option<PathBuf>,
+
+    /// Fail if the image has no layers to re-export
+    ///
+    /// Scratch-based images and config-only artifacts legitimately have no layers, as do images
+    /// whose layers are all excluded by layer filters; by default these are re-exported as a tarball without layers.
+    #[arg(long)]
+    fail_on_empty: bool,
+
+    /// Re-export many images, writing each to a tarball named after the image
+    ///
+    /// Images that resolve to the same digest are only exported once if `--index` is provided.
+    #[clap(flatten)]
+    batch: Batch,
+}
+
+impl Options {
+    /// Fill in the options that weren't provided from the configuration file.
+    pub fn configure(&mut self, config: &Config) {
+        self.target.configure(config);
+    }
+
+    /// Combined filters for layers; layers matching the provided filters are excluded.
+    fn layer_filters(&self) -> Result<Filters> {
+        let layer_globs = Filters::parse_glob(self.layer_glob.iter().flatten())?;
+        let layer_regexes = Filters::parse_regex(self.layer_regex.iter().flatten())?;
+        Ok((layer_globs + layer_regexes).exclude())
+    }
+
+    /// Whether the tarball is written to stdout.
+    fn to_stdout(&self) -> bool {
+        self.output == STDOUT
+    }
+
+    /// Create the writer for the tarball at the output.
+    async fn writer(&self) -> Result<Writer> {
+        if self.to_stdout() {
+            return Ok(Writer::stream(tokio::io::stdout()));
+        }
+
+        Writer::create(&self.output).await.context("create tarball")
+    }
+
+    /// Whether any layer filters are provided.
+    fn has_layer_filters(&self) -> bool {
+        self.layer_glob
+            .iter()
+            .chain(&self.layer_regex)
+            .flatten()
+            .next()
+            .is_some()
+    }
+}
+
+/// The output that writes the tarball to stdout.
+const STDOUT: &str = "-";
+
+/// The output if none is provided.
+const DEFAULT_OUTPUT: &str = "image.tar";
+
+/// Held while the index is updated, since images in a batch are re-exported in parallel.
+static INDEX_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// The tarball to which each image was re-exported, keyed by image digest.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Index(BTreeMap<String, Exported>);
+
+impl Index {
+    async fn read(path: &Path) -> Result<Self> {
+        if !tokio::fs::try_exists(path).await.unwrap_or_default() {
+            debug!(?path, "no index file, starting fresh");
+            return Ok(Self::default());
+        }
+
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .context("read index file")?;
+        serde_json::from_str(&content).context("parse index file")
+    }
+
+    async fn write(&self, path: &Path) -> Result<()> {
+        let content = serde_json::to_string_pretty(self).context("render index")?;
+        tokio::fs::write(path, content)
+            .await
+            .context("write index file")
+    }
+}
+
+/// A tarball to which an image was re-exported.
+#[derive(Debug, Serialize, Deserialize)]
+struct Exported {
+    /// The absolute path to the tarball.
+    path: PathBuf,
+
+    /// The tag recorded in the manifest of the tarball.
+    tag: String,
 }
 
 #[tracing::instrument]
 pub async fn main(opts: Options) -> Result<()> {
-    info!("re-exporting image for FOSSA CLI");
-    try_strategies!(&opts; strategy_tarball, strategy_daemon, strategy_registry)
+    if opts.batch.batch.is_some() {
+        return reexport_batch(opts).await;
+    }
+    reexport_image(&opts).await
 }
 
-async fn strategy_registry(opts: &Options) -> Result<Outcome> {
-    if opts.target.is_path().await {
-        debug!("input appears to be a file path, skipping strategy");
-        return Ok(Outcome::Skipped);
+/// Re-export every image in the batch to its own tarball inside the output directory.
+async fn reexport_batch(opts: Options) -> Result<()> {
+    if opts.to_stdout() {
+        bail!("tarballs can't be written to stdout with --batch");
     }
 
-    let reference = Reference::from_str(&opts.target.image)?;
-    let auth = match (&opts.target.username, &opts.target.password) {
-        (Some(username), Some(password)) => Authentication::basic(username, password),
-        _ => Authentication::docker(&reference).await?,
-    };
-
-    let tag = format!("{}:{}", reference.name, reference.version);
-    let registry = Registry::builder()
-        .maybe_platform(opts.target.platform.as_ref())
-        .reference(reference.clone())
-        .auth(auth)
-        .build()
+    let provided =
+        Some(Path::new(&opts.output)).filter(|output| *output != Path::new(DEFAULT_OUTPUT));
+    let dir = batch::output(&opts.target.image, provided)?;
+    tokio::fs::create_dir_all(&dir)
         .await
-        .context("configure remote registry")?;
+        .with_context(|| format!("create output directory {dir:?}"))?;
+    opts.batch
+        .clone()
+        .run(move |image, name| {
+            let mut opts = opts.clone();
+            let output = dir.join(format!("{name}.tar"));
+            opts.target.image = image.clone();
+            opts.output = output.to_string_lossy().to_string();
+            async move {
+                match reexport_image(&opts).await {
+                    Ok(()) => batch::Outcome {
+                        image,
+                        output,
+                        digest: None,
+                        identity: None,
+                        error: None,
+                    },
+                    Err(err) => batch::Outcome::failed(image, output, &err),
+                }
+            }
+        })
+        .await
+}
 
-    reexport(opts, tag, registry)
+async fn reexport_image(opts: &Options) -> Result<()> {
+    info!("re-exporting image for FOSSA CLI");
+    let options = source::Options::builder()
+        .maybe_platform(opts.target.platform.clone())
+        .maybe_annotations(opts.target.annotations.clone())
+        .maybe_manifest_digest(opts.target.manifest_digest.clone())
+        .maybe_auth(opts.target.auth().await?)
+        .layer_filters(opts.layer_filters()?)
+        .buffering(opts.target.buffering())
+        .timeouts(opts.target.timeouts.registry())
+        .maybe_limit_rate(opts.target.limit_rate)
+        .maybe_pull_policy(opts.target.pull_policy)
+        .maybe_kind(opts.target.source)
+        .daemon(opts.target.daemon())
+        .maybe_cache(opts.target.cache())
+        .build();
+    let source = opts.target.detect(options).await?;
+
+    let tag = tag(opts, &source).await?;
+    info!(tag = %tag, "created tag for reexport");
+    reexport(opts, tag, source)
         .await
         .context("reexporting image")
-        .map(|_| Outcome::Success)
 }
 
-async fn strategy_daemon(opts: &Options) -> Result<Outcome> {
+/// The tag recorded in the manifest of the tarball for the image read from the source.
+///
+/// Images read from a tarball on disk are tagged with the name of the file and the digest of the image,
+/// since the path isn't a reference; other images are tagged with their reference.
+async fn tag(opts: &Options, source: &AnySource) -> Result<String> {
+    if let AnySource::Daemon(_) = source {
+        return Ok(opts.target.image.clone());
+    }
+
     if opts.target.is_path().await {
-        debug!("input appears to be a file path, skipping strategy");
-        return Ok(Outcome::Skipped);
+        let path = Path::new(&opts.target.image);
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_else(|| opts.target.image.as_str().into());
+        let digest = source.digest().await.context("get image digest")?.as_hex();
+        return Ok(format!("{name}:{digest}"));
     }
 
-    let tag = opts.target.image.clone();
-    let daemon = Daemon::builder()
-        .reference(&tag)
-        .build()
-        .await
-        .context("build daemon reference")?;
+    let reference = Reference::from_str(&opts.target.image)?;
+    Ok(format!("{}:{}", reference.name, reference.version))
+}
 
-    tracing::info!("pulled image from daemon");
-    reexport(opts, tag, daemon)
-        .await
-        .context("reexporting image")
-        .map(|_| Outcome::Success)
+#[tracing::instrument]
+async fn reexport(opts: &Options, tag: String, registry: impl Unpack) -> Result<()> {
+    let digest = registry.digest().await.context("get image digest")?;
+    export_or_reuse(opts, tag, &digest, &registry).await?;
+
+    if let Some(path) = &opts.hints {
+        write_hints(path, digest, &registry)
+            .await
+            .context("write hints")?;
+        info!(filename = %path.display(), "wrote hints for FOSSA CLI");
+    }
+    Ok(())
 }
 
-async fn strategy_tarball(opts: &Options) -> Result<Outcome> {
-    let path = PathBuf::from(&opts.target.image);
-    if matches!(tokio::fs::try_exists(&path).await, Err(_) | Ok(false)) {
-        bail!("path does not exist: {path:?}");
+/// Export the image to the tarball, or reuse the tarball to which it was previously exported if it's in the index.
+async fn export_or_reuse(
+    opts: &Options,
+    tag: String,
+    digest: &Digest,
+    registry: &impl Unpack,
+) -> Result<()> {
+    let Some(index_path) = &opts.index else {
+        return export(opts, &tag, digest, registry).await;
+    };
+    if opts.has_layer_filters() {
+        warn!("layer filters are provided, so the index is not used");
+        return export(opts, &tag, digest, registry).await;
     }
 
-    let name = path
-        .file_name()
-        .map(|name| name.to_string_lossy())
-        .unwrap_or_else(|| opts.target.image.clone().into())
-        .to_string();
+    let index = Index::read(index_path).await?;
+    if let Some(exported) = index.0.get(&digest.to_string()) {
+        if reuse(opts, &tag, exported)
+            .await
+            .context("reuse previous export")?
+        {
+            return Ok(());
+        }
+    }
 
-    tracing::info!(path = %path.display(), name = %name, "using local tarball");
-    let tarball = Tarball::builder()
-        .path(path)
-        .name(&name)
-        .build()
+    export(opts, &tag, digest, registry).await?;
+    if opts.to_stdout() {
+        debug!("tarball was written to stdout, so it is not recorded in the index");
+        return Ok(());
+    }
+
+    // Other images in a batch may have been recorded since the index was read, so it's read again to keep them.
+    let _lock = INDEX_LOCK.lock().await;
+    let mut index = Index::read(index_path).await?;
+    let path = std::path::absolute(&opts.output).context("resolve output path")?;
+    index.0.insert(digest.to_string(), Exported { path, tag });
+    index.write(index_path).await
+}
+
+/// Write hints about the image for FOSSA CLI; see [`Hints`].
+async fn write_hints(path: &Path, digest: Digest, registry: &impl Unpack) -> Result<()> {
+    let layers = registry.layers().await.context("list layers")?;
+    let config = registry.config().await.unwrap_or_else(|err| {
+        warn!(
+            ?err,
+            "unable to read image config, hints won't record the platform"
+        );
+        Default::default()
+    });
+
+    let hints = Hints::new(digest, &config, &layers);
+    let content = serde_json::to_string_pretty(&hints).context("render hints")?;
+    tokio::fs::write(path, content)
         .await
-        .context("build tarball reference")?;
+        .context("write hints file")
+}
+
+/// Reuse the tarball to which the image was previously exported, if it still exists.
+/// Returns whether the tarball was reused.
+async fn reuse(opts: &Options, tag: &str, exported: &Exported) -> Result<bool> {
+    if !tokio::fs::try_exists(&exported.path)
+        .await
+        .unwrap_or_default()
+    {
+        debug!(path = ?exported.path, "previously exported tarball no longer exists");
+        return Ok(false);
+    }
+
+    if opts.to_stdout() && exported.tag == tag {
+        let mut file = tokio::fs::File::open(&exported.path)
+            .await
+            .context("open previously exported tarball")?;
+        tokio::io::copy(&mut file, &mut tokio::io::stdout())
+            .await
+            .context("copy tarball to stdout")?;
 
-    let digest = tarball.digest().await.context("get image digest")?.as_hex();
-    let tag = format!("{name}:{digest}");
+        info!(from = ?exported.path, "reused previously exported tarball");
+        return Ok(true);
+    }
+
+    let output = std::path::absolute(&opts.output).context("resolve output path")?;
+    if exported.tag == tag {
+        if output == exported.path {
+            info!(filename = %opts.output, "image was already exported to destination");
+            return Ok(true);
+        }
+
+        match tokio::fs::remove_file(&output).await {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                return Err(err).context("remove existing output");
+            }
+            _ => {}
+        }
+        if let Err(err) = tokio::fs::hard_link(&exported.path, &output).await {
+            debug!(?err, "unable to hard link tarball, copying instead");
+            tokio::fs::copy(&exported.path, &output)
+                .await
+                .context("copy tarball to destination")?;
+        }
+
+        info!(from = ?exported.path, filename = %opts.output, "reused previously exported tarball");
+        return Ok(true);
+    }
+
+    // The previous tarball is read while the new one is written, so they can't be the same file.
+    if output == exported.path {
+        debug!(path = ?exported.path, "previously exported tarball is the destination, exporting again");
+        return Ok(false);
+    }
 
-    tracing::info!(tag = %tag, "created tag for reexport");
-    reexport(opts, tag, tarball)
+    let mut tarball = opts.writer().await?;
+    let partial = (!opts.to_stdout()).then(|| Partial::register(&opts.output));
+    tarball
+        .append_retagged(&exported.path, tag)
         .await
-        .context("reexporting image")
-        .map(|_| Outcome::Success)
+        .context("copy previously exported tarball")?;
+    tarball.finish().await.context("finish tarball")?;
+    if let Some(partial) = partial {
+        partial.complete();
+    }
+
+    info!(from = ?exported.path, filename = %opts.output, %tag, "reused previously exported tarball with new tag");
+    Ok(true)
 }
 
-#[tracing::instrument]
-async fn reexport(opts: &Options, tag: String, registry: impl Source) -> Result<()> {
+#[tracing::instrument(skip(registry))]
+async fn export(opts: &Options, tag: &str, digest: &Digest, registry: &impl Unpack) -> Result<()> {
     let layers = registry.layers().await.context("list layers")?;
+    if layers.is_empty() {
+        if opts.fail_on_empty {
+            bail!("no layers to re-export found in image");
+        }
+        warn!("no layers to re-export found in image; the tarball will have no layers");
+    }
+
     let count = layers.len();
     info!("enumerated {}", pluralize("layer", count as isize, true));
 
@@ -141,69 +451,58 @@ async fn reexport(opts: &Options, tag: String, registry: impl Source) -> Result<
     // It then builds a representation of the image based on the combination of these two files:
     // - https://github.com/fossas/fossa-cli/blob/65046d8b1935a2693e6f30869afbc2efb868352e/src/Container/Tarball.hs#L74
     //
-    // It's a lot less error prone to use the disk as working state for the tarball we create:
-    // the `tokio-tar` library automatically creates a lot of metadata for us if it can use an on-disk artifact
-    // which we'd otherwise be stuck recreating.
-    //
-    // While this comes at the cost of a little more IO (we're indirecting through the disk)
-    // I think this is worth the cost unless it demonstrates to the contrary..
-    let digest = registry.digest().await.context("get image digest")?;
-    let tarball = TempFile::new().await.context("create tarball")?;
-    let mut tarball = Builder::new(tarball);
+    // The tarball is written directly to its destination,
+    // with each layer decompressed and streamed into it as it is pulled;
+    // see `circe_lib::fossacli::Writer` for details.
+    let mut tarball = opts.writer().await?;
+    let partial = (!opts.to_stdout()).then(|| Partial::register(&opts.output));
     let mut written = Vec::new();
 
     for (layer, sequence) in layers.into_iter().zip(1usize..) {
         info!(layer = %layer, %sequence, "reading layer");
 
-        let Some(layer_tarball) = registry
-            .layer_plain_tarball(&layer)
+        let appended = tarball
+            .append_layer(registry, &layer)
             .await
-            .context("fetch layer tarball")?
-        else {
+            .context("add layer to tarball")?;
+        if !appended {
             warn!(layer = %layer, %sequence, "skipped layer");
             continue;
-        };
-
-        tarball
-            .append_path_with_name(layer_tarball.file_path(), layer.digest.tarball_filename())
-            .await
-            .context("add layer to tarball")?;
+        }
 
         info!(layer = %layer, %sequence, filename = %layer.digest.tarball_filename(), "added layer to tarball");
         written.push(layer.digest.clone());
     }
 
-    let (manifest, manifest_content) = ManifestEntry::builder()
-        .config(Image::filename(&digest))
-        .repo_tags(&tag)
+    let manifest = ManifestEntry::builder()
+        .config(Image::filename(digest))
+        .repo_tags(tag)
         .layers(written.iter().map(Digest::tarball_filename))
         .build()
-        .pipe(Manifest::singleton)
-        .write_tempfile()
-        .await
-        .context("write manifest")?;
-    tarball
-        .append_path_with_name(manifest.file_path(), Manifest::filename())
+        .pipe(Manifest::singleton);
+    let manifest_content = tarball
+        .append_json(Manifest::filename(), &manifest)
         .await
         .context("add manifest to tarball")?;
     info!(filename = %Manifest::filename().display(), manifest = %manifest_content, "added manifest to tarball");
 
-    let (image, image_content) = Image::from(RootFs::layers(written))
-        .write_tempfile()
-        .await
-        .context("write image")?;
-    tarball
-        .append_path_with_name(image.file_path(), Image::filename(&digest))
+    // FOSSA CLI only requires the root filesystem, so the rest of the config is best effort.
+    let config = registry.config().await.unwrap_or_else(|err| {
+        warn!(?err, "unable to read image config, writing minimal config");
+        Default::default()
+    });
+    let image = Image::new(config, RootFs::layers(written));
+    let image_content = tarball
+        .append_json(Image::filename(digest), &image)
         .await
         .context("add image to tarball")?;
-    info!(filename = %Image::filename(&digest).display(), image = %image_content, "added image to tarball");
+    info!(filename = %Image::filename(digest).display(), image = %image_content, "added image to tarball");
 
-    let tarball = tarball.into_inner().await.context("finish tarball")?;
-    tarball.sync_all().await.context("sync tarball")?;
-    tokio::fs::copy(tarball.file_path(), &opts.output)
-        .await
-        .context("copy tarball to destination")?;
-    info!(filename = %opts.output, "copied final tarball to destination");
+    tarball.finish().await.context("finish tarball")?;
+    if let Some(partial) = partial {
+        partial.complete();
+    }
+    info!(filename = %opts.output, "wrote final tarball to destination");
 
     Ok(())
 }
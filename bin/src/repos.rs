@@ -0,0 +1,150 @@
+use circe_lib::{registry::Catalog, Authentication, Filters};
+use clap::Parser;
+use color_eyre::eyre::{Context, Result};
+use derive_more::Debug;
+use serde::Serialize;
+use std::{num::NonZeroUsize, path::PathBuf};
+use tracing::info;
+
+use crate::{
+    config,
+    extract::Timeouts,
+    output::{Format, Output, Record},
+};
+
+#[derive(Debug, Parser)]
+pub struct Options {
+    /// The registry host, including the port if it isn't the default (e.g. `harbor.example.com` or `localhost:5000`)
+    ///
+    /// The registry must implement the `/v2/_catalog` endpoint; self-hosted registries like Harbor
+    /// and the reference `registry` generally do, while most public registries (like Docker Hub) don't.
+    host: String,
+
+    /// Only list repositories whose name matches any of these globs (e.g. `team/*`)
+    ///
+    /// Names don't include the registry host, for example `library/alpine`.
+    #[arg(long)]
+    glob: Option<Vec<String>>,
+
+    /// Only list repositories whose name matches any of these regexes (e.g. `^team/`)
+    #[arg(long)]
+    regex: Option<Vec<String>>,
+
+    /// Skip repositories whose name matches any of these globs, written like `--glob`
+    #[arg(long)]
+    glob_exclude: Option<Vec<String>>,
+
+    /// Skip repositories whose name matches any of these regexes, written like `--regex`
+    #[arg(long)]
+    regex_exclude: Option<Vec<String>>,
+
+    /// List at most this many repositories
+    ///
+    /// The registry stops being asked for more once this many repositories match the filters.
+    #[arg(long)]
+    limit: Option<NonZeroUsize>,
+
+    /// The number of repositories requested from the registry at once [default: 100]
+    ///
+    /// Registries may return fewer per request than asked for, and some limit how many they return.
+    #[arg(long)]
+    page_size: Option<NonZeroUsize>,
+
+    /// Append this tag to each reference, e.g. `latest`
+    ///
+    /// References without a tag are resolved with `latest` by the other commands anyway;
+    /// use this to select another tag in every repository.
+    #[arg(long)]
+    tag: Option<String>,
+
+    /// The format in which the output is written
+    ///
+    /// `text` writes one reference per line, which can be passed to `--batch` of `extract`, `list`, and `reexport`.
+    /// `json` writes a single document; `ndjson` and `csv` write a record for each repository.
+    #[arg(long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+
+    /// Write the output to this file instead of stdout, replacing it if it exists
+    #[arg(long)]
+    output: Option<PathBuf>,
+
+    /// The username to use for authenticating to the registry
+    #[arg(long, requires = "password")]
+    username: Option<String>,
+
+    /// The password to use for authenticating to the registry
+    #[arg(long, requires = "username")]
+    #[debug(skip)]
+    password: Option<String>,
+
+    #[clap(flatten)]
+    timeouts: Timeouts,
+}
+
+/// A repository in the registry.
+#[derive(Debug, Serialize)]
+struct Repository {
+    /// The name of the repository, without the registry host.
+    repository: String,
+
+    /// The reference to the repository, with the tag if one was provided.
+    reference: String,
+}
+
+impl Record for &Repository {
+    fn text(&self) -> String {
+        self.reference.clone()
+    }
+}
+
+impl Options {
+    /// Combined filters for repository names.
+    fn filters(&self) -> Result<Filters> {
+        let globs = Filters::parse_glob(self.glob.iter().flatten())?;
+        let regexes = Filters::parse_regex(self.regex.iter().flatten())?;
+        let excluded_globs = Filters::parse_glob(self.glob_exclude.iter().flatten())?;
+        let excluded_regexes = Filters::parse_regex(self.regex_exclude.iter().flatten())?;
+        Ok(globs + regexes + (excluded_globs + excluded_regexes).exclude())
+    }
+}
+
+#[tracing::instrument]
+pub async fn main(opts: Options) -> Result<()> {
+    let auth = match (&opts.username, &opts.password) {
+        (Some(username), Some(password)) => Authentication::basic(username, password),
+        _ => match config::env_auth(&opts.host).context("read credentials from environment")? {
+            Some(auth) => auth,
+            None => Authentication::docker_host(&opts.host).await?,
+        },
+    };
+
+    let catalog = Catalog::builder()
+        .host(&opts.host)
+        .auth(auth)
+        .filters(opts.filters()?)
+        .maybe_limit(opts.limit)
+        .maybe_page_size(opts.page_size)
+        .timeouts(opts.timeouts.registry())
+        .build()
+        .context("configure registry catalog")?;
+    let repositories = catalog.repositories().await.context("list repositories")?;
+    info!(host = %opts.host, repositories = repositories.len(), "listed repositories");
+
+    let repositories = repositories
+        .into_iter()
+        .map(|repository| {
+            let reference = match &opts.tag {
+                Some(tag) => format!("{}/{repository}:{tag}", opts.host),
+                None => format!("{}/{repository}", opts.host),
+            };
+            Repository {
+                repository,
+                reference,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let mut output = Output::create(opts.format, opts.output.as_deref())?;
+    output.document(&repositories, &repositories)?;
+    output.finish()
+}
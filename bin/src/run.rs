@@ -0,0 +1,330 @@
+//! Extracts several images as described by a job file, for `circe run`.
+//!
+//! A job file is YAML (or JSON, which is also valid YAML) describing the images to extract:
+//!
+//! ```yaml
+//! # Settings used by every target that doesn't provide them itself.
+//! defaults:
+//!   platform: linux/amd64
+//!   layers: separate
+//!
+//! # Layers are shared between targets through this store; see `circe extract --layer-store`.
+//! # The store can't be used with file filters.
+//! layer_store: ./layers
+//!
+//! targets:
+//!   - image: ghcr.io/fossas/circe:latest
+//!     output: ./circe
+//!     exec: ["tar -C {} -czf circe.tar.gz ."]
+//!   - image: docker.io/library/alpine:latest
+//!     output: ./alpine
+//! ```
+//!
+//! Each target supports `image` and `output` (required), `platform`, `layers`, `overwrite`,
+//! the filters `layer_glob`, `layer_regex`, `file_glob`, and `file_regex`,
+//! and `exec`: commands run in order once the target is extracted, like `circe extract --exec`.
+//! Filters are taken from the defaults as a group, like the configuration file:
+//! a target that sets any layer filter (or any file filter) ignores the defaults of that kind.
+//! Relative paths are resolved against the directory containing the job file.
+//!
+//! Targets are extracted in order; a consolidated report of every target is printed once they're done.
+//! Unknown keys are rejected, so that a typo doesn't silently leave a setting unapplied.
+
+use std::{
+    num::NonZeroU64,
+    path::{Path, PathBuf},
+};
+
+use circe_lib::{extract::Report, Platform};
+use clap::Parser;
+use color_eyre::{
+    eyre::{bail, Context, OptionExt, Result},
+    Section, SectionExt,
+};
+use derive_more::Debug;
+use serde::Deserialize;
+use tracing::info;
+
+use crate::{
+    batch::{Outcome, Summary},
+    config::Config,
+    exec,
+    extract::{self, Mode, Target, Timeouts},
+};
+
+#[derive(Debug, Parser)]
+pub struct Options {
+    /// The job file describing the images to extract, in YAML or JSON
+    ///
+    /// See the README for the format of the file.
+    job: PathBuf,
+
+    /// Also write the consolidated report to this file
+    #[arg(long)]
+    report: Option<PathBuf>,
+
+    /// Stop after the first target that fails, instead of continuing with the rest
+    #[arg(long)]
+    fail_fast: bool,
+
+    /// Don't display progress bars
+    ///
+    /// Progress bars are also not displayed if stdout is not a terminal.
+    #[arg(long, short)]
+    quiet: bool,
+
+    /// Deadlines for requests to registries, applied to each target
+    #[clap(flatten)]
+    timeouts: Timeouts,
+
+    /// The maximum rate at which layers are downloaded, in bytes per second, like `circe extract --limit-rate`
+    #[arg(long, env = "CIRCE_LIMIT_RATE", value_parser = extract::parse_rate)]
+    limit_rate: Option<NonZeroU64>,
+
+    /// Defaults from the configuration file, applied to each target.
+    #[arg(skip)]
+    #[debug(skip)]
+    config: Config,
+}
+
+impl Options {
+    /// Keep the configuration file, so its defaults can be applied to each target.
+    pub fn configure(&mut self, config: &Config) {
+        self.config = config.clone();
+    }
+}
+
+/// The images to extract, as read from a job file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Job {
+    /// Settings for targets that don't provide them.
+    #[serde(default)]
+    defaults: Spec,
+
+    /// The layer store shared by every target.
+    layer_store: Option<PathBuf>,
+
+    targets: Vec<Spec>,
+}
+
+/// The settings for a target, or the defaults for every target.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Spec {
+    image: Option<String>,
+    output: Option<PathBuf>,
+    platform: Option<Platform>,
+    layers: Option<Mode>,
+    overwrite: Option<bool>,
+    layer_glob: Option<Vec<String>>,
+    layer_regex: Option<Vec<String>>,
+    file_glob: Option<Vec<String>>,
+    file_regex: Option<Vec<String>>,
+    exec: Option<Vec<String>>,
+}
+
+#[tracing::instrument]
+pub async fn main(opts: Options) -> Result<()> {
+    let content = tokio::fs::read_to_string(&opts.job)
+        .await
+        .context("read job file")
+        .with_section(|| opts.job.display().to_string().header("Path:"))?;
+    let job =
+        Job::parse(&content).with_section(|| opts.job.display().to_string().header("Path:"))?;
+
+    let base = opts.job.parent().unwrap_or(Path::new(""));
+    let targets = job.targets(base).context("resolve targets")?;
+
+    let mut outcomes = Vec::new();
+    for (spec, target) in targets {
+        let image = target.target.image.clone();
+        let output = PathBuf::from(&target.output_dir);
+        info!(%image, ?output, "extracting target");
+
+        match run_target(&opts, target, &spec).await {
+            Ok((output, report)) => outcomes.push(Outcome {
+                image,
+                output,
+                digest: Some(report.digest),
+                identity: report.identity,
+                error: None,
+            }),
+            Err(err) => {
+                outcomes.push(Outcome::failed(image, output, &err));
+                if opts.fail_fast {
+                    break;
+                }
+            }
+        }
+    }
+
+    Summary::new(outcomes).report(opts.report.as_deref()).await
+}
+
+/// Extract the target, then run its hooks.
+async fn run_target(
+    opts: &Options,
+    mut target: extract::Options,
+    spec: &Spec,
+) -> Result<(PathBuf, Report)> {
+    target.quiet = opts.quiet;
+    target.target.timeouts = opts.timeouts.clone();
+    target.target.limit_rate = opts.limit_rate;
+    target.configure(&opts.config);
+    let (output, report) = extract::extract_image(&target).await?;
+
+    for command in spec.exec.iter().flatten() {
+        exec::run(command, &output).await?;
+    }
+
+    Ok((output, report))
+}
+
+impl Job {
+    fn parse(content: &str) -> Result<Self> {
+        serde_yaml_ng::from_str(content).context("parse job file")
+    }
+
+    /// The options with which each target is extracted, along with its merged settings.
+    /// Relative paths are resolved against `base`.
+    fn targets(&self, base: &Path) -> Result<Vec<(Spec, extract::Options)>> {
+        if self.defaults.image.is_some() || self.defaults.output.is_some() {
+            bail!("defaults can't set 'image' or 'output'");
+        }
+        if self.targets.is_empty() {
+            bail!("job has no targets");
+        }
+
+        let layer_store = self.layer_store.as_ref().map(|store| base.join(store));
+        self.targets
+            .iter()
+            .map(|spec| {
+                let spec = spec.merge(&self.defaults);
+                let image = spec.image.clone().ok_or_eyre("'image' is required")?;
+                let output = spec.output.as_ref().ok_or_eyre("'output' is required")?;
+                if layer_store.is_some() && (spec.file_glob.is_some() || spec.file_regex.is_some())
+                {
+                    bail!("file filters can't be used with 'layer_store'");
+                }
+
+                let options = extract::Options {
+                    target: Target {
+                        image,
+                        platform: spec.platform.clone(),
+                        ..Default::default()
+                    },
+                    output_dir: base.join(output).to_string_lossy().to_string(),
+                    overwrite: spec.overwrite.unwrap_or_default(),
+                    layers: spec.layers,
+                    layer_glob: spec.layer_glob.clone(),
+                    layer_regex: spec.layer_regex.clone(),
+                    file_glob: spec.file_glob.clone(),
+                    file_regex: spec.file_regex.clone(),
+                    layer_store: layer_store.clone(),
+                    emit_empty_dirs: true,
+                    ..Default::default()
+                };
+                Ok((spec, options))
+            })
+            .enumerate()
+            .map(|(index, target)| target.with_context(|| format!("target {index}")))
+            .collect()
+    }
+}
+
+impl Spec {
+    /// Fill in the settings that this spec doesn't provide from the defaults.
+    fn merge(&self, defaults: &Spec) -> Spec {
+        let (layer_glob, layer_regex) = match (&self.layer_glob, &self.layer_regex) {
+            (None, None) => (defaults.layer_glob.clone(), defaults.layer_regex.clone()),
+            (glob, regex) => (glob.clone(), regex.clone()),
+        };
+        let (file_glob, file_regex) = match (&self.file_glob, &self.file_regex) {
+            (None, None) => (defaults.file_glob.clone(), defaults.file_regex.clone()),
+            (glob, regex) => (glob.clone(), regex.clone()),
+        };
+
+        Spec {
+            image: self.image.clone(),
+            output: self.output.clone(),
+            platform: self.platform.clone().or_else(|| defaults.platform.clone()),
+            layers: self.layers.or(defaults.layers),
+            overwrite: self.overwrite.or(defaults.overwrite),
+            layer_glob,
+            layer_regex,
+            file_glob,
+            file_regex,
+            exec: self.exec.clone().or_else(|| defaults.exec.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    const YAML: &str = r#"
+defaults:
+  platform: linux/arm64
+  layers: separate
+  file_glob: ["**/*.jar"]
+  exec: ["echo {}"]
+layer_store: layers
+targets:
+  - image: docker.io/library/alpine:latest
+    output: alpine
+  - image: docker.io/library/ubuntu:latest
+    output: /tmp/ubuntu
+    platform: linux/amd64
+    file_regex: ["\\.so$"]
+    exec: []
+"#;
+
+    const JSON: &str = r#"{
+  "targets": [{ "image": "docker.io/library/alpine:latest", "output": "alpine", "layers": "squash-other" }]
+}"#;
+
+    #[test]
+    fn merges_defaults() {
+        let job = Job::parse(YAML).expect("parse job");
+        let mut specs = job.targets.iter().map(|spec| spec.merge(&job.defaults));
+
+        let alpine = specs.next().expect("alpine");
+        pretty_assertions::assert_eq!(Some(Platform::linux_arm64()), alpine.platform);
+        pretty_assertions::assert_eq!(Some(Mode::Separate), alpine.layers);
+        pretty_assertions::assert_eq!(Some(vec![String::from("**/*.jar")]), alpine.file_glob);
+        pretty_assertions::assert_eq!(Some(vec![String::from("echo {}")]), alpine.exec);
+
+        let ubuntu = specs.next().expect("ubuntu");
+        pretty_assertions::assert_eq!(Some(Platform::linux_amd64()), ubuntu.platform);
+        pretty_assertions::assert_eq!(Some(Mode::Separate), ubuntu.layers);
+        pretty_assertions::assert_eq!(None, ubuntu.file_glob, "file filters merge as a group");
+        pretty_assertions::assert_eq!(Some(vec![String::from("\\.so$")]), ubuntu.file_regex);
+        pretty_assertions::assert_eq!(Some(vec![]), ubuntu.exec);
+    }
+
+    #[test]
+    fn resolves_paths() {
+        let job = Job::parse(JSON).expect("parse job");
+        let targets = job.targets(Path::new("/jobs")).expect("resolve targets");
+        let (_, options) = targets.first().expect("target");
+
+        pretty_assertions::assert_eq!("docker.io/library/alpine:latest", options.target.image);
+        pretty_assertions::assert_eq!(Path::new("/jobs/alpine"), Path::new(&options.output_dir));
+        pretty_assertions::assert_eq!(Some(Mode::SquashOther), options.layers);
+    }
+
+    #[test_case("targets: [{ image: alpine, output: out, file_glob: ['*'] }]\nlayer_store: store"; "store_with_file_filters")]
+    #[test_case("targets: [{ output: out }]"; "missing_image")]
+    #[test_case("targets: [{ image: alpine }]"; "missing_output")]
+    #[test_case("defaults: { image: alpine }\ntargets: [{ output: out }]"; "image_in_defaults")]
+    #[test_case("targets: []"; "no_targets")]
+    #[test_case("targets: [{ image: alpine, output: out, file_globs: ['*'] }]"; "unknown_key")]
+    #[test]
+    fn rejects_job(content: &str) {
+        let resolved = Job::parse(content).and_then(|job| job.targets(Path::new("")));
+        assert!(resolved.is_err(), "job is rejected");
+    }
+}
@@ -0,0 +1,81 @@
+//! Restricts what circe can do while unpacking layers,
+//! limiting the damage a hostile image can cause if it exploits a bug in extraction.
+//!
+//! Layers are unpacked on a dedicated thread that is restricted before it starts:
+//! Landlock limits it to writing within the output and temporary directories,
+//! and seccomp denies system calls that extraction never needs (like `mount` or `mknod`).
+//! Both restrictions are inherited by the threads and processes it starts (e.g. filter commands),
+//! but not by the rest of circe, which still needs to write elsewhere (for example, the progress display).
+
+use std::{future::Future, path::Path};
+
+use color_eyre::eyre::{Context, Report, Result};
+use tokio::runtime::{Handle, RuntimeFlavor};
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64"),
+    feature = "sandbox"
+))]
+mod linux;
+
+/// Whether this build of circe is able to sandbox extraction.
+pub const SUPPORTED: bool = cfg!(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64"),
+    feature = "sandbox"
+));
+
+/// Run `task` to completion on a thread that can only write within the `writable` directories.
+///
+/// The calling thread blocks until `task` completes. On a multi-threaded runtime its other tasks
+/// are moved to other threads first; a current-thread runtime (like each image's in a batch) is simply blocked.
+pub fn run<T, E, F, Fut>(writable: &[&Path], task: F) -> Result<T>
+where
+    T: Send,
+    E: Into<Report>,
+    F: FnOnce() -> Fut + Send,
+    Fut: Future<Output = std::result::Result<T, E>>,
+{
+    let restricted = || {
+        std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    restrict(writable).context("restrict thread")?;
+                    tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()
+                        .context("build runtime")?
+                        .block_on(task())
+                        .map_err(Into::into)
+                })
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+        })
+    };
+
+    match Handle::try_current().map(|handle| handle.runtime_flavor()) {
+        Ok(RuntimeFlavor::MultiThread) => tokio::task::block_in_place(restricted),
+        _ => restricted(),
+    }
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64"),
+    feature = "sandbox"
+))]
+fn restrict(writable: &[&Path]) -> Result<()> {
+    linux::no_new_privs().context("disable privilege escalation")?;
+    linux::landlock(writable).context("restrict filesystem access")?;
+    linux::seccomp().context("restrict system calls")
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64"),
+    feature = "sandbox"
+)))]
+fn restrict(_writable: &[&Path]) -> Result<()> {
+    color_eyre::eyre::bail!("sandboxing is not supported by this build of circe")
+}
@@ -0,0 +1,266 @@
+//! Landlock and seccomp, applied with raw system calls.
+//!
+//! `libc` provides the system call numbers but not the Landlock structures,
+//! so the few definitions needed are declared here from `linux/landlock.h` and `linux/audit.h`.
+//! This module opts out of the crate's `unsafe_code` denial;
+//! each call is a plain system call whose arguments outlive it.
+#![allow(unsafe_code)]
+
+use std::{
+    fs::OpenOptions,
+    io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::fs::OpenOptionsExt,
+    },
+    path::Path,
+    ptr,
+};
+
+use color_eyre::eyre::{bail, Context, Result};
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+const ACCESS_EXECUTE: u64 = 1 << 0;
+const ACCESS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_READ_FILE: u64 = 1 << 2;
+const ACCESS_READ_DIR: u64 = 1 << 3;
+const ACCESS_MAKE_CHAR: u64 = 1 << 6;
+const ACCESS_MAKE_BLOCK: u64 = 1 << 11;
+const ACCESS_REFER: u64 = 1 << 13;
+const ACCESS_TRUNCATE: u64 = 1 << 14;
+
+/// The access rights known to the first version of Landlock;
+/// later versions add rights, which are only handled if the kernel supports them.
+const ACCESS_V1: u64 = (1 << 13) - 1;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xC000_003E;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xC000_00B7;
+
+/// x32 system calls report the x86_64 architecture, but set this bit in the call number.
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// System calls denied to the extraction thread.
+///
+/// Extraction only reads, writes, and renames files, so these are never needed;
+/// they're the calls most useful for escalating from code execution to the rest of the system.
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_acct,
+    libc::SYS_bpf,
+    libc::SYS_chroot,
+    libc::SYS_delete_module,
+    libc::SYS_finit_module,
+    libc::SYS_init_module,
+    libc::SYS_kexec_load,
+    libc::SYS_mknodat,
+    libc::SYS_mount,
+    libc::SYS_name_to_handle_at,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_perf_event_open,
+    libc::SYS_pivot_root,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_ptrace,
+    libc::SYS_reboot,
+    libc::SYS_setns,
+    libc::SYS_swapoff,
+    libc::SYS_swapon,
+    libc::SYS_umount2,
+    libc::SYS_unshare,
+    libc::SYS_userfaultfd,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_ioperm,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_iopl,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_mknod,
+];
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// Prevent the thread (and its children) from gaining privileges, e.g. through setuid binaries.
+/// The kernel requires this before an unprivileged thread can restrict itself.
+pub fn no_new_privs() -> Result<()> {
+    // SAFETY: `prctl` with `PR_SET_NO_NEW_PRIVS` only reads its integer arguments.
+    let result = unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) };
+    check(result.into()).map(drop).context("set no_new_privs")
+}
+
+/// Restrict the thread to reading anywhere, but only writing within `writable`.
+///
+/// Reading is left unrestricted since extraction reads its inputs, credentials, and certificates
+/// from wherever they're configured; `/dev/null` is writable so commands can discard output.
+pub fn landlock(writable: &[&Path]) -> Result<()> {
+    // SAFETY: querying the version takes no attributes.
+    let abi = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            ptr::null::<RulesetAttr>(),
+            0usize,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 1 {
+        bail!(
+            "Landlock is not supported by this kernel: {}",
+            io::Error::last_os_error()
+        );
+    }
+
+    // The first version always denies linking or renaming files into another directory,
+    // so the layer store falls back to copying and deleted paths can't be recovered;
+    // later versions allow it within the writable directories through `REFER`.
+    let handled = match abi {
+        1 => ACCESS_V1,
+        2 => ACCESS_V1 | ACCESS_REFER,
+        _ => ACCESS_V1 | ACCESS_REFER | ACCESS_TRUNCATE,
+    };
+
+    let attr = RulesetAttr {
+        handled_access_fs: handled,
+    };
+    // SAFETY: `attr` is a valid ruleset attribute and outlives the call.
+    let fd = check(unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr as *const RulesetAttr,
+            size_of::<RulesetAttr>(),
+            0u32,
+        )
+    })
+    .context("create ruleset")?;
+    // SAFETY: the kernel returned a new file descriptor, which nothing else owns.
+    let ruleset = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+
+    allow(
+        &ruleset,
+        Path::new("/"),
+        ACCESS_EXECUTE | ACCESS_READ_FILE | ACCESS_READ_DIR,
+    )?;
+    allow(
+        &ruleset,
+        Path::new("/dev/null"),
+        ACCESS_READ_FILE | ACCESS_WRITE_FILE,
+    )?;
+
+    // Device nodes in layers are never created, so there's no reason to allow it.
+    let write = handled & !(ACCESS_MAKE_CHAR | ACCESS_MAKE_BLOCK);
+    for path in writable {
+        allow(&ruleset, path, write)?;
+    }
+
+    // SAFETY: the ruleset is a valid Landlock ruleset file descriptor.
+    let result =
+        unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0u32) };
+    check(result).map(drop).context("restrict self")
+}
+
+/// Deny the system calls in [`DENIED_SYSCALLS`] with `EPERM`.
+/// Calls made through another architecture's interface (like 32-bit compatibility mode) are refused too,
+/// since their numbers differ from the ones the filter checks.
+pub fn seccomp() -> Result<()> {
+    let deny = ret(libc::SECCOMP_RET_ERRNO | libc::EPERM as u32);
+    let mut program = vec![
+        load(std::mem::offset_of!(libc::seccomp_data, arch)),
+        jump(libc::BPF_JEQ, AUDIT_ARCH, 1, 0),
+        ret(libc::SECCOMP_RET_KILL_PROCESS),
+        load(std::mem::offset_of!(libc::seccomp_data, nr)),
+    ];
+
+    #[cfg(target_arch = "x86_64")]
+    program.extend([jump(libc::BPF_JGE, X32_SYSCALL_BIT, 0, 1), deny]);
+
+    for &syscall in DENIED_SYSCALLS {
+        program.extend([jump(libc::BPF_JEQ, syscall as u32, 0, 1), deny]);
+    }
+    program.push(ret(libc::SECCOMP_RET_ALLOW));
+
+    let filter = libc::sock_fprog {
+        len: u16::try_from(program.len()).context("filter length")?,
+        filter: program.as_mut_ptr(),
+    };
+    // SAFETY: `filter` points to `program`, which outlives the call; the kernel copies it.
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            0u32,
+            &filter as *const libc::sock_fprog,
+        )
+    };
+    check(result).map(drop).context("install filter")
+}
+
+fn allow(ruleset: &OwnedFd, path: &Path, access: u64) -> Result<()> {
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+        .open(path)
+        .with_context(|| format!("open {path:?}"))?;
+
+    let attr = PathBeneathAttr {
+        allowed_access: access,
+        parent_fd: file.as_raw_fd(),
+    };
+    // SAFETY: `attr` is a valid path beneath attribute and outlives the call,
+    // and both file descriptors are open for its duration.
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_add_rule,
+            ruleset.as_raw_fd(),
+            LANDLOCK_RULE_PATH_BENEATH,
+            &attr as *const PathBeneathAttr,
+            0u32,
+        )
+    };
+    check(result)
+        .map(drop)
+        .with_context(|| format!("allow access to {path:?}"))
+}
+
+fn load(offset: usize) -> libc::sock_filter {
+    libc::sock_filter {
+        code: (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16,
+        jt: 0,
+        jf: 0,
+        k: offset as u32,
+    }
+}
+
+fn jump(condition: u32, value: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: (libc::BPF_JMP | condition | libc::BPF_K) as u16,
+        jt,
+        jf,
+        k: value,
+    }
+}
+
+fn ret(action: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: (libc::BPF_RET | libc::BPF_K) as u16,
+        jt: 0,
+        jf: 0,
+        k: action,
+    }
+}
+
+fn check(result: libc::c_long) -> io::Result<libc::c_long> {
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(result)
+}
@@ -0,0 +1,484 @@
+//! Serves images over the read side of the OCI distribution API on a local address,
+//! so that tools which can only pull from a registry (like FOSSA CLI's registry path or containerd)
+//! can consume images circe reads from a tarball or stored with `circe prefetch`.
+//!
+//! A single image is served under every repository name and tag, and by the digest of its manifest:
+//! - `/v2/` reports that the registry is available, without authentication.
+//! - `/v2/<name>/manifests/<tag or digest>` serves an OCI image manifest for the image.
+//! - `/v2/<name>/blobs/<digest>` serves the config and layers of the image.
+//!
+//! Layers are served as they are stored in the source, but the manifest and config are written by circe
+//! (like `reexport` does), so the digest of the served image differs from the original.
+//!
+//! Without an image, the images stored in the cache are served instead (see [`cache`]).
+//!
+//! Reference:
+//! - https://github.com/opencontainers/distribution-spec/blob/main/spec.md#pulling-manifests
+
+use bytes::Bytes;
+use circe_lib::{
+    fossacli::{Image, RootFs},
+    source::{self, AnySource},
+    Digest, Layer, LayerMediaType, LayerMediaTypeFlag, Source,
+};
+use clap::Parser;
+use color_eyre::eyre::{bail, eyre, Context, Result};
+use derive_more::Debug;
+use futures_lite::StreamExt;
+use http_body_util::{combinators::UnsyncBoxBody, BodyExt, Full, StreamBody};
+use hyper::{
+    body::Frame, header, server::conn::http1, service::service_fn, Method, Request, Response,
+    StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use pluralizer::pluralize;
+use serde::Serialize;
+use serde_json::json;
+use sha2::{Digest as _, Sha256};
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+use tokio::net::TcpListener;
+use tracing::{debug, info, warn};
+
+use crate::{config::Config, extract::Target};
+use cache::Cached;
+
+mod cache;
+
+#[derive(Debug, Parser)]
+// Without an image, the images in the cache are served.
+#[command(mut_arg("image", |arg| arg.required(false).default_value("")))]
+pub struct Options {
+    /// Target container image to serve
+    ///
+    /// This is usually a Docker or OCI tarball, but any image circe can read is accepted.
+    /// If no image is provided, every image stored by `circe prefetch` in `--cache-dir` is served
+    /// under the reference it was stored with.
+    #[clap(flatten)]
+    target: Target,
+
+    /// The address on which to serve images
+    ///
+    /// Clients usually only pull over plain HTTP from loopback addresses without extra configuration.
+    #[arg(long, default_value = "127.0.0.1:5000")]
+    listen: SocketAddr,
+
+    /// Serve images on this port of the loopback address, instead of the address provided with `--listen`
+    #[arg(long, conflicts_with = "listen")]
+    port: Option<u16>,
+}
+
+impl Options {
+    /// Fill in the options that weren't provided from the configuration file.
+    pub fn configure(&mut self, config: &Config) {
+        self.target.configure(config);
+    }
+
+    /// The address on which to serve images.
+    fn address(&self) -> SocketAddr {
+        match self.port {
+            Some(port) => SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+            None => self.listen,
+        }
+    }
+}
+
+const MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+const CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.image.config.v1+json";
+
+/// The body of a response: either content held in memory, or a layer streamed from the source.
+type Body = UnsyncBoxBody<Bytes, circe_lib::Error>;
+
+#[tracing::instrument]
+pub async fn main(opts: Options) -> Result<()> {
+    let registry = match (opts.target.image.as_str(), opts.target.cache()) {
+        ("", Some(cache)) => Registry::Cache(Cached::new(&cache).await.context("read cache")?),
+        ("", None) => bail!(
+            "provide an image to serve, or `--cache-dir` to serve the images stored by `circe prefetch`"
+        ),
+        _ => Registry::Image(Box::new(image(&opts).await?)),
+    };
+    let registry = Arc::new(registry);
+
+    let address = opts.address();
+    let listener = TcpListener::bind(address)
+        .await
+        .with_context(|| format!("listen on {address}"))?;
+    let addr = listener.local_addr().context("read listening address")?;
+    match registry.as_ref() {
+        Registry::Image(served) => {
+            info!(%addr, manifest = %served.manifest_digest, "serving image; stop with ctrl-c");
+            println!("{addr}/image:latest");
+        }
+        Registry::Cache(cached) => {
+            info!(%addr, "serving {}; stop with ctrl-c", pluralize("image", cached.len() as isize, true));
+            for reference in cached.references() {
+                println!("{addr}/{reference}");
+            }
+        }
+    }
+
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted.context("accept connection")?,
+            _ = tokio::signal::ctrl_c() => {
+                info!("stopped serving");
+                return Ok(());
+            }
+        };
+
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            let service = service_fn(|request| {
+                let registry = registry.clone();
+                async move { Ok::<_, std::convert::Infallible>(registry.respond(request).await) }
+            });
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                debug!(%peer, ?err, "connection failed");
+            }
+        });
+    }
+}
+
+/// Read the image to serve from its source.
+async fn image(opts: &Options) -> Result<Served> {
+    let options = source::Options::builder()
+        .maybe_platform(opts.target.platform.clone())
+        .maybe_annotations(opts.target.annotations.clone())
+        .maybe_manifest_digest(opts.target.manifest_digest.clone())
+        .maybe_auth(opts.target.auth().await?)
+        .buffering(opts.target.buffering())
+        .timeouts(opts.target.timeouts.registry())
+        .maybe_limit_rate(opts.target.limit_rate)
+        .maybe_pull_policy(opts.target.pull_policy)
+        .maybe_kind(opts.target.source)
+        .daemon(opts.target.daemon())
+        .maybe_cache(opts.target.cache())
+        .build();
+    let source = opts.target.detect(options).await?;
+    Served::new(source).await.context("prepare image")
+}
+
+/// What the registry serves.
+#[derive(Debug)]
+enum Registry {
+    /// A single image, under every repository name and tag.
+    Image(Box<Served>),
+
+    /// The images stored in the cache, under the references they were stored with.
+    Cache(Cached),
+}
+
+impl Registry {
+    /// Respond to a request to the registry.
+    async fn respond(&self, request: Request<hyper::body::Incoming>) -> Response<Body> {
+        let method = request.method().clone();
+        let path = request.uri().path().to_string();
+        let head = method == Method::HEAD;
+        debug!(%method, %path, "request");
+        if method != Method::GET && !head {
+            return error(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "UNSUPPORTED",
+                "the registry is read-only",
+            );
+        }
+
+        match (Route::parse(&path), self) {
+            (Route::Base, _) => content("application/json", None, Bytes::from_static(b"{}"), head),
+            (route, Registry::Image(served)) => served.respond(route, head).await,
+            (route, Registry::Cache(cached)) => cached.respond(route, head).await,
+        }
+    }
+}
+
+/// The image as it's served.
+#[derive(Debug)]
+struct Served {
+    #[debug(skip)]
+    source: AnySource,
+    layers: Vec<Layer>,
+    manifest: Bytes,
+    manifest_digest: Digest,
+    config: Bytes,
+    config_digest: Digest,
+}
+
+impl Served {
+    /// Write the manifest and config for the image read from the source.
+    async fn new(source: AnySource) -> Result<Self> {
+        let layers = source.layers().await.context("list layers")?;
+        let (layers, foreign) = layers
+            .into_iter()
+            .partition::<Vec<_>, _>(|layer| !foreign(layer));
+        for layer in &foreign {
+            warn!(%layer, "skip: foreign layer");
+        }
+        info!(
+            "serving {}",
+            pluralize("layer", layers.len() as isize, true)
+        );
+
+        // Runtimes verify layers against the diff IDs in the config once they're decompressed,
+        // so they can't be guessed for compressed layers.
+        let diff_ids = layers
+            .iter()
+            .map(|layer| match (&layer.diff_id, compressed(layer)) {
+                (Some(diff_id), _) => Ok(diff_id.to_string()),
+                (None, false) => Ok(layer.digest.to_string()),
+                (None, true) => Err(eyre!(
+                    "layer {layer} is compressed, but its diff ID is unknown"
+                )),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // Like `reexport`, the rest of the config is best effort.
+        let config = source.config().await.unwrap_or_else(|err| {
+            warn!(?err, "unable to read image config, serving minimal config");
+            Default::default()
+        });
+        let config =
+            to_json(&Image::new(config, RootFs::layers(diff_ids))).context("write config")?;
+        let config_digest = sha256(&config);
+
+        let manifest = to_json(&json!({
+            "schemaVersion": 2,
+            "mediaType": MANIFEST_MEDIA_TYPE,
+            "config": {
+                "mediaType": CONFIG_MEDIA_TYPE,
+                "digest": config_digest.to_string(),
+                "size": config.len(),
+            },
+            "layers": layers.iter().map(|layer| json!({
+                "mediaType": layer.media_type.to_string(),
+                "digest": layer.digest.to_string(),
+                "size": layer.size,
+            })).collect::<Vec<_>>(),
+        }))
+        .context("write manifest")?;
+        let manifest_digest = sha256(&manifest);
+
+        Ok(Self {
+            source,
+            layers,
+            manifest,
+            manifest_digest,
+            config,
+            config_digest,
+        })
+    }
+
+    /// Respond to a request for an endpoint of the registry.
+    async fn respond(&self, route: Route<'_>, head: bool) -> Response<Body> {
+        match route {
+            Route::Manifest(_, reference) if self.serves_manifest(reference) => content(
+                MANIFEST_MEDIA_TYPE,
+                Some(&self.manifest_digest),
+                self.manifest.clone(),
+                head,
+            ),
+            Route::Manifest(..) => error(
+                StatusCode::NOT_FOUND,
+                "MANIFEST_UNKNOWN",
+                "manifest unknown",
+            ),
+            Route::Blob(_, digest) if digest == self.config_digest.to_string() => content(
+                CONFIG_MEDIA_TYPE,
+                Some(&self.config_digest),
+                self.config.clone(),
+                head,
+            ),
+            Route::Blob(_, digest) => {
+                match self
+                    .layers
+                    .iter()
+                    .find(|layer| layer.digest.to_string() == digest)
+                {
+                    Some(layer) => self.layer(layer, head).await,
+                    None => error(StatusCode::NOT_FOUND, "BLOB_UNKNOWN", "blob unknown"),
+                }
+            }
+            _ => error(StatusCode::NOT_FOUND, "NAME_UNKNOWN", "not found"),
+        }
+    }
+
+    /// Whether the reference names the served manifest: any tag, or its digest.
+    fn serves_manifest(&self, reference: &str) -> bool {
+        match reference.parse::<Digest>() {
+            Ok(digest) => digest == self.manifest_digest,
+            Err(_) => true,
+        }
+    }
+
+    /// Stream the layer from the source.
+    async fn layer(&self, layer: &Layer, head: bool) -> Response<Body> {
+        let builder = Response::builder()
+            .header(header::CONTENT_TYPE, layer.media_type.to_string())
+            .header(header::CONTENT_LENGTH, layer.size)
+            .header("Docker-Content-Digest", layer.digest.to_string());
+        if head {
+            return respond(builder, full(Bytes::new()));
+        }
+
+        match self.source.pull_layer(layer).await {
+            Ok(stream) => {
+                let body = StreamBody::new(stream.map(|chunk| chunk.map(Frame::data)));
+                respond(builder, body.boxed_unsync())
+            }
+            Err(err) => {
+                warn!(%layer, ?err, "unable to read layer");
+                error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "UNKNOWN",
+                    "unable to read layer",
+                )
+            }
+        }
+    }
+}
+
+/// The registry endpoint a request is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Route<'a> {
+    /// The base endpoint, `/v2/`.
+    Base,
+
+    /// The repositories in the registry, `/v2/_catalog`.
+    Catalog,
+
+    /// A manifest in a repository, by tag or digest.
+    Manifest(&'a str, &'a str),
+
+    /// A blob in a repository, by digest.
+    Blob(&'a str, &'a str),
+
+    /// The tags in a repository.
+    Tags(&'a str),
+
+    /// Anything else.
+    Unknown,
+}
+
+impl<'a> Route<'a> {
+    /// Parse the path of a request.
+    /// Repository names can contain slashes, so the endpoint is found from the end of the path.
+    fn parse(path: &'a str) -> Self {
+        let Some(rest) = path.strip_prefix("/v2/") else {
+            return Self::Unknown;
+        };
+        match rest {
+            "" => return Self::Base,
+            "_catalog" => return Self::Catalog,
+            _ => {}
+        }
+
+        let segments = rest.rsplitn(3, '/').collect::<Vec<_>>();
+        match segments.as_slice() {
+            [reference, "manifests", name] if valid(name, reference) => {
+                Self::Manifest(name, reference)
+            }
+            [digest, "blobs", name] if valid(name, digest) => Self::Blob(name, digest),
+            ["list", "tags", name] if !name.is_empty() => Self::Tags(name),
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// Whether the repository name and reference of a path are non-empty.
+fn valid(name: &str, reference: &str) -> bool {
+    !name.is_empty() && !reference.is_empty()
+}
+
+/// Whether the layer isn't distributed with the image.
+fn foreign(layer: &Layer) -> bool {
+    let LayerMediaType::Oci(flags) = &layer.media_type;
+    flags.contains(&LayerMediaTypeFlag::Foreign)
+}
+
+/// Whether the layer is distributed compressed.
+fn compressed(layer: &Layer) -> bool {
+    let LayerMediaType::Oci(flags) = &layer.media_type;
+    flags
+        .iter()
+        .any(|flag| matches!(flag, LayerMediaTypeFlag::Gzip | LayerMediaTypeFlag::Zstd))
+}
+
+/// The SHA256 digest of the content.
+fn sha256(content: &[u8]) -> Digest {
+    Digest::from_hash(Sha256::digest(content).to_vec())
+}
+
+/// Respond with content held in memory; `HEAD` requests only receive its headers.
+fn content(media_type: &str, digest: Option<&Digest>, body: Bytes, head: bool) -> Response<Body> {
+    let mut builder = Response::builder()
+        .header(header::CONTENT_TYPE, media_type)
+        .header(header::CONTENT_LENGTH, body.len())
+        .header("Docker-Distribution-API-Version", "registry/2.0");
+    if let Some(digest) = digest {
+        builder = builder.header("Docker-Content-Digest", digest.to_string());
+    }
+
+    let body = match head {
+        true => Bytes::new(),
+        false => body,
+    };
+    respond(builder, full(body))
+}
+
+/// Respond with an error in the format of the distribution spec.
+fn error(status: StatusCode, code: &str, message: &str) -> Response<Body> {
+    let body = json!({ "errors": [{ "code": code, "message": message }] }).to_string();
+    let builder = Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json");
+    respond(builder, full(Bytes::from(body)))
+}
+
+/// Finish the response; the headers set by this module are always valid.
+fn respond(builder: hyper::http::response::Builder, body: Body) -> Response<Body> {
+    builder.body(body).unwrap_or_else(|err| {
+        warn!(?err, "unable to build response");
+        let mut response = Response::new(full(Bytes::new()));
+        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        response
+    })
+}
+
+/// A body of content held in memory.
+fn full(content: Bytes) -> Body {
+    Full::new(content)
+        .map_err(|never| match never {})
+        .boxed_unsync()
+}
+
+/// Serialize the value to JSON, as it's served.
+fn to_json(value: &impl Serialize) -> Result<Bytes> {
+    serde_json::to_vec(value)
+        .map(Bytes::from)
+        .map_err(Into::into)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    #[test_case("/v2/", Route::Base; "base")]
+    #[test_case("/v2/_catalog", Route::Catalog; "catalog")]
+    #[test_case("/v2/image/manifests/latest", Route::Manifest("image", "latest"); "manifest_by_tag")]
+    #[test_case("/v2/team/app/manifests/sha256:abcd", Route::Manifest("team/app", "sha256:abcd"); "manifest_by_digest")]
+    #[test_case("/v2/team/app/blobs/sha256:abcd", Route::Blob("team/app", "sha256:abcd"); "blob")]
+    #[test_case("/v2/team/app/tags/list", Route::Tags("team/app"); "tags")]
+    #[test_case("/v2/tags/list", Route::Unknown; "tags_no_name")]
+    #[test_case("/v2/manifests/latest", Route::Unknown; "no_name")]
+    #[test_case("/v1/image/manifests/latest", Route::Unknown; "wrong_version")]
+    #[test]
+    fn route(path: &str, expected: Route<'_>) {
+        pretty_assertions::assert_eq!(expected, Route::parse(path));
+    }
+}
@@ -0,0 +1,225 @@
+//! Serves the images stored in a cache by `circe prefetch`, under the references they were stored with.
+//!
+//! Images are stored exactly as the registry distributed them, so they're served with their original manifests
+//! and digests; a client pulls `docker.io/library/alpine:3.20` from the cache as `<address>/library/alpine:3.20`.
+//! Repositories are also served with the registry host prefixed (`<address>/docker.io/library/alpine:3.20`),
+//! and Docker Hub's official images without `library/` (`<address>/alpine:3.20`).
+//!
+//! Images are stored per platform; if a tag was stored for several platforms,
+//! the first stored image (ordered by path) is served for the tag, and the others only by digest.
+//! `/v2/_catalog` and `/v2/<name>/tags/list` list the stored repositories and tags.
+
+use bytes::Bytes;
+use circe_lib::{
+    cache::{Cache, Layout},
+    Digest, Reference, Version,
+};
+use color_eyre::eyre::{Context, Result};
+use futures_lite::StreamExt;
+use http_body_util::{BodyExt, StreamBody};
+use hyper::{body::Frame, header, Response, StatusCode};
+use serde_json::json;
+use tracing::{info, warn};
+
+use super::{content, error, full, respond, to_json, Body, Route};
+
+/// The images stored in the cache, as they're served.
+#[derive(Debug)]
+pub struct Cached {
+    images: Vec<Layout>,
+}
+
+impl Cached {
+    /// Index every image stored in the cache.
+    pub async fn new(cache: &Cache) -> Result<Self> {
+        let images = cache.layouts().await.context("list stored images")?;
+        if images.is_empty() {
+            warn!(dir = ?cache.dir(), "no images are stored in the cache");
+        }
+        for image in &images {
+            info!(reference = %image.reference, digest = %image.digest, "serving stored image");
+        }
+        Ok(Self { images })
+    }
+
+    /// The number of images served.
+    pub fn len(&self) -> usize {
+        self.images.len()
+    }
+
+    /// The reference from which each image can be pulled, relative to the registry address.
+    pub fn references(&self) -> Vec<String> {
+        self.images
+            .iter()
+            .map(|image| {
+                let repository = image.reference.repository();
+                match &image.reference.version {
+                    Version::Tag(tag) => format!("{repository}:{tag}"),
+                    Version::Digest(digest) => format!("{repository}@{digest}"),
+                }
+            })
+            .collect()
+    }
+
+    /// Respond to a request for an endpoint of the registry.
+    pub async fn respond(&self, route: Route<'_>, head: bool) -> Response<Body> {
+        match route {
+            Route::Catalog => self.catalog(head),
+            Route::Tags(name) => self.tags(name, head),
+            Route::Manifest(name, reference) => self.manifest(name, reference, head).await,
+            Route::Blob(name, digest) => self.blob(name, digest, head).await,
+            _ => error(StatusCode::NOT_FOUND, "NAME_UNKNOWN", "not found"),
+        }
+    }
+
+    /// The images in the repository with the name.
+    fn repository<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Layout> + 'a {
+        self.images
+            .iter()
+            .filter(move |image| names(&image.reference).iter().any(|served| served == name))
+    }
+
+    /// List every repository stored in the cache.
+    fn catalog(&self, head: bool) -> Response<Body> {
+        let mut repositories = self
+            .images
+            .iter()
+            .map(|image| image.reference.repository())
+            .collect::<Vec<_>>();
+        repositories.sort();
+        repositories.dedup();
+        listing(&json!({ "repositories": repositories }), head)
+    }
+
+    /// List the tags stored for the repository.
+    fn tags(&self, name: &str, head: bool) -> Response<Body> {
+        let images = self.repository(name).collect::<Vec<_>>();
+        if images.is_empty() {
+            return error(
+                StatusCode::NOT_FOUND,
+                "NAME_UNKNOWN",
+                "repository name not known to registry",
+            );
+        }
+
+        let mut tags = images
+            .iter()
+            .filter_map(|image| match &image.reference.version {
+                Version::Tag(tag) => Some(tag.as_str()),
+                Version::Digest(_) => None,
+            })
+            .collect::<Vec<_>>();
+        tags.sort();
+        tags.dedup();
+        listing(&json!({ "name": name, "tags": tags }), head)
+    }
+
+    /// Serve the manifest of the image in the repository with the tag or digest.
+    async fn manifest(&self, name: &str, reference: &str, head: bool) -> Response<Body> {
+        let digest = reference.parse::<Digest>().ok();
+        let image = self
+            .repository(name)
+            .find(|image| match (&digest, &image.reference.version) {
+                (Some(digest), _) => &image.digest == digest,
+                (None, Version::Tag(tag)) => tag == reference,
+                (None, Version::Digest(_)) => false,
+            });
+        let Some(image) = image else {
+            return error(
+                StatusCode::NOT_FOUND,
+                "MANIFEST_UNKNOWN",
+                "manifest unknown",
+            );
+        };
+
+        match image.manifest().await {
+            Ok(manifest) => content(&image.media_type, Some(&image.digest), manifest, head),
+            Err(err) => {
+                warn!(reference = %image.reference, ?err, "unable to read manifest");
+                error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "UNKNOWN",
+                    "unable to read manifest",
+                )
+            }
+        }
+    }
+
+    /// Stream the blob with the digest from an image in the repository.
+    async fn blob(&self, name: &str, digest: &str, head: bool) -> Response<Body> {
+        let found = digest.parse::<Digest>().ok().and_then(|digest| {
+            self.repository(name).find_map(|image| {
+                let size = image.blob_size(&digest)?;
+                Some((image, digest.clone(), size))
+            })
+        });
+        let Some((image, digest, size)) = found else {
+            return error(StatusCode::NOT_FOUND, "BLOB_UNKNOWN", "blob unknown");
+        };
+
+        let builder = Response::builder()
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .header(header::CONTENT_LENGTH, size)
+            .header("Docker-Content-Digest", digest.to_string());
+        if head {
+            return respond(builder, full(Bytes::new()));
+        }
+
+        match image.blob(&digest).await {
+            Ok(stream) => {
+                let body = StreamBody::new(stream.map(|chunk| chunk.map(Frame::data)));
+                respond(builder, body.boxed_unsync())
+            }
+            Err(err) => {
+                warn!(%digest, ?err, "unable to read blob");
+                error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "UNKNOWN",
+                    "unable to read blob",
+                )
+            }
+        }
+    }
+}
+
+/// The repository names under which the image stored with the reference is served.
+fn names(reference: &Reference) -> Vec<String> {
+    let repository = reference.repository();
+    let mut names = vec![format!("{}/{repository}", reference.host)];
+    if reference.host == "docker.io" && reference.namespace == "library" {
+        names.push(reference.name.clone());
+    }
+    names.push(repository);
+    names
+}
+
+/// Respond with a JSON listing.
+fn listing(value: &serde_json::Value, head: bool) -> Response<Body> {
+    match to_json(value) {
+        Ok(body) => content("application/json", None, body, head),
+        Err(err) => {
+            warn!(?err, "unable to write listing");
+            error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "UNKNOWN",
+                "unable to write listing",
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+    use std::str::FromStr;
+
+    #[test_case("docker.io/library/alpine:3.20", &["docker.io/library/alpine", "alpine", "library/alpine"]; "official")]
+    #[test_case("docker.io/contribsys/faktory:latest", &["docker.io/contribsys/faktory", "contribsys/faktory"]; "docker_hub")]
+    #[test_case("localhost:5000/app:1.0", &["localhost:5000/app", "app"]; "no_namespace")]
+    #[test]
+    fn served_names(reference: &str, expected: &[&str]) {
+        let reference = Reference::from_str(reference).expect("parse reference");
+        pretty_assertions::assert_eq!(expected, names(&reference));
+    }
+}
@@ -0,0 +1,203 @@
+//! Estimates the disk space extraction needs, for `extract --dry-run` and the check made before extracting.
+
+use circe_lib::{extract::Strategy, Layer, LayerMediaType, LayerMediaTypeFlag};
+use color_eyre::{
+    eyre::{eyre, Result},
+    Section,
+};
+use indicatif::HumanBytes;
+use serde::Serialize;
+use std::path::Path;
+
+/// The disk space that extracting the layers is estimated to need.
+#[derive(Debug, Serialize)]
+pub struct Estimate {
+    /// The layers that would be extracted.
+    pub layers: Vec<LayerEstimate>,
+
+    /// The total size of the layers as distributed.
+    pub size: u64,
+
+    /// The total number of bytes the layers are estimated to write.
+    pub estimated_size: u64,
+
+    /// Whether `estimated_size` is only a lower bound,
+    /// because some compressed layers don't record their uncompressed size.
+    pub lower_bound: bool,
+
+    /// The space available in the output directory, if it could be determined.
+    pub available: Option<u64>,
+
+    /// The estimated size of the largest layer; layers are buffered in the temporary directory one at a time.
+    pub largest_layer: u64,
+
+    /// The space available in the temporary directory, if it could be determined.
+    pub temp_available: Option<u64>,
+
+    /// Whether the temporary directory is too small to buffer the largest layer,
+    /// so layers are buffered in the output directory instead.
+    pub spill: bool,
+}
+
+/// The size of a layer that would be extracted.
+#[derive(Debug, Serialize)]
+pub struct LayerEstimate {
+    pub digest: String,
+
+    /// The size of the layer as distributed.
+    pub size: u64,
+
+    /// The size of the layer's uncompressed content, if its descriptor records it.
+    pub uncompressed_size: Option<u64>,
+}
+
+impl Estimate {
+    /// Estimate the space needed to extract the layers with the strategies into the output directory.
+    ///
+    /// Layers squashed together are counted in full, even though later layers may delete or replace
+    /// content from earlier ones.
+    pub fn new<'a>(strategies: impl IntoIterator<Item = &'a Strategy>, output: &Path) -> Self {
+        let layers = strategies
+            .into_iter()
+            .flat_map(|strategy| match strategy {
+                Strategy::Squash(layers) => layers.iter().collect(),
+                Strategy::Separate(layer) | Strategy::Stored(layer, _) => vec![layer],
+            })
+            .collect::<Vec<_>>();
+
+        let largest_layer = layers
+            .iter()
+            .map(|layer| layer.estimated_size())
+            .max()
+            .unwrap_or_default();
+        let temp_available = available_space(&std::env::temp_dir());
+
+        Self {
+            size: layers.iter().map(|layer| distributed_size(layer)).sum(),
+            estimated_size: layers.iter().map(|layer| layer.estimated_size()).sum(),
+            lower_bound: layers
+                .iter()
+                .any(|layer| layer.uncompressed_size.is_none() && compressed(layer)),
+            available: available_space(output),
+            largest_layer,
+            temp_available,
+            spill: temp_available.is_some_and(|available| largest_layer > available),
+            layers: layers
+                .into_iter()
+                .map(|layer| LayerEstimate {
+                    digest: layer.digest.to_string(),
+                    size: distributed_size(layer),
+                    uncompressed_size: layer.uncompressed_size,
+                })
+                .collect(),
+        }
+    }
+
+    /// The number of bytes needed in the output directory:
+    /// the estimated size, along with room to buffer the largest layer if layers spill there.
+    pub fn needed(&self) -> u64 {
+        match self.spill {
+            true => self.estimated_size.saturating_add(self.largest_layer),
+            false => self.estimated_size,
+        }
+    }
+
+    /// Fail if the estimated size doesn't fit in the available space.
+    ///
+    /// If the available space can't be determined, the check passes.
+    pub fn check(&self) -> Result<()> {
+        let Some(available) = self.available else {
+            return Ok(());
+        };
+        let needed = self.needed();
+        if needed <= available {
+            return Ok(());
+        }
+
+        let needed = HumanBytes(needed);
+        let available = HumanBytes(available);
+        let at_least = if self.lower_bound { "at least " } else { "" };
+        Err(eyre!(
+            "not enough disk space to extract image: needs {at_least}{needed}, but only {available} is available"
+        ))
+        .with_suggestion(|| "free up disk space, or pass --skip-space-check to extract anyway")
+    }
+}
+
+/// The size of the layer as distributed; descriptors never record negative sizes.
+fn distributed_size(layer: &Layer) -> u64 {
+    u64::try_from(layer.size).unwrap_or_default()
+}
+
+/// Whether the layer is distributed compressed, so its content is larger than its size.
+fn compressed(layer: &Layer) -> bool {
+    let LayerMediaType::Oci(flags) = &layer.media_type;
+    flags
+        .iter()
+        .any(|flag| matches!(flag, LayerMediaTypeFlag::Gzip | LayerMediaTypeFlag::Zstd))
+}
+
+/// The space available to unprivileged users on the filesystem containing the path.
+///
+/// The path doesn't need to exist yet; its closest existing ancestor is used instead.
+#[cfg(unix)]
+fn available_space(path: &Path) -> Option<u64> {
+    let path = std::path::absolute(path).ok()?;
+    let existing = path.ancestors().find(|path| path.exists())?;
+    let stats = rustix::fs::statvfs(existing).ok()?;
+    Some(stats.f_bavail.saturating_mul(stats.f_frsize))
+}
+
+#[cfg(not(unix))]
+fn available_space(_: &Path) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use circe_lib::Digest;
+    use simple_test_case::test_case;
+
+    fn layer(size: i64, uncompressed_size: Option<u64>, flags: Vec<LayerMediaTypeFlag>) -> Layer {
+        Layer {
+            uncompressed_size,
+            ..Layer::builder()
+                .digest(Digest::from_hash(vec![0; 32]))
+                .size(size)
+                .media_type(LayerMediaType::Oci(flags))
+                .build()
+        }
+    }
+
+    #[test_case(vec![layer(10, Some(40), vec![LayerMediaTypeFlag::Gzip]), layer(20, None, vec![])], 30, 60, false; "exact")]
+    #[test_case(vec![layer(10, Some(40), vec![LayerMediaTypeFlag::Gzip]), layer(20, None, vec![LayerMediaTypeFlag::Zstd])], 30, 60, true; "lower_bound")]
+    #[test]
+    fn estimate(layers: Vec<Layer>, size: u64, estimated_size: u64, lower_bound: bool) {
+        let strategies = [Strategy::Squash(layers)];
+        let estimate = Estimate::new(&strategies, Path::new("."));
+        pretty_assertions::assert_eq!(
+            (size, estimated_size, lower_bound),
+            (estimate.size, estimate.estimated_size, estimate.lower_bound)
+        );
+    }
+
+    #[test_case(Some(59), None, true; "too_small")]
+    #[test_case(Some(60), None, false; "fits")]
+    #[test_case(None, None, false; "unknown")]
+    #[test_case(Some(60), Some(60), false; "temp_fits")]
+    #[test_case(Some(119), Some(59), true; "spill_too_small")]
+    #[test_case(Some(120), Some(59), false; "spill_fits")]
+    #[test]
+    fn check(available: Option<u64>, temp_available: Option<u64>, fails: bool) {
+        let strategies = [Strategy::Separate(layer(60, None, vec![]))];
+        let estimate = Estimate::new(&strategies, Path::new("."));
+        let estimate = Estimate {
+            available,
+            temp_available,
+            spill: temp_available.is_some_and(|temp| estimate.largest_layer > temp),
+            ..estimate
+        };
+        pretty_assertions::assert_eq!(fails, estimate.check().is_err());
+    }
+}
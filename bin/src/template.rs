@@ -0,0 +1,117 @@
+//! A tiny subset of Go templates, for commands that support `--format`.
+//!
+//! This mirrors the `--format` flag of the docker CLI closely enough for the common case
+//! of extracting a single field in a shell script without needing `jq`:
+//! - `{{.Field}}` renders a field of the output; fields can be nested, e.g. `{{.Config.Digest}}`.
+//! - `{{.}}` renders the whole output.
+//! - `{{json .Field}}` renders the field as JSON.
+//!
+//! Field names are matched ignoring case and underscores, so `{{.ShortDigest}}` renders the `short_digest` field.
+//! Strings and numbers are rendered as-is, missing fields and nulls are rendered as `<no value>`
+//! (like Go templates), and anything else is rendered as JSON.
+
+use color_eyre::{
+    eyre::{bail, eyre, Context, Result},
+    Section, SectionExt,
+};
+use serde::Serialize;
+use serde_json::Value;
+
+/// Render the template with fields from the value.
+pub fn render(template: &str, value: &impl Serialize) -> Result<String> {
+    let value = serde_json::to_value(value).context("serialize value")?;
+    render_value(template, &value).with_section(|| template.to_string().header("Template:"))
+}
+
+fn render_value(template: &str, value: &Value) -> Result<String> {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let (action, after) = rest[start + 2..]
+            .split_once("}}")
+            .ok_or_else(|| eyre!("unterminated action"))?;
+        rendered.push_str(&action_value(action.trim(), value)?);
+        rest = after;
+    }
+
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+/// Evaluate a single action, e.g. `.Digest` or `json .Layers`.
+fn action_value(action: &str, value: &Value) -> Result<String> {
+    let (json, path) = match action.split_once(char::is_whitespace) {
+        Some(("json", path)) => (true, path.trim()),
+        Some((function, _)) => bail!("unsupported function '{function}'"),
+        None => (false, action),
+    };
+
+    let Some(path) = path.strip_prefix('.') else {
+        bail!("unsupported action '{action}': fields must start with '.'");
+    };
+
+    let field = path
+        .split('.')
+        .filter(|name| !name.is_empty())
+        .try_fold(value, |value, name| field(value, name));
+
+    match (json, field) {
+        (true, field) => {
+            serde_json::to_string(field.unwrap_or(&Value::Null)).context("render field as JSON")
+        }
+        (false, None | Some(Value::Null)) => Ok(String::from("<no value>")),
+        (false, Some(Value::String(s))) => Ok(s.clone()),
+        (false, Some(Value::Number(n))) => Ok(n.to_string()),
+        (false, Some(Value::Bool(b))) => Ok(b.to_string()),
+        (false, Some(field)) => serde_json::to_string(field).context("render field"),
+    }
+}
+
+/// Look up the field in the value, ignoring case and underscores.
+fn field<'a>(value: &'a Value, name: &str) -> Option<&'a Value> {
+    let normalize = |s: &str| s.replace('_', "").to_lowercase();
+    let name = normalize(name);
+    value
+        .as_object()?
+        .iter()
+        .find(|(key, _)| normalize(key) == name)
+        .map(|(_, value)| value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use simple_test_case::test_case;
+
+    fn value() -> Value {
+        json!({
+            "name": "alpine",
+            "short_digest": "a3ed95caeb02",
+            "layers": [{ "size": 10 }],
+            "config": { "digest": "sha256:1234" },
+        })
+    }
+
+    #[test_case("{{.Name}}", "alpine"; "field")]
+    #[test_case("{{ .ShortDigest }}", "a3ed95caeb02"; "field_case_and_underscores")]
+    #[test_case("{{.Config.Digest}}", "sha256:1234"; "nested_field")]
+    #[test_case("{{.Layers}}", r#"[{"size":10}]"#; "array")]
+    #[test_case("{{json .Name}}", r#""alpine""#; "json")]
+    #[test_case("{{.Missing}}", "<no value>"; "missing")]
+    #[test_case("{{.Name}}@{{.Config.Digest}}", "alpine@sha256:1234"; "multiple")]
+    #[test]
+    fn renders(template: &str, expected: &str) {
+        let rendered = render(template, &value()).expect("render template");
+        pretty_assertions::assert_eq!(expected, rendered);
+    }
+
+    #[test_case("{{.Name"; "unterminated")]
+    #[test_case("{{Name}}"; "no_dot")]
+    #[test_case("{{upper .Name}}"; "unsupported_function")]
+    #[test]
+    fn rejects(template: &str) {
+        assert!(render(template, &value()).is_err());
+    }
+}
@@ -0,0 +1,74 @@
+use circe_lib::{extract::FileDigest, Digest};
+use color_eyre::{
+    eyre::{bail, ensure, Context, OptionExt, Result},
+    Section, SectionExt,
+};
+use std::{path::Path, process::Stdio, str::FromStr};
+use tokio::process::Command;
+use tracing::debug;
+
+/// The `fsverity` tool from `fsverity-utils`.
+///
+/// This is used rather than issuing the ioctls directly so that circe doesn't need `unsafe` code;
+/// the tool is widely packaged on distributions whose kernels support fs-verity.
+const FSVERITY: &str = "fsverity";
+
+/// Enable fs-verity on each extracted file, then check that the digest measured by the kernel
+/// matches the digest computed when the file was hashed.
+///
+/// Files sealed with fs-verity are read-only: the kernel rejects any attempt to write to them,
+/// and verifies their content against the Merkle tree as they are read.
+/// The filesystem must support fs-verity (e.g. ext4 or btrfs with the feature enabled).
+pub async fn enable(output: &Path, files: &[FileDigest]) -> Result<()> {
+    for file in files {
+        let path = output.join(&file.path);
+        let expected = file
+            .verity
+            .as_ref()
+            .ok_or_eyre("fs-verity digest not computed")
+            .with_section(|| path.display().to_string().header("Path:"))?;
+
+        fsverity(["enable".as_ref(), path.as_os_str()])
+            .await
+            .with_context(|| format!("enable fs-verity on {path:?}"))?;
+
+        // Output is in the form `sha256:<hex> <path>`.
+        let measured = fsverity(["measure".as_ref(), path.as_os_str()])
+            .await
+            .with_context(|| format!("measure {path:?}"))?;
+        let measured = measured
+            .split_whitespace()
+            .next()
+            .ok_or_eyre("empty measurement")
+            .and_then(|digest| Digest::from_str(digest).context("parse measurement"))?;
+
+        ensure!(
+            &measured == expected,
+            "fs-verity digest of {path:?} is {measured}, but {expected} was computed during extraction"
+        );
+        debug!(path = ?file.path, %measured, "enabled fs-verity");
+    }
+
+    Ok(())
+}
+
+async fn fsverity<'a>(args: impl IntoIterator<Item = &'a std::ffi::OsStr>) -> Result<String> {
+    let output = Command::new(FSVERITY)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .with_context(|| format!("run `{FSVERITY}`"))
+        .suggestion("install `fsverity-utils`")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        bail!(
+            "`{FSVERITY}` failed with {}: {}",
+            output.status,
+            stderr.trim()
+        );
+    }
+
+    String::from_utf8(output.stdout).context("parse output")
+}
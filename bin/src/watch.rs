@@ -0,0 +1,194 @@
+use circe_lib::{registry::Registry, Authentication, Digest, Reference};
+use clap::Parser;
+use color_eyre::eyre::{bail, Context, Result};
+use derive_more::Debug;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+use tracing::{debug, info, warn};
+
+use crate::{config, extract::Timeouts};
+
+#[derive(Debug, Parser)]
+pub struct Options {
+    /// Image references to watch (e.g. docker.io/library/ubuntu:latest)
+    ///
+    /// References are resolved the same way as they are for `extract`.
+    /// Only remote registries are supported, since the point of this command
+    /// is to detect when a tag has been pushed to.
+    #[arg(required = true)]
+    images: Vec<String>,
+
+    /// File recording the last observed digest for each image
+    ///
+    /// The file is created if it does not exist, and is updated after every check.
+    /// Images that have never been observed are always reported as changed.
+    #[arg(long, default_value = "circe-watch.json")]
+    state: PathBuf,
+
+    /// Check the images repeatedly, waiting this long between checks (e.g. `30s`, `15m`, `1h`)
+    ///
+    /// If not provided, the images are checked once.
+    #[arg(long, value_parser = humantime::parse_duration, conflicts_with = "once")]
+    interval: Option<Duration>,
+
+    /// Check the images once and exit
+    ///
+    /// This is the default behavior if `--interval` is not provided.
+    #[arg(long)]
+    once: bool,
+
+    /// The username to use for authenticating to the registry
+    #[arg(long, requires = "password")]
+    username: Option<String>,
+
+    /// The password to use for authenticating to the registry
+    #[arg(long, requires = "username")]
+    #[debug(skip)]
+    password: Option<String>,
+
+    #[clap(flatten)]
+    timeouts: Timeouts,
+}
+
+/// The last observed digest for each image, keyed by fully qualified reference.
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct State(BTreeMap<String, Digest>);
+
+impl State {
+    async fn read(path: &Path) -> Result<Self> {
+        if !tokio::fs::try_exists(path).await.unwrap_or_default() {
+            debug!(?path, "no state file, starting fresh");
+            return Ok(Self::default());
+        }
+
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .context("read state file")?;
+        serde_json::from_str(&content).context("parse state file")
+    }
+
+    async fn write(&self, path: &Path) -> Result<()> {
+        let content = serde_json::to_string_pretty(self).context("render state")?;
+        tokio::fs::write(path, content)
+            .await
+            .context("write state file")
+    }
+}
+
+/// Reported for each image whose digest differs from the last observed digest.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct Change {
+    /// The fully qualified reference for the image.
+    reference: String,
+
+    /// The digest the reference resolves to now.
+    digest: Digest,
+
+    /// The digest the reference resolved to in the last check, if any.
+    previous: Option<Digest>,
+}
+
+#[tracing::instrument]
+pub async fn main(opts: Options) -> Result<()> {
+    let references = opts
+        .images
+        .iter()
+        .map(|image| Reference::from_str(image).with_context(|| format!("parse {image:?}")))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut state = State::read(&opts.state).await?;
+    loop {
+        let changes;
+        (state, changes) = check(&opts, &references, state).await?;
+        state.write(&opts.state).await?;
+        for change in changes {
+            println!(
+                "{}",
+                serde_json::to_string(&change).context("render change")?
+            );
+        }
+
+        match opts.interval {
+            Some(interval) => {
+                debug!(?interval, "waiting for next check");
+                tokio::time::sleep(interval).await;
+            }
+            None => return Ok(()),
+        }
+    }
+}
+
+/// Resolve the current digest of each reference, reporting each image that changed.
+///
+/// Images that fail to resolve are logged and retain their prior state so that a transient
+/// registry failure doesn't cause them to be reported as changed on the next check.
+/// If every image fails to resolve this is reported as an error.
+async fn check(
+    opts: &Options,
+    references: &[Reference],
+    state: State,
+) -> Result<(State, Vec<Change>)> {
+    info!("checking {} images for changes", references.len());
+
+    let mut observed = state.0;
+    let mut changes = Vec::new();
+    let mut failures = 0;
+    for reference in references {
+        let key = reference.to_string();
+        let digest = match resolve(opts, reference).await {
+            Ok(digest) => digest,
+            Err(err) => {
+                warn!(?err, %reference, "unable to resolve digest");
+                failures += 1;
+                continue;
+            }
+        };
+
+        let previous = observed.insert(key.clone(), digest.clone());
+        if previous.as_ref() == Some(&digest) {
+            debug!(%reference, %digest, "unchanged");
+            continue;
+        }
+
+        info!(%reference, %digest, ?previous, "changed");
+        changes.push(Change {
+            reference: key,
+            digest,
+            previous,
+        });
+    }
+
+    if failures == references.len() {
+        bail!("unable to resolve the digest for any image");
+    }
+
+    Ok((State(observed), changes))
+}
+
+async fn resolve(opts: &Options, reference: &Reference) -> Result<Digest> {
+    let auth = match (&opts.username, &opts.password) {
+        (Some(username), Some(password)) => Authentication::basic(username, password),
+        _ => {
+            match config::env_auth(&reference.host).context("read credentials from environment")? {
+                Some(auth) => auth,
+                None => Authentication::docker(reference).await?,
+            }
+        }
+    };
+
+    Registry::builder()
+        .reference(reference.clone())
+        .auth(auth)
+        .timeouts(opts.timeouts.registry())
+        .build()
+        .await
+        .context("configure remote registry")?
+        .head_digest()
+        .await
+        .context("resolve digest")
+}
@@ -0,0 +1,153 @@
+use async_tempfile::TempDir;
+use circe_lib::Platform;
+use circe_test_support::mock::{self, Auth, MockRegistry};
+use color_eyre::Result;
+use simple_test_case::test_case;
+
+use crate::circe;
+
+/// Push an image with a single layer to the mock, returning its reference.
+async fn push(mock: &MockRegistry, name: &str) -> Result<String> {
+    let layer = mock::tarball(&[("etc/os-release", format!("ID={name}\n").as_bytes())]).await?;
+    mock.push_image(
+        &format!("team/{name}"),
+        "1.0",
+        &Platform::linux_amd64(),
+        &[layer],
+    );
+    Ok(mock.reference("team", name, "1.0").to_string())
+}
+
+#[test_case("app", &[], true, true; "same_digest")]
+#[test_case("other", &[], false, true; "other_digest")]
+#[test_case("app", &["--overwrite"], true, false; "overwrite")]
+#[test_case("app", &["--report-only-squashed-listing"], false, true; "listing")]
+#[test_case("app", &["--file-glob", "etc/*"], false, true; "file_filter")]
+#[test_case("app", &["--layers", "separate"], false, true; "layers")]
+#[test_case("app", &["--whiteouts"], false, true; "whiteouts")]
+#[tokio::test]
+async fn skip_extracted(image: &str, args: &[&str], succeeds: bool, skipped: bool) -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let app = push(&mock, "app").await?;
+    let image = push(&mock, image).await?;
+
+    let dir = TempDir::new().await?;
+    let output = dir.dir_path().join("output");
+    let extract = circe([
+        "extract",
+        "--source",
+        "registry",
+        "--report-format",
+        "none",
+        &app,
+    ])
+    .arg(&output)
+    .output()
+    .await?;
+    assert!(extract.status.success(), "{extract:?}");
+
+    // Extracting again would remove anything that wasn't extracted from the image.
+    let marker = output.join("marker");
+    tokio::fs::write(&marker, "").await?;
+    let extract = circe([
+        "extract",
+        "--source",
+        "registry",
+        "--report-format",
+        "none",
+        &image,
+    ])
+    .arg(&output)
+    .args(args)
+    .output()
+    .await?;
+
+    pretty_assertions::assert_eq!(succeeds, extract.status.success(), "{extract:?}");
+    pretty_assertions::assert_eq!(skipped, tokio::fs::try_exists(&marker).await?);
+    if !succeeds {
+        let stderr = String::from_utf8_lossy(&extract.stderr);
+        assert!(
+            stderr.contains("output directory already exists"),
+            "{stderr}"
+        );
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn batch_hook_fails_image() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let images = [push(&mock, "ok").await?, push(&mock, "fail").await?];
+
+    let dir = TempDir::new().await?;
+    let batch = dir.dir_path().join("batch.txt");
+    tokio::fs::write(&batch, images.join("\n")).await?;
+    let report = dir.dir_path().join("report.json");
+    let extract = circe(["extract", "--source", "registry", "--batch"])
+        .arg(&batch)
+        .arg(dir.dir_path().join("output"))
+        .args([
+            "--exec",
+            "case {} in *fail*) exit 3;; esac",
+            "--batch-report",
+        ])
+        .arg(&report)
+        .output()
+        .await?;
+
+    // A failing hook fails its image, not the whole batch, so circe doesn't exit with the hook's code.
+    pretty_assertions::assert_eq!(Some(1), extract.status.code(), "{extract:?}");
+    let report =
+        serde_json::from_str::<serde_json::Value>(&tokio::fs::read_to_string(&report).await?)?;
+    let errors = report["targets"]
+        .as_array()
+        .expect("targets")
+        .iter()
+        .map(|target| target["error"].as_str().map(String::from))
+        .collect::<Vec<_>>();
+    pretty_assertions::assert_eq!(
+        vec![
+            None,
+            Some(String::from(
+                "hook `case {} in *fail*) exit 3;; esac` failed: exit status: 3"
+            ))
+        ],
+        errors
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn hook_exit_code() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let image = push(&mock, "app").await?;
+
+    let dir = TempDir::new().await?;
+    let output = dir.dir_path().join("output");
+    let extract = circe([
+        "extract",
+        "--source",
+        "registry",
+        "--error-format",
+        "json",
+        &image,
+    ])
+    .arg(&output)
+    .args(["--exec", "exit 3"])
+    .output()
+    .await?;
+
+    pretty_assertions::assert_eq!(Some(3), extract.status.code(), "{extract:?}");
+    // Logs are also written to stderr, before the error.
+    let stderr = String::from_utf8(extract.stderr)?;
+    let error =
+        serde_json::from_str::<serde_json::Value>(stderr.lines().last().unwrap_or_default())?;
+    pretty_assertions::assert_eq!(
+        (Some("hook"), Some(3)),
+        (error["kind"].as_str(), error["exit_code"].as_i64())
+    );
+
+    // The image was extracted before the hook ran, so it's kept.
+    assert!(tokio::fs::try_exists(output.join("image.json")).await?);
+    Ok(())
+}
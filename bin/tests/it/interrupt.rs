@@ -0,0 +1,50 @@
+use std::{process::Stdio, time::Duration};
+
+use async_tempfile::TempDir;
+use circe_lib::Platform;
+use circe_test_support::mock::{self, Auth, MockRegistry};
+use color_eyre::{eyre::ensure, Result};
+
+use crate::circe;
+
+#[tokio::test]
+async fn remove_incomplete_output() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let layer = mock::tarball(&[("etc/os-release", b"ID=mock\n")]).await?;
+    let digest = mock::digest(&layer);
+    mock.push_image("team/app", "1.0", &Platform::linux_amd64(), &[layer]);
+    mock.stall(format!("/v2/team/app/blobs/{digest}"));
+
+    let dir = TempDir::new().await?;
+    let output = dir.dir_path().join("output");
+    let image = mock.reference("team", "app", "1.0").to_string();
+    let mut extract = circe(["extract", "--source", "registry", &image])
+        .arg(&output)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    // The output directory is created once the image is resolved, before its layers are downloaded.
+    for _ in 0..100 {
+        if tokio::fs::try_exists(&output).await? {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    ensure!(
+        tokio::fs::try_exists(&output).await?,
+        "output directory wasn't created"
+    );
+
+    let pid = extract.id().expect("circe is running").to_string();
+    let kill = tokio::process::Command::new("kill")
+        .args(["-INT", &pid])
+        .status()
+        .await?;
+    assert!(kill.success());
+
+    let status = extract.wait().await?;
+    pretty_assertions::assert_eq!(Some(130), status.code());
+    assert!(!tokio::fs::try_exists(&output).await?);
+    Ok(())
+}
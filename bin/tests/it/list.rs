@@ -0,0 +1,48 @@
+use async_tempfile::TempDir;
+use circe_lib::Platform;
+use circe_test_support::mock::{self, Auth, MockRegistry};
+use color_eyre::Result;
+use simple_test_case::test_case;
+
+use crate::circe;
+
+#[test_case("json", "{\n  \"{layer}\": [\n    \"etc/os-release\",\n    \"bin/b, c\"\n  ]\n}\n"; "json")]
+#[test_case("ndjson", "{\"layer\":\"{layer}\",\"path\":\"etc/os-release\"}\n{\"layer\":\"{layer}\",\"path\":\"bin/b, c\"}\n"; "ndjson")]
+#[test_case("csv", "layer,path\n{layer},etc/os-release\n{layer},\"bin/b, c\"\n"; "csv")]
+#[test_case("text", "{layer}  etc/os-release\n{layer}  bin/b, c\n"; "text")]
+#[tokio::test]
+async fn output_file(format: &str, expected: &str) -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let layer = mock::tarball(&[("etc/os-release", b"ID=mock\n"), ("bin/b, c", b"")]).await?;
+    let digest = mock::digest(&layer);
+    mock.push_image("team/app", "1.0", &Platform::linux_amd64(), &[layer]);
+
+    let dir = TempDir::new().await?;
+    let path = dir.dir_path().join("output");
+    let image = mock.reference("team", "app", "1.0").to_string();
+    let output = circe([
+        "list", "--source", "registry", "--format", format, &image, "--output",
+    ])
+    .arg(&path)
+    .output()
+    .await?;
+    assert!(output.status.success(), "{output:?}");
+
+    let written = tokio::fs::read_to_string(&path).await?;
+    pretty_assertions::assert_eq!(expected.replace("{layer}", &digest.to_string()), written);
+    Ok(())
+}
+
+#[test_case(&[], true; "auto")]
+#[test_case(&["--source", "registry"], false; "registry")]
+#[tokio::test]
+async fn suggests_source(args: &[&str], suggested: bool) -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let image = mock.reference("team", "missing", "1.0").to_string();
+    let output = circe(["list"]).args(args).arg(&image).output().await?;
+    assert!(!output.status.success(), "{output:?}");
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    pretty_assertions::assert_eq!(suggested, stderr.contains("Use `--source`"), "{stderr}");
+    Ok(())
+}
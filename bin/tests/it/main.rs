@@ -0,0 +1,27 @@
+//! Tests of the `circe` binary, run as a separate process the way users run it.
+
+use std::{ffi::OsStr, process::Stdio};
+
+use tokio::process::Command;
+
+mod extract;
+#[cfg(unix)]
+mod interrupt;
+mod list;
+#[cfg(all(target_os = "linux", feature = "fuse"))]
+mod mount;
+mod reexport;
+#[cfg(all(target_os = "linux", feature = "sandbox"))]
+mod sandbox;
+mod watch;
+
+/// A command that runs the `circe` binary built for these tests with the arguments.
+pub fn circe<I, S>(args: I) -> Command
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let mut command = Command::new(env!("CARGO_BIN_EXE_circe"));
+    command.args(args).stdin(Stdio::null());
+    command
+}
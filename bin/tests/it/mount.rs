@@ -0,0 +1,77 @@
+use std::{process::Stdio, time::Duration};
+
+use async_tempfile::TempDir;
+use circe_lib::Platform;
+use circe_test_support::mock::{self, Auth, MockRegistry};
+use color_eyre::{eyre::ensure, Result};
+
+use crate::circe;
+
+#[tokio::test]
+async fn serves_squashed_image() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let base =
+        mock::tarball(&[("etc/os-release", b"ID=mock\n"), ("etc/motd", b"welcome\n")]).await?;
+    let app = mock::tarball(&[
+        ("etc/.wh.motd", b""),
+        ("app/bin/run", b"#!/bin/sh\necho run\n"),
+    ])
+    .await?;
+    mock.push_image("team/app", "1.0", &Platform::linux_amd64(), &[base, app]);
+
+    let dir = TempDir::new().await?;
+    let mountpoint = dir.dir_path().join("mnt");
+    tokio::fs::create_dir_all(&mountpoint).await?;
+    let image = mock.reference("team", "app", "1.0").to_string();
+    let mut mount = circe(["mount", "--source", "registry", &image])
+        .arg(&mountpoint)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    let release = mountpoint.join("etc/os-release");
+    for _ in 0..100 {
+        if tokio::fs::try_exists(&release).await? {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    ensure!(
+        tokio::fs::try_exists(&release).await?,
+        "image wasn't mounted"
+    );
+
+    pretty_assertions::assert_eq!("ID=mock\n", tokio::fs::read_to_string(&release).await?);
+    pretty_assertions::assert_eq!(
+        "#!/bin/sh\necho run\n",
+        tokio::fs::read_to_string(mountpoint.join("app/bin/run")).await?
+    );
+    assert!(
+        !tokio::fs::try_exists(mountpoint.join("etc/motd")).await?,
+        "whiteout hides the file"
+    );
+
+    let mut names = Vec::new();
+    let mut entries = tokio::fs::read_dir(&mountpoint).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        names.push(entry.file_name().to_string_lossy().to_string());
+    }
+    drop(entries);
+    names.sort();
+    pretty_assertions::assert_eq!(vec!["app", "etc"], names);
+
+    let pid = mount.id().expect("circe is running").to_string();
+    let kill = tokio::process::Command::new("kill")
+        .args(["-INT", &pid])
+        .status()
+        .await?;
+    assert!(kill.success());
+
+    let status = mount.wait().await?;
+    assert!(status.success(), "{status:?}");
+    assert!(
+        !tokio::fs::try_exists(&release).await?,
+        "image is unmounted"
+    );
+    Ok(())
+}
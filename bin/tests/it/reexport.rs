@@ -0,0 +1,44 @@
+use async_tempfile::TempDir;
+use circe_lib::Platform;
+use circe_test_support::mock::{self, Auth, MockRegistry};
+use color_eyre::Result;
+use simple_test_case::test_case;
+
+use crate::circe;
+
+#[tokio::test]
+async fn detects_source() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let layer = mock::tarball(&[("etc/os-release", b"ID=mock\n")]).await?;
+    mock.push_image("team/app", "1.0", &Platform::linux_amd64(), &[layer]);
+
+    let dir = TempDir::new().await?;
+    let output = dir.dir_path().join("image.tar");
+    let image = mock.reference("team", "app", "1.0").to_string();
+    let reexport = circe(["reexport", &image]).arg(&output).output().await?;
+    assert!(reexport.status.success(), "{reexport:?}");
+    assert!(tokio::fs::try_exists(&output).await?);
+    Ok(())
+}
+
+#[test_case(&[], "not_found"; "auto")]
+#[test_case(&["--source", "registry"], "not_found"; "registry")]
+#[tokio::test]
+async fn classifies_failure(args: &[&str], kind: &str) -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let dir = TempDir::new().await?;
+    let image = mock.reference("team", "missing", "1.0").to_string();
+    let reexport = circe(["reexport", "--error-format", "json"])
+        .args(args)
+        .arg(&image)
+        .arg(dir.dir_path().join("image.tar"))
+        .output()
+        .await?;
+
+    // Logs are also written to stderr, before the error.
+    let stderr = String::from_utf8(reexport.stderr)?;
+    let error =
+        serde_json::from_str::<serde_json::Value>(stderr.lines().last().unwrap_or_default())?;
+    pretty_assertions::assert_eq!(Some(kind), error["kind"].as_str(), "{stderr}");
+    Ok(())
+}
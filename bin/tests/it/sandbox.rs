@@ -0,0 +1,48 @@
+use async_tempfile::TempDir;
+use circe_lib::Platform;
+use circe_test_support::mock::{self, Auth, MockRegistry};
+use color_eyre::Result;
+
+use crate::circe;
+
+#[tokio::test]
+async fn restricts_writes() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let layer = mock::tarball(&[("etc/os-release", b"ID=mock\n")]).await?;
+    mock.push_image("team/app", "1.0", &Platform::linux_amd64(), &[layer]);
+
+    // The temporary directory is writable, so circe is given its own to keep the rest of this one out of reach.
+    let dir = TempDir::new().await?;
+    let temp = dir.dir_path().join("temp");
+    let outside = dir.dir_path().join("outside");
+    tokio::fs::create_dir_all(&temp).await?;
+    tokio::fs::create_dir_all(&outside).await?;
+    let (inside, outside) = (temp.join("file"), outside.join("file"));
+
+    // Filter commands are run within the sandbox; the layer is passed through unchanged.
+    let filter = format!(
+        "touch '{}'; touch '{}'; cat",
+        inside.display(),
+        outside.display()
+    );
+    let output = dir.dir_path().join("output");
+    let image = mock.reference("team", "app", "1.0").to_string();
+    let extract = circe(["extract", "--source", "registry", "--sandbox", &image])
+        .arg(&output)
+        .args(["--filter-cmd", &filter])
+        .env("TMPDIR", &temp)
+        .output()
+        .await?;
+
+    assert!(extract.status.success(), "{extract:?}");
+    assert!(tokio::fs::try_exists(output.join("image.json")).await?);
+    assert!(
+        tokio::fs::try_exists(&inside).await?,
+        "write inside is allowed"
+    );
+    assert!(
+        !tokio::fs::try_exists(&outside).await?,
+        "write outside is denied"
+    );
+    Ok(())
+}
@@ -0,0 +1,95 @@
+use std::path::Path;
+
+use async_tempfile::TempDir;
+use circe_lib::{Platform, Reference};
+use circe_test_support::mock::{self, Auth, MockRegistry};
+use color_eyre::Result;
+use serde_json::{json, Value};
+
+use crate::circe;
+
+/// Check the references once with `circe watch`, returning the changes it printed
+/// and whether it succeeded.
+async fn watch(references: &[&Reference], state: &Path) -> Result<(Vec<Value>, bool)> {
+    let output = circe(["watch", "--once", "--state"])
+        .arg(state)
+        .args(references.iter().map(ToString::to_string))
+        .output()
+        .await?;
+    let changes = String::from_utf8(output.stdout)?
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<Vec<Value>, _>>()?;
+    Ok((changes, output.status.success()))
+}
+
+#[tokio::test]
+async fn classify_changes() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let layer = mock::tarball(&[("etc/os-release", b"ID=mock\n")]).await?;
+    let first = mock.push_image("team/app", "1.0", &Platform::linux_amd64(), &[layer]);
+    let stable = mock::tarball(&[("etc/os-release", b"ID=stable\n")]).await?;
+    let base = mock.push_image("team/base", "1.0", &Platform::linux_amd64(), &[stable]);
+
+    let app = mock.reference("team", "app", "1.0");
+    let other = mock.reference("team", "base", "1.0");
+    let dir = TempDir::new().await?;
+    let state = dir.dir_path().join("state.json");
+    let references = [&app, &other];
+
+    // Images that were never observed are changed.
+    let (changes, succeeded) = watch(&references, &state).await?;
+    assert!(succeeded);
+    pretty_assertions::assert_eq!(
+        vec![
+            json!({ "reference": app.to_string(), "digest": first.to_string(), "previous": null }),
+            json!({ "reference": other.to_string(), "digest": base.to_string(), "previous": null }),
+        ],
+        changes
+    );
+
+    // Nothing changes until the tag is pushed to.
+    let (changes, _) = watch(&references, &state).await?;
+    pretty_assertions::assert_eq!(Vec::<Value>::new(), changes);
+
+    let layer = mock::tarball(&[("etc/os-release", b"ID=updated\n")]).await?;
+    let second = mock.push_image("team/app", "1.0", &Platform::linux_amd64(), &[layer]);
+    let (changes, _) = watch(&references, &state).await?;
+    pretty_assertions::assert_eq!(
+        vec![json!({
+            "reference": app.to_string(),
+            "digest": second.to_string(),
+            "previous": first.to_string(),
+        })],
+        changes
+    );
+
+    // Images that fail to resolve keep their prior state, even if they were pushed to.
+    let layer = mock::tarball(&[("etc/os-release", b"ID=pushed\n")]).await?;
+    mock.push_image("team/base", "1.0", &Platform::linux_amd64(), &[layer]);
+    // The digest is requested with `HEAD`, falling back to `GET`.
+    for _ in 0..2 {
+        mock.fail("/v2/team/base/", "500 Internal Server Error");
+    }
+    let (changes, succeeded) = watch(&references, &state).await?;
+    assert!(succeeded);
+    pretty_assertions::assert_eq!(Vec::<Value>::new(), changes);
+    pretty_assertions::assert_eq!(
+        json!({ app.to_string(): second.to_string(), other.to_string(): base.to_string() }),
+        serde_json::from_str::<Value>(&tokio::fs::read_to_string(&state).await?)?
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn fails_if_nothing_resolves() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let missing = mock.reference("team", "missing", "1.0");
+    let dir = TempDir::new().await?;
+
+    let (changes, succeeded) = watch(&[&missing], &dir.dir_path().join("state.json")).await?;
+    assert!(!succeeded);
+    pretty_assertions::assert_eq!(Vec::<Value>::new(), changes);
+    Ok(())
+}
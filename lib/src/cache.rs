@@ -0,0 +1,464 @@
+//! Stores images from registries on disk, so that they can be read later without network access.
+//!
+//! Each image is stored as a tarball of an [OCI image layout](https://github.com/opencontainers/image-spec/blob/main/image-layout.md)
+//! containing the manifest, config, and layers for a single platform exactly as the registry distributes them,
+//! so that it's read by [`crate::docker::Tarball`] like any other tarball.
+//!
+//! Images are keyed by the reference used to store them and the platform requested for them;
+//! images stored by tag aren't refreshed when the tag is pushed to, so store them again to update them.
+
+use std::{
+    collections::HashMap,
+    io::SeekFrom,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+    sync::Arc,
+};
+
+use bytes::Bytes;
+use color_eyre::eyre::{self, eyre, Context, OptionExt};
+use futures_lite::{Stream, StreamExt};
+use oci_client::manifest::{OciDescriptor, OciImageIndex};
+use serde::Serialize;
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, Take},
+    sync::Semaphore,
+    task::JoinSet,
+};
+use tokio_util::io::ReaderStream;
+use tracing::{debug, info, warn};
+
+use crate::{
+    cio::{collect_tmp, walk_files},
+    error::Kind,
+    registry::Registry,
+    Digest, Error, Platform, Reference, Result, Version,
+};
+
+/// The name of the platform directory for images stored without requesting a platform.
+const DEFAULT_PLATFORM: &str = "default";
+
+/// The annotation in `index.json` that records the reference used to store the image.
+const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+
+/// A directory of images stored by [`Cache::store`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cache {
+    dir: PathBuf,
+}
+
+/// An image stored in a [`Cache`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Stored {
+    /// The reference used to store the image.
+    pub reference: String,
+
+    /// The digest of the image manifest.
+    pub digest: Digest,
+
+    /// The path of the tarball in which the image is stored.
+    pub path: PathBuf,
+
+    /// The number of layers stored.
+    pub layers: usize,
+}
+
+/// An image stored in a [`Cache`], indexed so that its manifest and blobs can be read
+/// from the tarball exactly as the registry distributed them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    /// The reference used to store the image.
+    pub reference: Reference,
+
+    /// The digest of the image manifest.
+    pub digest: Digest,
+
+    /// The media type of the image manifest.
+    pub media_type: String,
+
+    /// The path of the tarball in which the image is stored.
+    pub path: PathBuf,
+
+    /// Where each blob is in the tarball, by digest.
+    blobs: HashMap<String, Blob>,
+}
+
+/// The location of a blob's content in a tarball.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Blob {
+    position: u64,
+    size: u64,
+}
+
+impl Cache {
+    /// Use the directory as a cache; it's created when an image is first stored.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// The directory in which images are stored.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The path at which the image for the reference and platform is stored, whether or not it exists.
+    ///
+    /// The path is `{host}/{namespace}/{name}/{version}/{platform}.tar` inside the cache directory,
+    /// with characters that aren't safe in file names replaced.
+    pub fn path(&self, reference: &Reference, platform: Option<&Platform>) -> PathBuf {
+        let version = match &reference.version {
+            Version::Tag(tag) => tag.clone(),
+            Version::Digest(digest) => digest.to_string(),
+        };
+        let platform = platform
+            .map(Platform::to_string)
+            .unwrap_or_else(|| String::from(DEFAULT_PLATFORM));
+
+        let mut path = self.dir.join(sanitize(&reference.host));
+        let repository = reference
+            .namespace
+            .split('/')
+            .chain(reference.name.split('/'));
+        for segment in repository.filter(|segment| !segment.is_empty()) {
+            path.push(sanitize(segment));
+        }
+        path.push(sanitize(&version));
+        path.push(format!("{}.tar", sanitize(&platform)));
+        path
+    }
+
+    /// The path of the stored image for the reference and platform, if it has been stored.
+    pub async fn lookup(
+        &self,
+        reference: &Reference,
+        platform: Option<&Platform>,
+    ) -> Option<PathBuf> {
+        let path = self.path(reference, platform);
+        match tokio::fs::try_exists(&path).await {
+            Ok(true) => Some(path),
+            _ => None,
+        }
+    }
+
+    /// Every image stored in the cache, ordered by the path of its tarball.
+    ///
+    /// Tarballs that can't be read as stored images are skipped with a warning.
+    pub async fn layouts(&self) -> Result<Vec<Layout>> {
+        if !tokio::fs::try_exists(&self.dir).await.unwrap_or_default() {
+            return Ok(Vec::new());
+        }
+
+        let mut layouts = Vec::new();
+        let files = walk_files(&self.dir).await?;
+        for path in files
+            .into_iter()
+            .filter(|path| path.extension().is_some_and(|ext| ext == "tar"))
+        {
+            match Layout::open(&path).await {
+                Ok(layout) => layouts.push(layout),
+                Err(err) => warn!(?path, ?err, "skip: unable to read stored image"),
+            }
+        }
+        Ok(layouts)
+    }
+
+    /// Download the image from the registry and store it, replacing it if it's already stored.
+    ///
+    /// The platform must be the one the registry was built with, since it selects where the image is stored.
+    /// Up to `concurrency` layers are downloaded at once; the tarball is only written once all of them
+    /// are downloaded, so an interrupted download doesn't leave a partial image in the cache.
+    #[tracing::instrument(skip(registry))]
+    pub async fn store(
+        &self,
+        registry: &Registry,
+        platform: Option<&Platform>,
+        concurrency: NonZeroUsize,
+    ) -> Result<Stored> {
+        let reference = &registry.original;
+        let (manifest, digest) = registry.pull_image_manifest().await?;
+        let raw_manifest = registry.pull_manifest_raw(&digest).await?;
+        let config = registry
+            .pull_config(&manifest)
+            .await
+            .context("pull config")?;
+
+        let layers = manifest
+            .layers
+            .iter()
+            .filter(|layer| {
+                let foreign = layer.urls.as_ref().is_some_and(|urls| !urls.is_empty());
+                if foreign {
+                    debug!(digest = %layer.digest, "skip foreign layer");
+                }
+                !foreign
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        let downloaded = download(registry, &layers, concurrency).await?;
+
+        let path = self.path(reference, platform);
+        let parent = path.parent().ok_or_eyre("cache path has no parent")?;
+        tokio::fs::create_dir_all(parent)
+            .await
+            .with_context(|| format!("create cache directory {parent:?}"))?;
+
+        // Written next to the destination and renamed so that readers never see a partial tarball.
+        let partial = path.with_extension("tar.partial");
+        let file = File::create(&partial)
+            .await
+            .with_context(|| format!("create {partial:?}"))?;
+        let mut builder = tokio_tar::Builder::new(file);
+
+        let layout = serde_json::json!({ "imageLayoutVersion": "1.0.0" });
+        append(&mut builder, "oci-layout", layout.to_string().as_bytes()).await?;
+        let index = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": oci_client::manifest::OCI_IMAGE_INDEX_MEDIA_TYPE,
+            "manifests": [{
+                "mediaType": manifest.media_type.as_deref().unwrap_or(oci_client::manifest::OCI_IMAGE_MEDIA_TYPE),
+                "digest": digest,
+                "size": raw_manifest.len(),
+                "annotations": { REF_NAME_ANNOTATION: reference.to_string() },
+            }],
+        });
+        append(&mut builder, "index.json", index.to_string().as_bytes()).await?;
+        append(&mut builder, &blob_path(&digest)?, &raw_manifest).await?;
+        append(&mut builder, &blob_path(&manifest.config.digest)?, &config).await?;
+        for (layer, downloaded) in layers.iter().zip(downloaded) {
+            let content = File::open(downloaded.file_path())
+                .await
+                .context("open downloaded layer")?;
+            let mut header = tokio_tar::Header::new_gnu();
+            header.set_size(content.metadata().await.context("read layer size")?.len());
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, blob_path(&layer.digest)?, content)
+                .await
+                .context("write layer")?;
+        }
+        builder
+            .into_inner()
+            .await
+            .context("finish tarball")?
+            .flush()
+            .await
+            .context("flush tarball")?;
+
+        tokio::fs::rename(&partial, &path)
+            .await
+            .with_context(|| format!("move {partial:?} to {path:?}"))?;
+        info!(%reference, %digest, ?path, "stored image");
+        Ok(Stored {
+            reference: reference.to_string(),
+            digest: Digest::from_str(&digest).context("parse digest")?,
+            path,
+            layers: layers.len(),
+        })
+    }
+}
+
+impl Layout {
+    /// Index the image stored in the tarball at the path by [`Cache::store`].
+    pub async fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let file = File::open(&path)
+            .await
+            .with_context(|| format!("open {path:?}"))?;
+        let mut archive = tokio_tar::Archive::new(file);
+        let mut entries = archive.entries().context("read entries from tar")?;
+
+        let mut index = None;
+        let mut blobs = HashMap::new();
+        while let Some(entry) = entries.next().await {
+            let mut entry = entry.context("read entry")?;
+            let name = entry
+                .path()
+                .context("read entry path")?
+                .to_string_lossy()
+                .to_string();
+            if name == "index.json" {
+                let mut content = Vec::new();
+                entry
+                    .read_to_end(&mut content)
+                    .await
+                    .context("read index.json")?;
+                let parsed = serde_json::from_slice::<OciImageIndex>(&content)
+                    .context("parse index.json")?;
+                index = Some(parsed);
+            } else if let Some((algorithm, hex)) = name
+                .strip_prefix("blobs/")
+                .and_then(|blob| blob.split_once('/'))
+            {
+                let size = entry.header().entry_size().context("read blob size")?;
+                let position = entry.raw_file_position();
+                blobs.insert(format!("{algorithm}:{hex}"), Blob { position, size });
+            }
+        }
+
+        let index = index.ok_or_eyre("tarball has no index.json")?;
+        let manifest = match index.manifests.as_slice() {
+            [manifest] => manifest,
+            manifests => {
+                return Err(eyre!(
+                    "index.json lists {} manifests, expected 1",
+                    manifests.len()
+                ))
+                .map_err(Error::from)
+            }
+        };
+        let reference = manifest
+            .annotations
+            .as_ref()
+            .and_then(|annotations| annotations.get(REF_NAME_ANNOTATION))
+            .ok_or_eyre("index.json doesn't record the reference used to store the image")?;
+        let reference = Reference::from_str(reference).context("parse stored reference")?;
+        let digest = Digest::from_str(&manifest.digest).context("parse manifest digest")?;
+        if !blobs.contains_key(&manifest.digest) {
+            return Err(eyre!("manifest {digest} isn't stored in the tarball"))
+                .map_err(Error::from);
+        }
+
+        debug!(?path, %reference, %digest, blobs = blobs.len(), "indexed stored image");
+        Ok(Self {
+            reference,
+            digest,
+            media_type: manifest.media_type.clone(),
+            path,
+            blobs,
+        })
+    }
+
+    /// The size of the blob with the digest, if it's stored.
+    pub fn blob_size(&self, digest: &Digest) -> Option<u64> {
+        self.blobs.get(&digest.to_string()).map(|blob| blob.size)
+    }
+
+    /// Read the image manifest as it's stored.
+    pub async fn manifest(&self) -> Result<Bytes> {
+        let mut content = Vec::new();
+        self.reader(&self.digest)
+            .await?
+            .read_to_end(&mut content)
+            .await
+            .context("read manifest")?;
+        Ok(Bytes::from(content))
+    }
+
+    /// Stream the content of the blob with the digest as it's stored.
+    pub async fn blob(
+        &self,
+        digest: &Digest,
+    ) -> Result<Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>> {
+        let reader = self.reader(digest).await?;
+        let stream =
+            ReaderStream::new(reader).map(|chunk| chunk.context("read blob").map_err(Error::from));
+        Ok(Box::pin(stream))
+    }
+
+    /// Open the content of the blob with the digest in the tarball.
+    async fn reader(&self, digest: &Digest) -> Result<Take<File>> {
+        let Some(blob) = self.blobs.get(&digest.to_string()) else {
+            return Err(eyre::Report::new(Kind::NotFound))
+                .context(format!("blob {digest} isn't stored in {:?}", self.path))
+                .map_err(Error::from);
+        };
+
+        let mut file = File::open(&self.path)
+            .await
+            .with_context(|| format!("open {:?}", self.path))?;
+        file.seek(SeekFrom::Start(blob.position))
+            .await
+            .context("seek to blob")?;
+        Ok(file.take(blob.size))
+    }
+}
+
+/// Download the layers to temporary files, up to `concurrency` at once, in the order they're listed.
+async fn download(
+    registry: &Registry,
+    layers: &[OciDescriptor],
+    concurrency: NonZeroUsize,
+) -> Result<Vec<async_tempfile::TempFile>> {
+    let permits = Arc::new(Semaphore::new(concurrency.get()));
+    let mut tasks = JoinSet::new();
+    for (index, layer) in layers.iter().enumerate() {
+        let registry = registry.clone();
+        let permits = permits.clone();
+        let digest = Digest::from_str(&layer.digest).context("parse layer digest")?;
+        tasks.spawn(async move {
+            let _permit = permits.acquire_owned().await.context("wait to download")?;
+            debug!(%digest, "download layer");
+            let blob = registry.pull_blob(&digest).await?;
+            let file = collect_tmp(Box::pin(blob))
+                .await
+                .with_context(|| format!("download layer {digest}"))?;
+            eyre::Ok((index, file))
+        });
+    }
+
+    let mut downloaded = Vec::with_capacity(layers.len());
+    while let Some(result) = tasks.join_next().await {
+        downloaded.push(result.context("join download")??);
+    }
+    downloaded.sort_by_key(|(index, _)| *index);
+    Ok(downloaded.into_iter().map(|(_, file)| file).collect())
+}
+
+/// Append the content to the tarball at the path.
+async fn append(
+    builder: &mut tokio_tar::Builder<File>,
+    path: &str,
+    content: &[u8],
+) -> eyre::Result<()> {
+    let mut header = tokio_tar::Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder
+        .append_data(&mut header, path, content)
+        .await
+        .with_context(|| format!("write {path}"))
+}
+
+/// The path of the blob with the digest in the OCI layout.
+fn blob_path(digest: &str) -> Result<String> {
+    let digest = Digest::from_str(digest)
+        .context("parse digest")
+        .map_err(Error::from)?;
+    Ok(format!("blobs/{}/{}", digest.algorithm, digest.as_hex()))
+}
+
+/// Replace the characters that aren't safe in file names on every platform.
+fn sanitize(segment: &str) -> String {
+    segment
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '_' | '-' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    #[test_case("docker.io/library/alpine:3.20", None, "docker.io/library/alpine/3.20/default.tar"; "tag")]
+    #[test_case("localhost:5000/app:latest", Some("linux/arm64/v8"), "localhost_5000/app/latest/linux_arm64_v8.tar"; "port_and_platform")]
+    #[test_case("ghcr.io/org/team/app@sha256:2dbf67cffe2b7bce89eeee6a34ad3d800e9b3bba16a4fdd7c349d6c5d12ccebf", None, "ghcr.io/org/team/app/sha256_2dbf67cffe2b7bce89eeee6a34ad3d800e9b3bba16a4fdd7c349d6c5d12ccebf/default.tar"; "digest")]
+    #[test]
+    fn cache_path(reference: &str, platform: Option<&str>, expected: &str) {
+        let cache = Cache::new("/cache");
+        let reference = Reference::from_str(reference).expect("parse reference");
+        let platform = platform.map(|p| Platform::from_str(p).expect("parse platform"));
+        pretty_assertions::assert_eq!(
+            Path::new("/cache").join(expected),
+            cache.path(&reference, platform.as_ref())
+        );
+    }
+}
@@ -0,0 +1,109 @@
+//! Describes what this build of the library supports.
+//!
+//! Which sources and authentication methods are available depends on the features
+//! the library is compiled with; callers that shell out to different builds
+//! can use this to adapt to the build they have.
+
+use serde::Serialize;
+use strum::IntoEnumIterator;
+
+use crate::{DigestAlgorithm, LayerMediaType, LayerMediaTypeFlag};
+
+/// Layer media types that are read by translating them to a [`LayerMediaType`].
+///
+/// Reference: https://github.com/opencontainers/image-spec/blob/main/media-types.md#compatibility-matrix
+const COMPATIBLE_MEDIA_TYPES: &[&str] = &[
+    "application/vnd.docker.image.rootfs.diff.tar.gzip",
+    "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip",
+    "application/vnd.oci.image.layer.nondistributable.v1.tar",
+    "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip",
+    "application/vnd.oci.image.layer.nondistributable.v1.tar+zstd",
+];
+
+/// The capabilities of this build of the library.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct Capabilities {
+    /// Sources from which images can be read, in the order [`crate::source::detect`] tries them.
+    pub sources: Vec<&'static str>,
+
+    /// Layer media types that can be read.
+    pub media_types: Vec<String>,
+
+    /// Compression codecs with which layers can be decompressed.
+    pub compression: Vec<String>,
+
+    /// Algorithms with which digests are parsed and content is verified.
+    pub digest_algorithms: Vec<String>,
+
+    /// Methods with which to authenticate to registries.
+    pub auth: Vec<&'static str>,
+
+    /// Features with which the library is compiled.
+    pub features: Vec<&'static str>,
+}
+
+impl Capabilities {
+    /// Report the capabilities of this build.
+    pub fn current() -> Self {
+        let compression = LayerMediaTypeFlag::iter()
+            .filter(|flag| flag.is_compression())
+            .collect::<Vec<_>>();
+
+        let mut media_types = Vec::new();
+        for media_type in LayerMediaType::iter() {
+            media_types.push(media_type.to_string());
+            media_types.extend(
+                compression
+                    .iter()
+                    .map(|&flag| media_type.clone().replace_flags(vec![flag]).to_string()),
+            );
+        }
+        media_types.extend(COMPATIBLE_MEDIA_TYPES.iter().map(ToString::to_string));
+
+        let sources = [
+            (cfg!(feature = "native"), "tarball"),
+            (cfg!(feature = "daemon"), "daemon"),
+            (true, "registry"),
+        ];
+        let auth = [
+            (true, "none"),
+            (true, "basic"),
+            (true, "bearer"),
+            (cfg!(feature = "docker-auth"), "docker-config"),
+        ];
+        let features = [
+            (cfg!(feature = "native"), "native"),
+            (cfg!(feature = "daemon"), "daemon"),
+            (cfg!(feature = "docker-auth"), "docker-auth"),
+        ];
+
+        Self {
+            sources: enabled(sources),
+            media_types,
+            compression: compression.iter().map(ToString::to_string).collect(),
+            digest_algorithms: DigestAlgorithm::iter()
+                .map(|algorithm| algorithm.to_string())
+                .collect(),
+            auth: enabled(auth),
+            features: enabled(features),
+        }
+    }
+}
+
+fn enabled<const N: usize>(items: [(bool, &'static str); N]) -> Vec<&'static str> {
+    items
+        .into_iter()
+        .filter_map(|(enabled, item)| enabled.then_some(item))
+        .collect()
+}
+
+impl LayerMediaTypeFlag {
+    /// Report whether the flag indicates that the layer is compressed.
+    fn is_compression(&self) -> bool {
+        match self {
+            LayerMediaTypeFlag::Foreign => false,
+            LayerMediaTypeFlag::Zstd | LayerMediaTypeFlag::Gzip => true,
+        }
+    }
+}
@@ -1,28 +1,47 @@
 //! Container file system operations.
 
 use std::{
-    path::{Path, PathBuf},
+    collections::{HashMap, HashSet},
+    fs::FileTimes,
+    future::Future,
+    io::{self, SeekFrom},
+    path::{Component, Path, PathBuf},
     pin::Pin,
+    process::Stdio,
+    sync::{Arc, RwLock},
+    task::{ready, Context as TaskContext, Poll},
+    time::{Duration, SystemTime},
 };
 
 use async_tempfile::TempFile;
 use bytes::{Bytes, BytesMut};
 use color_eyre::{
-    eyre::{Context, OptionExt},
+    eyre::{bail, eyre, Context, OptionExt, Report},
     Result, Section, SectionExt,
 };
 use futures_lite::{Stream, StreamExt};
 use os_str_bytes::OsStrBytesExt;
 use serde::de::DeserializeOwned;
 use tap::Pipe;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter},
+    process::ChildStdout,
+    task::{JoinError, JoinSet},
+};
 use tokio_tar::{Archive, Entry};
-use tokio_util::io::{ReaderStream, StreamReader};
-use tracing::{debug, warn};
+use tokio_util::{
+    either::Either,
+    io::{ReaderStream, StreamReader},
+};
+use tracing::debug;
 
 use crate::{
-    transform::{self, Chunk},
-    Digest, FilterMatch, Filters, Layer, LayerMediaType, LayerMediaTypeFlag,
+    error::Kind,
+    metrics::warn,
+    transform::{self, Buffering, Chunk},
+    CaseCollisions, Digest, FileType, FilterCommand, FilterCommands, FilterMatch, Filters, Layer,
+    Limits, BINARY_SNIFF_LEN,
 };
 
 /// Unwrap a value, logging an error and performing the provided action if it fails.
@@ -34,7 +53,7 @@ macro_rules! unwrap_warn {
         match $expr {
             Ok(value) => value,
             Err(e) => {
-                tracing::warn!(error = ?e, $($msg)*);
+                crate::metrics::warn!(error = ?e, $($msg)*);
                 $action;
             }
         }
@@ -59,63 +78,380 @@ pub async fn file_digest(path: &Path) -> Result<Digest> {
     Ok(Digest::from_hash(hash))
 }
 
-/// Transform an OCI image layer (based on its media type) into its underlying tarball.
-/// Foreign layers return `None`.
-#[tracing::instrument(skip(stream))]
-pub fn peel_layer(
-    layer: &Layer,
-    stream: impl Stream<Item = Chunk> + Unpin + 'static,
-) -> Option<Pin<Box<dyn Stream<Item = Chunk>>>> {
-    // Applying the layer requires interpreting the layer's media type.
-    match &layer.media_type {
-        // Standard OCI layers.
-        LayerMediaType::Oci(flags) => {
-            // Foreign layers are skipped, as they would if you ran `docker pull`.
-            // This causes an extra iteration over the flags for layers that aren't foreign,
-            // but the flag count is small and this saves us the complexity of setting up layer transforms
-            // and then discarding them if this flag is encountered.
-            if flags.contains(&LayerMediaTypeFlag::Foreign) {
-                warn!("skip: foreign layer");
-                return None;
+/// Compute the fs-verity digest of the specified file on disk.
+///
+/// This is the digest that the kernel reports for the file (e.g. via `fsverity measure`) once fs-verity is enabled,
+/// using the defaults of `fsverity enable`: SHA256 over 4096 byte blocks with no salt.
+/// Recording it ahead of time means that a sealed file can later be checked against what was extracted.
+pub async fn fsverity_digest(path: &Path) -> Result<Digest> {
+    use sha2::{Digest as _, Sha256};
+    const BLOCK_SIZE: usize = 4096;
+    const HASH_SIZE: usize = 32;
+
+    let hash_block = |block: &[u8]| {
+        let mut hasher = Sha256::new();
+        hasher.update(block);
+        hasher.update(&[0; BLOCK_SIZE][block.len()..]);
+        hasher.finalize()
+    };
+
+    // The bottom level of the Merkle tree is the hash of each data block, with the last block zero padded.
+    let mut file = tokio::fs::File::open(path).await.context("open file")?;
+    let mut block = vec![0; BLOCK_SIZE];
+    let mut level = Vec::new();
+    let mut size = 0u64;
+    loop {
+        let mut filled = 0;
+        while filled < BLOCK_SIZE {
+            match file.read(&mut block[filled..]).await.context("read file")? {
+                0 => break,
+                n => filled += n,
             }
+        }
+        if filled == 0 {
+            break;
+        }
+
+        size += filled as u64;
+        level.extend_from_slice(&hash_block(&block[..filled]));
+    }
 
-            Some(match flags.as_slice() {
-                // No flags; this means the layer is uncompressed.
-                [] => Box::pin(stream),
+    // Each level above hashes the blocks of hashes below it, until only the root hash remains.
+    // Empty files have no blocks, and so by definition their root hash is all zeroes.
+    let root = match level.len() {
+        0 => vec![0; HASH_SIZE],
+        _ => {
+            while level.len() > HASH_SIZE {
+                level = level.chunks(BLOCK_SIZE).flat_map(hash_block).collect();
+            }
+            level
+        }
+    };
 
-                // The layer is compressed with zstd.
-                [LayerMediaTypeFlag::Zstd] => Box::pin(transform::zstd(stream)),
+    // The file digest is the hash of the `fsverity_descriptor` structure for the file.
+    let mut descriptor = Vec::with_capacity(256);
+    descriptor.push(1); // version
+    descriptor.push(1); // hash algorithm: SHA256
+    descriptor.push(BLOCK_SIZE.trailing_zeros() as u8); // log2 of the block size
+    descriptor.push(0); // salt size
+    descriptor.extend_from_slice(&[0; 4]); // reserved
+    descriptor.extend_from_slice(&size.to_le_bytes());
+    descriptor.extend_from_slice(&root);
+    descriptor.resize(256, 0); // the rest of the root hash, salt, and reserved fields
 
-                // The layer is compressed with gzip.
-                [LayerMediaTypeFlag::Gzip] => Box::pin(transform::gzip(stream)),
+    Ok(Digest::from_hash(Sha256::digest(&descriptor).to_vec()))
+}
 
-                // The layer has a more complicated set of flags.
-                // For this, we fall back to the generic sequence operator.
-                _ => Box::pin(transform::sequence(stream, flags)),
-            })
+/// Recursively enumerate the regular files in a directory.
+///
+/// Symlinks are not followed, so the result only includes files physically located under `dir`.
+/// The result is sorted so that it is stable across runs.
+#[tracing::instrument]
+pub async fn walk_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut pending = vec![dir.to_path_buf()];
+    let mut files = Vec::new();
+    while let Some(dir) = pending.pop() {
+        let mut entries = tokio::fs::read_dir(&dir)
+            .await
+            .with_context(|| format!("read directory {dir:?}"))?;
+        while let Some(entry) = entries.next_entry().await.context("read directory entry")? {
+            let kind = entry.file_type().await.context("read file type")?;
+            if kind.is_dir() {
+                pending.push(entry.path());
+            } else if kind.is_file() {
+                files.push(entry.path());
+            }
         }
     }
+
+    files.sort();
+    Ok(files)
 }
 
-/// Sink the stream into a temporary file.
+/// Sink the stream into a temporary file, in the directory reported by [`temp_dir`].
 #[tracing::instrument(skip(stream))]
 pub async fn collect_tmp<E: std::error::Error + Send + Sync + 'static>(
     mut stream: impl Stream<Item = Result<Bytes, E>> + Unpin,
 ) -> Result<TempFile> {
-    let file = TempFile::new().await.context("create temp file")?;
+    let dir = temp_dir();
+    let file = TempFile::new_in(dir.as_path())
+        .await
+        .context("create temp file")?;
     let mut writer = BufWriter::new(file);
 
     while let Some(chunk) = stream.next().await {
         let chunk = chunk.context("read chunk")?;
-        writer.write_all(&chunk).await.context("write chunk")?;
+        writer
+            .write_all(&chunk)
+            .await
+            .map_err(|err| mark_exhausted(err, &dir))
+            .context("write chunk")?;
     }
-    writer.flush().await.context("flush writer")?;
+    writer
+        .flush()
+        .await
+        .map_err(|err| mark_exhausted(err, &dir))
+        .context("flush writer")?;
 
     let file = writer.into_inner();
-    file.sync_all().await.context("sync file")?;
+    file.sync_all()
+        .await
+        .map_err(|err| mark_exhausted(err, &dir))
+        .context("sync file")?;
     Ok(file)
 }
 
+/// The name of the directory in the output directory to which temporary files spill; see [`Spill`].
+pub const SPILL_DIR_NAME: &str = ".circe-tmp";
+
+/// The installed spill directory, and whether temporary files are currently written to it.
+static SPILL: RwLock<Option<(PathBuf, bool)>> = RwLock::new(None);
+
+/// While held, temporary files (most importantly layers buffered while they're applied)
+/// can spill to [`SPILL_DIR_NAME`] in the output directory instead of the system temporary directory,
+/// so extraction succeeds on hosts whose temporary directory is too small for the layers of an image.
+///
+/// Temporary files spill once [`Spill::activate`] is called (e.g. because a preflight check found
+/// the temporary directory too small), or once the temporary directory runs out of space while a layer
+/// is buffered during [`crate::extract::extract`], in which case the layer is applied again.
+/// The spill directory is removed when this is dropped.
+#[derive(Debug)]
+pub struct Spill {
+    dir: PathBuf,
+}
+
+impl Spill {
+    /// Allow temporary files to spill to the output directory.
+    pub fn install(output: &Path) -> Self {
+        let dir = output.join(SPILL_DIR_NAME);
+        if let Ok(mut spill) = SPILL.write() {
+            *spill = Some((dir.clone(), false));
+        }
+        Self { dir }
+    }
+
+    /// Write temporary files to the spill directory from now on.
+    pub fn activate(&self) -> crate::Result<()> {
+        spill().map(|_| ()).map_err(crate::Error::from)
+    }
+
+    /// The directory to which temporary files spill.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        if let Ok(mut spill) = SPILL.write() {
+            *spill = None;
+        }
+        match std::fs::remove_dir_all(&self.dir) {
+            Ok(()) => debug!(dir = ?self.dir, "removed spill directory"),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => warn!(error = ?err, "remove spill directory {:?}", self.dir),
+        }
+    }
+}
+
+/// The directory in which temporary files are written:
+/// the spill directory if temporary files have spilled (see [`Spill`]), otherwise the system temporary directory.
+pub fn temp_dir() -> PathBuf {
+    match SPILL.read().ok().and_then(|spill| spill.clone()) {
+        Some((dir, true)) => dir,
+        _ => std::env::temp_dir(),
+    }
+}
+
+/// Start writing temporary files to the spill directory, creating it if needed.
+///
+/// Reports whether temporary files spilled as a result:
+/// false if no [`Spill`] is installed or temporary files had already spilled.
+pub(crate) fn spill() -> Result<bool> {
+    let Some(dir) = SPILL
+        .read()
+        .ok()
+        .and_then(|spill| spill.clone())
+        .and_then(|(dir, active)| (!active).then_some(dir))
+    else {
+        return Ok(false);
+    };
+
+    std::fs::create_dir_all(&dir)
+        .context("create spill directory")
+        .with_section(|| dir.display().to_string().header("Path:"))?;
+    if let Ok(mut spill) = SPILL.write() {
+        *spill = Some((dir, true));
+    }
+    Ok(true)
+}
+
+/// Reports that the temporary directory ran out of space while a file was written to it.
+#[derive(Debug, thiserror::Error)]
+#[error("no space left in temporary directory {0:?}")]
+struct TempSpaceExhausted(PathBuf);
+
+/// Mark an error writing to the temporary directory if it ran out of space, so that it's recognized by [`temp_exhausted`].
+fn mark_exhausted(err: io::Error, dir: &Path) -> io::Error {
+    match err.kind() {
+        io::ErrorKind::StorageFull => {
+            io::Error::new(err.kind(), TempSpaceExhausted(dir.to_path_buf()))
+        }
+        _ => err,
+    }
+}
+
+/// Report whether the error was caused by the temporary directory running out of space.
+pub(crate) fn temp_exhausted(err: &(dyn std::error::Error + 'static)) -> bool {
+    std::iter::successors(Some(err), |err| err.source()).any(|err| {
+        err.downcast_ref::<io::Error>()
+            .and_then(|err| err.get_ref())
+            .is_some_and(|inner| inner.is::<TempSpaceExhausted>())
+    })
+}
+
+/// Pipe a decompressed layer through the filter command that applies to it, if any;
+/// see [`crate::FilterCommands`] for details.
+///
+/// The layer is written to the standard input of the command as the output is read,
+/// and the output ends with an error if the command fails.
+#[tracing::instrument(skip(commands, stream))]
+pub fn filter_layer(
+    commands: &FilterCommands,
+    layer: &Layer,
+    stream: Pin<Box<dyn Stream<Item = Chunk>>>,
+) -> Result<Pin<Box<dyn Stream<Item = Chunk>>>> {
+    let Some(FilterCommand { command, .. }) = commands.command(layer) else {
+        return Ok(stream);
+    };
+
+    debug!(%command, "pipe layer through filter command");
+    let mut child = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("spawn filter command '{command}'"))?;
+    let mut stdin = child.stdin.take().ok_or_eyre("open filter command stdin")?;
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_eyre("open filter command stdout")?;
+
+    let feed = async move {
+        tokio::io::copy(&mut StreamReader::new(stream), &mut stdin).await?;
+        stdin.shutdown().await
+    };
+
+    let command = command.clone();
+    let exit = async move {
+        let status = child.wait().await?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!(
+                "filter command '{command}' failed: {status}"
+            )))
+        }
+    };
+
+    Ok(Box::pin(Filtered {
+        feed: Some(Box::pin(feed)),
+        output: ReaderStream::new(stdout),
+        exit: Some(Box::pin(exit)),
+    }))
+}
+
+/// The output of a filter command, which writes the layer to the command while its output is read.
+struct Filtered {
+    feed: Option<Pin<Box<dyn Future<Output = io::Result<()>>>>>,
+    output: ReaderStream<ChildStdout>,
+    exit: Option<Pin<Box<dyn Future<Output = io::Result<()>>>>>,
+}
+
+impl Stream for Filtered {
+    type Item = Chunk;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Chunk>> {
+        if let Some(feed) = self.feed.as_mut() {
+            if let Poll::Ready(result) = feed.as_mut().poll(cx) {
+                self.feed = None;
+                if let Err(err) = result {
+                    return Poll::Ready(Some(Err(err)));
+                }
+            }
+        }
+
+        if let Some(chunk) = ready!(Pin::new(&mut self.output).poll_next(cx)) {
+            return Poll::Ready(Some(chunk));
+        }
+
+        // The layer is only complete once it has been fully written to the command
+        // and the command has exited successfully.
+        if self.feed.is_some() {
+            return Poll::Pending;
+        }
+        match self.exit.as_mut() {
+            Some(exit) => {
+                let result = ready!(exit.as_mut().poll(cx));
+                self.exit = None;
+                Poll::Ready(result.err().map(Err))
+            }
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+/// Read the stream ahead of its consumer on a separate task, buffering up to `buffering.read_ahead` bytes;
+/// the content is gathered into chunks of `buffering.chunk_size` bytes as it is read.
+///
+/// This lets the stream be downloaded while content read earlier is still being decompressed and unpacked.
+/// If read-ahead is disabled the stream is returned unchanged.
+pub fn read_ahead(
+    buffering: Buffering,
+    stream: impl Stream<Item = Chunk> + Send + 'static,
+) -> Pin<Box<dyn Stream<Item = Chunk> + Send>> {
+    if buffering.read_ahead == 0 {
+        return Box::pin(stream);
+    }
+
+    let chunk_size = buffering.chunk_size.get();
+    let capacity = buffering.read_ahead.div_ceil(chunk_size);
+    let (tx, rx) = tokio::sync::mpsc::channel(capacity);
+    tokio::spawn(async move {
+        let mut stream = std::pin::pin!(stream);
+        let mut buffer = BytesMut::with_capacity(chunk_size);
+        loop {
+            let next = stream.next().await;
+            if let Some(Ok(chunk)) = &next {
+                buffer.extend_from_slice(chunk);
+                if buffer.len() < chunk_size {
+                    continue;
+                }
+            }
+
+            // Sending fails once the consumer has gone away, so there's no reason to keep reading.
+            if !buffer.is_empty() && tx.send(Ok(buffer.split().freeze())).await.is_err() {
+                return;
+            }
+            match next {
+                Some(Ok(_)) => continue,
+                Some(Err(err)) => {
+                    let _ = tx.send(Err(err)).await;
+                    return;
+                }
+                None => return,
+            }
+        }
+    });
+
+    Box::pin(futures_lite::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    }))
+}
+
 /// Buffer the contents of a byte stream.
 /// Limited to 100MB of memory.
 #[tracing::instrument(skip(stream))]
@@ -141,7 +477,7 @@ pub async fn collect_json<T: DeserializeOwned>(
 fn display_maybe_json(content: &[u8]) -> String {
     if content.iter().take(8000).any(|&b| b == b'\0') {
         String::from("<binary>")
-    } else if let Ok(json) = serde_json::from_slice::<serde_json::Value>(&content) {
+    } else if let Ok(json) = serde_json::from_slice::<serde_json::Value>(content) {
         json.to_string()
     } else {
         String::from_utf8_lossy(content).to_string()
@@ -156,34 +492,42 @@ pub async fn extract_json<T: DeserializeOwned>(
     tarball: &Path,
     closure: impl Fn(&Path) -> bool,
 ) -> Result<Option<T>> {
-    match extract_file(tarball, closure).await? {
+    match extract_file(tarball, Buffering::default(), closure).await? {
         Some(stream) => collect_json(stream).await.map(Some),
         None => Ok(None),
     }
 }
 
-/// Read a the buffered contents of a specific file out of a tarball.
-/// Returns the contents of the first file for which the closure evaluates to `true`.
-/// If no file is found, this function returns `None`.
-#[tracing::instrument(skip(closure))]
-pub async fn extract_file_buf(
-    tarball: &Path,
-    closure: impl Fn(&Path) -> bool,
-) -> Result<Option<Bytes>> {
-    match extract_file(tarball, closure).await? {
-        Some(stream) => collect_buf(stream).await.map(Some),
-        None => Ok(None),
-    }
-}
-
 /// Read a the contents of a specific file out of a tarball.
 /// Returns the contents of the first file for which the closure evaluates to `true`.
 /// If no file is found, this function returns `None`.
+/// The file is read in chunks of the size configured by `buffering`.
+///
+/// The tarball may also be a directory, like an OCI image layout that wasn't archived;
+/// its files are then matched by their path relative to the directory, in the order of [`walk_files`].
 #[tracing::instrument(skip(closure))]
 pub async fn extract_file(
     tarball: &Path,
+    buffering: Buffering,
     closure: impl Fn(&Path) -> bool,
 ) -> Result<Option<impl Stream<Item = Chunk>>> {
+    if is_dir(tarball).await {
+        for path in walk_files(tarball).await? {
+            if !closure(path.strip_prefix(tarball).unwrap_or(&path)) {
+                continue;
+            }
+
+            debug!(?path, "extracting file");
+            let file = File::open(&path)
+                .await
+                .with_context(|| format!("open {path:?}"))?;
+            let stream =
+                ReaderStream::with_capacity(Either::Right(file), buffering.chunk_size.get());
+            return Ok(Some(stream));
+        }
+        return Ok(None);
+    }
+
     let archive = tokio::fs::File::open(tarball)
         .await
         .context("open docker tarball")?;
@@ -198,59 +542,238 @@ pub async fn extract_file(
         }
 
         debug!(?path, "extracting file");
-        let stream = ReaderStream::new(entry);
+        let stream = ReaderStream::with_capacity(Either::Left(entry), buffering.chunk_size.get());
         return Ok(Some(stream));
     }
 
     Ok(None)
 }
 
+/// Whether the path is a directory; paths that can't be read aren't.
+pub async fn is_dir(path: &Path) -> bool {
+    tokio::fs::metadata(path)
+        .await
+        .is_ok_and(|metadata| metadata.is_dir())
+}
+
+/// The maximum number of files written concurrently while applying a layer.
+///
+/// Writing files is dominated by filesystem latency rather than throughput,
+/// especially for layers with many small files, so this is intentionally higher than the number of cores.
+const WRITE_CONCURRENCY: usize = 32;
+
+/// Settings for how [`apply_tarball`] writes a layer to disk.
+#[derive(Debug, Clone, Copy)]
+pub struct ApplyOptions<'a> {
+    /// Entries whose path doesn't match the filters, or that don't satisfy their predicates, are skipped.
+    pub filters: &'a Filters,
+
+    /// How to handle entries whose paths collide on case-insensitive filesystems.
+    pub collisions: CaseCollisions,
+
+    /// If set, paths deleted by whiteouts are moved to the same relative path in this directory
+    /// instead of being removed.
+    pub deleted: Option<&'a Path>,
+
+    /// Applying the layer fails if it exceeds any of these limits.
+    pub limits: Limits,
+}
+
 /// Apply a layer diff tarball to a location on disk.
-#[tracing::instrument(skip(stream))]
+///
+/// `on_write` is called with the path of each entry written to disk,
+/// `on_rename` is called with the original and new path of each entry renamed
+/// to avoid a case collision (see [`CaseCollisions`]),
+/// `on_whiteout` is called with the path deleted by each whiteout and whether anything existed there,
+/// and `on_recover` is called with the path deleted by a whiteout and the path to which it was moved
+/// if [`ApplyOptions::deleted`] is set.
+///
+/// The OCI spec guarantees that paths don't repeat within a layer, so the contents of regular files
+/// are written concurrently: the layer is buffered to disk, then its entries are read in order,
+/// with each regular file handed off to a bounded set of workers that copy its content from the buffered layer.
+/// Everything else (directories, whiteouts, symlinks) is applied in order as it is read,
+/// and hard links are applied once all files are written since they rely on their targets existing.
+///
+/// The [`ApplyOptions::limits`] are enforced as the layer is read: the decompressed size while it's buffered,
+/// and the number of entries and the size of each file as its entries are read.
+#[tracing::instrument(skip(stream, on_write, on_rename, on_whiteout, on_recover))]
 pub async fn apply_tarball(
-    path_filters: &Filters,
+    opts: ApplyOptions<'_>,
     stream: impl Stream<Item = Chunk> + Unpin,
     output: &Path,
+    mut on_write: impl FnMut(&Path),
+    mut on_rename: impl FnMut(&Path, &Path),
+    mut on_whiteout: impl FnMut(&Path, bool),
+    mut on_recover: impl FnMut(&Path, &Path),
 ) -> Result<()> {
-    let reader = StreamReader::new(stream);
-    let mut archive = Archive::new(reader);
+    let limits = opts.limits;
+    let stream = transform::limit_size(stream, limits.max_layer_size);
+    let buffered = collect_tmp(stream).await.context("buffer layer")?;
+    let tarball = Arc::<Path>::from(buffered.file_path().as_path());
+    let file = File::open(&tarball).await.context("open buffered layer")?;
+    let mut archive = Archive::new(file);
     let mut entries = archive.entries().context("read entries from tar")?;
 
-    // Future improvement: the OCI spec guarantees that paths will not repeat within the same layer,
-    // so we could concurrently read files and apply them to disk.
-    // The overall archive is streaming so we'd need to buffer the entries,
-    // but assuming disk is the bottleneck this might speed up the process significantly.
-    // We could also of course write the tar to disk and then extract it concurrently
-    // without buffering- maybe we could read the tar entries while streaming to disk,
-    // and then divide them among workers that apply them to disk concurrently?
+    let check_collisions = case_insensitive(output).await;
+    let canonical_output = tokio::fs::canonicalize(output)
+        .await
+        .context("canonicalize output")?;
+
+    let mut writes = JoinSet::new();
+    let mut written = Written::default();
+    let mut links = Vec::new();
+    let mut count = 0u64;
     while let Some(entry) = entries.next().await {
         let mut entry = unwrap_warn!(entry, continue, "read entry");
         let path = unwrap_warn!(entry.path(), continue, "read entry path");
 
+        count += 1;
+        if let Some(max) = limits.max_files.filter(|max| count > *max) {
+            return Err(Report::new(Kind::Limit))
+                .with_context(|| format!("layer contains more than {max} entries"));
+        }
+
+        let size = entry.header().entry_size().unwrap_or_default();
+        if let Some(max) = limits.max_file_size.filter(|max| size > *max) {
+            return Err(Report::new(Kind::Limit)).with_context(|| {
+                format!("{path:?} is {size} bytes, more than the limit of {max} bytes")
+            });
+        }
+
         // Paths inside the container are relative to the root of the container;
         // we need to convert them to be relative to the output directory.
-        let path = output.join(path);
+        let path = output.join(strip_root(path));
 
-        if !path_filters.matches(&path) {
+        if !opts.filters.matches(&path) {
             debug!(?path, "skip: path filter");
             continue;
         }
 
         // Whiteout files delete the file from the filesystem.
+        // Whiteouts commonly refer to files that a filter excluded from prior layers,
+        // so a missing file isn't a problem; it's just reported as such.
         if let Some(path) = is_whiteout(&path) {
+            if let Some(deleted) = opts.deleted {
+                // If the path can't be recovered, it's still deleted below.
+                match recover_deleted(&path, output, deleted).await {
+                    Ok(Some(recovered)) => {
+                        debug!(?path, ?recovered, "whiteout: recovered");
+                        on_whiteout(&path, true);
+                        on_recover(&path, &recovered);
+                        continue;
+                    }
+                    Ok(None) => {}
+                    Err(err) => warn!(error = ?err, "recover {path:?}"),
+                }
+            }
+
+            let existed = match tokio::fs::remove_file(&path).await {
+                Ok(()) => true,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => false,
+                Err(err) => {
+                    warn!(error = ?err, "whiteout: {path:?}");
+                    continue;
+                }
+            };
+            debug!(?path, existed, "whiteout");
+            on_whiteout(&path, existed);
+            continue;
+        }
+
+        if !unwrap_warn!(
+            admitted(opts.filters, &mut entry).await,
+            continue,
+            "inspect {path:?}"
+        ) {
+            debug!(?path, "skip: predicate");
+            continue;
+        }
+
+        // If the filesystem isn't case-sensitive, writing this entry could silently replace
+        // a different entry whose path differs only by case.
+        let kind = entry.header().entry_type();
+        let mut renamed = None;
+        if check_collisions && !kind.is_dir() {
+            if let Some(existing) = case_collision(&path, &written).await {
+                if opts.collisions == CaseCollisions::Error {
+                    return Err(collision_error(&path, &existing));
+                }
+
+                let target = unwrap_warn!(
+                    rename_collision(&path, &written).await,
+                    continue,
+                    "rename {path:?}"
+                );
+                warn!(?path, ?existing, renamed = ?target, "case collision: renamed");
+                renamed = Some(target);
+            }
+        }
+
+        if kind.is_hard_link() {
+            let target = unwrap_warn!(entry.link_name(), continue, "read link target {path:?}");
+            let Some(target) = target else {
+                warn!(?path, "skip: hard link without target");
+                continue;
+            };
+
+            written.insert(renamed.as_deref().unwrap_or(&path));
+            links.push(HardLink {
+                path,
+                renamed,
+                target: target.into_owned(),
+            });
+            continue;
+        }
+
+        // Regular files are written by workers, unless they're sparse (so their content isn't stored contiguously)
+        // or are actually directories (old tar formats mark directories with a trailing slash instead of a type).
+        if kind.is_file() && !kind.is_gnu_sparse() && !entry.path_bytes().ends_with(b"/") {
+            let dst = renamed.clone().unwrap_or_else(|| path.clone());
+            let Some(dst) = unwrap_warn!(
+                contained_path(&canonical_output, &dst, &mut written).await,
+                continue,
+                "prepare {path:?}"
+            ) else {
+                warn!(?path, "skip: tried to write outside of output directory");
+                continue;
+            };
+
+            written.insert(renamed.as_deref().unwrap_or(&path));
+            let write = WriteFile {
+                path,
+                renamed,
+                dst,
+                offset: entry.raw_file_position(),
+                size,
+                mtime: entry.header().mtime().ok(),
+            };
+
+            while writes.len() >= WRITE_CONCURRENCY {
+                if let Some(done) = writes.join_next().await {
+                    report_write(done, &mut on_write, &mut on_rename);
+                }
+            }
+            writes.spawn(write_file(tarball.clone(), write));
+            continue;
+        }
+
+        if let Some(renamed) = renamed {
             unwrap_warn!(
-                tokio::fs::remove_file(&path).await,
+                unpack_as(&mut entry, output, &renamed).await,
                 continue,
-                "whiteout: {path:?}"
+                "unpack {path:?} as {renamed:?}"
             );
-            debug!(?path, "whiteout");
+
+            written.insert(&renamed);
+            on_rename(&path, &renamed);
+            on_write(&renamed);
             continue;
         }
 
         // The tar library mostly handles symlinks properly, but still allows them to link to absolute paths.
         // This doesn't technically break anything from a security standpoint, but might for analysis.
         // Intercept its handling of absolute symlinks to handle this case.
-        if entry.header().entry_type().is_symlink() {
+        if kind.is_symlink() {
             let handled = unwrap_warn!(
                 safe_symlink(&entry, output).await,
                 continue,
@@ -259,6 +782,8 @@ pub async fn apply_tarball(
 
             // But if the function didn't handle it, fall back to the default behavior.
             if handled {
+                written.insert(&path);
+                on_write(&path);
                 continue;
             }
         }
@@ -269,36 +794,603 @@ pub async fn apply_tarball(
         // I don't _think_ this matters for now given how we're using this today, but it's technically incorrect.
         // To fix this we need to re-implement the logic in `unpack_in` to rewrite symlink destinations.
 
-        // Otherwise, apply the file as normal.
-        // Both _new_ and _changed_ files are handled the same way:
-        // the layer contains the entire file content, so we just overwrite the file.
-        if unwrap_warn!(entry.unpack_in(output).await, continue, "unpack {path:?}").is_none() {
+        // Otherwise, apply the entry as normal.
+        let Some(unpacked) =
+            unwrap_warn!(entry.unpack_in(output).await, continue, "unpack {path:?}")
+        else {
             warn!(?path, "skip: tried to write outside of output directory");
             continue;
+        };
+
+        // Directories are created even if they're empty, but `unpack_in` doesn't preserve their mode
+        // (this is only configurable at the archive level, where it would apply to every entry).
+        // Directories like `/tmp` rely on special modes like the sticky bit, so apply them explicitly.
+        if kind.is_dir() {
+            let mode = unwrap_warn!(entry.header().mode(), continue, "read mode {path:?}");
+            unwrap_warn!(
+                set_dir_mode(&unpacked, mode).await,
+                continue,
+                "set mode {mode:o} on {path:?}"
+            );
+            debug!(?path, mode = format!("{mode:o}"), "apply directory");
+            continue;
         }
 
         debug!(?path, "apply");
+        written.insert(&path);
+        on_write(&path);
+    }
+
+    while let Some(done) = writes.join_next().await {
+        report_write(done, &mut on_write, &mut on_rename);
+    }
+
+    for link in links {
+        let dst = link.renamed.as_deref().unwrap_or(&link.path);
+        unwrap_warn!(
+            hard_link(&canonical_output, &link.target, dst).await,
+            continue,
+            "link {:?} to {:?}",
+            link.path,
+            link.target
+        );
+
+        debug!(path = ?link.path, target = ?link.target, "apply hard link");
+        if let Some(renamed) = &link.renamed {
+            on_rename(&link.path, renamed);
+        }
+        on_write(dst);
+    }
+
+    Ok(())
+}
+
+/// Move a path deleted by a whiteout to the same relative path in the `deleted` directory,
+/// returning the path to which it was moved.
+///
+/// Directories aren't moved, since whiteouts don't remove them either;
+/// if nothing exists at the path there's nothing to recover and `None` is returned.
+async fn recover_deleted(path: &Path, output: &Path, deleted: &Path) -> Result<Option<PathBuf>> {
+    match tokio::fs::symlink_metadata(path).await {
+        Ok(meta) if meta.is_dir() => return Ok(None),
+        Ok(_) => {}
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).context("read metadata"),
+    }
+
+    let relative = path.strip_prefix(output).context("path is not in output")?;
+    if !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        bail!("path is not contained in output: {path:?}");
+    }
+
+    let target = deleted.join(relative);
+    let (Some(parent), Some(name)) = (target.parent(), target.file_name()) else {
+        bail!("path has no parent: {target:?}");
+    };
+    tokio::fs::create_dir_all(parent)
+        .await
+        .context("create parent")?;
+    tokio::fs::rename(path, &target)
+        .await
+        .context("move to deleted")?;
+
+    // Report the canonical path so that it can be compared to the canonical output directory;
+    // only the parent is canonicalized since the recovered entry may be a symlink.
+    let parent = tokio::fs::canonicalize(parent)
+        .await
+        .context("canonicalize parent")?;
+    Ok(Some(parent.join(name)))
+}
+
+/// The paths applied from the current layer so far, including those still being written.
+///
+/// Used to detect case collisions with files that haven't been written yet,
+/// and to avoid re-checking the same parent directory for each file inside it.
+#[derive(Debug, Default)]
+struct Written {
+    /// Paths in the layer, keyed by their lowercase form.
+    paths: HashMap<String, PathBuf>,
+
+    /// Directories already confirmed to be inside the output directory.
+    parents: HashSet<PathBuf>,
+}
+
+impl Written {
+    fn insert(&mut self, path: &Path) {
+        self.paths.insert(Self::key(path), path.to_path_buf());
+    }
+
+    /// The path in the layer that differs from this one only by case, if any.
+    fn collides(&self, path: &Path) -> Option<&Path> {
+        self.paths
+            .get(&Self::key(path))
+            .map(PathBuf::as_path)
+            .filter(|existing| *existing != path)
+    }
+
+    fn contains(&self, path: &Path) -> bool {
+        self.paths.contains_key(&Self::key(path))
+    }
+
+    fn key(path: &Path) -> String {
+        path.to_string_lossy().to_ascii_lowercase()
+    }
+}
+
+/// A regular file to be written from the buffered layer.
+#[derive(Debug)]
+struct WriteFile {
+    /// The path of the entry in the output directory.
+    path: PathBuf,
+
+    /// The path to which the entry is written instead of `path`, if it was renamed.
+    renamed: Option<PathBuf>,
+
+    /// The canonical path to which the file is written.
+    dst: PathBuf,
+
+    /// The offset of the file's content in the buffered layer.
+    offset: u64,
+
+    /// The size of the file's content.
+    size: u64,
+
+    /// The modification time of the file, in seconds since the unix epoch.
+    mtime: Option<u64>,
+}
+
+/// A hard link to be applied once all files are written.
+#[derive(Debug)]
+struct HardLink {
+    path: PathBuf,
+    renamed: Option<PathBuf>,
+    target: PathBuf,
+}
+
+/// Resolve the path to which an entry is written, ensuring it doesn't escape the output directory.
+///
+/// This mirrors the checks `tokio_tar` performs when unpacking entries:
+/// the path must not contain `..`, and its parent (which is created if needed)
+/// must not resolve outside the output directory through a symlink.
+/// Returns `None` if the path would escape the output directory.
+async fn contained_path(
+    canonical_output: &Path,
+    path: &Path,
+    written: &mut Written,
+) -> Result<Option<PathBuf>> {
+    if path.components().any(|c| c == Component::ParentDir) {
+        return Ok(None);
+    }
+
+    let name = path.file_name().ok_or_eyre("path has no file name")?;
+    let parent = path.parent().ok_or_eyre("path has no parent")?;
+    if !written.parents.contains(parent) {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .context("create parent directory")?;
+    }
+
+    let canonical_parent = tokio::fs::canonicalize(parent)
+        .await
+        .context("canonicalize parent directory")?;
+    if !canonical_parent.starts_with(canonical_output) {
+        return Ok(None);
+    }
+
+    written.parents.insert(parent.to_path_buf());
+    Ok(Some(canonical_parent.join(name)))
+}
+
+/// Write the content of a regular file from the buffered layer.
+async fn write_file(tarball: Arc<Path>, write: WriteFile) -> (WriteFile, Result<()>) {
+    let result = async {
+        let mut reader = File::open(&tarball).await.context("open buffered layer")?;
+        reader
+            .seek(SeekFrom::Start(write.offset))
+            .await
+            .context("seek to content")?;
+
+        // Like `tokio_tar`, write a new file rather than overwriting an existing file in place,
+        // since the existing file may be a symlink.
+        match tokio::fs::remove_file(&write.dst).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                return Err(err).context("remove existing file");
+            }
+            _ => {}
+        }
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&write.dst)
+            .await
+            .context("create file")?;
+
+        let copied = tokio::io::copy(&mut reader.take(write.size), &mut file)
+            .await
+            .context("write content")?;
+        if copied != write.size {
+            bail!("layer ended after {copied} of {} bytes", write.size);
+        }
+
+        // Also like `tokio_tar`, avoid zero modification times since some tools handle them poorly.
+        if let Some(mtime) = write.mtime {
+            let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(mtime.max(1));
+            let times = FileTimes::new().set_accessed(mtime).set_modified(mtime);
+            let file = file.into_std().await;
+            tokio::task::spawn_blocking(move || file.set_times(times))
+                .await
+                .context("join")?
+                .context("set modification time")?;
+        }
+
+        Ok(())
+    }
+    .await;
+
+    (write, result)
+}
+
+/// Report the outcome of a file written by [`write_file`].
+fn report_write(
+    done: Result<(WriteFile, Result<()>), JoinError>,
+    on_write: &mut impl FnMut(&Path),
+    on_rename: &mut impl FnMut(&Path, &Path),
+) {
+    let (write, result) = unwrap_warn!(done, return, "join write");
+    unwrap_warn!(result, return, "write {:?}", write.path);
+
+    debug!(path = ?write.path, "apply");
+    match &write.renamed {
+        Some(renamed) => {
+            on_rename(&write.path, renamed);
+            on_write(renamed);
+        }
+        None => on_write(&write.path),
+    }
+}
+
+/// Create a hard link to a target inside the output directory.
+async fn hard_link(canonical_output: &Path, target: &Path, path: &Path) -> Result<()> {
+    let target = canonical_output.join(strip_root(target));
+    if target.components().any(|c| c == Component::ParentDir) {
+        bail!("hard link target escapes output directory");
+    }
+
+    match tokio::fs::remove_file(path).await {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+            return Err(err).context("remove existing file");
+        }
+        _ => {}
+    }
+
+    tokio::fs::hard_link(&target, path)
+        .await
+        .context("create hard link")
+}
+
+/// Whether paths in the directory that differ only by case may refer to the same entry.
+///
+/// This is checked by looking for the directory under its own name with the case of its ASCII letters swapped.
+/// If that's not possible (e.g. the name has no letters), the directory is assumed to be case-insensitive;
+/// this only exists to skip unnecessary work when checking for collisions.
+async fn case_insensitive(dir: &Path) -> bool {
+    let Some(name) = dir.file_name().and_then(|name| name.to_str()) else {
+        return true;
+    };
+
+    let swapped = name
+        .chars()
+        .map(|c| match c.is_ascii_lowercase() {
+            true => c.to_ascii_uppercase(),
+            false => c.to_ascii_lowercase(),
+        })
+        .collect::<String>();
+    if swapped == name {
+        return true;
+    }
+
+    tokio::fs::try_exists(dir.with_file_name(swapped))
+        .await
+        .unwrap_or(true)
+}
+
+/// If an entry exists at the path under a name that differs only by ASCII case, returns its path.
+///
+/// Entries from the current layer are also considered, since they may not have been written yet.
+async fn case_collision(path: &Path, written: &Written) -> Option<PathBuf> {
+    if let Some(existing) = written.collides(path) {
+        return Some(existing.to_path_buf());
+    }
+
+    let name = path.file_name()?;
+    let parent = path.parent()?;
+    tokio::fs::symlink_metadata(path).await.ok()?;
+
+    // Something exists at the path, but it might just be the same path written by a prior layer.
+    let mut entries = tokio::fs::read_dir(parent).await.ok()?;
+    let mut existing = None;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let other = entry.file_name();
+        if other == name {
+            return None;
+        }
+        if other.eq_ignore_ascii_case(name) {
+            existing = Some(parent.join(other));
+        }
     }
 
+    existing
+}
+
+/// The error reported for a case collision when renaming isn't allowed.
+fn collision_error(path: &Path, existing: &Path) -> Report {
+    eyre!("path collides with an existing path")
+        .with_section(|| path.display().to_string().header("Path:"))
+        .with_section(|| existing.display().to_string().header("Existing:"))
+        .note("paths that differ only by case collide on case-insensitive filesystems")
+        .suggestion(
+            "extract to a case-sensitive filesystem, or allow colliding paths to be renamed",
+        )
+}
+
+/// Find a deterministic new name for a path that collides with an existing path.
+async fn rename_collision(path: &Path, written: &Written) -> Result<PathBuf> {
+    let name = path.file_name().ok_or_eyre("path has no file name")?;
+    for n in 1.. {
+        let mut renamed = name.to_os_string();
+        renamed.push(format!("~{n}"));
+        let renamed = path.with_file_name(renamed);
+        if written.contains(&renamed) {
+            continue;
+        }
+        if !tokio::fs::try_exists(&renamed)
+            .await
+            .context("check path")?
+        {
+            return Ok(renamed);
+        }
+    }
+
+    unreachable!("exhausted possible names for {path:?}")
+}
+
+/// Unpack the entry to a path other than the one recorded in the entry.
+///
+/// `dir` is the directory to which the layer is being applied.
+async fn unpack_as<R: AsyncRead + Unpin>(
+    entry: &mut Entry<R>,
+    dir: &Path,
+    path: &Path,
+) -> Result<()> {
+    let kind = entry.header().entry_type();
+    if kind.is_symlink() || kind.is_hard_link() {
+        let target = entry
+            .link_name()
+            .context("read link target")?
+            .ok_or_eyre("no link target")?
+            .into_owned();
+
+        return if kind.is_hard_link() {
+            tokio::fs::hard_link(dir.join(strip_root(&target)), path)
+                .await
+                .context("create hard link")
+        } else if target.is_absolute() {
+            let target = compute_symlink_target(path, &dir.join(strip_root(&target)))?;
+            symlink(&target, path).await.context("create symlink")
+        } else {
+            symlink(&target, path).await.context("create symlink")
+        };
+    }
+
+    // The name is the only part of the path that changes,
+    // so the path is still inside `dir` if the entry's original path is.
+    entry.unpack(path).await.context("unpack entry").map(drop)
+}
+
+/// Set the mode of a directory created while applying a layer, including special bits
+/// like the sticky bit (e.g. `/tmp`) or setgid (e.g. `/var/mail`).
+///
+/// The owner is always granted full access, regardless of the mode in the layer:
+/// otherwise read-only directories would prevent subsequent entries or layers from being applied inside them.
+#[cfg(unix)]
+async fn set_dir_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = (mode & 0o7777) | 0o700;
+    tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+        .await
+        .context("set permissions")
+}
+
+/// Directory modes are a unix concept; other platforms create directories with default permissions.
+#[cfg(not(unix))]
+async fn set_dir_mode(_path: &Path, _mode: u32) -> Result<()> {
     Ok(())
 }
 
+/// Remove empty directories under a directory, recursively.
+///
+/// Directories that only contained empty directories are removed as well;
+/// `dir` itself is never removed.
+/// Returns the number of directories removed.
+#[tracing::instrument]
+pub async fn prune_empty_dirs(dir: &Path) -> Result<usize> {
+    // Visit directories depth first, so that children are always pruned before their parents;
+    // this way a parent only containing empty directories is itself empty by the time it's visited.
+    let mut dirs = Vec::new();
+    let mut stack = vec![dir.to_path_buf()];
+    while let Some(current) = stack.pop() {
+        let mut entries = tokio::fs::read_dir(&current)
+            .await
+            .with_context(|| format!("read directory {current:?}"))?;
+        while let Some(entry) = entries.next_entry().await.context("read entry")? {
+            let kind = entry.file_type().await.context("read file type")?;
+            if kind.is_dir() {
+                stack.push(entry.path());
+            }
+        }
+        dirs.push(current);
+    }
+
+    let mut removed = 0;
+    for path in dirs.into_iter().skip(1).rev() {
+        let mut entries = tokio::fs::read_dir(&path)
+            .await
+            .with_context(|| format!("read directory {path:?}"))?;
+        if entries.next_entry().await.context("read entry")?.is_none() {
+            debug!(?path, "prune empty directory");
+            tokio::fs::remove_dir(&path)
+                .await
+                .with_context(|| format!("remove directory {path:?}"))?;
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Recreate the directory tree at `src` in `dst`, hard linking regular files instead of copying them.
+///
+/// Files are copied instead if they can't be linked (for example if `dst` is on a different filesystem).
+/// Directories are created with the same mode as in `src`, and symlinks are recreated with the same target.
+/// Returns the number of files that were linked rather than copied.
+#[tracing::instrument]
+pub async fn link_tree(src: &Path, dst: &Path) -> Result<usize> {
+    tokio::fs::create_dir_all(dst)
+        .await
+        .with_context(|| format!("create directory {dst:?}"))?;
+
+    let mut linked = 0;
+    let mut pending = vec![(src.to_path_buf(), dst.to_path_buf())];
+    while let Some((src, dst)) = pending.pop() {
+        let mut entries = tokio::fs::read_dir(&src)
+            .await
+            .with_context(|| format!("read directory {src:?}"))?;
+        while let Some(entry) = entries.next_entry().await.context("read directory entry")? {
+            let from = entry.path();
+            let to = dst.join(entry.file_name());
+            let kind = entry.file_type().await.context("read file type")?;
+            if kind.is_dir() {
+                let meta = entry.metadata().await.context("read metadata")?;
+                match tokio::fs::create_dir(&to).await {
+                    Err(err) if err.kind() != io::ErrorKind::AlreadyExists => {
+                        return Err(err).with_context(|| format!("create directory {to:?}"));
+                    }
+                    _ => {}
+                }
+                tokio::fs::set_permissions(&to, meta.permissions())
+                    .await
+                    .with_context(|| format!("set permissions on {to:?}"))?;
+                pending.push((from, to));
+            } else if kind.is_symlink() {
+                let target = tokio::fs::read_link(&from)
+                    .await
+                    .with_context(|| format!("read symlink {from:?}"))?;
+                symlink(&target, &to)
+                    .await
+                    .with_context(|| format!("create symlink {to:?}"))?;
+            } else if kind.is_file() {
+                match tokio::fs::hard_link(&from, &to).await {
+                    Ok(()) => linked += 1,
+                    Err(err) => {
+                        debug!(error = ?err, ?from, ?to, "hard link failed; copying");
+                        tokio::fs::copy(&from, &to)
+                            .await
+                            .with_context(|| format!("copy {from:?} to {to:?}"))?;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(linked)
+}
+
 /// Enumerate files in a tarball.
+///
+/// Entries that don't satisfy the predicates of the filters are omitted;
+/// their path filters aren't applied.
 #[tracing::instrument(skip(stream))]
-pub async fn enumerate_tarball(stream: impl Stream<Item = Chunk> + Unpin) -> Result<Vec<String>> {
+pub async fn enumerate_tarball(
+    stream: impl Stream<Item = Chunk> + Unpin,
+    filters: &Filters,
+) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+    visit_tarball(stream, filters, |file| {
+        files.push(file);
+        Ok(())
+    })
+    .await?;
+    Ok(files)
+}
+
+/// Report each file in a tarball as it is read, instead of collecting them like [`enumerate_tarball`].
+///
+/// Entries are omitted in the same way as [`enumerate_tarball`];
+/// if reporting a file fails, enumeration stops with that error.
+#[tracing::instrument(skip(stream, on_file))]
+pub async fn visit_tarball(
+    stream: impl Stream<Item = Chunk> + Unpin,
+    filters: &Filters,
+    mut on_file: impl FnMut(String) -> std::io::Result<()>,
+) -> Result<()> {
     let reader = StreamReader::new(stream);
     let mut archive = Archive::new(reader);
     let mut entries = archive.entries().context("read entries from tar")?;
 
-    let mut files = Vec::new();
     while let Some(entry) = entries.next().await {
-        let entry = unwrap_warn!(entry, continue, "read entry");
-        let path = unwrap_warn!(entry.path(), continue, "read entry path");
+        let mut entry = unwrap_warn!(entry, continue, "read entry");
+        let path = unwrap_warn!(entry.path(), continue, "read entry path").into_owned();
+        if is_whiteout(&path).is_none()
+            && !unwrap_warn!(
+                admitted(filters, &mut entry).await,
+                continue,
+                "inspect {path:?}"
+            )
+        {
+            debug!(?path, "skip: predicate");
+            continue;
+        }
+
         debug!(?path, "enumerate");
-        files.push(path.to_string_lossy().to_string());
+        on_file(path.to_string_lossy().to_string()).with_context(|| format!("report {path:?}"))?;
     }
 
-    Ok(files)
+    Ok(())
+}
+
+/// Report whether the entry satisfies the predicates of the filters.
+///
+/// If the predicates inspect content, the start of regular files is read from the entry;
+/// this is safe in [`apply_tarball`] because their content is copied from the buffered layer instead.
+/// Sparse files aren't inspected, since their content isn't stored contiguously.
+async fn admitted<R: AsyncRead + Unpin>(filters: &Filters, entry: &mut Entry<R>) -> Result<bool> {
+    let kind = entry.header().entry_type();
+    let file_type = if kind.is_dir() || entry.path_bytes().ends_with(b"/") {
+        Some(FileType::Dir)
+    } else if kind.is_symlink() {
+        Some(FileType::Symlink)
+    } else if kind.is_file() || kind.is_hard_link() {
+        Some(FileType::Regular)
+    } else {
+        None
+    };
+
+    let size = entry.header().entry_size().unwrap_or_default();
+    let mut head = Vec::new();
+    if filters.inspects_content() && kind.is_file() && !kind.is_gnu_sparse() {
+        entry
+            .take(BINARY_SNIFF_LEN as u64)
+            .read_to_end(&mut head)
+            .await
+            .context("read content")?;
+    }
+
+    Ok(filters.admits(file_type, size, &head))
 }
 
 /// Special handling for symlinks that link to an absolute path.
@@ -416,7 +1508,7 @@ pub async fn symlink(src: &Path, dst: &Path) -> std::io::Result<()> {
 
 /// Returns the path to the file that would be deleted by a whiteout file, if the path is a whiteout file.
 /// If the path is not a whiteout file, returns `None`.
-fn is_whiteout(path: &Path) -> Option<PathBuf> {
+pub(crate) fn is_whiteout(path: &Path) -> Option<PathBuf> {
     const WHITEOUT_PREFIX: &str = ".wh.";
 
     // If the file doesn't have a name, it's not a whiteout file.
@@ -431,9 +1523,52 @@ fn is_whiteout(path: &Path) -> Option<PathBuf> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{LayerMediaType, LayerMediaTypeFlag, Predicate};
     use pretty_assertions::assert_eq;
     use simple_test_case::test_case;
 
+    #[test_case(0, &[b"ab", b"c", b"def"]; "disabled")]
+    #[test_case(8, &[b"abc", b"def"]; "gathered")]
+    #[test_case(1, &[b"abc", b"def"]; "less_than_chunk")]
+    #[tokio::test]
+    async fn read_ahead(read_ahead: usize, expected: &[&[u8]]) -> Result<()> {
+        let input = [&b"ab"[..], b"c", b"def"].map(|chunk| Ok(Bytes::from_static(chunk)));
+        let buffering = Buffering::builder()
+            .chunk_size(std::num::NonZeroUsize::new(3).expect("nonzero"))
+            .read_ahead(read_ahead)
+            .build();
+
+        let chunks = super::read_ahead(buffering, futures_lite::stream::iter(input))
+            .try_collect::<_, io::Error, Vec<_>>()
+            .await?;
+        pretty_assertions::assert_eq!(expected, chunks);
+        Ok(())
+    }
+
+    #[test_case(io::ErrorKind::StorageFull, true; "storage_full")]
+    #[test_case(io::ErrorKind::PermissionDenied, false; "other")]
+    #[test]
+    fn temp_exhausted(kind: io::ErrorKind, expected: bool) {
+        let err = mark_exhausted(io::Error::from(kind), Path::new("/tmp"));
+        let err = crate::Error::from(Report::new(err).wrap_err("buffer layer"));
+        pretty_assertions::assert_eq!(expected, super::temp_exhausted(&err));
+        assert!(matches!(err, crate::Error::Io(_)), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn read_ahead_error() {
+        let input = [Ok(Bytes::from_static(b"ab")), Err(io::Error::other("fail"))];
+        let buffering = Buffering::builder().read_ahead(1024).build();
+
+        let chunks = super::read_ahead(buffering, futures_lite::stream::iter(input))
+            .collect::<Vec<_>>()
+            .await;
+        match chunks.as_slice() {
+            [Ok(chunk), Err(_)] => assert_eq!(b"ab", chunk.as_ref()),
+            chunks => panic!("unexpected chunks: {chunks:?}"),
+        }
+    }
+
     #[test]
     fn test_is_whiteout() {
         assert_eq!(None, is_whiteout(Path::new("foo")));
@@ -443,6 +1578,518 @@ mod tests {
         );
     }
 
+    #[test_case(b"", "3d248ca542a24fc62d1c43b916eae5016878e2533c88238480b26128a1f1af95"; "empty")]
+    #[test_case(b"hello", "555b589c26ee43b7a2510e6c67ced9fb3190b6da6e9e683984551f5d77a763de"; "one_block")]
+    #[test_case(&[b'a'; 5000], "918347c69490f04c08ed15c9711f5da336fac318892ef517e47f6c5c3f1c5811"; "two_blocks")]
+    #[test_case(&[b'a'; 4096 * 200], "bb9e4aa4428879f4f1d9479d2a851275cf119291c47879c9cef8bf1b4589a779"; "two_levels")]
+    #[tokio::test]
+    async fn fsverity_digest(content: &[u8], expected: &str) -> Result<()> {
+        let file = async_tempfile::TempFile::new().await?;
+        tokio::fs::write(file.file_path(), content).await?;
+
+        let digest = super::fsverity_digest(file.file_path()).await?;
+        pretty_assertions::assert_eq!(expected, digest.as_hex());
+        Ok(())
+    }
+
+    fn dir_header(mode: u32) -> tokio_tar::Header {
+        let mut header = tokio_tar::Header::new_gnu();
+        header.set_entry_type(tokio_tar::EntryType::Directory);
+        header.set_size(0);
+        header.set_mode(mode);
+        header.set_cksum();
+        header
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn apply_tarball_directory_modes() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut builder = tokio_tar::Builder::new(Vec::new());
+        builder
+            .append_data(&mut dir_header(0o1777), "tmp", tokio::io::empty())
+            .await?;
+        builder
+            .append_data(&mut dir_header(0o2775), "var/mail", tokio::io::empty())
+            .await?;
+        builder
+            .append_data(&mut dir_header(0o555), "readonly", tokio::io::empty())
+            .await?;
+        let tarball = builder.into_inner().await?;
+
+        let output = async_tempfile::TempDir::new().await?;
+        let stream = futures_lite::stream::once(Ok(Bytes::from(tarball)));
+        let filters = Filters::parse_glob(["**"])?;
+        apply_tarball(
+            ApplyOptions {
+                filters: &filters,
+                collisions: CaseCollisions::Error,
+                deleted: None,
+                limits: Limits::default(),
+            },
+            stream,
+            output.dir_path(),
+            |_| {},
+            |_, _| {},
+            |_, _| {},
+            |_, _| {},
+        )
+        .await?;
+
+        for (path, expected) in [("tmp", 0o1777), ("var/mail", 0o2775), ("readonly", 0o755)] {
+            let meta = tokio::fs::metadata(output.dir_path().join(path)).await?;
+            assert!(meta.is_dir(), "{path} must be a directory");
+            assert_eq!(
+                expected,
+                meta.permissions().mode() & 0o7777,
+                "mode of {path}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn apply_tarball_concurrent_files() -> Result<()> {
+        let mut builder = tokio_tar::Builder::new(Vec::new());
+        for i in 0..100 {
+            let content = format!("file {i}");
+            let mut header = tokio_tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(1_700_000_000);
+            header.set_cksum();
+            builder
+                .append_data(
+                    &mut header,
+                    format!("dir{}/file{i}", i % 7),
+                    content.as_bytes(),
+                )
+                .await?;
+        }
+
+        let mut header = tokio_tar::Header::new_gnu();
+        header.set_entry_type(tokio_tar::EntryType::Link);
+        header.set_size(0);
+        header.set_link_name("dir0/file0")?;
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "hardlink", tokio::io::empty())
+            .await?;
+
+        // Directory entries can come after the files inside them.
+        builder
+            .append_data(&mut dir_header(0o755), "dir1", tokio::io::empty())
+            .await?;
+        let tarball = builder.into_inner().await?;
+
+        let output = async_tempfile::TempDir::new().await?;
+        let stream = futures_lite::stream::once(Ok(Bytes::from(tarball)));
+        let filters = Filters::parse_glob(["**"])?;
+        let mut written = Vec::new();
+        apply_tarball(
+            ApplyOptions {
+                filters: &filters,
+                collisions: CaseCollisions::Error,
+                deleted: None,
+                limits: Limits::default(),
+            },
+            stream,
+            output.dir_path(),
+            |path| written.push(path.to_path_buf()),
+            |_, _| {},
+            |_, _| {},
+            |_, _| {},
+        )
+        .await?;
+
+        for i in 0..100 {
+            let path = output.dir_path().join(format!("dir{}/file{i}", i % 7));
+            let content = tokio::fs::read_to_string(&path).await?;
+            assert_eq!(format!("file {i}"), content, "content of {path:?}");
+        }
+
+        let meta = tokio::fs::metadata(output.dir_path().join("dir3/file3")).await?;
+        let mtime = meta.modified()?.duration_since(std::time::UNIX_EPOCH)?;
+        assert_eq!(1_700_000_000, mtime.as_secs());
+
+        let linked = tokio::fs::read_to_string(output.dir_path().join("hardlink")).await?;
+        assert_eq!("file 0", linked);
+
+        // Every file and the hard link are reported, but directories aren't.
+        assert_eq!(101, written.len());
+        Ok(())
+    }
+
+    #[test_case(Limits::default(), false; "unlimited")]
+    #[test_case(Limits::builder().max_files(3).max_file_size(100).build(), false; "at_limits")]
+    #[test_case(Limits::builder().max_layer_size(1024).build(), true; "layer_size")]
+    #[test_case(Limits::builder().max_files(2).build(), true; "files")]
+    #[test_case(Limits::builder().max_file_size(99).build(), true; "file_size")]
+    #[tokio::test]
+    async fn apply_tarball_limits(limits: Limits, exceeds: bool) -> Result<()> {
+        let mut builder = tokio_tar::Builder::new(Vec::new());
+        for i in 0..3 {
+            let mut header = tokio_tar::Header::new_gnu();
+            header.set_size(100);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, format!("file{i}"), [0u8; 100].as_slice())
+                .await?;
+        }
+        let tarball = builder.into_inner().await?;
+
+        let output = async_tempfile::TempDir::new().await?;
+        let stream = futures_lite::stream::once(Ok(Bytes::from(tarball)));
+        let filters = Filters::parse_glob(["**"])?;
+        let result = apply_tarball(
+            ApplyOptions {
+                filters: &filters,
+                collisions: CaseCollisions::Error,
+                deleted: None,
+                limits,
+            },
+            stream,
+            output.dir_path(),
+            |_| {},
+            |_, _| {},
+            |_, _| {},
+            |_, _| {},
+        )
+        .await;
+
+        match result {
+            Ok(()) => assert!(!exceeds, "must exceed limits"),
+            Err(err) => {
+                let err = crate::Error::from(err);
+                assert!(exceeds && matches!(err, crate::Error::Limit(_)), "{err:?}");
+            }
+        }
+        Ok(())
+    }
+
+    /// A layer with a small text file, a large text file, a binary file, a symlink, and a directory.
+    async fn predicate_layer() -> Result<Vec<u8>> {
+        let mut builder = tokio_tar::Builder::new(Vec::new());
+        for (path, content) in [
+            ("small.txt", b"hello".to_vec()),
+            ("large.txt", vec![b'a'; 100]),
+            ("binary", b"\x7fELF\0\0".to_vec()),
+        ] {
+            let mut header = tokio_tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, content.as_slice())
+                .await?;
+        }
+
+        let mut header = tokio_tar::Header::new_gnu();
+        header.set_entry_type(tokio_tar::EntryType::Symlink);
+        header.set_size(0);
+        header.set_mode(0o777);
+        header.set_link_name("small.txt")?;
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "link", tokio::io::empty())
+            .await?;
+
+        let mut header = tokio_tar::Header::new_gnu();
+        header.set_entry_type(tokio_tar::EntryType::Directory);
+        header.set_size(0);
+        header.set_mode(0o755);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "dir/", [].as_slice())
+            .await?;
+
+        builder.into_inner().await.map_err(Report::from)
+    }
+
+    #[test_case(vec![], &["binary", "dir", "large.txt", "link", "small.txt"]; "none")]
+    #[test_case(vec![Predicate::MaxSize(10)], &["binary", "dir", "link", "small.txt"]; "max_size")]
+    #[test_case(vec![Predicate::Types(vec![FileType::Regular])], &["binary", "large.txt", "small.txt"]; "regular")]
+    #[test_case(vec![Predicate::Types(vec![FileType::Symlink, FileType::Dir])], &["dir", "link"]; "symlink_dir")]
+    #[test_case(vec![Predicate::ExcludeBinary], &["dir", "large.txt", "link", "small.txt"]; "exclude_binary")]
+    #[test_case(vec![Predicate::ExcludeBinary, Predicate::MaxSize(10), Predicate::Types(vec![FileType::Regular])], &["small.txt"]; "all")]
+    #[tokio::test]
+    async fn apply_tarball_predicates(predicates: Vec<Predicate>, expected: &[&str]) -> Result<()> {
+        let tarball = predicate_layer().await?;
+        let filters = Filters::parse_glob(["**"])? + Filters::predicates(predicates);
+
+        let output = async_tempfile::TempDir::new().await?;
+        let stream = futures_lite::stream::once(Ok(Bytes::from(tarball.clone())));
+        apply_tarball(
+            ApplyOptions {
+                filters: &filters,
+                collisions: CaseCollisions::Error,
+                deleted: None,
+                limits: Limits::default(),
+            },
+            stream,
+            output.dir_path(),
+            |_| {},
+            |_, _| {},
+            |_, _| {},
+            |_, _| {},
+        )
+        .await?;
+
+        let mut applied = std::fs::read_dir(output.dir_path())?
+            .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().to_string()))
+            .collect::<Result<Vec<_>, _>>()?;
+        applied.sort();
+        pretty_assertions::assert_eq!(expected, applied, "applied");
+
+        let stream = futures_lite::stream::once(Ok(Bytes::from(tarball)));
+        let mut enumerated = enumerate_tarball(stream, &filters)
+            .await?
+            .into_iter()
+            .map(|path| path.trim_end_matches('/').to_string())
+            .collect::<Vec<_>>();
+        enumerated.sort();
+        pretty_assertions::assert_eq!(expected, enumerated, "enumerated");
+        Ok(())
+    }
+
+    // Layers in saved images (like those exported from the Docker daemon) can be many gigabytes,
+    // so they must be streamed from the tarball in chunks rather than read into memory.
+    #[tokio::test]
+    async fn extract_file_streams_in_chunks() -> Result<()> {
+        let file = async_tempfile::TempFile::new().await?;
+        tokio::fs::write(file.file_path(), predicate_layer().await?).await?;
+
+        let buffering = Buffering::builder()
+            .chunk_size(std::num::NonZeroUsize::new(16).expect("nonzero"))
+            .build();
+        let stream = extract_file(file.file_path(), buffering, |path| {
+            path == Path::new("large.txt")
+        })
+        .await?
+        .expect("file in tarball");
+        let chunks = stream
+            .map(|chunk| chunk.map(|chunk| chunk.len()))
+            .try_collect::<_, _, Vec<_>>()
+            .await?;
+
+        pretty_assertions::assert_eq!(100, chunks.iter().sum::<usize>());
+        assert!(chunks.iter().all(|&len| len <= 16), "chunks: {chunks:?}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn visit_tarball_stops_on_error() -> Result<()> {
+        let tarball = predicate_layer().await?;
+        let stream = futures_lite::stream::once(Ok(Bytes::from(tarball)));
+
+        let mut visited = Vec::new();
+        let result = visit_tarball(stream, &Filters::default(), |path| {
+            visited.push(path);
+            Err(std::io::Error::from(std::io::ErrorKind::BrokenPipe))
+        })
+        .await;
+
+        assert!(result.is_err(), "reporting error should stop enumeration");
+        pretty_assertions::assert_eq!(1, visited.len());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn apply_tarball_whiteouts() -> Result<()> {
+        let output = async_tempfile::TempDir::new().await?;
+        tokio::fs::create_dir_all(output.dir_path().join("etc")).await?;
+        tokio::fs::write(output.dir_path().join("etc/secret"), "hunter2").await?;
+
+        let mut builder = tokio_tar::Builder::new(Vec::new());
+        for path in ["etc/.wh.secret", "etc/.wh.missing"] {
+            let mut header = tokio_tar::Header::new_gnu();
+            header.set_size(0);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, tokio::io::empty())
+                .await?;
+        }
+        let tarball = builder.into_inner().await?;
+
+        let stream = futures_lite::stream::once(Ok(Bytes::from(tarball)));
+        let filters = Filters::parse_glob(["**"])?;
+        let mut whiteouts = Vec::new();
+        apply_tarball(
+            ApplyOptions {
+                filters: &filters,
+                collisions: CaseCollisions::Error,
+                deleted: None,
+                limits: Limits::default(),
+            },
+            stream,
+            output.dir_path(),
+            |_| {},
+            |_, _| {},
+            |path, existed| whiteouts.push((path.to_path_buf(), existed)),
+            |_, _| {},
+        )
+        .await?;
+
+        assert!(!tokio::fs::try_exists(output.dir_path().join("etc/secret")).await?);
+        pretty_assertions::assert_eq!(
+            vec![
+                (output.dir_path().join("etc/secret"), true),
+                (output.dir_path().join("etc/missing"), false),
+            ],
+            whiteouts
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn apply_tarball_recovers_deleted() -> Result<()> {
+        let output = async_tempfile::TempDir::new().await?;
+        let deleted = async_tempfile::TempDir::new().await?;
+        tokio::fs::create_dir_all(output.dir_path().join("etc/keys")).await?;
+        tokio::fs::write(output.dir_path().join("etc/secret"), "hunter2").await?;
+
+        let mut builder = tokio_tar::Builder::new(Vec::new());
+        for path in ["etc/.wh.secret", "etc/.wh.missing", "etc/.wh.keys"] {
+            let mut header = tokio_tar::Header::new_gnu();
+            header.set_size(0);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, tokio::io::empty())
+                .await?;
+        }
+        let tarball = builder.into_inner().await?;
+
+        let stream = futures_lite::stream::once(Ok(Bytes::from(tarball)));
+        let filters = Filters::parse_glob(["**"])?;
+        let mut recovered = Vec::new();
+        apply_tarball(
+            ApplyOptions {
+                filters: &filters,
+                collisions: CaseCollisions::Error,
+                deleted: Some(deleted.dir_path()),
+                limits: Limits::default(),
+            },
+            stream,
+            output.dir_path(),
+            |_| {},
+            |_, _| {},
+            |_, _| {},
+            |path, target| recovered.push((path.to_path_buf(), target.to_path_buf())),
+        )
+        .await?;
+
+        let target = tokio::fs::canonicalize(deleted.dir_path())
+            .await?
+            .join("etc/secret");
+        assert!(!tokio::fs::try_exists(output.dir_path().join("etc/secret")).await?);
+        assert_eq!("hunter2", tokio::fs::read_to_string(&target).await?);
+        assert_eq!(
+            vec![(output.dir_path().join("etc/secret"), target)],
+            recovered
+        );
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn link_tree_shares_files() -> Result<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        let src = async_tempfile::TempDir::new().await?;
+        tokio::fs::create_dir_all(src.dir_path().join("etc/ssl")).await?;
+        tokio::fs::write(src.dir_path().join("etc/os-release"), "ID=mock").await?;
+        tokio::fs::write(src.dir_path().join("etc/ssl/cert.pem"), "cert").await?;
+        symlink(
+            Path::new("../os-release"),
+            &src.dir_path().join("etc/ssl/link"),
+        )
+        .await?;
+
+        let dst = async_tempfile::TempDir::new().await?;
+        let linked = link_tree(src.dir_path(), &dst.dir_path().join("layer")).await?;
+        assert_eq!(2, linked);
+
+        let layer = dst.dir_path().join("layer");
+        for path in ["etc/os-release", "etc/ssl/cert.pem"] {
+            let original = tokio::fs::metadata(src.dir_path().join(path)).await?;
+            let copy = tokio::fs::metadata(layer.join(path)).await?;
+            assert_eq!(original.ino(), copy.ino(), "{path} is linked");
+        }
+        assert_eq!(
+            PathBuf::from("../os-release"),
+            tokio::fs::read_link(layer.join("etc/ssl/link")).await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rename_collision_is_deterministic() -> Result<()> {
+        let output = async_tempfile::TempDir::new().await?;
+        let path = output.dir_path().join("readme");
+        assert_eq!(
+            path.with_file_name("readme~1"),
+            rename_collision(&path, &Written::default()).await?
+        );
+
+        tokio::fs::write(path.with_file_name("readme~1"), "").await?;
+        assert_eq!(
+            path.with_file_name("readme~2"),
+            rename_collision(&path, &Written::default()).await?
+        );
+        Ok(())
+    }
+
+    // Linux filesystems are case-sensitive by default, so entries that only differ by case don't collide.
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn apply_tarball_case_sensitive() -> Result<()> {
+        let mut builder = tokio_tar::Builder::new(Vec::new());
+        for (path, content) in [("README", "upper"), ("readme", "lower")] {
+            let mut header = tokio_tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, content.as_bytes())
+                .await?;
+        }
+        let tarball = builder.into_inner().await?;
+
+        let output = async_tempfile::TempDir::new().await?;
+        assert!(!case_insensitive(output.dir_path()).await);
+
+        let stream = futures_lite::stream::once(Ok(Bytes::from(tarball)));
+        let filters = Filters::parse_glob(["**"])?;
+        let mut renamed = Vec::new();
+        apply_tarball(
+            ApplyOptions {
+                filters: &filters,
+                collisions: CaseCollisions::Rename,
+                deleted: None,
+                limits: Limits::default(),
+            },
+            stream,
+            output.dir_path(),
+            |_| {},
+            |path, _| renamed.push(path.to_path_buf()),
+            |_, _| {},
+            |_, _| {},
+        )
+        .await?;
+
+        assert!(renamed.is_empty(), "no entries renamed: {renamed:?}");
+        for (path, content) in [("README", "upper"), ("readme", "lower")] {
+            let written = tokio::fs::read_to_string(output.dir_path().join(path)).await?;
+            assert_eq!(content, written, "content of {path}");
+        }
+        Ok(())
+    }
+
     #[test_case(Path::new("/a/b/c"), Path::new("/a/b/d/e/f"), PathBuf::from("d/e/f"); "one_level")]
     #[test_case(Path::new("/usr/local/bin/ls"), Path::new("/bin/ls"), PathBuf::from("../../../bin/ls"); "usr_local_bin_to_bin")]
     #[test_case(Path::new("/usr/local/bin/ls"), Path::new("/usr/bin/ls"), PathBuf::from("../../bin/ls"); "usr_local_bin_to_usr_bin")]
@@ -454,4 +2101,62 @@ mod tests {
         pretty_assertions::assert_eq!(expected, relative);
         Ok(())
     }
+
+    fn filter_commands(commands: &[&str]) -> FilterCommands {
+        FilterCommands::parse(commands).expect("parse filter commands")
+    }
+
+    async fn filtered(commands: &FilterCommands, layer: &Layer) -> Result<String> {
+        let stream = futures_lite::stream::once(Ok(Bytes::from("hello layer")));
+        let stream = filter_layer(commands, layer, Box::pin(stream))?;
+        let mut output = String::new();
+        StreamReader::new(stream)
+            .read_to_string(&mut output)
+            .await?;
+        Ok(output)
+    }
+
+    #[tokio::test]
+    async fn filter_layer_pipes_through_command() -> Result<()> {
+        let gzip = Layer::builder()
+            .digest(Digest::from_hash(vec![0; 32]))
+            .size(0)
+            .media_type(LayerMediaType::Oci(vec![LayerMediaTypeFlag::Gzip]))
+            .build();
+        let plain = Layer {
+            media_type: LayerMediaType::Oci(vec![]),
+            ..gzip.clone()
+        };
+
+        let commands = filter_commands(&[
+            "tr a-z A-Z",
+            "application/vnd.oci.image.layer.v1.tar+gzip=FOO=bar tr l L",
+        ]);
+        assert_eq!("heLLo Layer", filtered(&commands, &gzip).await?);
+        assert_eq!("HELLO LAYER", filtered(&commands, &plain).await?);
+        assert_eq!(
+            "hello layer",
+            filtered(&filter_commands(&[]), &plain).await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn filter_layer_reports_failure() -> Result<()> {
+        let layer = Layer::builder()
+            .digest(Digest::from_hash(vec![0; 32]))
+            .size(0)
+            .media_type(LayerMediaType::Oci(vec![]))
+            .build();
+
+        let commands = filter_commands(&["cat; exit 3"]);
+        let err = filtered(&commands, &layer)
+            .await
+            .expect_err("command fails");
+        assert!(
+            format!("{err:?}").contains("exit status: 3"),
+            "unexpected error: {err:?}"
+        );
+        Ok(())
+    }
 }
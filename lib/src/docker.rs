@@ -1,315 +1,50 @@
 use std::{
-    collections::HashMap,
+    collections::HashSet,
     path::{Path, PathBuf},
     pin::Pin,
-    process::Stdio,
+    sync::Arc,
 };
 
 use crate::{
     cio::{
-        self, apply_tarball, collect_json, collect_tmp, enumerate_tarball, extract_file,
-        extract_json, file_digest, peel_layer,
+        apply_tarball, collect_json, collect_tmp, enumerate_tarball, extract_file, extract_json,
+        file_digest, filter_layer, is_dir, visit_tarball, walk_files, ApplyOptions,
     },
-    homedir,
-    transform::Chunk,
-    Authentication, Digest, FilterMatch, Filters, Layer, Reference, Source,
+    error::Kind,
+    ext::PriorityFind,
+    metrics,
+    progress::{report_bytes_decompressed, report_bytes_read, SharedProgress, Silent},
+    registry::{
+        current_platform_priority, is_artifact, is_attestation, is_image_config, manifest_layers,
+    },
+    transform::{peel_layer, Buffering, Chunk},
+    CaseCollisions, Digest, Error, FilterCommands, Filters, Identity, ImageConfig, Layer, Limits,
+    Platform, Result, Source, Subject, Unpack,
 };
 use async_tempfile::TempFile;
-use base64::Engine;
-use bollard::Docker;
 use bytes::Bytes;
 use color_eyre::{
-    eyre::{eyre, Context, Error, OptionExt, Result},
+    eyre::{self, Context, OptionExt, Report},
     Section, SectionExt,
 };
 use derive_more::Debug;
 use futures_lite::{Stream, StreamExt};
+use oci_client::manifest::{OciDescriptor, OciImageIndex};
 use serde::Deserialize;
-use tap::{Pipe, TapFallible};
-use tokio::{fs::File, io::AsyncWriteExt};
+use tap::Pipe;
+use tokio::fs::File;
 use tokio_tar::{Archive, Entry};
 use tokio_util::io::ReaderStream;
-use tracing::{debug, info, warn};
-
-impl Authentication {
-    /// Read authentication information for the host from the configured Docker credentials, if any.
-    ///
-    /// Reference:
-    /// - https://docs.docker.com/reference/cli/docker/login
-    /// - https://github.com/docker/docker-credential-helpers
-    pub async fn docker(target: &Reference) -> Result<Self> {
-        match Self::docker_internal(target).await {
-            Ok(auth) => {
-                debug!("inferred docker auth: {auth:?}");
-                Ok(auth)
-            }
-            Err(err) => {
-                warn!(?err, "unable to infer docker auth; trying unauthenticated");
-                Ok(Authentication::None)
-            }
-        }
-    }
-
-    async fn docker_internal(target: &Reference) -> Result<Self> {
-        let host = &target.host;
-        let path = homedir()
-            .context("get home directory")?
-            .join(".docker")
-            .join("config.json");
-
-        let config = tokio::fs::read_to_string(&path)
-            .await
-            .context("read docker config")
-            .with_section(|| path.display().to_string().header("Config file path:"))?;
-
-        serde_json::from_str::<DockerConfig>(&config)
-            .context("parse docker config")
-            .with_section(|| path.display().to_string().header("Config file path:"))
-            .with_section(|| config.header("Config file content:"))?
-            .auth(host)
-            .await
-            .tap_ok(|auth| info!("inferred docker auth: {auth:?}"))
-    }
-}
-
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct DockerConfig {
-    /// The default credential store.
-    ///
-    /// The value of the config property is the suffix of the program to use (i.e. everything after `docker-credential-`).
-    creds_store: Option<String>,
-
-    /// Credential stores per host.
-    ///
-    /// Credential helpers are specified in a similar way to credsStore, but allow for multiple helpers to be configured at a time.
-    /// Keys specify the registry domain, and values specify the suffix of the program to use (i.e. everything after docker-credential-).
-    #[serde(default)]
-    cred_helpers: HashMap<String, String>,
-
-    /// Logged in hosts.
-    #[serde(default)]
-    auths: HashMap<String, DockerAuth>,
-}
-
-impl DockerConfig {
-    /// Some hosts have fallback keys.
-    /// Given a host, this function returns an iterator representing fallback keys to check for authentication.
-    fn auth_keys(host: &str) -> impl Iterator<Item = &str> {
-        if host == "docker.io" {
-            vec!["docker.io", "https://index.docker.io/v1/"]
-        } else {
-            vec![host]
-        }
-        .into_iter()
-    }
-
-    /// Returns the auth for the host.
-    ///
-    /// Some hosts have fallback keys; the host that actually was used to retrieve the auth
-    /// is returned so that if it was a fallback key the correct key can be used to
-    /// retrieve auth information in subsequent operations.
-    async fn auth(&self, host: &str) -> Result<Authentication> {
-        for key in Self::auth_keys(host) {
-            if let Some(auth) = self.auths.get(key) {
-                match auth.decode(self, key).await {
-                    Ok(auth) => return Ok(auth),
-                    Err(err) => {
-                        warn!("failed decoding auth for host {key:?}: {err:?}");
-                        continue;
-                    }
-                }
-            }
-        }
-
-        Ok(Authentication::None)
-    }
-}
-
-#[derive(Debug, Deserialize)]
-#[serde(untagged)]
-enum DockerAuth {
-    /// The credentials are stored in plain text, not in a helper.
-    Plain {
-        /// Base64 encoded authentication credentials in the form of `username:password`.
-        auth: String,
-    },
-
-    /// The credentials are stored in a helper.
-    /// Use the host with the top level [`DockerConfig`] to determine which helper to use.
-    Helper {},
-}
+use tracing::{debug, info};
 
-impl DockerAuth {
-    async fn decode(&self, config: &DockerConfig, host: &str) -> Result<Authentication> {
-        match self {
-            DockerAuth::Plain { auth } => Self::decode_plain(auth),
-            DockerAuth::Helper {} => Self::decode_helper(config, host).await,
-        }
-    }
-
-    fn decode_plain(auth: &str) -> Result<Authentication> {
-        let auth = base64::engine::general_purpose::STANDARD
-            .decode(auth)
-            .context("decode base64 auth key")?;
-        let auth = String::from_utf8(auth).context("parse auth key as utf-8")?;
-        let (username, password) = auth
-            .split_once(':')
-            .ok_or_eyre("invalid auth key format, expected username:password")?;
-        Ok(Authentication::basic(username, password))
-    }
-
-    async fn decode_helper(config: &DockerConfig, host: &str) -> Result<Authentication> {
-        let helper = config
-            .cred_helpers
-            .get(host)
-            .or(config.creds_store.as_ref())
-            .ok_or_eyre("no helper found for host")?;
-
-        let binary = format!("docker-credential-{helper}");
-        let mut exec = tokio::process::Command::new(&binary)
-            .arg("get")
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-            .context("spawn docker credential helper")
-            .with_section(|| binary.clone().header("Helper binary:"))?;
-
-        if let Some(mut stdin) = exec.stdin.take() {
-            stdin
-                .write_all(host.as_bytes())
-                .await
-                .context("write request to helper")?;
-            drop(stdin);
-        }
-
-        let output = exec.wait_with_output().await.context("run helper")?;
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-            let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-            return Err(eyre!("auth helper failed with status: {}", output.status))
-                .with_section(|| binary.clone().header("Helper binary:"))
-                .with_section(|| host.to_string().header("Host:"))
-                .with_section(|| output.status.to_string().header("Command status code:"))
-                .with_section(|| stderr.header("Stderr:"))
-                .with_section(|| stdout.header("Stdout:"));
-        }
-
-        let credential = serde_json::from_slice::<DockerCredential>(&output.stdout)
-            .context("decode helper output")
-            .with_section(|| binary.header("Helper binary:"))?;
-        Ok(Authentication::basic(
-            credential.username,
-            credential.secret,
-        ))
-    }
-}
-
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "PascalCase")]
-struct DockerCredential {
-    username: String,
-    secret: String,
-}
-
-/// Each instance is a unique view of a local Docker daemon for a specific [`Reference`].
-/// Similar to [`crate::registry::Registry`], but interacts with a local Docker daemon.
-#[derive(Debug)]
-pub struct Daemon {
-    /// The file on disk representing the exported container.
-    ///
-    /// This is referenced in [`Tarball`] by path; in order to keep tarball generic
-    /// it doesn't actually take ownership of the tempfile handle itself.
-    #[debug(skip)]
-    _exported: TempFile,
-
-    /// References the exported local tarball.
-    tarball: Tarball,
-}
-
-#[bon::bon]
-impl Daemon {
-    /// Create a new daemon for a specific reference.
-    #[builder]
-    #[tracing::instrument(name = "Daemon::new")]
-    pub async fn new(
-        /// Filters for layers.
-        /// Layers that match any filter are excluded from the set of layers processed.
-        #[builder(into)]
-        layer_filters: Option<Filters>,
-
-        /// Filters for files.
-        /// Files that match any filter are excluded from the set of files processed.
-        #[builder(into)]
-        file_filters: Option<Filters>,
-
-        /// The reference for the image the user provided.
-        #[builder(into)]
-        reference: String,
-    ) -> Result<Self> {
-        crate::flag_disabled_daemon_docker()?;
+#[cfg(feature = "docker-auth")]
+mod auth;
 
-        let docker = Docker::connect_with_local_defaults().context("connect to docker daemon")?;
-        let image = find_image(&docker, &reference)
-            .await
-            .context("find image")?;
-
-        let stream = docker.export_image(&image);
-        let exported = cio::collect_tmp(stream)
-            .await
-            .context("collect exported image")?;
+#[cfg(feature = "daemon")]
+mod daemon;
 
-        debug!(exported = ?exported.file_path(), "exported temporary image");
-        let tarball = Tarball::builder()
-            .maybe_file_filters(file_filters)
-            .maybe_layer_filters(layer_filters)
-            .name(image)
-            .path(exported.file_path())
-            .build()
-            .await
-            .context("create tarball")?;
-
-        debug!(tarball = ?tarball.path, "created tarball");
-        Ok(Self {
-            _exported: exported,
-            tarball,
-        })
-    }
-}
-
-impl Source for Daemon {
-    async fn digest(&self) -> Result<Digest> {
-        self.tarball.digest().await
-    }
-
-    async fn name(&self) -> Result<String> {
-        self.tarball.name().await
-    }
-
-    async fn layers(&self) -> Result<Vec<Layer>> {
-        self.tarball.layers().await
-    }
-
-    async fn pull_layer(
-        &self,
-        layer: &Layer,
-    ) -> Result<Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>> {
-        self.tarball.pull_layer(layer).await
-    }
-
-    async fn list_files(&self, layer: &Layer) -> Result<Vec<String>> {
-        self.tarball.list_files(layer).await
-    }
-
-    async fn apply_layer(&self, layer: &Layer, output: &Path) -> Result<()> {
-        self.tarball.apply_layer(layer, output).await
-    }
-
-    async fn layer_plain_tarball(&self, layer: &Layer) -> Result<Option<TempFile>> {
-        self.tarball.layer_plain_tarball(layer).await
-    }
-}
+#[cfg(feature = "daemon")]
+pub use daemon::{Connection, Daemon};
 
 /// An implementation of [`Source`] that reads from a local docker tarball.
 ///
@@ -320,9 +55,12 @@ impl Source for Daemon {
 /// Circe only interacts with the OCI format.
 ///
 /// If the tarball is legacy format, extraction will fail.
+///
+/// The path may also be a directory holding an OCI image layout (`index.json` and `blobs/`),
+/// like one written by `skopeo copy docker://<image> oci:<dir>`; it is read the same way as the tarball.
 #[derive(Debug)]
 pub struct Tarball {
-    /// Path to the Docker tarball file.
+    /// Path to the Docker tarball file, or the directory of an OCI image layout.
     path: PathBuf,
 
     /// The parsed manifest from the tarball.
@@ -335,12 +73,32 @@ pub struct Tarball {
     name: String,
 
     /// Layer filters.
-    /// Layers that match any filter are excluded from the set of layers processed.
+    /// Only layers matched by the filters are processed; see [`Filters`].
     layer_filters: Filters,
 
     /// File filters.
-    /// Files that match any filter are excluded from the set of files processed.
+    /// Only files matched by the filters are processed; see [`Filters`].
     file_filters: Filters,
+
+    /// How to handle paths that collide on case-insensitive filesystems when layers are applied.
+    case_collisions: CaseCollisions,
+
+    /// Directory to which paths deleted by whiteouts are moved instead of being removed,
+    /// in a subdirectory for each layer named after the hex of its digest.
+    deleted_dir: Option<PathBuf>,
+
+    /// Commands through which layers are piped after they are decompressed.
+    filter_commands: FilterCommands,
+
+    /// Applying a layer fails if it exceeds any of these limits.
+    limits: Limits,
+
+    /// Receives progress updates as layers are read and applied.
+    #[debug(skip)]
+    progress: SharedProgress,
+
+    /// How layer content is buffered as it's read and unpacked.
+    buffering: Buffering,
 }
 
 #[bon::bon]
@@ -352,36 +110,85 @@ impl Tarball {
         #[builder(into)]
         name: String,
 
-        /// Path to the Docker tarball file.
+        /// Path to the Docker tarball file, or the directory of an OCI image layout.
         #[builder(into)]
         path: PathBuf,
 
+        /// The platform of the image to read, if the tarball contains images for multiple platforms.
+        /// If not provided, the image is chosen the same way as for [`crate::registry::Registry`];
+        /// if the tarball only contains one image, this is ignored.
+        platform: Option<Platform>,
+
+        /// The digest of the manifest to read, selecting it without platform resolution.
+        /// This also allows reading manifests that are otherwise skipped, like attestations.
+        manifest_digest: Option<Digest>,
+
         /// Filters for layers.
-        /// Layers that match any filter are excluded from the set of layers processed.
+        /// Only layers matched by the filters are processed; see [`Filters`].
         #[builder(into)]
         layer_filters: Option<Filters>,
 
         /// Filters for files.
-        /// Files that match any filter are excluded from the set of files processed.
+        /// Only files matched by the filters are processed; see [`Filters`].
         #[builder(into)]
         file_filters: Option<Filters>,
+
+        /// How to handle paths that collide on case-insensitive filesystems when layers are applied.
+        case_collisions: Option<CaseCollisions>,
+
+        /// Directory to which paths deleted by whiteouts are moved instead of being removed,
+        /// so that content deleted by later layers can still be inspected.
+        /// Each layer's deletions are moved to a subdirectory named after the hex of its digest.
+        #[builder(into)]
+        deleted_dir: Option<PathBuf>,
+
+        /// Commands through which layers are piped after they are decompressed.
+        filter_commands: Option<FilterCommands>,
+
+        /// Safety limits enforced as layers are applied; by default layers aren't limited.
+        limits: Option<Limits>,
+
+        /// Receives progress updates as layers are read and applied.
+        progress: Option<SharedProgress>,
+
+        /// How layer content is buffered as it's read and unpacked.
+        buffering: Option<Buffering>,
     ) -> Result<Self> {
         if !path.exists() {
-            return Err(eyre!("Docker tarball not found: {}", path.display()))
-                .with_section(|| path.display().to_string().header("Path:"));
+            return Err(Report::new(Kind::NotFound))
+                .with_context(|| format!("Docker tarball not found: {}", path.display()))
+                .with_section(|| path.display().to_string().header("Path:"))
+                .map_err(Error::from);
         }
 
         let digest = digest(&path).await.context("compute digest")?;
         let manifests = DockerManifest::peel(&path)
             .await
             .context("peel manifests")?;
-        let manifest = manifests.first().cloned().ok_or_eyre("no manifest found")?;
-        if manifests.len() > 1 {
-            tracing::warn!(
-                ?manifests,
-                "multiple manifests found in tarball, using first one"
-            );
-        }
+        let manifests = match manifest_digest {
+            Some(digest) => {
+                select_digest(manifests, &digest).context("select manifest by digest")?
+            }
+            None => manifests
+                .into_iter()
+                .filter(|manifest| {
+                    if manifest.attestation {
+                        debug!(digest = ?manifest.digest, "skip attestation manifest");
+                    }
+                    !manifest.attestation
+                })
+                .collect(),
+        };
+        let manifest = match manifests.len() {
+            0 => return Err(eyre::eyre!("no manifest found")).map_err(Error::from),
+            1 => manifests
+                .into_iter()
+                .next()
+                .ok_or_eyre("no manifest found")?,
+            _ => select_manifest(&path, manifests, platform.as_ref())
+                .await
+                .context("select manifest for platform")?,
+        };
 
         Ok(Self {
             path,
@@ -390,27 +197,266 @@ impl Tarball {
             name,
             layer_filters: layer_filters.unwrap_or_default(),
             file_filters: file_filters.unwrap_or_default(),
+            case_collisions: case_collisions.unwrap_or_default(),
+            deleted_dir,
+            filter_commands: filter_commands.unwrap_or_default(),
+            limits: limits.unwrap_or_default(),
+            progress: progress.unwrap_or_else(|| Arc::new(Silent)),
+            buffering: buffering.unwrap_or_default(),
         })
     }
 }
 
 impl Tarball {
-    async fn pull_layer_internal(&self, layer: &Layer) -> Result<impl Stream<Item = Chunk>> {
+    /// The digests of the layers of the image that aren't in the tarball, in the order they're applied.
+    ///
+    /// Tarballs written by `docker save` contain every layer, but OCI layouts that are seeded by other tools
+    /// may only contain some of the blobs an image refers to. Only layers selected by the layer filters are checked.
+    pub async fn missing_layers(&self) -> Result<Vec<Digest>> {
+        let entries = entries(&self.path).await?;
+        let layers = self.layers().await?;
+        layers
+            .into_iter()
+            .map(|layer| layer.digest)
+            .filter(|digest| {
+                let name = digest.as_hex();
+                !entries.iter().any(|entry| entry.ends_with(&name))
+            })
+            .collect::<Vec<_>>()
+            .pipe(Ok)
+    }
+
+    async fn pull_layer_internal(&self, layer: &Layer) -> eyre::Result<impl Stream<Item = Chunk>> {
         let name = layer.digest.as_hex();
-        extract_file(&self.path, move |path| path.ends_with(&name))
-            .await
-            .context("extract layer tarball")?
-            .ok_or_eyre("layer not found")
+        extract_file(&self.path, self.buffering, move |path| {
+            path.ends_with(&name)
+        })
+        .await
+        .context("extract layer tarball")?
+        .ok_or_eyre("layer not found")
+        .map(|stream| report_bytes_read(self.progress.clone(), layer.clone(), stream))
+    }
+
+    /// Read the layer and decompress it into a plain tarball, piping it through its filter command if any.
+    /// Foreign layers are emitted as `None`.
+    async fn plain_layer(
+        &self,
+        layer: &Layer,
+    ) -> eyre::Result<Option<Pin<Box<dyn Stream<Item = Chunk>>>>> {
+        let stream = self.pull_layer_internal(layer).await?;
+        peel_layer(layer, stream, self.buffering)
+            .map(|stream| report_bytes_decompressed(self.progress.clone(), layer.clone(), stream))
+            .map(|stream| filter_layer(&self.filter_commands, layer, stream))
+            .transpose()
+    }
+}
+
+/// The paths of the files in the tarball, or in the directory of an OCI image layout relative to it.
+async fn entries(tarball: &Path) -> eyre::Result<Vec<PathBuf>> {
+    if is_dir(tarball).await {
+        return walk_files(tarball)
+            .await?
+            .into_iter()
+            .map(|path| path.strip_prefix(tarball).map(Path::to_path_buf))
+            .collect::<Result<_, _>>()
+            .context("list layout files");
+    }
+
+    let file = File::open(tarball).await.context("open docker tarball")?;
+    enumerate_tarball(ReaderStream::new(file), &Filters::default())
+        .await
+        .context("list tarball entries")
+        .map(|entries| entries.into_iter().map(PathBuf::from).collect())
+}
+
+/// Select the manifest with the digest from the manifests in the tarball.
+fn select_digest(
+    manifests: Vec<DockerManifest>,
+    digest: &Digest,
+) -> eyre::Result<Vec<DockerManifest>> {
+    let digest = digest.to_string();
+    let available = manifests
+        .iter()
+        .filter_map(|manifest| manifest.digest.clone())
+        .collect::<Vec<_>>();
+    match manifests
+        .into_iter()
+        .find(|manifest| manifest.digest.as_ref() == Some(&digest))
+    {
+        Some(manifest) => Ok(vec![manifest]),
+        None => Err(Report::new(Kind::NotFound))
+            .with_context(|| format!("no manifest with digest {digest} in tarball"))
+            .with_section(|| available.join("\n").header("Manifests:")),
+    }
+}
+
+/// Read the config of the image described by the manifest from the tarball.
+///
+/// Artifacts whose config doesn't describe an image report an empty config.
+async fn read_config(tarball: &Path, manifest: &DockerManifest) -> eyre::Result<ImageConfig> {
+    let descriptor = manifest
+        .config
+        .as_ref()
+        .ok_or_eyre("manifest does not reference a config")?;
+    if let Some(media_type) = descriptor.media_type.as_deref() {
+        if !is_image_config(media_type) {
+            debug!(%media_type, "config doesn't describe an image");
+            return Ok(ImageConfig::default());
+        }
+    }
+
+    let name = descriptor.digest.as_hex();
+    extract_json(tarball, move |path| path.ends_with(&name))
+        .await
+        .context("extract image config")?
+        .ok_or_eyre("image config not found")
+}
+
+/// Select the manifest of the image for the platform from the manifests in the tarball,
+/// based on the platform recorded in the config of each image.
+///
+/// Like [`crate::registry::Registry`], the image whose platform best suits the requested one is chosen
+/// (see [`Platform::score`]), and if no platform is provided the image is chosen by the same priority
+/// (preferring platform-independent images, then the current platform).
+/// Images whose config doesn't record a platform are assumed to match, since there's no way to tell otherwise.
+async fn select_manifest(
+    tarball: &Path,
+    manifests: Vec<DockerManifest>,
+    platform: Option<&Platform>,
+) -> eyre::Result<DockerManifest> {
+    let mut images = Vec::new();
+    for manifest in manifests {
+        let config = read_config(tarball, &manifest).await.unwrap_or_else(|err| {
+            debug!(?err, "unable to read image config");
+            ImageConfig::default()
+        });
+        images.push((manifest, config));
+    }
+
+    let Some(platform) = platform else {
+        return images
+            .into_iter()
+            .priority_find(|(_, config)| match recorded_platform(config) {
+                // Attestation manifests (e.g. provenance from buildkit) are recorded with an unknown platform.
+                Some((UNKNOWN_PLATFORM, _)) => usize::MAX,
+                recorded => current_platform_priority(recorded),
+            })
+            .map(|(manifest, _)| manifest)
+            .ok_or_eyre("no manifest found");
+    };
+
+    let score = |config: &ImageConfig| match config_platform(config) {
+        Some(recorded) => platform.score(&recorded),
+        None => Some(0),
+    };
+    let best = images
+        .iter()
+        .enumerate()
+        .filter_map(|(position, (_, config))| score(config).map(|score| (score, position)))
+        .min_by_key(|(score, _)| *score);
+    if let Some((_, position)) = best {
+        return Ok(images.swap_remove(position).0);
     }
+
+    let available = images
+        .iter()
+        .filter_map(|(_, config)| config_platform(config))
+        .filter(|recorded| recorded.os != UNKNOWN_PLATFORM)
+        .map(|recorded| recorded.to_string())
+        .collect::<Vec<_>>();
+    Err(Report::new(Kind::NotFound))
+        .with_context(|| format!("no image for platform {platform} in tarball"))
+        .with_section(|| available.join("\n").header("Platforms:"))
 }
 
+/// The `(os, architecture)` recorded in the config, if any.
+fn recorded_platform(config: &ImageConfig) -> Option<(&str, &str)> {
+    match (&config.os, &config.architecture) {
+        (Some(os), Some(arch)) => Some((os.as_str(), arch.as_str())),
+        _ => None,
+    }
+}
+
+/// The platform recorded in the config, including its variant, if any.
+fn config_platform(config: &ImageConfig) -> Option<Platform> {
+    let (os, architecture) = recorded_platform(config)?;
+    Platform::builder()
+        .os(os)
+        .architecture(architecture)
+        .maybe_variant(config.variant.as_deref())
+        .maybe_os_version(config.os_version.as_deref())
+        .build()
+        .pipe(Some)
+}
+
+/// The platform component recorded for images that don't run on any platform, like attestations.
+const UNKNOWN_PLATFORM: &str = "unknown";
+
 /// A Docker OCI manifest.
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(try_from = "RawManifest")]
 struct DockerManifest {
+    /// The config blob for the image.
+    config: Option<ConfigDescriptor>,
+
     /// The layers in the manifest.
+    /// Blobs in artifacts that aren't filesystem layers are skipped; see [`manifest_layers`].
     #[debug(skip)]
     layers: Vec<Layer>,
+
+    /// The manifest this one refers to, for artifacts like signatures and SBOMs.
+    subject: Option<Subject>,
+
+    /// The digest of the manifest, if it was read from a blob in the OCI layout.
+    digest: Option<String>,
+
+    /// Whether an index in the tarball marks the manifest as an attestation.
+    attestation: bool,
+}
+
+/// A manifest as written in the tarball, before its layers are parsed.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawManifest {
+    config: Option<ConfigDescriptor>,
+    layers: Vec<OciDescriptor>,
+
+    #[serde(default)]
+    subject: Option<Subject>,
+
+    #[serde(default)]
+    artifact_type: Option<String>,
+}
+
+impl TryFrom<RawManifest> for DockerManifest {
+    type Error = Report;
+
+    fn try_from(manifest: RawManifest) -> Result<Self, Self::Error> {
+        let config_media_type = manifest
+            .config
+            .as_ref()
+            .and_then(|config| config.media_type.as_deref());
+        let artifact = is_artifact(manifest.artifact_type.as_deref(), config_media_type);
+        Ok(Self {
+            layers: manifest_layers(manifest.layers, artifact)?,
+            config: manifest.config,
+            subject: manifest.subject,
+            digest: None,
+            attestation: false,
+        })
+    }
+}
+
+/// Points to the config blob in a [`DockerManifest`].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ConfigDescriptor {
+    /// The content-addressable digest of the config blob.
+    digest: Digest,
+
+    /// The media type of the config blob; artifacts have configs that don't describe an image.
+    #[serde(default)]
+    media_type: Option<String>,
 }
 
 impl DockerManifest {
@@ -492,24 +538,65 @@ impl DockerManifest {
     ///
     /// So when we "peel" the manifest, this means that the program searches all the JSON files
     /// inside the tarball for valid manifests.
+    ///
+    /// Indexes found along the way are used to mark attestation manifests (e.g. provenance from buildkit),
+    /// which are valid manifests but describe the image rather than being one.
     // #[tracing::instrument]
-    async fn peel(tarball: &Path) -> Result<Vec<DockerManifest>> {
+    async fn peel(tarball: &Path) -> eyre::Result<Vec<DockerManifest>> {
+        let peeled = if is_dir(tarball).await {
+            Self::peel_dir(tarball).await?
+        } else {
+            Self::peel_tarball(tarball).await?
+        };
+
+        // Indexes may appear after the manifests they reference, so attestations are only known once every entry is read.
+        let attestations = peeled
+            .iter()
+            .filter_map(|(_, peeled)| match peeled {
+                Peeled::Index(index) => Some(index),
+                Peeled::Manifest(_) => None,
+            })
+            .flat_map(|index| index.manifests.iter())
+            .filter(|entry| is_attestation(entry))
+            .map(|entry| entry.digest.clone())
+            .collect::<HashSet<_>>();
+
+        let manifests = peeled
+            .into_iter()
+            .filter_map(|(path, peeled)| match peeled {
+                Peeled::Manifest(manifest) => Some((path, manifest)),
+                Peeled::Index(_) => None,
+            })
+            .map(|(path, mut manifest)| {
+                manifest.digest = blob_digest(&path);
+                manifest.attestation = manifest
+                    .digest
+                    .as_ref()
+                    .is_some_and(|digest| attestations.contains(digest));
+                manifest
+            })
+            .collect();
+        Ok(manifests)
+    }
+
+    /// Parse each JSON file in the tarball that is a manifest or an index.
+    async fn peel_tarball(tarball: &Path) -> eyre::Result<Vec<(PathBuf, Peeled)>> {
         let archive = tokio::fs::File::open(tarball)
             .await
             .context("open docker tarball")?;
 
         let mut archive = Archive::new(archive);
         archive.entries().context("read entries")?.then(
-            async |entry: Result<Entry<Archive<File>>, std::io::Error>| -> Result<Option<DockerManifest>> {
+            async |entry: Result<Entry<Archive<File>>, std::io::Error>| -> eyre::Result<Option<(PathBuf, Peeled)>> {
                 let entry = entry.context("read tarball entry")?;
                 let path = entry.path().context("read entry path")?.to_path_buf();
                 info!(?path, "evaluate for manifest");
 
                 // If there's a parse error, it just means
-                // the file wasn't an OCI manifest file.
+                // the file wasn't an OCI manifest or index file.
                 let stream = ReaderStream::new(entry);
                 match collect_json(stream).await {
-                    Ok(manifest) => Ok(Some(manifest)),
+                    Ok(peeled) => Ok(Some((path, peeled))),
                     Err(err) => {
                         debug!(?path, ?err, "error parsing manifest");
                         Ok(None)
@@ -517,11 +604,45 @@ impl DockerManifest {
                 }
             },
         )
-        .filter_map(|manifest| manifest.transpose())
-        .try_collect::<_, Error, Vec<_>>()
+        .filter_map(|peeled| peeled.transpose())
+        .try_collect::<_, eyre::Error, Vec<_>>()
         .await
         .context("search archive for manifests")
     }
+
+    /// Parse each JSON file in the directory of an OCI image layout that is a manifest or an index,
+    /// with its path relative to the directory.
+    async fn peel_dir(dir: &Path) -> eyre::Result<Vec<(PathBuf, Peeled)>> {
+        let mut peeled = Vec::new();
+        for path in walk_files(dir).await.context("list layout files")? {
+            let relative = path.strip_prefix(dir).unwrap_or(&path).to_path_buf();
+            info!(path = ?relative, "evaluate for manifest");
+
+            let file = File::open(&path)
+                .await
+                .with_context(|| format!("open {path:?}"))?;
+            match collect_json(ReaderStream::new(file)).await {
+                Ok(manifest) => peeled.push((relative, manifest)),
+                Err(err) => debug!(path = ?relative, ?err, "error parsing manifest"),
+            }
+        }
+        Ok(peeled)
+    }
+}
+
+/// A JSON file in the tarball of interest to [`DockerManifest::peel`].
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Peeled {
+    Manifest(DockerManifest),
+    Index(OciImageIndex),
+}
+
+/// The digest of the blob at the path, if it is in the OCI layout (`blobs/<algorithm>/<hex>`).
+fn blob_digest(path: &Path) -> Option<String> {
+    let hex = path.file_name()?.to_str()?;
+    let algorithm = path.parent()?.file_name()?.to_str()?;
+    Some(format!("{algorithm}:{hex}"))
 }
 
 impl Source for Tarball {
@@ -533,109 +654,125 @@ impl Source for Tarball {
         Ok(self.name.clone())
     }
 
+    async fn identity(&self) -> Result<Identity> {
+        Ok(Identity::builder()
+            .reference(self.path.display().to_string())
+            .resolved_digest(self.digest.clone())
+            .display_name(&self.name)
+            .build())
+    }
+
     async fn layers(&self) -> Result<Vec<Layer>> {
-        self.manifest
-            .layers
-            .iter()
-            .filter(|&layer| !self.layer_filters.matches(layer))
-            .cloned()
-            .collect::<Vec<_>>()
+        let config = self.config().await.unwrap_or_else(|err| {
+            debug!(
+                ?err,
+                "unable to read image config, layers won't report diff IDs"
+            );
+            ImageConfig::default()
+        });
+
+        Layer::with_diff_ids(self.manifest.layers.clone(), &config)
+            .pipe(|layers| self.layer_filters.select(layers))
             .pipe(Ok)
     }
 
+    async fn config(&self) -> Result<ImageConfig> {
+        read_config(&self.path, &self.manifest)
+            .await
+            .map_err(Error::from)
+    }
+
+    async fn subject(&self) -> Result<Option<Subject>> {
+        Ok(self.manifest.subject.clone())
+    }
+
     async fn pull_layer(
         &self,
         layer: &Layer,
     ) -> Result<Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>> {
         let stream = self.pull_layer_internal(layer).await?;
-        Ok(Box::pin(stream.map(|chunk| chunk.context("read chunk"))))
+        Ok(Box::pin(stream.map(|chunk| {
+            chunk.context("read chunk").map_err(Error::from)
+        })))
     }
+}
 
+impl Unpack for Tarball {
     async fn list_files(&self, layer: &Layer) -> Result<Vec<String>> {
-        let stream = self.pull_layer_internal(layer).await?;
-        match peel_layer(layer, stream) {
-            Some(stream) => enumerate_tarball(stream).await,
+        match self.plain_layer(layer).await? {
+            Some(stream) => enumerate_tarball(stream, &self.file_filters)
+                .await
+                .map_err(Error::from),
             None => Ok(vec![]),
         }
     }
 
+    async fn visit_files(
+        &self,
+        layer: &Layer,
+        on_file: impl FnMut(String) -> std::io::Result<()>,
+    ) -> Result<()> {
+        match self.plain_layer(layer).await? {
+            Some(stream) => visit_tarball(stream, &self.file_filters, on_file)
+                .await
+                .map_err(Error::from),
+            None => Ok(()),
+        }
+    }
+
     async fn apply_layer(&self, layer: &Layer, output: &Path) -> Result<()> {
-        let stream = self.pull_layer_internal(layer).await?;
-        match peel_layer(layer, stream) {
-            Some(stream) => apply_tarball(&self.file_filters, stream, output).await,
+        match self.plain_layer(layer).await? {
+            Some(stream) => {
+                let on_write = |path: &Path| self.progress.file_written(layer, path);
+                let on_rename =
+                    |path: &Path, renamed: &Path| self.progress.file_renamed(layer, path, renamed);
+                let on_whiteout = |path: &Path, existed: bool| {
+                    self.progress.whiteout_applied(layer, path, existed)
+                };
+                let on_recover = |path: &Path, recovered: &Path| {
+                    self.progress.file_recovered(layer, path, recovered)
+                };
+
+                // Each layer's deletions are kept separately, since a path may be deleted by more than one layer.
+                let deleted = self
+                    .deleted_dir
+                    .as_ref()
+                    .map(|dir| dir.join(layer.digest.as_hex()));
+                let opts = ApplyOptions {
+                    filters: &self.file_filters,
+                    collisions: self.case_collisions,
+                    deleted: deleted.as_deref(),
+                    limits: self.limits,
+                };
+                apply_tarball(
+                    opts,
+                    stream,
+                    output,
+                    on_write,
+                    on_rename,
+                    on_whiteout,
+                    on_recover,
+                )
+                .await
+                .inspect(|()| metrics::record_layer_applied())
+                .map_err(Error::from)
+            }
             None => Ok(()),
         }
     }
 
     async fn layer_plain_tarball(&self, layer: &Layer) -> Result<Option<TempFile>> {
-        let stream = self.pull_layer_internal(layer).await?;
-        match peel_layer(layer, stream) {
-            Some(stream) => collect_tmp(stream).await.map(Some),
+        match self.plain_layer(layer).await? {
+            Some(stream) => collect_tmp(stream).await.map(Some).map_err(Error::from),
             None => Ok(None),
         }
     }
 }
 
-/// Find the ID of the image for the specified reference in the Docker daemon, if it exists.
-/// If it doesn't exist, this function returns an error.
-#[tracing::instrument]
-async fn find_image(docker: &Docker, reference: &str) -> Result<String> {
-    let opts = bollard::image::ListImagesOptions::<String> {
-        all: true,
-        ..Default::default()
-    };
-
-    let images = docker
-        .list_images(Some(opts))
-        .await
-        .context("list images")?;
-    debug!(?images, "listed images");
-
-    // Images in the docker daemon don't use the fully qualified reference,
-    // they look like this:
-    // ```
-    // repo_tags: [
-    //     "changeset_example:latest",
-    // ],
-    // repo_digests: [
-    //     "changeset_example@sha256:1af7aa8d7fe18420f10b46a78c23c5c9cb01817d30a03a12c33e8a26555f7b4f",
-    // ],
-    // repo_tags: [
-    //     "fossaeng/changeset_example:latest",
-    // ],
-    // repo_digests: [
-    //     "fossaeng/changeset_example@sha256:495f92a2c50d0b1550b232213c19bd4b5121a2268f95f0b7be6bb1c7dd51c4ce",
-    // ],
-    // ```
-    // As such, we just use the string the user provided;
-    // if it matches any tag or digest it's good to go.
-
-    // Collect the images
-    let id_by_tag_or_digest = images
-        .iter()
-        .flat_map(|i| {
-            i.repo_tags
-                .iter()
-                .map(|t| t.as_str())
-                .chain(i.repo_digests.iter().map(|d| d.as_str()))
-                .zip(std::iter::repeat(i.id.as_str()))
-        })
-        .collect::<HashMap<_, _>>();
-
-    if let Some(image) = id_by_tag_or_digest.get(reference) {
-        debug!(?image, "found image");
-        return Ok(image.to_string());
-    }
-
-    let listings = id_by_tag_or_digest.keys().collect::<Vec<_>>();
-    Err(eyre!("image not found: {reference}"))
-        .with_note(|| format!("{listings:#?}").header("Images:"))
-}
-
 /// Extract the digest for the docker image.
 /// Tries to use the first digest in `index.json` as the digest;
 /// if this fails it just computes a digest from the tarball itself.
-async fn digest(tarball: &Path) -> Result<Digest> {
+async fn digest(tarball: &Path) -> eyre::Result<Digest> {
     #[derive(Debug, Deserialize)]
     struct Index {
         manifests: Vec<Manifest>,
@@ -653,6 +790,11 @@ async fn digest(tarball: &Path) -> Result<Digest> {
         }
     }
 
+    // A directory has no content of its own to digest.
+    if is_dir(tarball).await {
+        return Err(Report::new(Kind::NotFound))
+            .with_context(|| format!("find the image in 'index.json' in {tarball:?}"));
+    }
     file_digest(tarball).await
 }
 
@@ -660,13 +802,18 @@ async fn digest(tarball: &Path) -> Result<Digest> {
 mod tests {
 
     use super::*;
-    use crate::{digest, Layer, LayerMediaType};
+    use crate::{digest, History, Layer, LayerMediaType};
+    use simple_test_case::test_case;
 
     #[test]
     fn parse_docker_manifest_nginx() {
         let content = include_str!("./testdata/nginx_manifest.json");
 
         let expected = DockerManifest {
+            config: Some(ConfigDescriptor {
+                digest: digest!("b52e0b094bc0e26c9eddc9e4ab7a64ce0033c3360d8b7ad4ff4132c4e03e8f7b"),
+                media_type: Some(String::from("application/vnd.oci.image.config.v1+json")),
+            }),
             layers: vec![
                 Layer {
                     digest: digest!(
@@ -674,6 +821,8 @@ mod tests {
                     ),
                     size: 77844480,
                     media_type: LayerMediaType::default(),
+                    diff_id: None,
+                    uncompressed_size: None,
                 },
                 Layer {
                     digest: digest!(
@@ -681,6 +830,8 @@ mod tests {
                     ),
                     size: 118268416,
                     media_type: LayerMediaType::default(),
+                    diff_id: None,
+                    uncompressed_size: None,
                 },
                 Layer {
                     digest: digest!(
@@ -688,6 +839,8 @@ mod tests {
                     ),
                     size: 3584,
                     media_type: LayerMediaType::default(),
+                    diff_id: None,
+                    uncompressed_size: None,
                 },
                 Layer {
                     digest: digest!(
@@ -695,6 +848,8 @@ mod tests {
                     ),
                     size: 4608,
                     media_type: LayerMediaType::default(),
+                    diff_id: None,
+                    uncompressed_size: None,
                 },
                 Layer {
                     digest: digest!(
@@ -702,6 +857,8 @@ mod tests {
                     ),
                     size: 2560,
                     media_type: LayerMediaType::default(),
+                    diff_id: None,
+                    uncompressed_size: None,
                 },
                 Layer {
                     digest: digest!(
@@ -709,6 +866,8 @@ mod tests {
                     ),
                     size: 5120,
                     media_type: LayerMediaType::default(),
+                    diff_id: None,
+                    uncompressed_size: None,
                 },
                 Layer {
                     digest: digest!(
@@ -716,11 +875,443 @@ mod tests {
                     ),
                     size: 7168,
                     media_type: LayerMediaType::default(),
+                    diff_id: None,
+                    uncompressed_size: None,
                 },
             ],
+            subject: None,
+            digest: None,
+            attestation: false,
         };
 
         let manifest = serde_json::from_str(content).expect("parse manifest");
         pretty_assertions::assert_eq!(expected, manifest);
     }
+
+    /// Files pushed with ORAS aren't tarballs, even though they have a layer media type;
+    /// only the directory (which ORAS pushes as a tarball) is a layer.
+    #[test]
+    fn parse_docker_manifest_oras_artifact() {
+        let content = include_str!("./testdata/oras_artifact_manifest.json");
+        let manifest = serde_json::from_str::<DockerManifest>(content).expect("parse manifest");
+        let layers = manifest
+            .layers
+            .iter()
+            .map(|layer| layer.digest.to_string())
+            .collect::<Vec<_>>();
+        pretty_assertions::assert_eq!(
+            vec!["sha256:2b64c6d9afd8a34ed0dbf35f7de171a8825a50d9f42f05e98fe2b1addf00ab44"],
+            layers
+        );
+    }
+
+    #[test]
+    fn parse_docker_manifest_unsupported_layer() {
+        let content = include_str!("./testdata/oras_artifact_manifest.json")
+            .replace(r#""artifactType": "application/vnd.example.bundle","#, "")
+            .replace(
+                "application/vnd.oci.empty.v1+json",
+                "application/vnd.oci.image.config.v1+json",
+            );
+        let manifest = serde_json::from_str::<DockerManifest>(&content);
+        assert!(
+            manifest.is_err(),
+            "image layers must be supported: {manifest:?}"
+        );
+    }
+
+    #[test]
+    fn parse_image_config() {
+        let content = r#"{
+            "architecture": "amd64",
+            "os": "linux",
+            "created": "2025-01-01T00:00:00Z",
+            "config": { "Env": ["PATH=/usr/bin"] },
+            "rootfs": { "type": "layers", "diff_ids": ["sha256:5f1ee22ffb5e68686db3dcb6584eb1c73b5570615b0f14fabb070b96117e351d"] },
+            "history": [
+                { "created": "2025-01-01T00:00:00Z", "created_by": "/bin/sh -c #(nop) ADD file:1234 in /" },
+                { "created_by": "/bin/sh -c #(nop) ENV FOO=bar", "empty_layer": true }
+            ]
+        }"#;
+
+        let expected = ImageConfig {
+            created: Some(String::from("2025-01-01T00:00:00Z")),
+            architecture: Some(String::from("amd64")),
+            os: Some(String::from("linux")),
+            variant: None,
+            os_version: None,
+            history: vec![
+                History {
+                    created: Some(String::from("2025-01-01T00:00:00Z")),
+                    created_by: Some(String::from("/bin/sh -c #(nop) ADD file:1234 in /")),
+                    ..Default::default()
+                },
+                History {
+                    created_by: Some(String::from("/bin/sh -c #(nop) ENV FOO=bar")),
+                    empty_layer: Some(true),
+                    ..Default::default()
+                },
+            ],
+            diff_ids: vec![digest!(
+                "5f1ee22ffb5e68686db3dcb6584eb1c73b5570615b0f14fabb070b96117e351d"
+            )],
+        };
+
+        let config = serde_json::from_str::<ImageConfig>(content).expect("parse config");
+        pretty_assertions::assert_eq!(expected, config);
+        pretty_assertions::assert_eq!(vec![&expected.history[0]], config.layer_history());
+    }
+
+    async fn append(builder: &mut tokio_tar::Builder<File>, name: String, content: String) {
+        let mut header = tokio_tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, name, content.as_bytes())
+            .await
+            .expect("append entry");
+    }
+
+    /// Write a tarball in the OCI layout with an image for each platform,
+    /// each of which is described by a manifest and a config.
+    async fn multi_platform_tarball(path: &Path, platforms: &[(&str, &str)]) {
+        let file = File::create(path).await.expect("create tarball");
+        let mut builder = tokio_tar::Builder::new(file);
+        for (n, (os, arch)) in platforms.iter().enumerate() {
+            let config = format!("{:064x}", n + 1);
+            let content = serde_json::json!({ "os": os, "architecture": arch });
+            append(
+                &mut builder,
+                format!("blobs/sha256/{config}"),
+                content.to_string(),
+            )
+            .await;
+
+            let manifest = format!("{:064x}", n + 100);
+            let content = serde_json::json!({
+                "config": { "digest": format!("sha256:{config}") },
+                "layers": [],
+            });
+            append(
+                &mut builder,
+                format!("blobs/sha256/{manifest}"),
+                content.to_string(),
+            )
+            .await;
+        }
+        builder.finish().await.expect("finish tarball");
+    }
+
+    #[test_case(&[("linux", "amd64"), ("linux", "arm64")], Some("linux/arm64"), Some("arm64"); "selects_platform")]
+    #[test_case(&[("windows", "s390x"), ("linux", "amd64")], None, Some("amd64"); "prefers_linux_without_platform")]
+    #[test_case(&[("unknown", "unknown"), ("windows", "s390x")], None, Some("s390x"); "skips_attestations_without_platform")]
+    #[test_case(&[("linux", "amd64")], Some("linux/arm64"), Some("amd64"); "single_image_ignores_platform")]
+    #[test_case(&[("linux", "amd64"), ("linux", "arm64")], Some("linux/s390x"), None; "missing_platform")]
+    #[tokio::test]
+    async fn tarball_selects_platform(
+        platforms: &[(&str, &str)],
+        platform: Option<&str>,
+        expected: Option<&str>,
+    ) {
+        let dir = async_tempfile::TempDir::new()
+            .await
+            .expect("create temp dir");
+        let path = dir.dir_path().join("image.tar");
+        multi_platform_tarball(&path, platforms).await;
+
+        let platform = platform.map(|p| p.parse::<Platform>().expect("parse platform"));
+        let tarball = Tarball::builder()
+            .name("image")
+            .path(&path)
+            .maybe_platform(platform)
+            .build()
+            .await;
+
+        match (tarball, expected) {
+            (Ok(tarball), Some(expected)) => {
+                let config = tarball.config().await.expect("read config");
+                pretty_assertions::assert_eq!(Some(expected), config.architecture.as_deref());
+            }
+            (Err(err), None) => {
+                let err = format!("{err:?}");
+                assert!(
+                    err.contains("no image for platform"),
+                    "unexpected error: {err}"
+                );
+            }
+            (Ok(_), None) => panic!("expected an error for a missing platform"),
+            (Err(err), Some(_)) => panic!("read tarball: {err:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn tarball_skips_attestation_manifests() {
+        let dir = async_tempfile::TempDir::new()
+            .await
+            .expect("create temp dir");
+        let path = dir.dir_path().join("image.tar");
+
+        // The attestation manifest has no config, so without its index entry
+        // it would look like a platform-independent image and be preferred.
+        let file = File::create(&path).await.expect("create tarball");
+        let mut builder = tokio_tar::Builder::new(file);
+        let image = format!("{:064x}", 1);
+        let config = format!("{:064x}", 2);
+        let attestation = format!("{:064x}", 3);
+        let index = serde_json::json!({
+            "schemaVersion": 2,
+            "manifests": [
+                {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "digest": format!("sha256:{image}"),
+                    "size": 0,
+                    "platform": { "os": "windows", "architecture": "s390x" },
+                },
+                {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "digest": format!("sha256:{attestation}"),
+                    "size": 0,
+                    "annotations": {
+                        "vnd.docker.reference.digest": format!("sha256:{image}"),
+                        "vnd.docker.reference.type": "attestation-manifest",
+                    },
+                    "platform": { "os": "unknown", "architecture": "unknown" },
+                },
+            ],
+        });
+        let entries = [
+            (
+                format!("blobs/sha256/{attestation}"),
+                serde_json::json!({ "layers": [] }),
+            ),
+            (
+                format!("blobs/sha256/{config}"),
+                serde_json::json!({ "os": "windows", "architecture": "s390x" }),
+            ),
+            (
+                format!("blobs/sha256/{image}"),
+                serde_json::json!({
+                    "config": { "digest": format!("sha256:{config}") },
+                    "layers": [],
+                }),
+            ),
+            (String::from("index.json"), index),
+        ];
+        for (name, content) in entries {
+            append(&mut builder, name, content.to_string()).await;
+        }
+        builder.finish().await.expect("finish tarball");
+
+        let tarball = Tarball::builder()
+            .name("image")
+            .path(&path)
+            .build()
+            .await
+            .expect("read tarball");
+        let config = tarball.config().await.expect("read config");
+        pretty_assertions::assert_eq!(Some("s390x"), config.architecture.as_deref());
+    }
+
+    #[tokio::test]
+    async fn tarball_missing_layers() {
+        let dir = async_tempfile::TempDir::new()
+            .await
+            .expect("create temp dir");
+        let path = dir.dir_path().join("image.tar");
+
+        let file = File::create(&path).await.expect("create tarball");
+        let mut builder = tokio_tar::Builder::new(file);
+        let [image, config, present, missing] = [1, 2, 3, 4].map(|n| format!("{n:064x}"));
+        let layer = |hex: &str| {
+            serde_json::json!({
+                "mediaType": "application/vnd.oci.image.layer.v1.tar",
+                "digest": format!("sha256:{hex}"),
+                "size": 0,
+            })
+        };
+        let entries = [
+            (
+                format!("blobs/sha256/{config}"),
+                serde_json::json!({ "os": "linux", "architecture": "amd64" }),
+            ),
+            (
+                format!("blobs/sha256/{image}"),
+                serde_json::json!({
+                    "config": { "digest": format!("sha256:{config}") },
+                    "layers": [layer(&present), layer(&missing)],
+                }),
+            ),
+            (format!("blobs/sha256/{present}"), serde_json::json!({})),
+        ];
+        for (name, content) in entries {
+            append(&mut builder, name, content.to_string()).await;
+        }
+        builder.finish().await.expect("finish tarball");
+
+        let tarball = Tarball::builder()
+            .name("image")
+            .path(&path)
+            .build()
+            .await
+            .expect("read tarball");
+        let missing = format!("sha256:{missing}")
+            .parse::<Digest>()
+            .expect("parse digest");
+        pretty_assertions::assert_eq!(
+            vec![missing],
+            tarball.missing_layers().await.expect("check layers")
+        );
+    }
+
+    #[test_case(Some(3), Some("unknown"); "attestation")]
+    #[test_case(Some(1), Some("s390x"); "image")]
+    #[test_case(Some(4), None; "missing")]
+    #[test_case(None, Some("s390x"); "no_digest")]
+    #[tokio::test]
+    async fn tarball_selects_manifest_digest(manifest: Option<usize>, expected: Option<&str>) {
+        let dir = async_tempfile::TempDir::new()
+            .await
+            .expect("create temp dir");
+        let path = dir.dir_path().join("image.tar");
+
+        let file = File::create(&path).await.expect("create tarball");
+        let mut builder = tokio_tar::Builder::new(file);
+        let [image, config, attestation, attestation_config] =
+            [1, 2, 3, 5].map(|n| format!("{n:064x}"));
+        let index = serde_json::json!({
+            "schemaVersion": 2,
+            "manifests": [
+                {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "digest": format!("sha256:{image}"),
+                    "size": 0,
+                },
+                {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "digest": format!("sha256:{attestation}"),
+                    "size": 0,
+                    "annotations": { "vnd.docker.reference.type": "attestation-manifest" },
+                },
+            ],
+        });
+        let entries = [
+            (
+                format!("blobs/sha256/{attestation_config}"),
+                serde_json::json!({ "os": "unknown", "architecture": "unknown" }),
+            ),
+            (
+                format!("blobs/sha256/{attestation}"),
+                serde_json::json!({
+                    "config": { "digest": format!("sha256:{attestation_config}") },
+                    "layers": [],
+                }),
+            ),
+            (
+                format!("blobs/sha256/{config}"),
+                serde_json::json!({ "os": "windows", "architecture": "s390x" }),
+            ),
+            (
+                format!("blobs/sha256/{image}"),
+                serde_json::json!({
+                    "config": { "digest": format!("sha256:{config}") },
+                    "layers": [],
+                }),
+            ),
+            (String::from("index.json"), index),
+        ];
+        for (name, content) in entries {
+            append(&mut builder, name, content.to_string()).await;
+        }
+        builder.finish().await.expect("finish tarball");
+
+        let digest = manifest.map(|n| {
+            format!("sha256:{n:064x}")
+                .parse::<Digest>()
+                .expect("parse digest")
+        });
+        let tarball = Tarball::builder()
+            .name("image")
+            .path(&path)
+            .maybe_manifest_digest(digest)
+            .build()
+            .await;
+
+        match (tarball, expected) {
+            (Ok(tarball), Some(expected)) => {
+                let config = tarball.config().await.expect("read config");
+                pretty_assertions::assert_eq!(Some(expected), config.architecture.as_deref());
+            }
+            (Err(err), None) => {
+                let err = format!("{err:?}");
+                assert!(
+                    err.contains("no manifest with digest"),
+                    "unexpected error: {err}"
+                );
+            }
+            (Ok(_), None) => panic!("expected an error for a missing digest"),
+            (Err(err), Some(_)) => panic!("read tarball: {err:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn tarball_identity() {
+        let dir = async_tempfile::TempDir::new()
+            .await
+            .expect("create temp dir");
+        let path = dir.dir_path().join("image.tar");
+        multi_platform_tarball(&path, &[("linux", "amd64")]).await;
+
+        let tarball = Tarball::builder()
+            .name("image.tar")
+            .path(&path)
+            .build()
+            .await
+            .expect("read tarball");
+        let identity = tarball.identity().await.expect("read identity");
+        let expected = Identity::builder()
+            .reference(path.display().to_string())
+            .resolved_digest(tarball.digest().await.expect("read digest"))
+            .display_name("image.tar")
+            .build();
+        pretty_assertions::assert_eq!(expected, identity);
+    }
+
+    #[tokio::test]
+    async fn tarball_reports_subject() {
+        let dir = async_tempfile::TempDir::new()
+            .await
+            .expect("create temp dir");
+        let path = dir.dir_path().join("image.tar");
+
+        let file = File::create(&path).await.expect("create tarball");
+        let mut builder = tokio_tar::Builder::new(file);
+        let subject = format!("sha256:{:064x}", 1);
+        let content = serde_json::json!({
+            "config": { "digest": format!("sha256:{:064x}", 2) },
+            "layers": [],
+            "subject": {
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "digest": subject,
+                "size": 512,
+            },
+        });
+        let manifest = format!("blobs/sha256/{:064x}", 3);
+        append(&mut builder, manifest, content.to_string()).await;
+        builder.finish().await.expect("finish tarball");
+
+        let tarball = Tarball::builder()
+            .name("image")
+            .path(&path)
+            .build()
+            .await
+            .expect("read tarball");
+        let expected = Subject::builder()
+            .digest(subject.parse::<Digest>().expect("parse digest"))
+            .size(512)
+            .media_type("application/vnd.oci.image.manifest.v1+json")
+            .build();
+        let subject = tarball.subject().await.expect("read subject");
+        pretty_assertions::assert_eq!(Some(expected), subject);
+    }
 }
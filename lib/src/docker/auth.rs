@@ -0,0 +1,315 @@
+//! Reads registry credentials from the local Docker configuration,
+//! the `auth.json` files used by Podman, Buildah, and skopeo, or Kubernetes image pull secrets.
+
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use base64::Engine;
+use color_eyre::{
+    eyre::{self, eyre, Context, OptionExt},
+    Section, SectionExt,
+};
+use serde::Deserialize;
+use tap::TapFallible;
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, info};
+
+use crate::{metrics::warn, Authentication, Reference, Result};
+
+mod k8s;
+mod store;
+
+/// Names the `auth.json` file to read instead of the default locations used by Podman, Buildah, and skopeo.
+const REGISTRY_AUTH_FILE_VAR: &str = "REGISTRY_AUTH_FILE";
+
+impl Authentication {
+    /// Read authentication information for the host from the configured Docker credentials, if any.
+    ///
+    /// Credentials saved by Podman, Buildah, and skopeo (in `containers-auth.json` files) are read too;
+    /// they use the same format as the Docker configuration. Files are read in the same order as those tools:
+    /// 1. `$REGISTRY_AUTH_FILE` if it's set, otherwise:
+    ///    - `$XDG_RUNTIME_DIR/containers/auth.json`
+    ///    - `$XDG_CONFIG_HOME/containers/auth.json` (or `~/.config/containers/auth.json`)
+    /// 2. `~/.docker/config.json`
+    ///
+    /// The first file that has credentials for the host is used; files that don't exist are skipped.
+    /// If a file names the `osxkeychain`, `wincred`, or `desktop` helper but the helper isn't installed,
+    /// the credentials are read from the macOS Keychain or Windows Credential Manager directly.
+    ///
+    /// Reference:
+    /// - https://docs.docker.com/reference/cli/docker/login
+    /// - https://github.com/docker/docker-credential-helpers
+    /// - https://github.com/containers/image/blob/main/docs/containers-auth.json.5.md
+    pub async fn docker(target: &Reference) -> Result<Self> {
+        Self::docker_host(&target.host).await
+    }
+
+    /// Read authentication information for the registry host from the configured Docker credentials, if any;
+    /// see [`Authentication::docker`].
+    pub async fn docker_host(host: &str) -> Result<Self> {
+        for path in auth_files(|name| std::env::var_os(name)) {
+            match Self::docker_internal(host, &path).await {
+                Ok(Authentication::None) => debug!(?path, "no auth for host"),
+                Ok(auth) => {
+                    debug!(?path, "inferred docker auth: {auth:?}");
+                    return Ok(auth);
+                }
+                Err(err) => warn!(?err, "unable to infer docker auth"),
+            }
+        }
+
+        debug!("no docker auth found; trying unauthenticated");
+        Ok(Authentication::None)
+    }
+
+    async fn docker_internal(host: &str, path: &Path) -> eyre::Result<Self> {
+        let config = match tokio::fs::read_to_string(path).await {
+            Ok(config) => config,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Authentication::None)
+            }
+            Err(err) => {
+                return Err(err)
+                    .context("read docker config")
+                    .with_section(|| path.display().to_string().header("Config file path:"))
+            }
+        };
+
+        serde_json::from_str::<DockerConfig>(&config)
+            .context("parse docker config")
+            .with_section(|| path.display().to_string().header("Config file path:"))
+            .with_section(|| config.header("Config file content:"))?
+            .auth(host)
+            .await
+            .tap_ok(|auth| info!("inferred docker auth: {auth:?}"))
+    }
+}
+
+/// The files from which credentials are read, in order of precedence; see [`Authentication::docker`].
+///
+/// Environment variables are read with `env` so that the locations can be tested.
+fn auth_files(env: impl Fn(&str) -> Option<OsString>) -> Vec<PathBuf> {
+    let home = env("HOME")
+        .or_else(|| env("USERPROFILE"))
+        .map(PathBuf::from);
+    let mut files = match env(REGISTRY_AUTH_FILE_VAR) {
+        Some(path) => vec![PathBuf::from(path)],
+        None => {
+            let runtime = env("XDG_RUNTIME_DIR").map(PathBuf::from);
+            let config = env("XDG_CONFIG_HOME")
+                .map(PathBuf::from)
+                .or_else(|| home.as_ref().map(|home| home.join(".config")));
+            [runtime, config]
+                .into_iter()
+                .flatten()
+                .map(|dir| dir.join("containers").join("auth.json"))
+                .collect()
+        }
+    };
+
+    if let Some(home) = home {
+        files.push(home.join(".docker").join("config.json"));
+    }
+    files
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DockerConfig {
+    /// The default credential store.
+    ///
+    /// The value of the config property is the suffix of the program to use (i.e. everything after `docker-credential-`).
+    creds_store: Option<String>,
+
+    /// Credential stores per host.
+    ///
+    /// Credential helpers are specified in a similar way to credsStore, but allow for multiple helpers to be configured at a time.
+    /// Keys specify the registry domain, and values specify the suffix of the program to use (i.e. everything after docker-credential-).
+    #[serde(default)]
+    cred_helpers: HashMap<String, String>,
+
+    /// Logged in hosts.
+    #[serde(default)]
+    auths: HashMap<String, DockerAuth>,
+}
+
+impl DockerConfig {
+    /// Some hosts have fallback keys.
+    /// Given a host, this function returns an iterator representing fallback keys to check for authentication.
+    fn auth_keys(host: &str) -> impl Iterator<Item = &str> {
+        if host == "docker.io" {
+            vec!["docker.io", "https://index.docker.io/v1/"]
+        } else {
+            vec![host]
+        }
+        .into_iter()
+    }
+
+    /// Returns the auth for the host.
+    ///
+    /// Some hosts have fallback keys; the host that actually was used to retrieve the auth
+    /// is returned so that if it was a fallback key the correct key can be used to
+    /// retrieve auth information in subsequent operations.
+    ///
+    /// Keys may also be URLs for the host (e.g. `https://ghcr.io`), which are checked after the exact keys.
+    async fn auth(&self, host: &str) -> eyre::Result<Authentication> {
+        let urls = self
+            .auths
+            .keys()
+            .filter(|key| key.as_str() != host && hostname(key) == host)
+            .map(String::as_str);
+        for key in Self::auth_keys(host).chain(urls) {
+            if let Some(auth) = self.auths.get(key) {
+                match auth.decode(self, key).await {
+                    Ok(auth) => return Ok(auth),
+                    Err(err) => {
+                        warn!("failed decoding auth for host {key:?}: {err:?}");
+                        continue;
+                    }
+                }
+            }
+        }
+
+        Ok(Authentication::None)
+    }
+}
+
+/// The host named by a key in [`DockerConfig::auths`], which may be a URL.
+fn hostname(key: &str) -> &str {
+    let key = key.split_once("://").map_or(key, |(_, rest)| rest);
+    key.split_once('/').map_or(key, |(host, _)| host)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum DockerAuth {
+    /// The credentials are stored in plain text, not in a helper.
+    Plain {
+        /// Base64 encoded authentication credentials in the form of `username:password`.
+        auth: String,
+    },
+
+    /// The credentials are stored in plain text as separate fields,
+    /// as written by `kubectl create secret docker-registry`.
+    Credentials { username: String, password: String },
+
+    /// The credentials are stored in a helper.
+    /// Use the host with the top level [`DockerConfig`] to determine which helper to use.
+    Helper {},
+}
+
+impl DockerAuth {
+    async fn decode(&self, config: &DockerConfig, host: &str) -> eyre::Result<Authentication> {
+        match self {
+            DockerAuth::Plain { auth } => Self::decode_plain(auth),
+            DockerAuth::Credentials { username, password } => {
+                Ok(Authentication::basic(username, password))
+            }
+            DockerAuth::Helper {} => Self::decode_helper(config, host).await,
+        }
+    }
+
+    fn decode_plain(auth: &str) -> eyre::Result<Authentication> {
+        let auth = base64::engine::general_purpose::STANDARD
+            .decode(auth)
+            .context("decode base64 auth key")?;
+        let auth = String::from_utf8(auth).context("parse auth key as utf-8")?;
+        let (username, password) = auth
+            .split_once(':')
+            .ok_or_eyre("invalid auth key format, expected username:password")?;
+        Ok(Authentication::basic(username, password))
+    }
+
+    async fn decode_helper(config: &DockerConfig, host: &str) -> eyre::Result<Authentication> {
+        let helper = config
+            .cred_helpers
+            .get(host)
+            .or(config.creds_store.as_ref())
+            .ok_or_eyre("no helper found for host")?;
+
+        let binary = format!("docker-credential-{helper}");
+        let spawned = tokio::process::Command::new(&binary)
+            .arg("get")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn();
+        let mut exec = match spawned {
+            Ok(exec) => exec,
+            Err(err)
+                if err.kind() == std::io::ErrorKind::NotFound
+                    && store::HELPERS.contains(&helper.as_str()) =>
+            {
+                debug!(%binary, "credential helper not found; reading the native credential store");
+                return store::read(host)
+                    .await
+                    .context("read native credential store");
+            }
+            Err(err) => {
+                return Err(err)
+                    .context("spawn docker credential helper")
+                    .with_section(|| binary.clone().header("Helper binary:"))
+            }
+        };
+
+        if let Some(mut stdin) = exec.stdin.take() {
+            stdin
+                .write_all(host.as_bytes())
+                .await
+                .context("write request to helper")?;
+            drop(stdin);
+        }
+
+        let output = exec.wait_with_output().await.context("run helper")?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+            let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+            return Err(eyre!("auth helper failed with status: {}", output.status))
+                .with_section(|| binary.clone().header("Helper binary:"))
+                .with_section(|| host.to_string().header("Host:"))
+                .with_section(|| output.status.to_string().header("Command status code:"))
+                .with_section(|| stderr.header("Stderr:"))
+                .with_section(|| stdout.header("Stdout:"));
+        }
+
+        let credential = serde_json::from_slice::<DockerCredential>(&output.stdout)
+            .context("decode helper output")
+            .with_section(|| binary.header("Helper binary:"))?;
+        Ok(Authentication::basic(
+            credential.username,
+            credential.secret,
+        ))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerCredential {
+    username: String,
+    secret: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    #[test_case(&[("HOME", "/home/me")], &["/home/me/.config/containers/auth.json", "/home/me/.docker/config.json"]; "home")]
+    #[test_case(&[("HOME", "/home/me"), ("XDG_RUNTIME_DIR", "/run/user/1000"), ("XDG_CONFIG_HOME", "/config")], &["/run/user/1000/containers/auth.json", "/config/containers/auth.json", "/home/me/.docker/config.json"]; "xdg")]
+    #[test_case(&[("HOME", "/home/me"), ("XDG_RUNTIME_DIR", "/run/user/1000"), ("REGISTRY_AUTH_FILE", "/auth.json")], &["/auth.json", "/home/me/.docker/config.json"]; "registry_auth_file")]
+    #[test_case(&[], &[]; "no_home")]
+    #[test]
+    fn auth_files(vars: &[(&str, &str)], expected: &[&str]) {
+        let env = |name: &str| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| OsString::from(value))
+        };
+        let expected = expected.iter().map(PathBuf::from).collect::<Vec<_>>();
+        pretty_assertions::assert_eq!(expected, super::auth_files(env));
+    }
+}
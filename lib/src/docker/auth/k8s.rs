@@ -0,0 +1,181 @@
+//! Reads registry credentials from Kubernetes image pull secrets.
+//!
+//! Secrets are read in the form written by `kubectl get secret -o json` or `kubectl get secret -o yaml`.
+
+use std::{collections::HashMap, path::Path};
+
+use base64::Engine;
+use color_eyre::{
+    eyre::{self, bail, Context},
+    Report, Section, SectionExt,
+};
+use serde::Deserialize;
+
+use super::{DockerAuth, DockerConfig};
+use crate::{error::Kind, Authentication, Reference, Result};
+
+/// The secret type for the Docker configuration file format.
+const DOCKER_CONFIG_JSON: &str = "kubernetes.io/dockerconfigjson";
+
+/// The secret type for the legacy `~/.dockercfg` format, which is only the `auths` section.
+const DOCKER_CFG: &str = "kubernetes.io/dockercfg";
+
+impl Authentication {
+    /// Read authentication information for the host from a Kubernetes image pull secret,
+    /// of type `kubernetes.io/dockerconfigjson` (or the legacy `kubernetes.io/dockercfg`).
+    ///
+    /// The secret may be JSON or YAML, as written by `kubectl get secret <name> -o json` (or `-o yaml`);
+    /// both `data` (base64 encoded) and `stringData` are supported.
+    /// If the secret doesn't have credentials for the host, this reports [`Authentication::None`].
+    ///
+    /// Reference:
+    /// - https://kubernetes.io/docs/concepts/configuration/secret/#docker-config-secrets
+    pub async fn k8s_secret(path: impl AsRef<Path>, target: &Reference) -> Result<Self> {
+        let path = path.as_ref();
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .context("read secret")
+            .with_section(|| path.display().to_string().header("Secret path:"))?;
+
+        parse(&content)
+            .context("parse secret")
+            .with_section(|| path.display().to_string().header("Secret path:"))?
+            .auth(&target.host)
+            .await
+            .map_err(crate::Error::from)
+    }
+}
+
+/// The fields of a secret needed to read its Docker configuration.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Secret {
+    #[serde(rename = "type")]
+    kind: Option<String>,
+
+    /// Base64 encoded values.
+    #[serde(default)]
+    data: HashMap<String, String>,
+
+    /// Plain text values.
+    #[serde(default)]
+    string_data: HashMap<String, String>,
+}
+
+/// Parse the Docker configuration from the content of a secret.
+fn parse(content: &str) -> eyre::Result<DockerConfig> {
+    // YAML is a superset of JSON, so this reads secrets written in either form.
+    let secret =
+        serde_yaml_ng::from_str::<Secret>(content).context("parse secret as json or yaml")?;
+
+    let (key, decode): (_, fn(&str) -> eyre::Result<DockerConfig>) = match secret.kind.as_deref() {
+        Some(DOCKER_CONFIG_JSON) | None if has_key(&secret, ".dockerconfigjson") => {
+            (".dockerconfigjson", |value| {
+                serde_json::from_str(value).context("parse docker config")
+            })
+        }
+        Some(DOCKER_CFG) | None if has_key(&secret, ".dockercfg") => (".dockercfg", |value| {
+            let auths = serde_json::from_str::<HashMap<String, DockerAuth>>(value)
+                .context("parse docker config")?;
+            Ok(DockerConfig {
+                auths,
+                ..Default::default()
+            })
+        }),
+        Some(kind @ (DOCKER_CONFIG_JSON | DOCKER_CFG)) => {
+            bail!("secret of type {kind} has no credentials")
+        }
+        Some(kind) => {
+            return Err(Report::new(Kind::Unsupported))
+                .context(format!("unsupported secret type: {kind}"))
+        }
+        None => bail!("secret has no .dockerconfigjson or .dockercfg key"),
+    };
+
+    let value = match (secret.string_data.get(key), secret.data.get(key)) {
+        (Some(value), _) => value.clone(),
+        (None, Some(value)) => {
+            let value = base64::engine::general_purpose::STANDARD
+                .decode(value.trim())
+                .with_context(|| format!("decode base64 {key}"))?;
+            String::from_utf8(value).with_context(|| format!("parse {key} as utf-8"))?
+        }
+        (None, None) => bail!("secret has no {key} key"),
+    };
+    decode(&value)
+}
+
+fn has_key(secret: &Secret, key: &str) -> bool {
+    secret.data.contains_key(key) || secret.string_data.contains_key(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    /// `{"auths":{"ghcr.io":{"username":"me","password":"token","auth":"bWU6dG9rZW4="}}}`
+    const CONFIG: &str = "eyJhdXRocyI6eyJnaGNyLmlvIjp7InVzZXJuYW1lIjoibWUiLCJwYXNzd29yZCI6InRva2VuIiwiYXV0aCI6ImJXVTZkRzlyWlc0PSJ9fX0=";
+
+    /// `{"https://ghcr.io":{"auth":"bWU6dG9rZW4="}}`
+    const CFG: &str = "eyJodHRwczovL2doY3IuaW8iOnsiYXV0aCI6ImJXVTZkRzlyWlc0PSJ9fQ==";
+
+    fn json_secret() -> String {
+        serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Secret",
+            "metadata": { "name": "regcred" },
+            "type": DOCKER_CONFIG_JSON,
+            "data": { ".dockerconfigjson": CONFIG },
+        })
+        .to_string()
+    }
+
+    fn yaml_secret() -> String {
+        format!("apiVersion: v1\ndata:\n  .dockerconfigjson: {CONFIG}\nkind: Secret\nmetadata:\n  name: regcred\ntype: {DOCKER_CONFIG_JSON}\n")
+    }
+
+    fn string_data_secret() -> String {
+        String::from("apiVersion: v1\nkind: Secret\ntype: \"kubernetes.io/dockerconfigjson\"\nstringData:\n  .dockerconfigjson: |\n    {\n      \"auths\": { \"ghcr.io\": { \"username\": \"me\", \"password\": \"token\" } }\n    }\n")
+    }
+
+    /// Written by `kubectl`, with fields that aren't read in forms beyond plain `key: value` pairs.
+    fn annotated_secret() -> String {
+        format!("apiVersion: v1\ndata: {{ .dockerconfigjson: {CONFIG} }}\nkind: Secret\nmetadata:\n  annotations:\n    kubectl.kubernetes.io/last-applied-configuration: |\n      {{\"apiVersion\":\"v1\",\n      \"kind\":\"Secret\"}}\n  name: regcred\ntype: {DOCKER_CONFIG_JSON}\n")
+    }
+
+    fn dockercfg_secret() -> String {
+        format!("type: {DOCKER_CFG}\ndata:\n  .dockercfg: '{CFG}'\n")
+    }
+
+    #[test_case(json_secret(); "json")]
+    #[test_case(yaml_secret(); "yaml")]
+    #[test_case(string_data_secret(); "string_data")]
+    #[test_case(annotated_secret(); "annotated")]
+    #[test_case(dockercfg_secret(); "dockercfg")]
+    #[tokio::test]
+    async fn reads_secret(content: String) {
+        let config = parse(&content).expect("parse secret");
+        match config.auth("ghcr.io").await.expect("read auth") {
+            Authentication::Basic { username, password } => {
+                pretty_assertions::assert_eq!("me", username);
+                pretty_assertions::assert_eq!("token", password);
+            }
+            auth => panic!("unexpected authentication: {auth:?}"),
+        }
+
+        let other = config.auth("docker.io").await.expect("read auth");
+        assert!(
+            matches!(other, Authentication::None),
+            "no auth for other hosts"
+        );
+    }
+
+    #[test_case("type: kubernetes.io/tls\ndata:\n  tls.crt: abc\n"; "wrong_type")]
+    #[test_case("type: kubernetes.io/dockerconfigjson\ndata: {}\n"; "missing_key")]
+    #[test_case("type: kubernetes.io/dockerconfigjson\ndata:\n  .dockerconfigjson: not-base64!\n"; "invalid_base64")]
+    #[test]
+    fn rejects_secret(content: &str) {
+        assert!(parse(content).is_err(), "secret is rejected");
+    }
+}
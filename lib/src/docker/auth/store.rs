@@ -0,0 +1,184 @@
+//! Reads credentials directly from the operating system's credential store.
+//!
+//! Docker's `osxkeychain`, `wincred`, and `desktop` credential helpers save credentials in the
+//! macOS Keychain or the Windows Credential Manager. The helper binaries are usually installed with
+//! Docker Desktop, so they may not be on `PATH` (for example, if Docker Desktop isn't running or was uninstalled);
+//! in that case the credentials are read from the store directly.
+
+use color_eyre::eyre;
+
+use crate::Authentication;
+
+#[cfg(windows)]
+mod wincred;
+
+/// The credential helpers that save credentials in the native store on this platform.
+#[cfg(target_os = "macos")]
+pub const HELPERS: &[&str] = &["osxkeychain", "desktop"];
+
+/// The credential helpers that save credentials in the native store on this platform.
+#[cfg(windows)]
+pub const HELPERS: &[&str] = &["wincred", "desktop"];
+
+/// The credential helpers that save credentials in the native store on this platform.
+#[cfg(not(any(target_os = "macos", windows)))]
+pub const HELPERS: &[&str] = &[];
+
+/// Read the credentials that a helper in [`HELPERS`] saved for the server.
+/// Servers without saved credentials report [`Authentication::None`].
+#[cfg(target_os = "macos")]
+pub async fn read(server: &str) -> eyre::Result<Authentication> {
+    use color_eyre::eyre::Context;
+
+    let output = tokio::process::Command::new("/usr/bin/security")
+        .args(keychain_query(server))
+        .output()
+        .await
+        .context("run security")?;
+    if !output.status.success() {
+        tracing::debug!(status = %output.status, "no keychain item for server");
+        return Ok(Authentication::None);
+    }
+
+    // Attributes are written to stdout, but the password is written to stderr.
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    keychain_credentials(&stdout, &stderr)
+}
+
+/// Read the credentials that a helper in [`HELPERS`] saved for the server.
+/// Servers without saved credentials report [`Authentication::None`].
+#[cfg(windows)]
+pub async fn read(server: &str) -> eyre::Result<Authentication> {
+    wincred::read(server)
+}
+
+/// Read the credentials that a helper in [`HELPERS`] saved for the server.
+#[cfg(not(any(target_os = "macos", windows)))]
+pub async fn read(_server: &str) -> eyre::Result<Authentication> {
+    eyre::bail!("no native credential store is supported on this platform")
+}
+
+/// The host and port under which the helper saves the server in the keychain;
+/// servers may be URLs (e.g. `https://index.docker.io/v1/`) or hosts (e.g. `localhost:5000`).
+#[cfg(any(target_os = "macos", test))]
+fn keychain_server(server: &str) -> (&str, Option<&str>) {
+    let server = server.split_once("://").map_or(server, |(_, rest)| rest);
+    let server = server.split_once('/').map_or(server, |(host, _)| host);
+    match server.rsplit_once(':') {
+        Some((host, port)) if port.bytes().all(|b| b.is_ascii_digit()) => (host, Some(port)),
+        _ => (server, None),
+    }
+}
+
+/// The label the helpers give the keychain items they save.
+#[cfg(any(target_os = "macos", test))]
+const KEYCHAIN_LABEL: &str = "Docker Credentials";
+
+/// The arguments to `security` that print the keychain item a helper saved for the server.
+///
+/// The helper saves an internet password for the host and port of the server URL, with the username as its account.
+/// Other applications save internet passwords for the same host (like a browser saving the login to a registry's
+/// web interface), so only items with the helper's label are read.
+#[cfg(any(target_os = "macos", test))]
+fn keychain_query(server: &str) -> Vec<&str> {
+    let (host, port) = keychain_server(server);
+    let mut args = vec![
+        "find-internet-password",
+        "-g",
+        "-l",
+        KEYCHAIN_LABEL,
+        "-s",
+        host,
+    ];
+    if let Some(port) = port {
+        args.extend(["-P", port]);
+    }
+    args
+}
+
+/// Parse the account and password from the output of `security find-internet-password -g`.
+///
+/// The account is an attribute like `"acct"<blob>="username"`; the password is reported like `password: "secret"`,
+/// or in hex like `password: 0x736563726574  "secret"` if it isn't printable.
+#[cfg(any(target_os = "macos", test))]
+fn keychain_credentials(attributes: &str, password: &str) -> eyre::Result<Authentication> {
+    use color_eyre::eyre::{Context, OptionExt};
+
+    let username = attributes
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("\"acct\"<blob>="))
+        .and_then(|value| value.strip_prefix('"')?.strip_suffix('"'))
+        .ok_or_eyre("keychain item has no account")?;
+
+    let password = password
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("password: "))
+        .ok_or_eyre("keychain item has no password")?;
+    let password = match password.strip_prefix("0x") {
+        Some(hex) => {
+            let hex = hex.split_whitespace().next().unwrap_or_default();
+            let bytes = (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(hex.get(i..i + 2).unwrap_or_default(), 16))
+                .collect::<Result<Vec<_>, _>>()
+                .context("decode hex password")?;
+            String::from_utf8(bytes).context("parse password as utf-8")?
+        }
+        None => password
+            .strip_prefix('"')
+            .and_then(|password| password.strip_suffix('"'))
+            .ok_or_eyre("unexpected password format")?
+            .to_string(),
+    };
+
+    Ok(Authentication::basic(username, password))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    #[test_case("https://index.docker.io/v1/", ("index.docker.io", None); "url")]
+    #[test_case("ghcr.io", ("ghcr.io", None); "host")]
+    #[test_case("localhost:5000", ("localhost", Some("5000")); "port")]
+    #[test_case("https://localhost:5000/v2/", ("localhost", Some("5000")); "url_port")]
+    #[test]
+    fn keychain_server(server: &str, expected: (&str, Option<&str>)) {
+        pretty_assertions::assert_eq!(expected, super::keychain_server(server));
+    }
+
+    #[test_case("ghcr.io", &["-s", "ghcr.io"]; "host")]
+    #[test_case("https://localhost:5000/v2/", &["-s", "localhost", "-P", "5000"]; "port")]
+    #[test]
+    fn keychain_query(server: &str, expected: &[&str]) {
+        let args = ["find-internet-password", "-g", "-l", "Docker Credentials"]
+            .into_iter()
+            .chain(expected.iter().copied())
+            .collect::<Vec<_>>();
+        pretty_assertions::assert_eq!(args, super::keychain_query(server));
+    }
+
+    const ATTRIBUTES: &str = r#"keychain: "/Users/me/Library/Keychains/login.keychain-db"
+version: 512
+class: "inet"
+attributes:
+    0x00000007 <blob>="Docker Credentials"
+    "acct"<blob>="me"
+    "srvr"<blob>="ghcr.io"
+"#;
+
+    #[test_case("password: \"token\"\n", "token"; "plain")]
+    #[test_case("password: 0x746F6B656E  \"token\"\n", "token"; "hex")]
+    #[test]
+    fn keychain_credentials(password: &str, expected: &str) {
+        match super::keychain_credentials(ATTRIBUTES, password).expect("parse credentials") {
+            Authentication::Basic { username, password } => {
+                pretty_assertions::assert_eq!("me", username);
+                pretty_assertions::assert_eq!(expected, password);
+            }
+            auth => panic!("unexpected authentication: {auth:?}"),
+        }
+    }
+}
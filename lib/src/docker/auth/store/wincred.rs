@@ -0,0 +1,73 @@
+//! Reads credentials saved by the `wincred` helper from the Windows Credential Manager.
+//!
+//! The helper saves a generic credential named after the server, with the username and the secret as its blob.
+//! This uses unsafe code to call the Credential Manager; each call's arguments outlive it,
+//! and the credential it returns is only read before it's freed.
+#![allow(unsafe_code)]
+
+use std::{io, iter, ptr, slice};
+
+use color_eyre::eyre::{self, Context};
+use windows_sys::Win32::{
+    Foundation::ERROR_NOT_FOUND,
+    Security::Credentials::{CredFree, CredReadW, CREDENTIALW, CRED_TYPE_GENERIC},
+};
+
+use crate::Authentication;
+
+/// Read the credential saved for the server.
+/// Servers without a saved credential report [`Authentication::None`].
+pub fn read(server: &str) -> eyre::Result<Authentication> {
+    let target = server
+        .encode_utf16()
+        .chain(iter::once(0))
+        .collect::<Vec<u16>>();
+
+    let mut credential = ptr::null_mut::<CREDENTIALW>();
+    // SAFETY: `target` is nul terminated and outlives the call;
+    // on success `credential` points to a credential allocated by the system.
+    let found = unsafe { CredReadW(target.as_ptr(), CRED_TYPE_GENERIC, 0, &mut credential) };
+    if found == 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(ERROR_NOT_FOUND as i32) {
+            return Ok(Authentication::None);
+        }
+        return Err(err).context("read credential");
+    }
+
+    // SAFETY: `CredReadW` succeeded, so `credential` is valid until it's freed.
+    let auth = unsafe { decode(&*credential) };
+    // SAFETY: `credential` was allocated by `CredReadW` and isn't used after this.
+    unsafe { CredFree(credential.cast_const().cast()) };
+    auth
+}
+
+/// Read the username and secret from the credential.
+///
+/// # Safety
+///
+/// The credential's pointers must be valid for the sizes it reports, as they are for credentials from `CredReadW`.
+unsafe fn decode(credential: &CREDENTIALW) -> eyre::Result<Authentication> {
+    if credential.UserName.is_null() {
+        return Err(eyre::eyre!("credential has no username"));
+    }
+
+    // SAFETY: the username is a nul terminated string, so each character up to the terminator is readable.
+    let username = unsafe {
+        let len = (0..)
+            .take_while(|&i| *credential.UserName.add(i) != 0)
+            .count();
+        slice::from_raw_parts(credential.UserName, len)
+    };
+    let username = String::from_utf16(username).context("parse username")?;
+
+    // The helper saves the secret as UTF-8 bytes.
+    let secret = match credential.CredentialBlobSize {
+        0 => &[][..],
+        // SAFETY: the blob is readable for the size the credential reports.
+        size => unsafe { slice::from_raw_parts(credential.CredentialBlob, size as usize) },
+    };
+    let secret = std::str::from_utf8(secret).context("parse secret as utf-8")?;
+
+    Ok(Authentication::basic(username, secret))
+}
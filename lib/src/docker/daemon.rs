@@ -0,0 +1,723 @@
+//! Interacts with a Docker daemon, which is local unless a remote one is configured with [`Connection`].
+//!
+//! The image is saved from the daemon in the same format as `docker save` (not `docker export`,
+//! which flattens a container's filesystem), so each layer is read with its real digest
+//! and layer filters and strategies like [`crate::extract::Strategy::Separate`] work as they do for registries.
+
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    pin::Pin,
+};
+
+use async_tempfile::TempFile;
+use bollard::{
+    models::{ImageManifestSummaryKindEnum, ImageSummary, OciPlatform},
+    query_parameters::{CreateImageOptionsBuilder, ListImagesOptionsBuilder},
+    Docker, API_DEFAULT_VERSION,
+};
+use bon::Builder;
+use bytes::Bytes;
+use color_eyre::{
+    eyre::{self, Context, Report},
+    Section, SectionExt,
+};
+use derive_more::Debug;
+use futures_lite::{Stream, StreamExt};
+use tap::TapOptional;
+use tracing::debug;
+
+use super::Tarball;
+use crate::{
+    cio, error::Kind, progress::SharedProgress, transform::Buffering, CaseCollisions, Digest,
+    Error, FilterCommands, Filters, Identity, ImageConfig, Layer, Limits, Platform, PullPolicy,
+    Reference, Result, Source, Unpack,
+};
+
+/// The seconds allowed for each request to the daemon; the same as the default used by [`bollard`].
+const DAEMON_TIMEOUT: u64 = 120;
+
+/// How to connect to the Docker daemon.
+///
+/// Fields that aren't set are read from the environment the same way as the Docker CLI:
+/// the host from `DOCKER_HOST`, and the certificates from `DOCKER_CERT_PATH`
+/// (or `DOCKER_CONFIG`, or `~/.docker`) when `DOCKER_TLS_VERIFY` is set.
+/// Without a host or socket, the socket of the local daemon is discovered by probing the standard location
+/// and the locations used by tools like Colima, Rancher Desktop, Podman machine, and Docker Desktop on macOS.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Builder)]
+pub struct Connection {
+    /// The daemon to connect to, like `unix:///var/run/docker.sock`, `tcp://host:2376`, or `ssh://user@host`.
+    #[builder(into)]
+    pub host: Option<String>,
+
+    /// The directory containing `ca.pem`, `cert.pem`, and `key.pem`,
+    /// used to verify the daemon and authenticate to it over TLS.
+    ///
+    /// Setting this connects to `tcp://` hosts with TLS even if `DOCKER_TLS_VERIFY` isn't set.
+    #[builder(into)]
+    pub cert_path: Option<PathBuf>,
+
+    /// The socket (or named pipe, on Windows) of a local daemon.
+    ///
+    /// This takes precedence over the host, and disables discovering the socket.
+    #[builder(into)]
+    pub socket: Option<PathBuf>,
+}
+
+impl Connection {
+    /// Connect to the daemon.
+    fn connect(&self) -> Result<Docker> {
+        if let Some(socket) = &self.socket {
+            return connect_local(socket)
+                .with_context(|| format!("connect to docker daemon at {socket:?}"))
+                .map_err(Error::from);
+        }
+
+        let env = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let Some(host) = self.host.clone().or_else(|| env("DOCKER_HOST")) else {
+            return match discover_socket() {
+                Some(socket) => connect_local(&socket)
+                    .with_context(|| format!("connect to discovered docker daemon at {socket:?}")),
+                None => {
+                    Docker::connect_with_local_defaults().context("connect to local docker daemon")
+                }
+            }
+            .map_err(Error::from);
+        };
+
+        let scheme = host.split_once("://").map(|(scheme, _)| scheme);
+        let tls = scheme == Some("https")
+            || self.cert_path.is_some()
+            || env("DOCKER_TLS_VERIFY").is_some();
+        if matches!(scheme, Some("tcp" | "http" | "https" | "ssh")) {
+            crate::flag_offline(&format!("connecting to the docker daemon at '{host}'"))?;
+        }
+
+        let docker = match scheme {
+            #[cfg(unix)]
+            Some("unix") => Docker::connect_with_unix(&host, DAEMON_TIMEOUT, API_DEFAULT_VERSION),
+            #[cfg(windows)]
+            Some("npipe") => {
+                Docker::connect_with_named_pipe(&host, DAEMON_TIMEOUT, API_DEFAULT_VERSION)
+            }
+            Some("tcp" | "http" | "https") if tls => {
+                let certs = self
+                    .cert_path
+                    .clone()
+                    .or_else(|| env("DOCKER_CERT_PATH").map(PathBuf::from))
+                    .or_else(|| env("DOCKER_CONFIG").map(PathBuf::from))
+                    .or_else(|| env("HOME").map(|home| PathBuf::from(home).join(".docker")))
+                    .ok_or_else(|| eyre::eyre!("no directory for daemon certificates"))
+                    .with_note(|| "set DOCKER_CERT_PATH or --docker-cert-path")?;
+                Docker::connect_with_ssl(
+                    &host,
+                    &certs.join("key.pem"),
+                    &certs.join("cert.pem"),
+                    &certs.join("ca.pem"),
+                    DAEMON_TIMEOUT,
+                    API_DEFAULT_VERSION,
+                )
+            }
+            Some("tcp" | "http") => {
+                Docker::connect_with_http(&host, DAEMON_TIMEOUT, API_DEFAULT_VERSION)
+            }
+            Some("ssh") => Docker::connect_with_ssh(&host, DAEMON_TIMEOUT, API_DEFAULT_VERSION),
+            _ => {
+                return Err(eyre::Report::new(Kind::Unsupported))
+                    .context(format!("docker host '{host}'"))
+                    .with_note(|| "supported schemes are unix, npipe, tcp, http, https, and ssh")
+                    .map_err(Error::from)
+            }
+        };
+        docker
+            .with_context(|| format!("connect to docker daemon at '{host}'"))
+            .map_err(Error::from)
+    }
+}
+
+/// Connect to a local daemon at a socket, or a named pipe on Windows.
+fn connect_local(socket: &Path) -> Result<Docker, bollard::errors::Error> {
+    let socket = socket.to_string_lossy();
+    #[cfg(unix)]
+    return Docker::connect_with_unix(&socket, DAEMON_TIMEOUT, API_DEFAULT_VERSION);
+    #[cfg(windows)]
+    return Docker::connect_with_named_pipe(&socket, DAEMON_TIMEOUT, API_DEFAULT_VERSION);
+}
+
+/// Find the socket of a local daemon: the first of [`socket_candidates`] that exists.
+///
+/// Besides the standard location, this finds the daemons run by tools like Colima,
+/// Rancher Desktop, Podman machine, and Docker Desktop on macOS, which don't use it.
+fn discover_socket() -> Option<PathBuf> {
+    socket_candidates(|name| std::env::var_os(name))
+        .into_iter()
+        .find(|socket| socket.exists())
+        .tap_some(|socket| debug!(?socket, "discovered docker socket"))
+}
+
+/// The locations at which local daemons listen, in the order they're probed; see [`discover_socket`].
+///
+/// Environment variables are read with `env` so that the locations can be tested.
+#[cfg(unix)]
+fn socket_candidates(env: impl Fn(&str) -> Option<OsString>) -> Vec<PathBuf> {
+    let home = env("HOME").map(PathBuf::from);
+    let runtime = env("XDG_RUNTIME_DIR").map(PathBuf::from);
+
+    let mut sockets = vec![PathBuf::from("/var/run/docker.sock")];
+    if let Some(home) = &home {
+        sockets.extend([
+            // Docker Desktop on macOS, and on Linux.
+            home.join(".docker").join("run").join("docker.sock"),
+            home.join(".docker").join("desktop").join("docker.sock"),
+            // Colima's default profile.
+            home.join(".colima").join("default").join("docker.sock"),
+            // Rancher Desktop.
+            home.join(".rd").join("docker.sock"),
+        ]);
+    }
+    if let Some(runtime) = runtime {
+        // Rootless Podman, which is also how Podman machine is exposed on Linux.
+        sockets.push(runtime.join("podman").join("podman.sock"));
+    }
+    if let Some(home) = &home {
+        // Podman machine on macOS.
+        let machine = home.join(".local/share/containers/podman/machine");
+        sockets.extend([
+            machine.join("podman.sock"),
+            machine.join("qemu").join("podman.sock"),
+        ]);
+    }
+    sockets.push(PathBuf::from("/run/podman/podman.sock"));
+    sockets
+}
+
+/// Windows daemons listen on named pipes, which bollard finds with its defaults.
+#[cfg(windows)]
+fn socket_candidates(_: impl Fn(&str) -> Option<OsString>) -> Vec<PathBuf> {
+    Vec::new()
+}
+
+/// Each instance is a unique view of a Docker daemon for a specific [`Reference`].
+/// Similar to [`crate::registry::Registry`], but interacts with a Docker daemon.
+#[derive(Debug)]
+pub struct Daemon {
+    /// The image saved from the daemon, in the same format as `docker save`.
+    ///
+    /// This is referenced in [`Tarball`] by path; in order to keep tarball generic
+    /// it doesn't actually take ownership of the tempfile handle itself.
+    #[debug(skip)]
+    _exported: TempFile,
+
+    /// Reads the layers from the saved image.
+    tarball: Tarball,
+
+    /// The reference for the image the user provided.
+    reference: String,
+}
+
+#[bon::bon]
+impl Daemon {
+    /// Create a new daemon for a specific reference.
+    #[builder]
+    #[tracing::instrument(name = "Daemon::new", skip(progress))]
+    pub async fn new(
+        /// Filters for layers.
+        /// Only layers matched by the filters are processed; see [`Filters`].
+        #[builder(into)]
+        layer_filters: Option<Filters>,
+
+        /// Filters for files.
+        /// Only files matched by the filters are processed; see [`Filters`].
+        #[builder(into)]
+        file_filters: Option<Filters>,
+
+        /// How to handle paths that collide on case-insensitive filesystems when layers are applied.
+        case_collisions: Option<CaseCollisions>,
+
+        /// Directory to which paths deleted by whiteouts are moved instead of being removed,
+        /// so that content deleted by later layers can still be inspected.
+        /// Each layer's deletions are moved to a subdirectory named after the hex of its digest.
+        #[builder(into)]
+        deleted_dir: Option<PathBuf>,
+
+        /// Commands through which layers are piped after they are decompressed.
+        filter_commands: Option<FilterCommands>,
+
+        /// Safety limits enforced as layers are applied; by default layers aren't limited.
+        limits: Option<Limits>,
+
+        /// Receives progress updates as layers are read and applied.
+        progress: Option<SharedProgress>,
+
+        /// How layer content is buffered as it's read and unpacked.
+        buffering: Option<Buffering>,
+
+        /// The platform to read, if the daemon has content for multiple platforms of the image.
+        platform: Option<Platform>,
+
+        /// When the daemon pulls the image from its registry before it's read; by default it never does.
+        pull_policy: Option<PullPolicy>,
+
+        /// How to connect to the daemon; by default the daemon is found the same way as the Docker CLI.
+        connection: Option<Connection>,
+
+        /// The reference for the image the user provided.
+        #[builder(into)]
+        reference: String,
+    ) -> Result<Self> {
+        crate::flag_disabled_daemon_docker()?;
+
+        let docker = connection.unwrap_or_default().connect()?;
+        let pull_policy = pull_policy.unwrap_or_default();
+        if pull_policy != PullPolicy::Never {
+            crate::flag_offline("pulling the image with the docker daemon")?;
+        }
+        if pull_policy == PullPolicy::Always {
+            pull_image(&docker, &reference, platform.as_ref())
+                .await
+                .context("pull image")?;
+        }
+
+        let image = match find_image(&docker, &reference, platform.as_ref()).await {
+            Ok(image) => image,
+            Err(err) if pull_policy == PullPolicy::Missing => match Error::from(err) {
+                Error::NotFound(_) => {
+                    pull_image(&docker, &reference, platform.as_ref())
+                        .await
+                        .context("pull missing image")?;
+                    find_image(&docker, &reference, platform.as_ref())
+                        .await
+                        .context("find pulled image")?
+                }
+                err => return Err(err),
+            },
+            Err(err) => return Err(err.wrap_err("find image").into()),
+        };
+
+        // Despite its name, this saves the image (like `docker save`) rather than exporting a container.
+        let stream = docker.export_image(&image);
+        let exported = cio::collect_tmp(stream)
+            .await
+            .context("collect exported image")?;
+
+        debug!(exported = ?exported.file_path(), "exported temporary image");
+        let tarball = Tarball::builder()
+            .maybe_file_filters(file_filters)
+            .maybe_layer_filters(layer_filters)
+            .maybe_case_collisions(case_collisions)
+            .maybe_deleted_dir(deleted_dir)
+            .maybe_filter_commands(filter_commands)
+            .maybe_limits(limits)
+            .maybe_progress(progress)
+            .maybe_buffering(buffering)
+            .maybe_platform(platform)
+            .name(image)
+            .path(exported.file_path())
+            .build()
+            .await
+            .context("create tarball")?;
+
+        debug!(tarball = ?tarball.path, "created tarball");
+        Ok(Self {
+            _exported: exported,
+            tarball,
+            reference,
+        })
+    }
+}
+
+impl Source for Daemon {
+    async fn digest(&self) -> Result<Digest> {
+        self.tarball.digest().await
+    }
+
+    async fn name(&self) -> Result<String> {
+        self.tarball.name().await
+    }
+
+    async fn identity(&self) -> Result<Identity> {
+        let digest = self.tarball.digest().await?;
+        Ok(Identity::builder()
+            .reference(&self.reference)
+            .resolved_digest(digest)
+            .display_name(repository(&self.reference))
+            .build())
+    }
+
+    async fn layers(&self) -> Result<Vec<Layer>> {
+        self.tarball.layers().await
+    }
+
+    async fn config(&self) -> Result<ImageConfig> {
+        self.tarball.config().await
+    }
+
+    async fn pull_layer(
+        &self,
+        layer: &Layer,
+    ) -> Result<Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>> {
+        self.tarball.pull_layer(layer).await
+    }
+}
+
+impl Unpack for Daemon {
+    async fn list_files(&self, layer: &Layer) -> Result<Vec<String>> {
+        self.tarball.list_files(layer).await
+    }
+
+    async fn visit_files(
+        &self,
+        layer: &Layer,
+        on_file: impl FnMut(String) -> std::io::Result<()>,
+    ) -> Result<()> {
+        self.tarball.visit_files(layer, on_file).await
+    }
+
+    async fn apply_layer(&self, layer: &Layer, output: &Path) -> Result<()> {
+        self.tarball.apply_layer(layer, output).await
+    }
+
+    async fn layer_plain_tarball(&self, layer: &Layer) -> Result<Option<TempFile>> {
+        self.tarball.layer_plain_tarball(layer).await
+    }
+}
+
+/// The repository named by a reference to an image in the Docker daemon,
+/// which is the reference without its tag or digest (e.g. `library/ubuntu` for `library/ubuntu:latest`).
+fn repository(reference: &str) -> &str {
+    let reference = reference
+        .split_once('@')
+        .map_or(reference, |(name, _)| name);
+    match reference.rsplit_once(':') {
+        Some((name, tag)) if !tag.contains('/') => name,
+        _ => reference,
+    }
+}
+
+/// Find the ID of the image for the specified reference in the Docker daemon, if it exists.
+/// If it doesn't exist, or doesn't have content for the platform, this function returns an error.
+#[tracing::instrument]
+async fn find_image(
+    docker: &Docker,
+    reference: &str,
+    platform: Option<&Platform>,
+) -> eyre::Result<String> {
+    let opts = ListImagesOptionsBuilder::new()
+        .all(true)
+        .manifests(true)
+        .build();
+
+    let images = docker
+        .list_images(Some(opts))
+        .await
+        .context("list images")?;
+    debug!(?images, "listed images");
+
+    // Images in the docker daemon don't use the fully qualified reference,
+    // they look like this:
+    // ```
+    // repo_tags: [
+    //     "changeset_example:latest",
+    // ],
+    // repo_digests: [
+    //     "changeset_example@sha256:1af7aa8d7fe18420f10b46a78c23c5c9cb01817d30a03a12c33e8a26555f7b4f",
+    // ],
+    // repo_tags: [
+    //     "fossaeng/changeset_example:latest",
+    // ],
+    // repo_digests: [
+    //     "fossaeng/changeset_example@sha256:495f92a2c50d0b1550b232213c19bd4b5121a2268f95f0b7be6bb1c7dd51c4ce",
+    // ],
+    // ```
+    // As such, we primarily use the string the user provided;
+    // if it matches any tag or digest it's good to go.
+    // See `match_image` for the fallbacks used when it doesn't.
+
+    if let Some(image) = match_image(&images, reference) {
+        debug!(?image, "found image");
+        if let Some(platform) = platform {
+            let summary = images.iter().find(|i| i.id == image);
+            if let Some(summary) = summary {
+                ensure_platform(summary, reference, platform)?;
+            }
+        }
+        return Ok(image);
+    }
+
+    let listings = images
+        .iter()
+        .flat_map(|i| i.repo_tags.iter().chain(i.repo_digests.iter()))
+        .collect::<Vec<_>>();
+    Err(Report::new(Kind::NotFound))
+        .with_context(|| format!("image not found: {reference}"))
+        .with_note(|| format!("{listings:#?}").header("Images:"))
+}
+
+/// Have the daemon pull the image from its registry, for the platform if provided.
+///
+/// The daemon authenticates with its own credentials, as it does for `docker pull`.
+async fn pull_image(
+    docker: &Docker,
+    reference: &str,
+    platform: Option<&Platform>,
+) -> eyre::Result<()> {
+    let host = reference
+        .parse::<Reference>()
+        .context("parse reference")?
+        .host;
+    crate::flag_disallowed_host(&host)?;
+
+    // The daemon only accepts `os/architecture[/variant]`, without the OS version.
+    let platform = platform
+        .map(|p| match &p.variant {
+            Some(variant) => format!("{}/{}/{variant}", p.os, p.architecture),
+            None => format!("{}/{}", p.os, p.architecture),
+        })
+        .unwrap_or_default();
+    let opts = CreateImageOptionsBuilder::new()
+        .from_image(reference)
+        .platform(&platform)
+        .build();
+
+    let mut progress = docker.create_image(Some(opts), None, None);
+    while let Some(info) = progress.next().await {
+        let info = info.context("read pull progress")?;
+        if let Some(err) = info.error {
+            eyre::bail!("pull {reference}: {err}");
+        }
+        debug!(status = ?info.status, progress = ?info.progress, "pulling image");
+    }
+
+    debug!(%reference, "pulled image");
+    Ok(())
+}
+
+/// Ensure that the daemon has the content of the image for the platform.
+///
+/// Only the containerd image store records multiple platforms for an image,
+/// some of which may not have been pulled;
+/// images with content for a single platform are read regardless of the platform requested,
+/// consistent with how multi-platform selection works for registries.
+fn ensure_platform(image: &ImageSummary, reference: &str, platform: &Platform) -> eyre::Result<()> {
+    let platforms = image
+        .manifests
+        .iter()
+        .flatten()
+        .filter(|m| m.kind == Some(ImageManifestSummaryKindEnum::IMAGE))
+        .filter_map(|m| {
+            m.image_data
+                .as_ref()
+                .map(|data| (m.available, &data.platform))
+        })
+        .collect::<Vec<_>>();
+    if platforms.len() <= 1 {
+        return Ok(());
+    }
+
+    let recorded = |p: &OciPlatform| {
+        Platform::builder()
+            .os(p.os.as_deref().unwrap_or_default())
+            .architecture(p.architecture.as_deref().unwrap_or_default())
+            .maybe_variant(p.variant.as_deref())
+            .maybe_os_version(p.os_version.as_deref())
+            .build()
+    };
+    if platforms
+        .iter()
+        .any(|(available, p)| *available && platform.matches(&recorded(p)))
+    {
+        return Ok(());
+    }
+
+    let available = platforms
+        .iter()
+        .filter(|(available, _)| *available)
+        .map(|(_, p)| recorded(p).to_string())
+        .collect::<Vec<_>>();
+    Err(Report::new(Kind::NotFound))
+        .with_context(|| format!("platform {platform} of {reference} is not available locally"))
+        .with_section(|| available.join("\n").header("Available platforms:"))
+        .with_suggestion(|| format!("run `docker pull --platform {platform} {reference}`"))
+}
+
+/// Find the ID of the image matching the reference in the images listed by the daemon.
+///
+/// The reference is first matched exactly against tags and digests.
+/// If that fails:
+/// - References without a tag or digest are matched as though they had the tag `latest`,
+///   consistent with how the docker CLI resolves them.
+/// - References with a digest are matched by the digest alone; this allows a user to provide
+///   e.g. `docker.io/library/ubuntu@sha256:...` when the daemon has the content as `ubuntu@sha256:...`
+///   or under a different repository entirely. The digest is compared against both the manifest digests
+///   in `repo_digests` and the image ID (which is the digest of the image configuration).
+fn match_image(images: &[ImageSummary], reference: &str) -> Option<String> {
+    let by_tag_or_digest = |reference: &str| {
+        images.iter().find(|i| {
+            i.repo_tags.iter().any(|t| t == reference)
+                || i.repo_digests.iter().any(|d| d == reference)
+        })
+    };
+
+    if let Some(image) = by_tag_or_digest(reference) {
+        return Some(image.id.clone());
+    }
+
+    match reference.split_once('@') {
+        Some((_, digest)) => {
+            let suffix = format!("@{digest}");
+            images
+                .iter()
+                .find(|i| i.repo_digests.iter().any(|d| d.ends_with(&suffix)))
+                .or_else(|| images.iter().find(|i| i.id == digest))
+                .tap_some(|image| debug!(id = ?image.id, "matched image by digest"))
+                .map(|image| image.id.clone())
+        }
+        None => {
+            // The tag separator is only meaningful in the final path segment;
+            // earlier colons may be part of the host (e.g. `localhost:5000/app`).
+            let name = reference.rsplit('/').next().unwrap_or(reference);
+            if name.contains(':') {
+                return None;
+            }
+
+            by_tag_or_digest(&format!("{reference}:latest")).map(|image| image.id.clone())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bollard::models::{ImageManifestSummary, ImageManifestSummaryImageData};
+    use simple_test_case::test_case;
+
+    fn image_summary(id: &str, tags: &[&str], digests: &[&str]) -> ImageSummary {
+        ImageSummary {
+            id: id.to_string(),
+            repo_tags: tags.iter().map(|t| t.to_string()).collect(),
+            repo_digests: digests.iter().map(|d| d.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[cfg(unix)]
+    #[test_case(&[], &["/var/run/docker.sock", "/run/podman/podman.sock"]; "no_home")]
+    #[test_case(&[("HOME", "/home/me"), ("XDG_RUNTIME_DIR", "/run/user/1000")], &[
+        "/var/run/docker.sock",
+        "/home/me/.docker/run/docker.sock",
+        "/home/me/.docker/desktop/docker.sock",
+        "/home/me/.colima/default/docker.sock",
+        "/home/me/.rd/docker.sock",
+        "/run/user/1000/podman/podman.sock",
+        "/home/me/.local/share/containers/podman/machine/podman.sock",
+        "/home/me/.local/share/containers/podman/machine/qemu/podman.sock",
+        "/run/podman/podman.sock",
+    ]; "home")]
+    #[test]
+    fn socket_candidates(vars: &[(&str, &str)], expected: &[&str]) {
+        let env = |name: &str| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| OsString::from(value))
+        };
+        let expected = expected.iter().map(PathBuf::from).collect::<Vec<_>>();
+        pretty_assertions::assert_eq!(expected, super::socket_candidates(env));
+    }
+
+    #[test_case("changeset_example:latest", Some("sha256:1111"); "tag")]
+    #[test_case("changeset_example@sha256:aaaa", Some("sha256:1111"); "repo_digest")]
+    #[test_case("changeset_example", Some("sha256:1111"); "default_tag")]
+    #[test_case("docker.io/fossaeng/other@sha256:aaaa", Some("sha256:1111"); "digest_other_name")]
+    #[test_case("changeset_example@sha256:2222", Some("sha256:2222"); "image_id")]
+    #[test_case("changeset_example:v2", None; "other_tag")]
+    #[test_case("localhost:5000/app", None; "host_default_tag")]
+    #[test_case("localhost:5000/app:v1", Some("sha256:2222"); "host_tag")]
+    #[test_case("changeset_example@sha256:cccc", None; "unknown_digest")]
+    #[test]
+    fn match_image_fallbacks(reference: &str, expected: Option<&str>) {
+        let images = vec![
+            image_summary(
+                "sha256:1111",
+                &["changeset_example:latest"],
+                &["changeset_example@sha256:aaaa"],
+            ),
+            image_summary(
+                "sha256:2222",
+                &["localhost:5000/app:v1"],
+                &["localhost:5000/app@sha256:bbbb"],
+            ),
+        ];
+
+        pretty_assertions::assert_eq!(expected, match_image(&images, reference).as_deref());
+    }
+
+    fn manifest(os: &str, arch: &str, available: bool) -> ImageManifestSummary {
+        ImageManifestSummary {
+            available,
+            kind: Some(ImageManifestSummaryKindEnum::IMAGE),
+            image_data: Some(ImageManifestSummaryImageData {
+                platform: OciPlatform {
+                    os: Some(os.to_string()),
+                    architecture: Some(arch.to_string()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn ensure_platform_available() {
+        let image = ImageSummary {
+            manifests: Some(vec![
+                manifest("linux", "amd64", true),
+                manifest("linux", "arm64", false),
+            ]),
+            ..Default::default()
+        };
+        let single = ImageSummary {
+            manifests: Some(vec![manifest("linux", "amd64", true)]),
+            ..Default::default()
+        };
+
+        let platform = |p: &str| p.parse::<Platform>().expect("parse platform");
+        let cases = [
+            (&image, "linux/amd64", true),
+            (&image, "linux/arm64", false),
+            (&image, "linux/s390x", false),
+            (&single, "linux/arm64", true),
+            (&ImageSummary::default(), "linux/arm64", true),
+        ];
+
+        for (image, requested, expected) in cases {
+            pretty_assertions::assert_eq!(
+                ensure_platform(image, "alpine", &platform(requested)).is_ok(),
+                expected,
+                "platform: {requested}"
+            );
+        }
+    }
+
+    #[test]
+    fn repository_strips_version() {
+        let cases = [
+            ("alpine", "alpine"),
+            ("library/ubuntu:latest", "library/ubuntu"),
+            (
+                "fossaeng/changeset_example@sha256:1af7aa8d",
+                "fossaeng/changeset_example",
+            ),
+            ("localhost:5000/app:1.0", "localhost:5000/app"),
+            ("localhost:5000/app", "localhost:5000/app"),
+        ];
+
+        for (reference, expected) in cases {
+            pretty_assertions::assert_eq!(
+                expected,
+                repository(reference),
+                "reference: {reference}"
+            );
+        }
+    }
+}
@@ -0,0 +1,286 @@
+//! Read the entries of a layer tarball without extracting it to disk.
+//!
+//! See [`crate::Unpack::entries`].
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use color_eyre::eyre::{self, Context as _, Report};
+use derive_more::Debug;
+use futures_lite::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio_tar::{Archive, EntryType};
+use tokio_util::io::StreamReader;
+
+use crate::{cio::is_whiteout, transform::Chunk, Error, Result};
+
+type Reader = StreamReader<Pin<Box<dyn Stream<Item = Chunk>>>, Bytes>;
+
+/// A stream of the entries in a layer tarball, in the order they appear in the tarball.
+///
+/// Entries are read from a single underlying stream, so the body of each entry
+/// is only readable until the next entry is requested.
+#[derive(Debug)]
+pub struct Entries {
+    #[debug(skip)]
+    inner: tokio_tar::Entries<Reader>,
+}
+
+impl Entries {
+    /// Read the entries of an uncompressed tarball stream.
+    pub(crate) fn new(stream: Pin<Box<dyn Stream<Item = Chunk>>>) -> Result<Self> {
+        let mut archive = Archive::new(StreamReader::new(stream));
+        let inner = archive
+            .entries()
+            .context("read entries from tar")
+            .map_err(Error::from)?;
+        Ok(Self { inner })
+    }
+}
+
+impl Stream for Entries {
+    type Item = Result<Entry>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner).poll_next(cx).map(|entry| {
+            entry.map(|entry| {
+                entry
+                    .map_err(Report::from)
+                    .and_then(Entry::new)
+                    .context("read entry")
+                    .map_err(Error::from)
+            })
+        })
+    }
+}
+
+/// The kind of an [`Entry`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum EntryKind {
+    /// A regular file.
+    File,
+
+    /// A directory.
+    Directory,
+
+    /// A symbolic link; see [`Entry::link_target`].
+    Symlink,
+
+    /// A hard link to another entry in the layer; see [`Entry::link_target`].
+    HardLink,
+
+    /// Any other kind of entry, such as a device or FIFO.
+    Other,
+}
+
+impl From<EntryType> for EntryKind {
+    fn from(kind: EntryType) -> Self {
+        if kind.is_file() {
+            Self::File
+        } else if kind.is_dir() {
+            Self::Directory
+        } else if kind.is_symlink() {
+            Self::Symlink
+        } else if kind.is_hard_link() {
+            Self::HardLink
+        } else {
+            Self::Other
+        }
+    }
+}
+
+/// The details recorded in the tar header of an entry.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Header {
+    /// The path of the entry, relative to the root of the container.
+    pub path: PathBuf,
+
+    /// The kind of the entry.
+    pub kind: EntryKind,
+
+    /// The raw type flag of the entry, e.g. `0` for a regular file or `x` for PAX extensions.
+    pub typeflag: char,
+
+    /// The unix mode of the entry.
+    pub mode: u32,
+
+    /// The owner of the entry, if recorded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u64>,
+
+    /// The group of the entry, if recorded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u64>,
+
+    /// The name of the owner of the entry, if recorded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+
+    /// The name of the group of the entry, if recorded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub groupname: Option<String>,
+
+    /// The size of the entry's body in bytes.
+    pub size: u64,
+
+    /// The modification time of the entry in seconds since the unix epoch, if recorded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<u64>,
+
+    /// The target of the entry, if it is a link.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link_target: Option<PathBuf>,
+
+    /// The records in the entry, if it is a set of PAX extensions.
+    ///
+    /// Records are reported in the order they appear in the entry.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub pax: Vec<(String, String)>,
+}
+
+impl Header {
+    fn new(entry: &tokio_tar::Entry<Archive<Reader>>) -> eyre::Result<Self> {
+        let header = entry.header();
+        let kind = header.entry_type();
+        Ok(Self {
+            path: entry.path().context("read path")?.into_owned(),
+            kind: EntryKind::from(kind),
+            typeflag: char::from(kind.as_byte()),
+            mode: header.mode().context("read mode")?,
+            // Many tools leave these fields blank, which isn't an error
+            // since they aren't needed to read the entry.
+            uid: header.uid().ok(),
+            gid: header.gid().ok(),
+            username: header.username_bytes().map(lossy),
+            groupname: header.groupname_bytes().map(lossy),
+            size: header.size().context("read size")?,
+            mtime: header.mtime().ok(),
+            link_target: entry
+                .link_name()
+                .context("read link target")?
+                .map(|target| target.into_owned()),
+            pax: Vec::new(),
+        })
+    }
+}
+
+fn lossy(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).to_string()
+}
+
+/// List the headers of every entry in an uncompressed tarball stream.
+///
+/// Unlike [`Entries`], this reports the raw headers in the tarball:
+/// PAX extensions and GNU long name entries are listed as entries in their own right
+/// rather than being applied to the entries that follow them.
+/// This is intended for debugging malformed layers.
+pub(crate) async fn headers(stream: Pin<Box<dyn Stream<Item = Chunk>>>) -> Result<Vec<Header>> {
+    let mut archive = Archive::new(StreamReader::new(stream));
+    let mut entries = archive
+        .entries_raw()
+        .context("read entries from tar")
+        .map_err(Error::from)?;
+
+    let mut headers = Vec::new();
+    while let Some(entry) = entries.next().await {
+        let mut entry = entry.context("read entry").map_err(Error::from)?;
+        let mut header = Header::new(&entry)
+            .context("read header")
+            .map_err(Error::from)?;
+
+        if let Some(extensions) = entry
+            .pax_extensions()
+            .await
+            .context("read pax extensions")
+            .map_err(Error::from)?
+        {
+            header.pax = extensions
+                .map(|extension| {
+                    extension.map(|extension| {
+                        (lossy(extension.key_bytes()), lossy(extension.value_bytes()))
+                    })
+                })
+                .collect::<io::Result<_>>()
+                .context("parse pax extensions")
+                .map_err(Error::from)?;
+        }
+
+        headers.push(header);
+    }
+
+    Ok(headers)
+}
+
+/// An entry in a layer tarball.
+///
+/// The body of the entry is read with [`AsyncRead`].
+#[derive(Debug)]
+pub struct Entry {
+    header: Header,
+
+    #[debug(skip)]
+    inner: tokio_tar::Entry<Archive<Reader>>,
+}
+
+impl Entry {
+    fn new(inner: tokio_tar::Entry<Archive<Reader>>) -> eyre::Result<Self> {
+        let header = Header::new(&inner)?;
+        Ok(Self { header, inner })
+    }
+
+    /// The details recorded in the tar header of the entry.
+    ///
+    /// PAX extensions and GNU long names are already applied to the header,
+    /// so [`Header::pax`] is always empty.
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// The path of the entry, relative to the root of the container.
+    pub fn path(&self) -> &Path {
+        &self.header.path
+    }
+
+    /// The kind of the entry.
+    pub fn kind(&self) -> EntryKind {
+        self.header.kind
+    }
+
+    /// The size of the entry's body in bytes.
+    pub fn size(&self) -> u64 {
+        self.header.size
+    }
+
+    /// The unix mode of the entry.
+    pub fn mode(&self) -> u32 {
+        self.header.mode
+    }
+
+    /// The target of the entry, if it is a link.
+    pub fn link_target(&self) -> Option<&Path> {
+        self.header.link_target.as_deref()
+    }
+
+    /// If the entry is a whiteout, the path that it deletes from prior layers.
+    pub fn whiteout(&self) -> Option<PathBuf> {
+        is_whiteout(&self.header.path)
+    }
+}
+
+impl AsyncRead for Entry {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
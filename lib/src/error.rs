@@ -0,0 +1,309 @@
+//! Errors reported by the library.
+//!
+//! Internally the library uses `eyre` so that errors can be annotated with context as they propagate;
+//! at the public API boundary errors are converted to [`Error`], which classifies the failure
+//! so that callers can branch on it. The context is preserved in the source chain of the error.
+
+use color_eyre::Report;
+use oci_client::errors::{OciDistributionError, OciErrorCode};
+
+/// Convenience alias for results in this library.
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The underlying error, including any context with which it was annotated.
+pub type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// Errors reported by the library.
+///
+/// Each variant displays as the underlying error, so the variant itself only serves to classify the failure.
+/// New variants may be added as the library learns to distinguish more kinds of failure.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// The registry (or Docker daemon) rejected the provided credentials,
+    /// or requires credentials that were not provided.
+    ///
+    /// Note that some registries report authentication failures
+    /// when the actual issue is that the image does not exist.
+    #[error(transparent)]
+    Auth(BoxError),
+
+    /// The image, tag, layer, or file does not exist.
+    #[error(transparent)]
+    NotFound(BoxError),
+
+    /// Communication with the registry or Docker daemon failed.
+    #[error(transparent)]
+    Network(BoxError),
+
+    /// Input could not be parsed; for example an image reference, platform, digest, or manifest.
+    #[error(transparent)]
+    Parse(BoxError),
+
+    /// Reading from or writing to the local filesystem failed.
+    #[error(transparent)]
+    Io(BoxError),
+
+    /// The image (or other input) uses a format that isn't supported;
+    /// for example a layer media type or compression that circe can't read.
+    #[error(transparent)]
+    Unsupported(BoxError),
+
+    /// The operation was disabled by the environment; see e.g. [`crate::OCI_DISABLE_REGISTRY_OCI_VAR`].
+    #[error(transparent)]
+    Disabled(BoxError),
+
+    /// A layer exceeded one of the configured [`crate::Limits`].
+    #[error(transparent)]
+    Limit(BoxError),
+
+    /// Any other failure.
+    #[error(transparent)]
+    Other(BoxError),
+}
+
+impl Error {
+    /// Create a parse error.
+    pub(crate) fn parse(err: impl Into<BoxError>) -> Self {
+        Self::Parse(err.into())
+    }
+
+    /// Create an error indicating that the operation is disabled.
+    pub(crate) fn disabled(err: impl Into<BoxError>) -> Self {
+        Self::Disabled(err.into())
+    }
+}
+
+/// Marks the kind of an error created internally with `eyre`.
+///
+/// Most errors are classified by the type of their root cause (for example [`std::io::Error`] is [`Error::Io`]),
+/// but errors that the library reports itself have no such type.
+/// In those cases, this is used as the root cause so the kind survives any context added to it:
+/// ```not_rust
+/// Err(Report::new(Kind::NotFound)).context(format!("image not found: {reference}"))
+/// ```
+// Only sources that read local files currently report `NotFound` this way.
+#[cfg_attr(not(feature = "native"), allow(dead_code))]
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+pub(crate) enum Kind {
+    #[error("authentication failed")]
+    Auth,
+
+    #[error("not found")]
+    NotFound,
+
+    #[error("unsupported")]
+    Unsupported,
+
+    #[error("limit exceeded")]
+    Limit,
+}
+
+impl From<Report> for Error {
+    fn from(report: Report) -> Self {
+        let variant = report.chain().find_map(classify).unwrap_or(Error::Other);
+        variant(BoxError::from(report))
+    }
+}
+
+/// Constructs a variant of [`Error`].
+type Variant = fn(BoxError) -> Error;
+
+/// Classify an error in the chain, if it is of a known type.
+///
+/// The chain is walked from the outermost context to the root cause,
+/// so errors that were already classified (e.g. by a public function called internally) take precedence.
+fn classify(err: &(dyn std::error::Error + 'static)) -> Option<Variant> {
+    if let Some(err) = err.downcast_ref::<Error>() {
+        return Some(match err {
+            Error::Auth(_) => Error::Auth,
+            Error::NotFound(_) => Error::NotFound,
+            Error::Network(_) => Error::Network,
+            Error::Parse(_) => Error::Parse,
+            Error::Io(_) => Error::Io,
+            Error::Unsupported(_) => Error::Unsupported,
+            Error::Disabled(_) => Error::Disabled,
+            Error::Limit(_) => Error::Limit,
+            Error::Other(_) => Error::Other,
+        });
+    }
+
+    if let Some(kind) = err.downcast_ref::<Kind>() {
+        return Some(match kind {
+            Kind::Auth => Error::Auth,
+            Kind::NotFound => Error::NotFound,
+            Kind::Unsupported => Error::Unsupported,
+            Kind::Limit => Error::Limit,
+        });
+    }
+
+    if let Some(err) = err.downcast_ref::<OciDistributionError>() {
+        return Some(classify_oci(err));
+    }
+
+    #[cfg(feature = "daemon")]
+    if let Some(err) = err.downcast_ref::<bollard::errors::Error>() {
+        return Some(classify_bollard(err));
+    }
+
+    if err.is::<tokio::time::error::Elapsed>() {
+        return Some(Error::Network);
+    }
+
+    if let Some(err) = err.downcast_ref::<std::io::Error>() {
+        // Errors in streams are reported as I/O errors, so classify the error they wrap if possible.
+        let wrapped = err.get_ref().and_then(|inner| {
+            let inner: &(dyn std::error::Error + 'static) = inner;
+            std::iter::successors(Some(inner), |err| err.source()).find_map(classify)
+        });
+        if let Some(variant) = wrapped {
+            return Some(variant);
+        }
+
+        return Some(match err.kind() {
+            std::io::ErrorKind::TimedOut => Error::Network,
+            _ => Error::Io,
+        });
+    }
+
+    if err.is::<serde_json::Error>() || err.is::<regex::Error>() {
+        return Some(Error::Parse);
+    }
+
+    None
+}
+
+fn classify_oci(err: &OciDistributionError) -> Variant {
+    match err {
+        OciDistributionError::AuthenticationFailure(_)
+        | OciDistributionError::UnauthorizedError { .. }
+        | OciDistributionError::RegistryTokenDecodeError(_) => Error::Auth,
+        OciDistributionError::ImageManifestNotFoundError(_) => Error::NotFound,
+        OciDistributionError::RequestError(_) => Error::Network,
+        OciDistributionError::IoError(_) => Error::Io,
+        OciDistributionError::JsonError(_)
+        | OciDistributionError::ManifestEncodingError(_)
+        | OciDistributionError::ManifestParsingError(_)
+        | OciDistributionError::VersionedParsingError(_)
+        | OciDistributionError::DigestError(_) => Error::Parse,
+        OciDistributionError::ServerError { code, .. } => match code {
+            401 | 403 => Error::Auth,
+            404 => Error::NotFound,
+            _ => Error::Network,
+        },
+        OciDistributionError::RegistryError { envelope, .. } => envelope
+            .errors
+            .iter()
+            .find_map(|err| match err.code {
+                OciErrorCode::Unauthorized | OciErrorCode::Denied => Some(Error::Auth as Variant),
+                OciErrorCode::BlobUnknown
+                | OciErrorCode::ManifestBlobUnknown
+                | OciErrorCode::ManifestUnknown
+                | OciErrorCode::NameUnknown
+                | OciErrorCode::NotFound => Some(Error::NotFound),
+                _ => None,
+            })
+            .unwrap_or(Error::Other),
+        _ => Error::Other,
+    }
+}
+
+#[cfg(feature = "daemon")]
+fn classify_bollard(err: &bollard::errors::Error) -> Variant {
+    use bollard::errors::Error as BollardError;
+    match err {
+        BollardError::DockerResponseServerError { status_code, .. } => match status_code {
+            401 | 403 => Error::Auth,
+            404 => Error::NotFound,
+            _ => Error::Other,
+        },
+        BollardError::SocketNotFoundError(_)
+        | BollardError::HyperResponseError { .. }
+        | BollardError::HyperLegacyError { .. }
+        | BollardError::RequestTimeoutError => Error::Network,
+        BollardError::IOError { .. } => Error::Io,
+        BollardError::JsonDataError { .. } | BollardError::JsonSerdeError { .. } => Error::Parse,
+        _ => Error::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use color_eyre::eyre::Context;
+
+    #[test]
+    fn classify_oci_auth() {
+        let err = Err::<(), _>(OciDistributionError::AuthenticationFailure(
+            "denied".to_string(),
+        ))
+        .context("pull image manifest")
+        .map_err(Error::from);
+        assert!(matches!(err, Err(Error::Auth(_))), "{err:?}");
+    }
+
+    #[test]
+    fn classify_kind() {
+        let err = Err::<(), _>(Report::new(Kind::NotFound))
+            .context("image not found: ubuntu")
+            .map_err(Error::from);
+        assert!(matches!(err, Err(Error::NotFound(_))), "{err:?}");
+    }
+
+    #[test]
+    fn classify_unsupported() {
+        let err = Err::<(), _>(Report::new(Kind::Unsupported))
+            .context("unknown media type: application/x-custom")
+            .context("parse media type")
+            .map_err(Error::from);
+        assert!(matches!(err, Err(Error::Unsupported(_))), "{err:?}");
+    }
+
+    #[test]
+    fn classify_io() {
+        let err = Err::<(), _>(std::io::Error::other("disk full"))
+            .context("write report")
+            .map_err(Error::from);
+        assert!(matches!(err, Err(Error::Io(_))), "{err:?}");
+    }
+
+    #[test]
+    fn classify_timeout() {
+        let err = Err::<(), _>(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "no content received",
+        ))
+        .context("apply layer")
+        .map_err(Error::from);
+        assert!(matches!(err, Err(Error::Network(_))), "{err:?}");
+    }
+
+    #[test]
+    fn classify_wrapped_in_io() {
+        let limit = Report::new(Kind::Limit).wrap_err("layer is too large");
+        let err = Err::<(), _>(std::io::Error::other(BoxError::from(limit)))
+            .context("buffer layer")
+            .map_err(Error::from);
+        assert!(matches!(err, Err(Error::Limit(_))), "{err:?}");
+    }
+
+    #[test]
+    fn classify_preserves_existing() {
+        let inner = Error::parse("invalid reference");
+        let err = Error::from(Report::new(inner).wrap_err("parse reference"));
+        assert!(matches!(err, Error::Parse(_)), "{err:?}");
+    }
+
+    #[test]
+    fn preserves_context() {
+        let err = Err::<(), _>(std::io::Error::other("disk full"))
+            .context("write report")
+            .map_err(Error::from)
+            .expect_err("must error");
+        pretty_assertions::assert_eq!(err.to_string(), "write report");
+        pretty_assertions::assert_eq!(
+            std::error::Error::source(&err).map(ToString::to_string),
+            Some(String::from("disk full"))
+        );
+    }
+}
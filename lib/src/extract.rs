@@ -1,30 +1,152 @@
 use std::path::{Path, PathBuf};
 
-use crate::{Digest, Layer, Source};
-use bon::Builder;
-use color_eyre::{
-    eyre::{bail, Context, Error},
-    Result,
+use crate::{
+    cio::{self, file_digest, fsverity_digest, walk_files},
+    error::Kind,
+    metrics::{self, warn},
+    progress::Progress,
+    Digest, Error, Identity, Layer, Result, Unpack,
 };
+use bon::Builder;
+use color_eyre::eyre::{self, bail, eyre, Context};
 use futures_lite::{stream, StreamExt};
-use serde::Serialize;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest as _, Sha256};
+use std::str::FromStr;
+use strum::{AsRefStr, EnumIter, IntoEnumIterator};
 use tap::Pipe;
-use tracing::info;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tracing::{debug, info};
+
+mod vfs;
+
+pub use crate::cio::{Spill, SPILL_DIR_NAME};
+pub use vfs::{ListingEntry, Node, NodeKind, Vfs};
 
 /// Report containing details about the extracted container image.
-#[derive(Debug, Serialize, Builder)]
+///
+/// The shape of the report is described by the JSON schema in [`Report::SCHEMA`];
+/// [`Report::SCHEMA_VERSION`] is incremented whenever a field is removed or changes meaning,
+/// while fields may be added without changing it.
+#[derive(Debug, Serialize, Deserialize, Builder)]
 pub struct Report {
+    /// The version of the schema the report conforms to; see [`Report::SCHEMA_VERSION`].
+    ///
+    /// Reports written before the schema was versioned don't record it, and are parsed as version 0.
+    #[builder(skip = Report::SCHEMA_VERSION)]
+    #[serde(default)]
+    pub schema_version: u32,
+
     /// The content-addressable digest of the image.
     #[builder(into)]
     pub digest: String,
 
+    /// How the image is identified, consistently across sources.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity: Option<Identity>,
+
     /// The extracted layers and their corresponding filesystem paths.
     ///
     /// When multiple layer digests point to the same directory path,
     /// it indicates those layers were squashed together in their application order.
     #[builder(into)]
     pub layers: Vec<(Digest, PathBuf)>,
+
+    /// The digest of each layer in the image paired with the digest of its uncompressed content (its "diff ID"),
+    /// for the layers whose diff ID is known.
+    ///
+    /// Registries identify layers by their digest, while image configs and container runtimes use the diff ID;
+    /// see [`Layer::diff_id`] for details.
+    #[builder(default, into)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub diff_ids: Vec<(Digest, Digest)>,
+
+    /// The digest of each regular file written during extraction, if computed.
+    ///
+    /// See [`hash_files`] for details.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<FileDigest>>,
+
+    /// Every entry in the image once its layers are squashed, if the image was listed instead of extracted.
+    ///
+    /// In that case nothing is written to disk, so [`Report::layers`] is empty;
+    /// see [`Vfs::listing`] for details.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub listing: Option<Vec<ListingEntry>>,
+
+    /// Entries that were renamed to avoid colliding with another entry on a case-insensitive filesystem.
+    ///
+    /// See [`crate::CaseCollisions`] for details.
+    #[builder(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub collisions: Vec<Collision>,
+
+    /// The compressed and uncompressed size of the layers read during extraction, if recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+
+    /// The descriptor and timing of each layer applied during extraction, in the order the layers were applied.
+    #[builder(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub layer_details: Vec<LayerDetails>,
+
+    /// Identifies the options that shaped what was written (like which layers and files were extracted), if recorded.
+    ///
+    /// The value is opaque: it's only meant to be compared with the value for another extraction,
+    /// to tell whether both would write the same output.
+    #[builder(into)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub options: Option<String>,
+}
+
+/// The format in which a [`Report`] is emitted by [`Report::emit`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, AsRefStr, EnumIter)]
+pub enum ReportFormat {
+    /// Pretty-printed JSON, as written to [`Report::FILENAME`].
+    #[default]
+    #[strum(serialize = "json")]
+    Json,
+
+    /// YAML, which is easier for people to read; see [`Report::render_yaml`].
+    #[strum(serialize = "yaml")]
+    Yaml,
+
+    /// TOML; see [`Report::render_toml`].
+    #[strum(serialize = "toml")]
+    Toml,
+
+    /// Don't emit the report.
+    #[strum(serialize = "none")]
+    None,
+}
+
+impl ReportFormat {
+    /// Parse the value, reporting errors with detailed context.
+    fn parse(s: &str) -> eyre::Result<Self> {
+        Self::iter()
+            .find(|format| format.as_ref() == s)
+            .ok_or_else(|| {
+                let expected = Self::iter()
+                    .map(|format| format.as_ref().to_string())
+                    .join(", ");
+                eyre!("unknown report format: '{s}' (expected one of: {expected})")
+            })
+    }
+}
+
+impl FromStr for ReportFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s).map_err(Error::parse)
+    }
+}
+
+impl std::fmt::Display for ReportFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_ref())
+    }
 }
 
 impl Report {
@@ -38,14 +160,431 @@ impl Report {
         tokio::fs::write(&path, self.render()?)
             .await
             .context("write report")
+            .map_err(Error::from)
     }
 
     /// Render the report to a string.
     pub fn render(&self) -> Result<String> {
-        serde_json::to_string_pretty(self).context("serialize report")
+        serde_json::to_string_pretty(self)
+            .context("serialize report")
+            .map_err(Error::from)
+    }
+
+    /// Render the report as YAML, which is easier for people to read and review.
+    pub fn render_yaml(&self) -> Result<String> {
+        serde_yaml_ng::to_string(self)
+            .context("serialize report as yaml")
+            .map_err(Error::from)
+    }
+
+    /// Render the report as TOML, for committing alongside configuration that's written in TOML.
+    ///
+    /// Layers and diff IDs are rendered as arrays of `[digest, path]` pairs, as they are in JSON.
+    pub fn render_toml(&self) -> Result<String> {
+        toml::to_string_pretty(self)
+            .context("serialize report as toml")
+            .map_err(Error::from)
+    }
+
+    /// Render the report in the format, or nothing with [`ReportFormat::None`].
+    ///
+    /// Only JSON reports can be parsed with [`Report::parse`]; the other formats are for people and tools
+    /// that prefer them, such as when reports are committed to a repository.
+    pub fn render_as(&self, format: ReportFormat) -> Result<Option<String>> {
+        match format {
+            ReportFormat::Json => self.render().map(Some),
+            ReportFormat::Yaml => self.render_yaml().map(Some),
+            ReportFormat::Toml => self.render_toml().map(Some),
+            ReportFormat::None => Ok(None),
+        }
+    }
+
+    /// Emit the report in the format to the file at the path, replacing it if it exists, or to stdout if there's no path.
+    ///
+    /// Nothing is emitted with [`ReportFormat::None`], and the file isn't created.
+    pub async fn emit(&self, format: ReportFormat, path: Option<&Path>) -> Result<()> {
+        let Some(rendered) = self.render_as(format)? else {
+            return Ok(());
+        };
+        let rendered = match rendered.ends_with('\n') {
+            true => rendered,
+            false => format!("{rendered}\n"),
+        };
+
+        match path {
+            Some(path) => tokio::fs::write(path, rendered)
+                .await
+                .with_context(|| format!("write report to {path:?}")),
+            None => {
+                let mut stdout = std::io::stdout().lock();
+                std::io::Write::write_all(&mut stdout, rendered.as_bytes())
+                    .and_then(|()| std::io::Write::flush(&mut stdout))
+                    .context("write report to stdout")
+            }
+        }
+        .map_err(Error::from)
+    }
+
+    /// The current version of the report schema.
+    pub const SCHEMA_VERSION: u32 = 1;
+
+    /// The JSON schema describing the current version of the report.
+    pub const SCHEMA: &'static str = include_str!("../schema/image.schema.json");
+
+    /// Parse a report rendered by [`Report::render`].
+    ///
+    /// Reports from earlier versions of the schema are accepted, as are unknown fields
+    /// (which later versions may add without changing the schema version);
+    /// reports from later versions of the schema are rejected, since their fields may have changed meaning.
+    pub fn parse(content: &str) -> Result<Self> {
+        /// Just the version, so it's checked before fields that may have changed meaning are parsed.
+        #[derive(Deserialize)]
+        struct Versioned {
+            #[serde(default)]
+            schema_version: u32,
+        }
+
+        let Versioned { schema_version } =
+            serde_json::from_str(content).context("parse report schema version")?;
+        if schema_version > Self::SCHEMA_VERSION {
+            return Err(eyre::Report::new(Kind::Unsupported))
+                .context(format!(
+                    "report schema version {schema_version} is newer than the supported version {}",
+                    Self::SCHEMA_VERSION
+                ))
+                .map_err(Error::from);
+        }
+
+        serde_json::from_str(content)
+            .context("parse report")
+            .map_err(Error::from)
+    }
+
+    /// The standard name for the sidecar file listing file digests.
+    // Note: if this changes, make sure to update the `extract` CLI documentation.
+    pub const FILES_FILENAME: &'static str = "files.ndjson";
+
+    /// Write file digests to their standard sidecar location in the output directory,
+    /// one JSON object per line.
+    ///
+    /// This is an alternative to [`Report::files`] for large images,
+    /// where embedding every file in the report makes it unwieldy to parse.
+    pub async fn write_files(output: &Path, files: &[FileDigest]) -> Result<()> {
+        write_ndjson(&output.join(Self::FILES_FILENAME), files)
+            .await
+            .context("write file digests")
+            .map_err(Error::from)
+    }
+
+    /// The standard name for the sidecar file recording the layer that last changed each path.
+    // Note: if this changes, make sure to update the `extract` CLI documentation.
+    pub const PROVENANCE_FILENAME: &'static str = "provenance.ndjson";
+
+    /// Write the provenance of extracted paths to their standard sidecar location in the output directory,
+    /// one JSON object per line.
+    pub async fn write_provenance(output: &Path, provenance: &[Provenance]) -> Result<()> {
+        write_ndjson(&output.join(Self::PROVENANCE_FILENAME), provenance)
+            .await
+            .context("write provenance")
+            .map_err(Error::from)
+    }
+
+    /// The standard name for the sidecar file listing whiteouts.
+    // Note: if this changes, make sure to update the `extract` CLI documentation.
+    pub const WHITEOUTS_FILENAME: &'static str = "whiteouts.json";
+
+    /// The standard name for the directory to which paths deleted by whiteouts are recovered.
+    // Note: if this changes, make sure to update the `extract` CLI documentation.
+    pub const DELETED_DIR: &'static str = "deleted";
+
+    /// Write the whiteouts applied during extraction to their standard sidecar location in the output directory.
+    pub async fn write_whiteouts(output: &Path, whiteouts: &[Whiteout]) -> Result<()> {
+        let path = output.join(Self::WHITEOUTS_FILENAME);
+        let content = serde_json::to_string_pretty(whiteouts).context("serialize whiteouts")?;
+        tokio::fs::write(&path, content)
+            .await
+            .context("write whiteouts")
+            .map_err(Error::from)
+    }
+}
+
+/// Write the values to the file, one JSON object per line.
+async fn write_ndjson(path: &Path, values: &[impl Serialize]) -> eyre::Result<()> {
+    let file = tokio::fs::File::create(path).await.context("create file")?;
+    let mut writer = BufWriter::new(file);
+    for value in values {
+        let line = serde_json::to_string(value).context("serialize")?;
+        writer.write_all(line.as_bytes()).await.context("write")?;
+        writer.write_all(b"\n").await.context("write")?;
+    }
+    writer.flush().await.context("flush")
+}
+
+/// The digest of a regular file written during extraction.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileDigest {
+    /// The path of the file, relative to the output directory.
+    pub path: PathBuf,
+
+    /// The content-addressable digest of the file.
+    pub digest: Digest,
+
+    /// The fs-verity digest of the file, if computed.
+    ///
+    /// See [`verity_digests`] for details.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verity: Option<Digest>,
+}
+
+/// An entry that was written under a different name during extraction,
+/// because its path collides with another entry on a case-insensitive filesystem.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Collision {
+    /// The layer containing the entry.
+    pub layer: Digest,
+
+    /// The path of the entry, relative to the output directory.
+    pub path: PathBuf,
+
+    /// The path to which the entry was written instead, relative to the output directory.
+    pub renamed: PathBuf,
+}
+
+/// A path deleted by a whiteout during extraction.
+///
+/// Later layers delete files from earlier layers with whiteouts, but the files still exist in the earlier layers;
+/// for example, credentials that were removed in a later layer can still be read from the image.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Whiteout {
+    /// The layer containing the whiteout.
+    pub layer: Digest,
+
+    /// The path deleted by the whiteout, relative to the output directory.
+    pub path: PathBuf,
+
+    /// Whether anything existed at the path when the whiteout was applied.
+    pub existed: bool,
+
+    /// The path to which the deleted content was moved, relative to the output directory,
+    /// if deleted paths were recovered.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recovered: Option<PathBuf>,
+}
+
+/// The layer that last changed a path during extraction.
+///
+/// When layers are squashed, later layers overwrite or delete the paths written by earlier layers;
+/// this records which layer is responsible for what ended up at each path.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// The path, relative to the output directory.
+    pub path: PathBuf,
+
+    /// The layer that last wrote or deleted the path.
+    pub layer: Digest,
+
+    /// Whether the layer deleted the path with a whiteout, instead of writing it.
+    pub deleted: bool,
+}
+
+/// The descriptor of a layer applied during extraction, and how long it took to apply.
+///
+/// Layers are applied as they're read from the source, so downloading a layer overlaps with extracting it:
+/// both durations are measured from when the layer started to be applied.
+/// Comparing them shows whether extraction is limited by the source or by writing files.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LayerDetails {
+    /// The digest of the layer.
+    pub layer: Digest,
+
+    /// The size of the layer in bytes, as recorded in its descriptor.
+    pub size: i64,
+
+    /// The media type of the layer, as recorded in its descriptor.
+    pub media_type: String,
+
+    /// The milliseconds taken to read the layer from the source, if it was read.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download_ms: Option<u64>,
+
+    /// The milliseconds taken to apply the layer, including reading it, if it was applied.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extraction_ms: Option<u64>,
+}
+
+impl LayerDetails {
+    /// Describe the layer from its descriptor, without any timing.
+    pub fn new(layer: &Layer) -> Self {
+        Self {
+            layer: layer.digest.clone(),
+            size: layer.size,
+            media_type: layer.media_type.to_string(),
+            download_ms: None,
+            extraction_ms: None,
+        }
     }
 }
 
+/// The compressed and uncompressed size of layers, as observed while they were decompressed.
+///
+/// This helps to understand what takes up space in an image:
+/// layers with a low ratio are poorly compressed (or not compressed at all),
+/// which makes them good candidates for recompression (e.g. with zstd).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Compression {
+    /// The size of each layer, in the order the layers were read.
+    pub layers: Vec<LayerCompression>,
+
+    /// The total number of bytes read from the source for all layers.
+    pub compressed: u64,
+
+    /// The total number of bytes of all layers once decompressed.
+    pub uncompressed: u64,
+
+    /// The ratio of uncompressed to compressed bytes for all layers.
+    pub ratio: f64,
+}
+
+impl Compression {
+    /// Total the sizes of the layers.
+    ///
+    /// ```
+    /// # use circe_lib::{Digest, extract::{Compression, LayerCompression}};
+    /// # let digest = Digest::from_hash(vec![0; 32]);
+    /// let compression = Compression::new(vec![
+    ///     LayerCompression::new(digest.clone(), 100, 400),
+    ///     LayerCompression::new(digest, 100, 100),
+    /// ]);
+    /// assert_eq!(compression.compressed, 200);
+    /// assert_eq!(compression.uncompressed, 500);
+    /// assert_eq!(compression.ratio, 2.5);
+    /// ```
+    pub fn new(layers: Vec<LayerCompression>) -> Self {
+        let compressed = layers.iter().map(|layer| layer.compressed).sum();
+        let uncompressed = layers.iter().map(|layer| layer.uncompressed).sum();
+        Self {
+            layers,
+            compressed,
+            uncompressed,
+            ratio: compression_ratio(compressed, uncompressed),
+        }
+    }
+}
+
+/// The compressed and uncompressed size of a single layer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LayerCompression {
+    /// The digest of the layer.
+    pub layer: Digest,
+
+    /// The digest of the layer's uncompressed content, if known; see [`Layer::diff_id`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub diff_id: Option<Digest>,
+
+    /// The number of bytes read from the source for the layer.
+    pub compressed: u64,
+
+    /// The number of bytes of the layer once decompressed.
+    pub uncompressed: u64,
+
+    /// The ratio of uncompressed to compressed bytes for the layer.
+    pub ratio: f64,
+}
+
+impl LayerCompression {
+    /// Record the size of the layer.
+    pub fn new(layer: Digest, compressed: u64, uncompressed: u64) -> Self {
+        Self {
+            layer,
+            diff_id: None,
+            compressed,
+            uncompressed,
+            ratio: compression_ratio(compressed, uncompressed),
+        }
+    }
+}
+
+/// The ratio of uncompressed to compressed bytes, rounded to two decimal places.
+/// Layers that weren't read at all are reported as uncompressed.
+fn compression_ratio(compressed: u64, uncompressed: u64) -> f64 {
+    if compressed == 0 {
+        return 1.0;
+    }
+    let ratio = uncompressed as f64 / compressed as f64;
+    (ratio * 100.0).round() / 100.0
+}
+
+/// Compute the SHA256 digest of every regular file written to the layer directories during extraction.
+///
+/// The `layers` argument is the result of [`extract`]; paths are reported relative to `output`
+/// so that they include the layer directory, which disambiguates the same path across layers
+/// when they are extracted separately.
+///
+/// This is computed after extraction rather than while layers are applied because in squashed extraction
+/// later layers can overwrite or delete files written by earlier layers;
+/// hashing the final result is the only way to report what is actually on disk.
+pub async fn hash_files(output: &Path, layers: &[(Digest, PathBuf)]) -> Result<Vec<FileDigest>> {
+    let mut digests = Vec::new();
+    for dir in layers.iter().map(|(_, dir)| dir).unique() {
+        for path in walk_files(dir).await.context("enumerate files")? {
+            let digest = file_digest(&path)
+                .await
+                .with_context(|| format!("hash {path:?}"))?;
+            let path = path
+                .strip_prefix(output)
+                .map(Path::to_path_buf)
+                .unwrap_or(path);
+
+            debug!(?path, %digest, "hashed file");
+            digests.push(FileDigest {
+                path,
+                digest,
+                verity: None,
+            });
+        }
+    }
+
+    Ok(digests)
+}
+
+/// Compute the fs-verity digest of each file reported by [`hash_files`].
+///
+/// This is the digest the kernel reports for a file once fs-verity is enabled on it,
+/// so recording it allows extracted files to be sealed against tampering later (e.g. with `fsverity enable`)
+/// and checked against what was actually extracted, even on filesystems that don't support fs-verity.
+pub async fn verity_digests(output: &Path, files: &mut [FileDigest]) -> Result<()> {
+    for file in files {
+        let path = output.join(&file.path);
+        let digest = fsverity_digest(&path)
+            .await
+            .with_context(|| format!("compute fs-verity digest of {path:?}"))?;
+
+        debug!(path = ?file.path, %digest, "computed fs-verity digest");
+        file.verity = Some(digest);
+    }
+
+    Ok(())
+}
+
+/// Remove empty directories from the layer directories written during extraction.
+///
+/// Layers include entries for directories, which are created even if they are empty;
+/// this is an option for consumers who are only interested in files.
+/// The `layers` argument is the result of [`extract`].
+///
+/// Like [`hash_files`], this is run after extraction rather than while layers are applied
+/// since in squashed extraction a directory that is empty in one layer may have files written to it by a later layer.
+pub async fn prune_empty_dirs(layers: &[(Digest, PathBuf)]) -> Result<usize> {
+    let mut removed = 0;
+    for dir in layers.iter().map(|(_, dir)| dir).unique() {
+        removed += cio::prune_empty_dirs(dir)
+            .await
+            .with_context(|| format!("prune {dir:?}"))?;
+    }
+
+    Ok(removed)
+}
+
 /// Extraction strategy for container layers.
 pub enum Strategy {
     /// Squash multiple layers into a single unified filesystem.
@@ -56,6 +595,29 @@ pub enum Strategy {
 
     /// Extract a single layer to its own directory without combining it with others.
     Separate(Layer),
+
+    /// Extract a single layer to its own directory like [`Strategy::Separate`],
+    /// but through a store of extracted layers that is shared between images.
+    ///
+    /// The layer is only extracted into the store the first time it's seen;
+    /// its files are then hard linked into the output directory,
+    /// so images built on the same base layers share those files on disk instead of each having a copy.
+    ///
+    /// Layers are stored by digest, so the store must only be shared by sources with the same file filters.
+    /// Since linked files share their content with the store, they must not be modified in place.
+    Stored(Layer, PathBuf),
+}
+
+impl From<Layer> for Strategy {
+    fn from(layer: Layer) -> Self {
+        Strategy::Separate(layer)
+    }
+}
+
+impl From<&Layer> for Strategy {
+    fn from(layer: &Layer) -> Self {
+        Strategy::Separate(layer.clone())
+    }
 }
 
 impl IntoIterator for Strategy {
@@ -68,37 +630,48 @@ impl IntoIterator for Strategy {
 }
 
 /// Extract container layers according to the specified strategies.
+///
+/// `progress` is notified as each layer starts and completes;
+/// use [`crate::progress::Silent`] if progress isn't needed.
 pub async fn extract(
-    registry: &impl Source,
+    registry: &impl Unpack,
     output: &Path,
     strategies: impl IntoIterator<Item = Strategy>,
+    progress: &dyn Progress,
 ) -> Result<Vec<(Digest, PathBuf)>> {
     // TODO: we should be able to make these concurrent:
     // each squash needs to happen in order but the strategies
     // themselves are independent.
     stream::iter(strategies)
         .then(async |strategy| match strategy {
-            Strategy::Squash(layers) => squash(registry, output, &layers).await,
-            Strategy::Separate(layer) => copy(registry, output, layer).await,
+            Strategy::Squash(layers) => squash(registry, output, &layers, progress).await,
+            Strategy::Separate(layer) => copy(registry, output, layer, progress).await,
+            Strategy::Stored(layer, store) => {
+                stored(registry, output, layer, &store, progress).await
+            }
         })
-        .try_collect::<Vec<(Digest, PathBuf)>, Error, Vec<_>>()
+        .try_collect::<Vec<(Digest, PathBuf)>, eyre::Error, Vec<_>>()
         .await
         .context("apply layers")
         .map(|layers| layers.into_iter().flatten().collect::<Vec<_>>())
+        .map_err(Error::from)
 }
 
 async fn squash(
-    registry: &impl Source,
+    registry: &impl Unpack,
     output: &Path,
     layers: &[Layer],
-) -> Result<Vec<(Digest, PathBuf)>> {
+    progress: &dyn Progress,
+) -> eyre::Result<Vec<(Digest, PathBuf)>> {
     let target = target_dir(output, layers).context("target dir")?;
     info!(layers = ?layers.iter().map(|l| &l.digest).collect::<Vec<_>>(), target = ?target.display(), "squash layers");
 
     stream::iter(layers)
-        .then(async |layer| -> Result<(Digest, PathBuf)> {
+        .then(async |layer| -> eyre::Result<(Digest, PathBuf)> {
             tokio::fs::create_dir_all(&target).await?;
-            registry.apply_layer(layer, &target).await?;
+            progress.layer_started(layer);
+            apply_layer(registry, layer, &target).await?;
+            progress.layer_completed(layer);
             Ok((layer.digest.clone(), target.clone()))
         })
         .try_collect()
@@ -106,18 +679,83 @@ async fn squash(
 }
 
 async fn copy(
-    registry: &impl Source,
+    registry: &impl Unpack,
     output: &Path,
     layer: Layer,
-) -> Result<Vec<(Digest, PathBuf)>> {
+    progress: &dyn Progress,
+) -> eyre::Result<Vec<(Digest, PathBuf)>> {
     let target = target_dir(output, [&layer]).context("target dir")?;
     info!(layer = ?layer.digest, target = ?target.display(), "copy layer");
 
     tokio::fs::create_dir_all(&target).await?;
-    registry.apply_layer(&layer, &target).await?;
+    progress.layer_started(&layer);
+    apply_layer(registry, &layer, &target).await?;
+    progress.layer_completed(&layer);
+    Ok(vec![(layer.digest.clone(), target)])
+}
+
+async fn stored(
+    registry: &impl Unpack,
+    output: &Path,
+    layer: Layer,
+    store: &Path,
+    progress: &dyn Progress,
+) -> eyre::Result<Vec<(Digest, PathBuf)>> {
+    let target = target_dir(output, [&layer]).context("target dir")?;
+    let stored = store.join(layer.digest.as_hex());
+    progress.layer_started(&layer);
+
+    if tokio::fs::try_exists(&stored).await? {
+        info!(layer = ?layer.digest, stored = ?stored.display(), "reuse stored layer");
+        metrics::record_cache_hit();
+    } else {
+        info!(layer = ?layer.digest, stored = ?stored.display(), "store layer");
+
+        // The layer is applied to a staging directory and only moved into the store once it's complete,
+        // so that an interrupted extraction never leaves a partial layer in the store.
+        let staging = store.join(format!(".{}.{}", layer.digest.as_hex(), std::process::id()));
+        if tokio::fs::try_exists(&staging).await? {
+            tokio::fs::remove_dir_all(&staging)
+                .await
+                .context("remove stale staging directory")?;
+        }
+        tokio::fs::create_dir_all(&staging).await?;
+        apply_layer(registry, &layer, &staging).await?;
+
+        if let Err(err) = tokio::fs::rename(&staging, &stored).await {
+            // Another extraction sharing the store may have stored the layer first.
+            if !tokio::fs::try_exists(&stored).await? {
+                return Err(err).context("move layer into store");
+            }
+            tokio::fs::remove_dir_all(&staging)
+                .await
+                .context("remove staging directory")?;
+        }
+    }
+
+    let linked = cio::link_tree(&stored, &target)
+        .await
+        .context("link stored layer")?;
+    debug!(layer = ?layer.digest, linked, "linked stored layer");
+    progress.layer_completed(&layer);
     Ok(vec![(layer.digest.clone(), target)])
 }
 
+/// Apply the layer to the target directory.
+///
+/// If the temporary directory runs out of space while the layer is buffered and a [`Spill`] is installed,
+/// temporary files spill to the output directory and the layer is applied again;
+/// nothing is written to the target until the layer is buffered, so this is safe to retry.
+async fn apply_layer(registry: &impl Unpack, layer: &Layer, target: &Path) -> Result<()> {
+    match registry.apply_layer(layer, target).await {
+        Err(err) if cio::temp_exhausted(&err) && cio::spill()? => {
+            warn!(layer = ?layer.digest, dir = ?cio::temp_dir(), "temporary directory is full: spilling to output directory");
+            registry.apply_layer(layer, target).await
+        }
+        result => result,
+    }
+}
+
 /// Computes a directory for a set of layers to be squashed in the output directory.
 ///
 /// If there is only one layer, the directory name is the digest of the layer.
@@ -130,7 +768,7 @@ async fn copy(
 fn target_dir<'a>(
     output: &Path,
     layers: impl IntoIterator<Item = &'a Layer> + 'a,
-) -> Result<PathBuf> {
+) -> eyre::Result<PathBuf> {
     match layers.into_iter().collect::<Vec<_>>().as_slice() {
         [] => bail!("empty layers"),
         [layer] => format!("si_{}", layer.digest.as_hex()),
@@ -0,0 +1,392 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    ops::Bound,
+    path::{Component, Path, PathBuf},
+};
+
+use bytes::Bytes;
+use futures_lite::StreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use tap::Pipe;
+use tokio::io::AsyncReadExt;
+use tracing::{debug, info};
+
+use crate::{
+    entries::{Entries, EntryKind},
+    metrics::warn,
+    progress::Progress,
+    Digest, Layer, Result, Unpack,
+};
+
+/// The name of the whiteout file that hides the prior contents of its directory.
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// The maximum number of symlinks followed when resolving a path, matching Linux.
+const MAX_SYMLINKS: usize = 40;
+
+/// An in-memory representation of the filesystem produced by applying layers.
+///
+/// This is an alternative to [`super::extract`] for consumers that only need to look up
+/// or read files in the image (e.g. to print a file or list the squashed image)
+/// and would otherwise write the image to a temporary directory to do so.
+/// Since file contents are held in memory, this is best suited to images that comfortably fit in memory.
+///
+/// Paths are relative to the root of the container; leading `/` and `.` components are ignored.
+#[derive(Debug, Default, Clone)]
+pub struct Vfs {
+    nodes: BTreeMap<PathBuf, Node>,
+}
+
+/// A filesystem entry in a [`Vfs`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    /// The unix mode of the entry.
+    pub mode: u32,
+
+    /// The kind of the entry, along with its content.
+    pub kind: NodeKind,
+}
+
+/// The kind of a [`Node`].
+///
+/// Hard links are resolved when they are applied, so they are represented as a [`NodeKind::File`]
+/// with the same content as the file they link to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum NodeKind {
+    /// A regular file and its content.
+    File(Bytes),
+
+    /// A directory.
+    Directory,
+
+    /// A symbolic link and its target, as written in the layer.
+    Symlink(PathBuf),
+
+    /// Any other kind of entry, such as a device or FIFO.
+    Other,
+}
+
+/// An entry in the listing of a [`Vfs`]; see [`Vfs::listing`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListingEntry {
+    /// The path of the entry, relative to the root of the container.
+    pub path: PathBuf,
+
+    /// The kind of the entry.
+    ///
+    /// Hard links are resolved when layers are applied, so they are listed as files.
+    pub kind: EntryKind,
+
+    /// The unix mode of the entry.
+    pub mode: u32,
+
+    /// The size of the entry's content in bytes; zero for anything other than a file.
+    pub size: u64,
+
+    /// The SHA256 digest of the entry's content, if it is a file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<Digest>,
+
+    /// The target of the entry, if it is a symlink.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link_target: Option<PathBuf>,
+}
+
+impl Vfs {
+    /// Apply the layers in order, squashing them into a single filesystem.
+    ///
+    /// `progress` is notified as each layer starts and completes;
+    /// use [`crate::progress::Silent`] if progress isn't needed.
+    /// Foreign layers are skipped, since they aren't distributed with the image.
+    pub async fn squash(
+        source: &impl Unpack,
+        layers: &[Layer],
+        progress: &dyn Progress,
+    ) -> Result<Self> {
+        let mut vfs = Self::default();
+        for layer in layers {
+            progress.layer_started(layer);
+            match source.entries(layer).await? {
+                Some(entries) => vfs.apply(entries).await?,
+                None => warn!(layer = %layer.digest, "skip: foreign layer"),
+            }
+            progress.layer_completed(layer);
+        }
+
+        info!(layers = layers.len(), nodes = vfs.len(), "squashed layers");
+        Ok(vfs)
+    }
+
+    /// Apply the entries of a layer on top of the current filesystem.
+    ///
+    /// Like extracting to disk, entries that can't be read or applied are skipped with a warning.
+    pub async fn apply(&mut self, mut entries: Entries) -> Result<()> {
+        // Opaque whiteouts only hide the contents of prior layers,
+        // so track what this layer wrote to avoid hiding it too.
+        let mut written = HashSet::new();
+
+        while let Some(entry) = entries.next().await {
+            let mut entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    warn!(?err, "read entry");
+                    continue;
+                }
+            };
+
+            let Some(path) = normalize(entry.path()) else {
+                warn!(path = ?entry.path(), "skip: path escapes root");
+                continue;
+            };
+
+            if path.file_name().is_some_and(|name| name == OPAQUE_WHITEOUT) {
+                let dir = path.parent().unwrap_or(Path::new(""));
+                let hidden = self.descendants(dir).filter(|p| !written.contains(*p));
+                let hidden = hidden.map(Path::to_path_buf).collect::<Vec<_>>();
+                for hidden in hidden {
+                    self.nodes.remove(&hidden);
+                }
+                debug!(?dir, "opaque whiteout");
+                continue;
+            }
+
+            if let Some(deleted) = entry.whiteout().and_then(|path| normalize(&path)) {
+                self.remove(&deleted);
+                debug!(path = ?deleted, "whiteout");
+                continue;
+            }
+
+            let kind = match entry.kind() {
+                EntryKind::File => {
+                    let mut content = Vec::with_capacity(entry.size() as usize);
+                    if let Err(err) = entry.read_to_end(&mut content).await {
+                        warn!(?err, ?path, "read file");
+                        continue;
+                    }
+                    NodeKind::File(Bytes::from(content))
+                }
+                EntryKind::Directory => NodeKind::Directory,
+                EntryKind::Symlink => match entry.link_target() {
+                    Some(target) => NodeKind::Symlink(target.to_path_buf()),
+                    None => {
+                        warn!(?path, "skip: symlink without target");
+                        continue;
+                    }
+                },
+                EntryKind::HardLink => {
+                    let target = entry.link_target().and_then(normalize);
+                    match target.as_ref().and_then(|target| self.nodes.get(target)) {
+                        Some(Node {
+                            kind: NodeKind::File(content),
+                            ..
+                        }) => NodeKind::File(content.clone()),
+                        _ => {
+                            warn!(?path, ?target, "skip: hard link target is not a file");
+                            continue;
+                        }
+                    }
+                }
+                _ => NodeKind::Other,
+            };
+
+            debug!(?path, "apply");
+            written.extend(path.ancestors().map(Path::to_path_buf));
+            self.insert(
+                path,
+                Node {
+                    mode: entry.mode(),
+                    kind,
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    /// The number of entries in the filesystem.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Whether the filesystem has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Iterate over every entry in the filesystem, ordered by path.
+    pub fn iter(&self) -> impl Iterator<Item = (&Path, &Node)> {
+        self.nodes.iter().map(|(path, node)| (path.as_path(), node))
+    }
+
+    /// List every entry in the filesystem with its metadata, ordered by path.
+    ///
+    /// This is the inventory of the image as a container running it would see it,
+    /// without writing anything to disk; files are hashed like [`super::hash_files`].
+    pub fn listing(&self) -> Vec<ListingEntry> {
+        self.iter()
+            .map(|(path, node)| {
+                let (kind, size, digest, link_target) = match &node.kind {
+                    NodeKind::File(content) => {
+                        let digest = Digest::from_hash(Sha256::digest(content).to_vec());
+                        (EntryKind::File, content.len() as u64, Some(digest), None)
+                    }
+                    NodeKind::Directory => (EntryKind::Directory, 0, None, None),
+                    NodeKind::Symlink(target) => {
+                        (EntryKind::Symlink, 0, None, Some(target.clone()))
+                    }
+                    NodeKind::Other => (EntryKind::Other, 0, None, None),
+                };
+
+                ListingEntry {
+                    path: path.to_path_buf(),
+                    kind,
+                    mode: node.mode,
+                    size,
+                    digest,
+                    link_target,
+                }
+            })
+            .collect()
+    }
+
+    /// Look up the entry at the path without following a symlink at the path itself.
+    ///
+    /// Symlinks in the parent directories of the path are followed.
+    pub fn get(&self, path: impl AsRef<Path>) -> Option<&Node> {
+        let path = path.as_ref();
+        let name = path.file_name()?;
+        let parent = self.resolve(path.parent().unwrap_or(Path::new("")))?;
+        self.nodes.get(&parent.join(name))
+    }
+
+    /// Resolve the path to the entry it refers to, following any symlinks.
+    ///
+    /// Symlinks are resolved relative to the root of the container, never the host.
+    /// Returns `None` if the path doesn't exist or symlinks can't be resolved (e.g. because they loop).
+    pub fn resolve(&self, path: impl AsRef<Path>) -> Option<PathBuf> {
+        let mut pending = path.as_ref().components().rev().collect::<Vec<_>>();
+        let mut resolved = PathBuf::new();
+        let mut followed = 0;
+
+        while let Some(component) = pending.pop() {
+            match component {
+                Component::Normal(name) => {
+                    let candidate = resolved.join(name);
+                    match &self.nodes.get(&candidate)?.kind {
+                        NodeKind::Symlink(target) => {
+                            followed += 1;
+                            if followed > MAX_SYMLINKS {
+                                return None;
+                            }
+                            if target.has_root() {
+                                resolved = PathBuf::new();
+                            }
+                            pending.extend(target.components().rev());
+                        }
+                        _ => resolved = candidate,
+                    }
+                }
+                Component::ParentDir => {
+                    resolved.pop();
+                }
+                Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+            }
+        }
+
+        Some(resolved)
+    }
+
+    /// Read the content of the file at the path, following any symlinks.
+    ///
+    /// Returns `None` if the path doesn't exist or isn't a regular file.
+    pub fn read(&self, path: impl AsRef<Path>) -> Option<&Bytes> {
+        let path = self.resolve(path)?;
+        match &self.nodes.get(&path)?.kind {
+            NodeKind::File(content) => Some(content),
+            _ => None,
+        }
+    }
+
+    /// List the paths of the entries directly inside the directory at the path, ordered by path.
+    ///
+    /// Symlinks in the path are followed.
+    ///
+    /// Returns `None` if the path doesn't exist or isn't a directory.
+    pub fn read_dir(&self, path: impl AsRef<Path>) -> Option<Vec<&Path>> {
+        let dir = self.resolve(path)?;
+        if dir.parent().is_some() && self.nodes.get(&dir)?.kind != NodeKind::Directory {
+            return None;
+        }
+
+        self.descendants(&dir)
+            .filter(|path| path.parent() == Some(dir.as_path()))
+            .collect::<Vec<_>>()
+            .pipe(Some)
+    }
+
+    /// Iterate over the paths of every entry inside the directory, recursively.
+    fn descendants(&self, dir: &Path) -> impl Iterator<Item = &Path> {
+        let dir = dir.to_path_buf();
+        self.nodes
+            .range::<Path, _>((Bound::Excluded(dir.as_path()), Bound::Unbounded))
+            .map(|(path, _)| path.as_path())
+            .take_while(move |path| path.starts_with(&dir))
+    }
+
+    /// Remove the entry at the path along with anything inside it.
+    fn remove(&mut self, path: &Path) {
+        let removed = self
+            .descendants(path)
+            .map(Path::to_path_buf)
+            .collect::<Vec<_>>();
+        for removed in removed {
+            self.nodes.remove(&removed);
+        }
+        self.nodes.remove(path);
+    }
+
+    /// Insert the entry at the path, creating any missing parent directories.
+    fn insert(&mut self, path: PathBuf, node: Node) {
+        for parent in path.ancestors().skip(1) {
+            if parent.parent().is_some() && !self.nodes.contains_key(parent) {
+                self.nodes.insert(
+                    parent.to_path_buf(),
+                    Node {
+                        mode: 0o755,
+                        kind: NodeKind::Directory,
+                    },
+                );
+            }
+        }
+
+        // Directories are merged with the directory they replace,
+        // but anything else replaces the entry entirely.
+        match (self.nodes.get_mut(&path), &node.kind) {
+            (Some(existing), NodeKind::Directory) if existing.kind == NodeKind::Directory => {
+                existing.mode = node.mode;
+            }
+            _ => {
+                self.remove(&path);
+                self.nodes.insert(path, node);
+            }
+        }
+    }
+}
+
+/// Normalize a path in a layer to be relative to the root of the container.
+///
+/// Returns `None` if the path refers to a location outside the root.
+fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => return None,
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+
+    (normalized.parent().is_some()).then_some(normalized)
+}
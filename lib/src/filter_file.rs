@@ -0,0 +1,164 @@
+//! Parses filter files, which hold sets of filters written one per line in a syntax similar to `.gitignore`,
+//! so that filters used for repeated scans can be versioned alongside other configuration.
+//!
+//! ```not_rust
+//! # Blank lines, and lines starting with `#`, are ignored.
+//!
+//! # Each line is a glob for files to include:
+//! *.jar
+//! # Lines starting with `!` exclude matching files instead:
+//! !test/
+//! # Lines starting with `regex:` are regular expressions instead of globs:
+//! regex:\.so(\.\d+)*$
+//! !regex:/debug/
+//! # Lines starting with `layer:` filter layers by digest instead of files:
+//! layer:sha256:1234*
+//! !layer:regex:^sha256:5678
+//! ```
+//!
+//! Like `.gitignore`, file globs match at any depth: `*.jar` matches every `.jar` file,
+//! and `lib/*.so` matches `.so` files in any `lib` directory. Globs ending with `/` match everything in the directory.
+//! Regular expressions and layer filters are used as written.
+//! Start a line with `\` to use a leading `!` or `#` in a file glob literally.
+
+use color_eyre::eyre::{self, Context};
+use std::str::FromStr;
+
+use crate::{Error, Filters, Result};
+
+/// Filters read from a filter file; see the [module documentation](self) for the syntax.
+#[derive(Debug, Clone, Default)]
+pub struct FilterFile {
+    /// Filters for layers, matched against their digests.
+    pub layers: Filters,
+
+    /// Filters for files, matched against their paths.
+    pub files: Filters,
+}
+
+impl FilterFile {
+    /// Parse the content of a filter file, reporting errors with detailed context.
+    fn parse(content: &str) -> eyre::Result<Self> {
+        let mut file = Self::default();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (layer, filters) = Rule::parse(line)
+                .and_then(|rule| Ok((rule.layer, rule.filters()?)))
+                .with_context(|| format!("parse line {}", number + 1))?;
+            match layer {
+                true => file.layers = file.layers + filters,
+                false => file.files = file.files + filters,
+            }
+        }
+        Ok(file)
+    }
+}
+
+impl FromStr for FilterFile {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s).map_err(Error::parse)
+    }
+}
+
+/// A single line of a filter file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Rule<'a> {
+    exclude: bool,
+    layer: bool,
+    regex: bool,
+    pattern: &'a str,
+}
+
+impl<'a> Rule<'a> {
+    /// Parse the line, which must not be blank or a comment.
+    fn parse(line: &'a str) -> eyre::Result<Self> {
+        if let Some(pattern) = line
+            .strip_prefix('\\')
+            .filter(|rest| rest.starts_with(['!', '#']))
+        {
+            return Ok(Self {
+                exclude: false,
+                layer: false,
+                regex: false,
+                pattern,
+            });
+        }
+
+        let (exclude, rest) = strip(line, "!");
+        let (layer, rest) = strip(rest, "layer:");
+        let (regex, pattern) = strip(rest, "regex:");
+        eyre::ensure!(!pattern.is_empty(), "empty pattern: '{line}'");
+        Ok(Self {
+            exclude,
+            layer,
+            regex,
+            pattern,
+        })
+    }
+
+    /// The filters described by the rule.
+    fn filters(&self) -> Result<Filters> {
+        let filters = match self.regex {
+            true => Filters::parse_regex([self.pattern])?,
+            false if self.layer => Filters::parse_glob([self.pattern])?,
+            false => Filters::parse_glob([file_glob(self.pattern)])?,
+        };
+        match self.exclude {
+            true => Ok(filters.exclude()),
+            false => Ok(filters),
+        }
+    }
+}
+
+/// Strip the prefix from the value, reporting whether it was present.
+fn strip<'a>(value: &'a str, prefix: &str) -> (bool, &'a str) {
+    match value.strip_prefix(prefix) {
+        Some(rest) => (true, rest),
+        None => (false, value),
+    }
+}
+
+/// Make the glob match at any depth, since file filters are matched against the full path to which the file is extracted.
+fn file_glob(pattern: &str) -> String {
+    let pattern = pattern.trim_start_matches('/');
+    let pattern = match pattern.ends_with('/') {
+        true => format!("{pattern}**"),
+        false => pattern.to_string(),
+    };
+    match pattern.starts_with("**/") {
+        true => pattern,
+        false => format!("**/{pattern}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    #[test_case("*.jar", Rule { exclude: false, layer: false, regex: false, pattern: "*.jar" }; "glob")]
+    #[test_case("!test/", Rule { exclude: true, layer: false, regex: false, pattern: "test/" }; "exclude")]
+    #[test_case("regex:\\.so$", Rule { exclude: false, layer: false, regex: true, pattern: "\\.so$" }; "regex")]
+    #[test_case("!layer:regex:^sha256:", Rule { exclude: true, layer: true, regex: true, pattern: "^sha256:" }; "layer_regex_exclude")]
+    #[test_case("\\!important", Rule { exclude: false, layer: false, regex: false, pattern: "!important" }; "escaped")]
+    #[test]
+    fn rule(line: &str, expected: Rule<'_>) {
+        pretty_assertions::assert_eq!(expected, Rule::parse(line).expect("parse rule"));
+    }
+
+    #[test_case("*.jar", "**/*.jar"; "name")]
+    #[test_case("lib/*.so", "**/lib/*.so"; "path")]
+    #[test_case("/etc/passwd", "**/etc/passwd"; "leading_slash")]
+    #[test_case("test/", "**/test/**"; "directory")]
+    #[test_case("**/*.txt", "**/*.txt"; "already_any_depth")]
+    #[test]
+    fn glob(pattern: &str, expected: &str) {
+        pretty_assertions::assert_eq!(expected, file_glob(pattern));
+    }
+}
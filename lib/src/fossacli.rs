@@ -14,15 +14,34 @@
 //! though the vendored examples in this repo are more reliable as reference
 //! implementations since they are not subject to Docker platform changes.
 
-use std::path::PathBuf;
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
 
 use async_tempfile::TempFile;
 use bon::Builder;
-use color_eyre::{eyre::Context, Result};
-use serde::Serialize;
-use tokio::io::AsyncWriteExt;
+use bytes::Bytes;
+use color_eyre::eyre::{self, bail, eyre, Context};
+use derive_more::Debug;
+use futures_lite::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufWriter},
+};
+use tokio_tar::Header;
+use tokio_util::io::StreamReader;
+use tracing::debug;
 
-use crate::Digest;
+use crate::{
+    cio::collect_tmp, transform, Digest, Error, ImageConfig, Layer, LayerMediaType,
+    LayerMediaTypeFlag, Platform, Result, Unpack,
+};
+
+/// The size of a block in a tarball; headers and entry bodies are padded to this size.
+const BLOCK_SIZE: u64 = 512;
 
 /// The manifest for a tarball image.
 ///
@@ -32,7 +51,7 @@ use crate::Digest;
 /// For Circe, this is a singleton list;
 /// FOSSA CLI just uses the first entry in the list:
 /// https://github.com/fossas/fossa-cli/blob/0fc322a0e76e6fb6f78c0f3c13d6166d183ef830/src/Container/Docker/Manifest.hs#L81-L82
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Manifest(Vec<ManifestEntry>);
 
 impl Manifest {
@@ -48,7 +67,15 @@ impl Manifest {
 
     /// Write the manifest to a temporary file.
     pub async fn write_tempfile(&self) -> Result<(TempFile, String)> {
-        write_serialized_tempfile(self).await
+        write_serialized_tempfile(self).await.map_err(Error::from)
+    }
+
+    /// Replace the references pointing to each image in the manifest with the tag.
+    pub fn retag(&mut self, tag: impl Into<String>) {
+        let tag = tag.into();
+        for entry in &mut self.0 {
+            entry.repo_tags = vec![tag.clone()];
+        }
     }
 }
 
@@ -56,7 +83,7 @@ impl Manifest {
 ///
 /// Corresponds to the FOSSA CLI `ManifestJsonImageEntry` type:
 /// https://github.com/fossas/fossa-cli/blob/0fc322a0e76e6fb6f78c0f3c13d6166d183ef830/src/Container/Docker/Manifest.hs#L54-L59
-#[derive(Debug, Clone, Serialize, Builder)]
+#[derive(Debug, Clone, Serialize, Deserialize, Builder)]
 #[serde(rename_all = "PascalCase")]
 pub struct ManifestEntry {
     /// References the path to the [`Image`] for this manifest.
@@ -95,13 +122,25 @@ pub struct ManifestEntry {
 }
 
 /// Container image configuration for FOSSA CLI.
+///
+/// FOSSA CLI only requires [`Image::rootfs`];
+/// the rest of the configuration is carried over from the original image so that the metadata is accurate.
 #[derive(Debug, Clone, Serialize)]
 pub struct Image {
+    /// The configuration of the original image.
+    #[serde(flatten)]
+    pub config: ImageConfig,
+
     /// The root filesystem definition containing the container's layer information.
     pub rootfs: RootFs,
 }
 
 impl Image {
+    /// Create the image from the configuration of the original image.
+    pub fn new(config: ImageConfig, rootfs: RootFs) -> Self {
+        Self { config, rootfs }
+    }
+
     /// Build the target filename for the image.
     pub fn filename(digest: &Digest) -> PathBuf {
         let digest = digest.as_hex();
@@ -110,13 +149,13 @@ impl Image {
 
     /// Write the image to a temporary file.
     pub async fn write_tempfile(&self) -> Result<(TempFile, String)> {
-        write_serialized_tempfile(self).await
+        write_serialized_tempfile(self).await.map_err(Error::from)
     }
 }
 
 impl From<RootFs> for Image {
     fn from(rootfs: RootFs) -> Self {
-        Self { rootfs }
+        Self::new(ImageConfig::default(), rootfs)
     }
 }
 
@@ -153,8 +192,352 @@ impl RootFs {
     }
 }
 
+/// Hints about an image that FOSSA CLI can use to improve attribution, written alongside its tarball.
+///
+/// The tarball only records what FOSSA CLI needs to scan the image;
+/// hints record what's lost in converting to it, like the diff ID of each layer and which layers were skipped.
+/// FOSSA CLI doesn't read hints yet, so the schema is versioned: [`Hints::VERSION`] is incremented
+/// whenever a field is removed or changes meaning, while fields may be added without changing it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hints {
+    /// The version of the schema; see [`Hints::VERSION`].
+    pub version: u32,
+
+    /// The digest the image resolved to; the [`Image`] in the tarball is named for it.
+    pub digest: Digest,
+
+    /// The platform of the image, as recorded in its config.
+    pub platform: Option<Platform>,
+
+    /// The layers in the tarball, in order from the base image to the application.
+    pub layers: Vec<LayerHint>,
+
+    /// Foreign layers, which aren't distributed with the image and so aren't in the tarball.
+    pub skipped: Vec<LayerHint>,
+}
+
+impl Hints {
+    /// The current version of the schema.
+    pub const VERSION: u32 = 1;
+
+    /// Describe the image from its config and the layers selected for the tarball.
+    pub fn new(digest: Digest, config: &ImageConfig, layers: &[Layer]) -> Self {
+        let (skipped, layers) =
+            layers
+                .iter()
+                .partition::<Vec<_>, _>(|layer| match &layer.media_type {
+                    LayerMediaType::Oci(flags) => flags.contains(&LayerMediaTypeFlag::Foreign),
+                });
+        Self {
+            version: Self::VERSION,
+            digest,
+            platform: config.platform(),
+            layers: layers.into_iter().map(LayerHint::from).collect(),
+            skipped: skipped.into_iter().map(LayerHint::from).collect(),
+        }
+    }
+}
+
+/// A layer described by [`Hints`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LayerHint {
+    /// The digest of the layer as distributed; layers in the tarball are named for it.
+    pub digest: Digest,
+
+    /// The digest of the layer's uncompressed content, if the image config lists it.
+    pub diff_id: Option<Digest>,
+
+    /// The media type of the layer as distributed.
+    pub media_type: String,
+}
+
+impl From<&Layer> for LayerHint {
+    fn from(layer: &Layer) -> Self {
+        Self {
+            digest: layer.digest.clone(),
+            diff_id: layer.diff_id.clone(),
+            media_type: layer.media_type.to_string(),
+        }
+    }
+}
+
+/// Writes a tarball in the format FOSSA CLI expects directly to its destination.
+///
+/// Layers are decompressed and streamed into the tarball as they are pulled,
+/// rather than being buffered in a temporary file first.
+/// The tar header of each entry must record the size of the entry before its body,
+/// but the size of a decompressed layer isn't known until it has been read:
+/// the layer descriptor only records the size of the layer as distributed.
+/// So the header is written with the size from the descriptor,
+/// and is rewritten in place if the layer turns out to be a different size
+/// (as is always the case for compressed layers).
+///
+/// Writers that can't seek (like stdout, see [`Writer::stream`]) can't rewrite headers,
+/// so each layer is instead decompressed to a temporary file to learn its size before it is written.
+#[derive(Debug)]
+pub struct Writer {
+    destination: Destination,
+    offset: u64,
+}
+
+/// Where a [`Writer`] writes the tarball.
+#[derive(Debug)]
+enum Destination {
+    /// A file on disk, in which headers can be rewritten in place.
+    File {
+        #[debug(skip)]
+        file: BufWriter<File>,
+        path: PathBuf,
+    },
+
+    /// Any other writer, which is only ever appended to.
+    Stream(#[debug(skip)] Box<dyn AsyncWrite + Send + Unpin>),
+}
+
+impl Destination {
+    fn writer(&mut self) -> &mut (dyn AsyncWrite + Send + Unpin) {
+        match self {
+            Destination::File { file, .. } => file,
+            Destination::Stream(writer) => writer,
+        }
+    }
+}
+
+impl Writer {
+    /// Create the tarball at the path, replacing any existing file.
+    ///
+    /// The existing file is removed rather than truncated,
+    /// so that other hard links to it (e.g. from reusing a previous export) are unaffected.
+    pub async fn create(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        match tokio::fs::remove_file(&path).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                return Err(err)
+                    .with_context(|| format!("remove existing {path:?}"))
+                    .map_err(Error::from);
+            }
+            _ => {}
+        }
+
+        let file = File::create(&path)
+            .await
+            .with_context(|| format!("create {path:?}"))?;
+        Ok(Self {
+            destination: Destination::File {
+                file: BufWriter::new(file),
+                path,
+            },
+            offset: 0,
+        })
+    }
+
+    /// Write the tarball to the writer, for example stdout.
+    ///
+    /// The writer is only ever appended to, so layers are buffered in temporary files
+    /// to learn their decompressed size before they are written; see [`Writer`] for details.
+    pub fn stream(writer: impl AsyncWrite + Send + Unpin + 'static) -> Self {
+        Self {
+            destination: Destination::Stream(Box::new(writer)),
+            offset: 0,
+        }
+    }
+
+    /// Append the plain tarball of the layer, named for its digest.
+    ///
+    /// Foreign layers are skipped, since they aren't distributed with the image;
+    /// returns whether the layer was appended.
+    pub async fn append_layer(&mut self, source: &impl Unpack, layer: &Layer) -> Result<bool> {
+        let stream = source
+            .pull_layer(layer)
+            .await?
+            .map(|chunk| chunk.map_err(std::io::Error::other));
+        let Some(stream) = transform::peel_layer(layer, stream, transform::Buffering::default())
+        else {
+            return Ok(false);
+        };
+
+        // Only uncompressed layers are the same size as recorded in the descriptor.
+        let expected = match &layer.media_type {
+            LayerMediaType::Oci(flags) if flags.is_empty() => u64::try_from(layer.size).ok(),
+            LayerMediaType::Oci(_) => None,
+        };
+
+        let name = layer.digest.tarball_filename();
+        if let Destination::Stream(_) = self.destination {
+            return self
+                .append_buffered(&name, stream)
+                .await
+                .with_context(|| format!("append layer {}", layer.digest))
+                .map(|_| true)
+                .map_err(Error::from);
+        }
+
+        let mut reader = StreamReader::new(stream);
+        self.append_stream(&name, expected.unwrap_or_default(), &mut reader)
+            .await
+            .with_context(|| format!("append layer {}", layer.digest))
+            .map(|_| true)
+            .map_err(Error::from)
+    }
+
+    /// Serialize the value to JSON and append it with the provided name.
+    ///
+    /// Returns the serialized content.
+    pub async fn append_json<T: Serialize>(
+        &mut self,
+        name: impl AsRef<Path>,
+        value: &T,
+    ) -> Result<String> {
+        let name = name.as_ref();
+        let content = serde_json::to_string_pretty(value).context("serialize")?;
+        self.append_stream(name, content.len() as u64, &mut content.as_bytes())
+            .await
+            .with_context(|| format!("append {name:?}"))
+            .map(|_| content)
+            .map_err(Error::from)
+    }
+
+    /// Append every entry of a tarball previously written by a [`Writer`],
+    /// replacing the references in its manifest with the tag.
+    ///
+    /// This allows an image that was already exported under a different tag to be exported again
+    /// without pulling its layers.
+    pub async fn append_retagged(&mut self, tarball: &Path, tag: &str) -> Result<()> {
+        let file = File::open(tarball)
+            .await
+            .with_context(|| format!("open {tarball:?}"))?;
+        let mut archive = tokio_tar::Archive::new(file);
+        let mut entries = archive.entries().context("read entries from tar")?;
+
+        while let Some(entry) = entries.next().await {
+            let mut entry = entry.context("read entry")?;
+            let path = entry.path().context("read entry path")?.into_owned();
+            if path != Manifest::filename() {
+                let size = entry.header().size().context("read entry size")?;
+                self.append_stream(&path, size, &mut entry)
+                    .await
+                    .with_context(|| format!("append {path:?}"))?;
+                continue;
+            }
+
+            let mut content = Vec::new();
+            entry
+                .read_to_end(&mut content)
+                .await
+                .context("read manifest")?;
+            let mut manifest =
+                serde_json::from_slice::<Manifest>(&content).context("parse manifest")?;
+            manifest.retag(tag);
+            self.append_json(&path, &manifest).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Finish the tarball, flushing it to its destination.
+    pub async fn finish(mut self) -> Result<()> {
+        let trailer = [0; 2 * BLOCK_SIZE as usize];
+        let writer = self.destination.writer();
+        writer.write_all(&trailer).await.context("write trailer")?;
+        writer.flush().await.context("flush")?;
+
+        match &self.destination {
+            Destination::File { file, .. } => file.get_ref().sync_all().await.context("sync")?,
+            Destination::Stream(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Append an entry with the content of the stream, buffering it in a temporary file to learn its size.
+    async fn append_buffered(
+        &mut self,
+        name: impl AsRef<Path>,
+        stream: impl Stream<Item = std::io::Result<Bytes>> + Unpin,
+    ) -> eyre::Result<u64> {
+        let buffered = collect_tmp(stream).await.context("buffer entry")?;
+        let size = buffered.metadata().await.context("read size")?.len();
+        let mut file = File::open(buffered.file_path())
+            .await
+            .context("open buffered entry")?;
+        self.append_stream(name, size, &mut file).await
+    }
+
+    /// Append an entry with the content of the reader.
+    ///
+    /// The entry is written with the expected size,
+    /// and its header is rewritten if the content turns out to be a different size.
+    async fn append_stream(
+        &mut self,
+        name: impl AsRef<Path>,
+        expected: u64,
+        reader: &mut (impl tokio::io::AsyncRead + Unpin),
+    ) -> eyre::Result<u64> {
+        let name = name.as_ref();
+        let start = self.offset;
+        let mut header = entry_header(name, expected)?;
+        let mut writer = self.destination.writer();
+        writer
+            .write_all(header.as_bytes())
+            .await
+            .context("write header")?;
+
+        let size = tokio::io::copy(reader, &mut writer)
+            .await
+            .context("write content")?;
+        let padding = size.next_multiple_of(BLOCK_SIZE) - size;
+        writer
+            .write_all(&[0; BLOCK_SIZE as usize][..padding as usize])
+            .await
+            .context("write padding")?;
+        self.offset = start + BLOCK_SIZE + size + padding;
+
+        if size == expected {
+            return Ok(size);
+        }
+
+        let Destination::File { file, path } = &mut self.destination else {
+            bail!("{name:?} is {size} bytes, but {expected} bytes were written to its header");
+        };
+
+        debug!(
+            ?name,
+            expected,
+            size,
+            ?path,
+            "rewrite header with actual size"
+        );
+        header = entry_header(name, size)?;
+        file.seek(SeekFrom::Start(start))
+            .await
+            .context("seek to header")?;
+        file.write_all(header.as_bytes())
+            .await
+            .context("rewrite header")?;
+        file.seek(SeekFrom::Start(self.offset))
+            .await
+            .context("seek to end")?;
+
+        Ok(size)
+    }
+}
+
+/// Build the header for a regular file entry in the tarball.
+fn entry_header(name: &Path, size: u64) -> eyre::Result<Header> {
+    let mtime = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|err| eyre!("read current time: {err}"))?;
+
+    let mut header = Header::new_gnu();
+    header.set_path(name).context("set path")?;
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(mtime.as_secs());
+    header.set_cksum();
+    Ok(header)
+}
+
 /// Serializes a value to JSON and writes it to a temporary file.
-async fn write_serialized_tempfile<T: Serialize>(value: &T) -> Result<(TempFile, String)> {
+async fn write_serialized_tempfile<T: Serialize>(value: &T) -> eyre::Result<(TempFile, String)> {
     let mut file = TempFile::new().await.context("create")?;
     let value = serde_json::to_string_pretty(&value).context("serialize")?;
     file.write_all(value.as_bytes()).await.context("write")?;
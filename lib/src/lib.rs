@@ -3,39 +3,59 @@
 #![deny(unsafe_code)]
 #![warn(rust_2018_idioms)]
 
-use async_tempfile::TempFile;
 use bon::Builder;
 use bytes::Bytes;
 use color_eyre::{
-    eyre::{self, bail, ensure, eyre, Context},
-    Result, Section, SectionExt,
+    eyre::{self, bail, ensure, eyre, Context, Report},
+    Section, SectionExt,
 };
 use derive_more::derive::{Debug, Display, From};
 use enum_assoc::Assoc;
-use extract::Strategy;
 use futures_lite::Stream;
 use itertools::Itertools;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::{
     borrow::Cow,
     future::Future,
-    ops::Add,
-    path::{Path, PathBuf},
+    ops::{Add, Bound, Not},
     pin::Pin,
     str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        RwLock,
+    },
 };
 use strum::{AsRefStr, EnumIter, IntoEnumIterator};
 use tap::{Pipe, Tap};
-use tracing::{debug, warn};
+use tracing::debug;
+
+use crate::metrics::warn;
 
+#[cfg(feature = "native")]
+pub mod cache;
+pub mod capabilities;
+#[cfg(feature = "native")]
 mod cio;
+#[cfg(feature = "native")]
 pub mod docker;
+#[cfg(feature = "native")]
+pub mod entries;
+pub mod error;
 mod ext;
+#[cfg(feature = "native")]
 pub mod extract;
+pub mod filter_file;
+#[cfg(feature = "native")]
 pub mod fossacli;
+pub mod metrics;
+pub mod progress;
 pub mod registry;
+pub mod source;
 pub mod transform;
 
+use error::Kind;
+pub use error::{Error, Result};
+
 /// Users can set this environment variable to specify the OCI base.
 /// If not set, the default is [`OCI_DEFAULT_BASE`].
 pub const OCI_BASE_VAR: &str = "OCI_DEFAULT_BASE";
@@ -56,20 +76,173 @@ pub const OCI_DISABLE_REGISTRY_OCI_VAR: &str = "CIRCE_DISABLE_REGISTRY_OCI";
 /// Set to any value to disable docker daemon connection.
 pub const OCI_DISABLE_DAEMON_DOCKER_VAR: &str = "CIRCE_DISABLE_DAEMON_DOCKER";
 
+/// Set to any value to forbid network access, the same as [`install_offline`].
+pub const OFFLINE_VAR: &str = "CIRCE_OFFLINE";
+
 /// The OCI base.
+///
+/// This is the base of the installed [`OciDefaults`] if any, otherwise the value of [`OCI_BASE_VAR`] as-is;
+/// use [`OciDefaults::current`] to have it validated.
 pub fn oci_base() -> String {
-    std::env::var(OCI_BASE_VAR).unwrap_or(OCI_DEFAULT_BASE.to_string())
+    match installed_defaults() {
+        Some(defaults) => defaults.base,
+        None => std::env::var(OCI_BASE_VAR).unwrap_or(OCI_DEFAULT_BASE.to_string()),
+    }
 }
 
 /// The OCI namespace.
+///
+/// This is the namespace of the installed [`OciDefaults`] if any, otherwise the value of [`OCI_NAMESPACE_VAR`] as-is;
+/// use [`OciDefaults::current`] to have it validated.
 pub fn oci_namespace() -> String {
-    std::env::var(OCI_NAMESPACE_VAR).unwrap_or(OCI_DEFAULT_NAMESPACE.to_string())
+    match installed_defaults() {
+        Some(defaults) => defaults.namespace,
+        None => std::env::var(OCI_NAMESPACE_VAR).unwrap_or(OCI_DEFAULT_NAMESPACE.to_string()),
+    }
+}
+
+/// The defaults installed with [`OciDefaults::install`], which take precedence over the environment.
+static INSTALLED_DEFAULTS: RwLock<Option<OciDefaults>> = RwLock::new(None);
+
+fn installed_defaults() -> Option<OciDefaults> {
+    INSTALLED_DEFAULTS
+        .read()
+        .ok()
+        .and_then(|defaults| defaults.clone())
+}
+
+/// The registry host (the "base") and namespace used to complete partial image references:
+/// for example `ubuntu` is expanded to `{base}/{namespace}/ubuntu`.
+///
+/// Values are validated when constructed, so that a mistake like `https://ghcr.io` is reported up front
+/// instead of producing a malformed reference that fails later with a confusing error from the registry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OciDefaults {
+    base: String,
+    namespace: String,
+}
+
+impl Default for OciDefaults {
+    fn default() -> Self {
+        Self {
+            base: OCI_DEFAULT_BASE.to_string(),
+            namespace: OCI_DEFAULT_NAMESPACE.to_string(),
+        }
+    }
+}
+
+impl OciDefaults {
+    /// Validate the base and namespace.
+    ///
+    /// The base must be a registry host, optionally with a port (e.g. `ghcr.io` or `localhost:5000`),
+    /// without a scheme, path, or trailing slash. The namespace is one or more repository path components
+    /// separated by `/` (e.g. `library` or `somecorp/someproject`). Surrounding whitespace is ignored,
+    /// and the base is lowercased since hosts are case-insensitive.
+    pub fn new(base: &str, namespace: &str) -> Result<Self> {
+        let base = parse_oci_base(base).map_err(Error::parse)?;
+        let namespace = parse_oci_namespace(namespace).map_err(Error::parse)?;
+        Ok(Self { base, namespace })
+    }
+
+    /// Read the defaults from [`OCI_BASE_VAR`] and [`OCI_NAMESPACE_VAR`],
+    /// using [`OCI_DEFAULT_BASE`] and [`OCI_DEFAULT_NAMESPACE`] for variables that aren't set.
+    pub fn from_env() -> Result<Self> {
+        let base = std::env::var(OCI_BASE_VAR).unwrap_or(OCI_DEFAULT_BASE.to_string());
+        let base = parse_oci_base(&base)
+            .with_context(|| format!("invalid value for {OCI_BASE_VAR}"))
+            .map_err(Error::parse)?;
+        let namespace =
+            std::env::var(OCI_NAMESPACE_VAR).unwrap_or(OCI_DEFAULT_NAMESPACE.to_string());
+        let namespace = parse_oci_namespace(&namespace)
+            .with_context(|| format!("invalid value for {OCI_NAMESPACE_VAR}"))
+            .map_err(Error::parse)?;
+        Ok(Self { base, namespace })
+    }
+
+    /// The defaults in effect: the installed defaults if any (see [`OciDefaults::install`]),
+    /// otherwise the defaults read from the environment.
+    pub fn current() -> Result<Self> {
+        match installed_defaults() {
+            Some(defaults) => Ok(defaults),
+            None => Self::from_env(),
+        }
+    }
+
+    /// Use these defaults for every reference parsed from now on in this process,
+    /// instead of reading them from the environment.
+    pub fn install(self) {
+        if let Ok(mut installed) = INSTALLED_DEFAULTS.write() {
+            *installed = Some(self);
+        }
+    }
+
+    /// The registry host used for references that don't name one.
+    pub fn base(&self) -> &str {
+        &self.base
+    }
+
+    /// The namespace used for references that only name an image.
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+}
+
+/// Validate and normalize the base of [`OciDefaults`].
+fn parse_oci_base(value: &str) -> eyre::Result<String> {
+    let value = value.trim();
+    ensure!(!value.is_empty(), "the registry host cannot be empty");
+    if let Some((_, rest)) = value.split_once("://") {
+        bail!(
+            "'{value}' must be a registry host without a scheme; use '{}' instead",
+            rest.trim_end_matches('/')
+        );
+    }
+    if let Some(host) = value.strip_suffix('/') {
+        bail!(
+            "'{value}' must not end with '/'; use '{}' instead",
+            host.trim_end_matches('/')
+        );
+    }
+    if let Some((host, path)) = value.split_once('/') {
+        bail!("'{value}' must be a registry host without a path; use '{host}' as the host and '{path}' as the namespace");
+    }
+
+    let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':' | '[' | ']');
+    if let Some(c) = value.chars().find(|&c| !valid(c)) {
+        bail!("'{value}' must be a registry host like 'ghcr.io' or 'localhost:5000', but contains {c:?}");
+    }
+    Ok(value.to_ascii_lowercase())
+}
+
+/// Validate and normalize the namespace of [`OciDefaults`].
+fn parse_oci_namespace(value: &str) -> eyre::Result<String> {
+    let value = value.trim();
+    ensure!(!value.is_empty(), "the namespace cannot be empty");
+    ensure!(
+        !value.contains("://"),
+        "'{value}' must be a namespace like 'library' or 'somecorp/someproject', not a URL"
+    );
+    let trimmed = value.trim_matches('/');
+    ensure!(
+        trimmed == value,
+        "'{value}' must not start or end with '/'; use '{trimmed}' instead"
+    );
+    ensure!(
+        !value.split('/').any(str::is_empty),
+        "'{value}' must not contain empty components ('//')"
+    );
+    if let Some(component) = value.split('/').find(|c| !is_repository_component(c)) {
+        bail!("'{value}' is not a valid namespace: component '{component}' must be lowercase letters and digits, optionally separated by '.', '_', '__', or '-'");
+    }
+    Ok(value.to_string())
 }
 
 /// Whether OCI registry connection is disabled.
 pub fn flag_disabled_registry_oci() -> Result<()> {
     if std::env::var(OCI_DISABLE_REGISTRY_OCI_VAR).is_ok() {
-        bail!("{OCI_DISABLE_REGISTRY_OCI_VAR} is set, skipping OCI registry connection");
+        return Err(Error::disabled(format!(
+            "{OCI_DISABLE_REGISTRY_OCI_VAR} is set, skipping OCI registry connection"
+        )));
     }
     Ok(())
 }
@@ -77,11 +250,105 @@ pub fn flag_disabled_registry_oci() -> Result<()> {
 /// Whether docker daemon connection is disabled.
 pub fn flag_disabled_daemon_docker() -> Result<()> {
     if std::env::var(OCI_DISABLE_DAEMON_DOCKER_VAR).is_ok() {
-        bail!("{OCI_DISABLE_DAEMON_DOCKER_VAR} is set, skipping docker daemon connection");
+        return Err(Error::disabled(format!(
+            "{OCI_DISABLE_DAEMON_DOCKER_VAR} is set, skipping docker daemon connection"
+        )));
+    }
+    Ok(())
+}
+
+/// Whether [`install_offline`] was called.
+static INSTALLED_OFFLINE: AtomicBool = AtomicBool::new(false);
+
+/// Forbid network access from now on in this process, so that images can only be read from local sources:
+/// tarballs (including OCI layouts) and the local Docker daemon, without pulling.
+///
+/// Reading from a registry or a remote daemon then fails with [`Error::Disabled`],
+/// and tarballs are checked for every layer of the image up front so that missing layers are all reported at once.
+pub fn install_offline() {
+    INSTALLED_OFFLINE.store(true, Ordering::Relaxed);
+}
+
+/// Whether network access is forbidden, by [`install_offline`] or [`OFFLINE_VAR`].
+pub fn is_offline() -> bool {
+    INSTALLED_OFFLINE.load(Ordering::Relaxed) || std::env::var(OFFLINE_VAR).is_ok()
+}
+
+/// Whether the operation, which requires network access, is disabled because network access is forbidden.
+pub fn flag_offline(operation: &str) -> Result<()> {
+    if is_offline() {
+        return Err(Error::disabled(format!(
+            "network access is forbidden in offline mode, so {operation} is not allowed"
+        )));
     }
     Ok(())
 }
 
+/// The hosts installed with [`AllowedHosts::install`]; if none are installed, every host is allowed.
+static INSTALLED_ALLOWED_HOSTS: RwLock<Option<AllowedHosts>> = RwLock::new(None);
+
+/// The registry hosts that may be contacted, so that processing untrusted references can't cause
+/// lookups against arbitrary hosts.
+///
+/// Hosts are compared with [`Reference::host`] after the reference is completed with [`OciDefaults`],
+/// so `ubuntu` is checked as `docker.io` by default. Hosts are matched exactly, including any port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllowedHosts {
+    hosts: Vec<String>,
+}
+
+impl AllowedHosts {
+    /// Validate the hosts, which must be registry hosts in the same form as [`OciDefaults::base`]
+    /// (e.g. `ghcr.io` or `localhost:5000`). Hosts are lowercased since they're case-insensitive.
+    pub fn new(hosts: impl IntoIterator<Item = impl AsRef<str>>) -> Result<Self> {
+        hosts
+            .into_iter()
+            .map(|host| parse_oci_base(host.as_ref()))
+            .collect::<eyre::Result<Vec<_>>>()
+            .context("invalid allowed host")
+            .map_err(Error::parse)
+            .map(|hosts| Self { hosts })
+    }
+
+    /// The hosts in effect, if any were installed with [`AllowedHosts::install`].
+    pub fn current() -> Option<Self> {
+        INSTALLED_ALLOWED_HOSTS
+            .read()
+            .ok()
+            .and_then(|hosts| hosts.clone())
+    }
+
+    /// Only allow these hosts to be contacted from now on in this process.
+    pub fn install(self) {
+        if let Ok(mut installed) = INSTALLED_ALLOWED_HOSTS.write() {
+            *installed = Some(self);
+        }
+    }
+
+    /// Report whether the host may be contacted.
+    pub fn allows(&self, host: &str) -> bool {
+        self.hosts
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(host))
+    }
+
+    /// The hosts that may be contacted.
+    pub fn hosts(&self) -> &[String] {
+        &self.hosts
+    }
+}
+
+/// Whether connecting to the registry host is disallowed by the installed [`AllowedHosts`].
+pub fn flag_disallowed_host(host: &str) -> Result<()> {
+    match AllowedHosts::current() {
+        Some(allowed) if !allowed.allows(host) => Err(Error::disabled(format!(
+            "registry host '{host}' is not allowed; allowed hosts: {}",
+            allowed.hosts.join(", ")
+        ))),
+        _ => Ok(()),
+    }
+}
+
 /// A trait that abstracts interaction with container images.
 ///
 /// This trait provides methods to interact with container images,
@@ -99,21 +366,77 @@ pub trait Source: std::fmt::Debug {
     /// Layers are returned in order from the base image to the application.
     fn layers(&self) -> impl Future<Output = Result<Vec<Layer>>>;
 
+    /// Read the configuration of the image.
+    fn config(&self) -> impl Future<Output = Result<ImageConfig>>;
+
     /// Pull the bytes of a layer from the source in a stream.
     fn pull_layer(
         &self,
         layer: &Layer,
     ) -> impl Future<Output = Result<Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>>>;
 
+    /// Report how the image is identified, consistently across sources.
+    ///
+    /// Unlike [`Source::name`], which reports whatever name the source has on hand,
+    /// this reports the same kind of value for every source; see [`Identity`] for details.
+    fn identity(&self) -> impl Future<Output = Result<Identity>> {
+        async {
+            let name = self.name().await?;
+            let digest = self.digest().await?;
+            Ok(Identity::builder()
+                .reference(&name)
+                .resolved_digest(digest)
+                .display_name(name)
+                .build())
+        }
+    }
+
+    /// Report the manifest that this one refers to through its `subject` field, if any.
+    ///
+    /// Artifacts like signatures and SBOMs set this to the manifest of the image they describe.
+    /// Sources that don't record the field (like the Docker daemon) report `None`.
+    fn subject(&self) -> impl Future<Output = Result<Option<Subject>>> {
+        async { Ok(None) }
+    }
+}
+
+/// Extends [`Source`] with operations that unpack layer tarballs.
+///
+/// These are separate from [`Source`] because the tar implementation requires filesystem access,
+/// which isn't available on targets like `wasm32-wasip1`; see the `native` feature.
+#[cfg(feature = "native")]
+pub trait Unpack: Source {
     /// Enumerate files in a layer.
     fn list_files(&self, layer: &Layer) -> impl Future<Output = Result<Vec<String>>>;
 
+    /// Enumerate files in a layer, reporting each one as it is read rather than collecting them.
+    ///
+    /// This reports the same files as [`Unpack::list_files`], but large layers don't need to be held in memory.
+    /// If reporting a file fails, enumeration stops with that error.
+    /// Sources that can't stream their files report them once the layer has been listed.
+    fn visit_files(
+        &self,
+        layer: &Layer,
+        mut on_file: impl FnMut(String) -> std::io::Result<()>,
+    ) -> impl Future<Output = Result<()>> {
+        async move {
+            for file in self.list_files(layer).await? {
+                on_file(file).context("report file")?;
+            }
+            Ok(())
+        }
+    }
+
     /// Apply a layer to a location on disk.
     ///
     /// The intention of this method is that when it is run for each layer in an image in order it is equivalent
     /// to the functionality you'd get by running `docker pull`, `docker save`, and then recursively extracting the
     /// layers to the same directory.
-    fn apply_layer(&self, layer: &Layer, output: &Path) -> impl Future<Output = Result<()>>;
+    fn apply_layer(
+        &self,
+        layer: &Layer,
+        output: &std::path::Path,
+    ) -> impl Future<Output = Result<()>>;
 
     /// Normalize an OCI layer into a plain tarball layer.
     ///
@@ -122,7 +445,48 @@ pub trait Source: std::fmt::Debug {
     ///
     /// The twist though is that OCI servers can wrap various kinds of compression around tarballs;
     /// this method flattens them all down into plain uncompressed `.tar` files.
-    fn layer_plain_tarball(&self, layer: &Layer) -> impl Future<Output = Result<Option<TempFile>>>;
+    fn layer_plain_tarball(
+        &self,
+        layer: &Layer,
+    ) -> impl Future<Output = Result<Option<async_tempfile::TempFile>>>;
+
+    /// Read the entries of a layer as a stream, without extracting them to disk.
+    ///
+    /// This supports analyses that only need to look inside some files in the layer.
+    /// Entries are reported as they appear in the layer, so whiteouts are reported rather than applied,
+    /// and file filters are not applied.
+    /// Foreign layers are emitted as `None`.
+    fn entries(&self, layer: &Layer) -> impl Future<Output = Result<Option<entries::Entries>>> {
+        use futures_lite::StreamExt;
+        async move {
+            let stream = self
+                .pull_layer(layer)
+                .await?
+                .map(|chunk| chunk.map_err(std::io::Error::other));
+            transform::peel_layer(layer, stream, transform::Buffering::default())
+                .map(entries::Entries::new)
+                .transpose()
+        }
+    }
+
+    /// List the raw tar headers of each entry in a layer, without extracting them to disk.
+    ///
+    /// Unlike [`Unpack::entries`], PAX extensions and GNU long name entries are reported as entries
+    /// in their own right; this is intended for debugging malformed layers.
+    /// Foreign layers are emitted as `None`.
+    fn headers(&self, layer: &Layer) -> impl Future<Output = Result<Option<Vec<entries::Header>>>> {
+        use futures_lite::StreamExt;
+        async move {
+            let stream = self
+                .pull_layer(layer)
+                .await?
+                .map(|chunk| chunk.map_err(std::io::Error::other));
+            match transform::peel_layer(layer, stream, transform::Buffering::default()) {
+                Some(stream) => entries::headers(stream).await.map(Some),
+                None => Ok(None),
+            }
+        }
+    }
 }
 
 /// Authentication method for a registry.
@@ -270,25 +634,188 @@ impl Platform {
     }
 }
 
-impl FromStr for Platform {
-    type Err = eyre::Error;
+impl Platform {
+    /// Normalize the platform to its canonical form, following the conventions of containerd and the docker CLI:
+    /// - The OS is lowercase, and `macos` is `darwin`.
+    /// - Architecture aliases are their `GOARCH` names (e.g. `x86_64` is `amd64`, `aarch64` is `arm64`).
+    /// - Numeric variants have a `v` prefix (e.g. `arm/7` is `arm/v7`).
+    /// - `arm` has the variant `v7` unless otherwise specified, matching the default `GOARM`.
+    /// - Variants implied by the architecture are removed: `arm64/v8` is `arm64`, and `amd64/v1` is `amd64`.
+    ///
+    /// ```
+    /// # use circe_lib::Platform;
+    /// let platform = "linux/aarch64/v8".parse::<Platform>().expect("parse platform");
+    /// assert_eq!(platform.normalize(), Platform::linux_arm64());
+    ///
+    /// let platform = "linux/armhf".parse::<Platform>().expect("parse platform");
+    /// assert_eq!(platform.normalize().to_string(), "linux/arm/v7");
+    /// ```
+    pub fn normalize(&self) -> Self {
+        let os = match self.os.to_lowercase().as_str() {
+            "macos" => Self::DARWIN.to_string(),
+            os => os.to_string(),
+        };
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let variant = self.variant.as_deref().map(|variant| {
+            let variant = variant.to_lowercase();
+            match variant.parse::<u32>() {
+                Ok(level) => format!("v{level}"),
+                Err(_) => variant,
+            }
+        });
+        let (architecture, variant) = match self.architecture.to_lowercase().as_str() {
+            "x86_64" | "x86-64" | Self::AMD64 => match variant.as_deref() {
+                Some("v1") => (Self::AMD64, None),
+                _ => (Self::AMD64, variant),
+            },
+            "aarch64" | Self::ARM64 => match variant.as_deref() {
+                Some("v8") => (Self::ARM64, None),
+                _ => (Self::ARM64, variant),
+            },
+            "armhf" => ("arm", Some(String::from("v7"))),
+            "armel" => ("arm", Some(String::from("v6"))),
+            "arm" => ("arm", variant.or_else(|| Some(String::from("v7")))),
+            "i386" | "i686" => ("386", variant),
+            _ => (self.architecture.as_str(), variant),
+        };
+
+        Self {
+            os,
+            architecture: architecture.to_lowercase(),
+            variant,
+            os_version: self.os_version.clone(),
+            os_features: self.os_features.clone(),
+        }
+    }
+
+    /// Report whether an image built for the candidate platform can be used for this platform.
+    ///
+    /// See [`Platform::score`] for details.
+    ///
+    /// ```
+    /// # use circe_lib::Platform;
+    /// let platform = "linux/arm/v7".parse::<Platform>().expect("parse platform");
+    /// assert!(platform.matches(&"linux/arm/v6".parse().expect("parse platform")));
+    /// assert!(!platform.matches(&"linux/arm/v8".parse().expect("parse platform")));
+    /// assert!(Platform::linux_arm64().matches(&"linux/arm64/v8".parse().expect("parse platform")));
+    /// ```
+    pub fn matches(&self, candidate: &Platform) -> bool {
+        self.score(candidate).is_some()
+    }
+
+    /// Score how well an image built for the candidate platform suits this platform, once both are normalized
+    /// (see [`Platform::normalize`]). Lower scores are better matches, and `0` is an exact match;
+    /// `None` means the candidate can't be used.
+    ///
+    /// The OS and architecture must always match. If the variant doesn't:
+    /// - Candidates with an older numbered variant of the architecture are usable, since CPUs are backwards compatible
+    ///   (e.g. `linux/arm/v6` images run on `linux/arm/v7`); the closer the variant the better the score.
+    /// - If this platform doesn't specify a variant, candidates with any other variant are usable,
+    ///   but score worse than all of the above.
+    /// - Otherwise the candidate can't be used.
+    ///
+    /// If this platform specifies an OS version, the candidate must also be compatible with it
+    /// (see [`Platform::os_version_score`]), and its score is added to the score of the variant.
+    pub fn score(&self, candidate: &Platform) -> Option<usize> {
+        let target = self.normalize();
+        let candidate = candidate.normalize();
+        if target.os != candidate.os || target.architecture != candidate.architecture {
+            return None;
+        }
+
+        let version = match &target.os_version {
+            Some(version) => Self::os_version_score(version, candidate.os_version.as_deref()?)?,
+            None => 0,
+        };
+        if target.variant == candidate.variant {
+            return Some(version);
+        }
+
+        let variant = match (target.variant_level(), candidate.variant_level()) {
+            (Some(target), Some(candidate)) if candidate < target => (target - candidate) as usize,
+            _ if self.variant.is_none() => Self::VARIANT_MISMATCH_SCORE,
+            _ => return None,
+        };
+        Some(variant + version)
+    }
+
+    /// Score how well an image built for the candidate OS version suits the target OS version,
+    /// following the Windows rule for container compatibility: the host must run the same build as the image
+    /// (the first three components, e.g. `10.0.17763`) and the same or a later revision (the fourth component).
+    /// Lower scores are better: the score is the number of revisions by which the candidate trails the target,
+    /// so the latest revision the host can run is preferred.
+    ///
+    /// If the target revision is `x` or `*` (e.g. `10.0.17763.x`), or is omitted, any revision of the build is usable
+    /// and every candidate scores the same.
+    ///
+    /// ```
+    /// # use circe_lib::Platform;
+    /// assert_eq!(Platform::os_version_score("10.0.17763.1879", "10.0.17763.1879"), Some(0));
+    /// assert_eq!(Platform::os_version_score("10.0.17763.1879", "10.0.17763.1869"), Some(10));
+    /// assert_eq!(Platform::os_version_score("10.0.17763.1879", "10.0.17763.1935"), None);
+    /// assert_eq!(Platform::os_version_score("10.0.17763.x", "10.0.17763.1935"), Some(0));
+    /// assert_eq!(Platform::os_version_score("10.0.17763.x", "10.0.20348.1906"), None);
+    /// ```
+    pub fn os_version_score(target: &str, candidate: &str) -> Option<usize> {
+        let (target_build, target_revision) = split_os_version(target);
+        let (candidate_build, candidate_revision) = split_os_version(candidate);
+        if target_build != candidate_build {
+            return None;
+        }
+
+        match (target_revision, candidate_revision) {
+            (None | Some("x" | "*"), _) => Some(0),
+            (Some(target), candidate) => {
+                let target = target.parse::<usize>().ok()?;
+                let candidate = candidate.unwrap_or("0").parse::<usize>().ok()?;
+                target.checked_sub(candidate)
+            }
+        }
+    }
+
+    /// The score of a candidate whose variant doesn't match, when no variant is requested; see [`Platform::score`].
+    const VARIANT_MISMATCH_SCORE: usize = 1000;
+
+    /// The numbered level of the variant of a normalized platform (e.g. `7` for `v7`), if it has one.
+    ///
+    /// Platforms without a variant have the baseline level of their architecture.
+    fn variant_level(&self) -> Option<u32> {
+        match (self.architecture.as_str(), self.variant.as_deref()) {
+            (_, Some(variant)) => variant.strip_prefix('v')?.parse().ok(),
+            (Self::AMD64, None) => Some(1),
+            (Self::ARM64, None) => Some(8),
+            _ => None,
+        }
+    }
+}
+
+impl Platform {
+    /// Parse the value, reporting errors with detailed context.
+    fn parse(s: &str) -> eyre::Result<Self> {
         let input_section = || s.to_string().header("Input:");
         let expected_section = || {
-            "{os}/{architecture}[/{variant}]"
+            "{os}/{architecture}[/{variant}][:{os_version}]"
                 .to_string()
                 .header("Expected:")
         };
         let examples_section = || {
-            ["linux/amd64/v7", "darwin/arm64"]
-                .join("\n")
-                .header("Examples:")
+            [
+                "linux/amd64/v7",
+                "darwin/arm64",
+                "windows/amd64:10.0.17763.x",
+            ]
+            .join("\n")
+            .header("Examples:")
         };
 
-        // Docker platform strings are of the form: os/arch[/variant]
-        let parts = s.split('/').collect::<Vec<_>>();
-        if parts.iter().any(|part| part.is_empty()) {
+        // Docker platform strings are of the form: os/arch[/variant];
+        // like containerd, the OS version may follow a colon (used to select Windows images).
+        let (platform, os_version) = match s.split_once(':') {
+            Some((platform, os_version)) => (platform, Some(os_version)),
+            None => (s, None),
+        };
+        let parts = platform.split('/').collect::<Vec<_>>();
+        if parts.iter().any(|part| part.is_empty()) || os_version == Some("") {
             return eyre!("invalid platform format")
                 .with_section(input_section)
                 .with_section(expected_section)
@@ -300,12 +827,14 @@ impl FromStr for Platform {
             [os, architecture] => Self::builder()
                 .os(os.to_string())
                 .architecture(architecture.to_string())
+                .maybe_os_version(os_version)
                 .build()
                 .pipe(Ok),
             [os, architecture, variant] => Self::builder()
                 .os(os.to_string())
                 .architecture(architecture.to_string())
                 .variant(variant.to_string())
+                .maybe_os_version(os_version)
                 .build()
                 .pipe(Ok),
             _ => eyre!("invalid platform format")
@@ -317,22 +846,143 @@ impl FromStr for Platform {
     }
 }
 
+impl FromStr for Platform {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s).map_err(Error::parse)
+    }
+}
+
 impl std::fmt::Display for Platform {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}/{}", self.os, self.architecture)?;
         if let Some(variant) = &self.variant {
             write!(f, "/{variant}")?;
         }
+        if let Some(os_version) = &self.os_version {
+            write!(f, ":{os_version}")?;
+        }
         Ok(())
     }
 }
 
+/// Split an OS version into its build (e.g. `10.0.17763`) and its revision (e.g. `1879`), if it has one.
+fn split_os_version(version: &str) -> (&str, Option<&str>) {
+    match version.match_indices('.').nth(2) {
+        Some((index, _)) => (&version[..index], Some(&version[index + 1..])),
+        None => (version, None),
+    }
+}
+
+impl<'de> Deserialize<'de> for Platform {
+    /// Platforms serialize as a structure, but are usually written as strings like `linux/amd64`;
+    /// both forms are accepted.
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            String(String),
+            Structured {
+                os: String,
+                architecture: String,
+                #[serde(default)]
+                variant: Option<String>,
+                #[serde(default)]
+                os_version: Option<String>,
+                #[serde(default)]
+                os_features: Vec<String>,
+            },
+        }
+
+        match Repr::deserialize(deserializer)? {
+            Repr::String(s) => FromStr::from_str(&s).map_err(serde::de::Error::custom),
+            Repr::Structured {
+                os,
+                architecture,
+                variant,
+                os_version,
+                os_features,
+            } => Ok(Self {
+                os,
+                architecture,
+                variant,
+                os_version,
+                os_features,
+            }),
+        }
+    }
+}
+
 impl From<&Platform> for Platform {
     fn from(platform: &Platform) -> Self {
         platform.clone()
     }
 }
 
+/// An annotation that an image must have to be selected from an image index, written as `key=value`.
+///
+/// Indexes can contain several manifests for the same platform (for example attestations or provenance),
+/// which are distinguished by their annotations rather than their platform.
+///
+/// ```
+/// # use circe_lib::Annotation;
+/// let annotation = "org.opencontainers.image.ref.name=foo".parse::<Annotation>().expect("parse annotation");
+/// assert_eq!(annotation.key, "org.opencontainers.image.ref.name");
+/// assert_eq!(annotation.value, "foo");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Annotation {
+    /// The key of the annotation (e.g. `org.opencontainers.image.ref.name`).
+    pub key: String,
+
+    /// The value the annotation must have.
+    pub value: String,
+}
+
+impl Annotation {
+    /// Report whether the annotation is present with the same value in the annotations of a manifest.
+    pub fn matches<'a>(
+        &self,
+        annotations: impl IntoIterator<Item = (&'a String, &'a String)>,
+    ) -> bool {
+        annotations
+            .into_iter()
+            .any(|(key, value)| key == &self.key && value == &self.value)
+    }
+
+    /// Parse the value, reporting errors with detailed context.
+    fn parse(s: &str) -> eyre::Result<Self> {
+        match s.split_once('=') {
+            Some((key, value)) if !key.is_empty() => Ok(Self {
+                key: key.to_string(),
+                value: value.to_string(),
+            }),
+            _ => eyre!("invalid annotation format")
+                .with_section(|| s.to_string().header("Input:"))
+                .with_section(|| "{key}={value}".to_string().header("Expected:"))
+                .pipe(Err),
+        }
+    }
+}
+
+impl FromStr for Annotation {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s).map_err(Error::parse)
+    }
+}
+
+impl std::fmt::Display for Annotation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.key, self.value)
+    }
+}
+
 /// Create a [`Digest`] from a hex string at compile time.
 /// ```
 /// let digest = circe_lib::digest!("sha256", "a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4");
@@ -347,93 +997,210 @@ impl From<&Platform> for Platform {
 /// assert_eq!(digest.as_hex(), "a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4");
 /// ```
 ///
-/// This macro currently assumes that the hash is 32 bytes long.
-/// Providing a value of a different length will result in a compile-time error.
+/// The length of the hash is checked against the algorithm,
+/// so providing a value of the wrong length results in a compile-time error.
 /// ```compile_fail
 /// let digest = circe_lib::digest!("a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4deadbeef");
 /// ```
 ///
-/// You can work around this by providing the size of the hash as a third argument.
+/// As does an unsupported algorithm.
+/// ```compile_fail
+/// let digest = circe_lib::digest!("md5", "d41d8cd98f00b204e9800998ecf8427e");
+/// ```
+///
+/// Earlier versions accepted the size of the hash as a third argument.
+/// This form is deprecated: it still compiles, but the size must match the algorithm.
 /// ```
-/// let digest = circe_lib::digest!(circe_lib::Digest::SHA256, "a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4deadbeef", 36);
-/// assert_eq!(digest.algorithm, "sha256");
-/// assert_eq!(digest.as_hex(), "a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4deadbeef");
+/// # #![allow(deprecated)]
+/// let digest = circe_lib::digest!("sha256", "a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4", 32);
+/// assert_eq!(digest.as_hex(), "a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4");
 /// ```
 #[macro_export]
 macro_rules! digest {
     ($hex:expr) => {{
-        $crate::digest!($crate::Digest::SHA256, $hex, 32)
-    }};
-    ($algorithm:expr, $hex:expr) => {{
-        $crate::digest!($algorithm, $hex, 32)
+        $crate::digest!($crate::Digest::SHA256, $hex)
     }};
     ($algorithm:expr, $hex:expr, $size:expr) => {{
-        const HASH: [u8; $size] = hex_magic::hex!($hex);
-        static_assertions::const_assert_ne!(HASH.len(), 0);
-        static_assertions::const_assert_ne!($algorithm.len(), 0);
+        const _: () = assert!(
+            $crate::digest_size_argument($size)
+                == $crate::DigestAlgorithm::named($algorithm).hash_len(),
+            "digest size doesn't match the algorithm",
+        );
+        $crate::digest!($algorithm, $hex)
+    }};
+    ($algorithm:expr, $hex:expr) => {{
+        const ALGORITHM: $crate::DigestAlgorithm = $crate::DigestAlgorithm::named($algorithm);
+        const HASH: [u8; ALGORITHM.hash_len()] = hex_magic::hex!($hex);
         $crate::Digest {
-            algorithm: $algorithm.to_string(),
+            algorithm: ALGORITHM,
             hash: HASH.to_vec(),
         }
     }};
 }
 
+/// Support for the deprecated size argument to [`digest!`].
+#[doc(hidden)]
+#[deprecated(note = "the hash size is derived from the algorithm; use `digest!(algorithm, hex)`")]
+pub const fn digest_size_argument(size: usize) -> usize {
+    size
+}
+
+/// The hashing algorithms supported for digests.
+///
+/// Spec reference: https://github.com/opencontainers/image-spec/blob/main/descriptor.md#registered-algorithms
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, AsRefStr, EnumIter)]
+pub enum DigestAlgorithm {
+    /// SHA-256, the algorithm used by nearly all images.
+    #[strum(serialize = "sha256")]
+    Sha256,
+
+    /// SHA-512.
+    #[strum(serialize = "sha512")]
+    Sha512,
+}
+
+impl DigestAlgorithm {
+    /// The length of a hash produced by the algorithm, in bytes.
+    pub const fn hash_len(self) -> usize {
+        match self {
+            DigestAlgorithm::Sha256 => 32,
+            DigestAlgorithm::Sha512 => 64,
+        }
+    }
+
+    /// Look up the algorithm by name at compile time, for [`digest!`].
+    ///
+    /// Panics if the algorithm isn't supported, which is a compile-time error in a `const` context.
+    #[doc(hidden)]
+    pub const fn named(name: &str) -> Self {
+        const fn eq(a: &[u8], b: &[u8]) -> bool {
+            if a.len() != b.len() {
+                return false;
+            }
+            let mut i = 0;
+            while i < a.len() {
+                if a[i] != b[i] {
+                    return false;
+                }
+                i += 1;
+            }
+            true
+        }
+
+        if eq(name.as_bytes(), Digest::SHA256.as_bytes()) {
+            DigestAlgorithm::Sha256
+        } else if eq(name.as_bytes(), Digest::SHA512.as_bytes()) {
+            DigestAlgorithm::Sha512
+        } else {
+            panic!("unsupported digest algorithm")
+        }
+    }
+}
+
+impl DigestAlgorithm {
+    /// Parse the value, reporting errors with detailed context.
+    fn parse(s: &str) -> eyre::Result<Self> {
+        Self::iter()
+            .find(|algorithm| algorithm.as_ref() == s)
+            .ok_or_else(|| eyre!("unsupported digest algorithm: '{s}'"))
+            .with_section(|| Self::iter().join("\n").header("Supported algorithms:"))
+    }
+}
+
+impl FromStr for DigestAlgorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s).map_err(Error::parse)
+    }
+}
+
+impl std::fmt::Display for DigestAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_ref())
+    }
+}
+
+impl PartialEq<&str> for DigestAlgorithm {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_ref() == *other
+    }
+}
+
 /// A content-addressable digest in the format `algorithm:hash`.
 ///
 /// The `FromStr` implementation parses the format used in OCI containers by default,
-/// which is `algorithm:hex`.
+/// which is `algorithm:hex`. The hex must be lowercase and of the length the algorithm produces.
 ///
 /// ```
 /// # use std::str::FromStr;
 /// let digest = circe_lib::Digest::from_str("sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4").expect("parse digest");
 /// assert_eq!(digest.algorithm, "sha256");
 /// assert_eq!(digest.as_hex(), "a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4");
+///
+/// assert!(circe_lib::Digest::from_str("sha256:a3ed95caeb02").is_err());
+/// assert!(circe_lib::Digest::from_str("md5:d41d8cd98f00b204e9800998ecf8427e").is_err());
 /// ```
 #[derive(Debug, Clone, PartialEq, Eq)]
 #[debug("{}", self.to_string())]
 pub struct Digest {
-    /// The hashing algorithm used (e.g. "sha256")
-    pub algorithm: String,
+    /// The hashing algorithm used
+    pub algorithm: DigestAlgorithm,
 
     /// The raw hash bytes
     pub hash: Vec<u8>,
 }
 
 impl Digest {
-    /// The SHA256 algorithm
+    /// The name of the SHA256 algorithm
     pub const SHA256: &'static str = "sha256";
 
+    /// The name of the SHA512 algorithm
+    pub const SHA512: &'static str = "sha512";
+
+    /// The number of hex characters in the short form of a digest; see [`Digest::short`].
+    pub const SHORT_LEN: usize = 12;
+
     /// Returns the hash as a hex string
     pub fn as_hex(&self) -> String {
         hex::encode(&self.hash)
     }
 
-    /// Returns the filename to use for a tarball with this digest.
-    pub fn tarball_filename(&self) -> String {
-        format!("{}.tar", self.as_hex())
-    }
+    /// Returns the short form of the digest: the first 12 characters of the hex string.
+    ///
+    /// This matches the short image IDs displayed by the docker CLI.
+    /// ```
+    /// let digest = circe_lib::digest!("a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4");
+    /// assert_eq!(digest.short(), "a3ed95caeb02");
+    /// ```
+    pub fn short(&self) -> String {
+        let mut hex = self.as_hex();
+        hex.truncate(Self::SHORT_LEN);
+        hex
+    }
+
+    /// Returns the filename to use for a tarball with this digest.
+    pub fn tarball_filename(&self) -> String {
+        format!("{}.tar", self.as_hex())
+    }
 
     /// Parse the provided string as a SHA256 hex digest.
     pub fn from_sha256(s: &str) -> Result<Self> {
-        Ok(Self {
-            algorithm: Self::SHA256.to_string(),
-            hash: hex::decode(s).map_err(|e| eyre!("invalid hex string: {e}"))?,
-        })
+        Self::parse_hash(DigestAlgorithm::Sha256, s).map_err(Error::parse)
     }
 
     /// Create a new instance assuming it is sha256 encoded.
     pub fn from_hash(hash: impl Into<Vec<u8>>) -> Self {
         Self {
-            algorithm: Self::SHA256.to_string(),
+            algorithm: DigestAlgorithm::Sha256,
             hash: hash.into(),
         }
     }
 }
 
-impl FromStr for Digest {
-    type Err = color_eyre::Report;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
+impl Digest {
+    /// Parse the value, reporting errors with detailed context.
+    fn parse(s: &str) -> eyre::Result<Self> {
         let input_section = || s.to_string().header("Input:");
         let (algorithm, hex) = s.split_once(':').ok_or_else(|| {
             eyre!("invalid digest format: missing algorithm separator ':'")
@@ -447,13 +1214,39 @@ impl FromStr for Digest {
             bail!("hex cannot be empty");
         }
 
+        let algorithm = DigestAlgorithm::parse(algorithm).with_section(input_section)?;
+        Self::parse_hash(algorithm, hex).with_section(input_section)
+    }
+
+    /// Parse the hex encoded hash, which must be lowercase and the length of a hash from the algorithm.
+    fn parse_hash(algorithm: DigestAlgorithm, hex: &str) -> eyre::Result<Self> {
+        let expected = algorithm.hash_len() * 2;
+        ensure!(
+            hex.len() == expected,
+            "invalid {algorithm} digest: must be {expected} hex characters, but is {}",
+            hex.len(),
+        );
+        ensure!(
+            hex.chars()
+                .all(|c| c.is_ascii_digit() || matches!(c, 'a'..='f')),
+            "invalid {algorithm} digest: must contain only lowercase hex characters"
+        );
+
         Ok(Self {
-            algorithm: algorithm.to_string(),
+            algorithm,
             hash: hex::decode(hex).map_err(|e| eyre!("invalid hex string: {e}"))?,
         })
     }
 }
 
+impl FromStr for Digest {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s).map_err(Error::parse)
+    }
+}
+
 impl std::fmt::Display for Digest {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}:{}", self.algorithm, self.as_hex())
@@ -507,8 +1300,7 @@ impl From<&Digest> for String {
 /// let digest = Digest::from_str("sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4").expect("parse digest");
 /// assert_eq!(Version::digest(digest).to_string(), "sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4");
 /// ```
-#[derive(Debug, Clone, PartialEq, Eq, Display, Serialize)]
-#[serde(rename_all = "snake_case", tag = "kind", content = "value")]
+#[derive(Debug, Clone, PartialEq, Eq, Display)]
 pub enum Version {
     /// A named tag (e.g. "latest", "1.0.0")
     Tag(String),
@@ -551,14 +1343,60 @@ impl Version {
     }
 }
 
+impl Version {
+    /// Parse the value, reporting errors with detailed context.
+    ///
+    /// Tags can't contain `:`, so values that do are parsed as digests.
+    fn parse(s: &str) -> eyre::Result<Self> {
+        if s.contains(':') {
+            return Digest::from_str(s)
+                .context("parse digest")
+                .map(Self::Digest);
+        }
+
+        validate_tag(s)?;
+        Ok(Self::tag(s))
+    }
+}
+
+impl FromStr for Version {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s).map_err(Error::parse)
+    }
+}
+
+impl Serialize for Version {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_string().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Version {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        FromStr::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
 /// A container image reference provided by a user.
-#[derive(Debug, Clone, PartialEq, Eq, Builder, Serialize)]
+///
+/// References serialize in the same form in which they're written and displayed,
+/// e.g. `docker.io/library/ubuntu:latest`.
+#[derive(Debug, Clone, PartialEq, Eq, Builder)]
 pub struct Reference {
     /// Registry host (e.g. "docker.io", "ghcr.io")
     #[builder(into)]
     pub host: String,
 
-    /// Repository namespace
+    /// Repository namespace.
+    ///
+    /// Empty for repositories without a namespace, like `localhost:5000/app`;
+    /// only registries other than Docker Hub support these.
     #[builder(into)]
     pub namespace: String,
 
@@ -572,9 +1410,19 @@ pub struct Reference {
 }
 
 impl Reference {
+    /// The maximum length of a tag, per the OCI distribution spec.
+    pub const MAX_TAG_LEN: usize = 128;
+
+    /// The maximum length of a repository name including the host, as enforced by the docker CLI.
+    pub const MAX_NAME_LEN: usize = 255;
+
     /// The combined namespace and name, the "repository", of the reference.
     pub fn repository(&self) -> String {
-        format!("{}/{}", self.namespace, self.name)
+        if self.namespace.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{}/{}", self.namespace, self.name)
+        }
     }
 }
 
@@ -599,14 +1447,22 @@ impl<S: reference_builder::State> ReferenceBuilder<S> {
     }
 }
 
-impl FromStr for Reference {
-    type Err = eyre::Error;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
+impl Reference {
+    /// Parse the value, reporting errors with detailed context.
+    fn parse(s: &str) -> eyre::Result<Self> {
         // Returns an owned string so that we can support multiple name segments.
         fn parse_name(name: &str) -> Result<(String, Version)> {
             if let Some((name, digest)) = name.split_once('@') {
                 let digest = Digest::from_str(digest).context("parse digest")?;
+
+                // Like the docker CLI, the digest takes precedence over a tag in `{name}:{tag}@{digest}`.
+                let name = match name.split_once(':') {
+                    Some((name, tag)) => {
+                        warn!("ignoring tag '{tag}' in favor of digest '{digest}'");
+                        name
+                    }
+                    None => name,
+                };
                 Ok((name.to_string(), Version::Digest(digest)))
             } else if let Some((name, tag)) = name.split_once(':') {
                 Ok((name.to_string(), Version::Tag(tag.to_string())))
@@ -618,8 +1474,9 @@ impl FromStr for Reference {
         // Docker supports `docker pull ubuntu` and `docker pull library/ubuntu`,
         // both of which are parsed as `docker.io/library/ubuntu`.
         // The below recreates this behavior.
-        let base = oci_base();
-        let namespace = oci_namespace();
+        let defaults = OciDefaults::current()?;
+        let base = defaults.base;
+        let namespace = defaults.namespace;
         let parts = s.split('/').collect::<Vec<_>>();
         let (host, namespace, name, version) = match parts.as_slice() {
             // For docker compatibility, `{name}` is parsed as `{base}/{namespace}/{name}`.
@@ -629,28 +1486,39 @@ impl FromStr for Reference {
                 (base, namespace, name, version)
             }
 
-            // Two segments may mean "{namespace}/{name}" or may mean "{base}/{name}".
-            // This is a special case for docker compatibility.
+            // Two segments may mean "{namespace}/{name}", "{base}/{name}", or "{host}/{name}".
+            // The base is a special case for docker compatibility.
             [host, name] if *host == base => {
                 let (name, version) = parse_name(name)?;
                 warn!("expanding '{host}/{name}' to '{base}/{namespace}/{name}'; fully specify the reference to avoid this behavior");
                 (host.to_string(), namespace, name, version)
             }
-            [namespace, name] => {
+
+            // Other registries support repositories without a namespace, e.g. `localhost:5000/app`.
+            [host, name] if is_host(host) => {
                 let (name, version) = parse_name(name)?;
-                warn!("expanding '{namespace}/{name}' to '{base}/{namespace}/{name}'; fully specify the reference to avoid this behavior");
-                (base, namespace.to_string(), name, version)
+                (host.to_string(), String::new(), name, version)
             }
 
             // Some names have multiple segments, e.g. `docker.io/library/ubuntu/foo`.
             // We can't handle multi-segment names in other branches since they conflict with the various shorthands,
             // but handle them here since they're not ambiguous.
-            [host, namespace, name @ ..] => {
+            [host, namespace, name @ ..] if is_host(host) => {
+                ensure!(!namespace.is_empty(), "namespace cannot be empty: {s}");
                 let name = name.join("/");
                 let (name, version) = parse_name(&name)?;
                 (host.to_string(), namespace.to_string(), name, version)
             }
-            _ => {
+
+            // Without a host, the reference is `{namespace}/{name}` on the base registry.
+            [namespace, name @ ..] => {
+                ensure!(!namespace.is_empty(), "namespace cannot be empty: {s}");
+                let name = name.join("/");
+                let (name, version) = parse_name(&name)?;
+                warn!("expanding '{namespace}/{name}' to '{base}/{namespace}/{name}'; fully specify the reference to avoid this behavior");
+                (base, namespace.to_string(), name, version)
+            }
+            [] => {
                 return eyre!("invalid reference format: {s}")
                     .with_section(|| {
                         [
@@ -671,21 +1539,111 @@ impl FromStr for Reference {
         };
 
         ensure!(!host.is_empty(), "host cannot be empty: {s}");
-        ensure!(!namespace.is_empty(), "namespace cannot be empty: {s}");
         ensure!(!name.is_empty(), "name cannot be empty: {s}");
 
-        Ok(Reference {
+        let reference = Reference {
             host: host.to_string(),
             namespace: namespace.to_string(),
             name: name.to_string(),
             version,
-        })
+        };
+        reference
+            .validate()
+            .with_section(|| s.to_string().header("Reference:"))?;
+        Ok(reference)
+    }
+
+    /// Validate the repository and tag against the rules of the OCI distribution spec,
+    /// so that invalid references are reported clearly instead of as confusing errors from the registry.
+    ///
+    /// Spec reference: https://github.com/opencontainers/distribution-spec/blob/main/spec.md#pulling-manifests
+    fn validate(&self) -> eyre::Result<()> {
+        let repository = self.repository();
+        if let Some(component) = repository.split('/').find(|c| !is_repository_component(c)) {
+            let problem = if component.chars().any(|c| c.is_ascii_uppercase()) {
+                "repository names must be lowercase"
+            } else {
+                "each component must be lowercase letters and digits, optionally separated by '.', '_', '__', or '-'"
+            };
+            return eyre!("invalid repository name '{repository}': {problem}")
+                .with_section(|| component.to_string().header("Invalid component:"))
+                .with_section(|| {
+                    ["library/ubuntu", "fossas/circe", "my-org/my_app.api"]
+                        .join("\n")
+                        .header("Examples:")
+                })
+                .pipe(Err);
+        }
+
+        let name = format!("{}/{repository}", self.host);
+        ensure!(
+            name.len() <= Self::MAX_NAME_LEN,
+            "invalid repository name '{name}': must be at most {} characters including the host, but is {}",
+            Self::MAX_NAME_LEN,
+            name.len(),
+        );
+
+        if let Version::Tag(tag) = &self.version {
+            validate_tag(tag)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether the repository path component matches `[a-z0-9]+((\.|_|__|-+)[a-z0-9]+)*`.
+fn is_repository_component(component: &str) -> bool {
+    let alphanumeric = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit();
+    let separator = |s: &str| matches!(s, "." | "_" | "__") || s.chars().all(|c| c == '-');
+    component.starts_with(alphanumeric)
+        && component.ends_with(alphanumeric)
+        && component
+            .split(alphanumeric)
+            .filter(|s| !s.is_empty())
+            .all(separator)
+}
+
+/// Validate the tag against the character set and length limit of the OCI distribution spec.
+fn validate_tag(tag: &str) -> eyre::Result<()> {
+    ensure!(
+        tag.len() <= Reference::MAX_TAG_LEN,
+        "invalid tag '{tag}': must be at most {} characters, but is {}",
+        Reference::MAX_TAG_LEN,
+        tag.len(),
+    );
+    if !is_tag(tag) {
+        return eyre!("invalid tag '{tag}': tags must start with a letter, digit, or '_', and contain only letters, digits, '_', '.', and '-'")
+            .with_section(|| ["latest", "1.0.0", "sha-e01ce6b", "v2_rc.1"].join("\n").header("Examples:"))
+            .pipe(Err);
+    }
+    Ok(())
+}
+
+/// Whether the tag matches `[a-zA-Z0-9_][a-zA-Z0-9._-]*`; length is checked separately.
+fn is_tag(tag: &str) -> bool {
+    let word = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    tag.starts_with(word) && tag.chars().all(|c| word(c) || c == '.' || c == '-')
+}
+
+/// Whether the first segment of a reference is a registry host rather than a namespace.
+///
+/// Like the docker CLI, this is the case if it contains a `.` (a domain) or a `:` (a port),
+/// or is `localhost`; namespaces can contain neither.
+fn is_host(segment: &str) -> bool {
+    segment.contains(['.', ':']) || segment == "localhost"
+}
+
+impl FromStr for Reference {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s).map_err(Error::parse)
     }
 }
 
 impl std::fmt::Display for Reference {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}/{}/{}", self.host, self.namespace, self.name)?;
+        write!(f, "{}/{}", self.host, self.repository())?;
         match &self.version {
             Version::Tag(tag) => write!(f, ":{tag}"),
             Version::Digest(digest) => write!(f, "@{digest}"),
@@ -693,12 +1651,175 @@ impl std::fmt::Display for Reference {
     }
 }
 
+impl Serialize for Reference {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_string().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Reference {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        FromStr::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
 impl From<&Reference> for Reference {
     fn from(reference: &Reference) -> Self {
         reference.clone()
     }
 }
 
+/// The configuration of a container image, as recorded in its config blob.
+///
+/// Only the fields that describe the image as a whole are parsed;
+/// see the OCI spec for details: https://github.com/opencontainers/image-spec/blob/main/config.md
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageConfig {
+    /// When the image was created, as an RFC 3339 timestamp.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+
+    /// The CPU architecture the image runs on (e.g. `amd64`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub architecture: Option<String>,
+
+    /// The operating system the image runs on (e.g. `linux`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os: Option<String>,
+
+    /// The variant of the CPU the image runs on (e.g. `v7` for `arm`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+
+    /// The version of the operating system the image runs on (e.g. `10.0.17763.1879` for `windows`).
+    #[serde(
+        default,
+        rename = "os.version",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub os_version: Option<String>,
+
+    /// The history of each layer, in order from the base image to the application.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<History>,
+
+    /// The digest of each layer's uncompressed content (its "diff ID"),
+    /// in order from the base image to the application.
+    ///
+    /// This is read from the `rootfs` section of the config, but not written back out:
+    /// the root filesystem of an image that's been extracted or converted is described separately.
+    #[serde(
+        default,
+        rename = "rootfs",
+        deserialize_with = "deserialize_diff_ids",
+        skip_serializing
+    )]
+    pub diff_ids: Vec<Digest>,
+}
+
+impl ImageConfig {
+    /// The platform the image runs on, if the config records its operating system and architecture.
+    pub fn platform(&self) -> Option<Platform> {
+        Some(
+            Platform::builder()
+                .os(self.os.clone()?)
+                .architecture(self.architecture.clone()?)
+                .maybe_variant(self.variant.clone())
+                .maybe_os_version(self.os_version.clone())
+                .build(),
+        )
+    }
+
+    /// The history entries that correspond to layers, in order from the base image to the application.
+    ///
+    /// Entries for instructions that made no filesystem changes are skipped,
+    /// so if the config records history for every layer these match the layers in the manifest by position.
+    pub fn layer_history(&self) -> Vec<&History> {
+        self.history
+            .iter()
+            .filter(|history| history.empty_layer != Some(true))
+            .collect()
+    }
+}
+
+/// Read the diff IDs from the `rootfs` section of an image config.
+fn deserialize_diff_ids<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Digest>, D::Error> {
+    #[derive(Deserialize)]
+    struct RootFs {
+        #[serde(default)]
+        diff_ids: Vec<Digest>,
+    }
+
+    RootFs::deserialize(deserializer).map(|rootfs| rootfs.diff_ids)
+}
+
+/// The history of a layer in an [`ImageConfig`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct History {
+    /// When the layer was created, as an RFC 3339 timestamp.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+
+    /// The author of the layer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+
+    /// The command that created the layer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<String>,
+
+    /// A comment set when the layer was created.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+
+    /// Whether this entry doesn't correspond to a layer because it made no filesystem changes
+    /// (for example an `ENV` instruction in a Dockerfile).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub empty_layer: Option<bool>,
+}
+
+/// How an image is identified, reported the same way regardless of the source it was read from,
+/// so that output for images from different sources can be compared and displayed together.
+#[derive(Debug, Clone, PartialEq, Eq, Builder, Serialize, Deserialize)]
+pub struct Identity {
+    /// The image as the user referred to it: the fully qualified reference for images in a registry,
+    /// the reference provided for images in the Docker daemon, or the path of a tarball.
+    #[builder(into)]
+    pub reference: String,
+
+    /// The digest of the image the reference resolved to; see [`Source::digest`].
+    #[builder(into)]
+    pub resolved_digest: Digest,
+
+    /// A short name for the image suitable for display: the repository (e.g. `library/ubuntu`)
+    /// for images in a registry or the Docker daemon, or the file name of a tarball.
+    #[builder(into)]
+    pub display_name: String,
+}
+
+/// A descriptor for the manifest that another manifest refers to through its `subject` field;
+/// for example, the image described by a signature or SBOM artifact.
+#[derive(Debug, Clone, PartialEq, Eq, Builder, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Subject {
+    /// The content-addressable digest of the manifest.
+    #[builder(into)]
+    pub digest: Digest,
+
+    /// The size of the manifest in bytes.
+    pub size: i64,
+
+    /// The media type of the manifest.
+    #[builder(into)]
+    pub media_type: String,
+}
+
 /// A descriptor for a specific layer within an OCI container image.
 /// This follows the OCI Image Spec's layer descriptor format.
 #[derive(Debug, Clone, PartialEq, Eq, Builder, Deserialize)]
@@ -713,20 +1834,89 @@ pub struct Layer {
 
     /// The media type of the layer
     pub media_type: LayerMediaType,
+
+    /// The digest of the layer's uncompressed content (its "diff ID"), if known.
+    ///
+    /// Registries identify layers by the digest of their content as distributed (usually compressed),
+    /// while image configs and container runtimes identify them by this digest.
+    /// It's read from the image config when the source provides one.
+    #[builder(into)]
+    #[serde(default)]
+    pub diff_id: Option<Digest>,
+
+    /// The size of the layer's uncompressed content in bytes, if its descriptor records it.
+    ///
+    /// Some layer formats (like eStargz) annotate their descriptor with this size;
+    /// for other layers the uncompressed size isn't known until the layer is read.
+    #[serde(
+        default,
+        rename = "annotations",
+        deserialize_with = "deserialize_uncompressed_size"
+    )]
+    pub uncompressed_size: Option<u64>,
 }
 
+/// The descriptor annotation recording the size of a layer's uncompressed content.
+pub const UNCOMPRESSED_SIZE_ANNOTATION: &str = "io.containers.estargz.uncompressed-size";
+
 impl Layer {
     /// Convenience reference to the digest for the layer.
     pub fn digest(&self) -> &Digest {
         &self.digest
     }
 
+    /// Read the size of a layer's uncompressed content from its descriptor annotations, if recorded.
+    pub fn uncompressed_size_annotation<'a>(
+        annotations: impl IntoIterator<Item = (&'a String, &'a String)>,
+    ) -> Option<u64> {
+        annotations
+            .into_iter()
+            .find(|(key, _)| key.as_str() == UNCOMPRESSED_SIZE_ANNOTATION)
+            .and_then(|(_, value)| value.parse().ok())
+    }
+
+    /// The number of bytes the layer is estimated to write when extracted.
+    ///
+    /// This is the uncompressed size if it's recorded, and otherwise the size of the layer as distributed;
+    /// for compressed layers without a recorded size this is an underestimate.
+    pub fn estimated_size(&self) -> u64 {
+        self.uncompressed_size
+            .unwrap_or_else(|| u64::try_from(self.size).unwrap_or_default())
+    }
+
+    /// Set the diff ID of each layer from the image config, which lists them in the same order as the manifest.
+    ///
+    /// If the config doesn't list exactly one diff ID per layer, the layers can't be matched to them
+    /// and are returned unchanged.
+    pub fn with_diff_ids(layers: Vec<Layer>, config: &ImageConfig) -> Vec<Layer> {
+        if layers.len() != config.diff_ids.len() {
+            return layers;
+        }
+
+        layers
+            .into_iter()
+            .zip(config.diff_ids.iter())
+            .map(|(layer, diff_id)| Layer {
+                diff_id: Some(diff_id.clone()),
+                ..layer
+            })
+            .collect()
+    }
+
     /// Convenience reference to the digest for the layer as a hex string.
     pub fn digest_hex(&self) -> String {
         self.digest.as_hex()
     }
 }
 
+fn deserialize_uncompressed_size<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u64>, D::Error> {
+    let annotations =
+        Option::<std::collections::HashMap<String, String>>::deserialize(deserializer)?;
+    Ok(annotations.and_then(|annotations| Layer::uncompressed_size_annotation(&annotations)))
+}
+
 impl From<&Layer> for Layer {
     fn from(layer: &Layer) -> Self {
         layer.clone()
@@ -739,18 +1929,6 @@ impl std::fmt::Display for Layer {
     }
 }
 
-impl From<Layer> for Strategy {
-    fn from(layer: Layer) -> Self {
-        Strategy::Separate(layer)
-    }
-}
-
-impl From<&Layer> for Strategy {
-    fn from(layer: &Layer) -> Self {
-        Strategy::Separate(layer.clone())
-    }
-}
-
 /// Media types for OCI container image layers.
 ///
 /// Each entry in this enum is a unique media type "base"; some of them then can have flags applied.
@@ -797,26 +1975,24 @@ impl LayerMediaType {
 
         // Some need to have parsed flags.
         let (base, flags) = s.split_once('+').unwrap_or((s, ""));
-        match base {
-            // An OCI container layer that has restrictions on distribution.
-            //
-            // Non-distributable layers typically contain licensed content, proprietary code,
-            // or other material that cannot be freely redistributed.
-            // Registry operators are not required to push or pull these layers.
-            // Instead, the layer data might need to be obtained through other means
-            // (e.g. direct download from a vendor).
-            //
-            // These are officially marked deprecated in the OCI spec, along with the directive
-            // that clients should download the layers as usual:
-            // https://github.com/opencontainers/image-spec/blob/main/layer.md#non-distributable-layers
-            //
-            // For this reason, they're part of the "compatibility matrix" for OCI layers,
-            // and are simply translated to the standard OCI layer media type.
-            "application/vnd.oci.image.layer.nondistributable.v1.tar" => {
-                let flags = LayerMediaTypeFlag::parse_set(flags).context("parse flags")?;
-                return Self::Oci(flags).pipe(Some).pipe(Ok);
-            }
-            _ => {}
+
+        // An OCI container layer that has restrictions on distribution.
+        //
+        // Non-distributable layers typically contain licensed content, proprietary code,
+        // or other material that cannot be freely redistributed.
+        // Registry operators are not required to push or pull these layers.
+        // Instead, the layer data might need to be obtained through other means
+        // (e.g. direct download from a vendor).
+        //
+        // These are officially marked deprecated in the OCI spec, along with the directive
+        // that clients should download the layers as usual:
+        // https://github.com/opencontainers/image-spec/blob/main/layer.md#non-distributable-layers
+        //
+        // For this reason, they're part of the "compatibility matrix" for OCI layers,
+        // and are simply translated to the standard OCI layer media type.
+        if base == "application/vnd.oci.image.layer.nondistributable.v1.tar" {
+            let flags = LayerMediaTypeFlag::parse_set(flags).context("parse flags")?;
+            return Self::Oci(flags).pipe(Some).pipe(Ok);
         }
 
         Ok(None)
@@ -829,10 +2005,9 @@ impl Default for LayerMediaType {
     }
 }
 
-impl FromStr for LayerMediaType {
-    type Err = eyre::Error;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
+impl LayerMediaType {
+    /// Parse the value, reporting errors with detailed context.
+    fn parse(s: &str) -> eyre::Result<Self> {
         if let Some(mt) = Self::compatibility_matrix(s)? {
             debug!("translating layer media type from '{s}' to '{mt}' with compatibility matrix");
             return Ok(mt);
@@ -855,7 +2030,16 @@ impl FromStr for LayerMediaType {
                 return Ok(mt);
             }
         }
-        bail!("unknown media type: {s}");
+        Err(Report::new(Kind::Unsupported)).context(format!("unknown media type: {s}"))
+    }
+}
+
+impl FromStr for LayerMediaType {
+    type Err = Error;
+
+    /// Unknown media types are reported as [`Error::Unsupported`].
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s).map_err(Error::from)
     }
 }
 
@@ -926,13 +2110,21 @@ impl LayerMediaTypeFlag {
     }
 }
 
+impl LayerMediaTypeFlag {
+    /// Parse the value, reporting errors with detailed context.
+    fn parse(s: &str) -> eyre::Result<Self> {
+        Self::iter()
+            .find(|flag| flag.as_ref() == s)
+            .ok_or_else(|| Report::new(Kind::Unsupported).wrap_err(format!("unknown flag: '{s}'")))
+    }
+}
+
 impl FromStr for LayerMediaTypeFlag {
-    type Err = eyre::Error;
+    type Err = Error;
 
+    /// Unknown flags are reported as [`Error::Unsupported`].
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Self::iter()
-            .find(|flag| flag.as_ref() == s)
-            .ok_or_else(|| eyre!("unknown flag: '{s}'"))
+        Self::parse(s).map_err(Error::from)
     }
 }
 
@@ -951,8 +2143,48 @@ pub trait FilterMatch<T> {
 
 /// A set of filters; if any filter in the set matches, the value is considered matched.
 /// As a special case, if no filters are provided, the value is also considered matched.
-#[derive(Debug, Clone, From, Default)]
-pub struct Filters(Vec<Filter>);
+///
+/// Filters can also exclude values (see [`Filters::exclude`]): a value matched by any exclusion
+/// is not matched, regardless of the other filters. In other words, exclusions take precedence.
+///
+/// File filters may also carry [`Predicate`]s on the type, size, and content of entries;
+/// unlike the path filters, an entry must satisfy every predicate to be admitted.
+///
+/// Layer filters may also select layers by their position in the image with [`LayerRange`]s;
+/// these are checked along with the other filters by [`Filters::matches_at`].
+///
+/// Sets of filters can be combined with [`Filters::all_of`], [`Filters::any_of`], and negated with `!`;
+/// adding a combination to a set means values must also satisfy the combination.
+#[derive(Debug, Clone, Default)]
+pub struct Filters {
+    filters: Vec<Filter>,
+    excludes: Vec<Filter>,
+    ranges: Vec<LayerRange>,
+    predicates: Vec<Predicate>,
+    compositions: Vec<Composition>,
+}
+
+/// A combination of sets of filters, created by [`Filters::all_of`], [`Filters::any_of`], or negating a set.
+#[derive(Debug, Clone)]
+enum Composition {
+    All(Vec<Filters>),
+    Any(Vec<Filters>),
+    Not(Box<Filters>),
+}
+
+impl Composition {
+    /// Report whether the value, at the position if known, satisfies the combination.
+    fn matches<'a, T>(&self, value: &'a T, position: Option<(usize, usize)>) -> bool
+    where
+        Filter: FilterMatch<&'a T>,
+    {
+        match self {
+            Composition::All(sets) => sets.iter().all(|set| set.matches_position(value, position)),
+            Composition::Any(sets) => sets.iter().any(|set| set.matches_position(value, position)),
+            Composition::Not(set) => !set.matches_position(value, position),
+        }
+    }
+}
 
 impl Filters {
     /// Create glob filters from the given strings.
@@ -961,7 +2193,7 @@ impl Filters {
             .into_iter()
             .map(|s| Filter::parse_glob(s.as_ref()))
             .collect::<Result<Vec<_>>>()
-            .map(Self)
+            .map(Self::from)
     }
 
     /// Create regex filters from the given strings.
@@ -970,7 +2202,161 @@ impl Filters {
             .into_iter()
             .map(|s| Filter::parse_regex(s.as_ref()))
             .collect::<Result<Vec<_>>>()
-            .map(Self)
+            .map(Self::from)
+    }
+
+    /// Create a set of filters with only the given predicates.
+    pub fn predicates(predicates: impl IntoIterator<Item = Predicate>) -> Self {
+        Self {
+            predicates: predicates.into_iter().collect(),
+            ..Self::default()
+        }
+    }
+
+    /// Create a set of filters with only the given layer ranges.
+    pub fn ranges(ranges: impl IntoIterator<Item = LayerRange>) -> Self {
+        Self {
+            ranges: ranges.into_iter().collect(),
+            ..Self::default()
+        }
+    }
+
+    /// Match values matched by every one of the sets, for example
+    /// `Filters::all_of([Filters::parse_glob(["**/*.so"])?, !Filters::ranges([LayerRange::index(0)])])`
+    /// matches `.so` files in every layer but the base layer.
+    ///
+    /// Predicates aren't combined: those in the sets are moved to the new set, and entries must satisfy all of them.
+    pub fn all_of(sets: impl IntoIterator<Item = Filters>) -> Self {
+        Self::compose(sets, Composition::All)
+    }
+
+    /// Match values matched by any of the sets; if there are no sets, no value is matched.
+    ///
+    /// Predicates aren't combined: those in the sets are moved to the new set, and entries must satisfy all of them.
+    pub fn any_of(sets: impl IntoIterator<Item = Filters>) -> Self {
+        Self::compose(sets, Composition::Any)
+    }
+
+    /// Combine the sets, moving their predicates to the new set.
+    fn compose(
+        sets: impl IntoIterator<Item = Filters>,
+        composition: impl FnOnce(Vec<Filters>) -> Composition,
+    ) -> Self {
+        let mut predicates = Vec::new();
+        let sets = sets
+            .into_iter()
+            .map(|mut set| {
+                predicates.append(&mut set.predicates);
+                set
+            })
+            .collect();
+        Self {
+            predicates,
+            compositions: vec![composition(sets)],
+            ..Self::default()
+        }
+    }
+
+    /// Turn the filters into exclusions: values matched by any of them are no longer matched.
+    ///
+    /// Exclusions, layer ranges, and predicates in the set are kept as they are, so for example
+    /// `Filters::parse_glob(["**/*.jar"])? + Filters::parse_glob(["test/**"])?.exclude()`
+    /// matches every `.jar` file outside of `test`. Combinations in the set are negated.
+    pub fn exclude(self) -> Self {
+        let compositions = match self.compositions.is_empty() {
+            true => Vec::new(),
+            false => vec![Composition::Not(Box::new(Self {
+                compositions: self.compositions,
+                ..Self::default()
+            }))],
+        };
+        Self {
+            filters: Vec::new(),
+            excludes: self.excludes.into_iter().chain(self.filters).collect(),
+            ranges: self.ranges,
+            predicates: self.predicates,
+            compositions,
+        }
+    }
+
+    /// Report whether the value at `index` out of `count` values (such as a layer in an image) is matched.
+    ///
+    /// Layer ranges select values alongside the other filters: the value is matched if it's selected
+    /// by any filter or range (or there are none), and isn't excluded.
+    /// [`FilterMatch::matches`] doesn't know the position of the value, so it ignores layer ranges.
+    pub fn matches_at<'a, T>(&self, value: &'a T, index: usize, count: usize) -> bool
+    where
+        Filter: FilterMatch<&'a T>,
+    {
+        self.matches_position(value, Some((index, count)))
+    }
+
+    /// Report whether the value is matched, checking layer ranges only if its position is known.
+    fn matches_position<'a, T>(&self, value: &'a T, position: Option<(usize, usize)>) -> bool
+    where
+        Filter: FilterMatch<&'a T>,
+    {
+        let ranges = match position {
+            Some(_) => self.ranges.as_slice(),
+            None => &[],
+        };
+        let unfiltered = self.filters.is_empty() && ranges.is_empty();
+        let included = unfiltered
+            || self.filters.iter().any(|filter| filter.matches(value))
+            || position.is_some_and(|(index, count)| {
+                ranges.iter().any(|range| range.contains(index, count))
+            });
+        included
+            && !self.excludes.iter().any(|filter| filter.matches(value))
+            && self
+                .compositions
+                .iter()
+                .all(|composition| composition.matches(value, position))
+    }
+
+    /// Keep the values matched by the filters, checking layer ranges against their position in `values`.
+    pub fn select<T>(&self, values: Vec<T>) -> Vec<T>
+    where
+        T: std::fmt::Display,
+        for<'a> Filter: FilterMatch<&'a T>,
+    {
+        let count = values.len();
+        values
+            .into_iter()
+            .enumerate()
+            .filter(|(index, value)| {
+                self.matches_at(value, *index, count)
+                    .tap(|selected| debug!(%value, %index, %selected, "filters: select value"))
+            })
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    /// Report whether an entry satisfies every predicate in the set.
+    ///
+    /// `head` is the start of the entry's content, up to [`BINARY_SNIFF_LEN`] bytes;
+    /// it only needs to be read if [`Filters::inspects_content`] reports so.
+    pub fn admits(&self, file_type: Option<FileType>, size: u64, head: &[u8]) -> bool {
+        self.predicates
+            .iter()
+            .all(|predicate| predicate.holds(file_type, size, head))
+            .tap(|admitted| debug!(?file_type, %size, %admitted, "predicates: check entry"))
+    }
+
+    /// Report whether any predicate in the set inspects the content of entries.
+    pub fn inspects_content(&self) -> bool {
+        self.predicates
+            .iter()
+            .any(|predicate| matches!(predicate, Predicate::ExcludeBinary))
+    }
+}
+
+impl From<Vec<Filter>> for Filters {
+    fn from(filters: Vec<Filter>) -> Self {
+        Self {
+            filters,
+            ..Self::default()
+        }
     }
 }
 
@@ -978,7 +2364,16 @@ impl Add<Filter> for Filters {
     type Output = Self;
 
     fn add(mut self, filter: Filter) -> Self {
-        self.0.push(filter);
+        self.filters.push(filter);
+        self
+    }
+}
+
+impl Add<Predicate> for Filters {
+    type Output = Self;
+
+    fn add(mut self, predicate: Predicate) -> Self {
+        self.predicates.push(predicate);
         self
     }
 }
@@ -987,17 +2382,205 @@ impl Add<Filters> for Filters {
     type Output = Filters;
 
     fn add(mut self, filters: Filters) -> Filters {
-        self.0.extend(filters.0);
+        self.filters.extend(filters.filters);
+        self.excludes.extend(filters.excludes);
+        self.ranges.extend(filters.ranges);
+        self.predicates.extend(filters.predicates);
+        self.compositions.extend(filters.compositions);
         self
     }
 }
 
+impl Not for Filters {
+    type Output = Filters;
+
+    /// Match the values that the filters don't match; predicates are kept as they are.
+    fn not(mut self) -> Filters {
+        let predicates = std::mem::take(&mut self.predicates);
+        Filters {
+            predicates,
+            compositions: vec![Composition::Not(Box::new(self))],
+            ..Filters::default()
+        }
+    }
+}
+
 impl<'a, T> FilterMatch<&'a T> for Filters
 where
     Filter: FilterMatch<&'a T>,
 {
     fn matches(&self, value: &'a T) -> bool {
-        !self.0.is_empty() && self.0.iter().any(|filter| filter.matches(value))
+        self.matches_position(value, None)
+    }
+}
+
+/// A range of layers, selected by their position in the image.
+///
+/// Positions count from zero at the base layer; negative positions count back from the last layer,
+/// so `-1` is the last layer. Ranges are written like Rust ranges, for example `2..=5`, `1..`, or `-2..`;
+/// a single position like `3` selects only that layer.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LayerRange {
+    start: Bound<i64>,
+    end: Bound<i64>,
+}
+
+impl LayerRange {
+    /// Select the single layer at the position.
+    pub fn index(index: i64) -> Self {
+        Self {
+            start: Bound::Included(index),
+            end: Bound::Included(index),
+        }
+    }
+
+    /// Report whether the layer at `index` out of `count` layers is in the range.
+    pub fn contains(&self, index: usize, count: usize) -> bool {
+        let count = i64::try_from(count).unwrap_or(i64::MAX);
+        let index = i64::try_from(index).unwrap_or(i64::MAX);
+        let resolve = |position: i64| match position {
+            ..0 => count + position,
+            _ => position,
+        };
+
+        let after_start = match self.start {
+            Bound::Included(start) => index >= resolve(start),
+            Bound::Excluded(start) => index > resolve(start),
+            Bound::Unbounded => true,
+        };
+        let before_end = match self.end {
+            Bound::Included(end) => index <= resolve(end),
+            Bound::Excluded(end) => index < resolve(end),
+            Bound::Unbounded => true,
+        };
+        after_start && before_end
+    }
+
+    /// Parse the value, reporting errors with detailed context.
+    fn parse(s: &str) -> eyre::Result<Self> {
+        let position = |position: &str| {
+            position.trim().parse::<i64>().with_context(|| {
+                format!("invalid layer range: '{s}' (expected a position like `3` or `-1`, or a range like `2..=5` or `-2..`)")
+            })
+        };
+        let bound = |s: &str, bound: fn(i64) -> Bound<i64>| match s.trim() {
+            "" => Ok(Bound::Unbounded),
+            s => position(s).map(bound),
+        };
+
+        let Some((start, end)) = s.split_once("..") else {
+            return position(s).map(Self::index);
+        };
+        let end = match end.strip_prefix('=') {
+            Some("") => bail!("inclusive layer range must have an end: '{s}'"),
+            Some(end) => bound(end, Bound::Included)?,
+            None => bound(end, Bound::Excluded)?,
+        };
+        let start = bound(start, Bound::Included)?;
+        Ok(Self { start, end })
+    }
+}
+
+impl FromStr for LayerRange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s).map_err(Error::parse)
+    }
+}
+
+impl std::fmt::Display for LayerRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.start, self.end) {
+            (Bound::Included(start), Bound::Included(end)) if start == end => write!(f, "{start}"),
+            (start, end) => {
+                if let Bound::Included(start) | Bound::Excluded(start) = start {
+                    write!(f, "{start}")?;
+                }
+                match end {
+                    Bound::Included(end) => write!(f, "..={end}"),
+                    Bound::Excluded(end) => write!(f, "..{end}"),
+                    Bound::Unbounded => write!(f, ".."),
+                }
+            }
+        }
+    }
+}
+
+/// The number of bytes at the start of a file inspected to tell whether its content is binary.
+pub const BINARY_SNIFF_LEN: usize = 8000;
+
+/// Report whether content looks binary: like git, content containing a NUL byte is binary.
+pub fn is_binary(head: &[u8]) -> bool {
+    head.contains(&0)
+}
+
+/// A condition on the type, size, or content of an entry, beyond its path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Predicate {
+    /// Only regular files of at most this many bytes are admitted.
+    MaxSize(u64),
+
+    /// Only entries of these types are admitted.
+    Types(Vec<FileType>),
+
+    /// Only regular files whose content doesn't look binary are admitted.
+    ExcludeBinary,
+}
+
+impl Predicate {
+    /// Report whether the entry satisfies the predicate.
+    /// Entries that aren't regular, symlinks, or directories have no file type.
+    pub fn holds(&self, file_type: Option<FileType>, size: u64, head: &[u8]) -> bool {
+        let regular = file_type == Some(FileType::Regular);
+        match self {
+            Predicate::MaxSize(max) => !regular || size <= *max,
+            Predicate::Types(types) => file_type.is_some_and(|kind| types.contains(&kind)),
+            Predicate::ExcludeBinary => !regular || !is_binary(head),
+        }
+    }
+}
+
+/// The type of an entry, as matched by [`Predicate::Types`].
+/// Hard links are considered regular files.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, AsRefStr, EnumIter)]
+pub enum FileType {
+    /// A regular file.
+    #[strum(serialize = "regular")]
+    Regular,
+
+    /// A symbolic link.
+    #[strum(serialize = "symlink")]
+    Symlink,
+
+    /// A directory.
+    #[strum(serialize = "dir")]
+    Dir,
+}
+
+impl FileType {
+    /// Parse the value, reporting errors with detailed context.
+    fn parse(s: &str) -> eyre::Result<Self> {
+        Self::iter().find(|kind| kind.as_ref() == s).ok_or_else(|| {
+            let expected = Self::iter()
+                .map(|kind| kind.as_ref().to_string())
+                .join(", ");
+            eyre!("unknown file type: '{s}' (expected one of: {expected})")
+        })
+    }
+}
+
+impl FromStr for FileType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s).map_err(Error::parse)
+    }
+}
+
+impl std::fmt::Display for FileType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_ref())
     }
 }
 
@@ -1062,16 +2645,23 @@ impl FilterMatch<&str> for Regex {
     }
 }
 
-impl FromStr for Regex {
-    type Err = eyre::Error;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
+impl Regex {
+    /// Parse the value, reporting errors with detailed context.
+    fn parse(s: &str) -> eyre::Result<Self> {
         regex::Regex::new(s)
             .map_err(|e| eyre!("invalid regex: {e}"))
             .map(Self)
     }
 }
 
+impl FromStr for Regex {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s).map_err(Error::parse)
+    }
+}
+
 /// A glob filter.
 #[derive(Debug, Clone)]
 pub struct Glob(String);
@@ -1083,19 +2673,235 @@ impl FilterMatch<&str> for Glob {
     }
 }
 
+impl Glob {
+    /// Parse the value, reporting errors with detailed context.
+    fn parse(s: &str) -> eyre::Result<Self> {
+        s.to_string().pipe(Self).pipe(Ok)
+    }
+}
+
 impl FromStr for Glob {
-    type Err = eyre::Error;
+    type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        s.to_string().pipe(Self).pipe(Ok)
+        Self::parse(s).map_err(Error::parse)
+    }
+}
+
+/// How to handle entries whose paths collide when extracted to a case-insensitive filesystem.
+///
+/// Linux filesystems are case-sensitive, so images can contain paths that differ only by case
+/// (for example `README` and `readme` in the same directory); on filesystems that aren't case-sensitive
+/// (the defaults on macOS and Windows) the later entry would otherwise silently overwrite the earlier one.
+/// Only ASCII case differences are detected, and directories are always merged rather than renamed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CaseCollisions {
+    /// Write the colliding entry under a new name, formed by appending `~N` to its file name
+    /// with the smallest `N` that doesn't collide with an existing entry.
+    ///
+    /// Since layers are applied in order this is deterministic for a given image.
+    #[default]
+    Rename,
+
+    /// Fail extraction.
+    Error,
+}
+
+/// When the Docker daemon pulls an image from its registry before circe reads it.
+///
+/// By default the daemon is only used for images it already has;
+/// [`source::detect`] reads other images from their registry directly, without involving the daemon.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, AsRefStr, EnumIter)]
+pub enum PullPolicy {
+    /// Never pull the image; reading it from the daemon fails if the daemon doesn't have it.
+    #[default]
+    #[strum(serialize = "never")]
+    Never,
+
+    /// Pull the image if the daemon doesn't have it.
+    #[strum(serialize = "missing")]
+    Missing,
+
+    /// Always pull the image, so that the daemon has the latest content for its tag.
+    #[strum(serialize = "always")]
+    Always,
+}
+
+impl PullPolicy {
+    /// Parse the value, reporting errors with detailed context.
+    fn parse(s: &str) -> eyre::Result<Self> {
+        Self::iter()
+            .find(|policy| policy.as_ref() == s)
+            .ok_or_else(|| {
+                let expected = Self::iter()
+                    .map(|policy| policy.as_ref().to_string())
+                    .join(", ");
+                eyre!("unknown pull policy: '{s}' (expected one of: {expected})")
+            })
+    }
+}
+
+impl FromStr for PullPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s).map_err(Error::parse)
+    }
+}
+
+impl std::fmt::Display for PullPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_ref())
+    }
+}
+
+/// The kind of [`Source`] from which an image is read; see [`source::detect`].
+///
+/// By default the source is selected automatically, falling through to the next source when one can't read the image;
+/// selecting a specific source reports its error instead of masking it by trying the others.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, AsRefStr, EnumIter)]
+pub enum SourceKind {
+    /// Read a tarball if the target is a path that exists, or the Docker daemon if it has the image,
+    /// or otherwise the registry.
+    #[default]
+    #[strum(serialize = "auto")]
+    Auto,
+
+    /// Pull the image from its registry.
+    #[strum(serialize = "registry")]
+    Registry,
+
+    /// Read the image from the Docker daemon.
+    #[strum(serialize = "daemon")]
+    Daemon,
+
+    /// Read the image from a tarball, like one written by `docker save`.
+    #[strum(serialize = "tarball")]
+    Tarball,
+
+    /// Read the image from an OCI image layout, which must contain an `oci-layout` file.
+    /// The layout may be a tarball, or a directory like one written by `skopeo copy docker://<image> oci:<dir>`.
+    #[strum(serialize = "oci-layout")]
+    OciLayout,
+}
+
+impl SourceKind {
+    /// Parse the value, reporting errors with detailed context.
+    fn parse(s: &str) -> eyre::Result<Self> {
+        Self::iter().find(|kind| kind.as_ref() == s).ok_or_else(|| {
+            let expected = Self::iter()
+                .map(|kind| kind.as_ref().to_string())
+                .join(", ");
+            eyre!("unknown source: '{s}' (expected one of: {expected})")
+        })
+    }
+}
+
+impl FromStr for SourceKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s).map_err(Error::parse)
+    }
+}
+
+impl std::fmt::Display for SourceKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_ref())
+    }
+}
+
+/// Safety limits enforced as layers are applied, since images are often untrusted.
+///
+/// A malicious layer can decompress to far more than its distributed size, or contain an enormous number of paths;
+/// exceeding a limit fails with [`Error::Limit`] instead of filling the disk. No limits are set by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Builder)]
+pub struct Limits {
+    /// The maximum number of bytes a layer can decompress to.
+    pub max_layer_size: Option<u64>,
+
+    /// The maximum number of entries (files, directories, links, and whiteouts) in a layer.
+    pub max_files: Option<u64>,
+
+    /// The maximum size in bytes of a single file in a layer.
+    pub max_file_size: Option<u64>,
+}
+
+/// Commands through which decompressed layers are piped before they are read.
+///
+/// This is an escape hatch for layer encodings that aren't natively supported,
+/// such as a custom encryption or sanitization step: each layer is decompressed according to its media type
+/// as usual and written to the standard input of the command, and the standard output of the command
+/// is read as the plain layer tarball instead.
+///
+/// Commands that apply to the media type of a layer take precedence over commands that apply to all layers.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FilterCommands(Vec<FilterCommand>);
+
+impl FilterCommands {
+    /// Parse commands from the given strings; see [`FilterCommand`] for the format.
+    pub fn parse(commands: impl IntoIterator<Item = impl AsRef<str>>) -> Result<Self> {
+        commands
+            .into_iter()
+            .map(|s| FilterCommand::from_str(s.as_ref()))
+            .collect::<Result<Vec<_>>>()
+            .map(Self)
+    }
+
+    /// The command through which the layer is piped, if any.
+    pub fn command(&self, layer: &Layer) -> Option<&FilterCommand> {
+        let mut commands = self.0.iter();
+        commands
+            .clone()
+            .find(|command| command.media_type.as_ref() == Some(&layer.media_type))
+            .or_else(|| commands.find(|command| command.media_type.is_none()))
     }
 }
 
-/// Get the current home directory for the current user.
+/// A command through which decompressed layers are piped; see [`FilterCommands`].
 ///
-/// This is a convenience function for `std::env::var("HOME")` or `std::env::var("USERPROFILE")`.
-fn homedir() -> Result<PathBuf, std::env::VarError> {
-    std::env::var("HOME")
-        .or_else(|_| std::env::var("USERPROFILE"))
-        .map(PathBuf::from)
+/// Parsed from strings in the form `[MEDIA_TYPE=]COMMAND`, for example
+/// `application/vnd.oci.image.layer.v1.tar+gzip=decrypt --key key.pem`.
+/// The command is run with `sh -c`, so it can include arguments and pipelines.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterCommand {
+    /// The media type of the layers to which the command applies.
+    /// If not set, the command applies to all layers.
+    pub media_type: Option<LayerMediaType>,
+
+    /// The command to run.
+    pub command: String,
+}
+
+impl FilterCommand {
+    /// Parse the value, reporting errors with detailed context.
+    fn parse(s: &str) -> eyre::Result<Self> {
+        // Commands can contain `=` too (e.g. `FOO=bar decrypt`),
+        // so only treat the prefix as a media type if it looks like one.
+        let (media_type, command) = match s.split_once('=') {
+            Some((media_type, command))
+                if media_type.contains('/') && !media_type.contains(char::is_whitespace) =>
+            {
+                let media_type = LayerMediaType::from_str(media_type)
+                    .with_context(|| format!("parse media type '{media_type}'"))?;
+                (Some(media_type), command)
+            }
+            _ => (None, s),
+        };
+
+        let command = command.trim();
+        ensure!(!command.is_empty(), "command is empty");
+        Ok(Self {
+            media_type,
+            command: command.to_string(),
+        })
+    }
+}
+
+impl FromStr for FilterCommand {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s).map_err(Error::parse)
+    }
 }
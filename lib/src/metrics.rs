@@ -0,0 +1,108 @@
+//! Counters for the work done by the library, so that embedders can report telemetry themselves
+//! without depending on the CLI or subscribing to log events.
+//!
+//! Counters are process-wide and only ever increase, so operations that run concurrently are counted together.
+//! To measure a single operation, take a [`Snapshot`] before and after it and compare them with [`Snapshot::since`]:
+//! ```
+//! use circe_lib::metrics::Snapshot;
+//!
+//! let before = Snapshot::take();
+//! // Run the operation, for example `circe_lib::extract::extract`.
+//! let metrics = Snapshot::take().since(before);
+//! println!("downloaded {} bytes", metrics.bytes_downloaded);
+//! ```
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use futures_lite::{Stream, StreamExt};
+use serde::Serialize;
+
+use crate::transform::Chunk;
+
+static BYTES_DOWNLOADED: AtomicU64 = AtomicU64::new(0);
+static CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+static LAYERS_APPLIED: AtomicU64 = AtomicU64::new(0);
+static WARNINGS: AtomicU64 = AtomicU64::new(0);
+
+/// The values of the counters at a point in time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Snapshot {
+    /// Bytes of blobs (layers and image configs) downloaded from registries, before decompression.
+    pub bytes_downloaded: u64,
+
+    /// Layers reused from a layer store instead of being applied again; see [`crate::extract::Strategy::Stored`].
+    pub cache_hits: u64,
+
+    /// Layers applied to a directory by any source.
+    pub layers_applied: u64,
+
+    /// Warnings logged by the library, such as entries skipped while applying layers.
+    pub warnings: u64,
+}
+
+impl Snapshot {
+    /// Read the current values of the counters.
+    pub fn take() -> Self {
+        Self {
+            bytes_downloaded: BYTES_DOWNLOADED.load(Ordering::Relaxed),
+            cache_hits: CACHE_HITS.load(Ordering::Relaxed),
+            layers_applied: LAYERS_APPLIED.load(Ordering::Relaxed),
+            warnings: WARNINGS.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The amount by which each counter increased since the earlier snapshot.
+    pub fn since(self, earlier: Snapshot) -> Self {
+        Self {
+            bytes_downloaded: self
+                .bytes_downloaded
+                .saturating_sub(earlier.bytes_downloaded),
+            cache_hits: self.cache_hits.saturating_sub(earlier.cache_hits),
+            layers_applied: self.layers_applied.saturating_sub(earlier.layers_applied),
+            warnings: self.warnings.saturating_sub(earlier.warnings),
+        }
+    }
+}
+
+/// Count bytes downloaded from a registry.
+#[cfg_attr(feature = "native", allow(dead_code))]
+pub(crate) fn record_downloaded(bytes: u64) {
+    BYTES_DOWNLOADED.fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Count the bytes downloaded from a registry as they are read from the stream.
+pub(crate) fn count_downloaded(stream: impl Stream<Item = Chunk>) -> impl Stream<Item = Chunk> {
+    stream.inspect(|chunk| {
+        if let Ok(chunk) = chunk {
+            BYTES_DOWNLOADED.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        }
+    })
+}
+
+/// Count a layer reused from a layer store.
+#[cfg_attr(not(feature = "native"), allow(dead_code))]
+pub(crate) fn record_cache_hit() {
+    CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a layer applied to a directory.
+#[cfg_attr(not(feature = "native"), allow(dead_code))]
+pub(crate) fn record_layer_applied() {
+    LAYERS_APPLIED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a warning; use [`warn!`] instead of calling this directly.
+pub(crate) fn record_warning() {
+    WARNINGS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Log a warning with [`tracing::warn!`], counting it in [`Snapshot::warnings`].
+macro_rules! counted_warn {
+    ($($arg:tt)*) => {{
+        $crate::metrics::record_warning();
+        ::tracing::warn!($($arg)*)
+    }};
+}
+
+// Re-exported under the same name as `tracing::warn!` so that modules can import either one.
+pub(crate) use counted_warn as warn;
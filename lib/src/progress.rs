@@ -0,0 +1,96 @@
+//! Reports progress during extraction.
+//!
+//! Sources ([`crate::registry::Registry`], [`crate::docker::Daemon`], and [`crate::docker::Tarball`])
+//! report the bytes they read and decompress and the files they write for each layer,
+//! while [`crate::extract::extract`] reports when each layer starts and completes.
+//! Provide the same [`Progress`] implementation to both to observe the whole extraction.
+
+use std::{path::Path, sync::Arc};
+
+use futures_lite::{Stream, StreamExt};
+
+use crate::{transform::Chunk, Layer};
+
+/// Receives progress updates during extraction.
+///
+/// All methods default to doing nothing, so implementations only need to handle the events they care about.
+/// Methods are called inline with extraction, so implementations should return quickly.
+pub trait Progress: Send + Sync {
+    /// Called when a layer begins to be applied.
+    fn layer_started(&self, _layer: &Layer) {}
+
+    /// Called as bytes of the layer are read from the source.
+    ///
+    /// For remote registries this is the number of (possibly compressed) bytes downloaded,
+    /// so in total it should match the size of the layer.
+    fn bytes_read(&self, _layer: &Layer, _bytes: u64) {}
+
+    /// Called as bytes of the layer are decompressed into a plain tarball.
+    ///
+    /// Compared with [`Progress::bytes_read`], this shows how well the layer compresses;
+    /// for uncompressed layers the totals are the same.
+    fn bytes_decompressed(&self, _layer: &Layer, _bytes: u64) {}
+
+    /// Called after a file from the layer is written to disk.
+    fn file_written(&self, _layer: &Layer, _path: &Path) {}
+
+    /// Called when a file from the layer is written under a different name
+    /// because its path collides with an existing entry on a case-insensitive filesystem.
+    ///
+    /// See [`crate::CaseCollisions::Rename`] for details;
+    /// [`Progress::file_written`] is also called with the renamed path.
+    fn file_renamed(&self, _layer: &Layer, _path: &Path, _renamed: &Path) {}
+
+    /// Called when a whiteout from the layer deletes a path written by a prior layer.
+    ///
+    /// `existed` reports whether anything was at the path to delete;
+    /// it may not be if the path was excluded by a filter when prior layers were applied.
+    fn whiteout_applied(&self, _layer: &Layer, _path: &Path, _existed: bool) {}
+
+    /// Called when a path deleted by a whiteout from the layer is moved to `recovered` instead of being removed,
+    /// because the source was configured with a directory for deleted paths.
+    ///
+    /// [`Progress::whiteout_applied`] is called for the path first.
+    fn file_recovered(&self, _layer: &Layer, _path: &Path, _recovered: &Path) {}
+
+    /// Called when a layer has been applied.
+    fn layer_completed(&self, _layer: &Layer) {}
+}
+
+/// A [`Progress`] implementation that ignores all updates.
+///
+/// This is the default for sources that aren't given a [`Progress`] implementation.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Silent;
+
+impl Progress for Silent {}
+
+/// Convenience alias for a [`Progress`] implementation that can be shared between sources and extraction.
+pub type SharedProgress = Arc<dyn Progress>;
+
+/// Report the bytes read from the stream as they are read.
+pub(crate) fn report_bytes_read(
+    progress: SharedProgress,
+    layer: Layer,
+    stream: impl Stream<Item = Chunk>,
+) -> impl Stream<Item = Chunk> {
+    stream.inspect(move |chunk| {
+        if let Ok(chunk) = chunk {
+            progress.bytes_read(&layer, chunk.len() as u64);
+        }
+    })
+}
+
+/// Report the bytes decompressed from the layer as they are read.
+#[cfg(feature = "native")]
+pub(crate) fn report_bytes_decompressed(
+    progress: SharedProgress,
+    layer: Layer,
+    stream: std::pin::Pin<Box<dyn Stream<Item = Chunk>>>,
+) -> std::pin::Pin<Box<dyn Stream<Item = Chunk>>> {
+    Box::pin(stream.inspect(move |chunk| {
+        if let Ok(chunk) = chunk {
+            progress.bytes_decompressed(&layer, chunk.len() as u64);
+        }
+    }))
+}
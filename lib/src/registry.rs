@@ -1,31 +1,102 @@
 //! Interacts with remote OCI registries.
 
 use std::{
-    path::{Path, PathBuf},
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    num::NonZeroU64,
+    path::PathBuf,
     pin::Pin,
     str::FromStr,
+    sync::Arc,
+    time::Duration,
 };
 
-use async_tempfile::TempFile;
+use bon::Builder;
 use bytes::Bytes;
-use color_eyre::eyre::{Context, Result};
+use color_eyre::eyre::{self, Context};
+use color_eyre::Section;
 use derive_more::Debug;
 use futures_lite::{Stream, StreamExt};
 use oci_client::{
-    client::ClientConfig,
-    manifest::{ImageIndexEntry, OciDescriptor},
+    client::{ClientConfig, ClientProtocol},
+    errors::{OciDistributionError, OciErrorCode},
+    manifest::{ImageIndexEntry, OciDescriptor, OciImageManifest},
     secrets::RegistryAuth,
     Client, Reference as OciReference, RegistryOperation,
 };
+use tap::Pipe;
 use tracing::debug;
 
 use crate::{
-    cio::{apply_tarball, collect_tmp, enumerate_tarball, peel_layer},
+    error::Kind,
     ext::PriorityFind,
-    transform::Chunk,
-    Authentication, Digest, Filter, FilterMatch, Filters, Layer, LayerMediaType, Platform,
-    Reference, Source, Version,
+    metrics::{self, warn},
+    progress::{report_bytes_read, SharedProgress, Silent},
+    transform::{limit_rate, Buffering, Chunk},
+    Annotation, Authentication, CaseCollisions, Digest, Error, Filter, FilterCommands, FilterMatch,
+    Filters, Identity, ImageConfig, Layer, LayerMediaType, Limits, Platform, Reference, Result,
+    Source, Subject, Version,
+};
+
+#[cfg(feature = "native")]
+use crate::{
+    cio::{
+        apply_tarball, collect_tmp, enumerate_tarball, filter_layer, read_ahead, visit_tarball,
+        ApplyOptions,
+    },
+    progress::report_bytes_decompressed,
+    transform::peel_layer,
+    Unpack,
 };
+#[cfg(feature = "native")]
+use async_tempfile::TempFile;
+#[cfg(feature = "native")]
+use oci_client::manifest::{IMAGE_MANIFEST_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE};
+#[cfg(feature = "native")]
+use std::path::Path;
+
+#[cfg(feature = "native")]
+mod blob;
+#[cfg(feature = "native")]
+mod catalog;
+
+#[cfg(feature = "native")]
+pub use catalog::{Catalog, DEFAULT_PAGE_SIZE};
+
+/// Deadlines for requests to the registry, so that hung connections fail instead of blocking indefinitely.
+///
+/// Each timeout is disabled unless it's set.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Builder)]
+pub struct Timeouts {
+    /// The time allowed to connect to the registry, or to the storage that it redirects blob downloads to.
+    pub connect: Option<Duration>,
+
+    /// The time allowed for each request for a manifest or its digest to complete, including authentication.
+    pub manifest: Option<Duration>,
+
+    /// The time allowed to pass without receiving any content while downloading a blob.
+    ///
+    /// Layers can legitimately take a long time to download,
+    /// so this detects downloads that have stalled rather than bounding the whole download.
+    pub stall: Option<Duration>,
+}
+
+/// Whether an image exists in a registry, as reported by [`Registry::exists`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Existence {
+    /// The image exists, and its manifest has this digest.
+    Exists(Digest),
+
+    /// The registry reported that the image, or its repository, doesn't exist.
+    NotFound,
+
+    /// The registry requires credentials to read the image, and either none were provided
+    /// or the provided credentials weren't accepted.
+    AuthenticationRequired,
+
+    /// The registry accepted the credentials, but they aren't allowed to read the image.
+    AccessDenied,
+}
 
 /// Each instance is a unique view of remote registry for a specific [`Platform`] and [`Reference`].
 /// The intention here is to better support chained methods like "pull list of layers" and then "apply each layer to disk".
@@ -41,17 +112,60 @@ pub struct Registry {
     /// Authentication information for the registry.
     auth: RegistryAuth,
 
+    /// The bearer token issued by the registry for pulling, if it uses token authentication.
+    #[debug(skip)]
+    #[cfg_attr(not(feature = "native"), allow(dead_code))]
+    token: Option<String>,
+
     /// Layer filters.
-    /// Layers that match any filter are excluded from the set of layers processed by this registry.
+    /// Only layers matched by the filters are processed by this registry; see [`Filters`].
     layer_filters: Filters,
 
     /// File filters.
-    /// Files that match any filter are excluded from the set of files processed by this registry.
+    /// Only files matched by the filters are processed by this registry; see [`Filters`].
+    // Files are only read when layers are unpacked.
+    #[cfg_attr(not(feature = "native"), allow(dead_code))]
     file_filters: Filters,
 
+    /// How to handle paths that collide on case-insensitive filesystems when layers are applied.
+    #[cfg_attr(not(feature = "native"), allow(dead_code))]
+    case_collisions: CaseCollisions,
+
+    /// Directory to which paths deleted by whiteouts are moved instead of being removed,
+    /// in a subdirectory for each layer named after the hex of its digest.
+    #[cfg_attr(not(feature = "native"), allow(dead_code))]
+    deleted_dir: Option<PathBuf>,
+
+    /// Commands through which layers are piped after they are decompressed.
+    #[cfg_attr(not(feature = "native"), allow(dead_code))]
+    filter_commands: FilterCommands,
+
+    /// Applying a layer fails if it exceeds any of these limits.
+    #[cfg_attr(not(feature = "native"), allow(dead_code))]
+    limits: Limits,
+
     /// The client used to interact with the registry.
     #[debug(skip)]
     client: Client,
+
+    /// The client used to download blobs; see [`blob`] for why this is separate.
+    #[cfg(feature = "native")]
+    #[debug(skip)]
+    blobs: reqwest::Client,
+
+    /// Receives progress updates as layers are downloaded and applied.
+    #[debug(skip)]
+    progress: SharedProgress,
+
+    /// How layer content is buffered as it's downloaded and unpacked.
+    #[cfg_attr(not(feature = "native"), allow(dead_code))]
+    buffering: Buffering,
+
+    /// Deadlines for requests to the registry.
+    timeouts: Timeouts,
+
+    /// The maximum rate in bytes per second at which layers are downloaded.
+    limit_rate: Option<NonZeroU64>,
 }
 
 #[bon::bon]
@@ -66,50 +180,322 @@ impl Registry {
         #[builder(into)]
         platform: Option<Platform>,
 
+        /// Annotations that an image must have to be selected from an image index,
+        /// for indexes that contain several images for the same platform.
+        #[builder(into)]
+        annotations: Option<Vec<Annotation>>,
+
+        /// The digest of the manifest to read, selecting it from an image index without platform resolution.
+        ///
+        /// This is the same as referring to the image by this digest (`{repository}@{digest}`),
+        /// so it also allows reading manifests that are otherwise skipped, like attestations.
+        manifest_digest: Option<Digest>,
+
         /// Filters for layers.
-        /// Layers that match any filter are excluded from the set of layers processed by this registry.
+        /// Only layers matched by the filters are processed by this registry; see [`Filters`].
         layer_filters: Option<Filters>,
 
         /// Filters for files.
-        /// Files that match any filter are excluded from the set of files processed by this registry.
+        /// Only files matched by the filters are processed by this registry; see [`Filters`].
         file_filters: Option<Filters>,
 
+        /// How to handle paths that collide on case-insensitive filesystems when layers are applied.
+        case_collisions: Option<CaseCollisions>,
+
+        /// Directory to which paths deleted by whiteouts are moved instead of being removed,
+        /// so that content deleted by later layers can still be inspected.
+        /// Each layer's deletions are moved to a subdirectory named after the hex of its digest.
+        #[builder(into)]
+        deleted_dir: Option<PathBuf>,
+
+        /// Commands through which layers are piped after they are decompressed.
+        filter_commands: Option<FilterCommands>,
+
+        /// Safety limits enforced as layers are applied; by default layers aren't limited.
+        limits: Option<Limits>,
+
+        /// Receives progress updates as layers are downloaded and applied.
+        progress: Option<SharedProgress>,
+
+        /// How layer content is buffered as it's downloaded and unpacked.
+        buffering: Option<Buffering>,
+
+        /// Deadlines for requests to the registry; by default requests can take indefinitely long.
+        timeouts: Option<Timeouts>,
+
+        /// The maximum rate in bytes per second at which layers are downloaded; by default downloads aren't throttled.
+        ///
+        /// Each download is throttled separately; image configs and manifests aren't throttled.
+        limit_rate: Option<NonZeroU64>,
+
         /// The reference to use for the registry.
         reference: Reference,
     ) -> Result<Self> {
         crate::flag_disabled_registry_oci()?;
+        crate::flag_offline("reading from a registry")?;
+        crate::flag_disallowed_host(&reference.host)?;
 
-        let client = client(platform.clone());
+        let timeouts = timeouts.unwrap_or_default();
+        let client = client(
+            &reference.host,
+            platform.clone(),
+            annotations.unwrap_or_default(),
+            timeouts,
+        );
         let original = reference.clone();
-        let reference = OciReference::from(&reference);
+        let reference = match manifest_digest {
+            Some(digest) => OciReference::from(&Reference {
+                version: Version::Digest(digest),
+                ..reference
+            }),
+            None => OciReference::from(&reference),
+        };
         let auth = auth
             .map(RegistryAuth::from)
             .unwrap_or(RegistryAuth::Anonymous);
 
-        client
-            .auth(&reference, &auth, RegistryOperation::Pull)
-            .await
-            .context("authenticate to registry")?;
+        let token = deadline(
+            timeouts.manifest,
+            client.auth(&reference, &auth, RegistryOperation::Pull),
+        )
+        .await
+        .context("authenticate to registry")?;
 
         Ok(Self {
             auth,
+            token,
             client,
+            #[cfg(feature = "native")]
+            blobs: blob::client(timeouts)?,
             reference,
             original,
             layer_filters: layer_filters.unwrap_or_default(),
             file_filters: file_filters.unwrap_or_default(),
+            case_collisions: case_collisions.unwrap_or_default(),
+            deleted_dir,
+            filter_commands: filter_commands.unwrap_or_default(),
+            limits: limits.unwrap_or_default(),
+            progress: progress.unwrap_or_else(|| Arc::new(Silent)),
+            buffering: buffering.unwrap_or_default(),
+            timeouts,
+            limit_rate,
         })
     }
 }
 
 impl Registry {
-    async fn pull_layer_internal(&self, layer: &Layer) -> Result<impl Stream<Item = Chunk>> {
+    /// Report the digest of the manifest that the reference currently resolves to.
+    ///
+    /// Unlike [`Registry::digest`], this doesn't download the manifest or perform platform resolution:
+    /// the registry is asked for the digest with a `HEAD` request, falling back to `GET` only if the
+    /// registry doesn't report the digest in its response headers.
+    /// For multi-platform images this means the digest is that of the image index.
+    ///
+    /// Registries generally don't count `HEAD` requests against pull rate limits,
+    /// so this is suitable for frequently polling whether an image has changed.
+    #[tracing::instrument]
+    pub async fn head_digest(&self) -> Result<Digest> {
+        let digest = deadline(
+            self.timeouts.manifest,
+            self.client
+                .fetch_manifest_digest(&self.reference, &self.auth),
+        )
+        .await
+        .context("fetch manifest digest")?;
+        debug!(%digest, "fetched manifest digest");
+        Digest::from_str(&digest)
+            .context("parse digest")
+            .map_err(Error::from)
+    }
+
+    /// Check whether the image exists, and if it doesn't, why it can't be read.
+    ///
+    /// Like [`Registry::head_digest`], this asks for the digest of the manifest with a `HEAD` request,
+    /// so the manifest isn't downloaded. Responses that mean the image can't be read are reported as an [`Existence`];
+    /// other failures, like the registry being unreachable, are reported as errors.
+    ///
+    /// Some registries (including Docker Hub) require authentication for repositories that don't exist
+    /// so that they don't reveal which private repositories exist; for those, a missing image is reported
+    /// as [`Existence::AuthenticationRequired`] when reading anonymously.
+    #[tracing::instrument]
+    pub async fn exists(&self) -> Result<Existence> {
+        let digest = deadline(
+            self.timeouts.manifest,
+            self.client
+                .fetch_manifest_digest(&self.reference, &self.auth),
+        )
+        .await;
+        let err = match digest {
+            Ok(digest) => {
+                return Digest::from_str(&digest)
+                    .map(Existence::Exists)
+                    .context("parse digest")
+                    .map_err(Error::from)
+            }
+            Err(err) => err,
+        };
+
+        let existence = err
+            .chain()
+            .find_map(|err| err.downcast_ref::<OciDistributionError>())
+            .and_then(existence);
+        match existence {
+            Some(existence) => {
+                debug!(?existence, ?err, "image can't be read");
+                Ok(existence)
+            }
+            None => Err(err.wrap_err("fetch manifest digest").into()),
+        }
+    }
+
+    /// Check that the image exists, failing with an error that explains why it can't be read if it doesn't;
+    /// see [`Registry::exists`].
+    ///
+    /// Missing images are reported as [`Error::NotFound`], and images that can't be read with the
+    /// provided credentials (or without any) as [`Error::Auth`].
+    pub async fn require_exists(&self) -> Result<Digest> {
+        let reference = &self.original;
+        let anonymous = matches!(self.auth, RegistryAuth::Anonymous);
+        let (kind, message) = match self.exists().await? {
+            Existence::Exists(digest) => return Ok(digest),
+            Existence::NotFound => (Kind::NotFound, format!("image '{reference}' not found")),
+            Existence::AuthenticationRequired if anonymous => (
+                Kind::Auth,
+                format!("authentication required to read image '{reference}'"),
+            ),
+            Existence::AuthenticationRequired => (
+                Kind::Auth,
+                format!("credentials rejected reading image '{reference}'"),
+            ),
+            Existence::AccessDenied => (
+                Kind::Auth,
+                format!("access denied reading image '{reference}'"),
+            ),
+        };
+
+        let report = eyre::Report::new(kind).wrap_err(message);
+        let report = match kind {
+            Kind::Auth if anonymous => report.note(
+                "some registries report this for images that don't exist; check the reference, or provide credentials",
+            ),
+            _ => report,
+        };
+        Err(Error::from(report))
+    }
+
+    /// Pull the image manifest and report its digest, resolving the platform from an image index.
+    pub(crate) async fn pull_image_manifest(&self) -> eyre::Result<(OciImageManifest, String)> {
+        deadline(
+            self.timeouts.manifest,
+            self.client.pull_image_manifest(&self.reference, &self.auth),
+        )
+        .await
+        .context("pull image manifest")
+    }
+
+    #[cfg(feature = "native")]
+    async fn pull_layer_internal(&self, layer: &Layer) -> eyre::Result<impl Stream<Item = Chunk>> {
+        // Layers piped through filter commands may be encoded in ways circe doesn't know,
+        // so uncompressed layers can't be expected to look like tarballs.
+        let expected = blob::Expected::for_layer(layer);
+        let sniff =
+            expected != blob::Expected::Tar || self.filter_commands.command(layer).is_none();
+        self.pull_blob(&layer.digest)
+            .await
+            .context("initiate stream")
+            .map(|blob| match sniff {
+                true => blob::sniff(blob, expected, layer.digest.clone()),
+                false => Box::pin(blob),
+            })
+            .map(|blob| limit_rate(blob, self.limit_rate))
+            .map(|blob| report_bytes_read(self.progress.clone(), layer.clone(), blob))
+            .map(|blob| read_ahead(self.buffering, blob))
+    }
+
+    /// Request the blob with the digest from the repository; see [`blob::pull`].
+    #[cfg(feature = "native")]
+    pub(crate) async fn pull_blob(
+        &self,
+        digest: &Digest,
+    ) -> eyre::Result<impl Stream<Item = Chunk>> {
+        let registry = self.reference.resolve_registry();
+        let url = format!(
+            "{}://{registry}/v2/{}/blobs/{digest}",
+            scheme(registry),
+            self.reference.repository(),
+        );
+        let url = url.parse().context("parse blob url")?;
+        blob::pull(&self.blobs, url, self.token.as_deref(), &self.auth, digest)
+            .await
+            .map(metrics::count_downloaded)
+    }
+
+    /// Pull the image manifest with the digest exactly as the registry stores it,
+    /// so that it can be written somewhere else under the same digest.
+    #[cfg(feature = "native")]
+    pub(crate) async fn pull_manifest_raw(&self, digest: &str) -> eyre::Result<Vec<u8>> {
+        let reference = self.reference.clone_with_digest(digest.to_string());
+        let accepted = [OCI_IMAGE_MEDIA_TYPE, IMAGE_MANIFEST_MEDIA_TYPE];
+        deadline(
+            self.timeouts.manifest,
+            self.client
+                .pull_manifest_raw(&reference, &self.auth, &accepted),
+        )
+        .await
+        .map(|(manifest, _)| manifest)
+        .context("pull raw manifest")
+    }
+
+    /// Pull the config blob that the manifest refers to.
+    #[cfg(feature = "native")]
+    pub(crate) async fn pull_config(&self, manifest: &OciImageManifest) -> eyre::Result<Vec<u8>> {
+        let digest = Digest::from_str(&manifest.config.digest).context("parse config digest")?;
+        let mut blob = self.pull_blob(&digest).await.context("initiate stream")?;
+        let mut content = Vec::new();
+        while let Some(chunk) = blob.next().await {
+            content.extend_from_slice(&chunk.context("read config")?);
+        }
+        Ok(content)
+    }
+
+    /// Pull the config blob that the manifest refers to.
+    #[cfg(not(feature = "native"))]
+    async fn pull_config(&self, manifest: &OciImageManifest) -> eyre::Result<Vec<u8>> {
+        let mut content = Vec::new();
+        self.client
+            .pull_blob(&self.reference, &manifest.config, &mut content)
+            .await
+            .context("pull config")?;
+        metrics::record_downloaded(content.len() as u64);
+        Ok(content)
+    }
+
+    #[cfg(not(feature = "native"))]
+    async fn pull_layer_internal(&self, layer: &Layer) -> eyre::Result<impl Stream<Item = Chunk>> {
         let oci_layer = OciDescriptor::from(layer);
         self.client
             .pull_blob_stream(&self.reference, &oci_layer)
             .await
             .context("initiate stream")
-            .map(|layer| layer.stream)
+            .map(|blob| metrics::count_downloaded(blob.stream))
+            .map(|blob| limit_rate(blob, self.limit_rate))
+            .map(|blob| report_bytes_read(self.progress.clone(), layer.clone(), blob))
+    }
+}
+
+#[cfg(feature = "native")]
+impl Registry {
+    /// Pull the layer and decompress it into a plain tarball, piping it through its filter command if any.
+    /// Foreign layers are emitted as `None`.
+    async fn plain_layer(
+        &self,
+        layer: &Layer,
+    ) -> eyre::Result<Option<Pin<Box<dyn Stream<Item = Chunk>>>>> {
+        let stream = self.pull_layer_internal(layer).await?;
+        peel_layer(layer, stream, self.buffering)
+            .map(|stream| report_bytes_decompressed(self.progress.clone(), layer.clone(), stream))
+            .map(|stream| filter_layer(&self.filter_commands, layer, stream))
+            .transpose()
     }
 }
 
@@ -117,33 +503,81 @@ impl Source for Registry {
     /// Report the digest for the image.
     #[tracing::instrument]
     async fn digest(&self) -> Result<Digest> {
-        let (_, digest) = self
-            .client
-            .pull_image_manifest(&self.reference, &self.auth)
-            .await
-            .context("pull image manifest")?;
-        Digest::from_str(&digest).context("parse digest")
+        let (_, digest) = self.pull_image_manifest().await?;
+        Digest::from_str(&digest)
+            .context("parse digest")
+            .map_err(Error::from)
     }
 
     async fn name(&self) -> Result<String> {
         Ok(self.original.name.clone())
     }
 
+    /// Report the fully qualified reference and repository of the image.
+    #[tracing::instrument]
+    async fn identity(&self) -> Result<Identity> {
+        let digest = self.digest().await?;
+        Ok(Identity::builder()
+            .reference(self.original.to_string())
+            .resolved_digest(digest)
+            .display_name(self.original.repository())
+            .build())
+    }
+
     /// Enumerate layers for a container reference in the remote registry.
     /// Layers are returned in order from the base image to the application.
     #[tracing::instrument]
     async fn layers(&self) -> Result<Vec<Layer>> {
-        let (manifest, _) = self
-            .client
-            .pull_image_manifest(&self.reference, &self.auth)
+        let (manifest, _) = self.pull_image_manifest().await?;
+        let artifact = is_artifact(
+            manifest.artifact_type.as_deref(),
+            Some(&manifest.config.media_type),
+        );
+        let layers = manifest_layers(manifest.layers, artifact)?;
+
+        let config = self.config().await.unwrap_or_else(|err| {
+            debug!(
+                ?err,
+                "unable to pull image config, layers won't report diff IDs"
+            );
+            ImageConfig::default()
+        });
+
+        Layer::with_diff_ids(layers, &config)
+            .pipe(|layers| self.layer_filters.select(layers))
+            .pipe(Ok)
+    }
+
+    /// Pull the configuration for the image from the remote registry.
+    ///
+    /// Artifacts whose config doesn't describe an image report an empty config.
+    #[tracing::instrument]
+    async fn config(&self) -> Result<ImageConfig> {
+        let (manifest, _) = self.pull_image_manifest().await?;
+        if !is_image_config(&manifest.config.media_type) {
+            let media_type = &manifest.config.media_type;
+            debug!(%media_type, "config doesn't describe an image");
+            return Ok(ImageConfig::default());
+        }
+
+        let config = self
+            .pull_config(&manifest)
             .await
-            .context("pull image manifest")?;
+            .context("pull image config")?;
+        serde_json::from_slice(&config)
+            .context("parse image config")
+            .map_err(Error::from)
+    }
+
+    /// Report the manifest that the image's manifest refers to through its `subject` field, if any.
+    #[tracing::instrument]
+    async fn subject(&self) -> Result<Option<Subject>> {
+        let (manifest, _) = self.pull_image_manifest().await?;
         manifest
-            .layers
-            .into_iter()
-            .filter(|layer| !self.layer_filters.matches(layer))
-            .map(Layer::try_from)
-            .collect()
+            .subject
+            .map(Subject::try_from)
+            .transpose()
+            .map_err(Error::from)
     }
 
     /// Pull the bytes of a layer from the registry in a stream.
@@ -166,21 +600,40 @@ impl Source for Registry {
         &self,
         layer: &Layer,
     ) -> Result<Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>> {
-        self.pull_layer_internal(layer)
-            .await
-            .map(|stream| stream.map(|chunk| chunk.context("read chunk")).boxed())
+        let stream = self.pull_layer_internal(layer).await?;
+        stream
+            .map(|chunk| chunk.context("read chunk").map_err(Error::from))
+            .boxed()
+            .pipe(Ok)
     }
+}
 
+#[cfg(feature = "native")]
+impl Unpack for Registry {
     /// Enumerate files in a layer.
     #[tracing::instrument]
     async fn list_files(&self, layer: &Layer) -> Result<Vec<String>> {
-        let stream = self.pull_layer_internal(layer).await?;
-        match peel_layer(layer, stream) {
-            Some(stream) => enumerate_tarball(stream).await,
+        match self.plain_layer(layer).await? {
+            Some(stream) => enumerate_tarball(stream, &self.file_filters)
+                .await
+                .map_err(Error::from),
             None => Ok(vec![]),
         }
     }
 
+    async fn visit_files(
+        &self,
+        layer: &Layer,
+        on_file: impl FnMut(String) -> std::io::Result<()>,
+    ) -> Result<()> {
+        match self.plain_layer(layer).await? {
+            Some(stream) => visit_tarball(stream, &self.file_filters, on_file)
+                .await
+                .map_err(Error::from),
+            None => Ok(()),
+        }
+    }
+
     /// Apply a layer to a location on disk.
     ///
     /// The intention of this method is that when it is run for each layer in an image in order it is equivalent
@@ -223,9 +676,42 @@ impl Source for Registry {
     // this would speed up the overall process.
     #[tracing::instrument]
     async fn apply_layer(&self, layer: &Layer, output: &Path) -> Result<()> {
-        let stream = self.pull_layer_internal(layer).await?;
-        match peel_layer(layer, stream) {
-            Some(stream) => apply_tarball(&self.file_filters, stream, output).await,
+        match self.plain_layer(layer).await? {
+            Some(stream) => {
+                let on_write = |path: &Path| self.progress.file_written(layer, path);
+                let on_rename =
+                    |path: &Path, renamed: &Path| self.progress.file_renamed(layer, path, renamed);
+                let on_whiteout = |path: &Path, existed: bool| {
+                    self.progress.whiteout_applied(layer, path, existed)
+                };
+                let on_recover = |path: &Path, recovered: &Path| {
+                    self.progress.file_recovered(layer, path, recovered)
+                };
+
+                // Each layer's deletions are kept separately, since a path may be deleted by more than one layer.
+                let deleted = self
+                    .deleted_dir
+                    .as_ref()
+                    .map(|dir| dir.join(layer.digest.as_hex()));
+                let opts = ApplyOptions {
+                    filters: &self.file_filters,
+                    collisions: self.case_collisions,
+                    deleted: deleted.as_deref(),
+                    limits: self.limits,
+                };
+                apply_tarball(
+                    opts,
+                    stream,
+                    output,
+                    on_write,
+                    on_rename,
+                    on_whiteout,
+                    on_recover,
+                )
+                .await
+                .inspect(|()| metrics::record_layer_applied())
+                .map_err(Error::from)
+            }
             None => Ok(()),
         }
     }
@@ -249,9 +735,8 @@ impl Source for Registry {
     ///   but if we ever want to make this work for more than just that we'll need to re-evaluate.
     #[tracing::instrument]
     async fn layer_plain_tarball(&self, layer: &Layer) -> Result<Option<TempFile>> {
-        let stream = self.pull_layer_internal(layer).await?;
-        match peel_layer(layer, stream) {
-            Some(stream) => collect_tmp(stream).await.map(Some),
+        match self.plain_layer(layer).await? {
+            Some(stream) => collect_tmp(stream).await.map(Some).map_err(Error::from),
             None => Ok(None),
         }
     }
@@ -297,6 +782,23 @@ impl TryFrom<OciDescriptor> for Layer {
             digest: Digest::from_str(&value.digest).context("parse digest")?,
             media_type: LayerMediaType::from_str(&value.media_type).context("parse media type")?,
             size: value.size,
+            diff_id: None,
+            uncompressed_size: value
+                .annotations
+                .as_ref()
+                .and_then(Layer::uncompressed_size_annotation),
+        })
+    }
+}
+
+impl TryFrom<OciDescriptor> for Subject {
+    type Error = color_eyre::Report;
+
+    fn try_from(value: OciDescriptor) -> Result<Self, Self::Error> {
+        Ok(Self {
+            digest: Digest::from_str(&value.digest).context("parse digest")?,
+            media_type: value.media_type,
+            size: value.size,
         })
     }
 }
@@ -328,46 +830,268 @@ impl FilterMatch<&PathBuf> for Filter {
     }
 }
 
-fn client(platform: Option<Platform>) -> Client {
+/// Create the client for the registry at the host.
+///
+/// Blob downloads commonly redirect to pre-signed URLs on another domain (e.g. S3 or GCS);
+/// the client follows these redirects and doesn't send registry credentials across origins,
+/// since the signed URL carries its own authorization and storage backends reject requests with both.
+///
+/// The stall timeout is applied to every read, which also detects stalled blob downloads
+/// when they're made by this client rather than [`blob`].
+fn client(
+    host: &str,
+    platform: Option<Platform>,
+    annotations: Vec<Annotation>,
+    timeouts: Timeouts,
+) -> Client {
     Client::new(ClientConfig {
-        platform_resolver: match platform {
-            Some(platform) => Some(Box::new(target_platform_resolver(platform))),
-            None => Some(Box::new(current_platform_resolver)),
-        },
+        protocol: protocol(host),
+        platform_resolver: Some(Box::new(platform_resolver(platform, annotations))),
+        connect_timeout: timeouts.connect,
+        read_timeout: timeouts.stall,
         ..Default::default()
     })
 }
 
-fn target_platform_resolver(target: Platform) -> impl Fn(&[ImageIndexEntry]) -> Option<String> {
+/// Wait for the request to complete, failing if it takes longer than the timeout (if any).
+async fn deadline<T, E>(
+    timeout: Option<Duration>,
+    request: impl Future<Output = Result<T, E>>,
+) -> eyre::Result<T>
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let Some(timeout) = timeout else {
+        return request.await.map_err(eyre::Report::from);
+    };
+    match tokio::time::timeout(timeout, request).await {
+        Ok(result) => result.map_err(eyre::Report::from),
+        Err(elapsed) => Err(eyre::Report::from(elapsed))
+            .with_context(|| format!("request timed out after {timeout:?}")),
+    }
+}
+
+/// What the failure to fetch a manifest digest says about whether the image exists, if anything.
+fn existence(err: &OciDistributionError) -> Option<Existence> {
+    let status = |code: u16| match code {
+        401 => Some(Existence::AuthenticationRequired),
+        403 => Some(Existence::AccessDenied),
+        404 => Some(Existence::NotFound),
+        _ => None,
+    };
+    match err {
+        OciDistributionError::UnauthorizedError { .. } => Some(Existence::AuthenticationRequired),
+        OciDistributionError::ImageManifestNotFoundError(_) => Some(Existence::NotFound),
+        OciDistributionError::ServerError { code, .. } => status(*code),
+        OciDistributionError::RegistryError { envelope, .. } => {
+            envelope.errors.iter().find_map(|err| match err.code {
+                OciErrorCode::Unauthorized => Some(Existence::AuthenticationRequired),
+                OciErrorCode::Denied => Some(Existence::AccessDenied),
+                OciErrorCode::ManifestUnknown
+                | OciErrorCode::NameUnknown
+                | OciErrorCode::NotFound => Some(Existence::NotFound),
+                _ => None,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Loopback registries are accessed over plain HTTP, like the Docker daemon does by default;
+/// every other registry requires HTTPS.
+fn protocol(host: &str) -> ClientProtocol {
+    if is_loopback(host) {
+        ClientProtocol::HttpsExcept(vec![host.to_string()])
+    } else {
+        ClientProtocol::Https
+    }
+}
+
+/// The URL scheme for the registry host; see [`protocol`].
+#[cfg(feature = "native")]
+fn scheme(host: &str) -> &'static str {
+    if is_loopback(host) {
+        "http"
+    } else {
+        "https"
+    }
+}
+
+/// Whether the registry host, which may include a port, refers to the local machine.
+fn is_loopback(host: &str) -> bool {
+    if let Ok(addr) = host.parse::<SocketAddr>() {
+        return addr.ip().is_loopback();
+    }
+    if let Ok(ip) = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+    {
+        return ip.is_loopback();
+    }
+
+    let name = match host.rsplit_once(':') {
+        Some((name, port)) if port.parse::<u16>().is_ok() => name,
+        _ => host,
+    };
+    name.eq_ignore_ascii_case("localhost")
+}
+
+/// Select an entry from an image index, only considering entries that have all of the annotations;
+/// among those the entry is chosen by the target platform if provided, otherwise by the current platform.
+fn platform_resolver(
+    platform: Option<Platform>,
+    annotations: Vec<Annotation>,
+) -> impl Fn(&[ImageIndexEntry]) -> Option<String> {
     move |entries: &[ImageIndexEntry]| {
-        entries
+        let entries = entries
             .iter()
-            .find(|entry| {
-                entry.platform.as_ref().is_some_and(|platform| {
-                    platform.os == target.os && platform.architecture == target.architecture
-                })
+            .filter(|entry| {
+                annotations
+                    .iter()
+                    .all(|annotation| annotation.matches(entry.annotations.iter().flatten()))
             })
-            .map(|entry| entry.digest.clone())
+            .collect::<Vec<_>>();
+        match &platform {
+            Some(platform) => target_platform_resolver(platform, &entries),
+            None => current_platform_resolver(&entries),
+        }
     }
 }
 
-fn current_platform_resolver(entries: &[ImageIndexEntry]) -> Option<String> {
-    let current_os = go_os();
-    let current_arch = go_arch();
-    let linux = Platform::LINUX;
-    let amd64 = Platform::AMD64;
+/// Select the entry whose platform best suits the target, preferring exact variant matches;
+/// see [`Platform::score`].
+fn target_platform_resolver(target: &Platform, entries: &[&ImageIndexEntry]) -> Option<String> {
+    entries
+        .iter()
+        .filter_map(|entry| {
+            let platform = entry.platform.as_ref()?;
+            let platform = Platform::builder()
+                .os(&platform.os)
+                .architecture(&platform.architecture)
+                .maybe_variant(platform.variant.as_ref())
+                .maybe_os_version(platform.os_version.as_ref())
+                .build();
+            target.score(&platform).map(|score| (score, entry))
+        })
+        .min_by_key(|(score, _)| *score)
+        .map(|(_, entry)| entry.digest.clone())
+}
+
+fn current_platform_resolver(entries: &[&ImageIndexEntry]) -> Option<String> {
     entries
         .iter()
-        .priority_find(|entry| match entry.platform.as_ref() {
-            None => 0,
-            Some(p) if p.os == current_os && p.architecture == current_arch => 1,
-            Some(p) if p.os == linux && p.architecture == current_arch => 2,
-            Some(p) if p.os == linux && p.architecture == amd64 => 3,
-            _ => 4,
+        .priority_find(|entry| {
+            if is_attestation(entry) {
+                return usize::MAX;
+            }
+
+            let platform = entry.platform.as_ref();
+            current_platform_priority(platform.map(|p| (p.os.as_str(), p.architecture.as_str())))
         })
         .map(|entry| entry.digest.clone())
 }
 
+/// Media types of config blobs that describe a container image.
+const IMAGE_CONFIGS: &[&str] = &[
+    "application/vnd.oci.image.config.v1+json",
+    "application/vnd.docker.container.image.v1+json",
+];
+
+/// The annotation recording the file name of a blob, as set by ORAS.
+const TITLE_ANNOTATION: &str = "org.opencontainers.image.title";
+
+/// The annotation with which ORAS marks blobs that are tarballs of a directory, rather than a single file.
+const UNPACK_ANNOTATION: &str = "io.deis.oras.content.unpack";
+
+/// Whether the config blob with the media type describes a container image.
+///
+/// Artifacts (like content pushed with ORAS) have some other config, most commonly the empty config
+/// (`application/vnd.oci.empty.v1+json`), which shouldn't be read as an image config.
+pub(crate) fn is_image_config(media_type: &str) -> bool {
+    IMAGE_CONFIGS.contains(&media_type)
+}
+
+/// Whether a manifest is an artifact rather than a container image:
+/// artifacts either declare an `artifactType`, or have a config that doesn't describe an image.
+pub(crate) fn is_artifact(artifact_type: Option<&str>, config_media_type: Option<&str>) -> bool {
+    artifact_type.is_some()
+        || config_media_type.is_some_and(|media_type| !is_image_config(media_type))
+}
+
+/// Parse the layers in a manifest from their descriptors.
+///
+/// Artifacts can hold blobs that aren't filesystem layers: files pushed with ORAS are recorded
+/// with a layer media type (unless they're directories, they aren't tarballs), and other blobs use media types of their own.
+/// These blobs are skipped instead of failing, so that the layers of an artifact are only its tarballs;
+/// every layer in an image manifest must be parsed.
+pub(crate) fn manifest_layers(
+    descriptors: impl IntoIterator<Item = OciDescriptor>,
+    artifact: bool,
+) -> eyre::Result<Vec<Layer>> {
+    descriptors
+        .into_iter()
+        .filter(|descriptor| {
+            let layer = !artifact || is_artifact_layer(descriptor);
+            if !layer {
+                let (digest, media_type) = (&descriptor.digest, &descriptor.media_type);
+                warn!(%digest, %media_type, "skip: artifact blob isn't a filesystem layer");
+            }
+            layer
+        })
+        .map(Layer::try_from)
+        .collect()
+}
+
+/// Whether a blob in an artifact is a filesystem layer.
+fn is_artifact_layer(descriptor: &OciDescriptor) -> bool {
+    let annotations = descriptor.annotations.as_ref();
+    let annotated = |key: &str| annotations.and_then(|annotations| annotations.get(key));
+    let file = annotated(TITLE_ANNOTATION).is_some()
+        && annotated(UNPACK_ANNOTATION).is_none_or(|unpack| unpack != "true");
+    !file && LayerMediaType::from_str(&descriptor.media_type).is_ok()
+}
+
+/// Whether the entry is an attestation (e.g. provenance from buildkit) rather than an image.
+///
+/// Attestations are recorded with an `unknown/unknown` platform,
+/// and annotated with the type of reference they attach to the image they describe.
+pub(crate) fn is_attestation(entry: &ImageIndexEntry) -> bool {
+    const REFERENCE_TYPE: &str = "vnd.docker.reference.type";
+    const ATTESTATION: &str = "attestation-manifest";
+    let annotated = entry
+        .annotations
+        .as_ref()
+        .and_then(|annotations| annotations.get(REFERENCE_TYPE))
+        .is_some_and(|kind| kind == ATTESTATION);
+    let unknown = entry
+        .platform
+        .as_ref()
+        .is_some_and(|platform| platform.os == "unknown" && platform.architecture == "unknown");
+    annotated || unknown
+}
+
+/// Prioritize an image by its `(os, architecture)` when the user didn't request a platform,
+/// for use with [`PriorityFind`]:
+/// 1. Platform-independent images (which don't record a platform)
+/// 2. The current platform
+/// 3. The `linux` platform for the current architecture
+/// 4. The `linux` platform for the `amd64` architecture
+/// 5. Anything else
+pub(crate) fn current_platform_priority(platform: Option<(&str, &str)>) -> usize {
+    let current_os = go_os();
+    let current_arch = go_arch();
+    let linux = Platform::LINUX;
+    let amd64 = Platform::AMD64;
+    match platform {
+        None => 0,
+        Some((os, arch)) if os == current_os && arch == current_arch => 1,
+        Some((os, arch)) if os == linux && arch == current_arch => 2,
+        Some((os, arch)) if os == linux && arch == amd64 => 3,
+        _ => 4,
+    }
+}
+
 /// Returns the current OS as a string that matches a `GOOS` constant.
 /// This is required because the OCI spec requires the OS to be a valid GOOS value.
 // If you get a compile error here, you need to add a new `cfg` branch for your platform.
@@ -401,3 +1125,22 @@ const fn go_arch() -> &'static str {
         "arm64"
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    #[test_case("localhost", true; "localhost")]
+    #[test_case("localhost:5000", true; "localhost_port")]
+    #[test_case("127.0.0.1:5000", true; "ipv4_port")]
+    #[test_case("[::1]:5000", true; "ipv6_port")]
+    #[test_case("::1", true; "ipv6")]
+    #[test_case("docker.io", false; "remote")]
+    #[test_case("registry.local:5000", false; "remote_port")]
+    #[test_case("10.0.0.1:5000", false; "private_ip")]
+    #[test]
+    fn loopback(host: &str, expected: bool) {
+        pretty_assertions::assert_eq!(expected, is_loopback(host));
+    }
+}
@@ -0,0 +1,298 @@
+//! Downloads blobs from the registry, following redirects without leaking credentials.
+//!
+//! Registries backed by object storage (ECR, GCR, GHCR, and many others) respond to blob requests
+//! with a redirect to a pre-signed URL on another domain, which carries its own authorization.
+//! Storage backends reject requests that also carry the registry's `Authorization` header,
+//! and the header shouldn't leave the registry anyway.
+//!
+//! The OCI client follows redirects with the default `reqwest` policy, which only drops the header
+//! when a hop changes origin relative to the hop before it: if storage redirects again within its own origin
+//! the header is restored from the original request and sent to storage.
+//! Instead, redirects are followed here manually and credentials are only ever sent to the registry's origin.
+//!
+//! Misconfigured proxies sometimes answer blob requests with an error page (HTML or JSON) and a successful status;
+//! [`sniff`] checks the start of layer blobs so this is reported as such, instead of failing to decompress them.
+
+use std::pin::Pin;
+
+use color_eyre::eyre::{self, bail, eyre, Context};
+use futures_lite::{Stream, StreamExt};
+use oci_client::secrets::RegistryAuth;
+use reqwest::{header::LOCATION, redirect, Client, RequestBuilder, Url};
+use sha2::{digest::DynDigest, Sha256, Sha512};
+use tracing::debug;
+
+use crate::{
+    error::BoxError, transform::Chunk, Digest, DigestAlgorithm, Error, Layer, LayerMediaType,
+    LayerMediaTypeFlag,
+};
+
+use super::Timeouts;
+
+/// The maximum number of redirects followed for a single blob, matching `reqwest`.
+const MAX_REDIRECTS: usize = 10;
+
+/// Create the HTTP client used to download blobs; redirects are handled by [`pull`].
+///
+/// The stall timeout applies to each read, so downloads fail if the connection stops delivering content.
+pub fn client(timeouts: Timeouts) -> eyre::Result<Client> {
+    let mut builder = Client::builder().redirect(redirect::Policy::none());
+    if let Some(timeout) = timeouts.connect {
+        builder = builder.connect_timeout(timeout);
+    }
+    if let Some(timeout) = timeouts.stall {
+        builder = builder.read_timeout(timeout);
+    }
+    builder.build().context("build http client")
+}
+
+/// Request the blob at the URL, sending credentials only to the origin of the URL.
+///
+/// The bearer token, if any, is preferred over the credentials in `auth`;
+/// this matches how the OCI client authenticates other requests to the registry.
+///
+/// The content of the blob is verified against the digest as it is read;
+/// the stream ends with an error if the content doesn't match.
+pub async fn pull(
+    client: &Client,
+    url: Url,
+    token: Option<&str>,
+    auth: &RegistryAuth,
+    digest: &Digest,
+) -> eyre::Result<Pin<Box<dyn Stream<Item = Chunk> + Send>>> {
+    let hasher = hasher(digest);
+    let origin = url.origin();
+    let mut url = url;
+
+    for _ in 0..=MAX_REDIRECTS {
+        let mut request = client.get(url.clone());
+        if url.origin() == origin {
+            request = authorize(request, token, auth);
+        }
+
+        let response = request.send().await.context("send request")?;
+        if response.status().is_redirection() {
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .ok_or_else(|| eyre!("redirect without location: {}", response.status()))?
+                .to_str()
+                .context("read redirect location")?;
+            url = url.join(location).context("parse redirect location")?;
+
+            // Pre-signed URLs are credentials in their own right, so don't log them in full.
+            debug!(host = url.host_str(), status = %response.status(), "follow redirect");
+            continue;
+        }
+
+        let response = response
+            .error_for_status()
+            .with_context(|| format!("download blob {digest}"))?;
+        return Ok(verify(response.bytes_stream(), hasher, digest.clone()));
+    }
+
+    bail!("too many redirects downloading blob {digest}")
+}
+
+/// Add credentials for the registry to the request.
+pub(super) fn authorize(
+    request: RequestBuilder,
+    token: Option<&str>,
+    auth: &RegistryAuth,
+) -> RequestBuilder {
+    match (token, auth) {
+        (Some(token), _) => request.bearer_auth(token),
+        (None, RegistryAuth::Basic(username, password)) => {
+            request.basic_auth(username, Some(password))
+        }
+        _ => request,
+    }
+}
+
+/// Create a hasher for the algorithm of the digest.
+fn hasher(digest: &Digest) -> Box<dyn DynDigest + Send> {
+    match digest.algorithm {
+        DigestAlgorithm::Sha256 => Box::new(Sha256::default()),
+        DigestAlgorithm::Sha512 => Box::new(Sha512::default()),
+    }
+}
+
+/// Hash the content as it is read, ending the stream with an error if it doesn't match the digest.
+fn verify(
+    stream: impl Stream<Item = reqwest::Result<bytes::Bytes>> + Send + 'static,
+    mut hasher: Box<dyn DynDigest + Send>,
+    digest: Digest,
+) -> Pin<Box<dyn Stream<Item = Chunk> + Send>> {
+    Box::pin(async_stream::stream! {
+        let mut stream = std::pin::pin!(stream);
+        while let Some(chunk) = stream.next().await {
+            match chunk {
+                Ok(chunk) => {
+                    hasher.update(&chunk);
+                    yield Ok(chunk);
+                }
+                // Report stalls as timeouts so they're classified as network failures rather than local I/O.
+                Err(err) if err.is_timeout() => {
+                    yield Err(std::io::Error::new(std::io::ErrorKind::TimedOut, err));
+                    return;
+                }
+                Err(err) => {
+                    yield Err(std::io::Error::other(err));
+                    return;
+                }
+            }
+        }
+
+        let actual = Digest {
+            algorithm: digest.algorithm,
+            hash: hasher.finalize().to_vec(),
+        };
+        if actual != digest {
+            let message = format!("blob digest mismatch: expected {digest}, got {actual}");
+            yield Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message));
+        }
+    })
+}
+
+/// The number of bytes of unexpected content included in the error reported by [`sniff`].
+const SNIPPET_LEN: usize = 128;
+
+/// The number of bytes needed to recognize the magic number of any compressed format.
+const MAGIC_LEN: usize = 4;
+
+/// The content expected at the start of a layer blob.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expected {
+    /// Gzip compressed data.
+    Gzip,
+
+    /// Zstd compressed data.
+    Zstd,
+
+    /// An uncompressed tarball.
+    Tar,
+}
+
+impl Expected {
+    /// The content expected at the start of a blob for the layer, based on its media type.
+    ///
+    /// Layers with several compression flags are decompressed in order,
+    /// so the blob starts with the first of them.
+    pub fn for_layer(layer: &Layer) -> Self {
+        let LayerMediaType::Oci(flags) = &layer.media_type;
+        flags
+            .iter()
+            .find_map(|flag| match flag {
+                LayerMediaTypeFlag::Gzip => Some(Self::Gzip),
+                LayerMediaTypeFlag::Zstd => Some(Self::Zstd),
+                LayerMediaTypeFlag::Foreign => None,
+            })
+            .unwrap_or(Self::Tar)
+    }
+
+    /// Report whether the start of the content is plausible for the expected format.
+    ///
+    /// Compressed formats are identified by their magic numbers.
+    /// Tarballs have no magic number at their start, so they're only rejected
+    /// if they look like a document (HTML, XML, or JSON), which is what error pages are.
+    fn plausible(self, head: &[u8]) -> bool {
+        match self {
+            Self::Gzip => head.starts_with(&[0x1f, 0x8b]),
+            Self::Zstd => {
+                // Frames may be preceded by skippable frames, whose magic number is `0x184D2A5?`.
+                let skippable = head.len() >= MAGIC_LEN
+                    && head[0] & 0xf0 == 0x50
+                    && head[1..4] == [0x2a, 0x4d, 0x18];
+                head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) || skippable
+            }
+            Self::Tar => {
+                let head = head.strip_prefix(b"\xef\xbb\xbf").unwrap_or(head);
+                let start = head.iter().find(|byte| !byte.is_ascii_whitespace());
+                !matches!(start, Some(b'<' | b'{'))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Expected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Gzip => write!(f, "gzip data"),
+            Self::Zstd => write!(f, "zstd data"),
+            Self::Tar => write!(f, "a tarball"),
+        }
+    }
+}
+
+/// Check that the blob starts with the expected content, ending the stream with an error if it doesn't.
+///
+/// The error is a network error that includes the start of the content,
+/// since this usually means a proxy or the registry returned an error page instead of the blob.
+pub fn sniff(
+    stream: impl Stream<Item = Chunk> + Send + 'static,
+    expected: Expected,
+    digest: Digest,
+) -> Pin<Box<dyn Stream<Item = Chunk> + Send>> {
+    Box::pin(async_stream::stream! {
+        let mut stream = std::pin::pin!(stream);
+
+        // Chunks are usually much larger than the snippet, so this rarely needs more than one.
+        // Small blobs may end before the snippet is filled, with an error if their digest doesn't match;
+        // the error is reported after the content is checked, since an error page explains the mismatch.
+        let mut head = Vec::new();
+        let mut failed = None;
+        while head.len() < SNIPPET_LEN {
+            match stream.next().await {
+                Some(Ok(chunk)) => head.extend_from_slice(&chunk),
+                Some(Err(err)) => {
+                    failed = Some(err);
+                    break;
+                }
+                None => break,
+            }
+        }
+
+        // If the download failed almost immediately, there's too little content to judge.
+        let conclusive = failed.is_none() || head.len() >= MAGIC_LEN;
+        if conclusive && !expected.plausible(&head) {
+            let snippet = String::from_utf8_lossy(&head[..head.len().min(SNIPPET_LEN)]);
+            let message = format!(
+                "registry returned non-blob content for {digest}: expected {expected}, but content starts with {snippet:?}; \
+                 a proxy or the registry may have responded with an error page"
+            );
+            yield Err(std::io::Error::other(Error::Network(BoxError::from(message))));
+            return;
+        }
+
+        if !head.is_empty() {
+            yield Ok(bytes::Bytes::from(head));
+        }
+        if let Some(err) = failed {
+            yield Err(err);
+            return;
+        }
+        while let Some(chunk) = stream.next().await {
+            yield chunk;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    #[test_case(Expected::Gzip, b"\x1f\x8b\x08\x00", true; "gzip")]
+    #[test_case(Expected::Gzip, b"<html><body>502 Bad Gateway</body></html>", false; "gzip_html")]
+    #[test_case(Expected::Zstd, b"\x28\xb5\x2f\xfd\x00", true; "zstd")]
+    #[test_case(Expected::Zstd, b"\x50\x2a\x4d\x18\x00", true; "zstd_skippable")]
+    #[test_case(Expected::Zstd, b"{\"errors\":[]}", false; "zstd_json")]
+    #[test_case(Expected::Tar, b"etc/os-release\0\0\0", true; "tar")]
+    #[test_case(Expected::Tar, b"", true; "tar_empty")]
+    #[test_case(Expected::Tar, b"\n  <!DOCTYPE html>", false; "tar_html")]
+    #[test_case(Expected::Tar, b"{\"errors\":[]}", false; "tar_json")]
+    #[test]
+    fn plausible(expected: Expected, head: &[u8], plausible: bool) {
+        pretty_assertions::assert_eq!(plausible, expected.plausible(head));
+    }
+}
@@ -0,0 +1,391 @@
+//! Lists the repositories in a registry with the `/v2/_catalog` endpoint.
+//!
+//! The endpoint isn't part of the OCI distribution spec, but it's implemented by the reference registry
+//! (`distribution`), Harbor, Nexus, Artifactory, and other registries that are commonly self-hosted.
+//! Most public registries (including Docker Hub and GHCR) don't allow it.
+//!
+//! Results are paginated: each page is requested with `?n={page size}`, and the registry links the next page
+//! (which continues with `&last={repository}`) from the `Link` header of the response.
+//!
+//! Registries that use token authentication issue a separate token for the catalog, with the `registry:catalog:*` scope;
+//! the OCI client only requests tokens for repositories, so the token is requested here instead.
+
+use std::num::NonZeroUsize;
+
+use color_eyre::eyre::{self, eyre, Context};
+use derive_more::Debug;
+use oci_client::secrets::RegistryAuth;
+use reqwest::{
+    header::{LINK, WWW_AUTHENTICATE},
+    Client, Response, StatusCode, Url,
+};
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::{error::Kind, Authentication, Error, FilterMatch, Filters, Result};
+
+use super::{blob, deadline, scheme, Timeouts};
+
+/// The number of repositories requested in each page if no page size is provided.
+pub const DEFAULT_PAGE_SIZE: NonZeroUsize = NonZeroUsize::new(100).unwrap();
+
+/// The scope of the token that allows listing repositories.
+const CATALOG_SCOPE: &str = "registry:catalog:*";
+
+/// The repositories in a registry, listed with [`Catalog::repositories`].
+#[derive(Debug, Clone)]
+pub struct Catalog {
+    /// The registry host, which may include a port.
+    host: String,
+
+    /// Authentication information for the registry.
+    auth: RegistryAuth,
+
+    /// The client used to request the catalog.
+    #[debug(skip)]
+    client: Client,
+
+    /// Deadlines for requests to the registry; the manifest timeout applies to each page.
+    timeouts: Timeouts,
+
+    /// The number of repositories requested in each page.
+    page_size: NonZeroUsize,
+
+    /// Only repositories matched by the filters are listed.
+    filters: Filters,
+
+    /// The maximum number of repositories listed.
+    limit: Option<NonZeroUsize>,
+}
+
+/// A page of the catalog.
+#[derive(Debug, Deserialize)]
+struct Page {
+    #[serde(default)]
+    repositories: Vec<String>,
+}
+
+/// A token issued by the registry's token service.
+#[derive(Debug, Deserialize)]
+struct Token {
+    #[serde(alias = "access_token")]
+    token: String,
+}
+
+#[bon::bon]
+impl Catalog {
+    /// Create a catalog for the registry at the host.
+    #[builder]
+    pub fn new(
+        /// The registry host, which may include a port (e.g. `harbor.example.com` or `localhost:5000`).
+        #[builder(into)]
+        host: String,
+
+        /// Authentication information for the registry.
+        auth: Option<Authentication>,
+
+        /// Deadlines for requests to the registry; by default requests can take indefinitely long.
+        timeouts: Option<Timeouts>,
+
+        /// The number of repositories requested in each page; by default [`DEFAULT_PAGE_SIZE`].
+        ///
+        /// Registries may return fewer repositories per page than requested.
+        page_size: Option<NonZeroUsize>,
+
+        /// Filters for repository names (e.g. `library/alpine`).
+        /// Only repositories matched by the filters are listed; see [`Filters`].
+        filters: Option<Filters>,
+
+        /// The maximum number of repositories listed; by default every repository is listed.
+        ///
+        /// Pages stop being requested once this many repositories have matched the filters.
+        limit: Option<NonZeroUsize>,
+    ) -> Result<Self> {
+        crate::flag_disabled_registry_oci()?;
+        crate::flag_offline("listing repositories in a registry")?;
+        crate::flag_disallowed_host(&host)?;
+
+        let timeouts = timeouts.unwrap_or_default();
+        Ok(Self {
+            client: blob::client(timeouts)?,
+            auth: auth
+                .map(RegistryAuth::from)
+                .unwrap_or(RegistryAuth::Anonymous),
+            host,
+            timeouts,
+            page_size: page_size.unwrap_or(DEFAULT_PAGE_SIZE),
+            filters: filters.unwrap_or_default(),
+            limit,
+        })
+    }
+}
+
+impl Catalog {
+    /// The registry host.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// List the names of the repositories in the registry that match the filters, in the order the registry lists them.
+    ///
+    /// Registries that don't implement the catalog report [`Error::Unsupported`],
+    /// and registries that don't allow the credentials (or anonymous access) to list repositories report [`Error::Auth`].
+    #[tracing::instrument]
+    pub async fn repositories(&self) -> Result<Vec<String>> {
+        let host = &self.host;
+        let mut url = Url::parse(&format!(
+            "{}://{host}/v2/_catalog?n={}",
+            scheme(host),
+            self.page_size
+        ))
+        .context("parse catalog url")?;
+
+        let mut token = None;
+        let mut repositories = Vec::new();
+        loop {
+            let response = self.page(&url, &mut token).await?;
+            let next = next_page(&url, &response)?;
+            let body = deadline(self.timeouts.manifest, response.bytes())
+                .await
+                .context("read catalog page")?;
+            let page = serde_json::from_slice::<Page>(&body).context("parse catalog page")?;
+            debug!(%url, repositories = page.repositories.len(), "read catalog page");
+
+            for repository in page.repositories {
+                if !self.filters.matches(&repository) {
+                    continue;
+                }
+                repositories.push(repository);
+                if self
+                    .limit
+                    .is_some_and(|limit| repositories.len() >= limit.get())
+                {
+                    return Ok(repositories);
+                }
+            }
+
+            match next {
+                Some(next) if next == url => {
+                    return Err(eyre!("registry '{host}' linked a catalog page to itself").into())
+                }
+                Some(next) => url = next,
+                None => return Ok(repositories),
+            }
+        }
+    }
+
+    /// Request a page of the catalog, requesting a token for the catalog if the registry asks for one.
+    async fn page(&self, url: &Url, token: &mut Option<String>) -> Result<Response> {
+        let mut response = self.send(url, token.as_deref()).await?;
+        if response.status() == StatusCode::UNAUTHORIZED && token.is_none() {
+            if let Some(challenge) = bearer_challenge(&response) {
+                *token = Some(self.token(&challenge).await?);
+                response = self.send(url, token.as_deref()).await?;
+            }
+        }
+
+        let host = &self.host;
+        let anonymous = matches!(self.auth, RegistryAuth::Anonymous);
+        let (kind, message) = match response.status() {
+            status if status.is_success() => return Ok(response),
+            StatusCode::UNAUTHORIZED if anonymous => (
+                Kind::Auth,
+                format!("authentication required to list repositories in '{host}'"),
+            ),
+            StatusCode::UNAUTHORIZED => (
+                Kind::Auth,
+                format!("credentials rejected listing repositories in '{host}'"),
+            ),
+            StatusCode::FORBIDDEN => (
+                Kind::Auth,
+                format!("access denied listing repositories in '{host}'"),
+            ),
+            StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED => (
+                Kind::Unsupported,
+                format!("registry '{host}' doesn't support listing repositories"),
+            ),
+            status => {
+                return Err(eyre!("list repositories in '{host}': {status}").into());
+            }
+        };
+        Err(eyre::Report::new(kind))
+            .context(message)
+            .map_err(Error::from)
+    }
+
+    /// Send a request for the URL with the token, or the credentials if there's no token.
+    async fn send(&self, url: &Url, token: Option<&str>) -> Result<Response> {
+        let request = blob::authorize(self.client.get(url.clone()), token, &self.auth);
+        deadline(self.timeouts.manifest, request.send())
+            .await
+            .context("request catalog")
+            .map_err(Error::from)
+    }
+
+    /// Request a token with the catalog scope from the token service named in the challenge.
+    async fn token(&self, challenge: &Challenge) -> Result<String> {
+        let mut url = Url::parse(&challenge.realm).context("parse token realm")?;
+        {
+            let mut query = url.query_pairs_mut();
+            if let Some(service) = &challenge.service {
+                query.append_pair("service", service);
+            }
+            query.append_pair("scope", CATALOG_SCOPE);
+        }
+        debug!(realm = %challenge.realm, "request catalog token");
+
+        let request = blob::authorize(self.client.get(url), None, &self.auth);
+        let response = deadline(self.timeouts.manifest, request.send())
+            .await
+            .context("request catalog token")?;
+        let response = match response.status() {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                let host = &self.host;
+                return Err(eyre::Report::new(Kind::Auth))
+                    .context(format!(
+                        "credentials rejected listing repositories in '{host}'"
+                    ))
+                    .map_err(Error::from);
+            }
+            _ => response
+                .error_for_status()
+                .context("request catalog token")?,
+        };
+        let body = deadline(self.timeouts.manifest, response.bytes())
+            .await
+            .context("read catalog token")?;
+        let token = serde_json::from_slice::<Token>(&body).context("parse catalog token")?;
+        Ok(token.token)
+    }
+}
+
+/// The parameters of a `Bearer` authentication challenge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Challenge {
+    realm: String,
+    service: Option<String>,
+}
+
+/// The bearer challenge in the `WWW-Authenticate` header of the response, if it has one.
+fn bearer_challenge(response: &Response) -> Option<Challenge> {
+    let header = response.headers().get(WWW_AUTHENTICATE)?.to_str().ok()?;
+    parse_challenge(header)
+}
+
+/// Parse a `Bearer` challenge, like `Bearer realm="https://auth.example.com/token",service="registry"`.
+fn parse_challenge(header: &str) -> Option<Challenge> {
+    let (scheme, params) = header.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+
+    // Parameters are `key=value` or `key="value"`, separated by commas; quoted values may contain commas.
+    let mut realm = None;
+    let mut service = None;
+    let mut params = params;
+    while let Some((key, rest)) = params.split_once('=') {
+        let key = key.trim_matches([' ', ',']);
+        let (value, rest) = match rest.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"')?,
+            None => rest.split_once(',').unwrap_or((rest, "")),
+        };
+        match key.to_ascii_lowercase().as_str() {
+            "realm" => realm = Some(value.to_string()),
+            "service" => service = Some(value.to_string()),
+            _ => {}
+        }
+        params = rest;
+    }
+    Some(Challenge {
+        realm: realm?,
+        service,
+    })
+}
+
+/// The URL of the next page of the catalog, if the response links to one.
+fn next_page(url: &Url, response: &Response) -> eyre::Result<Option<Url>> {
+    let Some(link) = response.headers().get(LINK) else {
+        return Ok(None);
+    };
+    let link = link.to_str().context("read link header")?;
+    resolve_next(url, link)
+}
+
+/// Resolve the target of the `rel="next"` link in the `Link` header against the URL of the current page.
+///
+/// The next page must be on the same origin as the current one: requests for it carry the registry's credentials,
+/// so a link to another origin is rejected rather than followed.
+fn resolve_next(url: &Url, link: &str) -> eyre::Result<Option<Url>> {
+    let Some(next) = parse_next(link) else {
+        return Ok(None);
+    };
+    let next = url.join(next).context("parse next page")?;
+    if next.origin() != url.origin() {
+        return Err(eyre::Report::new(Kind::Unsupported)).context(format!(
+            "registry linked the next catalog page to another origin: {next}"
+        ));
+    }
+    Ok(Some(next))
+}
+
+/// The target of the `rel="next"` link in a `Link` header (RFC 8288), like `</v2/_catalog?last=b&n=100>; rel="next"`.
+fn parse_next(header: &str) -> Option<&str> {
+    header.split(',').find_map(|link| {
+        let (target, params) = link.split_once(';')?;
+        let next = params.split(';').any(|param| {
+            let param = param.trim().replace(' ', "");
+            param.eq_ignore_ascii_case(r#"rel="next""#) || param.eq_ignore_ascii_case("rel=next")
+        });
+        let target = target.trim().strip_prefix('<')?.strip_suffix('>')?;
+        next.then_some(target)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    #[test_case(r#"Bearer realm="https://auth.example.com/token",service="registry.example.com""#, Some(("https://auth.example.com/token", Some("registry.example.com"))); "bearer")]
+    #[test_case(r#"bearer realm="https://auth.example.com/token", scope="repository:a:pull,push""#, Some(("https://auth.example.com/token", None)); "no_service")]
+    #[test_case(r#"Basic realm="registry""#, None; "basic")]
+    #[test_case(r#"Bearer service="registry""#, None; "no_realm")]
+    #[test]
+    fn challenge(header: &str, expected: Option<(&str, Option<&str>)>) {
+        let expected = expected.map(|(realm, service)| Challenge {
+            realm: realm.to_string(),
+            service: service.map(String::from),
+        });
+        pretty_assertions::assert_eq!(expected, parse_challenge(header));
+    }
+
+    #[test_case(r#"</v2/_catalog?last=b&n=2>; rel="next""#, Some("/v2/_catalog?last=b&n=2"); "relative")]
+    #[test_case(r#"<https://r.example.com/v2/_catalog?last=b&n=2>; rel=next"#, Some("https://r.example.com/v2/_catalog?last=b&n=2"); "absolute")]
+    #[test_case(r#"</v2/_catalog?n=2>; rel="prev", </v2/_catalog?last=d&n=2>; rel="next""#, Some("/v2/_catalog?last=d&n=2"); "several")]
+    #[test_case(r#"</v2/_catalog?n=2>; rel="prev""#, None; "no_next")]
+    #[test]
+    fn next(header: &str, expected: Option<&str>) {
+        pretty_assertions::assert_eq!(expected, parse_next(header));
+    }
+
+    #[test_case(r#"</v2/_catalog?last=b&n=2>; rel="next""#, Some("https://r.example.com/v2/_catalog?last=b&n=2"); "relative")]
+    #[test_case(r#"<https://r.example.com/v2/_catalog?last=b&n=2>; rel="next""#, Some("https://r.example.com/v2/_catalog?last=b&n=2"); "same_origin")]
+    #[test_case(r#"</v2/_catalog?n=2>; rel="prev""#, None; "no_next")]
+    #[test]
+    fn resolve(header: &str, expected: Option<&str>) {
+        let url = Url::parse("https://r.example.com/v2/_catalog?n=2").expect("parse url");
+        let next = resolve_next(&url, header).expect("resolve next page");
+        pretty_assertions::assert_eq!(expected, next.as_ref().map(Url::as_str));
+    }
+
+    #[test_case(r#"<https://attacker.example.com/v2/_catalog?last=b&n=2>; rel="next""#; "other_host")]
+    #[test_case(r#"<http://r.example.com/v2/_catalog?last=b&n=2>; rel="next""#; "other_scheme")]
+    #[test_case(r#"<https://r.example.com:8443/v2/_catalog?last=b&n=2>; rel="next""#; "other_port")]
+    #[test_case(r#"<//attacker.example.com/v2/_catalog?last=b&n=2>; rel="next""#; "scheme_relative")]
+    #[test]
+    fn rejects_cross_origin(header: &str) {
+        let url = Url::parse("https://r.example.com/v2/_catalog?n=2").expect("parse url");
+        let err = resolve_next(&url, header).expect_err("cross-origin link must be rejected");
+        assert!(format!("{err:#}").contains("another origin"), "{err:#}");
+    }
+}
@@ -0,0 +1,485 @@
+//! Selects a [`Source`] for an image at runtime.
+//!
+//! [`Source`] uses `async fn`, so it can't be used as a trait object;
+//! [`AnySource`] enumerates the implementations instead so that callers can handle them uniformly,
+//! and [`detect`] picks the implementation appropriate for a target.
+
+use std::{num::NonZeroU64, path::PathBuf, pin::Pin};
+
+use bon::Builder;
+use bytes::Bytes;
+use color_eyre::eyre::{self, Context};
+use derive_more::Debug;
+use futures_lite::Stream;
+use itertools::Itertools;
+
+use crate::{
+    error::Kind,
+    progress::SharedProgress,
+    registry::{Registry, Timeouts},
+    transform::Buffering,
+    Annotation, Authentication, CaseCollisions, Digest, Error, FilterCommands, Filters, Identity,
+    ImageConfig, Layer, Limits, Platform, PullPolicy, Reference, Result, Source, SourceKind,
+    Subject,
+};
+
+#[cfg(feature = "daemon")]
+use crate::docker::{Connection, Daemon};
+#[cfg(feature = "native")]
+use crate::{cache::Cache, docker::Tarball, Unpack};
+#[cfg(feature = "native")]
+use color_eyre::Section;
+
+/// Any implementation of [`Source`] supported by this library.
+//
+// Every variant is boxed: the sources differ widely in size,
+// and which of them is largest depends on the enabled features.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum AnySource {
+    /// A remote OCI registry.
+    Registry(Box<Registry>),
+
+    /// A local Docker daemon.
+    #[cfg(feature = "daemon")]
+    Daemon(Box<Daemon>),
+
+    /// A Docker tarball or an OCI image layout on disk.
+    #[cfg(feature = "native")]
+    Tarball(Box<Tarball>),
+}
+
+impl From<Registry> for AnySource {
+    fn from(source: Registry) -> Self {
+        Self::Registry(Box::new(source))
+    }
+}
+
+#[cfg(feature = "daemon")]
+impl From<Daemon> for AnySource {
+    fn from(source: Daemon) -> Self {
+        Self::Daemon(Box::new(source))
+    }
+}
+
+#[cfg(feature = "native")]
+impl From<Tarball> for AnySource {
+    fn from(source: Tarball) -> Self {
+        Self::Tarball(Box::new(source))
+    }
+}
+
+/// Forward a method call to the source wrapped by [`AnySource`].
+macro_rules! dispatch {
+    ($self:expr, $source:ident => $call:expr) => {
+        match $self {
+            AnySource::Registry($source) => $call,
+            #[cfg(feature = "daemon")]
+            AnySource::Daemon($source) => $call,
+            #[cfg(feature = "native")]
+            AnySource::Tarball($source) => $call,
+        }
+    };
+}
+
+impl Source for AnySource {
+    async fn digest(&self) -> Result<Digest> {
+        dispatch!(self, source => source.digest().await)
+    }
+
+    async fn name(&self) -> Result<String> {
+        dispatch!(self, source => source.name().await)
+    }
+
+    async fn identity(&self) -> Result<Identity> {
+        dispatch!(self, source => source.identity().await)
+    }
+
+    async fn layers(&self) -> Result<Vec<Layer>> {
+        dispatch!(self, source => source.layers().await)
+    }
+
+    async fn config(&self) -> Result<ImageConfig> {
+        dispatch!(self, source => source.config().await)
+    }
+
+    async fn pull_layer(
+        &self,
+        layer: &Layer,
+    ) -> Result<Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>> {
+        dispatch!(self, source => source.pull_layer(layer).await)
+    }
+
+    async fn subject(&self) -> Result<Option<Subject>> {
+        dispatch!(self, source => source.subject().await)
+    }
+}
+
+#[cfg(feature = "native")]
+impl Unpack for AnySource {
+    async fn list_files(&self, layer: &Layer) -> Result<Vec<String>> {
+        dispatch!(self, source => source.list_files(layer).await)
+    }
+
+    async fn visit_files(
+        &self,
+        layer: &Layer,
+        on_file: impl FnMut(String) -> std::io::Result<()>,
+    ) -> Result<()> {
+        dispatch!(self, source => source.visit_files(layer, on_file).await)
+    }
+
+    async fn apply_layer(&self, layer: &Layer, output: &std::path::Path) -> Result<()> {
+        dispatch!(self, source => source.apply_layer(layer, output).await)
+    }
+
+    async fn layer_plain_tarball(&self, layer: &Layer) -> Result<Option<async_tempfile::TempFile>> {
+        dispatch!(self, source => source.layer_plain_tarball(layer).await)
+    }
+}
+
+/// Options for [`detect`], applied to whichever source is selected.
+#[derive(Debug, Clone, Default, Builder)]
+pub struct Options {
+    /// The platform to select from multi-platform images,
+    /// whether they are in a registry, the Docker daemon, or a tarball.
+    #[builder(into)]
+    pub platform: Option<Platform>,
+
+    /// Annotations that an image must have to be selected from an image index in a registry.
+    ///
+    /// Images read from the Docker daemon or a tarball don't record annotations, so this only applies to registries.
+    #[builder(into)]
+    pub annotations: Option<Vec<Annotation>>,
+
+    /// The digest of the manifest to read from an image index, bypassing platform resolution;
+    /// this also allows reading manifests that are otherwise skipped, like attestations.
+    ///
+    /// The Docker daemon doesn't support selecting manifests by digest, so it's skipped when this is provided.
+    #[builder(into)]
+    pub manifest_digest: Option<Digest>,
+
+    /// Authentication for a registry.
+    ///
+    /// If not provided, credentials are read from the local Docker configuration
+    /// when the `docker-auth` feature is enabled.
+    pub auth: Option<Authentication>,
+
+    /// Only layers matched by the filters are processed; see [`Filters`].
+    #[builder(into)]
+    pub layer_filters: Option<Filters>,
+
+    /// Only files matched by the filters are processed; see [`Filters`].
+    #[builder(into)]
+    pub file_filters: Option<Filters>,
+
+    /// How to handle paths that collide on case-insensitive filesystems when layers are applied.
+    pub case_collisions: Option<CaseCollisions>,
+
+    /// Directory to which paths deleted by whiteouts are moved instead of being removed.
+    #[builder(into)]
+    pub deleted_dir: Option<PathBuf>,
+
+    /// Commands through which layers are piped after they are decompressed.
+    pub filter_commands: Option<FilterCommands>,
+
+    /// Safety limits enforced as layers are applied.
+    pub limits: Option<Limits>,
+
+    /// When the Docker daemon pulls the image before it's read; see [`PullPolicy`].
+    ///
+    /// This only applies to the Docker daemon.
+    pub pull_policy: Option<PullPolicy>,
+
+    /// How to connect to the Docker daemon, which may be remote; see [`Connection`].
+    #[cfg(feature = "daemon")]
+    pub daemon: Option<Connection>,
+
+    /// Receives progress updates as layers are read and applied.
+    #[debug(skip)]
+    pub progress: Option<SharedProgress>,
+
+    /// How layer content is buffered as it's read and unpacked.
+    pub buffering: Option<Buffering>,
+
+    /// Deadlines for requests to a registry.
+    ///
+    /// Images read from the Docker daemon or a tarball aren't requested over the network, so this only applies to registries.
+    pub timeouts: Option<Timeouts>,
+
+    /// The maximum rate in bytes per second at which layers are downloaded from a registry.
+    pub limit_rate: Option<NonZeroU64>,
+
+    /// The kind of source to read the image from; by default it's selected automatically.
+    pub kind: Option<SourceKind>,
+
+    /// Images stored ahead of time, which are read instead of the Docker daemon or the registry; see [`Cache`].
+    ///
+    /// The cache is only consulted when the source is selected automatically and no manifest digest is provided.
+    #[cfg(feature = "native")]
+    pub cache: Option<Cache>,
+}
+
+/// Select the source for a target, which may be a path or an image reference.
+///
+/// With [`SourceKind::Auto`] (the default for [`Options::kind`]), sources are tried in the same order as the `circe` CLI:
+/// 1. If the target is a directory, it is read as an OCI image layout;
+///    otherwise if it is a path that exists, it is read as a Docker tarball.
+/// 2. Otherwise, if [`Options::cache`] has the image for the platform, it is read from the cache.
+/// 3. Otherwise, the image is read from the Docker daemon if it has it, or if it pulls it
+///    according to [`Options::pull_policy`] (unless a manifest digest is provided, which the daemon doesn't support).
+/// 4. Otherwise, the image is pulled from its registry.
+///
+/// Sources that aren't enabled by features are skipped.
+/// If the target is a path, the tarball (or layout) is the only source read, and its error is reported if it can't be read.
+/// Otherwise, if every source fails, the error from the registry is reported,
+/// with the errors from the other sources that were tried in its context.
+/// When a source is selected instead, only that source is used, and its error is reported if it can't read the image.
+#[tracing::instrument]
+pub async fn detect(target: &str, opts: Options) -> Result<AnySource> {
+    match opts.kind.unwrap_or_default() {
+        SourceKind::Auto => {}
+        SourceKind::Registry => return registry(target, opts).await,
+        SourceKind::Daemon => return daemon(target, &opts).await,
+        SourceKind::Tarball => return tarball(target, opts).await,
+        SourceKind::OciLayout => return oci_layout(target, opts).await,
+    }
+
+    #[cfg(feature = "native")]
+    if crate::cio::is_dir(std::path::Path::new(target)).await {
+        tracing::debug!(?target, "target is a directory; reading as OCI layout");
+        return oci_layout(target, opts).await;
+    }
+
+    #[cfg(feature = "native")]
+    if tokio::fs::try_exists(target).await.unwrap_or_default() {
+        tracing::debug!(?target, "target is a path; reading as tarball");
+        return tarball(target, opts).await;
+    }
+
+    // The errors from the other sources are reported alongside the error from the registry if it fails too.
+    #[cfg_attr(not(any(feature = "native", feature = "daemon")), allow(unused_mut))]
+    let mut failed = Vec::new();
+
+    #[cfg(feature = "native")]
+    if let Some(path) = cached(target, &opts).await {
+        tracing::info!(?target, ?path, "reading image from cache");
+        match read_tarball(path, target.to_string(), opts.clone()).await {
+            Ok(cached) => return Ok(cached),
+            Err(err) => {
+                crate::metrics::warn!(?err, "image not readable from cache");
+                failed.push(("cache", err));
+            }
+        }
+    }
+
+    #[cfg(feature = "daemon")]
+    if opts.manifest_digest.is_some() {
+        tracing::debug!("manifest digest provided; skipping docker daemon");
+    } else {
+        match daemon(target, &opts).await {
+            Ok(daemon) => return Ok(daemon),
+            Err(err) => {
+                crate::metrics::warn!(?err, "image not available from docker daemon");
+                failed.push(("docker daemon", err));
+            }
+        }
+    }
+
+    match registry(target, opts).await {
+        Err(registry) if !failed.is_empty() => Err(Error::from(eyre::Report::new(Attempts {
+            failed,
+            registry,
+        }))),
+        result => result,
+    }
+}
+
+/// The errors from each source that [`detect`] tried when none of them could read the image.
+///
+/// The registry is tried last, and its error is the source of this one,
+/// so that it determines the kind of error that [`detect`] reports.
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "read image from registry (other sources also failed: {})",
+    failed.iter().map(|(name, err)| format!("{name}: {}", describe_chain(err))).join("; ")
+)]
+struct Attempts {
+    failed: Vec<(&'static str, Error)>,
+    #[source]
+    registry: Error,
+}
+
+/// Describe the error with each error in its chain, like `{:#}` does for reports.
+fn describe_chain(err: &(dyn std::error::Error + 'static)) -> String {
+    std::iter::successors(Some(err), |err| err.source())
+        .map(|err| err.to_string())
+        .join(": ")
+}
+
+/// Report that a source can't be selected because the feature that provides it isn't enabled.
+#[cfg(not(all(feature = "native", feature = "daemon")))]
+fn disabled(kind: SourceKind, feature: &str) -> Result<AnySource> {
+    Err(eyre::Report::new(Kind::Unsupported))
+        .context(format!("source '{kind}' requires the '{feature}' feature"))
+        .map_err(Error::from)
+}
+
+/// Read the target as a path to a tarball.
+#[cfg(feature = "native")]
+async fn tarball(target: &str, opts: Options) -> Result<AnySource> {
+    let path = PathBuf::from(target);
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| target.to_string());
+    read_tarball(path, name, opts).await
+}
+
+/// The path of the image in [`Options::cache`], if it has been stored there.
+#[cfg(feature = "native")]
+async fn cached(target: &str, opts: &Options) -> Option<PathBuf> {
+    if opts.manifest_digest.is_some() {
+        return None;
+    }
+    let cache = opts.cache.as_ref()?;
+    let reference = target.parse::<Reference>().ok()?;
+    cache.lookup(&reference, opts.platform.as_ref()).await
+}
+
+/// Read the tarball at the path, naming the image with the name.
+#[cfg(feature = "native")]
+async fn read_tarball(path: PathBuf, name: String, opts: Options) -> Result<AnySource> {
+    let tarball = Tarball::builder()
+        .path(&path)
+        .name(name)
+        .maybe_platform(opts.platform)
+        .maybe_manifest_digest(opts.manifest_digest)
+        .maybe_layer_filters(opts.layer_filters)
+        .maybe_file_filters(opts.file_filters)
+        .maybe_case_collisions(opts.case_collisions)
+        .maybe_deleted_dir(opts.deleted_dir)
+        .maybe_filter_commands(opts.filter_commands)
+        .maybe_limits(opts.limits)
+        .maybe_progress(opts.progress)
+        .maybe_buffering(opts.buffering)
+        .build()
+        .await?;
+
+    // Missing layers can't be downloaded instead when offline, so report all of them up front.
+    if crate::is_offline() {
+        let missing = tarball.missing_layers().await?;
+        if !missing.is_empty() {
+            return Err(eyre::Report::new(Kind::NotFound))
+                .context(format!(
+                    "layers missing from {}: {}",
+                    path.display(),
+                    missing.iter().join(", ")
+                ))
+                .map_err(Error::from);
+        }
+    }
+    Ok(AnySource::from(tarball))
+}
+
+#[cfg(not(feature = "native"))]
+async fn tarball(_: &str, _: Options) -> Result<AnySource> {
+    disabled(SourceKind::Tarball, "native")
+}
+
+/// Read the target as a path to an OCI image layout, either a tarball or a directory.
+///
+/// Layouts are read the same way as tarballs written by `docker save`,
+/// but the `oci-layout` file that marks the layout must be present.
+#[cfg(feature = "native")]
+async fn oci_layout(target: &str, opts: Options) -> Result<AnySource> {
+    let path = std::path::Path::new(target);
+    let is_layout = |path: &std::path::Path| path.ends_with("oci-layout");
+    if crate::cio::extract_file(path, Buffering::default(), is_layout)
+        .await?
+        .is_none()
+    {
+        return Err(eyre::Report::new(Kind::NotFound))
+            .context(format!("find 'oci-layout' in {path:?}"))
+            .with_note(|| {
+                "the path isn't an OCI image layout; use '--source tarball' to read it anyway"
+            })
+            .map_err(Error::from);
+    }
+
+    tarball(target, opts).await
+}
+
+#[cfg(not(feature = "native"))]
+async fn oci_layout(_: &str, _: Options) -> Result<AnySource> {
+    disabled(SourceKind::OciLayout, "native")
+}
+
+/// Read the target as a reference to an image in the Docker daemon.
+#[cfg(feature = "daemon")]
+async fn daemon(target: &str, opts: &Options) -> Result<AnySource> {
+    if opts.manifest_digest.is_some() {
+        return Err(eyre::Report::new(Kind::Unsupported))
+            .context("select manifest by digest from docker daemon")
+            .map_err(Error::from);
+    }
+
+    Daemon::builder()
+        .reference(target)
+        .maybe_platform(opts.platform.clone())
+        .maybe_layer_filters(opts.layer_filters.clone())
+        .maybe_file_filters(opts.file_filters.clone())
+        .maybe_case_collisions(opts.case_collisions)
+        .maybe_deleted_dir(opts.deleted_dir.clone())
+        .maybe_filter_commands(opts.filter_commands.clone())
+        .maybe_limits(opts.limits)
+        .maybe_progress(opts.progress.clone())
+        .maybe_buffering(opts.buffering)
+        .maybe_pull_policy(opts.pull_policy)
+        .maybe_connection(opts.daemon.clone())
+        .build()
+        .await
+        .map(AnySource::from)
+}
+
+#[cfg(not(feature = "daemon"))]
+async fn daemon(_: &str, _: &Options) -> Result<AnySource> {
+    disabled(SourceKind::Daemon, "daemon")
+}
+
+/// Read the target as a reference to an image in a registry.
+async fn registry(target: &str, opts: Options) -> Result<AnySource> {
+    let reference = target.parse::<Reference>()?;
+    crate::flag_disallowed_host(&reference.host)?;
+    let auth = match opts.auth {
+        Some(auth) => auth,
+        #[cfg(feature = "docker-auth")]
+        None => Authentication::docker(&reference).await?,
+        #[cfg(not(feature = "docker-auth"))]
+        None => Authentication::default(),
+    };
+
+    let registry = Registry::builder()
+        .maybe_platform(opts.platform)
+        .maybe_annotations(opts.annotations)
+        .maybe_manifest_digest(opts.manifest_digest)
+        .reference(reference)
+        .auth(auth)
+        .maybe_layer_filters(opts.layer_filters)
+        .maybe_file_filters(opts.file_filters)
+        .maybe_case_collisions(opts.case_collisions)
+        .maybe_deleted_dir(opts.deleted_dir)
+        .maybe_filter_commands(opts.filter_commands)
+        .maybe_limits(opts.limits)
+        .maybe_progress(opts.progress)
+        .maybe_buffering(opts.buffering)
+        .maybe_timeouts(opts.timeouts)
+        .maybe_limit_rate(opts.limit_rate)
+        .build()
+        .await?;
+
+    // Check that the image exists first, so that a missing image or missing credentials are reported as such
+    // instead of as whichever failure pulling the manifest runs into.
+    registry.require_exists().await?;
+    Ok(AnySource::from(registry))
+}
@@ -1,41 +1,138 @@
 //! Primitives for stream transformations.
 
-use std::pin::Pin;
+use std::{
+    num::{NonZeroU64, NonZeroUsize},
+    pin::Pin,
+    time::Duration,
+};
 
 use async_compression::tokio::bufread::{GzipDecoder, ZstdDecoder};
+use bon::Builder;
 use bytes::Bytes;
-use color_eyre::Result;
-use futures_lite::Stream;
+use color_eyre::{Report, Result};
+use futures_lite::{Stream, StreamExt};
+use tokio::time::Instant;
 use tokio_util::io::{ReaderStream, StreamReader};
+use tracing::debug;
 
-use crate::LayerMediaTypeFlag;
+use crate::{
+    error::{BoxError, Kind},
+    metrics::warn,
+    Layer, LayerMediaType, LayerMediaTypeFlag,
+};
 
 /// Convenience alias for a chunk of bytes in a stream.
 pub type Chunk = Result<Bytes, std::io::Error>;
 
+/// The default size of the chunks read from decompressors and tarballs, matching [`ReaderStream`].
+pub const DEFAULT_CHUNK_SIZE: NonZeroUsize = NonZeroUsize::new(4 * 1024).unwrap();
+
+/// How layer content is buffered as it streams from the network through decompression to the tar reader.
+///
+/// Larger chunks mean fewer, larger reads at each stage, at the cost of more memory per layer.
+/// Read-ahead downloads layer content while earlier content is still being decompressed and unpacked,
+/// which mostly helps on high-latency links; it only applies to layers pulled from a registry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Builder)]
+pub struct Buffering {
+    /// The size in bytes of the chunks read from decompressors and tarballs;
+    /// content downloaded ahead is also gathered into chunks of this size.
+    #[builder(default = DEFAULT_CHUNK_SIZE)]
+    pub chunk_size: NonZeroUsize,
+
+    /// The number of bytes of a layer to download ahead of the content being unpacked.
+    /// Read-ahead is disabled when this is zero.
+    #[builder(default)]
+    pub read_ahead: usize,
+}
+
+impl Default for Buffering {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
 /// Identity transformer.
 pub fn identity(stream: impl Stream<Item = Chunk>) -> impl Stream<Item = Chunk> {
     stream
 }
 
+/// Throttle the stream to the rate in bytes per second, if any.
+///
+/// The stream is limited with a token bucket that starts full and holds up to one second of content:
+/// each chunk is passed through as it's read, then the stream waits until the bucket has refilled enough to cover it.
+/// While the stream waits it isn't read, which applies backpressure to whatever feeds it (e.g. the connection).
+pub fn limit_rate(
+    stream: impl Stream<Item = Chunk> + Send + 'static,
+    rate: Option<NonZeroU64>,
+) -> Pin<Box<dyn Stream<Item = Chunk> + Send>> {
+    let Some(rate) = rate else {
+        return Box::pin(stream);
+    };
+
+    let rate = rate.get() as f64;
+    Box::pin(async_stream::stream! {
+        let mut stream = std::pin::pin!(stream);
+        let mut tokens = rate;
+        let mut refilled = Instant::now();
+        while let Some(chunk) = stream.next().await {
+            let size = chunk.as_ref().map(Bytes::len).unwrap_or_default();
+            yield chunk;
+
+            let now = Instant::now();
+            tokens = (tokens + now.duration_since(refilled).as_secs_f64() * rate).min(rate);
+            refilled = now;
+            tokens -= size as f64;
+            if tokens < 0.0 {
+                tokio::time::sleep(Duration::from_secs_f64(-tokens / rate)).await;
+            }
+        }
+    })
+}
+
+/// End the stream with an error once more than `max` bytes have been read from it, if a maximum is set.
+///
+/// Decompressed layers are limited this way so that a layer which decompresses to far more than its
+/// distributed size fails as soon as it exceeds the limit, rather than after it has been fully read.
+// Layers are only limited when they're applied.
+#[cfg_attr(not(feature = "native"), allow(dead_code))]
+pub(crate) fn limit_size(
+    stream: impl Stream<Item = Chunk> + Unpin,
+    max: Option<u64>,
+) -> impl Stream<Item = Chunk> + Unpin {
+    let mut read = 0u64;
+    stream.map(move |chunk| {
+        let chunk = chunk?;
+        read = read.saturating_add(chunk.len() as u64);
+        match max {
+            Some(max) if read > max => {
+                let err = Report::new(Kind::Limit)
+                    .wrap_err(format!("layer decompresses to more than {max} bytes"));
+                Err(std::io::Error::other(BoxError::from(err)))
+            }
+            _ => Ok(chunk),
+        }
+    })
+}
+
 /// Decompress the stream using gzip.
-pub fn gzip(stream: impl Stream<Item = Chunk>) -> impl Stream<Item = Chunk> {
+pub fn gzip(stream: impl Stream<Item = Chunk>, buffering: Buffering) -> impl Stream<Item = Chunk> {
     let reader = StreamReader::new(stream);
     let inner = GzipDecoder::new(reader);
-    ReaderStream::new(inner)
+    ReaderStream::with_capacity(inner, buffering.chunk_size.get())
 }
 
 /// Decompress the stream using zstd.
-pub fn zstd(stream: impl Stream<Item = Chunk>) -> impl Stream<Item = Chunk> {
+pub fn zstd(stream: impl Stream<Item = Chunk>, buffering: Buffering) -> impl Stream<Item = Chunk> {
     let reader = StreamReader::new(stream);
     let inner = ZstdDecoder::new(reader);
-    ReaderStream::new(inner)
+    ReaderStream::with_capacity(inner, buffering.chunk_size.get())
 }
 
 /// Apply a sequence of transformations to the stream based on the media type flags.
 pub fn sequence(
     stream: impl Stream<Item = Chunk> + 'static,
     flags: &[LayerMediaTypeFlag],
+    buffering: Buffering,
 ) -> Pin<Box<dyn Stream<Item = Chunk>>> {
     // Left hand side type annotation is required to coerce to dynamic dispatching.
     let mut stream: Pin<Box<dyn Stream<Item = Chunk>>> = Box::pin(stream);
@@ -43,8 +140,8 @@ pub fn sequence(
     // Each flag in order consumes the prior stream, replacing it with a new transformed stream.
     for flag in flags {
         match flag {
-            LayerMediaTypeFlag::Zstd => stream = Box::pin(zstd(stream)),
-            LayerMediaTypeFlag::Gzip => stream = Box::pin(gzip(stream)),
+            LayerMediaTypeFlag::Zstd => stream = Box::pin(zstd(stream, buffering)),
+            LayerMediaTypeFlag::Gzip => stream = Box::pin(gzip(stream, buffering)),
             _ => (),
         }
     }
@@ -52,3 +149,44 @@ pub fn sequence(
     // The final stream is therefore the sequenced version of the stream.
     stream
 }
+
+/// Transform an OCI image layer (based on its media type) into its underlying tarball.
+/// Foreign layers return `None`.
+#[tracing::instrument(skip(stream))]
+pub fn peel_layer(
+    layer: &Layer,
+    stream: impl Stream<Item = Chunk> + Unpin + 'static,
+    buffering: Buffering,
+) -> Option<Pin<Box<dyn Stream<Item = Chunk>>>> {
+    // Applying the layer requires interpreting the layer's media type.
+    match &layer.media_type {
+        // Standard OCI layers.
+        LayerMediaType::Oci(flags) => {
+            // Foreign layers are skipped, as they would if you ran `docker pull`.
+            // This causes an extra iteration over the flags for layers that aren't foreign,
+            // but the flag count is small and this saves us the complexity of setting up layer transforms
+            // and then discarding them if this flag is encountered.
+            if flags.contains(&LayerMediaTypeFlag::Foreign) {
+                warn!("skip: foreign layer");
+                return None;
+            }
+
+            debug!(?flags, "peel layer");
+
+            Some(match flags.as_slice() {
+                // No flags; this means the layer is uncompressed.
+                [] => Box::pin(stream),
+
+                // The layer is compressed with zstd.
+                [LayerMediaTypeFlag::Zstd] => Box::pin(zstd(stream, buffering)),
+
+                // The layer is compressed with gzip.
+                [LayerMediaTypeFlag::Gzip] => Box::pin(gzip(stream, buffering)),
+
+                // The layer has a more complicated set of flags.
+                // For this, we fall back to the generic sequence operator.
+                _ => Box::pin(sequence(stream, flags, buffering)),
+            })
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! Storing images from a [`MockRegistry`] in a [`Cache`] and reading them back without the registry.
+
+use std::{num::NonZeroUsize, path::Path};
+
+use async_tempfile::TempDir;
+use circe_lib::{
+    cache::Cache,
+    docker::Tarball,
+    registry::Registry,
+    source::{self, AnySource},
+    Digest, Platform, Source, Unpack,
+};
+use color_eyre::Result;
+use futures_lite::StreamExt;
+
+use crate::mock::{self, Auth, MockRegistry};
+
+#[test_log::test(tokio::test)]
+async fn store_and_read() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let base = mock::tarball(&[("etc/os-release", b"ID=mock\n")]).await?;
+    let app = mock::tarball(&[("app/main.sh", b"echo hello\n")]).await?;
+    let digest = mock.push_image("team/app", "1.0", &Platform::linux_amd64(), &[base, app]);
+
+    let dir = TempDir::new().await?;
+    let cache = Cache::new(dir.dir_path());
+    let reference = mock.reference("team", "app", "1.0");
+    let registry = Registry::builder()
+        .reference(reference.clone())
+        .build()
+        .await?;
+    let concurrency = NonZeroUsize::new(2).expect("nonzero");
+    let stored = cache.store(&registry, None, concurrency).await?;
+    pretty_assertions::assert_eq!(digest, stored.digest);
+    pretty_assertions::assert_eq!(2, stored.layers);
+    pretty_assertions::assert_eq!(
+        Some(stored.path.clone()),
+        cache.lookup(&reference, None).await
+    );
+
+    let tarball = Tarball::builder()
+        .name("app")
+        .path(&stored.path)
+        .build()
+        .await?;
+    pretty_assertions::assert_eq!(digest, tarball.digest().await?);
+    pretty_assertions::assert_eq!(
+        Vec::<circe_lib::Digest>::new(),
+        tarball.missing_layers().await?
+    );
+
+    let output = TempDir::new().await?;
+    for layer in tarball.layers().await? {
+        tarball.apply_layer(&layer, output.dir_path()).await?;
+    }
+    let read = |path: &str| std::fs::read_to_string(output.dir_path().join(Path::new(path)));
+    pretty_assertions::assert_eq!("ID=mock\n", read("etc/os-release")?);
+    pretty_assertions::assert_eq!("echo hello\n", read("app/main.sh")?);
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn detect_reads_cached_image() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let layer = mock::tarball(&[("etc/os-release", b"ID=mock\n")]).await?;
+    mock.push_image("team/app", "1.0", &Platform::linux_amd64(), &[layer]);
+
+    let dir = TempDir::new().await?;
+    let cache = Cache::new(dir.dir_path());
+    let reference = mock.reference("team", "app", "1.0");
+    let registry = Registry::builder()
+        .reference(reference.clone())
+        .build()
+        .await?;
+    let concurrency = NonZeroUsize::new(1).expect("nonzero");
+    cache.store(&registry, None, concurrency).await?;
+
+    let target = reference.to_string();
+    let opts = source::Options::builder().cache(cache.clone()).build();
+    let source = source::detect(&target, opts).await?;
+    assert!(matches!(source, AnySource::Tarball(_)), "{source:?}");
+
+    // Images are stored per platform, so other platforms aren't served from the cache.
+    let missing = cache
+        .lookup(&reference, Some(&Platform::linux_arm64()))
+        .await;
+    pretty_assertions::assert_eq!(None, missing);
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn layouts() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let base = mock::tarball(&[("etc/os-release", b"ID=mock\n")]).await?;
+    let app = mock::tarball(&[("app/main.sh", b"echo hello\n")]).await?;
+    let base_digest = mock.push_image(
+        "base",
+        "latest",
+        &Platform::linux_amd64(),
+        std::slice::from_ref(&base),
+    );
+    let app_digest = mock.push_image("team/app", "1.0", &Platform::linux_amd64(), &[base, app]);
+
+    let dir = TempDir::new().await?;
+    let cache = Cache::new(dir.dir_path());
+    let concurrency = NonZeroUsize::new(2).expect("nonzero");
+    let references = [
+        mock.reference("", "base", "latest"),
+        mock.reference("team", "app", "1.0"),
+    ];
+    for reference in &references {
+        let registry = Registry::builder()
+            .reference(reference.clone())
+            .build()
+            .await?;
+        cache.store(&registry, None, concurrency).await?;
+    }
+
+    // Files that aren't stored images are skipped.
+    std::fs::write(dir.dir_path().join("notes.tar"), "not a tarball")?;
+    std::fs::write(dir.dir_path().join("image.tar.partial"), "interrupted")?;
+
+    let layouts = cache.layouts().await?;
+    pretty_assertions::assert_eq!(
+        vec![
+            (references[0].clone(), base_digest),
+            (references[1].clone(), app_digest)
+        ],
+        layouts
+            .iter()
+            .map(|layout| (layout.reference.clone(), layout.digest.clone()))
+            .collect::<Vec<_>>()
+    );
+
+    for layout in &layouts {
+        let manifest = layout.manifest().await?;
+        pretty_assertions::assert_eq!(layout.digest, mock::digest(&manifest));
+
+        let manifest = serde_json::from_slice::<serde_json::Value>(&manifest)?;
+        let layers = manifest["layers"].as_array().expect("layers");
+        for descriptor in layers.iter().chain([&manifest["config"]]) {
+            let digest = descriptor["digest"]
+                .as_str()
+                .expect("digest")
+                .parse::<Digest>()?;
+            let mut content = Vec::new();
+            let mut blob = layout.blob(&digest).await?;
+            while let Some(chunk) = blob.next().await {
+                content.extend_from_slice(&chunk?);
+            }
+            pretty_assertions::assert_eq!(digest, mock::digest(&content));
+            pretty_assertions::assert_eq!(Some(content.len() as u64), layout.blob_size(&digest));
+        }
+    }
+
+    let missing = mock::digest(b"missing");
+    pretty_assertions::assert_eq!(None, layouts[0].blob_size(&missing));
+    assert!(layouts[0].blob(&missing).await.is_err());
+    Ok(())
+}
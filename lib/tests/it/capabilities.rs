@@ -0,0 +1,29 @@
+use circe_lib::{capabilities::Capabilities, LayerMediaType};
+
+#[test]
+fn media_types_parse() {
+    let capabilities = Capabilities::current();
+    for media_type in &capabilities.media_types {
+        if let Err(err) = media_type.parse::<LayerMediaType>() {
+            panic!("reported media type '{media_type}' doesn't parse: {err:?}");
+        }
+    }
+}
+
+#[test]
+fn compression_media_types() {
+    let capabilities = Capabilities::current();
+    for codec in &capabilities.compression {
+        let media_type = format!("application/vnd.oci.image.layer.v1.tar+{codec}");
+        assert!(
+            capabilities.media_types.contains(&media_type),
+            "codec '{codec}' is reported without its media type"
+        );
+    }
+}
+
+#[test]
+fn registry_always_supported() {
+    let capabilities = Capabilities::current();
+    assert!(capabilities.sources.contains(&"registry"));
+}
@@ -0,0 +1,136 @@
+//! Listing repositories in a [`MockRegistry`] with [`Catalog`].
+
+use std::num::NonZeroUsize;
+
+use circe_lib::{registry::Catalog, Authentication, Error, Filters, Platform};
+use color_eyre::Result;
+use simple_test_case::test_case;
+
+use crate::mock::{self, Auth, MockRegistry};
+
+const REPOSITORIES: [&str; 5] = ["base", "team/api", "team/app", "team/web", "tools"];
+
+async fn push_repositories(mock: &MockRegistry) -> Result<()> {
+    let layer = mock::tarball(&[("etc/os-release", b"ID=mock\n")]).await?;
+    for repository in REPOSITORIES {
+        mock.push_image(
+            repository,
+            "1.0",
+            &Platform::linux_amd64(),
+            std::slice::from_ref(&layer),
+        );
+    }
+    mock.push_image("team/app", "2.0", &Platform::linux_amd64(), &[layer]);
+    Ok(())
+}
+
+/// The distinct catalog pages requested, since requests are retried after authenticating.
+fn catalog_pages(mock: &MockRegistry) -> Vec<String> {
+    let mut pages = mock
+        .requests()
+        .into_iter()
+        .map(|request| request.path)
+        .filter(|path| path.starts_with("/v2/_catalog"))
+        .collect::<Vec<_>>();
+    pages.dedup();
+    pages
+}
+
+#[test_case(Auth::Anonymous, None; "anonymous")]
+#[test_case(Auth::Bearer(None), None; "bearer")]
+#[test_case(Auth::Bearer(Some((String::from("user"), String::from("pass")))), Some(Authentication::basic("user", "pass")); "bearer_with_credentials")]
+#[test_case(Auth::Basic(String::from("user"), String::from("pass")), Some(Authentication::basic("user", "pass")); "basic")]
+#[test_log::test(tokio::test)]
+async fn list_repositories(auth: Auth, credentials: Option<Authentication>) -> Result<()> {
+    let mock = MockRegistry::start(auth).await?;
+    push_repositories(&mock).await?;
+
+    let catalog = Catalog::builder()
+        .host(mock.host())
+        .maybe_auth(credentials)
+        .page_size(NonZeroUsize::new(2).expect("nonzero"))
+        .build()?;
+    pretty_assertions::assert_eq!(REPOSITORIES.to_vec(), catalog.repositories().await?);
+
+    pretty_assertions::assert_eq!(
+        vec![
+            "/v2/_catalog?n=2",
+            "/v2/_catalog?last=team/api&n=2",
+            "/v2/_catalog?last=team/web&n=2",
+        ],
+        catalog_pages(&mock)
+    );
+    Ok(())
+}
+
+#[test_case(Filters::parse_glob(["team/*"]), None, vec!["team/api", "team/app", "team/web"]; "glob")]
+#[test_case(Filters::parse_regex(["^t"]), Some(2), vec!["team/api", "team/app"]; "regex_limit")]
+#[test_case(Filters::parse_glob(["team/*"]).map(Filters::exclude), None, vec!["base", "tools"]; "exclude")]
+#[test_log::test(tokio::test)]
+async fn list_repositories_filtered(
+    filters: circe_lib::Result<Filters>,
+    limit: Option<usize>,
+    expected: Vec<&str>,
+) -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    push_repositories(&mock).await?;
+
+    let catalog = Catalog::builder()
+        .host(mock.host())
+        .filters(filters?)
+        .maybe_limit(limit.and_then(NonZeroUsize::new))
+        .page_size(NonZeroUsize::new(2).expect("nonzero"))
+        .build()?;
+    pretty_assertions::assert_eq!(expected, catalog.repositories().await?);
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn list_repositories_limit_stops_paging() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    push_repositories(&mock).await?;
+
+    let catalog = Catalog::builder()
+        .host(mock.host())
+        .limit(NonZeroUsize::new(1).expect("nonzero"))
+        .page_size(NonZeroUsize::new(2).expect("nonzero"))
+        .build()?;
+    pretty_assertions::assert_eq!(vec!["base"], catalog.repositories().await?);
+
+    pretty_assertions::assert_eq!(vec!["/v2/_catalog?n=2"], catalog_pages(&mock));
+    Ok(())
+}
+
+#[test_case(Auth::Anonymous, Some("404 Not Found"), None; "unsupported")]
+#[test_case(Auth::Anonymous, Some("403 Forbidden"), None; "forbidden")]
+#[test_case(Auth::Bearer(Some((String::from("user"), String::from("pass")))), None, None; "bearer_anonymous")]
+#[test_case(Auth::Bearer(Some((String::from("user"), String::from("pass")))), None, Some(Authentication::basic("user", "wrong")); "bearer_wrong_credentials")]
+#[test_case(Auth::Basic(String::from("user"), String::from("pass")), None, Some(Authentication::basic("user", "wrong")); "basic_wrong_credentials")]
+#[test_log::test(tokio::test)]
+async fn list_repositories_fails(
+    auth: Auth,
+    failure: Option<&'static str>,
+    credentials: Option<Authentication>,
+) -> Result<()> {
+    let mock = MockRegistry::start(auth).await?;
+    push_repositories(&mock).await?;
+    if let Some(status) = failure {
+        mock.fail("/v2/_catalog", status);
+    }
+
+    let catalog = Catalog::builder()
+        .host(mock.host())
+        .maybe_auth(credentials)
+        .build()?;
+    let err = catalog
+        .repositories()
+        .await
+        .expect_err("repositories must not be listed");
+    let chain = format!("{err:?}");
+    match failure {
+        Some("404 Not Found") => assert!(matches!(err, Error::Unsupported(_)), "{chain}"),
+        _ => assert!(matches!(err, Error::Auth(_)), "{chain}"),
+    }
+    assert!(chain.contains(mock.host()), "{chain}");
+    Ok(())
+}
@@ -0,0 +1,45 @@
+use circe_lib::{Digest, DigestAlgorithm};
+use simple_test_case::test_case;
+
+const SHA256: &str = "a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4";
+const SHA512: &str = "cf83e1357eefb8bdf1542850d66d8007d620e4050b5715dc83f4a921d36ce9ce47d0d13c5d85f2b0ff8318d2877eec2f63b931bd47417a81a538327af927da3e";
+
+#[test_case("sha256", SHA256, DigestAlgorithm::Sha256; "sha256")]
+#[test_case("sha512", SHA512, DigestAlgorithm::Sha512; "sha512")]
+#[test]
+fn parse(algorithm: &str, hex: &str, expected: DigestAlgorithm) {
+    let input = format!("{algorithm}:{hex}");
+    let digest = input.parse::<Digest>().expect("parse digest");
+    pretty_assertions::assert_eq!(expected, digest.algorithm);
+    pretty_assertions::assert_eq!(expected.hash_len(), digest.hash.len());
+    pretty_assertions::assert_eq!(input, digest.to_string());
+}
+
+#[test]
+fn macro_sha512() {
+    let digest = circe_lib::digest!(Digest::SHA512, "cf83e1357eefb8bdf1542850d66d8007d620e4050b5715dc83f4a921d36ce9ce47d0d13c5d85f2b0ff8318d2877eec2f63b931bd47417a81a538327af927da3e");
+    pretty_assertions::assert_eq!(DigestAlgorithm::Sha512, digest.algorithm);
+    pretty_assertions::assert_eq!(SHA512, digest.as_hex());
+}
+
+#[test_case("md5:d41d8cd98f00b204e9800998ecf8427e", "unsupported digest algorithm: 'md5'"; "unsupported_algorithm")]
+#[test_case("sha256:a3ed95caeb02", "invalid sha256 digest: must be 64 hex characters, but is 12"; "sha256_short")]
+#[test_case("sha512:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4", "invalid sha512 digest: must be 128 hex characters, but is 64"; "sha512_as_sha256")]
+#[test_case("sha256:A3ED95CAEB02FFE68CDD9FD84406680AE93D633CB16422D00E8A7C22955B46D4", "invalid sha256 digest: must contain only lowercase hex characters"; "uppercase")]
+#[test_case("sha256:z3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4", "invalid sha256 digest: must contain only lowercase hex characters"; "not_hex")]
+#[test_case("a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4", "missing algorithm separator"; "no_algorithm")]
+#[test]
+fn parse_invalid(input: &str, message: &str) {
+    let err = input.parse::<Digest>().expect_err("must error");
+    assert!(
+        format!("{err:?}").contains(message),
+        "expected '{message}' in error: {err:?}"
+    );
+}
+
+#[test]
+fn from_sha256_validates_length() {
+    let _ = Digest::from_sha256("a3ed95caeb02").expect_err("must error");
+    let digest = Digest::from_sha256(SHA256).expect("parse digest");
+    pretty_assertions::assert_eq!(DigestAlgorithm::Sha256, digest.algorithm);
+}
@@ -0,0 +1,175 @@
+use std::path::Path;
+
+use bytes::Bytes;
+use circe_lib::{entries::EntryKind, LayerMediaTypeFlag, Unpack};
+use color_eyre::Result;
+use futures_lite::StreamExt;
+use tokio::io::AsyncReadExt;
+
+use crate::memory::{layer, Memory};
+
+async fn tarball() -> Result<Bytes> {
+    let mut builder = tokio_tar::Builder::new(Vec::new());
+
+    let mut header = tokio_tar::Header::new_gnu();
+    header.set_entry_type(tokio_tar::EntryType::Directory);
+    header.set_size(0);
+    header.set_mode(0o755);
+    header.set_cksum();
+    builder
+        .append_data(&mut header, "etc", tokio::io::empty())
+        .await?;
+
+    let content = b"ID=test\n";
+    let mut header = tokio_tar::Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder
+        .append_data(&mut header, "etc/os-release", &content[..])
+        .await?;
+
+    let mut header = tokio_tar::Header::new_gnu();
+    header.set_size(0);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder
+        .append_data(&mut header, "tmp/.wh.removed", tokio::io::empty())
+        .await?;
+
+    Ok(Bytes::from(builder.into_inner().await?))
+}
+
+#[test_log::test(tokio::test)]
+async fn entries_read_without_extracting() -> Result<()> {
+    let source = Memory {
+        layer: tarball().await?,
+    };
+
+    let mut entries = source
+        .entries(&layer(vec![]))
+        .await?
+        .expect("layer is not foreign");
+
+    let dir = entries.next().await.expect("dir entry")?;
+    pretty_assertions::assert_eq!(Path::new("etc"), dir.path());
+    pretty_assertions::assert_eq!(EntryKind::Directory, dir.kind());
+    drop(dir);
+
+    let mut file = entries.next().await.expect("file entry")?;
+    pretty_assertions::assert_eq!(Path::new("etc/os-release"), file.path());
+    pretty_assertions::assert_eq!(EntryKind::File, file.kind());
+    pretty_assertions::assert_eq!(0o644, file.mode());
+    let mut content = String::new();
+    file.read_to_string(&mut content).await?;
+    pretty_assertions::assert_eq!("ID=test\n", content);
+    drop(file);
+
+    let whiteout = entries.next().await.expect("whiteout entry")?;
+    pretty_assertions::assert_eq!(
+        Some(Path::new("tmp/removed").to_path_buf()),
+        whiteout.whiteout()
+    );
+    drop(whiteout);
+
+    assert!(entries.next().await.is_none(), "no more entries");
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn entries_foreign_layer() -> Result<()> {
+    let source = Memory {
+        layer: tarball().await?,
+    };
+
+    let entries = source
+        .entries(&layer(vec![LayerMediaTypeFlag::Foreign]))
+        .await?;
+    assert!(entries.is_none(), "foreign layers have no entries");
+    Ok(())
+}
+
+/// Encode a PAX extension record, which is prefixed by its own length in bytes.
+///
+/// Only supports records whose length is two digits, which is plenty for tests.
+fn pax_record(key: &str, value: &str) -> String {
+    let body = format!(" {key}={value}\n");
+    let len = body.len() + 2;
+    assert!((10..100).contains(&len), "record length must be two digits");
+    format!("{len}{body}")
+}
+
+#[test_log::test(tokio::test)]
+async fn headers_include_pax_records() -> Result<()> {
+    let mut builder = tokio_tar::Builder::new(Vec::new());
+
+    let records = [
+        pax_record("path", "etc/renamed"),
+        pax_record("SCHILY.xattr.user.test", "value"),
+    ]
+    .concat();
+    let mut header = tokio_tar::Header::new_ustar();
+    header.set_entry_type(tokio_tar::EntryType::XHeader);
+    header.set_size(records.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder
+        .append_data(&mut header, "PaxHeaders/os-release", records.as_bytes())
+        .await?;
+
+    let content = b"ID=test\n";
+    let mut header = tokio_tar::Header::new_ustar();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o644);
+    header.set_uid(1000);
+    header.set_gid(1000);
+    header.set_cksum();
+    builder
+        .append_data(&mut header, "etc/os-release", &content[..])
+        .await?;
+
+    let source = Memory {
+        layer: Bytes::from(builder.into_inner().await?),
+    };
+
+    let headers = source
+        .headers(&layer(vec![]))
+        .await?
+        .expect("layer is not foreign");
+    pretty_assertions::assert_eq!(2, headers.len());
+
+    let pax = &headers[0];
+    pretty_assertions::assert_eq!('x', pax.typeflag);
+    pretty_assertions::assert_eq!(EntryKind::Other, pax.kind);
+    pretty_assertions::assert_eq!(
+        vec![
+            (String::from("path"), String::from("etc/renamed")),
+            (
+                String::from("SCHILY.xattr.user.test"),
+                String::from("value")
+            ),
+        ],
+        pax.pax
+    );
+
+    let file = &headers[1];
+    pretty_assertions::assert_eq!('0', file.typeflag);
+    pretty_assertions::assert_eq!(Path::new("etc/os-release"), file.path);
+    pretty_assertions::assert_eq!(Some(1000), file.uid);
+    pretty_assertions::assert_eq!(Some(1000), file.gid);
+    pretty_assertions::assert_eq!(content.len() as u64, file.size);
+    assert!(
+        file.pax.is_empty(),
+        "pax records are not applied to the file"
+    );
+
+    // Reading entries applies the extensions instead.
+    let mut entries = source
+        .entries(&layer(vec![]))
+        .await?
+        .expect("layer is not foreign");
+    let entry = entries.next().await.expect("file entry")?;
+    pretty_assertions::assert_eq!(Path::new("etc/renamed"), entry.path());
+
+    Ok(())
+}
@@ -1,13 +1,26 @@
 use async_tempfile::TempDir;
 use circe_lib::{
-    extract::{extract, Report, Strategy},
+    extract::{
+        extract, hash_files, prune_empty_dirs, verity_digests, Collision, Compression, FileDigest,
+        LayerCompression, LayerDetails, Report, ReportFormat, Strategy,
+    },
+    progress::{Progress, Silent},
     registry::Registry,
-    Digest, Reference, Source,
+    Digest, Identity, Layer, LayerMediaType, Platform, Reference, Source,
 };
 use color_eyre::Result;
 use serde_json::{json, Value};
 use simple_test_case::test_case;
-use std::{path::PathBuf, str::FromStr};
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use crate::mock::{self, Auth, MockRegistry};
 
 macro_rules! assert_layers_extracted {
     ($report:expr, $layers:expr) => {
@@ -49,6 +62,7 @@ async fn report_roundtrip() -> Result<()> {
     pretty_assertions::assert_eq!(
         parsed,
         json!({
+            "schema_version": Report::SCHEMA_VERSION,
             "digest": digest_img.to_string(),
             "layers": [
                 [digest_layer_1.to_string(), "/tmp/layer1"],
@@ -57,6 +71,231 @@ async fn report_roundtrip() -> Result<()> {
         })
     );
 
+    let reparsed = Report::parse(&json)?;
+    pretty_assertions::assert_eq!(json, reparsed.render()?);
+
+    Ok(())
+}
+
+#[test_case(ReportFormat::Json, Some("{\n  \"schema_version\": 1,\n  \"digest\": \"sha256:abc\",\n  \"layers\": [\n    [\n      \"sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4\",\n      \"/tmp/layer1\"\n    ]\n  ]\n}"); "json")]
+#[test_case(ReportFormat::Yaml, Some("schema_version: 1\ndigest: sha256:abc\nlayers:\n- - sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4\n  - /tmp/layer1\n"); "yaml")]
+#[test_case(ReportFormat::None, None; "none")]
+#[test]
+fn report_render_as(format: ReportFormat, expected: Option<&str>) -> Result<()> {
+    let layer = Digest::from_str(
+        "sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4",
+    )?;
+    let report = Report::builder()
+        .digest("sha256:abc")
+        .layers([(layer, PathBuf::from("/tmp/layer1"))])
+        .build();
+    pretty_assertions::assert_eq!(expected.map(String::from), report.render_as(format)?);
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn report_emit_to_path() -> Result<()> {
+    let tmp = TempDir::new().await?;
+    let report = Report::builder().digest("sha256:abc").layers([]).build();
+
+    let path = tmp.dir_path().join("report.yaml");
+    report.emit(ReportFormat::Yaml, Some(&path)).await?;
+    pretty_assertions::assert_eq!(
+        "schema_version: 1\ndigest: sha256:abc\nlayers: []\n",
+        std::fs::read_to_string(&path)?
+    );
+
+    let skipped = tmp.dir_path().join("skipped");
+    report.emit(ReportFormat::None, Some(&skipped)).await?;
+    assert!(!skipped.exists(), "no report must be written with none");
+    Ok(())
+}
+
+#[test_case(json!({ "digest": "sha256:abc", "layers": [] }), Some(0); "unversioned")]
+#[test_case(json!({ "schema_version": 1, "digest": "sha256:abc", "layers": [], "added": true }), Some(1); "unknown_field")]
+#[test_case(json!({ "schema_version": 2, "digest": 1 }), None; "newer")]
+#[test]
+fn report_parse_versions(report: Value, expected: Option<u32>) -> Result<()> {
+    match (Report::parse(&report.to_string()), expected) {
+        (Ok(report), Some(version)) => {
+            pretty_assertions::assert_eq!(version, report.schema_version)
+        }
+        (Err(err), None) => assert!(matches!(err, circe_lib::Error::Unsupported(_)), "{err:?}"),
+        (result, expected) => panic!("expected version {expected:?}, got {result:?}"),
+    }
+    Ok(())
+}
+
+/// A report with every section filled in.
+fn full_report() -> Result<Report> {
+    let digest = Digest::from_str(
+        "sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4",
+    )?;
+    Ok(Report::builder()
+        .digest(digest.to_string())
+        .identity(
+            Identity::builder()
+                .reference("docker.io/library/ubuntu:latest")
+                .resolved_digest(digest.clone())
+                .display_name("library/ubuntu")
+                .build(),
+        )
+        .layers([(digest.clone(), PathBuf::from("/tmp/layer"))])
+        .diff_ids([(digest.clone(), digest.clone())])
+        .files(vec![])
+        .listing(vec![])
+        .collisions(vec![Collision {
+            layer: digest.clone(),
+            path: PathBuf::from("A"),
+            renamed: PathBuf::from("a"),
+        }])
+        .compression(Compression::new(vec![LayerCompression::new(
+            digest.clone(),
+            1,
+            2,
+        )]))
+        .layer_details(vec![LayerDetails {
+            download_ms: Some(1),
+            extraction_ms: Some(2),
+            ..LayerDetails::new(
+                &Layer::builder()
+                    .digest(digest)
+                    .size(1)
+                    .media_type(LayerMediaType::Oci(vec![]))
+                    .build(),
+            )
+        }])
+        .options("sha256:0000")
+        .build())
+}
+
+#[test]
+fn report_schema_describes_report() -> Result<()> {
+    let report = full_report()?;
+    let schema = serde_json::from_str::<Value>(Report::SCHEMA)?;
+    pretty_assertions::assert_eq!(
+        json!(Report::SCHEMA_VERSION),
+        schema["properties"]["schema_version"]["const"],
+        "schema version"
+    );
+
+    let rendered = serde_json::from_str::<Value>(&report.render()?)?;
+    let fields = rendered.as_object().expect("report is an object").keys();
+    let described = schema["properties"]
+        .as_object()
+        .expect("schema has properties");
+    for field in fields {
+        assert!(
+            described.contains_key(field),
+            "schema doesn't describe {field:?}"
+        );
+    }
+    Ok(())
+}
+
+#[test_case(ReportFormat::Yaml; "yaml")]
+#[test_case(ReportFormat::Toml; "toml")]
+#[test]
+fn report_render_as_roundtrip(format: ReportFormat) -> Result<()> {
+    let report = full_report()?;
+    let rendered = report
+        .render_as(format)?
+        .expect("format renders the report");
+    let parsed = match format {
+        ReportFormat::Yaml => serde_yaml_ng::from_str::<Value>(&rendered)?,
+        ReportFormat::Toml => toml::from_str::<Value>(&rendered)?,
+        format => panic!("unexpected format {format}"),
+    };
+    pretty_assertions::assert_eq!(serde_json::from_str::<Value>(&report.render()?)?, parsed);
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn hash_files_relative_to_output() -> Result<()> {
+    let output = TempDir::new().await?;
+    let layer = output.dir_path().join("layer");
+    tokio::fs::create_dir_all(layer.join("etc")).await?;
+    tokio::fs::write(layer.join("hello.txt"), "hello").await?;
+    tokio::fs::write(layer.join("etc").join("world.txt"), "world").await?;
+
+    let digest_layer = Digest::from_str(
+        "sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4",
+    )?;
+    let files = hash_files(output.dir_path(), &[(digest_layer, layer)]).await?;
+
+    pretty_assertions::assert_eq!(
+        files,
+        vec![
+            FileDigest {
+                path: PathBuf::from("layer/etc/world.txt"),
+                digest: Digest::from_str(
+                    "sha256:486ea46224d1bb4fb680f34f7c9ad96a8f24ec88be73ea8e5a6c65260e9cb8a7"
+                )?,
+                verity: None,
+            },
+            FileDigest {
+                path: PathBuf::from("layer/hello.txt"),
+                digest: Digest::from_str(
+                    "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+                )?,
+                verity: None,
+            },
+        ]
+    );
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn verity_digests_for_files() -> Result<()> {
+    let output = TempDir::new().await?;
+    let layer = output.dir_path().join("layer");
+    tokio::fs::create_dir_all(&layer).await?;
+    tokio::fs::write(layer.join("hello.txt"), "hello").await?;
+
+    let digest_layer = Digest::from_str(
+        "sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4",
+    )?;
+    let mut files = hash_files(output.dir_path(), &[(digest_layer, layer)]).await?;
+    verity_digests(output.dir_path(), &mut files).await?;
+
+    // Matches the output of `fsverity digest hello.txt`.
+    pretty_assertions::assert_eq!(
+        files
+            .into_iter()
+            .map(|file| file.verity)
+            .collect::<Vec<_>>(),
+        vec![Some(Digest::from_str(
+            "sha256:555b589c26ee43b7a2510e6c67ced9fb3190b6da6e9e683984551f5d77a763de"
+        )?)]
+    );
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn prune_empty_dirs_keeps_files() -> Result<()> {
+    let output = TempDir::new().await?;
+    let layer = output.dir_path().join("layer");
+    tokio::fs::create_dir_all(layer.join("etc")).await?;
+    tokio::fs::create_dir_all(layer.join("var").join("empty").join("nested")).await?;
+    tokio::fs::create_dir_all(layer.join("tmp")).await?;
+    tokio::fs::write(layer.join("etc").join("hosts"), "localhost").await?;
+
+    let digest_layer = Digest::from_str(
+        "sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4",
+    )?;
+    let removed = prune_empty_dirs(&[(digest_layer, layer.clone())]).await?;
+
+    pretty_assertions::assert_eq!(removed, 4);
+    assert!(layer.join("etc").join("hosts").exists(), "files are kept");
+    assert!(layer.exists(), "layer directory is kept");
+    assert!(
+        !layer.join("var").exists(),
+        "nested empty directories are removed"
+    );
+    assert!(!layer.join("tmp").exists(), "empty directories are removed");
+
     Ok(())
 }
 
@@ -79,6 +318,7 @@ async fn report(image: &str) -> Result<()> {
         &registry,
         tmp.dir_path(),
         layers.iter().cloned().map(Strategy::Separate),
+        &Silent,
     )
     .await?;
 
@@ -116,7 +356,7 @@ async fn squash(image: &str) -> Result<()> {
     let layers = registry.layers().await?;
     assert!(!layers.is_empty(), "image should have at least one layer");
 
-    let extracted = extract(&registry, tmp.dir_path(), Strategy::Squash(layers)).await?;
+    let extracted = extract(&registry, tmp.dir_path(), Strategy::Squash(layers), &Silent).await?;
     let report = Report::builder()
         .digest(registry.digest().await?)
         .layers(extracted)
@@ -148,7 +388,13 @@ async fn base(image: &str) -> Result<()> {
         .first()
         .cloned()
         .expect("image should have at least one layer");
-    let extracted = extract(&registry, tmp.dir_path(), Strategy::Separate(base.clone())).await?;
+    let extracted = extract(
+        &registry,
+        tmp.dir_path(),
+        Strategy::Separate(base.clone()),
+        &Silent,
+    )
+    .await?;
     let report = Report::builder()
         .digest(registry.digest().await?)
         .layers(extracted)
@@ -180,6 +426,7 @@ async fn squash_other(image: &str) -> Result<()> {
         &registry,
         tmp.dir_path(),
         Strategy::Squash(layers.into_iter().skip(1).collect()),
+        &Silent,
     )
     .await?;
     let report = Report::builder()
@@ -218,7 +465,7 @@ async fn base_and_squash_other(image: &str) -> Result<()> {
         ],
     };
 
-    let extracted = extract(&registry, tmp.dir_path(), strategies).await?;
+    let extracted = extract(&registry, tmp.dir_path(), strategies, &Silent).await?;
     let report = Report::builder()
         .digest(registry.digest().await?)
         .layers(extracted)
@@ -253,7 +500,7 @@ async fn separate(image: &str) -> Result<()> {
         .map(Strategy::Separate)
         .collect::<Vec<_>>();
 
-    let extracted = extract(&registry, tmp.dir_path(), strategies).await?;
+    let extracted = extract(&registry, tmp.dir_path(), strategies, &Silent).await?;
     let report = Report::builder()
         .digest(registry.digest().await?)
         .layers(extracted)
@@ -266,3 +513,70 @@ async fn separate(image: &str) -> Result<()> {
 
     Ok(())
 }
+
+#[derive(Debug, Default)]
+struct CountingProgress {
+    started: AtomicU64,
+    bytes: AtomicU64,
+    decompressed: AtomicU64,
+    files: AtomicU64,
+    completed: AtomicU64,
+}
+
+impl Progress for CountingProgress {
+    fn layer_started(&self, _: &Layer) {
+        self.started.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn bytes_read(&self, _: &Layer, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn bytes_decompressed(&self, _: &Layer, bytes: u64) {
+        self.decompressed.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn file_written(&self, _: &Layer, _: &Path) {
+        self.files.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn layer_completed(&self, _: &Layer) {
+        self.completed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[test_log::test(tokio::test)]
+async fn progress() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let base = mock::tarball(&[("etc/os-release", b"ID=mock\n"), ("bin/sh", b"")]).await?;
+    let app = mock::tarball(&[("app/main.sh", b"echo hello\n")]).await?;
+    mock.push_image("team/app", "1.0", &Platform::linux_amd64(), &[base, app]);
+
+    let progress = Arc::new(CountingProgress::default());
+    let registry = Registry::builder()
+        .reference(mock.reference("team", "app", "1.0"))
+        .progress(progress.clone())
+        .build()
+        .await?;
+
+    let tmp = TempDir::new().await?;
+    let layers = registry.layers().await?;
+    let size = layers.iter().map(|l| l.size as u64).sum::<u64>();
+
+    extract(
+        &registry,
+        tmp.dir_path(),
+        Strategy::Squash(layers),
+        progress.as_ref(),
+    )
+    .await?;
+
+    // The mock serves uncompressed layers, so they decompress to as many bytes as were read.
+    pretty_assertions::assert_eq!(2, progress.started.load(Ordering::Relaxed));
+    pretty_assertions::assert_eq!(2, progress.completed.load(Ordering::Relaxed));
+    pretty_assertions::assert_eq!(size, progress.bytes.load(Ordering::Relaxed));
+    pretty_assertions::assert_eq!(size, progress.decompressed.load(Ordering::Relaxed));
+    pretty_assertions::assert_eq!(3, progress.files.load(Ordering::Relaxed));
+
+    Ok(())
+}
@@ -0,0 +1,158 @@
+use circe_lib::{filter_file::FilterFile, FilterMatch, Filters, LayerRange};
+use color_eyre::Result;
+use simple_test_case::test_case;
+
+#[test_case(&[], &[], "app/lib.jar", true; "empty")]
+#[test_case(&["**/*.jar"], &[], "app/lib.jar", true; "included")]
+#[test_case(&["**/*.jar"], &[], "app/main.sh", false; "not_included")]
+#[test_case(&[], &["test/**"], "app/lib.jar", true; "not_excluded")]
+#[test_case(&[], &["test/**"], "test/lib.jar", false; "excluded")]
+#[test_case(&["**/*.jar"], &["test/**"], "app/lib.jar", true; "included_not_excluded")]
+#[test_case(&["**/*.jar"], &["test/**"], "test/lib.jar", false; "exclude_takes_precedence")]
+#[test]
+fn matches(include: &[&str], exclude: &[&str], path: &str, expected: bool) -> Result<()> {
+    let filters = Filters::parse_glob(include)? + Filters::parse_glob(exclude)?.exclude();
+    pretty_assertions::assert_eq!(expected, filters.matches(&path.to_string()));
+    Ok(())
+}
+
+#[test]
+fn exclude_combines_kinds() -> Result<()> {
+    let filters = Filters::parse_glob(["**/*.so"])?
+        + Filters::parse_regex([r"^usr/lib/debug/"])?.exclude()
+        + Filters::parse_glob(["**/libtest*"])?.exclude();
+
+    assert!(filters.matches(&String::from("usr/lib/libc.so")));
+    assert!(!filters.matches(&String::from("usr/lib/debug/libc.so")));
+    assert!(!filters.matches(&String::from("usr/lib/libtest.so")));
+    Ok(())
+}
+
+#[test_case("2", 5, &[2]; "index")]
+#[test_case("-1", 5, &[4]; "index_from_end")]
+#[test_case("7", 5, &[]; "index_out_of_range")]
+#[test_case("1..3", 5, &[1, 2]; "exclusive")]
+#[test_case("1..=3", 5, &[1, 2, 3]; "inclusive")]
+#[test_case("1..", 5, &[1, 2, 3, 4]; "skip_base")]
+#[test_case("-2..", 5, &[3, 4]; "last_two")]
+#[test_case("..-1", 5, &[0, 1, 2, 3]; "all_but_last")]
+#[test_case("..", 3, &[0, 1, 2]; "all")]
+#[test]
+fn layer_range(range: &str, count: usize, expected: &[usize]) -> Result<()> {
+    let range = range.parse::<LayerRange>()?;
+    let selected = (0..count)
+        .filter(|&index| range.contains(index, count))
+        .collect::<Vec<_>>();
+    pretty_assertions::assert_eq!(expected, selected);
+    Ok(())
+}
+
+#[test_case("one"; "not_a_number")]
+#[test_case("1..=" ; "inclusive_without_end")]
+#[test_case("1...3"; "extra_dot")]
+#[test]
+fn layer_range_invalid(range: &str) {
+    assert!(range.parse::<LayerRange>().is_err());
+}
+
+#[test]
+fn select_combines_ranges_and_globs() -> Result<()> {
+    let layers = ["sha256:aa", "sha256:bb", "sha256:cc", "sha256:dd"].map(String::from);
+    let filters = Filters::ranges([LayerRange::index(0)])
+        + Filters::parse_glob(["sha256:cc"])?
+        + Filters::parse_glob(["sha256:aa"])?.exclude();
+
+    pretty_assertions::assert_eq!(
+        vec![String::from("sha256:cc")],
+        filters.select(layers.to_vec())
+    );
+    pretty_assertions::assert_eq!(layers.to_vec(), Filters::default().select(layers.to_vec()));
+    Ok(())
+}
+
+#[test_case("usr/lib/libc.so", true; "both")]
+#[test_case("usr/lib/debug/libc.so", false; "excluded_by_second")]
+#[test_case("usr/bin/ls", false; "excluded_by_first")]
+#[test]
+fn all_of(path: &str, expected: bool) -> Result<()> {
+    let filters = Filters::all_of([
+        Filters::parse_glob(["**/*.so"])?,
+        !Filters::parse_regex([r"/debug/"])?,
+    ]);
+    pretty_assertions::assert_eq!(expected, filters.matches(&path.to_string()));
+    Ok(())
+}
+
+#[test_case("app/lib.jar", true; "first")]
+#[test_case("usr/lib/libc.so", true; "second")]
+#[test_case("usr/bin/ls", false; "neither")]
+#[test]
+fn any_of(path: &str, expected: bool) -> Result<()> {
+    let filters = Filters::any_of([
+        Filters::parse_glob(["**/*.jar"])?,
+        Filters::parse_glob(["**/*.so"])?,
+    ]);
+    pretty_assertions::assert_eq!(expected, filters.matches(&path.to_string()));
+    assert!(!Filters::any_of([]).matches(&path.to_string()));
+    Ok(())
+}
+
+#[test]
+fn compositions_narrow_sets() -> Result<()> {
+    let filters = Filters::parse_glob(["**/*.so", "**/*.jar"])?
+        + !Filters::parse_glob(["test/**"])?
+        + Filters::parse_glob(["**/libtest*"])?.exclude();
+
+    assert!(filters.matches(&String::from("usr/lib/libc.so")));
+    assert!(!filters.matches(&String::from("test/lib.jar")));
+    assert!(!filters.matches(&String::from("usr/lib/libtest.so")));
+    assert!(!filters.matches(&String::from("usr/bin/ls")));
+    Ok(())
+}
+
+#[test]
+fn compositions_select_layers_by_position() -> Result<()> {
+    let layers = ["sha256:aa", "sha256:bb", "sha256:cc"].map(String::from);
+    let filters = Filters::all_of([
+        Filters::parse_glob(["sha256:[ab]*"])?,
+        !Filters::ranges([LayerRange::index(0)]),
+    ]);
+
+    pretty_assertions::assert_eq!(
+        vec![String::from("sha256:bb")],
+        filters.select(layers.to_vec())
+    );
+    pretty_assertions::assert_eq!(
+        vec![String::from("sha256:aa"), String::from("sha256:cc")],
+        filters.exclude().select(layers.to_vec())
+    );
+    Ok(())
+}
+
+#[test]
+fn filter_file() -> Result<()> {
+    let file = r"
+        # Java archives outside of tests, and shared libraries.
+        *.jar
+        !test/
+        regex:\.so$
+        layer:sha256:aa*
+    "
+    .parse::<FilterFile>()?;
+
+    assert!(file.files.matches(&String::from("/out/app/lib.jar")));
+    assert!(file.files.matches(&String::from("/out/usr/lib/libc.so")));
+    assert!(!file.files.matches(&String::from("/out/app/test/lib.jar")));
+    assert!(!file.files.matches(&String::from("/out/app/main.sh")));
+    assert!(file.layers.matches(&String::from("sha256:aa12")));
+    assert!(!file.layers.matches(&String::from("sha256:bb12")));
+    Ok(())
+}
+
+#[test]
+fn filter_file_invalid() {
+    let err = "*.jar\nregex:("
+        .parse::<FilterFile>()
+        .expect_err("parse filter file");
+    assert!(format!("{err:#}").contains("line 2"), "{err:#}");
+}
@@ -0,0 +1,227 @@
+use async_compression::tokio::write::GzipEncoder;
+use bytes::Bytes;
+use std::path::Path;
+
+use circe_lib::{
+    fossacli::{Hints, Image, Manifest, ManifestEntry, RootFs, Writer},
+    Digest, ImageConfig, Layer, LayerMediaType, LayerMediaTypeFlag,
+};
+use color_eyre::Result;
+use futures_lite::StreamExt;
+use tap::Pipe;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::memory::{layer, Memory};
+
+async fn tarball() -> Result<Bytes> {
+    let mut builder = tokio_tar::Builder::new(Vec::new());
+    let content = b"ID=test\n";
+    let mut header = tokio_tar::Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder
+        .append_data(&mut header, "etc/os-release", &content[..])
+        .await?;
+    Ok(Bytes::from(builder.into_inner().await?))
+}
+
+async fn gzip(content: &[u8]) -> Result<Bytes> {
+    let mut encoder = GzipEncoder::new(Vec::new());
+    encoder.write_all(content).await?;
+    encoder.shutdown().await?;
+    Ok(Bytes::from(encoder.into_inner()))
+}
+
+/// Read the path and content of every entry in the tarball.
+async fn read_entries(path: &Path) -> Result<Vec<(String, Bytes)>> {
+    let file = tokio::fs::File::open(path).await?;
+    let mut archive = tokio_tar::Archive::new(file);
+    let mut entries = archive.entries()?;
+    let mut read = Vec::new();
+    while let Some(entry) = entries.next().await {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().to_string();
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content).await?;
+        read.push((path, Bytes::from(content)));
+    }
+    Ok(read)
+}
+
+#[test_log::test(tokio::test)]
+async fn writer_streams_layers() -> Result<()> {
+    let plain = tarball().await?;
+    let temp = async_tempfile::TempDir::new().await?;
+    let output = temp.dir_path().join("image.tar");
+
+    let mut writer = Writer::create(&output).await?;
+
+    // Compressed layers are a different size than their descriptor,
+    // so their header is rewritten after the layer is written.
+    let compressed = Memory {
+        layer: gzip(&plain).await?,
+    };
+    let appended = writer
+        .append_layer(&compressed, &layer(vec![LayerMediaTypeFlag::Gzip]))
+        .await?;
+    assert!(appended, "gzip layer is appended");
+
+    // Uncompressed layers are the size recorded in their descriptor.
+    let uncompressed = Memory {
+        layer: plain.clone(),
+    };
+    let descriptor = Layer::builder()
+        .digest(Digest::from_hash(vec![1; 32]))
+        .size(plain.len() as i64)
+        .media_type(LayerMediaType::Oci(vec![]))
+        .build();
+    let appended = writer.append_layer(&uncompressed, &descriptor).await?;
+    assert!(appended, "uncompressed layer is appended");
+
+    let appended = writer
+        .append_layer(&uncompressed, &layer(vec![LayerMediaTypeFlag::Foreign]))
+        .await?;
+    assert!(!appended, "foreign layer is skipped");
+
+    let rendered = writer.append_json("manifest.json", &["value"]).await?;
+    writer.finish().await?;
+
+    let written = read_entries(&output).await?;
+    pretty_assertions::assert_eq!(
+        vec![
+            (layer(vec![]).digest.tarball_filename(), plain.clone()),
+            (descriptor.digest.tarball_filename(), plain),
+            (String::from("manifest.json"), Bytes::from(rendered)),
+        ],
+        written
+    );
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn writer_retags_previous_export() -> Result<()> {
+    let plain = tarball().await?;
+    let temp = async_tempfile::TempDir::new().await?;
+    let original = temp.dir_path().join("original.tar");
+    let retagged = temp.dir_path().join("retagged.tar");
+
+    let descriptor = layer(vec![]);
+    let digest = Digest::from_hash(vec![2; 32]);
+    let manifest = ManifestEntry::builder()
+        .config(Image::filename(&digest))
+        .repo_tags("alpine:3")
+        .layers([descriptor.digest.tarball_filename()])
+        .build()
+        .pipe(Manifest::singleton);
+    let image = Image::from(RootFs::layers([descriptor.digest.to_string()]));
+
+    let mut writer = Writer::create(&original).await?;
+    writer
+        .append_layer(&Memory { layer: plain }, &descriptor)
+        .await?;
+    writer.append_json(Manifest::filename(), &manifest).await?;
+    writer.append_json(Image::filename(&digest), &image).await?;
+    writer.finish().await?;
+
+    let mut writer = Writer::create(&retagged).await?;
+    writer.append_retagged(&original, "alpine:latest").await?;
+    writer.finish().await?;
+
+    let original = read_entries(&original).await?;
+    let retagged = read_entries(&retagged).await?;
+    let names = |entries: &[(String, Bytes)]| {
+        entries
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>()
+    };
+    pretty_assertions::assert_eq!(names(&original), names(&retagged));
+    pretty_assertions::assert_eq!(original[0], retagged[0], "layer is copied");
+    pretty_assertions::assert_eq!(original[2], retagged[2], "image is copied");
+
+    let manifest = serde_json::from_slice::<serde_json::Value>(&retagged[1].1)?;
+    pretty_assertions::assert_eq!(
+        serde_json::json!(["alpine:latest"]),
+        manifest[0]["RepoTags"]
+    );
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn writer_streams_to_writer() -> Result<()> {
+    let plain = tarball().await?;
+    let temp = async_tempfile::TempDir::new().await?;
+    let output = temp.dir_path().join("image.tar");
+
+    // Writers are only appended to, so this works the same as writing to stdout.
+    let file = tokio::fs::File::create(&output).await?;
+    let mut writer = Writer::stream(file);
+
+    let compressed = Memory {
+        layer: gzip(&plain).await?,
+    };
+    let appended = writer
+        .append_layer(&compressed, &layer(vec![LayerMediaTypeFlag::Gzip]))
+        .await?;
+    assert!(appended, "gzip layer is appended");
+
+    let rendered = writer.append_json("manifest.json", &["value"]).await?;
+    writer.finish().await?;
+
+    let written = read_entries(&output).await?;
+    pretty_assertions::assert_eq!(
+        vec![
+            (layer(vec![]).digest.tarball_filename(), plain),
+            (String::from("manifest.json"), Bytes::from(rendered)),
+        ],
+        written
+    );
+
+    Ok(())
+}
+
+#[test]
+fn hints() -> Result<()> {
+    let app = Layer {
+        digest: Digest::from_hash(vec![1; 32]),
+        diff_id: Some(Digest::from_hash(vec![2; 32])),
+        ..layer(vec![LayerMediaTypeFlag::Gzip])
+    };
+    let foreign = layer(vec![LayerMediaTypeFlag::Gzip, LayerMediaTypeFlag::Foreign]);
+    let config = ImageConfig {
+        os: Some(String::from("windows")),
+        architecture: Some(String::from("amd64")),
+        ..Default::default()
+    };
+
+    let image = Digest::from_hash(vec![3; 32]);
+    let hints = Hints::new(image.clone(), &config, &[foreign.clone(), app.clone()]);
+    let expected = serde_json::json!({
+        "version": Hints::VERSION,
+        "digest": image.to_string(),
+        "platform": {
+            "os": "windows",
+            "architecture": "amd64",
+            "variant": null,
+            "os_version": null,
+            "os_features": [],
+        },
+        "layers": [{
+            "digest": app.digest.to_string(),
+            "diff_id": app.diff_id.as_ref().map(ToString::to_string),
+            "media_type": app.media_type.to_string(),
+        }],
+        "skipped": [{
+            "digest": foreign.digest.to_string(),
+            "diff_id": null,
+            "media_type": foreign.media_type.to_string(),
+        }],
+    });
+    pretty_assertions::assert_eq!(expected, serde_json::to_value(&hints)?);
+
+    let parsed = serde_json::from_value::<Hints>(expected)?;
+    pretty_assertions::assert_eq!(hints, parsed);
+    Ok(())
+}
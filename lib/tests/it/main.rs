@@ -1,6 +1,32 @@
+#[cfg(feature = "native")]
+mod cache;
+mod capabilities;
+#[cfg(feature = "native")]
+mod catalog;
+mod digest;
+#[cfg(feature = "docker-auth")]
 mod docker;
+#[cfg(feature = "native")]
+mod entries;
+#[cfg(feature = "native")]
 mod extract;
+mod filters;
+#[cfg(feature = "native")]
+mod fossacli;
+#[cfg(feature = "native")]
+mod memory;
+#[cfg(feature = "native")]
+use circe_test_support::mock;
 mod platform;
+#[cfg(feature = "native")]
+mod redirect;
 mod reference;
+#[cfg(feature = "native")]
 mod registry;
+#[cfg(feature = "native")]
+mod registry_mock;
+#[cfg(feature = "native")]
+mod source;
 mod transform;
+#[cfg(feature = "native")]
+mod vfs;
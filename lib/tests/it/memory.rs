@@ -0,0 +1,64 @@
+//! Test helpers for sources that serve layers from memory.
+
+use std::{path::Path, pin::Pin};
+
+use async_tempfile::TempFile;
+use bytes::Bytes;
+use circe_lib::{
+    Digest, ImageConfig, Layer, LayerMediaType, LayerMediaTypeFlag, Result as CirceResult, Source,
+    Unpack,
+};
+use futures_lite::Stream;
+
+/// Serves a single layer from memory.
+#[derive(Debug)]
+pub struct Memory {
+    pub layer: Bytes,
+}
+
+impl Source for Memory {
+    async fn digest(&self) -> CirceResult<Digest> {
+        Ok(Digest::from_hash(vec![0; 32]))
+    }
+
+    async fn name(&self) -> CirceResult<String> {
+        Ok(String::from("memory"))
+    }
+
+    async fn layers(&self) -> CirceResult<Vec<Layer>> {
+        unimplemented!()
+    }
+
+    async fn config(&self) -> CirceResult<ImageConfig> {
+        unimplemented!()
+    }
+
+    async fn pull_layer(
+        &self,
+        _layer: &Layer,
+    ) -> CirceResult<Pin<Box<dyn Stream<Item = CirceResult<Bytes>> + Send>>> {
+        Ok(Box::pin(futures_lite::stream::once(Ok(self.layer.clone()))))
+    }
+}
+
+impl Unpack for Memory {
+    async fn list_files(&self, _layer: &Layer) -> CirceResult<Vec<String>> {
+        unimplemented!()
+    }
+
+    async fn apply_layer(&self, _layer: &Layer, _output: &Path) -> CirceResult<()> {
+        unimplemented!()
+    }
+
+    async fn layer_plain_tarball(&self, _layer: &Layer) -> CirceResult<Option<TempFile>> {
+        unimplemented!()
+    }
+}
+
+pub fn layer(flags: Vec<LayerMediaTypeFlag>) -> Layer {
+    Layer::builder()
+        .digest(Digest::from_hash(vec![0; 32]))
+        .size(0)
+        .media_type(LayerMediaType::Oci(flags))
+        .build()
+}
@@ -6,6 +6,8 @@ use simple_test_case::test_case;
 #[test_case("darwin/arm64", Platform::macos_arm64(); "darwin/arm64")]
 #[test_case("darwin/amd64", Platform::macos_amd64(); "darwin/amd64")]
 #[test_case("windows/amd64", Platform::windows_amd64(); "windows/amd64")]
+#[test_case("windows/amd64:10.0.17763.x", Platform::builder().os("windows").architecture("amd64").os_version("10.0.17763.x").build(); "windows/amd64:10.0.17763.x")]
+#[test_case("linux/arm/v7:5.10", Platform::builder().os("linux").architecture("arm").variant("v7").os_version("5.10").build(); "linux/arm/v7:5.10")]
 #[test]
 fn parse(input: &str, expected: Platform) {
     let platform = input.parse::<Platform>().unwrap();
@@ -17,6 +19,8 @@ fn parse(input: &str, expected: Platform) {
 #[test_case("/arm64/v8"; "/arm64/v8")]
 #[test_case("/amd64"; "/amd64")]
 #[test_case("linux/amd64/v8/extra"; "linux/amd64/v8/extra")]
+#[test_case("windows/amd64:"; "empty_os_version")]
+#[test_case("windows/:10.0.17763.1879"; "missing_architecture_with_os_version")]
 #[test]
 fn parse_invalid(input: &str) {
     let parsed = input.parse::<Platform>();
@@ -29,6 +33,7 @@ fn parse_invalid(input: &str) {
 #[test_case(Platform::windows_amd64(), "windows/amd64"; "windows/amd64")]
 #[test_case(Platform::macos_arm64(), "darwin/arm64"; "darwin/arm64")]
 #[test_case(Platform::macos_amd64(), "darwin/amd64"; "darwin/amd64")]
+#[test_case(Platform::builder().os("windows").architecture("amd64").os_version("10.0.17763.1879").build(), "windows/amd64:10.0.17763.1879"; "windows/amd64:10.0.17763.1879")]
 #[test]
 fn display(platform: Platform, expected: &str) {
     pretty_assertions::assert_eq!(platform.to_string(), expected);
@@ -42,3 +47,76 @@ fn constructors() {
     assert_eq!(Platform::macos_arm64().to_string(), "darwin/arm64");
     assert_eq!(Platform::macos_amd64().to_string(), "darwin/amd64");
 }
+
+#[test_case(Platform::linux_amd64(); "linux/amd64")]
+#[test_case(Platform::linux_arm64().with_variant("v8"); "linux/arm64/v8")]
+#[test_case(Platform::builder().os("windows").architecture("amd64").os_version("10.0.14393.1066").os_features(vec![String::from("win32k")]).build(); "windows_os_version")]
+#[test]
+fn serde_roundtrip(platform: Platform) {
+    let serialized = serde_json::to_string(&platform).expect("serialize");
+    let deserialized = serde_json::from_str::<Platform>(&serialized).expect("deserialize");
+    pretty_assertions::assert_eq!(platform, deserialized);
+}
+
+#[test_case("\"linux/amd64\"", Platform::linux_amd64(); "string")]
+#[test_case("\"linux/arm64/v8\"", Platform::linux_arm64().with_variant("v8"); "string_variant")]
+#[test_case(r#"{"os":"linux","architecture":"amd64"}"#, Platform::linux_amd64(); "structured_minimal")]
+#[test]
+fn deserialize(input: &str, expected: Platform) {
+    let platform = serde_json::from_str::<Platform>(input).expect("deserialize");
+    pretty_assertions::assert_eq!(expected, platform);
+}
+
+#[test_case("\"linux\""; "string")]
+#[test_case(r#"{"os":"linux"}"#; "structured_missing_architecture")]
+#[test]
+fn deserialize_invalid(input: &str) {
+    let _ = serde_json::from_str::<Platform>(input).expect_err("must error");
+}
+
+#[test_case("linux/amd64", "linux/amd64"; "amd64")]
+#[test_case("linux/x86_64", "linux/amd64"; "x86_64")]
+#[test_case("linux/amd64/v1", "linux/amd64"; "amd64_v1")]
+#[test_case("linux/amd64/v3", "linux/amd64/v3"; "amd64_v3")]
+#[test_case("linux/aarch64", "linux/arm64"; "aarch64")]
+#[test_case("linux/arm64/v8", "linux/arm64"; "arm64_v8")]
+#[test_case("linux/arm64/8", "linux/arm64"; "arm64_8")]
+#[test_case("linux/arm", "linux/arm/v7"; "arm")]
+#[test_case("linux/arm/6", "linux/arm/v6"; "arm_6")]
+#[test_case("linux/armhf", "linux/arm/v7"; "armhf")]
+#[test_case("linux/armel", "linux/arm/v6"; "armel")]
+#[test_case("linux/i386", "linux/386"; "i386")]
+#[test_case("MacOS/ARM64", "darwin/arm64"; "macos")]
+#[test_case("linux/s390x", "linux/s390x"; "unknown_architecture")]
+#[test]
+fn normalize(input: &str, expected: &str) {
+    let platform = input.parse::<Platform>().unwrap();
+    pretty_assertions::assert_eq!(expected, platform.normalize().to_string());
+}
+
+#[test_case("linux/arm/v7", "linux/arm/v7", Some(0); "exact")]
+#[test_case("linux/arm/v7", "linux/arm", Some(0); "implied_variant")]
+#[test_case("linux/arm64", "linux/arm64/v8", Some(0); "arm64_v8")]
+#[test_case("linux/arm64/v8", "linux/aarch64", Some(0); "aarch64")]
+#[test_case("linux/arm/v7", "linux/arm/v6", Some(1); "older_variant")]
+#[test_case("linux/arm/v7", "linux/arm/v5", Some(2); "much_older_variant")]
+#[test_case("linux/arm/v6", "linux/arm/v7", None; "newer_variant")]
+#[test_case("linux/amd64/v3", "linux/amd64", Some(2); "amd64_baseline")]
+#[test_case("linux/amd64", "linux/amd64/v3", Some(1000); "unspecified_variant")]
+#[test_case("linux/arm64", "linux/arm/v7", None; "different_architecture")]
+#[test_case("linux/amd64", "windows/amd64", None; "different_os")]
+#[test_case("windows/amd64:10.0.17763.1879", "windows/amd64:10.0.17763.1879", Some(0); "os_version_exact")]
+#[test_case("windows/amd64:10.0.17763.1879", "windows/amd64:10.0.17763.1869", Some(10); "os_version_older_revision")]
+#[test_case("windows/amd64:10.0.17763.1879", "windows/amd64:10.0.17763.1935", None; "os_version_newer_revision")]
+#[test_case("windows/amd64:10.0.17763.1879", "windows/amd64:10.0.20348.1906", None; "os_version_different_build")]
+#[test_case("windows/amd64:10.0.17763.x", "windows/amd64:10.0.17763.1935", Some(0); "os_version_any_revision")]
+#[test_case("windows/amd64:10.0.17763", "windows/amd64:10.0.17763.1935", Some(0); "os_version_build_only")]
+#[test_case("windows/amd64:10.0.17763.x", "windows/amd64", None; "os_version_unrecorded")]
+#[test_case("windows/amd64", "windows/amd64:10.0.17763.1935", Some(0); "os_version_unspecified")]
+#[test]
+fn score(target: &str, candidate: &str, expected: Option<usize>) {
+    let target = target.parse::<Platform>().unwrap();
+    let candidate = candidate.parse::<Platform>().unwrap();
+    pretty_assertions::assert_eq!(expected, target.score(&candidate));
+    pretty_assertions::assert_eq!(expected.is_some(), target.matches(&candidate));
+}
@@ -0,0 +1,110 @@
+//! Blob downloads that redirect to another origin, like registries backed by S3 or GCS.
+
+use bytes::Bytes;
+use circe_lib::{registry::Registry, Digest, Layer, LayerMediaType, Platform, Source};
+use color_eyre::Result;
+use futures_lite::StreamExt;
+
+use crate::mock::{self, Auth, MockRegistry, Request, LAYER};
+
+const BLOB: &[u8] = b"layer content";
+
+fn layer(digest: Digest) -> Layer {
+    Layer::builder()
+        .digest(digest)
+        .size(BLOB.len() as i64)
+        .media_type(LayerMediaType::Oci(vec![]))
+        .build()
+}
+
+async fn read(registry: &Registry, layer: &Layer) -> circe_lib::Result<Vec<u8>> {
+    let mut stream = registry.pull_layer(layer).await?;
+    let mut content = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        content.extend_from_slice(&chunk?);
+    }
+    Ok(content)
+}
+
+#[test_log::test(tokio::test)]
+async fn blob_redirect_strips_authorization() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Bearer(None)).await?;
+    mock.redirect_blobs();
+    let digest = mock.push_blob(LAYER, BLOB);
+
+    let reference = mock.reference("app", "redirect", "latest");
+    let registry = Registry::builder().reference(reference).build().await?;
+    let content = read(&registry, &layer(digest.clone())).await?;
+    pretty_assertions::assert_eq!(BLOB, content.as_slice());
+
+    let requests = mock.requests();
+    assert!(
+        requests
+            .last()
+            .is_some_and(|request| request.authorization.is_some()),
+        "registry receives credentials: {requests:?}"
+    );
+
+    // Storage redirects again within its own origin, which must not restore the credentials.
+    pretty_assertions::assert_eq!(
+        vec![
+            Request {
+                method: String::from("GET"),
+                path: format!("/presigned/{digest}"),
+                authorization: None,
+            },
+            Request {
+                method: String::from("GET"),
+                path: format!("/blob/{digest}?signature=mock"),
+                authorization: None,
+            },
+        ],
+        mock.storage_requests()
+    );
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn blob_redirect_verifies_digest() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Bearer(None)).await?;
+    mock.redirect_blobs();
+    let digest = mock::digest(BLOB);
+    mock.put_blob(&digest, LAYER, Bytes::from_static(b"tampered content"));
+
+    let reference = mock.reference("app", "redirect", "latest");
+    let registry = Registry::builder().reference(reference).build().await?;
+    let err = read(&registry, &layer(digest))
+        .await
+        .expect_err("content doesn't match digest");
+    assert!(
+        format!("{err:?}").contains("digest mismatch"),
+        "unexpected error: {err:?}"
+    );
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn blob_redirect_applies_image() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Bearer(None)).await?;
+    mock.redirect_blobs();
+    let layer = mock::tarball(&[("etc/os-release", b"ID=mock\n")]).await?;
+    mock.push_image("app/redirect", "latest", &Platform::linux_amd64(), &[layer]);
+
+    let reference = mock.reference("app", "redirect", "latest");
+    let registry = Registry::builder().reference(reference).build().await?;
+    let layers = registry.layers().await?;
+    pretty_assertions::assert_eq!(1, layers.len());
+
+    let content = read(&registry, &layers[0]).await?;
+    assert!(!content.is_empty(), "layer is downloaded through storage");
+    assert!(
+        mock.storage_requests()
+            .iter()
+            .all(|request| request.authorization.is_none()),
+        "storage never receives credentials"
+    );
+
+    Ok(())
+}
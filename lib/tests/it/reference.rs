@@ -1,10 +1,19 @@
-use circe_lib::{Digest, Reference};
+use circe_lib::{AllowedHosts, Digest, OciDefaults, Reference, Version};
 use proptest::prelude::*;
 use simple_test_case::test_case;
 
 #[test_case("docker.io/library/ubuntu:latest", Reference::builder().host("docker.io").namespace("library").name("ubuntu").tag("latest").build(); "docker.io/library/ubuntu:latest")]
-#[test_case("ghcr.io/user/repo@sha256:123abc", Reference::builder().host("ghcr.io").namespace("user").name("repo").digest(circe_lib::digest!("sha256", "123abc", 3)).build(); "ghcr.io/user/repo@sha256:123abc")]
+#[test_case("ghcr.io/user/repo@sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4", Reference::builder().host("ghcr.io").namespace("user").name("repo").digest(circe_lib::digest!("sha256", "a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4")).build(); "ghcr.io/user/repo@sha256_full")]
 #[test_case("docker.io/library/ubuntu", Reference::builder().host("docker.io").namespace("library").name("ubuntu").build(); "docker.io/library/ubuntu")]
+#[test_case("localhost:5000/app:tag", Reference::builder().host("localhost:5000").namespace("").name("app").tag("tag").build(); "localhost:5000/app:tag")]
+#[test_case("localhost/app", Reference::builder().host("localhost").namespace("").name("app").build(); "localhost/app")]
+#[test_case("localhost:5000/team/app:1.0", Reference::builder().host("localhost:5000").namespace("team").name("app").tag("1.0").build(); "localhost:5000/team/app:1.0")]
+#[test_case("127.0.0.1:5000/team/app@sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4", Reference::builder().host("127.0.0.1:5000").namespace("team").name("app").digest(circe_lib::digest!("sha256", "a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4")).build(); "127.0.0.1:5000/team/app@sha256_full")]
+#[test_case("[::1]:5000/app", Reference::builder().host("[::1]:5000").namespace("").name("app").build(); "ipv6_localhost_port/app")]
+#[test_case("registry:5000/app", Reference::builder().host("registry:5000").namespace("").name("app").build(); "registry:5000/app")]
+#[test_case("quay.io/app:tag", Reference::builder().host("quay.io").namespace("").name("app").tag("tag").build(); "quay.io/app:tag")]
+#[test_case("ghcr.io/my__org/my-app.api_v2:V1_rc.1", Reference::builder().host("ghcr.io").namespace("my__org").name("my-app.api_v2").tag("V1_rc.1").build(); "separators")]
+#[test_case("ghcr.io/user/repo:1.0@sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4", Reference::builder().host("ghcr.io").namespace("user").name("repo").digest(circe_lib::digest!("sha256", "a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4")).build(); "tag_and_digest")]
 #[test]
 fn parse(input: &str, expected: Reference) {
     let reference = input.parse::<Reference>().unwrap();
@@ -12,9 +21,10 @@ fn parse(input: &str, expected: Reference) {
 }
 
 #[test_case(Reference::builder().host("docker.io").namespace("library").name("ubuntu").tag("latest").build(), "docker.io/library/ubuntu:latest"; "docker.io/library/ubuntu:latest")]
-#[test_case(Reference::builder().host("ghcr.io").namespace("user").name("repo").digest(circe_lib::digest!("sha256", "123abc", 3)).build(), "ghcr.io/user/repo@sha256:123abc"; "ghcr.io/user/repo@sha256:123abc")]
+#[test_case(Reference::builder().host("ghcr.io").namespace("user").name("repo").digest(circe_lib::digest!("sha256", "a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4")).build(), "ghcr.io/user/repo@sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4"; "ghcr.io/user/repo@sha256_full")]
 #[test_case(Reference::builder().host("ghcr.io").namespace("fossas").name("project/app").tag("sha-e01ce6b").build(), "ghcr.io/fossas/project/app:sha-e01ce6b"; "ghcr.io/fossas/project/app:sha-e01ce6b")]
 #[test_case(Reference::builder().host("docker.io").namespace("library").name("ubuntu").build(), "docker.io/library/ubuntu:latest"; "docker.io/library/ubuntu")]
+#[test_case(Reference::builder().host("localhost:5000").namespace("").name("app").tag("tag").build(), "localhost:5000/app:tag"; "localhost:5000/app:tag")]
 #[test]
 fn display(reference: Reference, expected: &str) {
     pretty_assertions::assert_eq!(reference.to_string(), expected);
@@ -22,17 +32,19 @@ fn display(reference: Reference, expected: &str) {
 
 #[test_case("ubuntu", "docker.io/library/ubuntu:latest"; "ubuntu")]
 #[test_case("ubuntu:14.04", "docker.io/library/ubuntu:14.04"; "ubuntu:14.04")]
-#[test_case("ubuntu@sha256:123abc", "docker.io/library/ubuntu@sha256:123abc"; "ubuntu@sha256:123abc")]
+#[test_case("ubuntu@sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4", "docker.io/library/ubuntu@sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4"; "ubuntu@sha256_full")]
 #[test_case("library/ubuntu", "docker.io/library/ubuntu:latest"; "library/ubuntu")]
 #[test_case("contribsys/faktory", "docker.io/contribsys/faktory:latest"; "contribsys/faktory")]
 #[test_case("contribsys/faktory:1.0.0", "docker.io/contribsys/faktory:1.0.0"; "contribsys/faktory:1.0.0")]
 #[test_case("library/ubuntu:14.04", "docker.io/library/ubuntu:14.04"; "library/ubuntu:14.04")]
-#[test_case("library/ubuntu@sha256:123abc", "docker.io/library/ubuntu@sha256:123abc"; "library/ubuntu@sha256:123abc")]
+#[test_case("library/ubuntu@sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4", "docker.io/library/ubuntu@sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4"; "library/ubuntu@sha256_full")]
 #[test_case("docker.io/library/ubuntu:14.04", "docker.io/library/ubuntu:14.04"; "docker.io/library/ubuntu:14.04")]
-#[test_case("docker.io/library/ubuntu@sha256:123abc", "docker.io/library/ubuntu@sha256:123abc"; "docker.io/library/ubuntu@sha256:123abc")]
+#[test_case("docker.io/library/ubuntu@sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4", "docker.io/library/ubuntu@sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4"; "docker.io/library/ubuntu@sha256_full")]
 #[test_case("host.dev/somecorp/someproject/someimage", "host.dev/somecorp/someproject/someimage:latest"; "host.dev/somecorp/someproject/someimage")]
 #[test_case("host.dev/somecorp/someproject/someimage:1.0.0", "host.dev/somecorp/someproject/someimage:1.0.0"; "host.dev/somecorp/someproject/someimage:1.0.0")]
-#[test_case("host.dev/somecorp/someproject/someimage@sha256:123abc", "host.dev/somecorp/someproject/someimage@sha256:123abc"; "host.dev/somecorp/someproject/someimage@sha256:123abc")]
+#[test_case("host.dev/somecorp/someproject/someimage@sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4", "host.dev/somecorp/someproject/someimage@sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4"; "host.dev/somecorp/someproject/someimage@sha256_full")]
+#[test_case("library/ubuntu/foo", "docker.io/library/ubuntu/foo:latest"; "library/ubuntu/foo")]
+#[test_case("library/ubuntu/foo:1.0", "docker.io/library/ubuntu/foo:1.0"; "library/ubuntu/foo:1.0")]
 #[test]
 #[cfg_attr(
     feature = "test-custom-namespace",
@@ -45,17 +57,17 @@ fn docker_like(input: &str, expected: &str) {
 
 #[test_case("ubuntu", "host.dev/somecorp/someproject/ubuntu:latest"; "ubuntu")]
 #[test_case("ubuntu:14.04", "host.dev/somecorp/someproject/ubuntu:14.04"; "ubuntu:14.04")]
-#[test_case("ubuntu@sha256:123abc", "host.dev/somecorp/someproject/ubuntu@sha256:123abc"; "ubuntu@sha256:123abc")]
+#[test_case("ubuntu@sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4", "host.dev/somecorp/someproject/ubuntu@sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4"; "ubuntu@sha256_full")]
 #[test_case("library/ubuntu", "host.dev/library/ubuntu:latest"; "library/ubuntu")]
 #[test_case("contribsys/faktory", "host.dev/contribsys/faktory:latest"; "contribsys/faktory")]
 #[test_case("contribsys/faktory:1.0.0", "host.dev/contribsys/faktory:1.0.0"; "contribsys/faktory:1.0.0")]
 #[test_case("library/ubuntu:14.04", "host.dev/library/ubuntu:14.04"; "library/ubuntu:14.04")]
-#[test_case("library/ubuntu@sha256:123abc", "host.dev/library/ubuntu@sha256:123abc"; "library/ubuntu@sha256:123abc")]
+#[test_case("library/ubuntu@sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4", "host.dev/library/ubuntu@sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4"; "library/ubuntu@sha256_full")]
 #[test_case("docker.io/library/ubuntu:14.04", "docker.io/library/ubuntu:14.04"; "docker.io/library/ubuntu:14.04")]
-#[test_case("docker.io/library/ubuntu@sha256:123abc", "docker.io/library/ubuntu@sha256:123abc"; "docker.io/library/ubuntu@sha256:123abc")]
+#[test_case("docker.io/library/ubuntu@sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4", "docker.io/library/ubuntu@sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4"; "docker.io/library/ubuntu@sha256_full")]
 #[test_case("host.dev/somecorp/someproject/someimage", "host.dev/somecorp/someproject/someimage:latest"; "host.dev/somecorp/someproject/someimage")]
 #[test_case("host.dev/somecorp/someproject/someimage:1.0.0", "host.dev/somecorp/someproject/someimage:1.0.0"; "host.dev/somecorp/someproject/someimage:1.0.0")]
-#[test_case("host.dev/somecorp/someproject/someimage@sha256:123abc", "host.dev/somecorp/someproject/someimage@sha256:123abc"; "host.dev/somecorp/someproject/someimage@sha256:123abc")]
+#[test_case("host.dev/somecorp/someproject/someimage@sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4", "host.dev/somecorp/someproject/someimage@sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4"; "host.dev/somecorp/someproject/someimage@sha256_full")]
 #[test]
 #[cfg_attr(
     not(feature = "test-custom-namespace"),
@@ -94,28 +106,116 @@ fn docker_like_custom_base_namespace(input: &str, expected: &str) {
 #[test_case("/repo:tag"; "/repo:tag")]
 #[test_case("host/:tag"; "host/tag")]
 #[test_case("host/"; "host/")]
+#[test_case("localhost:5000/"; "localhost:5000/")]
+#[test_case("host.dev//app"; "host.dev/empty_namespace/app")]
 #[test]
 fn invalid_references(input: &str) {
-    let _ = input.parse::<Reference>().expect_err("must error");
+    let err = input.parse::<Reference>().expect_err("must error");
+    assert!(matches!(err, circe_lib::Error::Parse(_)), "{err:?}");
+}
+
+#[test_case(" Host.Dev:5000 ", "somecorp/someproject", "host.dev:5000", "somecorp/someproject"; "normalized")]
+#[test_case("[::1]:5000", "library", "[::1]:5000", "library"; "ipv6")]
+#[test]
+fn oci_defaults(base: &str, namespace: &str, expected_base: &str, expected_namespace: &str) {
+    let defaults = OciDefaults::new(base, namespace).expect("valid defaults");
+    pretty_assertions::assert_eq!(expected_base, defaults.base());
+    pretty_assertions::assert_eq!(expected_namespace, defaults.namespace());
+}
+
+#[test_case("https://ghcr.io", "library", "use 'ghcr.io' instead"; "scheme")]
+#[test_case("ghcr.io/", "library", "use 'ghcr.io' instead"; "trailing_slash")]
+#[test_case("ghcr.io/somecorp", "library", "use 'ghcr.io' as the host and 'somecorp' as the namespace"; "path")]
+#[test_case("", "library", "cannot be empty"; "empty_base")]
+#[test_case("ghcr.io", "", "cannot be empty"; "empty_namespace")]
+#[test_case("ghcr.io", "somecorp/", "use 'somecorp' instead"; "namespace_trailing_slash")]
+#[test_case("ghcr.io", "somecorp//app", "empty components"; "namespace_empty_component")]
+#[test_case("ghcr.io", "SomeCorp", "component 'SomeCorp'"; "namespace_uppercase")]
+#[test]
+fn invalid_oci_defaults(base: &str, namespace: &str, expected: &str) {
+    let err = OciDefaults::new(base, namespace).expect_err("must error");
+    assert!(matches!(err, circe_lib::Error::Parse(_)), "{err:?}");
+    assert!(err.to_string().contains(expected), "{err}");
+}
+
+#[test_case("ghcr.io", true; "exact")]
+#[test_case("GHCR.IO", true; "case_insensitive")]
+#[test_case("localhost:5000", true; "port")]
+#[test_case("localhost", false; "without_port")]
+#[test_case("evil.ghcr.io", false; "subdomain")]
+#[test]
+fn allowed_hosts(host: &str, expected: bool) {
+    let allowed = AllowedHosts::new(["Ghcr.io", "localhost:5000"]).expect("valid hosts");
+    pretty_assertions::assert_eq!(expected, allowed.allows(host));
+}
+
+#[test]
+fn invalid_allowed_hosts() {
+    let err = AllowedHosts::new(["ghcr.io", "https://docker.io"]).expect_err("must error");
+    assert!(matches!(err, circe_lib::Error::Parse(_)), "{err:?}");
+}
+
+#[test_case("docker.io/library/Ubuntu", "invalid repository name 'library/Ubuntu': repository names must be lowercase"; "uppercase")]
+#[test_case("docker.io/library/ubuntu-", "invalid repository name 'library/ubuntu-'"; "trailing_separator")]
+#[test_case("ghcr.io/my___org/app", "invalid repository name 'my___org/app'"; "triple_underscore")]
+#[test_case("ghcr.io/org/-app", "invalid repository name 'org/-app'"; "leading_separator")]
+#[test_case("ghcr.io/org/app!", "invalid repository name 'org/app!'"; "punctuation")]
+#[test_case("ubuntu:-latest", "invalid tag '-latest'"; "tag_leading_dash")]
+#[test_case("ubuntu:.latest", "invalid tag '.latest'"; "tag_leading_dot")]
+#[test_case("ubuntu:v1+build", "invalid tag 'v1+build'"; "tag_plus")]
+#[test]
+fn invalid_reference_message(input: &str, expected: &str) {
+    let err = input.parse::<Reference>().expect_err("must error");
+    assert!(matches!(err, circe_lib::Error::Parse(_)), "{err:?}");
+    assert!(err.to_string().contains(expected), "{err}");
+}
+
+#[test]
+fn tag_length_limit() {
+    let tag = "a".repeat(Reference::MAX_TAG_LEN);
+    assert!(format!("ubuntu:{tag}").parse::<Reference>().is_ok());
+
+    let err = format!("ubuntu:{tag}a")
+        .parse::<Reference>()
+        .expect_err("must error");
+    assert!(
+        err.to_string().contains("must be at most 128 characters"),
+        "{err}"
+    );
+}
+
+#[test]
+fn name_length_limit() {
+    let host = "ghcr.io";
+    let name = "a".repeat(Reference::MAX_NAME_LEN - host.len() - "/org/".len());
+    assert!(format!("{host}/org/{name}").parse::<Reference>().is_ok());
+
+    let err = format!("{host}/org/{name}a")
+        .parse::<Reference>()
+        .expect_err("must error");
+    assert!(
+        err.to_string().contains("must be at most 255 characters"),
+        "{err}"
+    );
 }
 
 // Strategy to generate valid host names
 fn host_strategy() -> impl Strategy<Value = String> {
     // Generate reasonable hostnames like docker.io, ghcr.io, etc
-    "[a-z][a-z0-9-]*(\\.[a-z0-9-]+)*\\.[a-z]{2,}"
+    "[a-z][a-z0-9-]{0,15}(\\.[a-z0-9-]{1,15}){0,3}\\.[a-z]{2,6}"
         .prop_filter("Valid hostname required", |s| !s.contains(".."))
 }
 
 // Strategy to generate valid namespaces
 fn namespace_strategy() -> impl Strategy<Value = String> {
-    // Generate repository namespaces like library, user
-    "[a-z][a-z0-9-]*"
+    // Generate repository namespaces like library, user, my-org
+    "[a-z][a-z0-9]{0,15}(-[a-z0-9]{1,15}){0,3}"
 }
 
 // Strategy to generate valid names
 fn name_strategy() -> impl Strategy<Value = String> {
-    // Generate repository names like ubuntu, project
-    "[a-z][a-z0-9-]*"
+    // Generate repository names like ubuntu, project, my_app.api
+    "[a-z][a-z0-9]{0,15}([._-][a-z0-9]{1,15}){0,3}"
 }
 
 // Strategy to generate valid repositories
@@ -167,6 +267,15 @@ proptest! {
         prop_assert_eq!(reference, parsed);
     }
 
+    // Property: serializing a reference and deserializing it should yield the original reference
+    #[test]
+    fn roundtrip_serde(reference in reference_strategy()) {
+        let serialized = serde_json::to_string(&reference).unwrap();
+        prop_assert_eq!(format!("\"{reference}\""), serialized.as_str());
+        let deserialized = serde_json::from_str::<Reference>(&serialized).unwrap();
+        prop_assert_eq!(reference, deserialized);
+    }
+
     // Property: parsing should reject empty hosts
     #[test]
     fn rejects_empty_host(repository in repository_strategy(), version in tag_strategy()) {
@@ -189,3 +298,69 @@ proptest! {
         prop_assert!(matches!(reference.version, circe_lib::Version::Tag(tag) if tag == "latest"));
     }
 }
+
+#[test_case(Version::latest(), "latest"; "tag")]
+#[test_case(Version::digest(circe_lib::digest!("a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4")), "sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4"; "digest")]
+#[test]
+fn version_serde(version: Version, expected: &str) {
+    let serialized = serde_json::to_string(&version).expect("serialize");
+    pretty_assertions::assert_eq!(format!("\"{expected}\""), serialized);
+    let deserialized = serde_json::from_str::<Version>(&serialized).expect("deserialize");
+    pretty_assertions::assert_eq!(version, deserialized);
+}
+
+#[test_case("\"-latest\""; "invalid_tag")]
+#[test_case("\"sha256:abc\""; "invalid_digest")]
+#[test_case("{\"kind\":\"tag\",\"value\":\"latest\"}"; "structured")]
+#[test]
+fn version_deserialize_invalid(input: &str) {
+    let _ = serde_json::from_str::<Version>(input).expect_err("must error");
+}
+
+#[test]
+fn digest_serde() {
+    let digest =
+        circe_lib::digest!("a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4");
+    let serialized = serde_json::to_string(&digest).expect("serialize");
+    pretty_assertions::assert_eq!(format!("\"{digest}\""), serialized);
+    let deserialized = serde_json::from_str::<Digest>(&serialized).expect("deserialize");
+    pretty_assertions::assert_eq!(digest, deserialized);
+}
+
+#[test_case("\"Docker.io/Library/Ubuntu\""; "uppercase")]
+#[test_case("\"\""; "empty")]
+#[test_case("{\"host\":\"docker.io\"}"; "structured")]
+#[test]
+fn reference_deserialize_invalid(input: &str) {
+    let _ = serde_json::from_str::<Reference>(input).expect_err("must error");
+}
+
+#[test]
+fn reference_deserialize_in_struct() {
+    #[derive(serde::Deserialize)]
+    struct Config {
+        images: Vec<Reference>,
+    }
+
+    // Partial references expand with the configured defaults, which differ under `test-custom-namespace`.
+    let defaults = OciDefaults::current().expect("defaults");
+    let ubuntu = Reference::builder()
+        .host(defaults.base())
+        .namespace(defaults.namespace())
+        .name("ubuntu")
+        .tag("latest")
+        .build();
+
+    let config =
+        serde_json::from_str::<Config>(r#"{"images": ["ubuntu", "ghcr.io/fossas/circe:v1"]}"#)
+            .expect("deserialize");
+    pretty_assertions::assert_eq!(
+        vec![
+            ubuntu,
+            "ghcr.io/fossas/circe:v1"
+                .parse::<Reference>()
+                .expect("parse"),
+        ],
+        config.images
+    );
+}
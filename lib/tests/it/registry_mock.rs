@@ -0,0 +1,915 @@
+//! Pulling images from a [`MockRegistry`], so that the registry path is tested without network access.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_tempfile::TempDir;
+use bytes::Bytes;
+use circe_lib::{
+    extract::{extract, Strategy},
+    metrics::Snapshot,
+    progress::{Progress, Silent},
+    registry::{Existence, Registry, Timeouts},
+    Annotation, Authentication, Digest, DigestAlgorithm, Error, Filters, Identity, ImageConfig,
+    Layer, LayerMediaType, Platform, Source, Subject, Unpack,
+};
+use color_eyre::Result;
+use futures_lite::StreamExt;
+use sha2::{Digest as _, Sha512};
+use simple_test_case::test_case;
+
+use crate::mock::{self, Auth, MockRegistry, EMPTY, LAYER, MANIFEST};
+
+async fn push_image(mock: &MockRegistry) -> Result<()> {
+    let base = mock::tarball(&[("etc/os-release", b"ID=mock\n")]).await?;
+    let app = mock::tarball(&[("app/main.sh", b"echo hello\n")]).await?;
+    mock.push_image("team/app", "1.0", &Platform::linux_amd64(), &[base, app]);
+    Ok(())
+}
+
+async fn assert_applies(registry: &Registry) -> Result<()> {
+    let layers = registry.layers().await?;
+    pretty_assertions::assert_eq!(2, layers.len());
+
+    let tmp = TempDir::new().await?;
+    for layer in &layers {
+        registry.apply_layer(layer, tmp.dir_path()).await?;
+    }
+
+    let read = |path: &str| std::fs::read_to_string(tmp.dir_path().join(Path::new(path)));
+    pretty_assertions::assert_eq!("ID=mock\n", read("etc/os-release")?);
+    pretty_assertions::assert_eq!("echo hello\n", read("app/main.sh")?);
+    Ok(())
+}
+
+#[test_case(Auth::Anonymous, None; "anonymous")]
+#[test_case(Auth::Bearer(None), None; "bearer")]
+#[test_case(Auth::Bearer(Some((String::from("user"), String::from("pass")))), Some(Authentication::basic("user", "pass")); "bearer_with_credentials")]
+#[test_case(Auth::Basic(String::from("user"), String::from("pass")), Some(Authentication::basic("user", "pass")); "basic")]
+#[test_log::test(tokio::test)]
+async fn pull_image(auth: Auth, credentials: Option<Authentication>) -> Result<()> {
+    let mock = MockRegistry::start(auth).await?;
+    push_image(&mock).await?;
+
+    let registry = Registry::builder()
+        .maybe_auth(credentials)
+        .file_filters(Filters::parse_glob(["**"])?)
+        .reference(mock.reference("team", "app", "1.0"))
+        .build()
+        .await?;
+    assert_applies(&registry).await
+}
+
+#[test_case(Auth::Bearer(Some((String::from("user"), String::from("pass")))); "bearer")]
+#[test_case(Auth::Basic(String::from("user"), String::from("pass")); "basic")]
+#[test_log::test(tokio::test)]
+async fn pull_image_wrong_credentials(auth: Auth) -> Result<()> {
+    let mock = MockRegistry::start(auth).await?;
+    push_image(&mock).await?;
+
+    let registry = Registry::builder()
+        .auth(Authentication::basic("user", "wrong"))
+        .reference(mock.reference("team", "app", "1.0"))
+        .build()
+        .await;
+    let layers = match registry {
+        Ok(registry) => registry.layers().await.map(|_| ()),
+        Err(err) => Err(err),
+    };
+    assert!(layers.is_err(), "pulling with wrong credentials must fail");
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn pull_image_by_digest() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let layer = mock::tarball(&[("etc/os-release", b"ID=mock\n")]).await?;
+    let digest = mock.push_image("team/app", "1.0", &Platform::linux_amd64(), &[layer]);
+
+    let reference = format!("{}/team/app@{digest}", mock.host()).parse()?;
+    let registry = Registry::builder().reference(reference).build().await?;
+    pretty_assertions::assert_eq!(digest, registry.digest().await?);
+    pretty_assertions::assert_eq!(1, registry.layers().await?.len());
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn layer_diff_ids() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let base = mock::tarball(&[("etc/os-release", b"ID=mock\n")]).await?;
+    let app = mock::tarball(&[("app/main.sh", b"echo hello\n")]).await?;
+    mock.push_image(
+        "team/app",
+        "1.0",
+        &Platform::linux_amd64(),
+        &[base, app.clone()],
+    );
+
+    // Diff IDs are matched to layers before filtering, so the remaining layer gets its own.
+    let base = registry_layers(&mock, None).await?[0].digest.to_string();
+    let layers = registry_layers(&mock, Some(Filters::parse_glob([base])?.exclude())).await?;
+    let diff_ids = layers
+        .iter()
+        .map(|layer| layer.diff_id.clone())
+        .collect::<Vec<_>>();
+
+    // The mock distributes uncompressed layers, so each diff ID is the digest of the layer.
+    pretty_assertions::assert_eq!(vec![Some(mock::digest(&app))], diff_ids);
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn layer_uncompressed_size() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let base = mock::tarball(&[("etc/os-release", b"ID=mock\n")]).await?;
+    let app = mock::tarball(&[("app/main.sh", b"echo hello\n")]).await?;
+    let digest = mock.push_image("team/app", "1.0", &Platform::linux_amd64(), &[base, app]);
+
+    // Only the first layer records its uncompressed size.
+    let mut manifest = serde_json::from_slice::<serde_json::Value>(&mock.blob(&digest))?;
+    manifest["layers"][0]["annotations"] =
+        serde_json::json!({ circe_lib::UNCOMPRESSED_SIZE_ANNOTATION: "4096" });
+    mock.push_manifest("team/app", "1.0", MANIFEST, &manifest);
+
+    let sizes = registry_layers(&mock, None)
+        .await?
+        .iter()
+        .map(|layer| layer.uncompressed_size)
+        .collect::<Vec<_>>();
+    pretty_assertions::assert_eq!(vec![Some(4096), None], sizes);
+    Ok(())
+}
+
+async fn registry_layers(mock: &MockRegistry, filters: Option<Filters>) -> Result<Vec<Layer>> {
+    let registry = Registry::builder()
+        .reference(mock.reference("team", "app", "1.0"))
+        .maybe_layer_filters(filters)
+        .build()
+        .await?;
+    Ok(registry.layers().await?)
+}
+
+#[test_log::test(tokio::test)]
+async fn identity() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let layer = mock::tarball(&[("etc/os-release", b"ID=mock\n")]).await?;
+    let digest = mock.push_image("team/app", "1.0", &Platform::linux_amd64(), &[layer]);
+
+    let registry = Registry::builder()
+        .reference(mock.reference("team", "app", "1.0"))
+        .build()
+        .await?;
+    let expected = Identity::builder()
+        .reference(format!("{}/team/app:1.0", mock.host()))
+        .resolved_digest(digest)
+        .display_name("team/app")
+        .build();
+    pretty_assertions::assert_eq!(expected, registry.identity().await?);
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn pull_subject() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let layer = mock::tarball(&[("etc/os-release", b"ID=mock\n")]).await?;
+    let image = mock.push_image("team/app", "1.0", &Platform::linux_amd64(), &[layer]);
+    let size = mock.blob(&image).len() as i64;
+
+    let empty = mock.push_blob(EMPTY, "{}");
+    let sbom = mock.push_blob("application/spdx+json", "{}");
+    let artifact = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": MANIFEST,
+        "artifactType": "application/spdx+json",
+        "config": { "mediaType": EMPTY, "digest": empty.to_string(), "size": 2 },
+        "layers": [{ "mediaType": "application/spdx+json", "digest": sbom.to_string(), "size": 2 }],
+        "subject": { "mediaType": MANIFEST, "digest": image.to_string(), "size": size },
+    });
+    mock.push_manifest("team/app", "sbom", MANIFEST, &artifact);
+
+    let registry = Registry::builder()
+        .reference(mock.reference("team", "app", "1.0"))
+        .build()
+        .await?;
+    pretty_assertions::assert_eq!(None, registry.subject().await?);
+
+    let registry = Registry::builder()
+        .reference(mock.reference("team", "app", "sbom"))
+        .build()
+        .await?;
+    let expected = Subject::builder()
+        .digest(image)
+        .size(size)
+        .media_type(MANIFEST)
+        .build();
+    pretty_assertions::assert_eq!(Some(expected), registry.subject().await?);
+    Ok(())
+}
+
+/// Artifacts pushed with ORAS hold files as blobs with a layer media type, and directories as tarballs;
+/// only the tarballs are layers, and the empty config is reported as an empty image config.
+#[test_log::test(tokio::test)]
+async fn pull_oras_artifact() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let dir = mock::tarball(&[("dir/config.yaml", b"key: value\n")]).await?;
+    let empty = mock.push_blob(EMPTY, "{}");
+    let file = mock.push_blob(LAYER, "hello world");
+    let tarball = mock.push_blob(LAYER, dir.clone());
+    let readme = mock.push_blob("application/vnd.example.readme", "readme");
+    let artifact = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": MANIFEST,
+        "artifactType": "application/vnd.example.bundle",
+        "config": { "mediaType": EMPTY, "digest": empty.to_string(), "size": 2 },
+        "layers": [
+            {
+                "mediaType": LAYER,
+                "digest": file.to_string(),
+                "size": 11,
+                "annotations": { "org.opencontainers.image.title": "hello.txt" },
+            },
+            {
+                "mediaType": LAYER,
+                "digest": tarball.to_string(),
+                "size": dir.len(),
+                "annotations": {
+                    "org.opencontainers.image.title": "dir",
+                    "io.deis.oras.content.unpack": "true",
+                },
+            },
+            {
+                "mediaType": "application/vnd.example.readme",
+                "digest": readme.to_string(),
+                "size": 6,
+                "annotations": { "org.opencontainers.image.title": "README.md" },
+            },
+        ],
+    });
+    mock.push_manifest("team/app", "bundle", MANIFEST, &artifact);
+
+    let registry = Registry::builder()
+        .file_filters(Filters::parse_glob(["**"])?)
+        .reference(mock.reference("team", "app", "bundle"))
+        .build()
+        .await?;
+    pretty_assertions::assert_eq!(ImageConfig::default(), registry.config().await?);
+
+    let layers = registry.layers().await?;
+    let digests = layers
+        .iter()
+        .map(|layer| layer.digest.clone())
+        .collect::<Vec<_>>();
+    pretty_assertions::assert_eq!(vec![tarball], digests);
+
+    let tmp = TempDir::new().await?;
+    registry.apply_layer(&layers[0], tmp.dir_path()).await?;
+    let config = std::fs::read_to_string(tmp.dir_path().join("dir/config.yaml"))?;
+    pretty_assertions::assert_eq!("key: value\n", config);
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn head_digest() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Bearer(None)).await?;
+    let layer = mock::tarball(&[("etc/os-release", b"ID=mock\n")]).await?;
+    let digest = mock.push_image("team/app", "1.0", &Platform::linux_amd64(), &[layer]);
+
+    let registry = Registry::builder()
+        .reference(mock.reference("team", "app", "1.0"))
+        .build()
+        .await?;
+    pretty_assertions::assert_eq!(digest, registry.head_digest().await?);
+
+    let manifest_requests = mock
+        .requests()
+        .into_iter()
+        .filter(|request| request.path.contains("/manifests/"))
+        .map(|request| request.method)
+        .collect::<Vec<_>>();
+    pretty_assertions::assert_eq!(vec![String::from("HEAD")], manifest_requests);
+    Ok(())
+}
+
+#[test_case("1.0", None, Existence::Exists; "exists")]
+#[test_case("2.0", None, |_| Existence::NotFound; "not_found")]
+#[test_case("1.0", Some("401 Unauthorized"), |_| Existence::AuthenticationRequired; "unauthorized")]
+#[test_case("1.0", Some("403 Forbidden"), |_| Existence::AccessDenied; "forbidden")]
+#[test_log::test(tokio::test)]
+async fn exists(
+    tag: &str,
+    failure: Option<&'static str>,
+    expected: fn(Digest) -> Existence,
+) -> Result<()> {
+    let mock = MockRegistry::start(Auth::Bearer(None)).await?;
+    let layer = mock::tarball(&[("etc/os-release", b"ID=mock\n")]).await?;
+    let digest = mock.push_image("team/app", "1.0", &Platform::linux_amd64(), &[layer]);
+
+    let registry = Registry::builder()
+        .reference(mock.reference("team", "app", tag))
+        .build()
+        .await?;
+    if let Some(status) = failure {
+        // Error responses don't report a digest, so the client follows the `HEAD` request with a `GET`.
+        mock.fail("/v2/team/app/manifests/", status);
+        mock.fail("/v2/team/app/manifests/", status);
+    }
+
+    pretty_assertions::assert_eq!(expected(digest), registry.exists().await?);
+    Ok(())
+}
+
+#[test_case("2.0", None; "not_found")]
+#[test_case("1.0", Some("403 Forbidden"); "forbidden")]
+#[test_log::test(tokio::test)]
+async fn require_exists(tag: &str, failure: Option<&'static str>) -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    push_image(&mock).await?;
+
+    let registry = Registry::builder()
+        .reference(mock.reference("team", "app", tag))
+        .build()
+        .await?;
+    if let Some(status) = failure {
+        mock.fail("/v2/team/app/manifests/", status);
+        mock.fail("/v2/team/app/manifests/", status);
+    }
+
+    let err = registry
+        .require_exists()
+        .await
+        .expect_err("image must not be readable");
+    let chain = format!("{err:?}");
+    match failure {
+        None => assert!(matches!(err, Error::NotFound(_)), "{chain}"),
+        Some(_) => assert!(matches!(err, Error::Auth(_)), "{chain}"),
+    }
+    assert!(chain.contains("team/app"), "{chain}");
+    Ok(())
+}
+
+#[test_case(Platform::linux_amd64(); "amd64")]
+#[test_case(Platform::linux_arm64(); "arm64")]
+#[test_log::test(tokio::test)]
+async fn pull_index_platform(platform: Platform) -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let mut manifests = Vec::new();
+    for candidate in [Platform::linux_amd64(), Platform::linux_arm64()] {
+        let layer = mock::tarball(&[("etc/arch", candidate.architecture.as_bytes())]).await?;
+        let tag = candidate.architecture.clone();
+        let digest = mock.push_image("team/app", &tag, &candidate, &[layer]);
+        manifests.push((candidate, digest));
+    }
+    mock.push_index("team/app", "1.0", &manifests);
+
+    let registry = Registry::builder()
+        .platform(platform.clone())
+        .reference(mock.reference("team", "app", "1.0"))
+        .build()
+        .await?;
+    let config = registry.config().await?;
+    pretty_assertions::assert_eq!(Some(platform.architecture), config.architecture);
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn pull_index_manifest_digest() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let mut manifests = Vec::new();
+    for candidate in [Platform::linux_amd64(), Platform::linux_arm64()] {
+        let layer = mock::tarball(&[("etc/arch", candidate.architecture.as_bytes())]).await?;
+        let tag = candidate.architecture.clone();
+        let digest = mock.push_image("team/app", &tag, &candidate, &[layer]);
+        manifests.push((candidate, digest));
+    }
+    mock.push_index("team/app", "1.0", &manifests);
+
+    // The digest takes precedence over the platform.
+    let (arm64, digest) = manifests[1].clone();
+    let registry = Registry::builder()
+        .platform(Platform::linux_amd64())
+        .manifest_digest(digest.clone())
+        .reference(mock.reference("team", "app", "1.0"))
+        .build()
+        .await?;
+    let config = registry.config().await?;
+    pretty_assertions::assert_eq!(Some(arm64.architecture), config.architecture);
+    pretty_assertions::assert_eq!(digest, registry.digest().await?);
+    Ok(())
+}
+
+#[test_case("linux/arm/v7", Some("v7"); "exact")]
+#[test_case("linux/arm/v6", Some("v6"); "exact_older")]
+#[test_case("linux/arm", Some("v7"); "default_variant")]
+#[test_case("linux/arm/v8", Some("v8"); "exact_newer")]
+#[test_case("linux/arm/v5", None; "no_compatible_variant")]
+#[test_log::test(tokio::test)]
+async fn pull_index_variant(platform: &str, expected: Option<&str>) -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let mut manifests = Vec::new();
+    for variant in ["v6", "v8", "v7"] {
+        let candidate = Platform::builder()
+            .os("linux")
+            .architecture("arm")
+            .variant(variant)
+            .build();
+        let layer = mock::tarball(&[("etc/variant", variant.as_bytes())]).await?;
+        let digest = mock.push_image("team/app", variant, &candidate, &[layer]);
+        manifests.push((candidate, digest));
+    }
+    mock.push_index("team/app", "1.0", &manifests);
+
+    let registry = Registry::builder()
+        .platform(platform.parse::<Platform>()?)
+        .reference(mock.reference("team", "app", "1.0"))
+        .build()
+        .await?;
+    match expected {
+        Some(expected) => {
+            let config = registry.config().await?;
+            pretty_assertions::assert_eq!(Some(expected), config.variant.as_deref());
+        }
+        None => {
+            let _ = registry.config().await.expect_err("no compatible variant");
+        }
+    }
+    Ok(())
+}
+
+#[test_case("windows/amd64:10.0.17763.1879", Some("10.0.17763.1879"); "exact")]
+#[test_case("windows/amd64:10.0.17763.1900", Some("10.0.17763.1879"); "newer_host_revision")]
+#[test_case("windows/amd64:10.0.17763.x", Some("10.0.17763.1879"); "any_revision")]
+#[test_case("windows/amd64:10.0.20348.2000", Some("10.0.20348.1906"); "other_build")]
+#[test_case("windows/amd64:10.0.17763.1000", None; "older_host_revision")]
+#[test_case("windows/amd64:10.0.14393.1066", None; "no_compatible_build")]
+#[test_log::test(tokio::test)]
+async fn pull_index_os_version(platform: &str, expected: Option<&str>) -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let mut manifests = Vec::new();
+    for os_version in ["10.0.17763.1879", "10.0.20348.1906"] {
+        let candidate = Platform::builder()
+            .os("windows")
+            .architecture("amd64")
+            .os_version(os_version)
+            .build();
+        let layer = mock::tarball(&[("version", os_version.as_bytes())]).await?;
+        let digest = mock.push_image("team/app", os_version, &candidate, &[layer]);
+        manifests.push((candidate, digest));
+    }
+    mock.push_index("team/app", "1.0", &manifests);
+
+    let registry = Registry::builder()
+        .platform(platform.parse::<Platform>()?)
+        .reference(mock.reference("team", "app", "1.0"))
+        .build()
+        .await?;
+    match expected {
+        Some(expected) => {
+            let config = registry.config().await?;
+            pretty_assertions::assert_eq!(Some(expected), config.os_version.as_deref());
+        }
+        None => {
+            let _ = registry
+                .config()
+                .await
+                .expect_err("no compatible os version");
+        }
+    }
+    Ok(())
+}
+
+#[test_case(&["org.opencontainers.image.ref.name=foo"], Some("foo"); "foo")]
+#[test_case(&["org.opencontainers.image.ref.name=bar"], Some("bar"); "bar")]
+#[test_case(&["org.opencontainers.image.ref.name=bar", "com.example.kind=app"], Some("bar"); "multiple")]
+#[test_case(&["org.opencontainers.image.ref.name=foo", "com.example.kind=app"], None; "not_all_match")]
+#[test_case(&["org.opencontainers.image.ref.name=baz"], None; "no_match")]
+#[test_log::test(tokio::test)]
+async fn pull_index_annotation(annotations: &[&str], expected: Option<&str>) -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let mut manifests = Vec::new();
+    for (name, kind) in [("foo", "lib"), ("bar", "app")] {
+        let layer = mock::tarball(&[("name", name.as_bytes())]).await?;
+        let digest = mock.push_image("team/app", name, &Platform::linux_amd64(), &[layer]);
+        let annotations = vec![
+            ("org.opencontainers.image.ref.name", name),
+            ("com.example.kind", kind),
+        ];
+        manifests.push((Platform::linux_amd64(), digest, annotations));
+    }
+    mock.push_annotated_index("team/app", "1.0", &manifests);
+
+    let annotations = annotations
+        .iter()
+        .map(|annotation| annotation.parse::<Annotation>())
+        .collect::<Result<Vec<_>, _>>()?;
+    let registry = Registry::builder()
+        .platform(Platform::linux_amd64())
+        .annotations(annotations)
+        .reference(mock.reference("team", "app", "1.0"))
+        .build()
+        .await?;
+    match expected {
+        Some(expected) => {
+            let layer = mock::tarball(&[("name", expected.as_bytes())]).await?;
+            let layers = registry.layers().await?;
+            pretty_assertions::assert_eq!(mock::digest(&layer), layers[0].digest);
+        }
+        None => {
+            let _ = registry.layers().await.expect_err("no annotated image");
+        }
+    }
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn pull_index_skips_attestations() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let unknown = Platform::builder()
+        .os("unknown")
+        .architecture("unknown")
+        .build();
+    let attestation = mock::tarball(&[("provenance.json", b"{}")]).await?;
+    let attestation = mock.push_image("team/app", "attestation", &unknown, &[attestation]);
+    let platform = Platform::builder()
+        .os("linux")
+        .architecture("s390x")
+        .build();
+    let image = mock::tarball(&[("etc/os-release", b"ID=mock\n")]).await?;
+    let digest = mock::digest(&image);
+    let manifest = mock.push_image("team/app", "s390x", &platform, &[image]);
+    mock.push_annotated_index(
+        "team/app",
+        "1.0",
+        &[
+            (
+                unknown,
+                attestation,
+                vec![("vnd.docker.reference.type", "attestation-manifest")],
+            ),
+            (platform, manifest, vec![]),
+        ],
+    );
+
+    let registry = Registry::builder()
+        .reference(mock.reference("team", "app", "1.0"))
+        .build()
+        .await?;
+    let layers = registry.layers().await?;
+    pretty_assertions::assert_eq!(digest, layers[0].digest);
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn pull_layer_server_error() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    push_image(&mock).await?;
+
+    let registry = Registry::builder()
+        .reference(mock.reference("team", "app", "1.0"))
+        .build()
+        .await?;
+    let layers = registry.layers().await?;
+
+    mock.fail("/v2/team/app/blobs/", "503 Service Unavailable");
+    let tmp = TempDir::new().await?;
+    let err = registry
+        .apply_layer(&layers[0], tmp.dir_path())
+        .await
+        .expect_err("server error must fail the layer");
+    assert!(
+        format!("{err:?}").contains("503"),
+        "unexpected error: {err:?}"
+    );
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn manifest_timeout() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    push_image(&mock).await?;
+
+    let registry = Registry::builder()
+        .timeouts(
+            Timeouts::builder()
+                .manifest(Duration::from_millis(200))
+                .build(),
+        )
+        .reference(mock.reference("team", "app", "1.0"))
+        .build()
+        .await?;
+
+    mock.stall("/v2/team/app/manifests/");
+    let err = registry
+        .layers()
+        .await
+        .expect_err("stalled manifest must time out");
+    assert!(matches!(err, circe_lib::Error::Network(_)), "{err:?}");
+    assert!(format!("{err:?}").contains("timed out"), "{err:?}");
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn blob_stall_timeout() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    push_image(&mock).await?;
+
+    let registry = Registry::builder()
+        .file_filters(Filters::parse_glob(["**"])?)
+        .timeouts(
+            Timeouts::builder()
+                .stall(Duration::from_millis(200))
+                .build(),
+        )
+        .reference(mock.reference("team", "app", "1.0"))
+        .build()
+        .await?;
+    let layers = registry.layers().await?;
+
+    mock.stall(format!("/v2/team/app/blobs/{}", layers[0].digest));
+    let tmp = TempDir::new().await?;
+    let err = registry
+        .apply_layer(&layers[0], tmp.dir_path())
+        .await
+        .expect_err("stalled download must time out");
+    assert!(matches!(err, circe_lib::Error::Network(_)), "{err:?}");
+    assert!(format!("{err:?}").contains("timed out"), "{err:?}");
+
+    // The stall only affects the one download; the layer applies when it's retried.
+    registry.apply_layer(&layers[0], tmp.dir_path()).await?;
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn pull_layer_error_page() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let layer = mock::tarball(&[("etc/os-release", b"ID=mock\n")]).await?;
+    mock.push_image(
+        "team/app",
+        "1.0",
+        &Platform::linux_amd64(),
+        std::slice::from_ref(&layer),
+    );
+
+    // A misconfigured proxy serves its error page in place of the blob.
+    let page = b"<html><body><h1>502 Bad Gateway</h1></body></html>";
+    mock.put_blob(&mock::digest(&layer), LAYER, Bytes::from_static(page));
+
+    let registry = Registry::builder()
+        .reference(mock.reference("team", "app", "1.0"))
+        .build()
+        .await?;
+    let layers = registry.layers().await?;
+    let tmp = TempDir::new().await?;
+    let err = registry
+        .apply_layer(&layers[0], tmp.dir_path())
+        .await
+        .expect_err("error page must not be applied");
+
+    assert!(matches!(err, circe_lib::Error::Network(_)), "{err:?}");
+    let message = format!("{err:?}");
+    assert!(message.contains("non-blob content"), "{message}");
+    assert!(message.contains("502 Bad Gateway"), "{message}");
+    Ok(())
+}
+
+#[test_case(b"layer content", true; "matching")]
+#[test_case(b"tampered content", false; "mismatched")]
+#[test_log::test(tokio::test)]
+async fn pull_layer_sha512(content: &'static [u8], valid: bool) -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let digest = Digest {
+        algorithm: DigestAlgorithm::Sha512,
+        hash: Sha512::digest(b"layer content").to_vec(),
+    };
+    mock.put_blob(&digest, LAYER, Bytes::from_static(content));
+
+    let registry = Registry::builder()
+        .reference(mock.reference("team", "app", "1.0"))
+        .build()
+        .await?;
+    let layer = Layer::builder()
+        .digest(digest)
+        .size(content.len() as i64)
+        .media_type(LayerMediaType::Oci(vec![]))
+        .build();
+
+    let mut stream = registry.pull_layer(&layer).await?;
+    let mut pulled = Vec::new();
+    let mut result = Ok(());
+    while let Some(chunk) = stream.next().await {
+        match chunk {
+            Ok(chunk) => pulled.extend_from_slice(&chunk),
+            Err(err) => result = Err(err),
+        }
+    }
+
+    pretty_assertions::assert_eq!(valid, result.is_ok(), "unexpected result: {result:?}");
+    pretty_assertions::assert_eq!(content, pulled.as_slice());
+    Ok(())
+}
+
+#[derive(Default)]
+struct Whiteouts(Mutex<Vec<(PathBuf, bool)>>, Mutex<Vec<(Digest, PathBuf)>>);
+
+impl Progress for Whiteouts {
+    fn whiteout_applied(&self, _layer: &Layer, path: &Path, existed: bool) {
+        self.0
+            .lock()
+            .expect("lock whiteouts")
+            .push((path.to_path_buf(), existed));
+    }
+
+    fn file_recovered(&self, layer: &Layer, _path: &Path, recovered: &Path) {
+        self.1
+            .lock()
+            .expect("lock recovered")
+            .push((layer.digest.clone(), recovered.to_path_buf()));
+    }
+}
+
+#[test_log::test(tokio::test)]
+async fn apply_layer_reports_whiteouts() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let base = mock::tarball(&[
+        ("etc/secret", b"hunter2\n"),
+        ("etc/os-release", b"ID=mock\n"),
+    ])
+    .await?;
+    let cleanup = mock::tarball(&[("etc/.wh.secret", b""), ("etc/.wh.never", b"")]).await?;
+    mock.push_image(
+        "team/app",
+        "1.0",
+        &Platform::linux_amd64(),
+        &[base, cleanup],
+    );
+
+    let whiteouts = Arc::new(Whiteouts::default());
+    let registry = Registry::builder()
+        .file_filters(Filters::parse_glob(["**"])?)
+        .progress(whiteouts.clone())
+        .reference(mock.reference("team", "app", "1.0"))
+        .build()
+        .await?;
+
+    let tmp = TempDir::new().await?;
+    for layer in registry.layers().await? {
+        registry.apply_layer(&layer, tmp.dir_path()).await?;
+    }
+
+    assert!(!tmp.dir_path().join("etc/secret").exists());
+    pretty_assertions::assert_eq!(
+        vec![
+            (tmp.dir_path().join("etc/secret"), true),
+            (tmp.dir_path().join("etc/never"), false),
+        ],
+        whiteouts.0.lock().expect("lock whiteouts").clone()
+    );
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn apply_layer_recovers_deleted() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let base = mock::tarball(&[
+        ("etc/secret", b"hunter2\n"),
+        ("etc/os-release", b"ID=mock\n"),
+    ])
+    .await?;
+    let cleanup = mock::tarball(&[("etc/.wh.secret", b""), ("etc/.wh.never", b"")]).await?;
+    mock.push_image(
+        "team/app",
+        "1.0",
+        &Platform::linux_amd64(),
+        &[base, cleanup],
+    );
+
+    let tmp = TempDir::new().await?;
+    let deleted = tmp.dir_path().join("deleted");
+    let whiteouts = Arc::new(Whiteouts::default());
+    let registry = Registry::builder()
+        .file_filters(Filters::parse_glob(["**"])?)
+        .deleted_dir(&deleted)
+        .progress(whiteouts.clone())
+        .reference(mock.reference("team", "app", "1.0"))
+        .build()
+        .await?;
+
+    let squashed = tmp.dir_path().join("squashed");
+    std::fs::create_dir(&squashed)?;
+    let layers = registry.layers().await?;
+    for layer in &layers {
+        registry.apply_layer(layer, &squashed).await?;
+    }
+
+    let recovered = std::fs::canonicalize(&deleted)?
+        .join(layers[1].digest.as_hex())
+        .join("etc/secret");
+    assert!(!squashed.join("etc/secret").exists());
+    pretty_assertions::assert_eq!("hunter2\n", std::fs::read_to_string(&recovered)?);
+    pretty_assertions::assert_eq!(
+        vec![
+            (squashed.join("etc/secret"), true),
+            (squashed.join("etc/never"), false),
+        ],
+        whiteouts.0.lock().expect("lock whiteouts").clone()
+    );
+    pretty_assertions::assert_eq!(
+        vec![(layers[1].digest.clone(), recovered)],
+        whiteouts.1.lock().expect("lock recovered").clone()
+    );
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test_log::test(tokio::test)]
+async fn extract_stored_layers_shared() -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let base = mock::tarball(&[("etc/os-release", b"ID=mock\n")]).await?;
+    let app = mock::tarball(&[("app/main.sh", b"echo hello\n")]).await?;
+    let other = mock::tarball(&[("app/other.sh", b"echo other\n")]).await?;
+    mock.push_image(
+        "team/app",
+        "1.0",
+        &Platform::linux_amd64(),
+        &[base.clone(), app],
+    );
+    mock.push_image(
+        "team/other",
+        "1.0",
+        &Platform::linux_amd64(),
+        &[base, other],
+    );
+
+    let tmp = TempDir::new().await?;
+    let store = tmp.dir_path().join("store");
+    let mut extracted = Vec::new();
+    for repository in ["app", "other"] {
+        let registry = Registry::builder()
+            .file_filters(Filters::parse_glob(["**"])?)
+            .reference(mock.reference("team", repository, "1.0"))
+            .build()
+            .await?;
+        let strategies = registry
+            .layers()
+            .await?
+            .into_iter()
+            .map(|layer| Strategy::Stored(layer, store.clone()))
+            .collect::<Vec<_>>();
+
+        let output = tmp.dir_path().join(repository);
+        extracted.push(extract(&registry, &output, strategies, &Silent).await?);
+    }
+
+    let (base, app) = (&extracted[0][0], &extracted[0][1]);
+    let (other_base, other) = (&extracted[1][0], &extracted[1][1]);
+    pretty_assertions::assert_eq!(base.0, other_base.0);
+
+    let original = std::fs::metadata(base.1.join("etc/os-release"))?;
+    let shared = std::fs::metadata(other_base.1.join("etc/os-release"))?;
+    pretty_assertions::assert_eq!(original.ino(), shared.ino(), "base layer files are linked");
+    assert!(app.1.join("app/main.sh").exists());
+    assert!(other.1.join("app/other.sh").exists());
+
+    let base_pulls = mock
+        .requests()
+        .into_iter()
+        .filter(|request| request.path.ends_with(&format!("/blobs/{}", base.0)))
+        .count();
+    pretty_assertions::assert_eq!(1, base_pulls, "base layer is only pulled once");
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn metrics_snapshot() -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    push_image(&mock).await?;
+
+    let tmp = TempDir::new().await?;
+    let store = tmp.dir_path().join("store");
+    let before = Snapshot::take();
+    let mut size = 0;
+    for output in ["first", "second"] {
+        let registry = Registry::builder()
+            .reference(mock.reference("team", "app", "1.0"))
+            .build()
+            .await?;
+        let layers = registry.layers().await?;
+        size = layers.iter().map(|layer| layer.size as u64).sum::<u64>();
+        let strategies = layers
+            .into_iter()
+            .map(|layer| Strategy::Stored(layer, store.clone()))
+            .collect::<Vec<_>>();
+        extract(&registry, &tmp.dir_path().join(output), strategies, &Silent).await?;
+    }
+
+    // Counters are shared with tests running concurrently, so they may have increased by more.
+    let metrics = Snapshot::take().since(before);
+    assert!(metrics.layers_applied >= 2, "{metrics:?}");
+    assert!(metrics.cache_hits >= 2, "{metrics:?}");
+    assert!(metrics.bytes_downloaded >= size, "{metrics:?}");
+    Ok(())
+}
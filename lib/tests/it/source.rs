@@ -0,0 +1,181 @@
+use async_tempfile::{TempDir, TempFile};
+#[cfg(feature = "daemon")]
+use circe_lib::docker::{Connection, Daemon};
+use circe_lib::{
+    cache::Cache, registry::Registry, source, Error, Platform, PullPolicy, Reference, Source,
+    SourceKind, Unpack,
+};
+use color_eyre::Result;
+use simple_test_case::test_case;
+use std::{num::NonZeroUsize, str::FromStr};
+use tokio::io::AsyncWriteExt;
+
+use crate::mock::{Auth, MockRegistry};
+
+#[test_log::test(tokio::test)]
+async fn detect_existing_path_as_tarball() -> Result<()> {
+    let mut file = TempFile::new().await?;
+    file.write_all(b"not a tarball").await?;
+    file.flush().await?;
+
+    // If the path were treated as a reference, this would try the daemon and registry instead.
+    let target = file.file_path().to_string_lossy().to_string();
+    let err = source::detect(&target, source::Options::default())
+        .await
+        .expect_err("invalid tarball must not be read");
+    let chain = format!("{err:?}");
+    assert!(chain.contains("manifest"), "{chain}");
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn detect_oci_layout_requires_marker() -> Result<()> {
+    let mut file = TempFile::new().await?;
+    let content = crate::mock::tarball(&[("index.json", b"{}")]).await?;
+    file.write_all(&content).await?;
+    file.flush().await?;
+
+    let target = file.file_path().to_string_lossy().to_string();
+    let opts = source::Options::builder()
+        .kind(SourceKind::OciLayout)
+        .build();
+    let err = source::detect(&target, opts)
+        .await
+        .expect_err("tarball without an oci-layout file must not be read");
+    assert!(matches!(err, Error::NotFound(_)), "{err:?}");
+
+    Ok(())
+}
+
+#[test_case("auto", Some(SourceKind::Auto); "auto")]
+#[test_case("registry", Some(SourceKind::Registry); "registry")]
+#[test_case("daemon", Some(SourceKind::Daemon); "daemon")]
+#[test_case("tarball", Some(SourceKind::Tarball); "tarball")]
+#[test_case("oci-layout", Some(SourceKind::OciLayout); "oci_layout")]
+#[test_case("oci", None; "unknown")]
+#[test]
+fn parse_source_kind(value: &str, expected: Option<SourceKind>) {
+    let parsed = SourceKind::from_str(value).ok();
+    pretty_assertions::assert_eq!(expected, parsed);
+    if let Some(kind) = parsed {
+        pretty_assertions::assert_eq!(value, kind.to_string());
+    }
+}
+
+#[test_case("never", Some(PullPolicy::Never); "never")]
+#[test_case("missing", Some(PullPolicy::Missing); "missing")]
+#[test_case("always", Some(PullPolicy::Always); "always")]
+#[test_case("sometimes", None; "unknown")]
+#[test]
+fn parse_pull_policy(value: &str, expected: Option<PullPolicy>) {
+    let parsed = PullPolicy::from_str(value).ok();
+    pretty_assertions::assert_eq!(expected, parsed);
+    if let Some(policy) = parsed {
+        pretty_assertions::assert_eq!(value, policy.to_string());
+    }
+}
+
+#[cfg(feature = "daemon")]
+#[test_log::test(tokio::test)]
+async fn daemon_unsupported_host() {
+    let connection = Connection::builder().host("ftp://build-server").build();
+    let err = Daemon::builder()
+        .reference("alpine:latest")
+        .connection(connection)
+        .build()
+        .await
+        .expect_err("unsupported host must not connect");
+    assert!(matches!(err, Error::Unsupported(_)), "{err:?}");
+}
+
+#[cfg(feature = "daemon")]
+#[test_log::test(tokio::test)]
+async fn detect_reports_daemon_and_registry_failures() {
+    let connection = Connection::builder().host("ftp://build-server").build();
+    let opts = source::Options::builder().daemon(connection).build();
+    let err = source::detect("localhost:1/library/alpine:latest", opts)
+        .await
+        .expect_err("image must not be read");
+    let chain = format!("{err:?}");
+    assert!(matches!(err, Error::Network(_)), "{chain}");
+    assert!(chain.contains("other sources also failed"), "{chain}");
+    assert!(chain.contains("docker daemon: "), "{chain}");
+    assert!(chain.contains("ftp://build-server"), "{chain}");
+}
+
+#[test_log::test(tokio::test)]
+async fn detect_reports_cache_failure() -> Result<()> {
+    let dir = TempDir::new().await?;
+    let cache = Cache::new(dir.dir_path());
+    let target = "localhost:1/library/alpine:latest";
+    let path = cache.path(&Reference::from_str(target)?, None);
+    tokio::fs::create_dir_all(path.parent().expect("cache path has a parent")).await?;
+    tokio::fs::write(&path, b"not a tarball").await?;
+
+    // The image can't be read from the cache, so the registry is tried instead; it isn't listening either.
+    let opts = source::Options::builder().cache(cache);
+    #[cfg(feature = "daemon")]
+    let opts = opts.daemon(Connection::builder().host("ftp://build-server").build());
+    let err = source::detect(target, opts.build())
+        .await
+        .expect_err("image must not be read");
+    let chain = format!("{err:?}");
+    assert!(matches!(err, Error::Network(_)), "{chain}");
+    assert!(chain.contains("cache: "), "{chain}");
+    assert!(chain.contains("manifest"), "{chain}");
+
+    Ok(())
+}
+
+#[test_case(SourceKind::Auto; "auto")]
+#[test_case(SourceKind::OciLayout; "oci_layout")]
+#[test_log::test(tokio::test)]
+async fn detect_oci_layout_directory(kind: SourceKind) -> Result<()> {
+    let mock = MockRegistry::start(Auth::Anonymous).await?;
+    let layer = crate::mock::tarball(&[("etc/os-release", b"ID=mock\n")]).await?;
+    let digest = mock.push_image("team/app", "1.0", &Platform::linux_amd64(), &[layer]);
+
+    // The cache stores images as tarballs of OCI layouts; unpacking one gives the layout directory.
+    let cache = TempDir::new().await?;
+    let registry = Registry::builder()
+        .reference(mock.reference("team", "app", "1.0"))
+        .build()
+        .await?;
+    let concurrency = NonZeroUsize::new(1).expect("nonzero");
+    let stored = Cache::new(cache.dir_path())
+        .store(&registry, None, concurrency)
+        .await?;
+    let layout = TempDir::new().await?;
+    let file = tokio::fs::File::open(&stored.path).await?;
+    tokio_tar::Archive::new(file)
+        .unpack(layout.dir_path())
+        .await?;
+
+    let target = layout.dir_path().to_string_lossy().to_string();
+    let opts = source::Options::builder().kind(kind).build();
+    let source = source::detect(&target, opts).await?;
+    pretty_assertions::assert_eq!(digest, source.digest().await?);
+
+    let output = TempDir::new().await?;
+    for layer in source.layers().await? {
+        source.apply_layer(&layer, output.dir_path()).await?;
+    }
+    let content = tokio::fs::read_to_string(output.dir_path().join("etc/os-release")).await?;
+    pretty_assertions::assert_eq!("ID=mock\n", content);
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn detect_oci_layout_directory_requires_marker() -> Result<()> {
+    let dir = TempDir::new().await?;
+    tokio::fs::write(dir.dir_path().join("index.json"), b"{}").await?;
+
+    let target = dir.dir_path().to_string_lossy().to_string();
+    let err = source::detect(&target, source::Options::default())
+        .await
+        .expect_err("directory without an oci-layout file must not be read");
+    assert!(matches!(err, Error::NotFound(_)), "{err:?}");
+
+    Ok(())
+}
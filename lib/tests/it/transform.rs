@@ -1,12 +1,16 @@
 use async_compression::tokio::bufread::{GzipEncoder, ZstdEncoder};
 use circe_lib::{
-    transform::{self, Chunk},
+    transform::{self, Buffering, Chunk},
     LayerMediaTypeFlag,
 };
 use color_eyre::Result;
-use futures_lite::Stream;
+use futures_lite::{Stream, StreamExt};
 use simple_test_case::test_case;
-use std::io::Cursor;
+use std::{
+    io::Cursor,
+    num::{NonZeroU64, NonZeroUsize},
+    time::{Duration, Instant},
+};
 use tokio_util::io::{ReaderStream, StreamReader};
 
 #[test_case(b"Hello, World!"; "hello_world")]
@@ -24,7 +28,7 @@ async fn identity(input: &[u8]) -> Result<()> {
 async fn gzip(input: &[u8]) -> Result<()> {
     let compressed = gzip(input).await?;
     let stream = stream(&compressed);
-    let transformed = transform::gzip(stream);
+    let transformed = transform::gzip(stream, Buffering::default());
     let result = buffer(transformed).await?;
     assert_eq!(result, input);
     Ok(())
@@ -35,12 +39,59 @@ async fn gzip(input: &[u8]) -> Result<()> {
 async fn zstd(input: &[u8]) -> Result<()> {
     let compressed = zstd(input).await?;
     let stream = stream(&compressed);
-    let transformed = transform::zstd(stream);
+    let transformed = transform::zstd(stream, Buffering::default());
     let result = buffer(transformed).await?;
     assert_eq!(result, input);
     Ok(())
 }
 
+#[test_case(1; "one")]
+#[test_case(5; "five")]
+#[test_case(4096; "default")]
+#[test_log::test(tokio::test)]
+async fn chunk_size(size: usize) -> Result<()> {
+    let input = b"Hello, World!".repeat(100);
+    let compressed = gzip(&input).await?;
+    let buffering = Buffering::builder()
+        .chunk_size(NonZeroUsize::new(size).expect("nonzero"))
+        .build();
+
+    let chunks = transform::gzip(stream(&compressed), buffering)
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?;
+    assert!(
+        chunks.iter().all(|chunk| chunk.len() <= size),
+        "chunks are at most {size} bytes"
+    );
+    assert_eq!(chunks.concat(), input);
+    Ok(())
+}
+
+#[test_case(None, Duration::ZERO; "unlimited")]
+#[test_case(NonZeroU64::new(20_000), Duration::from_millis(450); "limited")]
+#[test_log::test(tokio::test)]
+async fn limit_rate(rate: Option<NonZeroU64>, minimum: Duration) -> Result<()> {
+    // The bucket starts with one second of content, so the remaining half second of content is throttled.
+    let input = b"0123456789".repeat(3_000);
+    let chunks = input
+        .chunks(1_000)
+        .map(|chunk| Ok(bytes::Bytes::copy_from_slice(chunk)))
+        .collect::<Vec<Chunk>>();
+
+    let start = Instant::now();
+    let limited = transform::limit_rate(futures_lite::stream::iter(chunks), rate);
+    let result = buffer(limited).await?;
+    let elapsed = start.elapsed();
+    assert_eq!(result, input);
+    assert!(
+        elapsed >= minimum,
+        "took {elapsed:?}, expected at least {minimum:?}"
+    );
+    Ok(())
+}
+
 #[test_case(b"Hello, World!", &[LayerMediaTypeFlag::Zstd]; "hello_world_zstd")]
 #[test_case(b"Hello, World!", &[LayerMediaTypeFlag::Gzip]; "hello_world_gzip")]
 #[test_case(b"Hello, World!", &[LayerMediaTypeFlag::Zstd, LayerMediaTypeFlag::Gzip]; "hello_world_zstd_gzip")]
@@ -72,7 +123,7 @@ async fn flags(input: &[u8], flags: &[LayerMediaTypeFlag]) -> Result<()> {
     }
 
     let stream = stream(&compressed);
-    let transformed = transform::sequence(stream, flags);
+    let transformed = transform::sequence(stream, flags, Buffering::default());
     let result = buffer(transformed).await.context("buffer stream")?;
     assert_eq!(result, input);
     Ok(())
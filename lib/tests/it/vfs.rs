@@ -0,0 +1,199 @@
+use std::path::Path;
+
+use bytes::Bytes;
+use circe_lib::{
+    entries::EntryKind,
+    extract::{ListingEntry, NodeKind, Vfs},
+    progress::Silent,
+    Digest, Unpack,
+};
+use color_eyre::Result;
+use tokio_tar::EntryType;
+
+use crate::memory::{layer, Memory};
+
+/// Build a layer tarball from `(path, type, content)` entries.
+///
+/// For links, the content is the link target.
+async fn tarball(entries: &[(&str, EntryType, &str)]) -> Result<Memory> {
+    let mut builder = tokio_tar::Builder::new(Vec::new());
+    for (path, kind, content) in entries {
+        let mut header = tokio_tar::Header::new_gnu();
+        header.set_entry_type(*kind);
+        header.set_mode(if kind.is_dir() { 0o755 } else { 0o644 });
+        if kind.is_symlink() || kind.is_hard_link() {
+            header.set_size(0);
+            header.set_link_name(content)?;
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, tokio::io::empty())
+                .await?;
+        } else {
+            header.set_size(content.len() as u64);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, content.as_bytes())
+                .await?;
+        }
+    }
+
+    Ok(Memory {
+        layer: Bytes::from(builder.into_inner().await?),
+    })
+}
+
+async fn apply(vfs: &mut Vfs, source: Memory) -> Result<()> {
+    let entries = source
+        .entries(&layer(vec![]))
+        .await?
+        .expect("layer is not foreign");
+    vfs.apply(entries).await?;
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn vfs_squash_single_layer() -> Result<()> {
+    let source = tarball(&[
+        ("etc", EntryType::Directory, ""),
+        ("etc/os-release", EntryType::Regular, "ID=test\n"),
+        ("usr/bin/tool", EntryType::Regular, "#!/bin/sh\n"),
+    ])
+    .await?;
+
+    let vfs = Vfs::squash(&source, &[layer(vec![])], &Silent).await?;
+    pretty_assertions::assert_eq!(Some(&Bytes::from("ID=test\n")), vfs.read("/etc/os-release"));
+
+    // Parent directories are created even if the layer doesn't contain them.
+    pretty_assertions::assert_eq!(
+        Some(vec![Path::new("usr/bin/tool")]),
+        vfs.read_dir("usr/bin")
+    );
+    pretty_assertions::assert_eq!(
+        Some(vec![Path::new("etc"), Path::new("usr")]),
+        vfs.read_dir("/")
+    );
+
+    pretty_assertions::assert_eq!(None, vfs.read("etc"), "directories aren't readable");
+    pretty_assertions::assert_eq!(None, vfs.read_dir("etc/os-release"));
+    pretty_assertions::assert_eq!(None, vfs.read("missing"));
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn vfs_later_layers_override() -> Result<()> {
+    let mut vfs = Vfs::default();
+    apply(
+        &mut vfs,
+        tarball(&[
+            ("etc/os-release", EntryType::Regular, "ID=base\n"),
+            ("etc/removed", EntryType::Regular, "removed\n"),
+            ("var/cache/a", EntryType::Regular, "a\n"),
+            ("var/cache/b", EntryType::Regular, "b\n"),
+        ])
+        .await?,
+    )
+    .await?;
+
+    apply(
+        &mut vfs,
+        tarball(&[
+            ("etc/os-release", EntryType::Regular, "ID=next\n"),
+            ("etc/.wh.removed", EntryType::Regular, ""),
+            ("var/cache/c", EntryType::Regular, "c\n"),
+            ("var/cache/.wh..wh..opq", EntryType::Regular, ""),
+        ])
+        .await?,
+    )
+    .await?;
+
+    pretty_assertions::assert_eq!(Some(&Bytes::from("ID=next\n")), vfs.read("etc/os-release"));
+    pretty_assertions::assert_eq!(None, vfs.get("etc/removed"));
+
+    // Opaque whiteouts hide the prior contents of the directory, but not the contents of the same layer.
+    pretty_assertions::assert_eq!(
+        Some(vec![Path::new("var/cache/c")]),
+        vfs.read_dir("var/cache")
+    );
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn vfs_links() -> Result<()> {
+    let mut vfs = Vfs::default();
+    apply(
+        &mut vfs,
+        tarball(&[
+            ("usr/lib/libc.so", EntryType::Regular, "libc\n"),
+            ("lib", EntryType::Symlink, "usr/lib"),
+            ("usr/lib/libc.so.6", EntryType::Symlink, "/lib/libc.so"),
+            ("usr/lib/hard.so", EntryType::Link, "usr/lib/libc.so"),
+            ("loop", EntryType::Symlink, "loop"),
+        ])
+        .await?,
+    )
+    .await?;
+
+    pretty_assertions::assert_eq!(Some(&Bytes::from("libc\n")), vfs.read("lib/libc.so"));
+    pretty_assertions::assert_eq!(Some(&Bytes::from("libc\n")), vfs.read("lib/libc.so.6"));
+    pretty_assertions::assert_eq!(Some(&Bytes::from("libc\n")), vfs.read("usr/lib/hard.so"));
+    pretty_assertions::assert_eq!(
+        Some(NodeKind::Symlink("/lib/libc.so".into())),
+        vfs.get("lib/libc.so.6").map(|node| node.kind.clone()),
+        "get doesn't follow the final symlink"
+    );
+    pretty_assertions::assert_eq!(None, vfs.read("loop"), "symlink loops don't resolve");
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn vfs_listing() -> Result<()> {
+    let mut vfs = Vfs::default();
+    apply(
+        &mut vfs,
+        tarball(&[
+            ("usr/lib/libc.so", EntryType::Regular, "libc\n"),
+            ("usr/lib/hard.so", EntryType::Link, "usr/lib/libc.so"),
+            ("lib", EntryType::Symlink, "usr/lib"),
+        ])
+        .await?,
+    )
+    .await?;
+
+    let digest =
+        Digest::from_sha256("39470f46972e69a6b5e531099219d6d09215dbf10de15d669cbe53213f1320e7")?;
+    let file = |path: &str| ListingEntry {
+        path: path.into(),
+        kind: EntryKind::File,
+        mode: 0o644,
+        size: 5,
+        digest: Some(digest.clone()),
+        link_target: None,
+    };
+    let dir = |path: &str| ListingEntry {
+        path: path.into(),
+        kind: EntryKind::Directory,
+        mode: 0o755,
+        size: 0,
+        digest: None,
+        link_target: None,
+    };
+
+    pretty_assertions::assert_eq!(
+        vec![
+            ListingEntry {
+                path: "lib".into(),
+                kind: EntryKind::Symlink,
+                mode: 0o644,
+                size: 0,
+                digest: None,
+                link_target: Some("usr/lib".into()),
+            },
+            dir("usr"),
+            dir("usr/lib"),
+            file("usr/lib/hard.so"),
+            file("usr/lib/libc.so"),
+        ],
+        vfs.listing()
+    );
+    Ok(())
+}
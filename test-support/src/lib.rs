@@ -0,0 +1,5 @@
+//! Test support shared by the circe crates.
+//!
+//! This crate isn't published; the library and the binary depend on it only for their tests.
+
+pub mod mock;
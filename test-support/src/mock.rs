@@ -0,0 +1,634 @@
+//! An in-process OCI registry, so that registry features can be tested without network access.
+//!
+//! The registry implements the read side of the distribution spec over plain HTTP on a loopback port
+//! (which [`circe_lib::registry::Registry`] accesses without TLS):
+//! - `/v2/` with anonymous, bearer token, or basic authentication.
+//! - Manifests and indexes by tag or digest, with `GET` and `HEAD`.
+//! - Blobs, optionally redirected to separate "storage" on another origin like S3-backed registries do.
+//! - `/v2/_catalog`, paginated with `n` and `last` and linking the next page like the reference registry.
+//!
+//! Every request is recorded, and requests can be made to fail or stall to test how failures are handled.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use base64::Engine;
+use bytes::Bytes;
+use circe_lib::{Digest, Platform, Reference};
+use color_eyre::Result;
+use serde_json::{json, Value};
+use sha2::{Digest as _, Sha256};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// The token issued by the registry when it uses bearer authentication.
+pub const TOKEN: &str = "mock-registry-token";
+
+pub const MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+pub const INDEX: &str = "application/vnd.oci.image.index.v1+json";
+pub const CONFIG: &str = "application/vnd.oci.image.config.v1+json";
+pub const LAYER: &str = "application/vnd.oci.image.layer.v1.tar";
+pub const EMPTY: &str = "application/vnd.oci.empty.v1+json";
+
+/// How the registry authenticates requests.
+#[derive(Debug, Clone, Default)]
+pub enum Auth {
+    /// Requests aren't authenticated.
+    #[default]
+    Anonymous,
+
+    /// Requests require a token, which is issued by the registry's token endpoint.
+    /// If credentials are set, the token endpoint requires them with basic authentication.
+    Bearer(Option<(String, String)>),
+
+    /// Requests require the credentials with basic authentication.
+    Basic(String, String),
+}
+
+/// A request received by the registry or its storage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+
+    /// The path of the request, including the query.
+    pub path: String,
+
+    /// The `Authorization` header of the request, if any.
+    pub authorization: Option<String>,
+}
+
+/// A response sent by the registry or its storage.
+#[derive(Debug, Clone)]
+struct Response {
+    status: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: Bytes,
+
+    /// Whether to send only part of the body, then hold the connection open without sending the rest.
+    stall: bool,
+}
+
+impl Response {
+    fn new(status: &'static str) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: Bytes::new(),
+            stall: false,
+        }
+    }
+
+    fn content(media_type: &str, digest: &Digest, body: Bytes) -> Self {
+        Self::new("200 OK")
+            .header("Content-Type", media_type)
+            .header("Docker-Content-Digest", digest.to_string())
+            .body(body)
+    }
+
+    fn header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    fn body(mut self, body: Bytes) -> Self {
+        self.body = body;
+        self
+    }
+}
+
+/// Content served by the registry.
+#[derive(Debug, Default)]
+struct State {
+    auth: Auth,
+
+    /// Whether blobs are redirected to storage.
+    redirect: bool,
+
+    /// Blobs (including manifests) by digest, along with their media type.
+    blobs: HashMap<String, (String, Bytes)>,
+
+    /// Manifest digests by `repository:tag`.
+    tags: HashMap<String, Digest>,
+
+    /// Status codes to respond with instead of handling requests, along with the path prefix they apply to.
+    failures: Vec<(String, &'static str)>,
+
+    /// Path prefixes of requests whose responses stall.
+    stalls: Vec<String>,
+
+    requests: Vec<Request>,
+    storage_requests: Vec<Request>,
+}
+
+/// An in-process OCI registry.
+#[derive(Debug, Clone)]
+pub struct MockRegistry {
+    host: String,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockRegistry {
+    /// Start a registry with the authentication scheme.
+    ///
+    /// The registry runs until the test ends.
+    pub async fn start(auth: Auth) -> Result<Self> {
+        let state = Arc::new(Mutex::new(State {
+            auth,
+            ..Default::default()
+        }));
+
+        let storage = serve({
+            let state = state.clone();
+            move |host, request| handle_storage(host, &state, request)
+        })
+        .await?;
+        let host = serve({
+            let state = state.clone();
+            move |host, request| handle_registry(host, &storage, &state, request)
+        })
+        .await?;
+
+        Ok(Self { host, state })
+    }
+
+    /// The `host:port` of the registry.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// A reference to the repository and tag in this registry.
+    pub fn reference(&self, namespace: &str, name: &str, tag: &str) -> Reference {
+        Reference::builder()
+            .host(&self.host)
+            .namespace(namespace)
+            .name(name)
+            .tag(tag)
+            .build()
+    }
+
+    /// Redirect blob downloads to storage on another origin,
+    /// which serves them from a pre-signed URL after another redirect
+    /// and rejects requests that carry credentials.
+    pub fn redirect_blobs(&self) {
+        self.state().redirect = true;
+    }
+
+    /// Respond to the next request whose path starts with the prefix with the status (e.g. `503 Service Unavailable`)
+    /// instead of handling it.
+    ///
+    /// Call multiple times to fail multiple requests.
+    pub fn fail(&self, prefix: impl Into<String>, status: &'static str) {
+        self.state().failures.push((prefix.into(), status));
+    }
+
+    /// Stall the response to the next request whose path starts with the prefix:
+    /// only part of the body is sent, and the connection is then held open without sending the rest.
+    ///
+    /// Call multiple times to stall multiple requests.
+    pub fn stall(&self, prefix: impl Into<String>) {
+        self.state().stalls.push(prefix.into());
+    }
+
+    /// The requests received by the registry so far.
+    pub fn requests(&self) -> Vec<Request> {
+        self.state().requests.clone()
+    }
+
+    /// The requests received by storage so far; see [`MockRegistry::redirect_blobs`].
+    pub fn storage_requests(&self) -> Vec<Request> {
+        self.state().storage_requests.clone()
+    }
+
+    /// Store the blob with the media type, returning its digest.
+    pub fn push_blob(&self, media_type: &str, content: impl Into<Bytes>) -> Digest {
+        let content = content.into();
+        let digest = digest(&content);
+        self.put_blob(&digest, media_type, content);
+        digest
+    }
+
+    /// The content of the blob with the digest.
+    pub fn blob(&self, digest: &Digest) -> Bytes {
+        self.state().blobs[&digest.to_string()].1.clone()
+    }
+
+    /// Store the content as the blob with the digest, even if it doesn't match.
+    pub fn put_blob(&self, digest: &Digest, media_type: &str, content: impl Into<Bytes>) {
+        let blob = (media_type.to_string(), content.into());
+        self.state().blobs.insert(digest.to_string(), blob);
+    }
+
+    /// Store the manifest and tag it in the repository, returning its digest.
+    pub fn push_manifest(
+        &self,
+        repository: &str,
+        tag: &str,
+        media_type: &str,
+        manifest: &Value,
+    ) -> Digest {
+        let digest = self.push_blob(media_type, manifest.to_string());
+        let key = format!("{repository}:{tag}");
+        self.state().tags.insert(key, digest.clone());
+        digest
+    }
+
+    /// Push an image for the platform with the uncompressed tarballs as its layers and tag it in the repository.
+    ///
+    /// Returns the digest of the manifest.
+    pub fn push_image(
+        &self,
+        repository: &str,
+        tag: &str,
+        platform: &Platform,
+        layers: &[Bytes],
+    ) -> Digest {
+        let layers = layers
+            .iter()
+            .map(|layer| descriptor(LAYER, &self.push_blob(LAYER, layer.clone()), layer.len()))
+            .collect::<Vec<_>>();
+        let diff_ids = layers
+            .iter()
+            .map(|layer| layer["digest"].clone())
+            .collect::<Vec<_>>();
+
+        let config = json!({
+            "architecture": platform.architecture,
+            "os": platform.os,
+            "variant": platform.variant,
+            "os.version": platform.os_version,
+            "rootfs": { "type": "layers", "diff_ids": diff_ids },
+        })
+        .to_string();
+        let config = descriptor(
+            CONFIG,
+            &self.push_blob(CONFIG, config.clone()),
+            config.len(),
+        );
+
+        let manifest = json!({
+            "schemaVersion": 2,
+            "mediaType": MANIFEST,
+            "config": config,
+            "layers": layers,
+        });
+        self.push_manifest(repository, tag, MANIFEST, &manifest)
+    }
+
+    /// Push an index of the manifests for each platform and tag it in the repository.
+    ///
+    /// Returns the digest of the index.
+    pub fn push_index(
+        &self,
+        repository: &str,
+        tag: &str,
+        manifests: &[(Platform, Digest)],
+    ) -> Digest {
+        let manifests = manifests
+            .iter()
+            .map(|(platform, digest)| (platform.clone(), digest.clone(), Vec::new()))
+            .collect::<Vec<_>>();
+        self.push_annotated_index(repository, tag, &manifests)
+    }
+
+    /// Push an index like [`MockRegistry::push_index`], annotating each manifest in the index.
+    pub fn push_annotated_index(
+        &self,
+        repository: &str,
+        tag: &str,
+        manifests: &[AnnotatedManifest<'_>],
+    ) -> Digest {
+        let manifests = manifests
+            .iter()
+            .map(|(platform, digest, annotations)| {
+                let size = self.state().blobs[&digest.to_string()].1.len();
+                let mut descriptor = descriptor(MANIFEST, digest, size);
+                descriptor["platform"] = json!({
+                    "architecture": platform.architecture,
+                    "os": platform.os,
+                });
+                if let Some(variant) = &platform.variant {
+                    descriptor["platform"]["variant"] = json!(variant);
+                }
+                if let Some(os_version) = &platform.os_version {
+                    descriptor["platform"]["os.version"] = json!(os_version);
+                }
+                for (key, value) in annotations {
+                    descriptor["annotations"][*key] = json!(value);
+                }
+                descriptor
+            })
+            .collect::<Vec<_>>();
+
+        let index = json!({
+            "schemaVersion": 2,
+            "mediaType": INDEX,
+            "manifests": manifests,
+        });
+        self.push_manifest(repository, tag, INDEX, &index)
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("lock registry state")
+    }
+}
+
+/// Build an uncompressed tarball with the files.
+pub async fn tarball(files: &[(&str, &[u8])]) -> Result<Bytes> {
+    let mut builder = tokio_tar::Builder::new(Vec::new());
+    for (path, content) in files {
+        let mut header = tokio_tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, path, *content).await?;
+    }
+    Ok(Bytes::from(builder.into_inner().await?))
+}
+
+/// A manifest in an index pushed with [`MockRegistry::push_annotated_index`], with its annotations.
+pub type AnnotatedManifest<'a> = (Platform, Digest, Vec<(&'a str, &'a str)>);
+
+/// The sha256 digest of the content.
+pub fn digest(content: &[u8]) -> Digest {
+    Digest::from_hash(Sha256::digest(content).to_vec())
+}
+
+fn descriptor(media_type: &str, digest: &Digest, size: usize) -> Value {
+    json!({
+        "mediaType": media_type,
+        "digest": digest.to_string(),
+        "size": size,
+    })
+}
+
+fn handle_registry(host: &str, storage: &str, state: &Mutex<State>, request: &Request) -> Response {
+    let mut state = state.lock().expect("lock registry state");
+    state.requests.push(request.clone());
+
+    let path = request.path.split('?').next().unwrap_or_default();
+    if let Some(index) = state
+        .failures
+        .iter()
+        .position(|(prefix, _)| path.starts_with(prefix.as_str()))
+    {
+        let (_, status) = state.failures.remove(index);
+        return Response::new(status);
+    }
+
+    if path == "/token" {
+        return match &state.auth {
+            Auth::Bearer(Some((username, password)))
+                if request.authorization != Some(basic(username, password)) =>
+            {
+                Response::new("401 Unauthorized")
+            }
+            _ => Response::new("200 OK")
+                .header("Content-Type", "application/json")
+                .body(Bytes::from(json!({ "token": TOKEN }).to_string())),
+        };
+    }
+
+    let (authorized, challenge) = match &state.auth {
+        Auth::Anonymous => (true, String::new()),
+        Auth::Bearer(_) => (
+            request.authorization.as_deref() == Some(&format!("Bearer {TOKEN}")),
+            format!(r#"Bearer realm="http://{host}/token",service="mock""#),
+        ),
+        Auth::Basic(username, password) => (
+            request.authorization == Some(basic(username, password)),
+            String::from(r#"Basic realm="mock""#),
+        ),
+    };
+    if !authorized {
+        return Response::new("401 Unauthorized").header("WWW-Authenticate", challenge);
+    }
+
+    let head = request.method == "HEAD";
+    let response = match route(path) {
+        Some(Route::Base) => Response::new("200 OK"),
+        Some(Route::Catalog) => catalog(&state, &request.path),
+        Some(Route::Manifest(repository, reference)) => {
+            let digest = match reference.parse::<Digest>() {
+                Ok(digest) => Some(digest),
+                Err(_) => state
+                    .tags
+                    .get(&format!("{repository}:{reference}"))
+                    .cloned(),
+            };
+            match digest.and_then(|digest| Some((state.blobs.get(&digest.to_string())?, digest))) {
+                Some(((media_type, content), digest)) => {
+                    Response::content(media_type, &digest, content.clone())
+                }
+                None => Response::new("404 Not Found"),
+            }
+        }
+        Some(Route::Blob(digest)) if state.redirect => Response::new("307 Temporary Redirect")
+            .header("Location", format!("http://{storage}/presigned/{digest}")),
+        Some(Route::Blob(digest)) => match (digest.parse::<Digest>(), state.blobs.get(digest)) {
+            (Ok(parsed), Some((_, content))) => {
+                Response::content("application/octet-stream", &parsed, content.clone())
+            }
+            _ => Response::new("404 Not Found"),
+        },
+        None => Response::new("404 Not Found"),
+    };
+
+    let response = match state
+        .stalls
+        .iter()
+        .position(|prefix| path.starts_with(prefix.as_str()))
+    {
+        Some(index) => {
+            state.stalls.remove(index);
+            Response {
+                stall: true,
+                ..response
+            }
+        }
+        None => response,
+    };
+
+    if head {
+        let length = response.body.len();
+        response
+            .body(Bytes::new())
+            .header("Content-Length", length.to_string())
+    } else {
+        response
+    }
+}
+
+fn handle_storage(host: &str, state: &Mutex<State>, request: &Request) -> Response {
+    let mut state = state.lock().expect("lock registry state");
+    state.storage_requests.push(request.clone());
+
+    if let Some(digest) = request.path.strip_prefix("/presigned/") {
+        return Response::new("302 Found").header(
+            "Location",
+            format!("http://{host}/blob/{digest}?signature=mock"),
+        );
+    }
+
+    let digest = request
+        .path
+        .strip_prefix("/blob/")
+        .and_then(|path| path.strip_suffix("?signature=mock"));
+    match (digest, &request.authorization) {
+        (Some(digest), None) => match state.blobs.get(digest) {
+            Some((_, content)) => Response::new("200 OK").body(content.clone()),
+            None => Response::new("404 Not Found"),
+        },
+        _ => Response::new("403 Forbidden"),
+    }
+}
+
+enum Route<'a> {
+    Base,
+    Catalog,
+    Manifest(&'a str, &'a str),
+    Blob(&'a str),
+}
+
+fn route(path: &str) -> Option<Route<'_>> {
+    let path = path.strip_prefix("/v2/")?;
+    if path.is_empty() {
+        return Some(Route::Base);
+    }
+    if path == "_catalog" {
+        return Some(Route::Catalog);
+    }
+
+    // Repository names may contain `/`, so split on the last endpoint segment.
+    if let Some((repository, reference)) = path.rsplit_once("/manifests/") {
+        Some(Route::Manifest(repository, reference))
+    } else if let Some((_, digest)) = path.rsplit_once("/blobs/") {
+        Some(Route::Blob(digest))
+    } else {
+        None
+    }
+}
+
+/// A page of the repositories that have tags, in order, starting after `last` and with at most `n` repositories;
+/// if there are more, the next page is linked from the `Link` header.
+fn catalog(state: &State, path: &str) -> Response {
+    let query = path
+        .split_once('?')
+        .map(|(_, query)| query)
+        .unwrap_or_default();
+    let param = |name: &str| {
+        query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.to_string())
+    };
+    let n = param("n")
+        .and_then(|n| n.parse::<usize>().ok())
+        .unwrap_or(usize::MAX);
+    let last = param("last").unwrap_or_default();
+
+    let mut repositories = state
+        .tags
+        .keys()
+        .filter_map(|key| key.rsplit_once(':'))
+        .map(|(repository, _)| repository.to_string())
+        .filter(|repository| *repository > last)
+        .collect::<Vec<_>>();
+    repositories.sort();
+    repositories.dedup();
+
+    let more = repositories.len() > n;
+    repositories.truncate(n);
+    let body = json!({ "repositories": repositories }).to_string();
+    let response = Response::new("200 OK")
+        .header("Content-Type", "application/json")
+        .body(Bytes::from(body));
+    match repositories.last() {
+        Some(last) if more => response.header(
+            "Link",
+            format!(r#"</v2/_catalog?last={last}&n={n}>; rel="next""#),
+        ),
+        _ => response,
+    }
+}
+
+fn basic(username: &str, password: &str) -> String {
+    let credentials =
+        base64::engine::general_purpose::STANDARD.encode(format!("{username}:{password}"));
+    format!("Basic {credentials}")
+}
+
+/// Serve each request with the handler until the test ends, returning the `host:port` of the server.
+///
+/// Each connection serves a single request without a body, which is all that pulling images requires.
+async fn serve(
+    handler: impl Fn(&str, &Request) -> Response + Send + Sync + 'static,
+) -> Result<String> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let host = listener.local_addr()?.to_string();
+    let served = host.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let Ok(request) = read_request(&mut stream).await else {
+                continue;
+            };
+
+            let response = handler(&served, &request);
+            let mut head = format!("HTTP/1.1 {}\r\n", response.status);
+            for (name, value) in &response.headers {
+                head.push_str(&format!("{name}: {value}\r\n"));
+            }
+            if !response
+                .headers
+                .iter()
+                .any(|(name, _)| *name == "Content-Length")
+            {
+                head.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+            }
+            head.push_str("Connection: close\r\n\r\n");
+
+            if response.stall {
+                let partial = response.body.slice(..response.body.len() / 2);
+                stream.write_all(head.as_bytes()).await.ok();
+                stream.write_all(&partial).await.ok();
+                tokio::spawn(async move {
+                    let _stream = stream;
+                    std::future::pending::<()>().await;
+                });
+                continue;
+            }
+
+            let response = [head.as_bytes(), &response.body].concat();
+            stream.write_all(&response).await.ok();
+            stream.shutdown().await.ok();
+        }
+    });
+    Ok(host)
+}
+
+/// Read the request line and headers.
+async fn read_request(stream: &mut TcpStream) -> Result<Request> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(stream.read_u8().await?);
+    }
+
+    let head = String::from_utf8(head)?;
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default().to_string();
+    let authorization = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("authorization"))
+        .map(|(_, value)| value.trim().to_string());
+    Ok(Request {
+        method,
+        path,
+        authorization,
+    })
+}